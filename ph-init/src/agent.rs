@@ -0,0 +1,106 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::init::InitServer;
+use crate::wayland;
+
+// Name of the virtio console port which carries events to the host
+const AGENT_PORT_NAME: &str = "ph.agent";
+
+lazy_static! {
+    // A virtio console port can only be open once, so the file is shared by
+    // everything which sends events.
+    static ref AGENT_PORT: Mutex<Option<File>> = Mutex::new(None);
+}
+
+/// Open the `ph.agent` port. Returns `false` if the host did not create it.
+pub fn connect() -> io::Result<bool> {
+    let path = match find_agent_port()? {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let port = OpenOptions::new().read(true).write(true).open(path)?;
+    *AGENT_PORT.lock().unwrap() = Some(port);
+    Ok(true)
+}
+
+pub fn is_connected() -> bool {
+    AGENT_PORT.lock().unwrap().is_some()
+}
+
+/// Read commands sent by the host on the agent port, one per line, and run
+/// them on a new thread. Does nothing if the port is not open.
+pub fn listen() {
+    let port = match AGENT_PORT.lock().unwrap().as_ref().map(File::try_clone) {
+        Some(Ok(port)) => port,
+        Some(Err(err)) => {
+            warn!("Failed to read commands from agent port: {}", err);
+            return;
+        }
+        None => return,
+    };
+    thread::spawn(move || {
+        for line in BufReader::new(port).lines() {
+            match line {
+                Ok(line) => run_command(line.trim()),
+                Err(err) => {
+                    warn!("Error reading command from host: {}", err);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+fn run_command(command: &str) {
+    match command {
+        "restart-wayland" => wayland::restart(),
+        "power-off" => {
+            info!("Powering off at the request of the host");
+            unsafe { libc::sync(); }
+            if let Err(err) = InitServer::power_off() {
+                warn!("reboot() failed: {:?}", err);
+            }
+        }
+        "" => {},
+        _ => warn!("Unknown command from host: {}", command),
+    }
+}
+
+/// Send an event to the host as one line of text, the event name followed
+/// by `fields`. The write returns once the host has received the line.
+pub fn send(event: &str, fields: &str) {
+    let mut port = AGENT_PORT.lock().unwrap();
+    let port = match port.as_mut() {
+        Some(port) => port,
+        None => return,
+    };
+    let result = if fields.is_empty() {
+        writeln!(port, "{}", event)
+    } else {
+        writeln!(port, "{} {}", event, fields)
+    };
+    if let Err(err) = result {
+        warn!("Failed to send {} event to host: {}", event, err);
+    }
+}
+
+// The port device is named /dev/vportNpM depending on probe order, so
+// look it up by the name assigned by the host.
+fn find_agent_port() -> io::Result<Option<PathBuf>> {
+    let ports = Path::new("/sys/class/virtio-ports");
+    if !ports.exists() {
+        return Ok(None);
+    }
+    for entry in fs::read_dir(ports)? {
+        let entry = entry?;
+        let name = fs::read_to_string(entry.path().join("name")).unwrap_or_default();
+        if name.trim() == AGENT_PORT_NAME {
+            return Ok(Some(Path::new("/dev").join(entry.file_name())));
+        }
+    }
+    Ok(None)
+}
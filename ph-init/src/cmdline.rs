@@ -3,6 +3,7 @@ use std::fs;
 use std::path::Path;
 use crate::sys::mount_procfs;
 use crate::error::{Error,Result};
+use crate::vars;
 
 pub struct CmdLine {
     vars: HashMap<String, Option<String>>,
@@ -35,6 +36,18 @@ impl CmdLine {
         CmdLine{ vars }
     }
 
+    /// Warn about each `phinit.*` variable which is not known to ph-init or
+    /// which is missing the value it needs.
+    pub fn check_vars(&self) {
+        let mut names: Vec<&String> = self.vars.keys().collect();
+        names.sort();
+        for name in names {
+            if let Some(problem) = vars::check(name, self.vars[name].is_some()) {
+                warn!("Kernel command line: {}", problem);
+            }
+        }
+    }
+
     pub fn has_var(&self, name: &str) -> bool {
         self.vars.contains_key(name)
     }
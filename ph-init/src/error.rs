@@ -16,6 +16,7 @@ pub enum Error {
     MountOverlay(io::Error),
     MoveMount(String, String, io::Error),
     Mount9P(String, String, io::Error),
+    MountVirtioFs(String, String, io::Error),
     Umount(String, io::Error),
     MkDir(String, io::Error),
     SetHostname(io::Error),
@@ -34,6 +35,8 @@ pub enum Error {
     XAuthFail(io::Error),
     WriteBashrc(io::Error),
     NetworkConfigure(netlink::Error),
+    UpperMount(String, io::Error),
+    CleanWorkDir(io::Error),
 }
 
 impl fmt::Display for Error {
@@ -54,6 +57,7 @@ impl fmt::Display for Error {
             MountOverlay(err) => write!(f, "failed to mount overlayfs: {}", err),
             MoveMount(from, to, err) => write!(f, "failed to move mount from {} to {}: {}", from, to, err),
             Mount9P(tag,target, err) => write!(f, "failed to mount 9p volume {} at {}: {}", tag, target, err),
+            MountVirtioFs(tag,target, err) => write!(f, "failed to mount virtio-fs volume {} at {}: {}", tag, target, err),
             Umount(target, err) => write!(f, "failed to unmount {}: {}", target, err),
             MkDir(target, err) => write!(f, "failed to mkdir {}: {}", target, err),
             SetHostname(err) => write!(f, "sethostname() failed: {}", err),
@@ -72,6 +76,8 @@ impl fmt::Display for Error {
             XAuthFail(err) => write!(f, "error creating .Xauthority file: {}", err),
             WriteBashrc(err) => write!(f, "error writing bashrc file: {}", err),
             NetworkConfigure(err) => write!(f, "error configuring network: {}", err),
+            UpperMount(upper, err) => write!(f, "failed to mount overlay upper layer {}: {}", upper, err),
+            CleanWorkDir(err) => write!(f, "failed to clean overlay work directory: {}", err),
         }
     }
 }
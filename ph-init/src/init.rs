@@ -1,15 +1,19 @@
 
-use crate::{Error, Result, Logger, LogLevel, netlink};
+use crate::{Error, Result, Logger, LogLevel, netlink, agent, selftest, wayland};
 use crate::cmdline::CmdLine;
-use crate::sys::{sethostname, setsid, set_controlling_tty, mount_devtmpfs, mount_tmpfs, mkdir, umount, mount_sysfs, mount_procfs, mount_devpts, chown, chmod, create_directories, mount_overlay, move_mount, pivot_root, mount_9p, mount, waitpid, reboot, getpid, mount_tmpdir, mount_cgroup, mkdir_mode, umask, _chown};
-use std::path::Path;
+use crate::sys::{sethostname, setsid, set_controlling_tty, mount_devtmpfs, mount_tmpfs, mkdir, umount, mount_sysfs, mount_procfs, mount_devpts, chown, chmod, create_directories, mount_overlay, move_mount, pivot_root, mount_9p, mount_9p_readonly, mount_virtiofs, mount, waitpid, reboot, getpid, mount_tmpdir, mount_cgroup, mkdir_mode, umask, _chown, _chroot};
+use std::path::{Path, PathBuf};
 use std::{fs, process, io, env};
-use crate::service::{Service, ServiceLaunch};
+use crate::service::{Service, ServiceLaunch, ServiceManifest, Readiness};
 use std::collections::BTreeMap;
 use std::io::Read;
 use std::net::Ipv4Addr;
 use std::str::FromStr;
+use std::process::Command;
+use std::os::unix::process::CommandExt;
 use crate::netlink::NetlinkSocket;
+use crate::user::SessionUser;
+use crate::security::{ModuleKey, ModuleKeyStatus};
 
 const BASHRC: &str = r#"
 export PS1="airwolf > "
@@ -26,8 +30,10 @@ fi
 pub struct InitServer {
     hostname: String,
     homedir: String,
+    user: SessionUser,
     cmdline: CmdLine,
     rootfs: RootFS,
+    module_key: Option<ModuleKey>,
     services: BTreeMap<u32, Service>,
 }
 
@@ -38,14 +44,18 @@ impl InitServer {
         let cmdline = CmdLine::load()?;
         let homedir = cmdline.lookup("phinit.home")
             .unwrap_or("/home/user".to_string());
+        let user = SessionUser::load(&cmdline);
         let rootfs = RootFS::load(&cmdline)?;
+        let module_key = ModuleKey::load(&cmdline);
         let services = BTreeMap::new();
 
         Ok(InitServer {
             hostname,
             homedir,
+            user,
             cmdline,
             rootfs,
+            module_key,
             services,
         })
     }
@@ -58,6 +68,7 @@ impl InitServer {
 
     fn initialize(&self) -> Result<()> {
         self.set_loglevel();
+        self.cmdline.check_vars();
         umask(0);
         sethostname(&self.hostname)?;
         setsid()?;
@@ -113,26 +124,63 @@ impl InitServer {
         mount_tmpdir("/tmp")?;
         mkdir("/dev/shm")?;
         mount_tmpdir("/dev/shm")?;
+        Self::link_console_ports();
         mkdir("/run/user")?;
-        mkdir("/run/user/1000")?;
-        chown("/run/user/1000", 1000,1000)?;
+        let runtime_dir = self.user.runtime_dir();
+        mkdir(&runtime_dir)?;
+        chown(&runtime_dir, self.user.uid(), self.user.gid())?;
+        if let Err(err) = self.user.create_account(self.homedir()) {
+            warn!("Failed to create account for {}: {}", self.user.name(), err);
+        }
 
         self.mount_home_if_exists()?;
+        self.mount_store_if_exists()?;
         Logger::set_file_output("/run/phinit.log")
             .map_err(Error::OpenLogFailed)?;
         Ok(())
     }
 
+    /// Add the module signing key of the realm to the kernel keyring before
+    /// anything runs which could load a module.
+    pub fn install_module_key(&self) -> ModuleKeyStatus {
+        match self.module_key {
+            Some(ref key) => key.install(),
+            None => ModuleKeyStatus::None,
+        }
+    }
+
+    // There is no udev in the guest to create the /dev/virtio-ports/NAME
+    // links to named console ports.
+    fn link_console_ports() {
+        let entries = match fs::read_dir("/sys/class/virtio-ports") {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        if let Err(err) = mkdir("/dev/virtio-ports") {
+            warn!("{}", err);
+            return;
+        }
+        for entry in entries.flatten() {
+            let name = fs::read_to_string(entry.path().join("name")).unwrap_or_default();
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let link = Path::new("/dev/virtio-ports").join(name);
+            if let Err(err) = std::os::unix::fs::symlink(Path::new("..").join(entry.file_name()), &link) {
+                warn!("Failed to create {}: {}", link.display(), err);
+            }
+        }
+    }
+
     fn setup_readonly_root(&self) -> Result<()> {
         create_directories(&[
             "/tmp/ro",
             "/tmp/rw",
-            "/tmp/rw/upper",
-            "/tmp/rw/work",
         ])?;
-        mount_tmpfs("/tmp/rw")?;
-        create_directories(&["/tmp/rw/upper", "/tmp/rw/work"])?;
         self.rootfs.mount("/tmp/ro")?;
+        self.mount_upper_layer()?;
+        Self::prepare_upper_directories()?;
         mount_overlay("/tmp/sysroot",
                       "lowerdir=/tmp/ro,upperdir=/tmp/rw/upper,workdir=/tmp/rw/work")?;
         create_directories(&[
@@ -151,6 +199,83 @@ impl InitServer {
         Ok(())
     }
 
+    // The upper layer of the overlay is mounted at /tmp/rw and is either a
+    // tmpfs (the default), a block device (phinit.upper=/dev/vdb), or a
+    // directory on the home share (phinit.upper=home:path/to/dir).
+    fn mount_upper_layer(&self) -> Result<()> {
+        let upper = match self.cmdline.lookup("phinit.upper") {
+            Some(upper) => upper,
+            None => return mount_tmpfs("/tmp/rw"),
+        };
+
+        if upper.starts_with("/dev/") {
+            self.fsck_upper_device(&upper);
+            mount(&upper, "/tmp/rw", "ext4", libc::MS_NOATIME, None)
+                .map_err(|e| Error::UpperMount(upper.clone(), e))
+        } else if upper.starts_with("home:") {
+            let subdir = upper.trim_start_matches("home:").trim_start_matches('/');
+            mkdir("/tmp/home")?;
+            self.mount_home_share("/tmp/home")?;
+            let path = Path::new("/tmp/home").join(subdir);
+            fs::create_dir_all(&path)
+                .map_err(|e| Error::MkDir(path.display().to_string(), e))?;
+            mount(&path.display().to_string(), "/tmp/rw", "", libc::MS_BIND, None)
+                .map_err(|e| Error::UpperMount(upper.clone(), e))?;
+            umount("/tmp/home")
+        } else {
+            warn!("Unrecognized value for phinit.upper: {}, using tmpfs", upper);
+            mount_tmpfs("/tmp/rw")
+        }
+    }
+
+    // The boot filesystem does not contain fsck, so run it from the read-only root
+    fn fsck_upper_device(&self, device: &str) {
+        if let Err(err) = mount("devtmpfs", "/tmp/ro/dev", "devtmpfs", libc::MS_NOSUID|libc::MS_NOEXEC, None) {
+            warn!("Could not mount /dev for fsck: {}", err);
+            return;
+        }
+        let status = unsafe {
+            Command::new("/sbin/fsck")
+                .arg("-p")
+                .arg(device)
+                .pre_exec(|| {
+                    _chroot("/tmp/ro")?;
+                    env::set_current_dir("/")
+                })
+                .status()
+        };
+        match status.map(|s| s.code()) {
+            Ok(Some(0)) => {},
+            Ok(Some(1)) => info!("fsck corrected errors on {}", device),
+            Ok(code) => warn!("fsck on {} failed with exit status {:?}", device, code),
+            Err(err) => warn!("Failed to run fsck on {}: {}", device, err),
+        }
+        if let Err(err) = umount("/tmp/ro/dev") {
+            warn!("{}", err);
+        }
+    }
+
+    // Overlayfs requires an empty work directory on the same filesystem as the
+    // upper directory. A persistent upper layer may contain a work directory
+    // left over from an earlier boot, so remove anything found in it.
+    fn prepare_upper_directories() -> Result<()> {
+        for dir in &["/tmp/rw/upper", "/tmp/rw/work"] {
+            if !Path::new(dir).exists() {
+                mkdir(dir)?;
+            }
+        }
+        for entry in fs::read_dir("/tmp/rw/work").map_err(Error::CleanWorkDir)? {
+            let path = entry.map_err(Error::CleanWorkDir)?.path();
+            let result = if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            result.map_err(Error::CleanWorkDir)?;
+        }
+        Ok(())
+    }
+
     fn setup_writeable_root(&self) -> Result<()> {
         self.rootfs.mount("/tmp/sysroot")?;
 
@@ -164,6 +289,9 @@ impl InitServer {
     }
 
     fn has_9p_home(&self) -> bool {
+        if self.cmdline.has_var("phinit.no_home") {
+            return false;
+        }
         // XXX
         // /sys/bus/virtio/drivers/9pnet_virtio/virtio*/mount_tag
         true
@@ -175,11 +303,31 @@ impl InitServer {
             if !homedir.exists() {
                 mkdir(homedir)?;
             }
-            mount_9p("home", self.homedir())?;
+            self.mount_home_share(self.homedir())?;
         }
         Ok(())
     }
 
+    // A read-only store such as /nix is exported with the tag "store" and
+    // mounted at the path it has on the host
+    fn mount_store_if_exists(&self) -> Result<()> {
+        if let Some(store) = self.cmdline.lookup("phinit.store") {
+            if !Path::new(&store).exists() {
+                mkdir(&store)?;
+            }
+            mount_9p_readonly("store", &store)?;
+        }
+        Ok(())
+    }
+
+    // The home share is 9p unless pH exports it with virtio-fs
+    fn mount_home_share(&self, target: &str) -> Result<()> {
+        match self.cmdline.lookup("phinit.home_fs") {
+            Some(ref fs) if fs == "virtiofs" => mount_virtiofs("home", target),
+            _ => mount_9p("home", target),
+        }
+    }
+
 
     pub fn run_daemons(&mut self) -> Result<()> {
         if !Path::new("/dev/wl0").exists() {
@@ -188,23 +336,23 @@ impl InitServer {
 
         chmod("/dev/wl0", 0o666)?;
 
+        let mut manifest = ServiceManifest::new();
+
         let dbus = ServiceLaunch::new("dbus-daemon", "/usr/bin/dbus-daemon")
             .base_environment()
-            .uidgid(1000,1000)
+            .session_user(&self.user)
             .env("HOME", self.homedir())
             .env("NO_AT_BRIDGE", "1")
             .env("QT_ACCESSIBILITY", "1")
             .env("SHELL", "/bin/bash")
-            .env("USER", "user")
             .env("WAYLAND_DISPLAY", "wayland-0")
             .arg("--session")
             .arg("--nosyslog")
-            .arg("--address=unix:path=/run/user/1000/bus")
+            .arg(format!("--address={}", self.user.dbus_address()))
             .arg("--print-address")
-            .pipe_output()
-            .launch()?;
+            .pipe_output();
 
-        self.services.insert(dbus.pid(), dbus);
+        manifest.add(dbus);
 
         let shm_driver = if self.cmdline.has_var("phinit.virtwl_dmabuf") {
             "virtwl-dmabuf" 
@@ -212,40 +360,61 @@ impl InitServer {
             "virtwl"
         };
 
-        let sommelier = ServiceLaunch::new("sommelier", "/opt/ph/usr/bin/sommelier")
-            .base_environment()
-            .uidgid(1000,1000)
-            .env("SOMMELIER_SHM_DRIVER", shm_driver)
-            .arg("--master")
-            .pipe_output()
-            .launch()?;
-
-        self.services.insert(sommelier.pid(), sommelier);
+        // Windows are tagged so that they can be told apart from those of
+        // other VMs on the same host compositor
+        let tag = self.cmdline.lookup("phinit.wayland_tag");
 
+        for service in manifest.launch()? {
+            self.services.insert(service.pid(), service);
+        }
 
-        if self.cmdline.has_var("phinit.no_x11") {
-            return Ok(());
+        let x11 = !self.cmdline.has_var("phinit.no_x11");
+        if x11 {
+            mkdir_mode("/tmp/.X11-unix", 0o1777)?;
+            self.write_xauth().map_err(Error::XAuthFail)?;
         }
 
-        mkdir_mode("/tmp/.X11-unix", 0o1777)?;
-        self.write_xauth().map_err(Error::XAuthFail)?;
+        // The sommelier services are launched again from the same manifest
+        // when the host compositor restarts
+        let user = self.user.clone();
+        let home = self.homedir().to_string();
+        let socket = PathBuf::from(format!("{}/wayland-0", self.user.runtime_dir()));
+        let ready_socket = socket.clone();
+        wayland::start(move || {
+            let mut manifest = ServiceManifest::new();
+            let sommelier = ServiceLaunch::new("sommelier", "/opt/ph/usr/bin/sommelier")
+                .base_environment()
+                .session_user(&user)
+                .env("SOMMELIER_SHM_DRIVER", shm_driver)
+                .arg("--master")
+                .optional_arg(tag.as_ref().map(|tag| format!("--vm-identifier={}", tag)))
+                .pipe_output()
+                .ready_when(Readiness::Socket(ready_socket.clone()));
+            manifest.add(sommelier);
+            if x11 {
+                Self::add_sommelier_x(&mut manifest, &user, &home, shm_driver, tag.as_ref());
+            }
+            manifest
+        }, socket)
+    }
 
+    // X11 clients are served by a second sommelier which connects to the
+    // wayland socket of the first one, so it must not start before it exists.
+    fn add_sommelier_x(manifest: &mut ServiceManifest, user: &SessionUser, home: &str, shm_driver: &str, tag: Option<&String>) {
         let sommelierx = ServiceLaunch::new("sommelier-x", "/opt/ph/usr/bin/sommelier")
             .base_environment()
-            .uidgid(1000,1000)
+            .session_user(user)
             .env("SOMMELIER_SHM_DRIVER", shm_driver)
             .arg("-X")
             .arg("--x-display=0")
             .arg("--no-exit-with-child")
-            .arg(format!("--x-auth={}/.Xauthority", self.homedir()))
+            .optional_arg(tag.map(|tag| format!("--vm-identifier={}", tag)))
+            .arg(format!("--x-auth={}/.Xauthority", home))
             .arg("/bin/true")
             .pipe_output()
-            .launch()?;
-
-
-        self.services.insert(sommelierx.pid(), sommelierx);
+            .requires("sommelier");
 
-        Ok(())
+        manifest.add(sommelierx);
     }
 
     pub fn setup_network(&self) -> Result<()> {
@@ -297,17 +466,25 @@ impl InitServer {
         v.extend_from_slice(&randbuf);
 
         fs::write(&xauth_path, v)?;
-        _chown(&xauth_path, 1000, 1000)?;
+        _chown(&xauth_path, self.user.uid(), self.user.gid())?;
         Ok(())
     }
 
     pub fn launch_console_shell(&mut self, splash: &'static str) -> Result<()> {
+        if self.cmdline.has_var("phinit.selftest") {
+            selftest::run(self.homedir());
+            return Self::power_off()
+                .map_err(Error::RebootFailed);
+        }
+        if let Some(run) = self.cmdline.lookup("phinit.run") {
+            return self.launch_console_program(&run);
+        }
         fs::write("/run/bashrc", BASHRC).map_err(Error::WriteBashrc)?;
         let root = self.cmdline.has_var("phinit.rootshell");
         let realm = self.cmdline.lookup("phinit.realm");
         let home = if root { "/".to_string() } else { self.homedir().to_string() };
 
-        let shell = ServiceLaunch::new_shell(root, &home, realm)
+        let shell = ServiceLaunch::new_shell(&self.user, root, &home, realm)
             .arg("--rcfile").arg("/run/bashrc")
             .launch_with_preexec(move || {
 //                set_controlling_tty(0, true)?;
@@ -319,11 +496,27 @@ impl InitServer {
         Ok(())
     }
 
+    // Run a single program on the console in place of the shell, as root
+    // since a minimal root has no user account.
+    fn launch_console_program(&mut self, path: &str) -> Result<()> {
+        let program = ServiceLaunch::new("shell", path)
+            .root(true)
+            .home("/")
+            .env("HOME", "/")
+            .base_environment()
+            .launch_with_preexec(|| {
+                env::set_current_dir("/")?;
+                Ok(())
+            })?;
+        self.services.insert(program.pid(), program);
+        Ok(())
+    }
+
     fn wait_for_next_child(&mut self) -> Result<()> {
         if let Some(child) = self.wait_for_child() {
             info!("Service exited: {}", child.name());
             if child.name() == "shell" {
-                reboot(libc::RB_AUTOBOOT)
+                Self::power_off()
                     .map_err(Error::RebootFailed)?;
             }
         }
@@ -336,10 +529,17 @@ impl InitServer {
         }
     }
 
+    // The kernel has no ACPI so it cannot power off and halts instead. Tell
+    // the host that the reset which follows is a power off, not a reboot.
+    pub fn power_off() -> io::Result<()> {
+        agent::send("power-off", "");
+        reboot(libc::RB_AUTOBOOT)
+    }
+
     fn handle_waitpid_err(err: io::Error) -> ! {
         if let Some(errno) = err.raw_os_error() {
             if errno == libc::ECHILD {
-                if let Err(err) = reboot(libc::RB_AUTOBOOT) {
+                if let Err(err) = Self::power_off() {
                     warn!("reboot() failed: {:?}", err);
                     process::exit(-1);
                 }
@@ -351,7 +551,8 @@ impl InitServer {
 
     fn wait_for_child(&mut self) -> Option<Service> {
         match waitpid(-1, 0) {
-            Ok((pid,_status)) => self.services.remove(&(pid as u32)),
+            Ok((pid,_status)) => self.services.remove(&(pid as u32))
+                .or_else(|| wayland::reap(pid as u32)),
             Err(err) => Self::handle_waitpid_err(err)
         }
     }
@@ -5,26 +5,49 @@ extern crate lazy_static;
 mod log;
 mod error;
 mod cmdline;
+mod vars;
 mod service;
 mod init;
 mod sys;
 mod netlink;
+mod user;
+mod agent;
+mod pressure;
+mod security;
+mod selftest;
+mod notify;
+mod wayland;
 
 pub use error::{Error,Result};
 pub use log::{Logger,LogLevel};
 use crate::init::InitServer;
+use crate::pressure::PressureMonitor;
+use crate::notify::NotifyServer;
+use std::{env, process};
 
 fn run_init() -> Result<()> {
     let mut server = InitServer::create("airwolf")?;
     server.setup_filesystem()?;
+    let module_key = server.install_module_key();
     server.run_daemons()?;
     server.setup_network()?;
+    if let Err(err) = agent::connect() {
+        warn!("Failed to open agent port: {}", err);
+    }
+    agent::listen();
+    security::report_status(module_key);
+    PressureMonitor::start();
+    NotifyServer::start();
     server.launch_console_shell(SPLASH)?;
     server.run()?;
     Ok(())
 }
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(|s| s.as_str()) == Some("notify") {
+        process::exit(notify::notify_command(&args[1..]));
+    }
     if let Err(err) = run_init() {
         warn!("ph-init error: {}", err);
     }
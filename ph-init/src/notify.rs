@@ -0,0 +1,150 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+use crate::agent;
+
+// Socket on which guest programs ask for a notification to be shown on the host
+const NOTIFY_SOCKET: &str = "/run/ph/notify";
+
+// Longer titles and bodies are cut so that the event fits in one line of
+// the agent port even when every byte is escaped
+const MAX_TITLE: usize = 200;
+const MAX_BODY: usize = 1000;
+const MAX_REQUEST: u64 = 4096;
+
+const URGENCIES: &[&str] = &["low", "normal", "critical"];
+
+///
+/// Passes desktop notifications from programs in the guest to the host as
+/// `notify` events on the `ph.agent` port.
+///
+/// A request is the urgency, the title and the body separated by newlines,
+/// written to `/run/ph/notify` by `ph-init notify`. The title and body are
+/// sent with `%`, `=`, whitespace and control characters escaped as `%XX`:
+///
+///     notify urgency=normal title=Build%20done body=make%20finished%20in%2012m
+///
+/// The host decides whether to show notifications and how often.
+///
+pub struct NotifyServer {
+    listener: UnixListener,
+}
+
+impl NotifyServer {
+    /// Listen for requests on a new thread. Does nothing if the host did not
+    /// create an agent port.
+    pub fn start() {
+        if !agent::is_connected() {
+            return;
+        }
+        match Self::bind() {
+            Ok(server) => { thread::spawn(move || server.run()); },
+            Err(err) => warn!("Notification socket not created: {}", err),
+        }
+    }
+
+    fn bind() -> io::Result<Self> {
+        let path = Path::new(NOTIFY_SOCKET);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        // Any user in the guest may raise a notification
+        fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+        Ok(NotifyServer { listener })
+    }
+
+    fn run(self) {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => if let Err(err) = Self::handle(stream) {
+                    warn!("Error reading notification request: {}", err);
+                },
+                Err(err) => warn!("Error accepting notification request: {}", err),
+            }
+        }
+    }
+
+    fn handle(stream: UnixStream) -> io::Result<()> {
+        let mut request = String::new();
+        stream.take(MAX_REQUEST).read_to_string(&mut request)?;
+        let mut parts = request.splitn(3, '\n');
+        let urgency = parts.next().unwrap_or("");
+        let title = parts.next().unwrap_or("");
+        let body = parts.next().unwrap_or("");
+        if !URGENCIES.contains(&urgency) || title.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected urgency and title"));
+        }
+        let fields = format!("urgency={} title={} body={}",
+                             urgency, escape(truncate(title, MAX_TITLE)), escape(truncate(body, MAX_BODY)));
+        agent::send("notify", &fields);
+        Ok(())
+    }
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '%' || c == '=' || c.is_whitespace() || c.is_control() {
+            let mut buf = [0u8; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("%{:02X}", b));
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn notify_usage() -> i32 {
+    eprintln!("Usage: ph-init notify [--urgency low|normal|critical] TITLE [BODY]");
+    eprintln!();
+    eprintln!("Show a desktop notification on the host.");
+    2
+}
+
+/// `ph-init notify [--urgency low|normal|critical] TITLE [BODY]`, run by
+/// programs in the guest. Returns the exit code.
+pub fn notify_command(args: &[String]) -> i32 {
+    let (urgency, args) = match args.split_first() {
+        Some((opt, rest)) if opt == "--urgency" => match rest.split_first() {
+            Some((urgency, rest)) if URGENCIES.contains(&urgency.as_str()) => (urgency.as_str(), rest),
+            _ => return notify_usage(),
+        },
+        _ => ("normal", args),
+    };
+    let (title, body) = match args {
+        [title] => (title.as_str(), ""),
+        [title, body] => (title.as_str(), body.as_str()),
+        _ => return notify_usage(),
+    };
+    if title.is_empty() || title.contains('\n') {
+        return notify_usage();
+    }
+    let result = UnixStream::connect(NOTIFY_SOCKET).and_then(|mut stream| {
+        stream.write_all(format!("{}\n{}\n{}", urgency, title, body).as_bytes())?;
+        stream.shutdown(Shutdown::Write)
+    });
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Failed to send notification to {}: {}", NOTIFY_SOCKET, err);
+            1
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::agent;
+
+const PSI_MEMORY: &str = "/proc/pressure/memory";
+
+// Notify when tasks are stalled on memory for 150ms in any 1s window
+const PSI_TRIGGER: &str = "some 150000 1000000";
+
+// A pressure event is reported at most this often
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Pressure has subsided when the trigger has not fired for this long
+const CLEAR_INTERVAL: Duration = Duration::from_secs(10);
+
+///
+/// Watches memory pressure in the guest and forwards events to the host over
+/// the `ph.agent` virtio console port so that the host can reclaim memory
+/// elsewhere or warn the user before the OOM killer runs.
+///
+/// Events are sent as one line of text each, an event name followed by
+/// `key=value` fields:
+///
+///     memory-pressure some_avg10=23.51 full_avg10=4.02 mem_available_kb=51200
+///     memory-pressure-cleared some_avg10=0.85 full_avg10=0.00 mem_available_kb=803412
+///     oom-kill count=1
+///
+pub struct PressureMonitor {
+    psi: File,
+    oom_kills: u64,
+    last_report: Option<Instant>,
+    last_trigger: Option<Instant>,
+}
+
+impl PressureMonitor {
+    /// Start monitoring on a new thread. Does nothing if the host did not
+    /// create an agent port or the kernel does not support PSI.
+    pub fn start() {
+        let monitor = match Self::open() {
+            Ok(Some(monitor)) => monitor,
+            Ok(None) => return,
+            Err(err) => {
+                warn!("Memory pressure monitor not started: {}", err);
+                return;
+            }
+        };
+        thread::spawn(move || monitor.run());
+    }
+
+    fn open() -> io::Result<Option<Self>> {
+        if !agent::is_connected() {
+            return Ok(None);
+        }
+        if !Path::new(PSI_MEMORY).exists() {
+            info!("Kernel does not support PSI, memory pressure will not be reported");
+            return Ok(None);
+        }
+        let mut psi = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(PSI_MEMORY)?;
+        // The kernel expects the trigger to be terminated with a nul byte
+        psi.write_all(format!("{}\0", PSI_TRIGGER).as_bytes())?;
+
+        Ok(Some(PressureMonitor {
+            psi,
+            oom_kills: Self::read_oom_kills(),
+            last_report: None,
+            last_trigger: None,
+        }))
+    }
+
+    fn run(mut self) {
+        loop {
+            match self.wait_trigger(CLEAR_INTERVAL) {
+                Ok(true) => self.on_trigger(),
+                Ok(false) => self.on_timeout(),
+                Err(err) => {
+                    warn!("Memory pressure monitor stopped: {}", err);
+                    return;
+                }
+            }
+            self.check_oom_kills();
+        }
+    }
+
+    fn wait_trigger(&self, timeout: Duration) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.psi.as_raw_fd(),
+            events: libc::POLLPRI,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        if pollfd.revents & libc::POLLERR != 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "PSI trigger removed"));
+        }
+        Ok(pollfd.revents & libc::POLLPRI != 0)
+    }
+
+    fn on_trigger(&mut self) {
+        let now = Instant::now();
+        self.last_trigger = Some(now);
+        if self.last_report.map(|t| now.duration_since(t) < REPORT_INTERVAL).unwrap_or(false) {
+            return;
+        }
+        self.last_report = Some(now);
+        let fields = Self::pressure_fields();
+        agent::send("memory-pressure", &fields);
+    }
+
+    fn on_timeout(&mut self) {
+        if self.last_trigger.take().is_some() {
+            self.last_report = None;
+            let fields = Self::pressure_fields();
+            agent::send("memory-pressure-cleared", &fields);
+        }
+    }
+
+    fn check_oom_kills(&mut self) {
+        let count = Self::read_oom_kills();
+        if count > self.oom_kills {
+            self.oom_kills = count;
+            agent::send("oom-kill", &format!("count={}", count));
+        }
+    }
+
+    fn pressure_fields() -> String {
+        let psi = fs::read_to_string(PSI_MEMORY).unwrap_or_default();
+        format!("some_avg10={} full_avg10={} mem_available_kb={}",
+                Self::psi_avg10(&psi, "some").unwrap_or("0.00"),
+                Self::psi_avg10(&psi, "full").unwrap_or("0.00"),
+                Self::mem_available_kb().unwrap_or(0))
+    }
+
+    // Lines of /proc/pressure/memory look like:
+    //
+    //     some avg10=0.00 avg60=0.00 avg300=0.00 total=0
+    //
+    fn psi_avg10<'a>(psi: &'a str, kind: &str) -> Option<&'a str> {
+        psi.lines()
+            .find(|line| line.starts_with(kind))
+            .and_then(|line| line.split_whitespace().find(|f| f.starts_with("avg10=")))
+            .map(|f| f.trim_start_matches("avg10="))
+    }
+
+    fn mem_available_kb() -> Option<u64> {
+        let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+        meminfo.lines()
+            .find(|line| line.starts_with("MemAvailable:"))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|kb| kb.parse().ok())
+    }
+
+    fn read_oom_kills() -> u64 {
+        fs::read_to_string("/proc/vmstat").ok()
+            .and_then(|vmstat| vmstat.lines()
+                .find(|line| line.starts_with("oom_kill "))
+                .and_then(|line| line.split_whitespace().nth(1))
+                .and_then(|n| n.parse().ok()))
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::agent;
+use crate::cmdline::CmdLine;
+use crate::sys;
+
+// pH puts the module signing certificate of the realm here on the boot filesystem
+const MODULE_KEY_PATH: &str = "/etc/ph/module-key.der";
+
+// Certificates added to this keyring must be signed by a key which is built
+// into the kernel or already on the keyring.
+const SECONDARY_KEYRING: &str = ".secondary_trusted_keys";
+
+const SECURITYFS: &str = "/sys/kernel/security";
+const LOCKDOWN_PATH: &str = "/sys/kernel/security/lockdown";
+const SIG_ENFORCE_PATH: &str = "/sys/module/module/parameters/sig_enforce";
+
+///
+/// An X.509 certificate for the key which out-of-tree modules of the realm
+/// are signed with.
+///
+/// A custom guest kernel built with `CONFIG_SECONDARY_TRUSTED_KEYRING` accepts
+/// the certificate if it is signed by the key the kernel was built with, much
+/// like a machine owner key. Modules signed with the realm key can then be
+/// loaded even when the kernel enforces module signatures or is locked down.
+///
+pub struct ModuleKey {
+    cert: Vec<u8>,
+}
+
+impl ModuleKey {
+    /// Read the certificate while the boot filesystem is still the root.
+    pub fn load(cmdline: &CmdLine) -> Option<Self> {
+        if !cmdline.has_var("phinit.module_key") {
+            return None;
+        }
+        match fs::read(MODULE_KEY_PATH) {
+            Ok(cert) => Some(ModuleKey { cert }),
+            Err(err) => {
+                warn!("Failed to read module signing key {}: {}", MODULE_KEY_PATH, err);
+                None
+            }
+        }
+    }
+
+    /// Add the certificate to the secondary trusted keyring. Needs `/proc`.
+    pub fn install(&self) -> ModuleKeyStatus {
+        let keyring = match find_keyring(SECONDARY_KEYRING) {
+            Ok(Some(keyring)) => keyring,
+            Ok(None) => {
+                warn!("Cannot add module signing key because the kernel has no {} keyring", SECONDARY_KEYRING);
+                return ModuleKeyStatus::Unsupported;
+            }
+            Err(err) => {
+                warn!("Failed to read /proc/keys: {}", err);
+                return ModuleKeyStatus::Unsupported;
+            }
+        };
+        match sys::add_key("asymmetric", &self.cert, keyring) {
+            Ok(_) => {
+                info!("Added module signing key to {}", SECONDARY_KEYRING);
+                ModuleKeyStatus::Loaded
+            }
+            Err(err) => {
+                warn!("Module signing key was rejected by the kernel: {}", err);
+                ModuleKeyStatus::Rejected
+            }
+        }
+    }
+}
+
+#[derive(Clone,Copy)]
+pub enum ModuleKeyStatus {
+    None,
+    Loaded,
+    Rejected,
+    Unsupported,
+}
+
+impl ModuleKeyStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            ModuleKeyStatus::None => "none",
+            ModuleKeyStatus::Loaded => "loaded",
+            ModuleKeyStatus::Rejected => "rejected",
+            ModuleKeyStatus::Unsupported => "unsupported",
+        }
+    }
+}
+
+// The serial number of a keyring in /proc/keys, where a line looks like:
+//
+//     2b3bc6d1 I------     1 perm 1f0f0000     0     0 keyring   .secondary_trusted_keys: 2
+//
+fn find_keyring(name: &str) -> io::Result<Option<i32>> {
+    let keys = fs::read_to_string("/proc/keys")?;
+    for line in keys.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() > 8 && fields[7] == "keyring" && fields[8].trim_end_matches(':') == name {
+            if let Ok(serial) = u32::from_str_radix(fields[0], 16) {
+                return Ok(Some(serial as i32));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// The active mode from a line like "none [integrity] confidentiality"
+fn lockdown_mode() -> &'static str {
+    if !Path::new(LOCKDOWN_PATH).exists() && Path::new(SECURITYFS).exists() {
+        let _ = sys::mount("securityfs", SECURITYFS, "securityfs", 0, None);
+    }
+    let modes = match fs::read_to_string(LOCKDOWN_PATH) {
+        Ok(modes) => modes,
+        // Linux 5.3 and earlier, or a kernel without the lockdown LSM
+        Err(_) => return "unsupported",
+    };
+    let active = modes.split_whitespace()
+        .find(|m| m.starts_with('['))
+        .map(|m| m.trim_matches(|c| c == '[' || c == ']'));
+    match active {
+        Some("none") => "none",
+        Some("integrity") => "integrity",
+        Some("confidentiality") => "confidentiality",
+        _ => "unknown",
+    }
+}
+
+// Y if only signed modules can be loaded, missing if modules are not supported
+fn sig_enforce() -> u32 {
+    match fs::read_to_string(SIG_ENFORCE_PATH) {
+        Ok(ref s) if s.trim() == "Y" => 1,
+        _ => 0,
+    }
+}
+
+/// Send a `security` event to the host with the lockdown mode of the guest
+/// kernel, whether module signatures are enforced, and what happened to
+/// the module signing key:
+///
+///     security lockdown=integrity sig_enforce=1 module_key=loaded
+///
+pub fn report_status(module_key: ModuleKeyStatus) {
+    let fields = format!("lockdown={} sig_enforce={} module_key={}",
+                         lockdown_mode(), sig_enforce(), module_key.as_str());
+    info!("Guest kernel security: {}", fields);
+    agent::send("security", &fields);
+}
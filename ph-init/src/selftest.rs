@@ -0,0 +1,188 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::agent;
+
+const HWRNG: &str = "/dev/hwrng";
+const HWRNG_CURRENT: &str = "/sys/class/misc/hw_random/rng_current";
+
+// Block 0 of the scratch disk starts with this signature followed by bytes
+// of (offset % 251). The check writes bytes of (offset % 241) to block 1.
+const DISK_MAGIC: &[u8] = b"pH-selftest-disk";
+const DISK_BLOCK_SIZE: usize = 4096;
+
+// Directory in the home share which holds a file written by the host
+const SHARE_DIR: &str = "ph-selftest";
+const HOST_FILE: &str = "host.txt";
+const HOST_FILE_CONTENTS: &str = "written by the host\n";
+const GUEST_FILE: &str = "guest.txt";
+const GUEST_FILE_CONTENTS: &str = "written by the guest\n";
+
+// Files larger than the msize of the share, so that each read and write
+// request carries as much data as the mount allows, spread over many guest
+// pages. The host writes bytes of (offset % 239) to one and the guest
+// writes bytes of (offset % 233) to the other.
+const LARGE_HOST_FILE: &str = "large-host.bin";
+const LARGE_GUEST_FILE: &str = "large-guest.bin";
+const LARGE_FILE_SIZE: usize = 4 * 1024 * 1024;
+
+// The host connects to the socket of this port and expects its line back
+const ECHO_PORT: &str = "/dev/virtio-ports/ph.selftest";
+
+///
+/// Exercise each virtio device from the guest in place of a shell when pH
+/// runs its integration tests, which it asks for with `phinit.selftest`.
+///
+/// The result of each check is sent to the host over the agent port,
+/// followed by a summary once every check has run:
+///
+///     selftest device=rng result=pass
+///     selftest device=blk result=fail
+///     selftest-done passed=3 failed=1
+///
+/// Why a check failed is logged on the console. The host sets up the
+/// scratch disk, the files in the home share and the echo port, and checks
+/// what the guest wrote after the VM has powered off.
+///
+pub fn run(homedir: &str) {
+    let share = Path::new(homedir).join(SHARE_DIR);
+    let results = [
+        report("rng", check_rng()),
+        report("blk", check_block()),
+        report("9p", check_share(&share)),
+        report("serial", check_echo()),
+    ];
+    let failed = results.iter().filter(|&&ok| !ok).count();
+    agent::send("selftest-done", &format!("passed={} failed={}", results.len() - failed, failed));
+}
+
+fn report(device: &str, result: io::Result<()>) -> bool {
+    let ok = match result {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("selftest: {} check failed: {}", device, err);
+            false
+        }
+    };
+    let fields = format!("device={} result={}", device, if ok { "pass" } else { "fail" });
+    agent::send("selftest", &fields);
+    ok
+}
+
+fn failure<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message.into())
+}
+
+fn check_rng() -> io::Result<()> {
+    let current = fs::read_to_string(HWRNG_CURRENT)?;
+    if !current.starts_with("virtio_rng") {
+        return Err(failure(format!("hardware RNG is {}", current.trim())));
+    }
+    let mut buf = [0u8; 64];
+    File::open(HWRNG)?.read_exact(&mut buf)?;
+    if buf.iter().all(|&b| b == 0) {
+        return Err(failure("read only zero bytes"));
+    }
+    Ok(())
+}
+
+fn check_block() -> io::Result<()> {
+    let path = find_scratch_disk()?
+        .ok_or_else(|| failure("no disk starts with the selftest signature"))?;
+    let mut disk = OpenOptions::new().read(true).write(true).open(&path)?;
+    let mut block = vec![0u8; DISK_BLOCK_SIZE];
+    disk.read_exact(&mut block)?;
+    if (DISK_MAGIC.len()..DISK_BLOCK_SIZE).any(|i| block[i] != (i % 251) as u8) {
+        return Err(failure(format!("block 0 of {} does not hold the expected pattern", path.display())));
+    }
+
+    let pattern: Vec<u8> = (0..DISK_BLOCK_SIZE).map(|i| (i % 241) as u8).collect();
+    disk.seek(SeekFrom::Start(DISK_BLOCK_SIZE as u64))?;
+    disk.write_all(&pattern)?;
+    disk.sync_all()?;
+
+    // Read the block from the device again rather than the page cache
+    fs::write("/proc/sys/vm/drop_caches", "1")?;
+    disk.seek(SeekFrom::Start(DISK_BLOCK_SIZE as u64))?;
+    disk.read_exact(&mut block)?;
+    if block != pattern {
+        return Err(failure(format!("block 1 of {} reads back differently than it was written", path.display())));
+    }
+    Ok(())
+}
+
+// Only a disk which starts with the signature is written to
+fn find_scratch_disk() -> io::Result<Option<PathBuf>> {
+    for entry in fs::read_dir("/sys/block")? {
+        let name = entry?.file_name();
+        if !name.to_string_lossy().starts_with("vd") {
+            continue;
+        }
+        let path = Path::new("/dev").join(&name);
+        let mut magic = vec![0u8; DISK_MAGIC.len()];
+        if File::open(&path).and_then(|mut f| f.read_exact(&mut magic)).is_ok() && magic == DISK_MAGIC {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+fn check_share(dir: &Path) -> io::Result<()> {
+    if fs::read_to_string(dir.join(HOST_FILE))? != HOST_FILE_CONTENTS {
+        return Err(failure(format!("{} does not hold what the host wrote", HOST_FILE)));
+    }
+
+    let tmp = dir.join("guest.tmp");
+    let guest = dir.join(GUEST_FILE);
+    fs::write(&tmp, GUEST_FILE_CONTENTS)?;
+    fs::rename(&tmp, &guest)?;
+    if fs::metadata(&guest)?.len() != GUEST_FILE_CONTENTS.len() as u64 {
+        return Err(failure(format!("{} has the wrong size after rename", GUEST_FILE)));
+    }
+    if fs::read_to_string(&guest)? != GUEST_FILE_CONTENTS {
+        return Err(failure(format!("{} reads back differently than it was written", GUEST_FILE)));
+    }
+
+    check_large_files(dir)?;
+
+    let scratch = dir.join("scratch");
+    fs::create_dir(&scratch)?;
+    fs::write(scratch.join("file"), "x")?;
+    if fs::read_dir(&scratch)?.count() != 1 {
+        return Err(failure("directory listing does not show the new file"));
+    }
+    fs::remove_dir_all(&scratch)?;
+    if scratch.exists() {
+        return Err(failure("directory still exists after it was removed"));
+    }
+    Ok(())
+}
+
+fn check_large_files(dir: &Path) -> io::Result<()> {
+    // One read for the whole file, which the kernel splits into requests of
+    // up to msize bytes
+    let mut data = vec![0u8; LARGE_FILE_SIZE];
+    File::open(dir.join(LARGE_HOST_FILE))?.read_exact(&mut data)?;
+    if let Some(offset) = (0..LARGE_FILE_SIZE).find(|&i| data[i] != (i % 239) as u8) {
+        return Err(failure(format!("{} differs from what the host wrote at offset {}", LARGE_HOST_FILE, offset)));
+    }
+
+    let pattern: Vec<u8> = (0..LARGE_FILE_SIZE).map(|i| (i % 233) as u8).collect();
+    let mut file = File::create(dir.join(LARGE_GUEST_FILE))?;
+    file.write_all(&pattern)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Blocks until the host has connected to the port and sent a line
+fn check_echo() -> io::Result<()> {
+    let port = OpenOptions::new().read(true).write(true).open(ECHO_PORT)?;
+    let mut line = String::new();
+    BufReader::new(&port).read_line(&mut line)?;
+    if line.is_empty() {
+        return Err(failure("port closed before a line arrived"));
+    }
+    (&port).write_all(line.as_bytes())?;
+    Ok(())
+}
@@ -1,10 +1,14 @@
 use std::process::{Command, Child, Stdio};
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::process::CommandExt;
 use std::path::{PathBuf, Path};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use crate::{Result, Error};
-use std::{io, thread, env};
+use std::{fs, io, mem, thread, env};
 use crate::sys::_setsid;
+use crate::user::SessionUser;
 use std::io::{Read, BufReader, BufRead};
 use std::thread::JoinHandle;
 
@@ -17,7 +21,6 @@ enum StdioMode {
 const BASE_ENVIRONMENT: &[&str] = &[
     "LANG=en_US.UTF8",
     "LC_COLLATE=C",
-    "XDG_RUNTIME_DIR=/run/user/1000",
 ];
 
 const SHELL_ENVIRONMENT: &[&str] = &[
@@ -31,9 +34,12 @@ const SHELL_ENVIRONMENT: &[&str] = &[
     "XDG_SESSION_TYPE=wayland",
     "GDK_BACKEND=wayland",
     "WAYLAND_DISPLAY=wayland-0",
-    "DBUS_SESSION_BUS_ADDRESS=unix:path=/run/user/1000/bus",
 ];
 
+// How long to wait for a service to become ready before the services which
+// come after it are started anyway, or skipped if they require it.
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 pub struct Service {
     name: String,
@@ -59,6 +65,14 @@ impl Service {
         self.child.id()
     }
 
+    // Kill a service which will not be handed over to the main loop of init
+    // and wait for it to exit.
+    fn kill(mut self) {
+        warn!("Stopping {}", self.name);
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
     fn log_output(&mut self) {
         if let Some(c) = self.child.stdout.take() {
             self.add_logger(ServiceLogger::new(&self.name, c))
@@ -105,6 +119,52 @@ impl ServiceLogger {
     }
 }
 
+/// How to tell that a service has finished starting, so that services which
+/// are ordered after it can use it.
+pub enum Readiness {
+    /// Ready as soon as the process has been started
+    Started,
+    /// Ready once a unix socket exists at the path
+    Socket(PathBuf),
+    /// Ready once a file or directory exists at the path
+    #[allow(dead_code)]
+    Path(PathBuf),
+    /// Ready once the command exits successfully
+    #[allow(dead_code)]
+    Command(PathBuf, Vec<String>),
+}
+
+impl Readiness {
+    fn is_ready(&self) -> bool {
+        match self {
+            Readiness::Started => true,
+            Readiness::Socket(path) => fs::metadata(path)
+                .map(|meta| meta.file_type().is_socket())
+                .unwrap_or(false),
+            Readiness::Path(path) => path.exists(),
+            Readiness::Command(exec, args) => Command::new(exec)
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map(|status| status.success())
+                .unwrap_or(false),
+        }
+    }
+
+    fn wait(&self, name: &str) -> bool {
+        let start = Instant::now();
+        while !self.is_ready() {
+            if start.elapsed() >= READY_TIMEOUT {
+                warn!("{} is not ready after {} seconds", name, READY_TIMEOUT.as_secs());
+                return false;
+            }
+            thread::sleep(READY_POLL_INTERVAL);
+        }
+        true
+    }
+}
+
 pub struct ServiceLaunch {
     name: String,
     home: String,
@@ -114,6 +174,9 @@ pub struct ServiceLaunch {
     uid: u32,
     gid: u32,
     stdio: StdioMode,
+    after: Vec<String>,
+    requires: Vec<String>,
+    readiness: Readiness,
 }
 
 impl ServiceLaunch {
@@ -129,13 +192,17 @@ impl ServiceLaunch {
             uid: 0,
             gid: 0,
             stdio: StdioMode::InheritAll,
+            after: Vec::new(),
+            requires: Vec::new(),
+            readiness: Readiness::Started,
         }
     }
 
-    pub fn new_shell<S>(root: bool, home: &str, realm: Option<S>) -> Self
+    pub fn new_shell<S>(user: &SessionUser, root: bool, home: &str, realm: Option<S>) -> Self
         where S: Into<String>
     {
         let shell = Self::new("shell", "/bin/bash")
+            .session_user(user)
             .root(root)
             .home(home)
             .env("HOME", home)
@@ -169,6 +236,16 @@ impl ServiceLaunch {
         self
     }
 
+    /// Add `arg` if it is `Some`.
+    pub fn optional_arg<S>(mut self, arg: Option<S>) -> Self
+        where S: Into<String>
+    {
+        if let Some(arg) = arg {
+            self.args.push(arg.into());
+        }
+        self
+    }
+
     pub fn env<K,V>(mut self, name: K, val: V) -> Self
         where K: Into<String>, V: Into<String>,
     {
@@ -195,11 +272,20 @@ impl ServiceLaunch {
         self
     }
 
+    /// Run as `user` with the environment variables which refer to the
+    /// session user's runtime directory.
+    pub fn session_user(self, user: &SessionUser) -> Self {
+        self.uidgid(user.uid(), user.gid())
+            .env("USER", user.name())
+            .env("XDG_RUNTIME_DIR", user.runtime_dir())
+            .env("DBUS_SESSION_BUS_ADDRESS", user.dbus_address())
+    }
+
     pub fn root(self, root: bool) -> Self {
         if root {
             self.uidgid(0,0)
         } else {
-            self.uidgid(1000,1000)
+            self
         }
     }
 
@@ -208,6 +294,31 @@ impl ServiceLaunch {
         self
     }
 
+    /// When launched from a `ServiceManifest`, start this service only
+    /// after the service `name` is ready, or has failed to become ready.
+    #[allow(dead_code)]
+    pub fn after(mut self, name: &str) -> Self {
+        self.after.push(name.to_string());
+        self
+    }
+
+    /// When launched from a `ServiceManifest`, start this service after the
+    /// service `name` is ready and do not start it at all if `name` fails to
+    /// start or to become ready.
+    pub fn requires(mut self, name: &str) -> Self {
+        self.requires.push(name.to_string());
+        self
+    }
+
+    pub fn ready_when(mut self, readiness: Readiness) -> Self {
+        self.readiness = readiness;
+        self
+    }
+
+    fn dependencies(&self) -> impl Iterator<Item=&String> {
+        self.after.iter().chain(self.requires.iter())
+    }
+
     fn output_stdio(&self) -> Stdio {
         match self.stdio {
             StdioMode::InheritAll => Stdio::inherit(),
@@ -246,3 +357,110 @@ impl ServiceLaunch {
         }
     }
 }
+
+enum ServiceState {
+    Failed,
+    Started(Readiness),
+    Ready,
+    NotReady,
+}
+
+impl ServiceState {
+    // Wait for a started service to become ready the first time a service
+    // which comes after it is launched.
+    fn wait_ready(&mut self, name: &str) -> bool {
+        match self {
+            ServiceState::Started(readiness) => {
+                let ready = readiness.wait(name);
+                *self = if ready { ServiceState::Ready } else { ServiceState::NotReady };
+                ready
+            }
+            ServiceState::Ready => true,
+            ServiceState::Failed | ServiceState::NotReady => false,
+        }
+    }
+}
+
+///
+/// A set of services which are launched in the order of their `after` and
+/// `requires` dependencies instead of the order they were added in. Before a
+/// service is launched the services it depends on are given time to become
+/// ready according to their `Readiness` check.
+///
+pub struct ServiceManifest {
+    launches: Vec<ServiceLaunch>,
+}
+
+impl ServiceManifest {
+    pub fn new() -> Self {
+        ServiceManifest { launches: Vec::new() }
+    }
+
+    pub fn add(&mut self, launch: ServiceLaunch) {
+        self.launches.push(launch);
+    }
+
+    /// Launch every service whose dependencies allow it and return the
+    /// services which were started. A service which cannot be executed
+    /// fails the whole manifest, and the services which were already
+    /// started are killed before the error is returned.
+    pub fn launch(self) -> Result<Vec<Service>> {
+        let mut pending = self.launches;
+        let mut states = HashMap::new();
+
+        while !pending.is_empty() {
+            // The first service which does not depend on a service that is
+            // still pending. Dependencies outside the manifest never start.
+            let next = pending.iter().position(|launch| {
+                launch.dependencies().all(|dep| !pending.iter().any(|p| &p.name == dep))
+            });
+            let launch = match next {
+                Some(idx) => pending.remove(idx),
+                None => {
+                    for launch in &pending {
+                        warn!("Not starting {} because of a dependency cycle", launch.name);
+                    }
+                    break;
+                }
+            };
+            let name = launch.name.clone();
+            match Self::launch_one(launch, &mut states) {
+                Ok(state) => { states.insert(name, state); },
+                Err(err) => {
+                    for service in Self::started(states) {
+                        service.kill();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(Self::started(states))
+    }
+
+    fn started(states: HashMap<String, (ServiceState, Option<Service>)>) -> Vec<Service> {
+        states.into_iter()
+            .filter_map(|(_, (_, service))| service)
+            .collect()
+    }
+
+    fn launch_one(mut launch: ServiceLaunch, states: &mut HashMap<String, (ServiceState, Option<Service>)>) -> Result<(ServiceState, Option<Service>)> {
+        for dep in &launch.after {
+            if let Some((state, _)) = states.get_mut(dep) {
+                state.wait_ready(dep);
+            }
+        }
+        for dep in &launch.requires {
+            let ready = match states.get_mut(dep) {
+                Some((state, _)) => state.wait_ready(dep),
+                None => false,
+            };
+            if !ready {
+                warn!("Not starting {} because {} is not running", launch.name, dep);
+                return Ok((ServiceState::Failed, None));
+            }
+        }
+        let readiness = mem::replace(&mut launch.readiness, Readiness::Started);
+        let service = launch.launch()?;
+        Ok((ServiceState::Started(readiness), Some(service)))
+    }
+}
@@ -69,14 +69,34 @@ pub fn move_mount(source: &str, target: &str) -> Result<()> {
         .map_err(|e| Error::MoveMount(source.to_string(), target.to_string(), e))
 }
 
+// Requests and replies of up to this size are exchanged with the 9p server.
+// It is the largest the virtio transport of the kernel accepts, 125 pages,
+// so that a read or write of a large file takes few requests.
+const P9_MSIZE: usize = 512000;
+
 pub fn mount_9p(name: &str, target: &str) -> Result<()> {
     const MS_LAZYTIME: libc::c_ulong = (1 << 25);
-    mount(name, target, "9p",
-          libc::MS_NOATIME|MS_LAZYTIME,
-          Some("trans=virtio,cache=loose"))
+    mount_9p_with_flags(name, target, libc::MS_NOATIME|MS_LAZYTIME)
+}
+
+pub fn mount_9p_readonly(name: &str, target: &str) -> Result<()> {
+    mount_9p_with_flags(name, target, libc::MS_RDONLY|libc::MS_NOATIME)
+}
+
+fn mount_9p_with_flags(name: &str, target: &str, flags: libc::c_ulong) -> Result<()> {
+    let options = format!("trans=virtio,cache=loose,msize={}", P9_MSIZE);
+    mount(name, target, "9p", flags, Some(&options))
         .map_err(|e| Error::Mount9P(name.to_string(), target.to_string(), e))
 }
 
+pub fn mount_virtiofs(tag: &str, target: &str) -> Result<()> {
+    const MS_LAZYTIME: libc::c_ulong = 1 << 25;
+    mount(tag, target, "virtiofs",
+          libc::MS_NOATIME|MS_LAZYTIME,
+          None)
+        .map_err(|e| Error::MountVirtioFs(tag.to_string(), target.to_string(), e))
+}
+
 fn cstr(s: &str) -> CString {
     CString::new(s).unwrap()
 }
@@ -173,6 +193,16 @@ pub fn pivot_root(new_root: &str, put_old: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn _chroot(path: &str) -> io::Result<()> {
+    let path = cstr(path);
+    unsafe {
+        if libc::chroot(path.as_ptr()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
 pub fn umount(path: &str) -> Result<()> {
     let _path = cstr(path);
     unsafe {
@@ -242,4 +272,21 @@ pub fn reboot(cmd: libc::c_int) -> io::Result<()> {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+/// Add a key of `key_type` to `keyring` and return its serial number. The
+/// kernel generates a description for the key from the payload.
+pub fn add_key(key_type: &str, payload: &[u8], keyring: i32) -> io::Result<i32> {
+    let key_type = cstr(key_type);
+    unsafe {
+        let ret = libc::syscall(libc::SYS_add_key,
+                                key_type.as_ptr(),
+                                ptr::null::<libc::c_char>(),
+                                payload.as_ptr(),
+                                payload.len(),
+                                keyring);
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as i32)
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::cmdline::CmdLine;
+
+const DEFAULT_USER: &str = "user";
+const DEFAULT_UID: u32 = 1000;
+
+///
+/// The unprivileged user which owns the session. By default this is `user`
+/// with uid and gid 1000, and it can be changed with the `phinit.user`,
+/// `phinit.uid`, and `phinit.gid` kernel command line variables.
+///
+#[derive(Clone)]
+pub struct SessionUser {
+    name: String,
+    uid: u32,
+    gid: u32,
+}
+
+impl SessionUser {
+    pub fn load(cmdline: &CmdLine) -> Self {
+        let name = cmdline.lookup("phinit.user")
+            .unwrap_or(DEFAULT_USER.to_string());
+        let uid = Self::lookup_id(cmdline, "phinit.uid", DEFAULT_UID);
+        let gid = Self::lookup_id(cmdline, "phinit.gid", uid);
+        SessionUser { name, uid, gid }
+    }
+
+    fn lookup_id(cmdline: &CmdLine, var: &str, default: u32) -> u32 {
+        match cmdline.lookup(var) {
+            Some(val) => match val.parse::<u32>() {
+                Ok(id) => id,
+                Err(_) => {
+                    warn!("Invalid value for {}: {}", var, val);
+                    default
+                }
+            },
+            None => default,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn runtime_dir(&self) -> String {
+        format!("/run/user/{}", self.uid)
+    }
+
+    pub fn dbus_address(&self) -> String {
+        format!("unix:path={}/bus", self.runtime_dir())
+    }
+
+    /// Add entries for the session user to /etc/passwd and /etc/group if
+    /// the root filesystem does not already have them.
+    pub fn create_account(&self, home: &str) -> io::Result<()> {
+        let passwd = format!("{}:x:{}:{}:{}:{}:/bin/bash\n", self.name, self.uid, self.gid, self.name, home);
+        Self::add_entry("/etc/passwd", &self.name, self.uid, &passwd)?;
+        let group = format!("{}:x:{}:\n", self.name, self.gid);
+        Self::add_entry("/etc/group", &self.name, self.gid, &group)
+    }
+
+    fn add_entry(path: &str, name: &str, id: u32, entry: &str) -> io::Result<()> {
+        if Path::new(path).exists() {
+            let content = fs::read_to_string(path)?;
+            if content.lines().any(|line| Self::entry_matches(line, name, id)) {
+                return Ok(());
+            }
+        }
+        info!("Adding {} to {}", name, path);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(entry.as_bytes())
+    }
+
+    fn entry_matches(line: &str, name: &str, id: u32) -> bool {
+        let fields: Vec<&str> = line.split(':').collect();
+        fields.len() > 2 && (fields[0] == name || fields[2] == id.to_string())
+    }
+}
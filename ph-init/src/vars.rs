@@ -0,0 +1,69 @@
+//! The `phinit.*` kernel command line variables which ph-init reads.
+//!
+//! pH builds this file into the VMM as well and checks the command line it
+//! passes to the guest kernel against the same list, so a variable must be
+//! added here when ph-init starts reading it.
+
+pub const PREFIX: &str = "phinit.";
+
+///
+/// A variable which ph-init reads from the kernel command line.
+///
+pub struct PhinitVar {
+    pub name: &'static str,
+    /// `true` if the variable is useless without a value, `false` if it is
+    /// a flag which only needs to be present.
+    pub takes_value: bool,
+}
+
+const fn flag(name: &'static str) -> PhinitVar {
+    PhinitVar { name, takes_value: false }
+}
+
+const fn value(name: &'static str) -> PhinitVar {
+    PhinitVar { name, takes_value: true }
+}
+
+pub const PHINIT_VARS: &[PhinitVar] = &[
+    flag("phinit.debug"),
+    value("phinit.gid"),
+    value("phinit.home"),
+    value("phinit.home_fs"),
+    value("phinit.ip"),
+    flag("phinit.module_key"),
+    flag("phinit.no_home"),
+    flag("phinit.no_x11"),
+    value("phinit.realm"),
+    value("phinit.root"),
+    flag("phinit.root_rw"),
+    value("phinit.rootflags"),
+    value("phinit.rootfstype"),
+    flag("phinit.rootshell"),
+    value("phinit.run"),
+    flag("phinit.selftest"),
+    value("phinit.store"),
+    value("phinit.uid"),
+    value("phinit.upper"),
+    value("phinit.user"),
+    flag("phinit.verbose"),
+    flag("phinit.virtwl_dmabuf"),
+    value("phinit.wayland_tag"),
+];
+
+pub fn lookup(name: &str) -> Option<&'static PhinitVar> {
+    PHINIT_VARS.iter().find(|var| var.name == name)
+}
+
+/// Check the variable `name` from the kernel command line, which was given
+/// a value if `has_value` is set. Returns a description of the problem if
+/// it is a `phinit.*` variable which ph-init would ignore.
+pub fn check(name: &str, has_value: bool) -> Option<String> {
+    if !name.starts_with(PREFIX) {
+        return None;
+    }
+    match lookup(name) {
+        None => Some(format!("unknown variable {} is ignored by ph-init", name)),
+        Some(var) if var.takes_value && !has_value => Some(format!("{} is ignored without a value", name)),
+        Some(_) => None,
+    }
+}
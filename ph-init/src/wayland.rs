@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Result;
+use crate::service::{Service, ServiceManifest};
+
+// How long the old services are given to exit after SIGTERM before they
+// are killed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+lazy_static! {
+    static ref SESSION: Mutex<Option<WaylandSession>> = Mutex::new(None);
+}
+
+///
+/// The sommelier services which connect wayland and X11 clients in the guest
+/// to the host compositor through virtio-wl.
+///
+/// When the host compositor restarts, the host hangs up every wayland
+/// connection of the guest and sends `restart-wayland` on the agent port.
+/// The services are then stopped and launched again so that new clients
+/// reach the new compositor.
+///
+struct WaylandSession {
+    manifest: Box<dyn Fn() -> ServiceManifest + Send>,
+    // Created by the first service, and removed before it is launched again
+    socket: PathBuf,
+    services: BTreeMap<u32, Service>,
+}
+
+/// Launch the services in the manifest returned by `manifest`, which is
+/// called again each time the services are restarted.
+pub fn start<F>(manifest: F, socket: PathBuf) -> Result<()>
+    where F: Fn() -> ServiceManifest + Send + 'static
+{
+    let services = launch(&manifest)?;
+    *SESSION.lock().unwrap() = Some(WaylandSession {
+        manifest: Box::new(manifest),
+        socket,
+        services,
+    });
+    Ok(())
+}
+
+fn launch(manifest: &dyn Fn() -> ServiceManifest) -> Result<BTreeMap<u32, Service>> {
+    Ok(manifest().launch()?
+        .into_iter()
+        .map(|service| (service.pid(), service))
+        .collect())
+}
+
+/// Remove the wayland service with process id `pid` after it has exited.
+/// Returns `None` if `pid` is not a wayland service.
+pub fn reap(pid: u32) -> Option<Service> {
+    SESSION.lock().unwrap()
+        .as_mut()
+        .and_then(|session| session.services.remove(&pid))
+}
+
+/// Stop the wayland services and launch them again. The services are reaped
+/// by the main loop of init as they exit.
+pub fn restart() {
+    let pids: Vec<u32> = match SESSION.lock().unwrap().as_ref() {
+        Some(session) => session.services.keys().cloned().collect(),
+        None => return,
+    };
+    info!("Restarting wayland services for the new host compositor");
+    signal_all(&pids, libc::SIGTERM);
+    if !wait_exited(&pids) {
+        warn!("Wayland services did not exit after {} seconds, killing them", EXIT_TIMEOUT.as_secs());
+        signal_all(&pids, libc::SIGKILL);
+        wait_exited(&pids);
+    }
+
+    let mut session = SESSION.lock().unwrap();
+    if let Some(session) = session.as_mut() {
+        // Left behind by the old service, it would look ready before the new one binds it
+        let _ = fs::remove_file(&session.socket);
+        match launch(&*session.manifest) {
+            Ok(services) => session.services.extend(services),
+            Err(err) => warn!("Failed to restart wayland services: {}", err),
+        }
+    }
+}
+
+fn signal_all(pids: &[u32], signal: libc::c_int) {
+    for &pid in pids {
+        unsafe { libc::kill(pid as libc::pid_t, signal); }
+    }
+}
+
+fn wait_exited(pids: &[u32]) -> bool {
+    let start = Instant::now();
+    loop {
+        let running = SESSION.lock().unwrap()
+            .as_ref()
+            .map(|session| pids.iter().any(|pid| session.services.contains_key(pid)))
+            .unwrap_or(false);
+        if !running {
+            return true;
+        }
+        if start.elapsed() >= EXIT_TIMEOUT {
+            return false;
+        }
+        thread::sleep(EXIT_POLL_INTERVAL);
+    }
+}
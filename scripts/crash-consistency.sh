@@ -0,0 +1,95 @@
+#!/bin/bash
+#
+# Crash consistency test for the virtio-block device.
+#
+# Usage: scripts/crash-consistency.sh IMAGE [ITERATIONS]
+#
+# IMAGE is a bootable ext4 root filesystem image which is modified by the
+# test, so pass a copy. Each iteration boots pH with IMAGE as a read-write
+# root disk and runs a workload in a root shell which writes files with
+# fsync and prints a checksum for each one once fsync has returned. The pH
+# process is killed with SIGKILL after a random delay, then the journal is
+# replayed and the image is checked with e2fsck. Every file reported as
+# synced must still be on the image with the same contents.
+#
+# Requires script(1) from util-linux and e2fsprogs. Set PH to the path of
+# the pH binary if it is not target/release/pH.
+#
+
+set -u
+
+IMAGE=${1:?usage: $0 IMAGE [ITERATIONS]}
+ITERATIONS=${2:-20}
+PH=${PH:-target/release/pH}
+
+# Seconds to wait for the guest to boot before the workload starts
+BOOT_WAIT=${BOOT_WAIT:-5}
+# The VMM is killed up to this many seconds after the workload starts
+MAX_RUN=${MAX_RUN:-10}
+
+WORKDIR=$(mktemp -d)
+trap 'rm -rf "$WORKDIR"' EXIT
+
+# The marker is split in the command so that the echoed command line does
+# not match when the console log is searched.
+WORKLOAD='rm -rf /crash; mkdir /crash; cd /crash; i=0; while true; do i=$((i+1)); dd if=/dev/urandom of=f$i bs=64k count=$((RANDOM % 16 + 1)) conv=fsync 2>/dev/null && echo "SYN""CED f$i $(sha256sum < f$i | cut -d" " -f1)"; done'
+
+run_vm() {
+    local log=$1
+    local fifo=$WORKDIR/input
+    rm -f "$fifo"
+    mkfifo "$fifo"
+
+    script -qfec "$PH --disk $IMAGE --root --no-network --no-wayland" "$log" < "$fifo" > /dev/null &
+    local pid=$!
+    exec 3> "$fifo"
+
+    sleep "$BOOT_WAIT"
+    echo "$WORKLOAD" >&3
+    sleep $((RANDOM % MAX_RUN + 1))
+
+    pkill -KILL -P "$pid" 2> /dev/null
+    kill -KILL "$pid" 2> /dev/null
+    wait "$pid" 2> /dev/null
+    exec 3>&-
+}
+
+check_image() {
+    local log=$1
+
+    e2fsck -E journal_only -p "$IMAGE" > /dev/null 2>&1
+    if ! e2fsck -fn "$IMAGE" > "$WORKDIR/fsck.log" 2>&1; then
+        echo "e2fsck found errors:"
+        cat "$WORKDIR/fsck.log"
+        return 1
+    fi
+
+    local synced=0
+    while read -r _ name sum; do
+        local actual
+        actual=$(debugfs -R "cat /crash/$name" "$IMAGE" 2> /dev/null | sha256sum | cut -d' ' -f1)
+        if [ "$actual" != "$sum" ]; then
+            echo "/crash/$name was synced but its contents are lost or corrupted"
+            return 1
+        fi
+        synced=$((synced + 1))
+    done < <(tr -d '\r' < "$log" | grep '^SYNCED ')
+
+    echo "$synced synced files verified"
+}
+
+failures=0
+for n in $(seq 1 "$ITERATIONS"); do
+    log=$WORKDIR/console.$n
+    echo "Iteration $n of $ITERATIONS"
+    run_vm "$log"
+    if ! check_image "$log"; then
+        failures=$((failures + 1))
+        cp "$log" "console-failure.$n.log"
+        # Repair the image so that later iterations test from a clean state
+        e2fsck -fy "$IMAGE" > /dev/null 2>&1
+    fi
+done
+
+echo "$failures of $ITERATIONS iterations failed"
+[ "$failures" -eq 0 ]
@@ -202,7 +202,7 @@ static void sl_xdg_toplevel_set_title(struct wl_client* client,
                                       const char* title) {
   struct sl_host_xdg_toplevel* host = wl_resource_get_user_data(resource);
 
-  zxdg_toplevel_v6_set_title(host->proxy, title);
+  sl_toplevel_set_title(host->ctx, host->proxy, title);
 }
 
 static void sl_xdg_toplevel_set_app_id(struct wl_client* client,
@@ -210,7 +210,7 @@ static void sl_xdg_toplevel_set_app_id(struct wl_client* client,
                                        const char* app_id) {
   struct sl_host_xdg_toplevel* host = wl_resource_get_user_data(resource);
 
-  zxdg_toplevel_v6_set_app_id(host->proxy, app_id);
+  sl_toplevel_set_app_id(host->ctx, host->proxy, app_id);
 }
 
 static void sl_xdg_toplevel_show_window_menu(struct wl_client* client,
@@ -185,6 +185,32 @@ __attribute__((__format__(__printf__, 1, 0))) static char* sl_xasprintf(
   return str;
 }
 
+// Toplevel titles and application IDs are prefixed with the VM identifier so
+// that windows of different VMs sharing a host compositor can be told apart.
+void sl_toplevel_set_title(struct sl_context* ctx,
+                           struct zxdg_toplevel_v6* toplevel,
+                           const char* title) {
+  if (ctx->vm_identifier) {
+    char* title_str = sl_xasprintf("[%s] %s", ctx->vm_identifier, title);
+    zxdg_toplevel_v6_set_title(toplevel, title_str);
+    free(title_str);
+  } else {
+    zxdg_toplevel_v6_set_title(toplevel, title);
+  }
+}
+
+void sl_toplevel_set_app_id(struct sl_context* ctx,
+                            struct zxdg_toplevel_v6* toplevel,
+                            const char* app_id) {
+  if (ctx->vm_identifier) {
+    char* app_id_str = sl_xasprintf("%s.%s", ctx->vm_identifier, app_id);
+    zxdg_toplevel_v6_set_app_id(toplevel, app_id_str);
+    free(app_id_str);
+  } else {
+    zxdg_toplevel_v6_set_app_id(toplevel, app_id);
+  }
+}
+
 struct sl_mmap* sl_mmap_create(int fd,
                                size_t size,
                                size_t bpp,
@@ -740,7 +766,9 @@ void sl_window_update(struct sl_window* window) {
     if (parent)
       zxdg_toplevel_v6_set_parent(window->xdg_toplevel, parent->xdg_toplevel);
     if (window->name)
-      zxdg_toplevel_v6_set_title(window->xdg_toplevel, window->name);
+      sl_toplevel_set_title(ctx, window->xdg_toplevel, window->name);
+    if (ctx->vm_identifier && window->clazz)
+      sl_toplevel_set_app_id(ctx, window->xdg_toplevel, window->clazz);
     if (window->size_flags & P_MIN_SIZE) {
       zxdg_toplevel_v6_set_min_size(window->xdg_toplevel,
                                     window->min_width / ctx->scale,
@@ -2322,9 +2350,9 @@ static void sl_handle_property_notify(struct sl_context* ctx,
       return;
 
     if (window->name) {
-      zxdg_toplevel_v6_set_title(window->xdg_toplevel, window->name);
+      sl_toplevel_set_title(ctx, window->xdg_toplevel, window->name);
     } else {
-      zxdg_toplevel_v6_set_title(window->xdg_toplevel, "");
+      sl_toplevel_set_title(ctx, window->xdg_toplevel, "");
     }
   } else if (event->atom == XCB_ATOM_WM_NORMAL_HINTS) {
     struct sl_window* window = sl_lookup_window(ctx, event->window);
@@ -3390,6 +3418,7 @@ static void sl_print_usage() {
       "  --peer-cmd-prefix=PREFIX\tPeer process command line prefix\n"
       "  --accelerators=ACCELERATORS\tList of keyboard accelerators\n"
       "  --application-id=ID\t\tForced application ID for X11 clients\n"
+      "  --vm-identifier=ID\t\tPrefix for window titles and app IDs\n"
       "  --x-display=DISPLAY\t\tX11 display to listen on\n"
       "  --xwayland-path=PATH\t\tPath to Xwayland executable\n"
       "  --xwayland-gl-driver-path=PATH\tPath to GL drivers for Xwayland\n"
@@ -3457,6 +3486,7 @@ int main(int argc, char** argv) {
       .desired_scale = 1.0,
       .scale = 1.0,
       .application_id = NULL,
+      .vm_identifier = NULL,
       .exit_with_child = 1,
       .sd_notify = NULL,
       .clipboard_manager = 0,
@@ -3582,6 +3612,8 @@ int main(int argc, char** argv) {
       accelerators = sl_arg_value(arg);
     } else if (strstr(arg, "--application-id") == arg) {
       ctx.application_id = sl_arg_value(arg);
+    } else if (strstr(arg, "--vm-identifier") == arg) {
+      ctx.vm_identifier = sl_arg_value(arg);
     } else if (strstr(arg, "-X") == arg) {
       ctx.xwayland = 1;
     } else if (strstr(arg, "--x-display") == arg) {
@@ -45,6 +45,7 @@ struct sl_pointer_constraints;
 struct sl_window;
 struct zaura_shell;
 struct zcr_keyboard_extension_v1;
+struct zxdg_toplevel_v6;
 
 enum {
   ATOM_WM_S0,
@@ -144,6 +145,7 @@ struct sl_context {
   double desired_scale;
   double scale;
   const char* application_id;
+  const char* vm_identifier;
   int exit_with_child;
   const char* sd_notify;
   int clipboard_manager;
@@ -562,4 +564,12 @@ int sl_process_pending_configure_acks(struct sl_window* window,
 
 void sl_window_update(struct sl_window* window);
 
+void sl_toplevel_set_title(struct sl_context* ctx,
+                           struct zxdg_toplevel_v6* toplevel,
+                           const char* title);
+
+void sl_toplevel_set_app_id(struct sl_context* ctx,
+                            struct zxdg_toplevel_v6* toplevel,
+                            const char* app_id);
+
 #endif  // VM_TOOLS_SOMMELIER_SOMMELIER_H_
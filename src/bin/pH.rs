@@ -1,9 +1,409 @@
 #![allow(non_snake_case)]
 
-use ph::VmConfig;
+use std::{env, process};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use ph::{VmConfig, Bundle, ControlClient, ExitStatus, FailureKind, RebootAction};
+use ph::util::JsonValue;
+
+const CONTROL_SOCKET_ENV: &str = "PH_CONTROL_SOCKET";
+
+// A guest which reboots more than MAX_RESTARTS times within RESTART_WINDOW
+// is assumed to be failing to boot, and pH exits as if it had crashed.
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+// Subcommands with a short description and the options each one accepts.
+// Shell completions are generated from this table.
+const SUBCOMMANDS: &[(&str, &str, &[&str])] = &[
+    ("ctl", "Call a method on the control socket of a running VM", &["--socket", "--output"]),
+    ("attach", "Attach the terminal to the console of a running VM", &["--socket", "--replay"]),
+    ("bundle", "Export the options and images of a VM to a directory, or boot a VM from one", &[]),
+    ("completions", "Print a shell completion script", &[]),
+];
+
+const OUTPUT_FORMATS: &[&str] = &["text", "json"];
+const SHELLS: &[&str] = &["bash", "zsh", "fish"];
 
 fn main() {
-    VmConfig::new()
-        .ram_size_megs(2048)
-        .boot();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|s| s.as_str()) {
+        Some("ctl") => process::exit(ctl(&args[1..])),
+        Some("attach") => process::exit(attach(&args[1..])),
+        Some("bundle") => process::exit(bundle(&args[1..])),
+        Some("completions") => process::exit(completions(&args[1..])),
+        _ => {},
+    }
+    process::exit(run_vm(&args));
+}
+
+fn run_vm(args: &[String]) -> i32 {
+    let mut restarts = VecDeque::new();
+    loop {
+        let config = VmConfig::with_args(args.to_vec())
+            .ram_size_megs(2048);
+        let restart = config.get_reboot_action() == RebootAction::Restart;
+        let status = config.boot();
+        if status != ExitStatus::Reboot || !restart {
+            return status.exit_code();
+        }
+        let now = Instant::now();
+        while restarts.front().map_or(false, |&t| now.duration_since(t) > RESTART_WINDOW) {
+            restarts.pop_front();
+        }
+        if restarts.len() >= MAX_RESTARTS {
+            eprintln!("Guest rebooted {} times within {} seconds, not restarting it again",
+                      restarts.len() + 1, RESTART_WINDOW.as_secs());
+            return ExitStatus::Crash.exit_code();
+        }
+        restarts.push_back(now);
+    }
+}
+
+fn ctl_usage() -> i32 {
+    eprintln!("Usage: pH ctl [--socket PATH] [--output text|json] [METHOD [ARGS...]]");
+    eprintln!();
+    eprintln!("Call METHOD on the control socket of a running VM. Without a");
+    eprintln!("METHOD the available methods are listed. The socket path may");
+    eprintln!("also be set with the {} environment variable.", CONTROL_SOCKET_ENV);
+    eprintln!();
+    eprintln!("The result is shown as text by default. With --output json it is");
+    eprintln!("printed as a single line of JSON for use by scripts.");
+    FailureKind::Config.exit_code()
+}
+
+fn attach_usage() -> i32 {
+    eprintln!("Usage: pH attach [--socket PATH] [--replay KIB]");
+    eprintln!();
+    eprintln!("Attach the terminal to the console of a running VM, first showing");
+    eprintln!("up to KIB of its recent output. Type Ctrl-] d to detach and leave");
+    eprintln!("the VM running. The socket path may also be set with the {}", CONTROL_SOCKET_ENV);
+    eprintln!("environment variable.");
+    FailureKind::Config.exit_code()
+}
+
+fn bundle_usage() -> i32 {
+    eprintln!("Usage: pH bundle export DIR [OPTIONS...]");
+    eprintln!("       pH bundle run DIR [OPTIONS...]");
+    eprintln!();
+    eprintln!("Export writes the pH OPTIONS and copies of the disk images, kernel");
+    eprintln!("and other files they name to the new directory DIR. Options which");
+    eprintln!("refer to the host, including --ram-file, are left out.");
+    eprintln!();
+    eprintln!("Run boots a VM with the options stored in DIR. OPTIONS given after");
+    eprintln!("DIR are added to them, and are used in place of stored options");
+    eprintln!("which only take one value.");
+    FailureKind::Config.exit_code()
+}
+
+fn completions_usage() -> i32 {
+    eprintln!("Usage: pH completions {}", SHELLS.join("|"));
+    eprintln!();
+    eprintln!("Print a completion script for the shell. For example:");
+    eprintln!();
+    eprintln!("    source <(pH completions bash)");
+    FailureKind::Config.exit_code()
+}
+
+///
+/// Options given before the positional arguments of a subcommand. Each
+/// option takes a value.
+///
+struct Options<'a> {
+    values: Vec<(&'a str, &'a str)>,
+    rest: &'a [String],
+}
+
+impl <'a> Options<'a> {
+    // Return None if an option is not in `allowed`, has no value, or help was asked for
+    fn parse(args: &'a [String], allowed: &[&str]) -> Option<Self> {
+        let mut values = Vec::new();
+        let mut rest = args;
+        while let Some(opt) = rest.first() {
+            if !opt.starts_with('-') {
+                break;
+            }
+            if !allowed.contains(&opt.as_str()) || rest.len() < 2 {
+                return None;
+            }
+            values.push((opt.as_str(), rest[1].as_str()));
+            rest = &rest[2..];
+        }
+        Some(Options { values, rest })
+    }
+
+    fn get(&self, name: &str) -> Option<&'a str> {
+        self.values.iter().rev()
+            .find(|(opt, _)| *opt == name)
+            .map(|&(_, value)| value)
+    }
+
+    // The --socket option or the environment
+    fn socket(&self) -> Option<String> {
+        self.get("--socket")
+            .map(|s| s.to_string())
+            .or_else(|| env::var(CONTROL_SOCKET_ENV).ok())
+    }
+}
+
+fn connect(socket: &str) -> Option<ControlClient> {
+    let mut client = match ControlClient::connect(socket) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to connect to control socket {}: {}", socket, e);
+            return None;
+        }
+    };
+    if let Err(e) = client.check_version() {
+        eprintln!("{}", e);
+        return None;
+    }
+    Some(client)
+}
+
+// pH attach [--socket PATH] [--replay KIB]
+fn attach(args: &[String]) -> i32 {
+    let opts = match Options::parse(args, &["--socket", "--replay"]) {
+        Some(opts) => opts,
+        None => return attach_usage(),
+    };
+    if !opts.rest.is_empty() {
+        return attach_usage();
+    }
+    let socket = match opts.socket() {
+        Some(socket) => socket,
+        None => return attach_usage(),
+    };
+    let replay = match opts.get("--replay").map(|kib| kib.parse::<usize>()) {
+        None => None,
+        Some(Ok(kib)) => Some(kib),
+        Some(Err(_)) => return attach_usage(),
+    };
+    let client = match connect(&socket) {
+        Some(client) => client,
+        None => return 1,
+    };
+    match client.attach_console(replay) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Failed to attach to console: {}", e);
+            1
+        }
+    }
+}
+
+// pH bundle export DIR [OPTIONS...]
+// pH bundle run DIR [OPTIONS...]
+fn bundle(args: &[String]) -> i32 {
+    let (verb, args) = match args.split_first() {
+        Some((verb, args)) => (verb.as_str(), args),
+        None => return bundle_usage(),
+    };
+    let (dir, options) = match args.split_first() {
+        Some((dir, options)) => (dir, options),
+        None => return bundle_usage(),
+    };
+    match verb {
+        "export" => {
+            match Bundle::export(dir, options) {
+                Ok(bundle) => {
+                    println!("Exported VM to {}", bundle.dir().display());
+                    0
+                }
+                Err(e) => {
+                    eprintln!("Failed to export bundle: {}", e);
+                    1
+                }
+            }
+        }
+        "run" => {
+            let bundle_args = match Bundle::open(dir).and_then(|bundle| bundle.args()) {
+                Ok(args) => args,
+                Err(e) => {
+                    eprintln!("Failed to open bundle {}: {}", dir, e);
+                    return 1;
+                }
+            };
+            // The first occurrence of an option is the one which is used
+            let mut vm_args = options.to_vec();
+            vm_args.extend(bundle_args);
+            run_vm(&vm_args)
+        }
+        _ => bundle_usage(),
+    }
+}
+
+// pH ctl [--socket PATH] [--output text|json] [METHOD [ARGS...]]
+fn ctl(args: &[String]) -> i32 {
+    let opts = match Options::parse(args, &["--socket", "--output"]) {
+        Some(opts) => opts,
+        None => return ctl_usage(),
+    };
+    let json = match opts.get("--output") {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(_) => return ctl_usage(),
+    };
+    let socket = match opts.socket() {
+        Some(socket) => socket,
+        None => return ctl_usage(),
+    };
+    let mut client = match connect(&socket) {
+        Some(client) => client,
+        None => return 1,
+    };
+
+    let args = opts.rest;
+    let (method, params) = match args.split_first() {
+        Some((method, params)) => (method.as_str(), params),
+        None => ("capabilities", &args[..0]),
+    };
+    let params: Vec<&str> = params.iter().map(|s| s.as_str()).collect();
+    match client.call(method, &params) {
+        Ok(Ok(result)) => {
+            if json {
+                println!("{}", result);
+            } else if method == "capabilities" {
+                print!("{}", method_table(&result));
+            } else {
+                print!("{}", result.to_text());
+            }
+            0
+        },
+        Ok(Err(message)) => {
+            eprintln!("{}: {}", method, message);
+            1
+        },
+        Err(e) => {
+            eprintln!("Control socket error: {}", e);
+            1
+        }
+    }
+}
+
+// One line for each method listed by `capabilities` with the name first, so
+// that shell completion can take the names from the first column.
+fn method_table(capabilities: &JsonValue) -> String {
+    let methods = capabilities.get("methods")
+        .and_then(|m| m.as_array())
+        .unwrap_or(&[]);
+    let name = |m: &JsonValue| m.get("name").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let width = methods.iter().map(|m| name(m).len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for m in methods {
+        let description = m.get("description").and_then(|v| v.as_str()).unwrap_or("");
+        out.push_str(&format!("{:width$}  {}\n", name(m), description, width = width));
+    }
+    out
+}
+
+// pH completions bash|zsh|fish
+fn completions(args: &[String]) -> i32 {
+    let script = match args.iter().map(|s| s.as_str()).collect::<Vec<_>>().as_slice() {
+        ["bash"] => bash_completion(),
+        ["zsh"] => zsh_completion(),
+        ["fish"] => fish_completion(),
+        _ => return completions_usage(),
+    };
+    print!("{}", script);
+    0
+}
+
+fn subcommand_names() -> String {
+    SUBCOMMANDS.iter().map(|&(name, _, _)| name).collect::<Vec<_>>().join(" ")
+}
+
+fn bash_completion() -> String {
+    let mut options = String::new();
+    for &(name, _, opts) in SUBCOMMANDS {
+        options.push_str(&format!("        {}) opts=\"{}\";;\n", name, opts.join(" ")));
+    }
+    format!(r#"# bash completion for pH
+_pH() {{
+    local cur prev opts socket i
+    cur="${{COMP_WORDS[COMP_CWORD]}}"
+    prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    if [ "$COMP_CWORD" -eq 1 ]; then
+        COMPREPLY=($(compgen -W "{commands}" -- "$cur"))
+        return
+    fi
+    case "$prev" in
+        --socket) COMPREPLY=($(compgen -f -- "$cur")); return;;
+        --output) COMPREPLY=($(compgen -W "{formats}" -- "$cur")); return;;
+        --replay) return;;
+    esac
+    case "${{COMP_WORDS[1]}}" in
+{options}        *) return;;
+    esac
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "$opts" -- "$cur"))
+    elif [ "${{COMP_WORDS[1]}}" = completions ]; then
+        COMPREPLY=($(compgen -W "{shells}" -- "$cur"))
+    elif [ "${{COMP_WORDS[1]}}" = ctl ]; then
+        socket=()
+        for ((i=2; i<COMP_CWORD-1; i++)); do
+            [ "${{COMP_WORDS[i]}}" = --socket ] && socket=(--socket "${{COMP_WORDS[i+1]}}")
+        done
+        COMPREPLY=($(compgen -W "$(pH ctl "${{socket[@]}}" 2>/dev/null | awk '{{print $1}}')" -- "$cur"))
+    fi
+}}
+complete -F _pH pH
+"#, commands = subcommand_names(), formats = OUTPUT_FORMATS.join(" "), shells = SHELLS.join(" "), options = options)
+}
+
+fn zsh_completion() -> String {
+    let mut options = String::new();
+    for &(name, _, opts) in SUBCOMMANDS {
+        options.push_str(&format!("        {}) opts=({});;\n", name, opts.join(" ")));
+    }
+    format!(r#"#compdef pH
+_pH() {{
+    local -a opts socket methods
+    local i
+    if (( CURRENT == 2 )); then
+        compadd {commands}
+        return
+    fi
+    case ${{words[CURRENT-1]}} in
+        --socket) _files; return;;
+        --output) compadd {formats}; return;;
+        --replay) return;;
+    esac
+    case ${{words[2]}} in
+{options}        *) return;;
+    esac
+    if [[ $PREFIX == -* ]]; then
+        compadd -- $opts
+    elif [[ ${{words[2]}} == completions ]]; then
+        compadd {shells}
+    elif [[ ${{words[2]}} == ctl ]]; then
+        for ((i=3; i<CURRENT-1; i++)); do
+            [[ ${{words[i]}} == --socket ]] && socket=(--socket ${{words[i+1]}})
+        done
+        methods=(${{(f)"$(pH ctl $socket 2>/dev/null | awk '{{print $1}}')"}})
+        compadd -a methods
+    fi
+}}
+compdef _pH pH
+"#, commands = subcommand_names(), formats = OUTPUT_FORMATS.join(" "), shells = SHELLS.join(" "), options = options)
+}
+
+fn fish_completion() -> String {
+    let mut script = String::from("# fish completion for pH\ncomplete -c pH -f\n");
+    for &(name, description, opts) in SUBCOMMANDS {
+        script.push_str(&format!("complete -c pH -n __fish_use_subcommand -a {} -d '{}'\n", name, description));
+        for opt in opts {
+            let arg = match *opt {
+                "--socket" => "-r -F".to_string(),
+                "--output" => format!("-x -a '{}'", OUTPUT_FORMATS.join(" ")),
+                _ => "-x".to_string(),
+            };
+            script.push_str(&format!("complete -c pH -n '__fish_seen_subcommand_from {}' -l {} {}\n",
+                                     name, opt.trim_start_matches('-'), arg));
+        }
+    }
+    script.push_str(&format!("complete -c pH -n '__fish_seen_subcommand_from completions' -a '{}'\n", SHELLS.join(" ")));
+    script.push_str("complete -c pH -n '__fish_seen_subcommand_from ctl' -a '(pH ctl 2>/dev/null | awk \\'{print $1}\\')'\n");
+    script
 }
@@ -1,16 +1,31 @@
 pub mod serial;
 pub mod rtc;
 mod virtio_9p;
+mod virtio_fs;
 mod virtio_serial;
 mod virtio_rng;
+mod virtio_input;
+mod virtio_snd;
+mod virtio_balloon;
 mod virtio_wl;
+mod virtio_vsock;
 mod virtio_block;
 mod virtio_net;
+mod vhost_net;
+mod vhost_user;
 
 pub use self::virtio_serial::VirtioSerial;
-pub use self::virtio_9p::VirtioP9;
-pub use self::virtio_9p::SyntheticFS;
+pub use self::serial::UartBackend;
+pub use self::virtio_9p::{VirtioP9, P9Share};
+pub use self::virtio_9p::{SyntheticFS, CacheHint};
+pub use self::virtio_fs::{VirtioFs, VirtioFsShare};
 pub use self::virtio_rng::VirtioRandom;
+pub use self::virtio_input::{VirtioInput, InputHandle};
+pub use self::virtio_snd::{VirtioSound, SoundBackend};
+pub use self::virtio_balloon::{VirtioBalloon, BalloonHandle};
 pub use self::virtio_wl::VirtioWayland;
+pub use self::virtio_vsock::VirtioSock;
 pub use self::virtio_block::VirtioBlock;
-pub use self::virtio_net::VirtioNet;
+pub use self::virtio_net::{VirtioNet, MacAddress, MIN_MTU};
+pub use self::vhost_net::VhostNet;
+pub use self::vhost_user::{VhostUser, VhostUserBackend, VhostUserKind, VhostUserSpec};
@@ -1,5 +1,6 @@
 use std::sync::{Arc,RwLock};
-use std::mem;
+use std::time::Instant;
+use std::{mem, ptr};
 use libc;
 
 use crate::vm::io::{IoDispatcher,IoPortOps};
@@ -16,9 +17,14 @@ const RTC_CENTURY: u8 = 0x32;
 const RTC_REG_C: u8 = 0x0C;
 const RTC_REG_D: u8 = 0x0D;
 
+/// Time at which the clock of a deterministic VM starts, 2020-01-01 00:00:00 UTC
+pub const FIXED_EPOCH: libc::time_t = 1_577_836_800;
+
 pub struct Rtc {
     idx: u8,
-    data: [u8; 128]
+    data: [u8; 128],
+    // Fixed start time and the instant the clock started running from it
+    start: Option<(libc::time_t, Instant)>,
 }
 
 impl IoPortOps for Rtc {
@@ -40,15 +46,25 @@ impl IoPortOps for Rtc {
 }
 
 impl Rtc {
-    pub fn register(io: Arc<IoDispatcher>) {
-        let rtc = Arc::new(RwLock::new(Rtc::new()));
+    /// Register the RTC. If `start` is given the clock reads as that time
+    /// when the VM starts instead of the host time.
+    pub fn register(io: Arc<IoDispatcher>, start: Option<libc::time_t>) {
+        let rtc = Arc::new(RwLock::new(Rtc::new(start)));
         io.register_ioports(0x0070, 2, rtc);
     }
 
-    fn new() -> Rtc {
+    fn new(start: Option<libc::time_t>) -> Rtc {
         Rtc {
             idx:0,
-            data: [0; 128]
+            data: [0; 128],
+            start: start.map(|t| (t, Instant::now())),
+        }
+    }
+
+    fn current_time(&self) -> libc::time_t {
+        match self.start {
+            Some((t, started)) => t + started.elapsed().as_secs() as libc::time_t,
+            None => unsafe { libc::time(ptr::null_mut()) },
         }
     }
 
@@ -58,7 +74,7 @@ impl Rtc {
     }
 
     fn data_in(&mut self) -> u8 {
-        let now = RtcTime::now();
+        let now = RtcTime::from_time(self.current_time());
         match self.idx {
             RTC_SECONDS => now.seconds,
             RTC_MINUTES => now.minutes,
@@ -92,14 +108,12 @@ struct RtcTime {
 }
 
 impl RtcTime {
-    fn now() -> RtcTime {
+    fn from_time(time: libc::time_t) -> RtcTime {
         fn bcd(val: i32) -> u8 {
             (((val/10) << 4) + (val % 10)) as u8
         }
         unsafe {
             let mut tm: libc::tm = mem::zeroed();
-            let mut time: libc::time_t = 0;
-            libc::time(&mut time as *mut _);
             libc::gmtime_r(&time, &mut tm as *mut _);
             RtcTime {
                 seconds: bcd(tm.tm_sec),
@@ -1,8 +1,18 @@
-use std::sync::{Arc, RwLock};
-use std::io::{self, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use std::io;
 
 use crate::vm::io::{IoPortOps,IoDispatcher};
 use crate::kvm::Kvm;
+use crate::util::remove_stale_socket;
+use crate::vm::console;
 
 const UART_TX: u16 = 0;
 const UART_RX: u16 = 0;
@@ -43,6 +53,110 @@ const UART_SCR: u16 = 7;
 
 const FIFO_LEN: usize = 64;
 
+// Number of the standard COM ports
+pub const MAX_UARTS: u8 = 4;
+
+// A socket client which does not read its output for this long is dropped,
+// so that a vcpu writing to the port is not held up for longer
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+// How long input from a socket client waits for room in the receive FIFO
+const RX_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+///
+/// Where the output of a UART goes on the host, and where its input comes
+/// from.
+///
+#[derive(Clone,Debug,PartialEq)]
+pub enum UartBackend {
+    /// Output is discarded
+    Null,
+    /// Output goes wherever console output goes
+    Stdio,
+    /// Output is appended to the file at the path
+    File(PathBuf),
+    /// A client of a unix socket at the path receives the output and sends
+    /// the input of the port
+    Unix(PathBuf),
+}
+
+impl UartBackend {
+    pub fn parse(s: &str) -> Option<Self> {
+        let path = |prefix: &str| if s.starts_with(prefix) && s.len() > prefix.len() {
+            Some(PathBuf::from(&s[prefix.len()..]))
+        } else {
+            None
+        };
+        match s {
+            "null" => Some(UartBackend::Null),
+            "stdio" => Some(UartBackend::Stdio),
+            _ => path("file:").map(UartBackend::File)
+                .or_else(|| path("unix:").map(UartBackend::Unix)),
+        }
+    }
+}
+
+enum Output {
+    Null,
+    Stdio,
+    File(File),
+    Client(Arc<Mutex<Option<UnixStream>>>),
+}
+
+impl Output {
+    fn write(&mut self, buf: &[u8]) {
+        match self {
+            Output::Null => {},
+            Output::Stdio => console::write_serial(buf),
+            Output::File(file) => {
+                if let Err(e) = file.write_all(buf) {
+                    warn!("Failed to write serial port output to file, no more output will be written: {}", e);
+                    *self = Output::Null;
+                }
+            }
+            Output::Client(client) => {
+                let mut client = client.lock().unwrap();
+                let failed = match *client {
+                    Some(ref mut stream) => stream.write_all(buf).is_err(),
+                    None => false,
+                };
+                if failed {
+                    if let Some(stream) = client.take() {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                }
+            }
+        }
+    }
+}
+
+///
+/// An interrupt line shared by the UARTs on it, which is raised while any of
+/// them raises it. COM1 and COM3 share IRQ 4, and COM2 and COM4 IRQ 3.
+///
+struct IrqLine {
+    kvm: Kvm,
+    irq: u8,
+    // One bit for each UART which raises the line
+    raised: Mutex<u8>,
+}
+
+impl IrqLine {
+    fn set(&self, id: u8, level: bool) {
+        let mut raised = self.raised.lock().unwrap();
+        let old = *raised != 0;
+        if level {
+            *raised |= 1 << id;
+        } else {
+            *raised &= !(1 << id);
+        }
+        let new = *raised != 0;
+        if old != new {
+            self.kvm.irq_line(self.irq as u32, new as u32).unwrap();
+        }
+    }
+}
+
 
 
 trait Bits {
@@ -65,10 +179,14 @@ impl Bits for u8 {
     }
 }
 
+///
+/// A 16550 UART at one of the standard COM port addresses.
+///
 pub struct SerialDevice {
+    id: u8,
     iobase: u16,
-    kvm: Kvm,
-    irq: u8,
+    line: Arc<IrqLine>,
+    output: Output,
     irq_state: u8,
     txcnt: usize,
     rxcnt: usize,
@@ -103,7 +221,7 @@ impl SerialDevice {
     fn flush_tx(&mut self) {
         self.lsr.set(UART_LSR_TEMT | UART_LSR_THRE);
         if self.txcnt > 0 {
-            io::stdout().write(&self.txbuf[..self.txcnt]).unwrap();
+            self.output.write(&self.txbuf[..self.txcnt]);
             self.txcnt = 0;
         }
     }
@@ -134,12 +252,12 @@ impl SerialDevice {
         if iir == 0 {
             self.iir = UART_IIR_NO_INT;
             if self.irq_state != 0 {
-                self.kvm.irq_line(self.irq as u32, 0).unwrap();
+                self.line.set(self.id, false);
             }
         } else {
             self.iir = iir;
             if self.irq_state == 0 {
-                self.kvm.irq_line(self.irq as u32, 1).unwrap();
+                self.line.set(self.id, true);
             }
         }
         self.irq_state = iir;
@@ -184,9 +302,9 @@ impl SerialDevice {
             },
             UART_IER => {
                 if self.lcr.is_set(UART_LCR_DLAB) {
-                    self.ier = data & 0x0f;
-                } else {
                     self.dlm = data;
+                } else {
+                    self.ier = data & 0x0f;
                 }
             },
             UART_FCR => {
@@ -226,7 +344,7 @@ impl SerialDevice {
                 }
             },
             UART_IIR => {
-                data = self.iir & UART_IIR_TYPE_BITS;
+                data = self.iir | UART_IIR_TYPE_BITS;
             },
             UART_LCR => {
                 data = self.lcr;
@@ -270,10 +388,116 @@ impl SerialDevice {
         }
     }
 
-    pub fn register(kvm: Kvm, io: Arc<IoDispatcher>, id: u8) {
-        if let Some((base,irq)) = SerialDevice::base_irq_for_id(id) {
-            let dev = SerialDevice::new(kvm, base, irq);
-            io.register_ioports(base, 8, Arc::new(RwLock::new(dev)));
+    // Add bytes from the host to the receive FIFO, returning how many fit
+    fn receive(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(FIFO_LEN - self.rxcnt);
+        if n > 0 {
+            self.rxbuf[self.rxcnt..self.rxcnt + n].copy_from_slice(&data[..n]);
+            self.rxcnt += n;
+            self.lsr.set(UART_LSR_DR);
+            self.update_irq();
+        }
+        n
+    }
+
+    /// Add a UART for each port in `ports`, numbered from 0 for COM1, with
+    /// its backend. A backend which cannot be opened is replaced by `Null`.
+    pub fn register_ports(kvm: &Kvm, io: &IoDispatcher, ports: &[(u8, UartBackend)]) {
+        let mut lines: Vec<Arc<IrqLine>> = Vec::new();
+        for (id, backend) in ports {
+            let (base, irq) = match SerialDevice::base_irq_for_id(*id) {
+                Some(base_irq) => base_irq,
+                None => continue,
+            };
+            let line = match lines.iter().find(|l| l.irq == irq) {
+                Some(line) => line.clone(),
+                None => {
+                    kvm.reserve_gsi(irq as u32);
+                    let line = Arc::new(IrqLine { kvm: kvm.clone(), irq, raised: Mutex::new(0) });
+                    lines.push(line.clone());
+                    line
+                }
+            };
+            let (output, listener) = match Self::open_backend(backend) {
+                Ok(backend) => backend,
+                Err(e) => {
+                    warn!("Failed to open backend of serial port {}: {}", id, e);
+                    (Output::Null, None)
+                }
+            };
+            let client = match output {
+                Output::Client(ref client) => Some(client.clone()),
+                _ => None,
+            };
+            let dev = Arc::new(RwLock::new(SerialDevice::new(*id, base, line, output)));
+            io.register_ioports(base, 8, dev.clone());
+            if let (Some(listener), Some(client)) = (listener, client) {
+                thread::spawn(move || Self::client_loop(listener, client, dev));
+            }
+        }
+    }
+
+    fn open_backend(backend: &UartBackend) -> io::Result<(Output, Option<UnixListener>)> {
+        match backend {
+            UartBackend::Null => Ok((Output::Null, None)),
+            UartBackend::Stdio => Ok((Output::Stdio, None)),
+            UartBackend::File(path) => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .mode(0o600)
+                    .custom_flags(libc::O_CLOEXEC)
+                    .open(path)?;
+                Ok((Output::File(file), None))
+            }
+            UartBackend::Unix(path) => {
+                let listener = Self::bind(path)?;
+                Ok((Output::Client(Arc::new(Mutex::new(None))), Some(listener)))
+            }
+        }
+    }
+
+    fn bind(path: &Path) -> io::Result<UnixListener> {
+        remove_stale_socket(path)?;
+        UnixListener::bind(path)
+    }
+
+    // Pass input from one client of the socket at a time to the UART. The
+    // client receives the output of the port while it is connected.
+    fn client_loop(listener: UnixListener, client: Arc<Mutex<Option<UnixStream>>>, dev: Arc<RwLock<SerialDevice>>) {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Error accepting connection to serial port: {}", e);
+                    continue;
+                }
+            };
+            let writer = stream.try_clone()
+                .and_then(|s| s.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)).map(|_| s));
+            match writer {
+                Ok(writer) => *client.lock().unwrap() = Some(writer),
+                Err(e) => {
+                    warn!("Error accepting connection to serial port: {}", e);
+                    continue;
+                }
+            }
+            let mut buf = [0u8; FIFO_LEN];
+            loop {
+                let n = match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let mut data = &buf[..n];
+                while !data.is_empty() {
+                    let taken = dev.write().unwrap().receive(data);
+                    data = &data[taken..];
+                    if !data.is_empty() {
+                        thread::sleep(RX_RETRY_INTERVAL);
+                    }
+                }
+            }
+            *client.lock().unwrap() = None;
         }
     }
 
@@ -287,11 +511,12 @@ impl SerialDevice {
         }
     }
 
-    fn new(kvm: Kvm, iobase: u16, irq: u8) -> SerialDevice {
+    fn new(id: u8, iobase: u16, line: Arc<IrqLine>, output: Output) -> SerialDevice {
         SerialDevice {
+            id,
             iobase,
-            kvm,
-            irq,
+            line,
+            output,
             irq_state: 0,
             txcnt: 0,
             rxcnt: 0,
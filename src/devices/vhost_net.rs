@@ -0,0 +1,268 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::{fmt, io, mem, result, thread};
+
+use crate::memory::GuestRam;
+use crate::system::{self, EPoll, EventFd, Tap};
+use crate::system::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
+use crate::virtio::VirtQueue;
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+const VHOST: u64 = 0xAF;
+const VHOST_GET_FEATURES: libc::c_ulong = ior!(VHOST, 0x00, 8);
+const VHOST_SET_FEATURES: libc::c_ulong = iow!(VHOST, 0x00, 8);
+const VHOST_SET_OWNER: libc::c_ulong = io!(VHOST, 0x01);
+const VHOST_SET_MEM_TABLE: libc::c_ulong = iow!(VHOST, 0x03, mem::size_of::<VhostMemoryHeader>());
+const VHOST_SET_VRING_NUM: libc::c_ulong = iow!(VHOST, 0x10, mem::size_of::<VhostVringState>());
+const VHOST_SET_VRING_ADDR: libc::c_ulong = iow!(VHOST, 0x11, mem::size_of::<VhostVringAddr>());
+const VHOST_SET_VRING_BASE: libc::c_ulong = iow!(VHOST, 0x12, mem::size_of::<VhostVringState>());
+const VHOST_SET_VRING_KICK: libc::c_ulong = iow!(VHOST, 0x20, mem::size_of::<VhostVringFile>());
+const VHOST_SET_VRING_CALL: libc::c_ulong = iow!(VHOST, 0x21, mem::size_of::<VhostVringFile>());
+const VHOST_NET_SET_BACKEND: libc::c_ulong = iow!(VHOST, 0x30, mem::size_of::<VhostVringFile>());
+
+const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
+const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+const VIRTIO_F_ANY_LAYOUT: u64 = 1 << 27;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Negotiated features which change how the rings are processed and so must
+// be supported by the host kernel. The network offload features only concern
+// the tap device.
+const VHOST_RING_FEATURES: u64 =
+    VIRTIO_RING_F_INDIRECT_DESC |
+    VIRTIO_RING_F_EVENT_IDX |
+    VIRTIO_F_ANY_LAYOUT |
+    VIRTIO_F_VERSION_1;
+
+#[derive(Debug)]
+pub enum Error {
+    Open(io::Error),
+    Ioctl(system::Error),
+    InvalidRing(system::Error),
+    UnsupportedFeatures(u64),
+    CallEventFd(system::Error),
+    SetupPoll(system::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Open(e) => write!(f, "failed to open {}: {}", VHOST_NET_PATH, e),
+            Ioctl(e) => write!(f, "{}", e),
+            InvalidRing(e) => write!(f, "virtqueue is not in guest memory: {}", e),
+            UnsupportedFeatures(bits) => write!(f, "negotiated features {:016x} are not supported by vhost-net", bits),
+            CallEventFd(e) => write!(f, "failed to create vring call eventfd: {}", e),
+            SetupPoll(e) => write!(f, "failed to set up poll for vring call eventfds: {}", e),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+#[repr(C)]
+struct VhostMemoryHeader {
+    nregions: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct VhostMemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+#[repr(C)]
+struct VhostVringState {
+    index: u32,
+    num: u32,
+}
+
+#[repr(C)]
+struct VhostVringAddr {
+    index: u32,
+    flags: u32,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+#[repr(C)]
+struct VhostVringFile {
+    index: u32,
+    fd: RawFd,
+}
+
+///
+/// Moves packets between the virtqueues of a virtio-net device and a tap
+/// device in the host kernel with `/dev/vhost-net`.
+///
+/// The kernel reads the guest rings directly through a table of the guest
+/// memory regions and is notified by the ioeventfds of the queues. When it
+/// places used entries in a ring it signals a call eventfd, and a thread
+/// forwards the signal to the interrupt line of the queue. The interrupt is
+/// not passed directly to KVM as an irqfd because a legacy interrupt line
+/// also needs the ISR of the device to be set.
+///
+/// The device must be opened while pH still has the privileges to open
+/// `/dev/vhost-net`.
+///
+pub struct VhostNet {
+    file: File,
+}
+
+impl VhostNet {
+    pub fn open() -> Result<VhostNet> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(VHOST_NET_PATH)
+            .map_err(Error::Open)?;
+        let vhost = VhostNet { file };
+        unsafe {
+            ioctl_with_val(vhost.fd(), VHOST_SET_OWNER, 0)
+                .map_err(|e| Self::ioctl_error("VHOST_SET_OWNER", e))?;
+        }
+        Ok(vhost)
+    }
+
+    fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    fn ioctl_error(name: &'static str, err: system::ErrnoError) -> Error {
+        Error::Ioctl(system::Error::IoctlError(name, err))
+    }
+
+    fn get_features(&self) -> Result<u64> {
+        let mut features = 0u64;
+        unsafe {
+            ioctl_with_mut_ref(self.fd(), VHOST_GET_FEATURES, &mut features)
+                .map_err(|e| Self::ioctl_error("VHOST_GET_FEATURES", e))?;
+        }
+        Ok(features)
+    }
+
+    fn set_features(&self, features: u64) -> Result<()> {
+        unsafe {
+            ioctl_with_ref(self.fd(), VHOST_SET_FEATURES, &features)
+                .map_err(|e| Self::ioctl_error("VHOST_SET_FEATURES", e))?;
+        }
+        Ok(())
+    }
+
+    fn set_mem_table(&self, memory: &GuestRam) -> Result<()> {
+        let header = VhostMemoryHeader {
+            nregions: memory.regions().len() as u32,
+            padding: 0,
+        };
+        // The header is followed by a variable length array of regions
+        let header_words = mem::size_of::<VhostMemoryHeader>() / mem::size_of::<u64>();
+        let region_words = mem::size_of::<VhostMemoryRegion>() / mem::size_of::<u64>();
+        let mut table = vec![0u64; header_words + memory.regions().len() * region_words];
+        unsafe {
+            let ptr = table.as_mut_ptr() as *mut VhostMemoryHeader;
+            ptr.write(header);
+            let regions = ptr.add(1) as *mut VhostMemoryRegion;
+            for (i, r) in memory.regions().iter().enumerate() {
+                regions.add(i).write(VhostMemoryRegion {
+                    guest_phys_addr: r.guest_range().base(),
+                    memory_size: r.guest_range().size() as u64,
+                    userspace_addr: r.base_address(),
+                    flags_padding: 0,
+                });
+            }
+            ioctl_with_ref(self.fd(), VHOST_SET_MEM_TABLE, &*ptr)
+                .map_err(|e| Self::ioctl_error("VHOST_SET_MEM_TABLE", e))?;
+        }
+        Ok(())
+    }
+
+    fn set_vring(&self, index: u32, memory: &GuestRam, queue: &VirtQueue, call: &EventFd) -> Result<()> {
+        let size = queue.size() as usize;
+        let (desc, avail, used) = queue.ring_addresses();
+        let host_address = |addr, len| memory.host_address(addr, len)
+            .map_err(Error::InvalidRing);
+        let addr = VhostVringAddr {
+            index,
+            flags: 0,
+            desc_user_addr: host_address(desc, 16 * size)?,
+            used_user_addr: host_address(used, 6 + 8 * size)?,
+            avail_user_addr: host_address(avail, 6 + 2 * size)?,
+            log_guest_addr: 0,
+        };
+        unsafe {
+            ioctl_with_ref(self.fd(), VHOST_SET_VRING_NUM, &VhostVringState { index, num: size as u32 })
+                .map_err(|e| Self::ioctl_error("VHOST_SET_VRING_NUM", e))?;
+            ioctl_with_ref(self.fd(), VHOST_SET_VRING_BASE, &VhostVringState { index, num: queue.next_avail() as u32 })
+                .map_err(|e| Self::ioctl_error("VHOST_SET_VRING_BASE", e))?;
+            ioctl_with_ref(self.fd(), VHOST_SET_VRING_ADDR, &addr)
+                .map_err(|e| Self::ioctl_error("VHOST_SET_VRING_ADDR", e))?;
+            ioctl_with_ref(self.fd(), VHOST_SET_VRING_KICK, &VhostVringFile { index, fd: queue.ioevent().as_raw_fd() })
+                .map_err(|e| Self::ioctl_error("VHOST_SET_VRING_KICK", e))?;
+            ioctl_with_ref(self.fd(), VHOST_SET_VRING_CALL, &VhostVringFile { index, fd: call.as_raw_fd() })
+                .map_err(|e| Self::ioctl_error("VHOST_SET_VRING_CALL", e))?;
+        }
+        Ok(())
+    }
+
+    fn set_backend(&self, index: u32, tap: &Tap) -> Result<()> {
+        unsafe {
+            ioctl_with_ref(self.fd(), VHOST_NET_SET_BACKEND, &VhostVringFile { index, fd: tap.as_raw_fd() })
+                .map_err(|e| Self::ioctl_error("VHOST_NET_SET_BACKEND", e))?;
+        }
+        Ok(())
+    }
+
+    /// Hand the receive and transmit queues to the host kernel and start
+    /// forwarding interrupts. `features` are the features negotiated by the
+    /// guest driver.
+    pub fn start(self, memory: &GuestRam, rx: &VirtQueue, tx: &VirtQueue, tap: &Tap, features: u64) -> Result<()> {
+        let required = features & VHOST_RING_FEATURES;
+        let missing = required & !self.get_features()?;
+        if missing != 0 {
+            return Err(Error::UnsupportedFeatures(missing));
+        }
+        self.set_features(required)?;
+        self.set_mem_table(memory)?;
+
+        let poll = EPoll::new().map_err(Error::SetupPoll)?;
+        let queues = vec![rx.clone(), tx.clone()];
+        let mut calls = Vec::new();
+        for (idx, queue) in queues.iter().enumerate() {
+            let call = EventFd::new().map_err(Error::CallEventFd)?;
+            self.set_vring(idx as u32, memory, queue, &call)?;
+            poll.add_read(call.as_raw_fd(), idx as u64).map_err(Error::SetupPoll)?;
+            calls.push(call);
+        }
+        for idx in 0..queues.len() {
+            self.set_backend(idx as u32, tap)?;
+        }
+
+        thread::spawn(move || {
+            // The kernel processes the queues only while the vhost device is open
+            let _vhost = self;
+            if let Err(e) = forward_interrupts(poll, &queues, &calls) {
+                warn!("vhost-net: error forwarding interrupts: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+pub(crate) fn forward_interrupts(mut poll: EPoll, queues: &[VirtQueue], calls: &[EventFd]) -> system::Result<()> {
+    loop {
+        let events = poll.wait()?;
+        for ev in events.iter() {
+            let idx = ev.id() as usize;
+            calls[idx].read()?;
+            queues[idx].notify_used();
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::{fmt, io, result, thread};
+
+use crate::devices::MacAddress;
+use crate::devices::vhost_net::forward_interrupts;
+use crate::memory::{GuestRam, MemoryManager};
+use crate::system::{self, EPoll, EventFd, ScmSocket};
+use crate::virtio::{self, VirtioBus, VirtioDeviceOps, VirtQueue};
+
+const VIRTIO_ID_NET: u16 = 1;
+const VIRTIO_ID_FS: u16 = 26;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+// Transport features which may be offered to the guest when the backend
+// supports them, besides the device specific features in the low 24 bits
+const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
+const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const DEVICE_FEATURES: u64 = (1 << 24) - 1;
+const TRANSPORT_FEATURES: u64 =
+    VIRTIO_RING_F_INDIRECT_DESC |
+    VIRTIO_RING_F_EVENT_IDX |
+    VIRTIO_F_VERSION_1;
+
+// Offered by a backend which takes GET_PROTOCOL_FEATURES and starts each ring
+// disabled until SET_VRING_ENABLE
+const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+
+const VHOST_USER_GET_FEATURES: u32 = 1;
+const VHOST_USER_SET_FEATURES: u32 = 2;
+const VHOST_USER_SET_OWNER: u32 = 3;
+const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+const VHOST_USER_SET_VRING_NUM: u32 = 8;
+const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+const VHOST_USER_SET_VRING_BASE: u32 = 10;
+const VHOST_USER_GET_VRING_BASE: u32 = 11;
+const VHOST_USER_SET_VRING_KICK: u32 = 12;
+const VHOST_USER_SET_VRING_CALL: u32 = 13;
+const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+
+const VHOST_USER_VERSION: u32 = 1;
+const VHOST_USER_REPLY: u32 = 1 << 2;
+const VHOST_USER_HEADER_SIZE: usize = 12;
+
+// The largest number of memory regions in a SET_MEM_TABLE message
+const VHOST_USER_MAX_REGIONS: usize = 8;
+
+const VIRTIO_FS_TAG_LEN: usize = 36;
+
+#[derive(Debug)]
+pub enum Error {
+    Connect(PathBuf, io::Error),
+    Send(u32, system::ErrnoError),
+    Receive(u32, io::Error),
+    InvalidReply(u32),
+    RamNotShared,
+    InvalidRing(system::Error),
+    CallEventFd(system::Error),
+    SetupPoll(system::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Connect(path, e) => write!(f, "failed to connect to {}: {}", path.display(), e),
+            Send(request, e) => write!(f, "failed to send request {}: {}", request, e),
+            Receive(request, e) => write!(f, "failed to receive reply to request {}: {}", request, e),
+            InvalidReply(request) => write!(f, "invalid reply to request {}", request),
+            RamNotShared => write!(f, "guest RAM is not backed by a file which can be shared"),
+            InvalidRing(e) => write!(f, "virtqueue is not in guest memory: {}", e),
+            CallEventFd(e) => write!(f, "failed to create vring call eventfd: {}", e),
+            SetupPoll(e) => write!(f, "failed to set up poll for vring call eventfds: {}", e),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+///
+/// The kind of a device whose queues are processed by a vhost-user backend,
+/// and the configuration pH provides for it.
+///
+#[derive(Clone,Debug,PartialEq)]
+pub enum VhostUserKind {
+    /// A virtio-fs device exporting a directory with mount tag `tag`, served
+    /// by a daemon such as virtiofsd.
+    Fs(String),
+    /// A virtio-net device with the given hardware address.
+    Net(MacAddress),
+}
+
+///
+/// A vhost-user device declared with `--vhost-user` or
+/// `VmConfig::vhost_user()`: its kind and the socket its backend listens on.
+///
+#[derive(Clone,Debug,PartialEq)]
+pub struct VhostUserSpec {
+    kind: VhostUserKind,
+    socket: PathBuf,
+}
+
+impl VhostUserSpec {
+    pub fn new<P: Into<PathBuf>>(kind: VhostUserKind, socket: P) -> Self {
+        VhostUserSpec { kind, socket: socket.into() }
+    }
+
+    /// Parse `fs:SOCKET,tag=TAG` or `net:SOCKET[,mac=ADDRESS]`.
+    pub fn parse(s: &str) -> Option<VhostUserSpec> {
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next()?;
+        let mut options = parts.next()?.split(',');
+        let socket = options.next().filter(|p| !p.is_empty())?;
+        let mut tag = None;
+        let mut mac = None;
+        for option in options {
+            let mut kv = option.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("tag"), Some(t)) if !t.is_empty() && t.len() <= VIRTIO_FS_TAG_LEN => tag = Some(t.to_string()),
+                (Some("mac"), Some(addr)) => mac = Some(MacAddress::parse(addr)?),
+                _ => return None,
+            }
+        }
+        let kind = match (kind, tag, mac) {
+            ("fs", Some(tag), None) => VhostUserKind::Fs(tag),
+            ("net", None, mac) => VhostUserKind::Net(mac.unwrap_or_else(MacAddress::random)),
+            _ => return None,
+        };
+        Some(VhostUserSpec::new(kind, socket))
+    }
+
+    pub fn kind(&self) -> &VhostUserKind {
+        &self.kind
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    fn device_type(&self) -> u16 {
+        match self.kind {
+            VhostUserKind::Fs(_) => VIRTIO_ID_FS,
+            VhostUserKind::Net(_) => VIRTIO_ID_NET,
+        }
+    }
+
+    // A high priority queue and one request queue, or a receive and a
+    // transmit queue
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        match self.kind {
+            VhostUserKind::Fs(ref tag) => {
+                let mut config = tag.as_bytes().to_vec();
+                config.resize(VIRTIO_FS_TAG_LEN, 0);
+                // num_request_queues
+                config.extend_from_slice(&1u32.to_le_bytes());
+                config
+            }
+            VhostUserKind::Net(mac) => {
+                let mut config = mac.octets().to_vec();
+                // status, which is only read with VIRTIO_NET_F_STATUS
+                config.extend_from_slice(&[0, 0]);
+                config
+            }
+        }
+    }
+
+    fn extra_features(&self) -> u64 {
+        match self.kind {
+            VhostUserKind::Fs(_) => 0,
+            VhostUserKind::Net(_) => VIRTIO_NET_F_MAC,
+        }
+    }
+}
+
+///
+/// The master side of a connection to a vhost-user backend, a process which
+/// reads and writes the rings of a device directly in guest memory.
+///
+/// Each message is a header of the request code, flags and payload size, all
+/// 32-bit little endian, followed by the payload. File descriptors travel
+/// with a message as `SCM_RIGHTS`: the files which back guest RAM with the
+/// memory table, and the eventfds of a queue with the kick and call requests.
+/// The backend is told where each ring is mapped in this process, and maps
+/// guest RAM itself to translate those addresses.
+///
+pub struct VhostUserBackend {
+    socket: UnixStream,
+    features: u64,
+}
+
+impl VhostUserBackend {
+    pub fn connect(path: &Path) -> Result<VhostUserBackend> {
+        let socket = UnixStream::connect(path)
+            .map_err(|e| Error::Connect(path.to_path_buf(), e))?;
+        let mut backend = VhostUserBackend { socket, features: 0 };
+        backend.send(VHOST_USER_SET_OWNER, &[], &[])?;
+        backend.features = backend.get_u64(VHOST_USER_GET_FEATURES, &[])?;
+        if backend.has_protocol_features() {
+            // None of the protocol extensions are used
+            let _ = backend.get_u64(VHOST_USER_GET_PROTOCOL_FEATURES, &[])?;
+            backend.send(VHOST_USER_SET_PROTOCOL_FEATURES, &0u64.to_le_bytes(), &[])?;
+        }
+        Ok(backend)
+    }
+
+    fn has_protocol_features(&self) -> bool {
+        self.features & VHOST_USER_F_PROTOCOL_FEATURES != 0
+    }
+
+    fn send(&self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        let mut msg = Vec::with_capacity(VHOST_USER_HEADER_SIZE + payload.len());
+        msg.extend_from_slice(&request.to_le_bytes());
+        msg.extend_from_slice(&VHOST_USER_VERSION.to_le_bytes());
+        msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        msg.extend_from_slice(payload);
+        self.socket.send_with_fds(&msg, fds)
+            .map_err(|e| Error::Send(request, e))?;
+        Ok(())
+    }
+
+    // Send a request which is answered with a 64-bit value, or with a vring
+    // state which is read as one
+    fn get_u64(&self, request: u32, payload: &[u8]) -> Result<u64> {
+        self.send(request, payload, &[])?;
+        let mut reply = [0u8; VHOST_USER_HEADER_SIZE + 8];
+        (&self.socket).read_exact(&mut reply)
+            .map_err(|e| Error::Receive(request, e))?;
+        let word = |i: usize| u32::from_le_bytes([reply[i], reply[i + 1], reply[i + 2], reply[i + 3]]);
+        if word(0) != request || word(4) & VHOST_USER_REPLY == 0 || word(8) != 8 {
+            return Err(Error::InvalidReply(request));
+        }
+        let mut value = [0u8; 8];
+        value.copy_from_slice(&reply[VHOST_USER_HEADER_SIZE..]);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn vring_state(index: u32, num: u32) -> Vec<u8> {
+        let mut payload = index.to_le_bytes().to_vec();
+        payload.extend_from_slice(&num.to_le_bytes());
+        payload
+    }
+
+    fn set_mem_table(&self, memory: &GuestRam) -> Result<()> {
+        let regions = memory.regions();
+        if regions.len() > VHOST_USER_MAX_REGIONS {
+            return Err(Error::RamNotShared);
+        }
+        let mut payload = (regions.len() as u32).to_le_bytes().to_vec();
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        let mut fds = Vec::new();
+        for r in regions {
+            let (fd, offset) = r.backing_file().ok_or(Error::RamNotShared)?;
+            payload.extend_from_slice(&r.guest_range().base().to_le_bytes());
+            payload.extend_from_slice(&(r.guest_range().size() as u64).to_le_bytes());
+            payload.extend_from_slice(&r.base_address().to_le_bytes());
+            payload.extend_from_slice(&(offset as u64).to_le_bytes());
+            fds.push(fd);
+        }
+        self.send(VHOST_USER_SET_MEM_TABLE, &payload, &fds)
+    }
+
+    fn set_vring(&self, index: u32, memory: &GuestRam, queue: &VirtQueue, call: &EventFd) -> Result<()> {
+        let size = queue.size() as usize;
+        let (desc, avail, used) = queue.ring_addresses();
+        let host_address = |addr, len| memory.host_address(addr, len)
+            .map_err(Error::InvalidRing);
+        let mut addr = Self::vring_state(index, 0);
+        addr.extend_from_slice(&host_address(desc, 16 * size)?.to_le_bytes());
+        addr.extend_from_slice(&host_address(used, 6 + 8 * size)?.to_le_bytes());
+        addr.extend_from_slice(&host_address(avail, 6 + 2 * size)?.to_le_bytes());
+        // log_guest_addr
+        addr.extend_from_slice(&0u64.to_le_bytes());
+
+        self.send(VHOST_USER_SET_VRING_NUM, &Self::vring_state(index, size as u32), &[])?;
+        self.send(VHOST_USER_SET_VRING_BASE, &Self::vring_state(index, u32::from(queue.next_avail())), &[])?;
+        self.send(VHOST_USER_SET_VRING_ADDR, &addr, &[])?;
+        // Without protocol features a ring starts once it has a kick eventfd,
+        // so it is given last
+        let file = u64::from(index).to_le_bytes();
+        self.send(VHOST_USER_SET_VRING_CALL, &file, &[call.as_raw_fd()])?;
+        self.send(VHOST_USER_SET_VRING_KICK, &file, &[queue.ioevent().as_raw_fd()])?;
+        if self.has_protocol_features() {
+            self.send(VHOST_USER_SET_VRING_ENABLE, &Self::vring_state(index, 1), &[])?;
+        }
+        Ok(())
+    }
+
+    /// Hand `queues` to the backend and start forwarding interrupts.
+    /// `features` are the features negotiated by the guest driver.
+    fn start(&self, memory: &GuestRam, queues: &[VirtQueue], features: u64) -> Result<()> {
+        let protocol = self.features & VHOST_USER_F_PROTOCOL_FEATURES;
+        self.send(VHOST_USER_SET_FEATURES, &(features | protocol).to_le_bytes(), &[])?;
+        self.set_mem_table(memory)?;
+
+        let poll = EPoll::new().map_err(Error::SetupPoll)?;
+        let mut calls = Vec::new();
+        for (idx, queue) in queues.iter().enumerate() {
+            let call = EventFd::new().map_err(Error::CallEventFd)?;
+            self.set_vring(idx as u32, memory, queue, &call)?;
+            poll.add_read(call.as_raw_fd(), idx as u64).map_err(Error::SetupPoll)?;
+            calls.push(call);
+        }
+        let queues = queues.to_vec();
+        thread::spawn(move || {
+            if let Err(e) = forward_interrupts(poll, &queues, &calls) {
+                warn!("vhost-user: error forwarding interrupts: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    // Stop each ring, which the backend does when asked where it stopped
+    fn stop(&self, nqueues: usize) -> Result<()> {
+        for idx in 0..nqueues {
+            self.get_u64(VHOST_USER_GET_VRING_BASE, &Self::vring_state(idx as u32, 0))?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// A virtio device whose queues are processed by a vhost-user backend. pH
+/// only provides the PCI transport and the configuration space of the
+/// device, and forwards the interrupts signalled by the backend.
+///
+/// Guest RAM must be backed by a file so that the backend can map it, which
+/// `VmConfig` arranges when any vhost-user device is declared.
+///
+pub struct VhostUser {
+    spec: VhostUserSpec,
+    backend: VhostUserBackend,
+    features: u64,
+    config: Vec<u8>,
+    running_queues: usize,
+}
+
+impl VhostUser {
+    pub fn create(vbus: &mut VirtioBus, spec: &VhostUserSpec, backend: VhostUserBackend) -> virtio::Result<()> {
+        let features = (backend.features & (DEVICE_FEATURES | TRANSPORT_FEATURES)) | spec.extra_features();
+        let config = spec.config();
+        let config_size = config.len();
+        let dev = Arc::new(RwLock::new(VhostUser {
+            spec: spec.clone(),
+            backend,
+            features: 0,
+            config,
+            running_queues: 0,
+        }));
+        vbus.new_virtio_device(spec.device_type(), dev)
+            .set_num_queues(spec.num_queues())
+            .set_features(features)
+            .set_config_size(config_size)
+            .register()
+    }
+}
+
+impl VirtioDeviceOps for VhostUser {
+    fn reset(&mut self) {
+        if self.running_queues > 0 {
+            if let Err(e) = self.backend.stop(self.running_queues) {
+                warn!("vhost-user {}: failed to stop backend: {}", self.spec.socket().display(), e);
+            }
+            self.running_queues = 0;
+        }
+    }
+
+    fn enable_features(&mut self, bits: u64) -> bool {
+        self.features = bits;
+        true
+    }
+
+    fn read_config(&mut self, offset: usize, size: usize) -> u64 {
+        virtio::read_config_buffer(&self.config, offset, size)
+    }
+
+    fn start(&mut self, memory: &MemoryManager, queues: Vec<VirtQueue>) {
+        // The features which pH provides itself are not passed on
+        let features = self.features & self.backend.features;
+        match self.backend.start(memory.guest_ram(), &queues, features) {
+            Ok(()) => self.running_queues = queues.len(),
+            Err(e) => warn!("vhost-user {}: failed to start backend: {}", self.spec.socket().display(), e),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::os::unix::io::RawFd;
+
+use crate::devices::virtio_9p::file::P9File;
+
+///
+/// How the host page cache should treat the files of a share, passed to the
+/// kernel with `posix_fadvise()`.
+///
+/// Reading through a large file, such as a video played from the home
+/// directory, fills the page cache with pages which are read only once and
+/// pushes out everything else. `Sequential` doubles read ahead, `NoReuse`
+/// marks the data as accessed once, and `DontNeed` drops the pages of each
+/// read from the cache as soon as it completes. Writes are not affected.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum CacheHint {
+    Normal,
+    Sequential,
+    NoReuse,
+    DontNeed,
+}
+
+impl CacheHint {
+    /// Parse `normal`, `sequential`, `noreuse` or `dontneed`.
+    pub fn parse(s: &str) -> Option<CacheHint> {
+        match s {
+            "normal" => Some(CacheHint::Normal),
+            "sequential" => Some(CacheHint::Sequential),
+            "noreuse" => Some(CacheHint::NoReuse),
+            "dontneed" => Some(CacheHint::DontNeed),
+            _ => None,
+        }
+    }
+
+    // The hints are only advice, so failures are ignored
+    fn advise(fd: RawFd, offset: u64, len: usize, advice: libc::c_int) {
+        unsafe {
+            libc::posix_fadvise(fd, offset as libc::off_t, len as libc::off_t, advice);
+        }
+    }
+
+    /// Give the advice which applies to the whole of a newly opened `file`.
+    pub fn advise_open(self, file: &P9File) {
+        let advice = match self {
+            CacheHint::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            CacheHint::NoReuse => libc::POSIX_FADV_NOREUSE,
+            _ => return,
+        };
+        if let Some(fd) = file.host_fd() {
+            Self::advise(fd, 0, 0, advice);
+        }
+    }
+
+    /// Give the advice which follows a read of `len` bytes at `offset`.
+    pub fn advise_read(self, file: &P9File, offset: u64, len: usize) {
+        if self != CacheHint::DontNeed || len == 0 {
+            return;
+        }
+        if let Some(fd) = file.host_fd() {
+            Self::advise(fd, offset, len, libc::POSIX_FADV_DONTNEED);
+        }
+    }
+}
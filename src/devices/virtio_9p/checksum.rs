@@ -0,0 +1,163 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::devices::virtio_9p::file::P9File;
+
+// Reflected CRC-32 as used by zlib, gzip and the `crc32` utility, so that
+// logged values can be compared with a checksum taken in the guest.
+const CRC32_POLY: u32 = 0xEDB8_8320;
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32_POLY } else { crc >> 1 };
+        }
+        *entry = crc;
+    }
+    table
+}
+
+struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    fn new(table: [u32; 256]) -> Self {
+        Crc32 { table, crc: !0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.crc = self.table[((self.crc ^ b as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn value(&self) -> u32 {
+        !self.crc
+    }
+}
+
+///
+/// Computes checksums of the data in a sample of reads and writes so that
+/// a report of corrupted file contents can be tracked down to a layer.
+///
+/// For each sampled read the data copied into guest memory is checksummed
+/// and the same range is then read again from the host file. For each
+/// sampled write the data taken from guest memory is checksummed and then
+/// read back from the host file after it is written. A difference between the
+/// two values points at the host filesystem (or at another process changing
+/// the file), while matching values which differ from a checksum taken in
+/// the guest point at the guest or the virtio transport.
+///
+pub struct ChecksumSampler {
+    table: [u32; 256],
+    every: u64,
+    count: u64,
+}
+
+impl ChecksumSampler {
+    /// Sample one in every `every` reads and writes.
+    pub fn new(every: u64) -> Self {
+        ChecksumSampler {
+            table: crc32_table(),
+            every: every.max(1),
+            count: 0,
+        }
+    }
+
+    /// Returns a checksum for the next operation if it is part of the sample.
+    pub fn sample(&mut self) -> Option<Checksum> {
+        self.count += 1;
+        if self.count % self.every == 0 {
+            Some(Checksum { crc: Crc32::new(self.table) })
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Checksum {
+    crc: Crc32,
+}
+
+impl Checksum {
+    pub fn update(&mut self, data: &[u8]) {
+        self.crc.update(data);
+    }
+
+    /// Compare the data seen on the transport with `len` bytes at `offset`
+    /// in `file` as they are now. A file which the guest opened write-only is
+    /// read back through a new descriptor for `path`.
+    pub fn verify(self, file: &P9File, path: &Path, offset: u64, len: usize) -> ChecksumResult {
+        let mut host = Crc32::new(self.crc.table);
+        let mut buffer = vec![0u8; len];
+        let done = match read_back(file, path, &mut buffer, offset) {
+            Ok(done) => done,
+            Err(e) => return ChecksumResult::HostUnreadable(self.crc.value(), e.raw_os_error().unwrap_or(0)),
+        };
+        host.update(&buffer[..done]);
+        if done != len {
+            ChecksumResult::HostShort(self.crc.value(), done)
+        } else if host.value() != self.crc.value() {
+            ChecksumResult::Mismatch(self.crc.value(), host.value())
+        } else {
+            ChecksumResult::Match(self.crc.value())
+        }
+    }
+}
+
+fn read_back(file: &P9File, path: &Path, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    match read_full(|buf, off| file.read_at(buf, off), buffer, offset) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EBADF) => {
+            let file = File::open(path)?;
+            read_full(|buf, off| file.read_at(buf, off), buffer, offset)
+        }
+        result => result,
+    }
+}
+
+fn read_full<F>(mut read: F, buffer: &mut [u8], offset: u64) -> io::Result<usize>
+    where F: FnMut(&mut [u8], u64) -> io::Result<usize>
+{
+    let mut done = 0;
+    while done < buffer.len() {
+        match read(&mut buffer[done..], offset + done as u64)? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    Ok(done)
+}
+
+pub enum ChecksumResult {
+    Match(u32),
+    Mismatch(u32, u32),
+    HostShort(u32, usize),
+    HostUnreadable(u32, i32),
+}
+
+impl ChecksumResult {
+    pub fn is_match(&self) -> bool {
+        match self {
+            ChecksumResult::Match(..) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ChecksumResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumResult::Match(crc) => write!(f, "crc32={:08x} host=ok", crc),
+            ChecksumResult::Mismatch(crc, host) => write!(f, "crc32={:08x} host=MISMATCH({:08x})", crc, host),
+            ChecksumResult::HostShort(crc, n) => write!(f, "crc32={:08x} host=SHORT({} bytes)", crc, n),
+            ChecksumResult::HostUnreadable(crc, errno) => write!(f, "crc32={:08x} host=unreadable(errno {})", crc, errno),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Upper bound on worker threads stuck in operations which never returned
+const MAX_WORKERS: usize = 8;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+///
+/// Runs blocking host filesystem operations on a pool of worker threads and
+/// gives up waiting for them after a timeout.
+///
+/// If the exported directory is on a mount which stops responding (an NFS
+/// server which went away, a stuck FUSE daemon) the operation fails with `EIO`
+/// instead of blocking the device thread and freezing the guest. The worker
+/// thread remains blocked in the host kernel, and if the operation completes
+/// later its result is discarded, although any side effect on the host
+/// filesystem has already happened.
+///
+/// Requests are processed one at a time by the device thread, so a worker
+/// which is not idle is always stuck in an operation which has timed out. Once
+/// `MAX_WORKERS` workers are stuck every further operation fails immediately.
+///
+#[derive(Clone)]
+pub struct Deadline {
+    pool: Option<Arc<WorkerPool>>,
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// Operations run directly on the calling thread without a timeout.
+    pub fn none() -> Self {
+        Deadline { pool: None, timeout: Duration::from_secs(0) }
+    }
+
+    pub fn new(timeout: Option<Duration>) -> Self {
+        match timeout {
+            Some(timeout) => Deadline { pool: Some(Arc::new(WorkerPool::new())), timeout },
+            None => Self::none(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    pub fn run<F, R>(&self, f: F) -> io::Result<R>
+        where F: FnOnce() -> io::Result<R> + Send + 'static,
+              R: Send + 'static
+    {
+        let pool = match self.pool {
+            Some(ref pool) => pool,
+            None => return f(),
+        };
+        let (tx, rx) = mpsc::channel();
+        if !pool.submit(Box::new(move || { let _ = tx.send(f()); })) {
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+        match rx.recv_timeout(self.timeout) {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("9p filesystem operation did not complete in {} seconds", self.timeout.as_secs());
+                Err(io::Error::from_raw_os_error(libc::EIO))
+            }
+        }
+    }
+}
+
+struct PoolState {
+    workers: usize,
+    idle: usize,
+}
+
+struct WorkerPool {
+    sender: Mutex<mpsc::Sender<Job>>,
+    receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl WorkerPool {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        WorkerPool {
+            sender: Mutex::new(sender),
+            receiver: Arc::new(Mutex::new(receiver)),
+            state: Arc::new(Mutex::new(PoolState { workers: 0, idle: 0 })),
+        }
+    }
+
+    // Returns false if every worker is stuck and no more can be started
+    fn submit(&self, job: Job) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.idle == 0 {
+                if state.workers == MAX_WORKERS {
+                    return false;
+                }
+                state.workers += 1;
+                state.idle += 1;
+                let receiver = self.receiver.clone();
+                let pool_state = self.state.clone();
+                thread::spawn(move || Self::worker_loop(&receiver, &pool_state));
+            }
+            state.idle -= 1;
+        }
+        self.sender.lock().unwrap().send(job).is_ok()
+    }
+
+    fn worker_loop(receiver: &Mutex<mpsc::Receiver<Job>>, state: &Mutex<PoolState>) {
+        loop {
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            job();
+            state.lock().unwrap().idle += 1;
+        }
+    }
+}
@@ -1,41 +1,82 @@
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
 use std::{fs, io};
 
 use crate::devices::virtio_9p::{
     pdu::PduParser, file::Qid,
 };
 
+///
+/// The entries of a directory, ordered by the offset of each entry.
+///
+/// The offset of an entry is a cookie derived from a hash of its name rather
+/// than its position, so the same name has the same offset each time the
+/// directory is loaded. A guest which resumes reading at an offset after the
+/// directory was loaded again gets the entries which follow that name in
+/// hash order, and does not skip or repeat entries which were present all
+/// along. Names which collide take the next free offset, which may differ
+/// between loads only for those names. Offsets are never 0, which starts a
+/// read at the beginning, and fit in a signed 64-bit `off_t`.
+///
+#[derive(Clone)]
 pub struct Directory {
-    entries: Vec<P9DirEntry>,
+    entries: BTreeMap<u64, P9DirEntry>,
 }
 
 impl Directory {
 
     pub fn new() -> Directory {
-        Directory { entries: Vec::new() }
+        Directory { entries: BTreeMap::new() }
     }
 
     pub fn write_entries(&self, pp: &mut PduParser, offset: u64, size: usize) -> io::Result<()> {
         let mut remaining = size;
 
-        pp.w32(0)?;
-        for entry in self.entries.iter()
-            .skip_while(|e| e.offset <= offset)
-        {
+        let count_addr = pp.reserve_w32()?;
+        for entry in self.entries_after(offset) {
             if entry.size() > remaining {
                 break;
             }
             entry.write(pp)?;
             remaining -= entry.size();
         }
-        pp.w32_at(0, (size - remaining) as u32);
+        pp.patch_w32(count_addr, (size - remaining) as u32);
         Ok(())
     }
 
-    pub fn push_entry(&mut self, entry: P9DirEntry) {
-        self.entries.push(entry)
+    pub fn push_entry(&mut self, mut entry: P9DirEntry) {
+        let mut offset = Self::name_offset(&entry.name);
+        while self.entries.contains_key(&offset) {
+            offset = Self::next_offset(offset);
+        }
+        entry.offset = offset;
+        self.entries.insert(offset, entry);
+    }
+
+    fn name_offset(name: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        Self::next_offset(hasher.finish() >> 2)
+    }
+
+    fn next_offset(offset: u64) -> u64 {
+        if offset >= i64::max_value() as u64 {
+            1
+        } else {
+            offset + 1
+        }
+    }
+
+    /// Entries which follow the entry at `offset`
+    pub fn entries_after(&self, offset: u64) -> impl Iterator<Item=&P9DirEntry> {
+        self.entries.range((Bound::Excluded(offset), Bound::Unbounded))
+            .map(|(_, entry)| entry)
     }
 }
 
+#[derive(Clone)]
 pub struct P9DirEntry{
     qid: Qid,
     offset: u64,
@@ -44,15 +85,23 @@ pub struct P9DirEntry{
 }
 
 impl P9DirEntry {
-    pub fn new(qid: Qid, offset: u64, dtype: u8, name: &str) -> Self {
+    /// An entry which is given its offset when it is added to a `Directory`.
+    pub fn new(qid: Qid, dtype: u8, name: &str) -> Self {
         let name = name.to_string();
-        let offset = offset + Self::size_with_name(&name) as u64;
-
-        P9DirEntry { qid, offset, dtype, name }
+        P9DirEntry { qid, offset: 0, dtype, name }
     }
-    pub fn from_direntry(entry: fs::DirEntry, offset: u64) -> io::Result<Self> {
+    pub fn from_direntry(entry: fs::DirEntry) -> io::Result<Self> {
         let meta = entry.metadata()?;
         let qid = Qid::from_metadata(&meta);
+        let name = match entry.file_name().into_string() {
+            Ok(s) => s,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        Ok(P9DirEntry::from_metadata(qid, &meta, &name))
+    }
+
+    /// An entry for a file with attributes `meta` and the given `qid`.
+    pub fn from_metadata(qid: Qid, meta: &fs::Metadata, name: &str) -> Self {
         let dtype = if meta.is_dir() {
             libc::DT_DIR
         } else if meta.is_file() {
@@ -60,22 +109,25 @@ impl P9DirEntry {
         } else {
             libc::DT_UNKNOWN
         };
-        let name = match entry.file_name().into_string() {
-            Ok(s) => s,
-            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
-        };
-        // qid + offset + dtype + strlen + name
-        let offset = offset + Self::size_with_name(&name) as u64;
-        Ok(P9DirEntry{
-            qid, offset,
-            dtype, name,
-        })
+        P9DirEntry::new(qid, dtype, name)
     }
 
     pub fn offset(&self) -> u64 {
         self.offset
     }
 
+    pub fn ino(&self) -> u64 {
+        self.qid.path()
+    }
+
+    pub fn dtype(&self) -> u8 {
+        self.dtype
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     fn size(&self) -> usize {
         Self::size_with_name(&self.name)
     }
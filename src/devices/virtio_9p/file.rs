@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::cell::{RefCell, RefMut, Cell};
 use std::collections::BTreeMap;
 use std::{io, fmt};
@@ -8,7 +9,7 @@ use std::os::linux::fs::MetadataExt;
 use std::os::unix::fs::FileExt;
 
 use crate::devices::virtio_9p::{
-    pdu::PduParser, directory::Directory, filesystem::FileSystemOps,
+    pdu::PduParser, directory::Directory, filesystem::FileSystemOps, deadline::Deadline,
 };
 use std::io::{Cursor, SeekFrom, Seek, Read};
 use std::sync::{RwLock, Arc};
@@ -20,7 +21,7 @@ const _P9_DOTL_NOACCESS: u32      = 0o00000003;
 const P9_DOTL_CREATE: u32        = 0o00000100;
 const P9_DOTL_EXCL: u32          = 0o00000200;
 const P9_DOTL_NOCTTY: u32        = 0o00000400;
-const P9_DOTL_TRUNC: u32         = 0o00001000;
+pub const P9_DOTL_TRUNC: u32         = 0o00001000;
 const P9_DOTL_APPEND: u32        = 0o00002000;
 const P9_DOTL_NONBLOCK: u32      = 0o00004000;
 const P9_DOTL_DSYNC: u32         = 0o00010000;
@@ -71,8 +72,8 @@ impl <T: AsRef<[u8]>> Buffer <T> {
 }
 
 enum FileObject {
-    File(File),
-    BufferFile(Buffer<&'static [u8]>),
+    File(Arc<File>),
+    BufferFile(Buffer<Cow<'static, [u8]>>),
     NotAFile,
 }
 
@@ -88,41 +89,74 @@ impl FileObject {
 pub struct P9File {
     file: FileObject,
     lock: Cell<LockType>,
+    deadline: Deadline,
 }
 
 impl P9File {
 
     fn new(file: FileObject) -> Self {
-        P9File { file, lock: Cell::new(LockType::LockUn) }
+        P9File { file, lock: Cell::new(LockType::LockUn), deadline: Deadline::none() }
     }
     pub fn new_not_a_file() -> Self {
         Self::new(FileObject::NotAFile)
     }
 
     pub fn from_file(file: File) -> Self {
-        Self::new(FileObject::File(file))
+        Self::new(FileObject::File(Arc::new(file)))
     }
 
-    pub fn from_buffer(buffer: Buffer<&'static [u8]>) -> Self {
+    /// Create a file on which reads, writes, and syncs are subject to `deadline`
+    pub fn from_file_with_deadline(file: File, deadline: Deadline) -> Self {
+        let mut file = Self::from_file(file);
+        file.deadline = deadline;
+        file
+    }
+
+    pub fn from_buffer(buffer: Buffer<Cow<'static, [u8]>>) -> Self {
         Self::new(FileObject::BufferFile(buffer))
     }
 
+    /// The descriptor of a host file, which reads and writes may use directly
+    /// instead of going through `read_at()` and `write_at()`.
+    pub fn host_fd(&self) -> Option<RawFd> {
+        self.file.fd()
+    }
+
     pub fn sync_all(&self) -> io::Result<()> {
         match self.file {
-            FileObject::File(ref f) => f.sync_all(),
+            FileObject::File(ref f) => {
+                let f = f.clone();
+                self.deadline.run(move || f.sync_all())
+            },
             _ => Ok(()),
         }
     }
 
     pub fn sync_data(&self) -> io::Result<()> {
         match self.file {
-            FileObject::File(ref f) => f.sync_data(),
+            FileObject::File(ref f) => {
+                let f = f.clone();
+                self.deadline.run(move || f.sync_data())
+            },
             _ => Ok(()),
         }
     }
 
     pub fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
         match self.file {
+            FileObject::File(ref f) if self.deadline.is_enabled() => {
+                // The worker may outlive this request so it cannot read into guest memory directly
+                let f = f.clone();
+                let len = buffer.len();
+                let data = self.deadline.run(move || {
+                    let mut data = vec![0u8; len];
+                    let n = f.read_at(&mut data, offset)?;
+                    data.truncate(n);
+                    Ok(data)
+                })?;
+                buffer[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            },
             FileObject::File(ref f) => f.read_at(buffer,offset),
             FileObject::BufferFile(ref f) => f.read_at(buffer, offset),
             FileObject::NotAFile =>  Ok(0),
@@ -131,6 +165,11 @@ impl P9File {
 
     pub fn write_at(&self, buffer: &[u8], offset: u64) -> io::Result<usize> {
         match self.file {
+            FileObject::File(ref f) if self.deadline.is_enabled() => {
+                let f = f.clone();
+                let data = buffer.to_vec();
+                self.deadline.run(move || f.write_at(&data, offset))
+            },
             FileObject::File(ref f) => f.write_at(buffer,offset),
             FileObject::BufferFile(ref f) => f.write_at(buffer, offset),
             FileObject::NotAFile =>  Ok(0),
@@ -263,6 +302,16 @@ impl Qid {
         self.qtype == P9_QTDIR
     }
 
+    /// The qid of the same type for the file `path` of a read-only store,
+    /// which keeps one version as the file never changes.
+    pub fn for_store(&self, path: u64) -> Qid {
+        Qid::new(self.qtype, 0, path)
+    }
+
+    pub fn path(&self) -> u64 {
+        self.path
+    }
+
     pub fn write(&self, pp: &mut PduParser) -> io::Result<()> {
         pp.w8(self.qtype)?;
         pp.w32(self.version)?;
@@ -342,6 +391,30 @@ impl <T: FileSystemOps> Fids<T> {
         self.fidmap.clear()
     }
 
+    /// Number of fids with an open file or directory.
+    pub fn open_count(&self) -> usize {
+        self.fidmap.values()
+            .filter(|fid| fid.file.is_some() || fid.directory.borrow().is_some())
+            .count()
+    }
+
+    /// Move every fid from below `old_root` to the same relative path below
+    /// `new_root`. Fids whose path does not exist below `new_root` are removed.
+    /// Returns the number of fids removed.
+    pub fn rebase(&mut self, old_root: &Path, new_root: &Path) -> usize {
+        let before = self.fidmap.len();
+        self.fidmap.retain(|_, fid| {
+            let path = match fid.path.strip_prefix(old_root) {
+                Ok(relative) if relative.as_os_str().is_empty() => new_root.to_path_buf(),
+                Ok(relative) => new_root.join(relative),
+                Err(_) => return false,
+            };
+            fid.set_path(path).is_ok()
+        });
+        self.root = new_root.to_path_buf();
+        before - self.fidmap.len()
+    }
+
     pub fn add(&mut self, fid: Fid<T>) {
         self.fidmap.insert(fid.id, fid);
     }
@@ -403,7 +476,7 @@ impl <T: FileSystemOps> Fid<T> {
     }
 
     pub fn write_stat(&self, pp: &mut PduParser) -> io::Result<()> {
-        self.ops.write_stat(self.path(), pp)
+        self.ops.stat(self.path())?.write(pp)
     }
 
     pub fn reload_qid(&mut self) -> io::Result<()> {
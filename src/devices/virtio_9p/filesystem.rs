@@ -7,14 +7,18 @@ use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{DirBuilderExt,OpenOptionsExt,PermissionsExt};
 use std::os::linux::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 
 use libc;
 use crate::devices::virtio_9p::file::{
-    P9File, P9_DOTL_RDONLY, P9_DOTL_RDWR, P9_DOTL_WRONLY, translate_p9_flags, Qid
+    P9File, P9_DOTL_RDONLY, P9_DOTL_RDWR, P9_DOTL_TRUNC, P9_DOTL_WRONLY, translate_p9_flags, Qid
 };
 use crate::devices::virtio_9p::pdu::PduParser;
 use crate::devices::virtio_9p::directory::{Directory, P9DirEntry};
+use crate::devices::virtio_9p::deadline::Deadline;
+use crate::devices::virtio_9p::store::StoreCache;
 
 
 pub enum FsTouch {
@@ -24,12 +28,109 @@ pub enum FsTouch {
     MtimeNow,
 }
 
+///
+/// Attributes of a file returned by `FileSystemOps::stat()`, which the 9p and
+/// virtio-fs servers each encode in their own protocol.
+///
+#[derive(Copy,Clone)]
+pub struct FileStat {
+    pub qid: Qid,
+    pub ino: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub rdev: u64,
+    pub size: u64,
+    pub blksize: u64,
+    pub blocks: u64,
+    pub atime: (u64, u64),
+    pub mtime: (u64, u64),
+    pub ctime: (u64, u64),
+}
+
+impl FileStat {
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        FileStat {
+            qid: Qid::from_metadata(meta),
+            ino: meta.st_ino(),
+            mode: meta.st_mode(),
+            uid: meta.st_uid(),
+            gid: meta.st_gid(),
+            nlink: meta.st_nlink(),
+            rdev: meta.st_rdev(),
+            size: meta.st_size(),
+            blksize: meta.st_blksize(),
+            blocks: meta.st_blocks(),
+            atime: (meta.st_atime() as u64, meta.st_atime_nsec() as u64),
+            mtime: (meta.st_mtime() as u64, meta.st_mtime_nsec() as u64),
+            ctime: (meta.st_ctime() as u64, meta.st_ctime_nsec() as u64),
+        }
+    }
+
+    pub fn write(&self, pp: &mut PduParser) -> io::Result<()> {
+        const P9_STATS_BASIC: u64 =  0x000007ff;
+        pp.w64(P9_STATS_BASIC)?;
+        self.qid.write(pp)?;
+
+        pp.w32(self.mode)?;
+        pp.w32(self.uid)?;
+        pp.w32(self.gid)?;
+        pp.w64(self.nlink)?;
+        pp.w64(self.rdev)?;
+        pp.w64(self.size)?;
+        pp.w64(self.blksize)?;
+        pp.w64(self.blocks)?;
+        pp.w64(self.atime.0)?;
+        pp.w64(self.atime.1)?;
+        pp.w64(self.mtime.0)?;
+        pp.w64(self.mtime.1)?;
+        pp.w64(self.ctime.0)?;
+        pp.w64(self.ctime.1)?;
+        pp.w64(0)?;   // btime
+        pp.w64(0)?;   // btime nsec
+        pp.w64(0)?;
+        pp.w64(0)?;
+        Ok(())
+    }
+}
+
+///
+/// Filesystem statistics returned by `FileSystemOps::statfs()`.
+///
+#[derive(Copy,Clone)]
+pub struct FsStat {
+    pub fs_type: u32,
+    pub bsize: u32,
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub namelen: u32,
+}
+
+impl FsStat {
+    pub fn write(&self, pp: &mut PduParser) -> io::Result<()> {
+        pp.w32(self.fs_type)?;
+        pp.w32(self.bsize)?;
+        pp.w64(self.blocks)?;
+        pp.w64(self.bfree)?;
+        pp.w64(self.bavail)?;
+        pp.w64(self.files)?;
+        pp.w64(self.ffree)?;
+        pp.w64(0)?;
+        pp.w32(self.namelen)?;
+        Ok(())
+    }
+}
+
 pub trait FileSystemOps: Clone+Sync+Send {
     fn read_qid(&self, path: &Path) -> io::Result<Qid>;
-    fn write_stat(&self, path: &Path, pp: &mut PduParser) -> io::Result<()>;
+    fn stat(&self, path: &Path) -> io::Result<FileStat>;
     fn open(&self, path: &Path, flags: u32) -> io::Result<P9File>;
     fn create(&self, path: &Path, flags: u32, mode: u32) -> io::Result<P9File>;
-    fn write_statfs(&self, path: &Path, pp: &mut PduParser) -> io::Result<()>;
+    fn statfs(&self, path: &Path) -> io::Result<FsStat>;
     fn chown(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()>;
     fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
     fn touch(&self, path: &Path, which: FsTouch, tv: (u64, u64)) -> io::Result<()>;
@@ -49,12 +150,28 @@ pub struct FileSystem {
     root: PathBuf,
     readonly: bool,
     euid_root: bool,
+    deadline: Deadline,
+    store: Option<Arc<StoreCache>>,
 }
 
 impl FileSystem {
-    pub fn new(root: PathBuf, readonly: bool) -> FileSystem {
+    /// If `timeout` is set, operations on the host filesystem which take longer
+    /// fail with `EIO`.
+    pub fn new(root: PathBuf, readonly: bool, timeout: Option<Duration>) -> FileSystem {
         let euid_root = Self::is_euid_root();
-        FileSystem { root, readonly, euid_root }
+        let deadline = Deadline::new(timeout);
+        FileSystem { root, readonly, euid_root, deadline, store: None }
+    }
+
+    /// Export the read-only tree `root`, such as `/nix` or `/usr`, with the
+    /// attributes, listings and failed lookups of its files cached in a
+    /// `StoreCache` shared with other shares of `root`. Operations which
+    /// would change the tree fail with `EROFS`.
+    pub fn new_store(root: PathBuf, timeout: Option<Duration>) -> FileSystem {
+        let store = StoreCache::for_root(&root);
+        let mut filesystem = Self::new(root, true, timeout);
+        filesystem.store = Some(store);
+        filesystem
     }
 
     pub fn is_euid_root() -> bool {
@@ -85,11 +202,27 @@ impl FileSystem {
     }
 
     fn new_file(&self, file: File) -> P9File {
-        P9File::from_file(file)
+        P9File::from_file_with_deadline(file, self.deadline.clone())
     }
 
     fn metadata(&self, path: &Path) -> io::Result<Metadata> {
-        path.symlink_metadata()
+        self.run(path, |path| path.symlink_metadata())
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.store.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    // Run `f` on `path` subject to the operation timeout
+    fn run<F, R>(&self, path: &Path, f: F) -> io::Result<R>
+        where F: FnOnce(&Path) -> io::Result<R> + Send + 'static,
+              R: Send + 'static
+    {
+        let path = path.to_path_buf();
+        self.deadline.run(move || f(&path))
     }
 }
 
@@ -99,89 +232,83 @@ fn cstr(path: &Path) -> io::Result<CString> {
 
 impl FileSystemOps for FileSystem {
     fn read_qid(&self, path: &Path) -> io::Result<Qid> {
+        if let Some(ref store) = self.store {
+            return store.stat(path, |path| self.metadata(path)).map(|stat| stat.qid);
+        }
         let meta = self.metadata(&path)?;
         let qid = Qid::from_metadata(&meta);
         Ok(qid)
     }
 
-    fn write_stat(&self, path: &Path, pp: &mut PduParser) -> io::Result<()> {
+    fn stat(&self, path: &Path) -> io::Result<FileStat> {
+        if let Some(ref store) = self.store {
+            return store.stat(path, |path| self.metadata(path));
+        }
         let meta = self.metadata(path)?;
-
-        const P9_STATS_BASIC: u64 =  0x000007ff;
-        pp.w64(P9_STATS_BASIC)?;
-
-        let qid = Qid::from_metadata(&meta);
-        qid.write(pp)?;
-
-        pp.w32(meta.st_mode())?;
-        pp.w32(meta.st_uid())?;
-        pp.w32(meta.st_gid())?;
-        pp.w64(meta.st_nlink())?;
-        pp.w64(meta.st_rdev())?;
-        pp.w64(meta.st_size())?;
-        pp.w64(meta.st_blksize())?;
-        pp.w64(meta.st_blocks())?;
-        pp.w64(meta.st_atime() as u64)?;
-        pp.w64(meta.st_atime_nsec() as u64)?;
-        pp.w64(meta.st_mtime() as u64)?;
-        pp.w64(meta.st_mtime_nsec() as u64)?;
-        pp.w64(meta.st_ctime() as u64)?;
-        pp.w64(meta.st_ctime_nsec() as u64)?;
-        pp.w64(0)?;
-        pp.w64(0)?;
-        pp.w64(0)?;
-        pp.w64(0)?;
-        Ok(())
+        Ok(FileStat::from_metadata(&meta))
     }
 
     fn open(&self, path: &Path, flags: u32) -> io::Result<P9File> {
-        let file =FileSystem::open_with_flags(&path, flags, self.euid_root)?;
+        let rdwr = flags & libc::O_ACCMODE as u32;
+        if rdwr != P9_DOTL_RDONLY || flags & P9_DOTL_TRUNC != 0 {
+            self.check_writable()?;
+        }
+        let euid_root = self.euid_root;
+        let file = self.run(path, move |path| FileSystem::open_with_flags(path, flags, euid_root))?;
         Ok(self.new_file(file))
     }
 
     fn create(&self, path: &Path, flags: u32, mode: u32) -> io::Result<P9File> {
-        let file = FileSystem::create_with_flags(&path, flags, mode, self.euid_root)?;
+        self.check_writable()?;
+        let euid_root = self.euid_root;
+        let file = self.run(path, move |path| FileSystem::create_with_flags(path, flags, mode, euid_root))?;
         Ok(self.new_file(file))
     }
 
-    fn write_statfs(&self, path: &Path, pp: &mut PduParser) -> io::Result<()> {
+    fn statfs(&self, path: &Path) -> io::Result<FsStat> {
         let path_cstr = cstr(&path)?;
 
-        let mut statfs: libc::statfs64 = unsafe { mem::zeroed() };
-        unsafe {
-            let ret = libc::statfs64(path_cstr.as_ptr(), &mut statfs);
-            if ret < 0 {
-                return Err(io::Error::last_os_error());
+        let statfs = self.deadline.run(move || {
+            let mut statfs: libc::statfs64 = unsafe { mem::zeroed() };
+            unsafe {
+                let ret = libc::statfs64(path_cstr.as_ptr(), &mut statfs);
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
             }
-        }
-        pp.w32(statfs.f_type as u32)?;
-        pp.w32(statfs.f_bsize as u32)?;
-        pp.w64(statfs.f_blocks)?;
-        pp.w64(statfs.f_bfree)?;
-        pp.w64(statfs.f_bavail)?;
-        pp.w64(statfs.f_files)?;
-        pp.w64(statfs.f_ffree)?;
-        pp.w64(0)?;
-        pp.w32(statfs.f_namelen as u32)?;
-        Ok(())
+            Ok(statfs)
+        })?;
+        Ok(FsStat {
+            fs_type: statfs.f_type as u32,
+            bsize: statfs.f_bsize as u32,
+            blocks: statfs.f_blocks,
+            bfree: statfs.f_bfree,
+            bavail: statfs.f_bavail,
+            files: statfs.f_files,
+            ffree: statfs.f_ffree,
+            namelen: statfs.f_namelen as u32,
+        })
     }
 
     fn chown(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        self.check_writable()?;
         let path_cstr = cstr(&path)?;
-        unsafe {
+        self.deadline.run(move || unsafe {
             if libc::chown(path_cstr.as_ptr(), uid, gid) < 0 {
                 return Err(io::Error::last_os_error());
             }
             Ok(())
-        }
+        })
     }
 
     fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.check_writable()?;
         let meta = self.metadata(path)?;
         Ok(meta.permissions().set_mode(mode))
     }
 
     fn touch(&self, path: &Path, which: FsTouch, tv: (u64, u64)) -> io::Result<()> {
+        self.check_writable()?;
         let path_cstr = cstr(&path)?;
 
         let tval = libc::timespec {
@@ -203,65 +330,90 @@ impl FileSystemOps for FileSystem {
             FsTouch::Mtime => [omit, tval ],
             FsTouch::MtimeNow => [omit, now],
         };
-        unsafe {
+        self.deadline.run(move || unsafe {
             if libc::utimensat(-1, path_cstr.as_ptr(), times.as_ptr(), 0) < 0 {
                 return Err(io::Error::last_os_error());
             }
-        }
-        Ok(())
+            Ok(())
+        })
     }
 
     fn truncate(&self, path: &Path, size: u64) -> io::Result<()> {
+        self.check_writable()?;
         let path_cstr = cstr(&path)?;
-        unsafe {
+        self.deadline.run(move || unsafe {
             if libc::truncate64(path_cstr.as_ptr(), size as i64) < 0 {
                 return Err(io::Error::last_os_error());
             }
-        }
-        Ok(())
+            Ok(())
+        })
     }
 
     fn readlink(&self, path: &Path) -> io::Result<OsString> {
-        fs::read_link(&path).map(|pbuf| pbuf.into_os_string())
+        if let Some(ref store) = self.store {
+            return store.readlink(path, |path| self.run(path, |path| fs::read_link(path).map(|pbuf| pbuf.into_os_string())));
+        }
+        self.run(path, |path| fs::read_link(path).map(|pbuf| pbuf.into_os_string()))
     }
 
     fn symlink(&self, target: &Path, linkpath: &Path) -> io::Result<()> {
-        unix::fs::symlink(target, linkpath)
+        self.check_writable()?;
+        let target = target.to_path_buf();
+        self.run(linkpath, move |linkpath| unix::fs::symlink(target, linkpath))
     }
 
     fn link(&self, target: &Path, newpath: &Path) -> io::Result<()> {
-        fs::hard_link(target, newpath)
+        self.check_writable()?;
+        let newpath = newpath.to_path_buf();
+        self.run(target, move |target| fs::hard_link(target, newpath))
     }
 
     fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
-        fs::rename(from, to)
+        self.check_writable()?;
+        let to = to.to_path_buf();
+        self.run(from, move |from| fs::rename(from, to))
     }
 
     fn remove_file(&self, path: &Path) -> io::Result<()> {
-        fs::remove_file(path)
+        self.check_writable()?;
+        self.run(path, |path| fs::remove_file(path))
     }
 
     fn remove_dir(&self, path: &Path) -> io::Result<()> {
-        fs::remove_dir(path)
+        self.check_writable()?;
+        self.run(path, |path| fs::remove_dir(path))
     }
 
     fn create_dir(&self, path: &Path, mode: u32) -> io::Result<()> {
-        fs::DirBuilder::new()
-            .recursive(false)
-            .mode(mode & 0o755)
-            .create(path)
+        self.check_writable()?;
+        self.run(path, move |path| {
+            fs::DirBuilder::new()
+                .recursive(false)
+                .mode(mode & 0o755)
+                .create(path)
+        })
     }
 
     fn readdir_populate(&self, path: &Path) -> io::Result<Directory> {
-        let mut directory = Directory::new();
-        let mut offset = 0;
-        for dent in fs::read_dir(path)? {
-            let dent = dent?;
-            let p9entry = P9DirEntry::from_direntry(dent, offset)?;
-            offset = p9entry.offset();
-            directory.push_entry(p9entry);
+        if let Some(ref store) = self.store {
+            return store.readdir(path, |path| self.run(path, |path| {
+                let mut entries = Vec::new();
+                for dent in fs::read_dir(path)? {
+                    let dent = dent?;
+                    let name = dent.file_name().into_string()
+                        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+                    entries.push((name, dent.metadata()?));
+                }
+                Ok(entries)
+            }));
         }
-        Ok(directory)
+        self.run(path, |path| {
+            let mut directory = Directory::new();
+            for dent in fs::read_dir(path)? {
+                directory.push_entry(P9DirEntry::from_direntry(dent?)?);
+            }
+            Ok(directory)
+        })
     }
 }
 
@@ -1,35 +1,105 @@
-use std::sync::{Arc,RwLock};
-use std::thread;
+use std::sync::{Arc,Mutex,RwLock};
+use std::{io, thread};
 
 use std::path::{PathBuf, Path};
+use std::time::Duration;
 
 use crate::memory::{GuestRam, MemoryManager};
+use crate::disk::IoShare;
+use crate::system::{seccomp, SeccompProfile};
 use crate::virtio::{self,VirtioBus,VirtioDeviceOps, VirtQueue, Result};
+use crate::vm::metrics::{IoBytes, Labels};
 use crate::devices::virtio_9p::server::Server;
-use crate::devices::virtio_9p::filesystem::{FileSystem, FileSystemOps};
 use self::pdu::PduParser;
+use self::process::ServerProcess;
 
+mod cache;
+mod checksum;
 mod pdu;
+mod deadline;
 mod file;
 mod directory;
 mod filesystem;
+mod process;
 mod server;
+mod store;
 mod synthetic;
+mod uring;
 
 
 const VIRTIO_ID_9P: u16 = 9;
 const VIRTIO_9P_MOUNT_TAG: u64 = 0x1;
 
 pub use synthetic::SyntheticFS;
+pub use cache::CacheHint;
+
+pub(crate) use self::filesystem::{FileSystem, FileSystemOps, FileStat, FsStat, FsTouch};
+pub(crate) use self::file::P9File;
+pub(crate) use self::directory::Directory;
 
 pub struct VirtioP9<T: FileSystemOps> {
-    filesystem: T,
-    root_dir: PathBuf,
+    server: Arc<Mutex<Server<T>>>,
     feature_bits: u64,
-    debug: bool,
     config: Vec<u8>,
 }
 
+///
+/// Handle for changing the host directory exported by a running `VirtioP9` device.
+///
+#[derive(Clone)]
+pub struct P9Share {
+    tag: String,
+    server: Arc<Mutex<Server<FileSystem>>>,
+}
+
+impl P9Share {
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn root(&self) -> PathBuf {
+        self.server.lock().unwrap().root().to_path_buf()
+    }
+
+    /// Export `root_dir` in place of the current directory. Fails with `EBUSY`
+    /// if the guest has any file or directory open on the share. Fids for paths
+    /// which also exist below the new directory remain valid and all other fids
+    /// are released.
+    pub fn remount<P: AsRef<Path>>(&self, root_dir: P) -> io::Result<()> {
+        self.server.lock().unwrap().remount(root_dir.as_ref())
+    }
+
+    /// Reads and writes of host files wait for `io_share`.
+    pub fn set_io_share(&self, io_share: IoShare) {
+        self.server.lock().unwrap().set_io_share(io_share)
+    }
+
+    /// Read and write host files through io_uring, with guest RAM registered
+    /// as fixed buffers if `register_buffers` is set. Reads and writes then
+    /// have no timeout. Must be called before the device is started.
+    pub fn use_io_uring(&self, register_buffers: bool) {
+        self.server.lock().unwrap().use_io_uring(register_buffers)
+    }
+
+    /// Serve the share from a child process which cannot reach guest memory.
+    /// Files are then not read and written through io_uring and the share
+    /// cannot be remounted. Must be called before the device is started.
+    pub fn use_separate_process(&self) {
+        self.server.lock().unwrap().use_separate_process()
+    }
+
+    /// Advise the host kernel how to cache the files of the share.
+    pub fn set_cache_hint(&self, hint: CacheHint) {
+        self.server.lock().unwrap().set_cache_hint(hint)
+    }
+
+    /// Log a CRC-32 of the data of one in every `every` reads and writes,
+    /// checked against the host file. See `ChecksumSampler`.
+    pub fn sample_checksums(&self, every: u64) {
+        self.server.lock().unwrap().sample_checksums(every)
+    }
+}
+
 impl <T: FileSystemOps+'static> VirtioP9<T> {
     fn create_config(tag_name: &str) -> Vec<u8> {
         let tag_len = tag_name.len() as u16;
@@ -41,30 +111,53 @@ impl <T: FileSystemOps+'static> VirtioP9<T> {
         config
     }
 
-    fn new(filesystem: T, tag_name: &str, root_dir: &str, debug: bool) -> Arc<RwLock<Self>> {
-        Arc::new(RwLock::new(VirtioP9 {
-            filesystem,
-            root_dir: PathBuf::from(root_dir),
-            feature_bits: 0,
-            debug,
-            config: VirtioP9::<T>::create_config(tag_name),
-        }))
+    fn create_server(filesystem: T, root_dir: &str, debug: bool) -> Arc<Mutex<Server<T>>> {
+        let mut server = Server::new(Path::new(root_dir), filesystem);
+        if debug {
+            server.enable_debug();
+        }
+        Arc::new(Mutex::new(server))
     }
 
-    pub fn create_with_filesystem(filesystem: T, vbus: &mut VirtioBus, tag_name: &str, root_dir: &str, debug: bool) -> Result<()> {
-        vbus.new_virtio_device(VIRTIO_ID_9P, VirtioP9::new(filesystem, tag_name, root_dir, debug))
+    fn register(vbus: &mut VirtioBus, server: Arc<Mutex<Server<T>>>, tag_name: &str) -> Result<()> {
+        server.lock().unwrap()
+            .count_bytes(IoBytes::new("9p", "shared files", Labels::new().with("tag", tag_name)));
+        let dev = Arc::new(RwLock::new(VirtioP9 {
+            server,
+            feature_bits: 0,
+            config: VirtioP9::<T>::create_config(tag_name),
+        }));
+        vbus.new_virtio_device(VIRTIO_ID_9P, dev)
             .set_num_queues(1)
             .set_features(VIRTIO_9P_MOUNT_TAG)
             .set_config_size(tag_name.len() + 3)
             .register()
     }
+
+    pub fn create_with_filesystem(filesystem: T, vbus: &mut VirtioBus, tag_name: &str, root_dir: &str, debug: bool) -> Result<()> {
+        let server = Self::create_server(filesystem, root_dir, debug);
+        Self::register(vbus, server, tag_name)
+    }
 }
 
 impl VirtioP9<FileSystem> {
 
-    pub fn create(vbus: &mut VirtioBus, tag_name: &str, root_dir: &str, read_only: bool, debug: bool) -> Result<()> {
-        let filesystem = FileSystem::new(PathBuf::from(root_dir), read_only);
-        Self::create_with_filesystem(filesystem, vbus, tag_name, root_dir, debug)
+    /// Export `root_dir` from the host. Filesystem operations which take longer
+    /// than `timeout` fail in the guest with `EIO`.
+    pub fn create(vbus: &mut VirtioBus, tag_name: &str, root_dir: &str, read_only: bool, timeout: Option<Duration>, debug: bool) -> Result<P9Share> {
+        let filesystem = FileSystem::new(PathBuf::from(root_dir), read_only, timeout);
+        let server = Self::create_server(filesystem, root_dir, debug);
+        Self::register(vbus, server.clone(), tag_name)?;
+        Ok(P9Share { tag: tag_name.to_string(), server })
+    }
+
+    /// Export the read-only tree `root_dir` with the caching of a
+    /// `FileSystem::new_store()`.
+    pub fn create_store(vbus: &mut VirtioBus, tag_name: &str, root_dir: &str, timeout: Option<Duration>, debug: bool) -> Result<P9Share> {
+        let filesystem = FileSystem::new_store(PathBuf::from(root_dir), timeout);
+        let server = Self::create_server(filesystem, root_dir, debug);
+        Self::register(vbus, server.clone(), tag_name)?;
+        Ok(P9Share { tag: tag_name.to_string(), server })
     }
 }
 
@@ -84,24 +177,44 @@ impl <T: FileSystemOps+'static> VirtioDeviceOps for VirtioP9<T> {
 
     fn start(&mut self, memory: &MemoryManager, mut queues: Vec<VirtQueue>) {
         let vq = queues.pop().unwrap();
-        let root_dir = self.root_dir.clone();
-        let filesystem = self.filesystem.clone();
+        let server = self.server.clone();
         let ram = memory.guest_ram().clone();
-        let debug = self.debug;
-        thread::spawn(move || run_device(ram, vq, &root_dir, filesystem, debug));
+        if server.lock().unwrap().is_separate_process() {
+            thread::spawn(move || run_process_device(ram, vq, server));
+            return;
+        }
+        server.lock().unwrap().start_io_uring(&ram);
+        thread::spawn(move || run_device(ram, vq, server));
     }
 }
 
-fn run_device<T: FileSystemOps>(memory: GuestRam, vq: VirtQueue, root_dir: &Path, filesystem: T, debug: bool) {
-    let mut server = Server::new(&root_dir, filesystem);
-
-    if debug {
-        server.enable_debug();
+fn run_device<T: FileSystemOps>(memory: GuestRam, vq: VirtQueue, server: Arc<Mutex<Server<T>>>) {
+    if let Err(e) = seccomp::sandbox_thread(SeccompProfile::P9) {
+        warn!("Failed to install seccomp filter for virtio 9p device: {}", e);
     }
-
     vq.on_each_chain(|mut chain| {
         let mut pp = PduParser::new(&mut chain, memory.clone());
-        server.handle(&mut pp);
+        server.lock().unwrap().handle(&mut pp);
+    });
+}
+
+fn run_process_device<T: FileSystemOps>(memory: GuestRam, vq: VirtQueue, server: Arc<Mutex<Server<T>>>) {
+    let mut process = match ServerProcess::spawn(&server, &memory) {
+        Ok(process) => process,
+        Err(e) => {
+            // Requests are not served from this process instead, so they all fail
+            warn!("Failed to start 9p server process: {}", e);
+            vq.on_each_chain(|_| {});
+            return;
+        }
+    };
+    if let Err(e) = seccomp::sandbox_thread(SeccompProfile::P9) {
+        warn!("Failed to install seccomp filter for virtio 9p device: {}", e);
+    }
+    vq.on_each_chain(|mut chain| {
+        if let Err(e) = process.forward(&mut chain) {
+            warn!("Error passing request to 9p server process: {}", e);
+        }
     });
 }
 
@@ -119,7 +119,7 @@ impl <'a> PduParser<'a> {
     }
 
     pub fn read_done(&mut self) -> io::Result<()> {
-        self.reply_start_addr = self.chain.current_write_address(8)
+        self.reply_start_addr = self.chain.current_write_address(P9_HEADER_LEN)
             .ok_or(io::Error::from_raw_os_error(libc::EIO))?;
 
         // reserve header
@@ -192,8 +192,19 @@ impl <'a> PduParser<'a> {
         self.memory.write_int::<u16>(self.reply_start_addr + offset as u64,  val).unwrap();
     }
 
-    pub fn w32_at(&self, offset: usize, val: u32) {
-        self._w32_at(offset + P9_HEADER_LEN, val);
+    /// Write a placeholder for a count which comes before data of a length
+    /// which is not known yet, and return the guest address of the count for
+    /// `patch_w32()`. The data after it may span any number of descriptors,
+    /// but the count itself must not be split across two.
+    pub fn reserve_w32(&mut self) -> io::Result<u64> {
+        let addr = self.chain.current_write_address(4)
+            .ok_or(io::Error::from_raw_os_error(libc::EIO))?;
+        self.w32(0)?;
+        Ok(addr)
+    }
+
+    pub fn patch_w32(&self, addr: u64, val: u32) {
+        self.memory.write_int::<u32>(addr, val).unwrap();
     }
 
     pub fn _w32_at(&self, offset: usize, val: u32) {
@@ -0,0 +1,165 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+
+use crate::memory::{GuestRam, MemoryRegion};
+use crate::system::{seccomp, SeccompProfile};
+use crate::virtio::Chain;
+use super::filesystem::FileSystemOps;
+use super::pdu::PduParser;
+use super::server::Server;
+
+// The largest request or reply passed between the processes, well above the
+// largest msize Linux negotiates over virtio
+const MAX_MESSAGE: usize = 8 << 20;
+
+// Requests are copied to the start of the staging memory of the child and
+// replies are written after them
+const REPLY_BASE: u64 = MAX_MESSAGE as u64;
+
+///
+/// A copy of a 9p `Server` running in a child process, so that a guest which
+/// gets past the exported directory through a bug in the server finds neither
+/// guest memory nor the files the VM process holds open.
+///
+/// The child is forked from the device thread when the device starts. Before
+/// it reads any request it unmaps guest RAM, points every file descriptor
+/// except the standard streams and its end of a socket pair at
+/// `/dev/null`, and installs the seccomp filter of the 9p device. The device
+/// thread sends the readable buffers of each chain as a frame of the request
+/// size, the writeable size and the request, all sizes being 32-bit little
+/// endian, and writes the reply in the frame which comes back, the reply size
+/// and the reply, to the writeable buffers.
+///
+/// Only the forking thread exists in the child, which serves requests with
+/// read and write calls and not io_uring.
+///
+pub struct ServerProcess {
+    pid: libc::pid_t,
+    socket: UnixStream,
+}
+
+impl ServerProcess {
+    pub fn spawn<T: FileSystemOps>(server: &Arc<Mutex<Server<T>>>, memory: &GuestRam) -> io::Result<ServerProcess> {
+        let (socket, child_socket) = UnixStream::pair()?;
+        // Held across the fork so that the copy in the child is not locked
+        // by a thread which does not exist there
+        let mut server = server.lock().unwrap();
+        let pid = unsafe { libc::fork() };
+        if pid == -1 {
+            return Err(io::Error::last_os_error());
+        } else if pid == 0 {
+            drop(socket);
+            let status = match serve_requests(&mut server, memory, child_socket) {
+                Ok(()) => 0,
+                Err(e) => {
+                    warn!("9p server process failed: {}", e);
+                    1
+                }
+            };
+            unsafe { libc::_exit(status) }
+        }
+        Ok(ServerProcess { pid, socket })
+    }
+
+    /// Pass the request in `chain` to the child process and write the reply
+    /// to `chain`.
+    pub fn forward(&mut self, chain: &mut Chain) -> io::Result<()> {
+        let len = chain.remaining_read();
+        if len > MAX_MESSAGE {
+            return Err(invalid_data(format!("request of {} bytes is too large", len)));
+        }
+        let capacity = chain.remaining_write().min(MAX_MESSAGE);
+        let mut frame = Vec::with_capacity(8 + len);
+        frame.extend_from_slice(&(len as u32).to_le_bytes());
+        frame.extend_from_slice(&(capacity as u32).to_le_bytes());
+        frame.resize(8 + len, 0);
+        chain.read_exact(&mut frame[8..])?;
+        self.socket.write_all(&frame)?;
+
+        let reply_len = read_u32(&mut self.socket)? as usize;
+        if reply_len > capacity {
+            return Err(invalid_data(format!("reply of {} bytes is larger than the {} writeable bytes", reply_len, capacity)));
+        }
+        let mut reply = vec![0u8; reply_len];
+        self.socket.read_exact(&mut reply)?;
+        chain.write_all(&reply)
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        unsafe {
+            libc::kill(self.pid, libc::SIGKILL);
+            libc::waitpid(self.pid, std::ptr::null_mut(), 0);
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u32(r: &mut dyn Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+// Descriptors are pointed at /dev/null rather than closed, so that the
+// number of one which is still owned by something in the copy of the
+// process is never reused for another file.
+fn hide_other_files(keep: RawFd) -> io::Result<()> {
+    let null = File::open("/dev/null")?;
+    let fds: Vec<RawFd> = fs::read_dir("/proc/self/fd")?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str().and_then(|s| s.parse().ok()))
+        .collect();
+    for fd in fds {
+        if fd > libc::STDERR_FILENO && fd != keep && fd != null.as_raw_fd() {
+            unsafe { libc::dup2(null.as_raw_fd(), fd); }
+        }
+    }
+    Ok(())
+}
+
+fn staging_memory() -> io::Result<GuestRam> {
+    let size = MAX_MESSAGE * 2;
+    let mut memory = GuestRam::new(size);
+    memory.set_regions(vec![MemoryRegion::new(0, size)?]);
+    Ok(memory)
+}
+
+// Runs in the child process
+fn serve_requests<T: FileSystemOps>(server: &mut Server<T>, memory: &GuestRam, mut socket: UnixStream) -> io::Result<()> {
+    unsafe { memory.unmap()?; }
+    hide_other_files(socket.as_raw_fd())?;
+    let staging = staging_memory()?;
+    seccomp::sandbox_thread(SeccompProfile::P9)?;
+
+    loop {
+        let len = match read_u32(&mut socket) {
+            Ok(len) => len as usize,
+            // The device thread has gone away
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let capacity = read_u32(&mut socket)? as usize;
+        if len > MAX_MESSAGE || capacity > MAX_MESSAGE {
+            return Err(invalid_data(format!("request frame of {} and {} bytes is too large", len, capacity)));
+        }
+        if len > 0 {
+            socket.read_exact(staging.mut_slice(0, len)?)?;
+        }
+
+        let mut chain = Chain::staged(staging.clone(), (0, len), (REPLY_BASE, capacity));
+        server.handle(&mut PduParser::new(&mut chain, staging.clone()));
+        let reply_len = chain.get_wlen();
+        socket.write_all(&(reply_len as u32).to_le_bytes())?;
+        if reply_len > 0 {
+            socket.write_all(staging.slice(REPLY_BASE, reply_len)?)?;
+        }
+    }
+}
@@ -1,10 +1,18 @@
+use std::cell::RefCell;
 use std::path::{PathBuf, Path};
 use std::{io, cmp};
 
+use crate::disk::IoShare;
+use crate::memory::GuestRam;
+use crate::vm::metrics::IoBytes;
+use crate::util::debug_flags::{self, DebugFlag};
 use crate::devices::virtio_9p::{
+    cache::CacheHint,
+    checksum::{Checksum, ChecksumResult, ChecksumSampler},
     pdu::{PduParser, P9Attr},
     filesystem::{FileSystemOps, FsTouch},
-    file::{Fids, Fid, Qid},
+    file::{Fids, Fid, P9File, Qid},
+    uring::FileRing,
 };
 
 const P9_TSTATFS: u8      = 8;
@@ -33,7 +41,7 @@ const P9_TWALK :u8        = 110;
 const P9_TREAD: u8        = 116;
 const P9_TWRITE: u8       = 118;
 const P9_TCLUNK: u8       = 120;
-const P9_REMOVE: u8       = 122;
+const P9_TREMOVE: u8      = 122;
 
 
 const P9_LOCK_FLAGS_BLOCK: u32 = 1;
@@ -44,6 +52,15 @@ pub struct Server<T: FileSystemOps> {
     msize: u32,
     fids: Fids<T>,
     filesystem: T,
+    io_share: Option<IoShare>,
+    checksums: Option<ChecksumSampler>,
+    // Whether to use io_uring once the device starts, and whether to register guest RAM with it
+    io_uring: Option<bool>,
+    ring: Option<RefCell<FileRing>>,
+    cache_hint: CacheHint,
+    // Whether the device serves requests from a child process holding a copy of this server
+    separate_process: bool,
+    bytes: Option<IoBytes>,
 }
 
 fn system_error<T>(errno: libc::c_int) -> io::Result<T> {
@@ -60,7 +77,14 @@ impl <T: FileSystemOps> Server<T> {
             debug: false,
             msize: 0,
             fids,
-            filesystem
+            filesystem,
+            io_share: None,
+            checksums: None,
+            io_uring: None,
+            ring: None,
+            cache_hint: CacheHint::Normal,
+            separate_process: false,
+            bytes: None,
         }
     }
 
@@ -68,6 +92,105 @@ impl <T: FileSystemOps> Server<T> {
         self.debug = true;
     }
 
+    // Requests are logged if debugging was enabled when the share was
+    // created or is switched on with the `debug` control method
+    fn is_debug(&self) -> bool {
+        self.debug || debug_flags::is_enabled(DebugFlag::P9)
+    }
+
+    pub fn set_io_share(&mut self, io_share: IoShare) {
+        self.io_share = Some(io_share);
+    }
+
+    pub fn set_cache_hint(&mut self, hint: CacheHint) {
+        self.cache_hint = hint;
+    }
+
+    /// Count the bytes of file reads and writes in `metrics`. The counts of a
+    /// server in a separate process stay in that process and are not seen.
+    pub fn count_bytes(&mut self, bytes: IoBytes) {
+        self.bytes = Some(bytes);
+    }
+
+    pub fn sample_checksums(&mut self, every: u64) {
+        self.checksums = Some(ChecksumSampler::new(every));
+    }
+
+    /// Read and write host files through io_uring once the device is
+    /// started, with guest RAM registered as fixed buffers if
+    /// `register_buffers` is set.
+    pub fn use_io_uring(&mut self, register_buffers: bool) {
+        self.io_uring = Some(register_buffers);
+    }
+
+    /// Serve requests from a child process once the device is started. See
+    /// `ServerProcess`.
+    pub fn use_separate_process(&mut self) {
+        self.separate_process = true;
+    }
+
+    pub fn is_separate_process(&self) -> bool {
+        self.separate_process
+    }
+
+    pub fn start_io_uring(&mut self, memory: &GuestRam) {
+        if self.ring.is_some() {
+            return;
+        }
+        if let Some(register_buffers) = self.io_uring {
+            match FileRing::new(memory, register_buffers) {
+                Ok(ring) => self.ring = Some(RefCell::new(ring)),
+                Err(e) => warn!("Failed to set up io_uring for 9p file IO, using read and write calls: {}", e),
+            }
+        }
+    }
+
+    fn sample_checksum(&mut self) -> Option<Checksum> {
+        self.checksums.as_mut().and_then(|c| c.sample())
+    }
+
+    fn log_checksum(op: &str, fid: &Fid<T>, offset: u64, len: u32, result: ChecksumResult) {
+        if result.is_match() {
+            notify!("9p checksum: {} {} offset={} len={} {}", op, fid, offset, len, result);
+        } else {
+            warn!("9p checksum: {} {} offset={} len={} {}", op, fid, offset, len, result);
+        }
+    }
+
+    // The size of a read is only known once it completes, so transfers are
+    // counted afterwards and the reply is delayed instead.
+    fn wait_io_share(&self, len: usize) {
+        if let Some(ref io_share) = self.io_share {
+            io_share.acquire(len);
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Replace the exported directory with `root`, moving each fid to the same
+    /// relative path below the new directory.
+    pub fn remount(&mut self, root: &Path) -> io::Result<()> {
+        if self.separate_process {
+            // The child process holds the fids and this copy is never used
+            return Err(io::Error::new(io::ErrorKind::Other, "share is served by a separate process"));
+        }
+        if !self.filesystem.read_qid(root)?.is_dir() {
+            return system_error(libc::ENOTDIR);
+        }
+        let open = self.fids.open_count();
+        if open > 0 {
+            warn!("Cannot remount {}: {} files open", self.root.display(), open);
+            return system_error(libc::EBUSY);
+        }
+        let released = self.fids.rebase(&self.root, root);
+        notify!("Remounted 9p share {} at {} ({} fids released)",
+                self.root.display(), root.display(), released);
+        self.root = root.to_path_buf();
+        Ok(())
+    }
+
     fn fid_mut(&mut self, id: u32) -> io::Result<&mut Fid<T>> {
         self.fids.fid_mut(id)
     }
@@ -86,7 +209,7 @@ impl <T: FileSystemOps> Server<T> {
         match pp.command() {
             Ok(cmd) => {
                 if let Err(err) = self.dispatch(cmd, pp) {
-                    if self.debug {
+                    if self.is_debug() {
                         notify!("error handling command: {}", err);
                     }
                     let _ = pp.bail_err(err);
@@ -126,7 +249,7 @@ impl <T: FileSystemOps> Server<T> {
             P9_TREAD => self.p9_read(pp)?,
             P9_TWRITE => self.p9_write(pp)?,
             P9_TCLUNK => self.p9_clunk(pp)?,
-            P9_REMOVE => self.p9_remove(pp)?,
+            P9_TREMOVE => self.p9_remove(pp)?,
             n => warn!("unhandled 9p command: {}", n),
         }
         Ok(())
@@ -141,10 +264,10 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_statfs(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let fid = self.p9_statfs_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_statfs({})", fid)
         }
-        self.filesystem.write_statfs(fid.path(), pp)?;
+        self.filesystem.statfs(fid.path())?.write(pp)?;
         pp.write_done()
     }
 
@@ -158,11 +281,12 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_open(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (fid, flags) = self.p9_open_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_open({}, {:08x})", fid, flags)
         }
 
         let file = self.filesystem.open(fid.path(), flags)?;
+        self.cache_hint.advise_open(&file);
 
         let id = fid.id();
         let fid = self.fid_mut(id)?;
@@ -188,12 +312,13 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_create(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (dfid, path, flags, mode) = self.p9_create_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_create({:?}, flags={:08x}, mode={:04o})",
                     path, flags, mode)
         }
 
         let file = self.filesystem.create(&path, flags, mode)?;
+        self.cache_hint.advise_open(&file);
 
         let id = dfid.id();
         let dfid = self.fid_mut(id)?;
@@ -218,13 +343,13 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_symlink(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (newpath, target) = self.p9_symlink_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_symlink({:?}, {})", newpath, target)
         }
 
         self.filesystem.symlink(&Path::new(&target), &newpath)?;
 
-        self.filesystem.write_stat(&newpath, pp)?;
+        self.filesystem.stat(&newpath)?.write(pp)?;
         pp.write_done()
     }
 
@@ -240,7 +365,7 @@ impl <T: FileSystemOps> Server<T> {
 
     fn p9_mknod(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (path, mode, major, minor) = self.p9_mknod_args(pp)?;
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_mknod({:?}, {:04o}, {}:{})", path, mode, major, minor)
         }
         system_error(libc::EACCES)
@@ -255,7 +380,7 @@ impl <T: FileSystemOps> Server<T> {
 
     fn p9_rename(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (oldfid, newpath) = self.p9_rename_args(pp)?;
-        if self.debug {
+        if self.is_debug() {
             format!("p9_rename({}, {:?})", oldfid, newpath);
         }
         self.filesystem.rename(oldfid.path(), &newpath)?;
@@ -274,7 +399,7 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_readlink(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let fid = self.p9_readlink_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_readlink({})", fid);
         }
 
@@ -293,7 +418,7 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_getattr(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (fid,mask) = self.p9_getattr_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_getattr({}, {})", fid, mask);
         }
 
@@ -316,7 +441,7 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_setattr(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (fid, attr) = self.p9_setattr_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_setattr({}, {:?})", fid, attr);
         }
 
@@ -362,11 +487,13 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_readdir(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (fid, offset, count) = self.p9_readdir_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_readdir({}, offset={}, count={})", fid, offset, count);
         }
 
-        if offset == 0 {
+        // Offsets are stable across loads, so a read can resume at an
+        // offset from an earlier load of the directory
+        if offset == 0 || fid.directory().is_none() {
             fid.load_directory()?;
         }
 
@@ -391,7 +518,7 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_fsync(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (fid, datasync) = self.p9_fsync_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_fsync({}, {})", fid, datasync);
         }
 
@@ -462,13 +589,14 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_unlinkat(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (path, flags) = self.p9_unlinkat_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_unlinkat({:?}, {:08x})", path, flags);
         }
 
-        if path.is_dir() && (flags & libc::AT_REMOVEDIR as u32) == 0 {
+        let is_dir = self.filesystem.read_qid(&path)?.is_dir();
+        if is_dir && (flags & libc::AT_REMOVEDIR as u32) == 0 {
             return system_error(libc::EISDIR);
-        } else if path.is_dir() {
+        } else if is_dir {
             self.filesystem.remove_dir(&path)?;
         } else {
             self.filesystem.remove_file(&path)?;
@@ -534,7 +662,7 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_version(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let (msize, version) = self.p9_version_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_version({}, {})", version, msize);
         }
 
@@ -599,7 +727,7 @@ impl <T: FileSystemOps> Server<T> {
             return system_error(libc::EBADF);
         }
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_walk({}, newfid={}, names={:?})", fid, newfid_id, names);
         }
 
@@ -642,33 +770,100 @@ impl <T: FileSystemOps> Server<T> {
     }
 
     fn p9_read(&mut self, pp: &mut PduParser) -> io::Result<()> {
+        let mut checksum = self.sample_checksum();
         let (fid, offset, count) = self.p9_read_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_read({}, offset={}, count={})", fid, offset, count);
         }
 
         let file = fid.file()?;
-        // space for size field
-        pp.w32(0)?;
+        let count_addr = pp.reserve_w32()?;
+
+        let nread = match (self.ring.as_ref(), file.host_fd()) {
+            (Some(ring), Some(fd)) => {
+                let mut slices = pp.chain.write_slices(count as usize);
+                let n = ring.borrow_mut().read(fd, offset, &mut slices)?;
+                if let Some(ref mut checksum) = checksum {
+                    let mut left = n;
+                    for slice in &slices {
+                        let len = cmp::min(slice.len(), left);
+                        checksum.update(&slice[..len]);
+                        left -= len;
+                    }
+                }
+                pp.chain.advance_write(n);
+                n as u32
+            }
+            _ => Self::read_into_chain(file, pp, offset, count, &mut checksum)?,
+        };
+        if let Some(checksum) = checksum {
+            let result = checksum.verify(file, fid.path(), offset, nread as usize);
+            Self::log_checksum("read", fid, offset, nread, result);
+        }
+        self.cache_hint.advise_read(file, offset, nread as usize);
+        if let Some(ref bytes) = self.bytes {
+            bytes.record_read(nread as usize);
+        }
+        self.wait_io_share(nread as usize);
+        pp.patch_w32(count_addr, nread as u32);
+        pp.write_done()
+    }
 
+    // The buffer for the data of a large read is usually split over many
+    // descriptors, one for each guest page, and each of them is filled in
+    // turn until `count` bytes have been read. An error after some data has
+    // been placed in the chain ends the read short, since an error reply
+    // would have to be written where the data already is.
+    fn read_into_chain(file: &P9File, pp: &mut PduParser, offset: u64, count: u32, checksum: &mut Option<Checksum>) -> io::Result<u32> {
         let mut nread = 0;
-
         while nread < count {
             let current = pp.chain.current_write_slice();
             if current.len() == 0 {
                 break;
             }
-            let rlen = cmp::min(current.len(), count as usize);
-            let n = file.read_at(&mut current[..rlen], offset + nread as u64)?;
-            if n == 0 {
-                break;
+            let rlen = cmp::min(current.len(), (count - nread) as usize);
+            let n = match file.read_at(&mut current[..rlen], offset + nread as u64) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) if nread > 0 => break,
+                Err(e) => return Err(e),
+            };
+            if let Some(ref mut checksum) = checksum {
+                checksum.update(&current[..n]);
             }
             pp.chain.inc_write_offset(n);
             nread += n as u32;
         }
-        pp.w32_at(0, nread as u32);
-        pp.write_done()
+        Ok(nread)
+    }
+
+    // Like `read_into_chain()`, the data of a write may follow the request
+    // in several descriptors. No more than `count` bytes are written even if
+    // the descriptors hold more.
+    fn write_from_chain(file: &P9File, pp: &mut PduParser, offset: u64, count: u32, checksum: &mut Option<Checksum>) -> io::Result<u32> {
+        let mut nwritten = 0;
+        while nwritten < count {
+            let current = pp.chain.current_read_slice();
+            let wlen = cmp::min(current.len(), (count - nwritten) as usize);
+            if wlen == 0 {
+                break;
+            }
+            let n = match file.write_at(&current[..wlen], offset + nwritten as u64) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) if nwritten > 0 => break,
+                Err(e) => return Err(e),
+            };
+            if let Some(ref mut checksum) = checksum {
+                checksum.update(&current[..n]);
+            }
+            pp.chain.inc_read_offset(n);
+            nwritten += n as u32;
+        }
+        Ok(nwritten)
     }
 
     fn p9_write_args(&self, pp: &mut PduParser) -> io::Result<(&Fid<T>, u64, u32)> {
@@ -679,47 +874,77 @@ impl <T: FileSystemOps> Server<T> {
     }
 
     fn p9_write(&mut self, pp: &mut PduParser) -> io::Result<()> {
+        let mut checksum = self.sample_checksum();
         let (fid, offset, count) = self.p9_write_args(pp)?;
 
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_write({}, offset={}, count={})", fid, offset, count);
         }
 
         let file = fid.file()?;
-        let mut nread = 0;
-        while nread < count {
-            let n = file.write_at(pp.chain.current_read_slice(), offset + nread as u64)?;
-            if n == 0 {
-                break;
+        let nread = match (self.ring.as_ref(), file.host_fd()) {
+            (Some(ring), Some(fd)) => {
+                let slices = pp.chain.read_slices(count as usize);
+                let n = ring.borrow_mut().write(fd, offset, &slices)?;
+                if let Some(ref mut checksum) = checksum {
+                    let mut left = n;
+                    for slice in &slices {
+                        let len = cmp::min(slice.len(), left);
+                        checksum.update(&slice[..len]);
+                        left -= len;
+                    }
+                }
+                pp.chain.advance_read(n);
+                n as u32
             }
-            pp.chain.inc_read_offset(n);
-            nread += n as u32;
+            _ => Self::write_from_chain(file, pp, offset, count, &mut checksum)?,
+        };
+        if let Some(checksum) = checksum {
+            let result = checksum.verify(file, fid.path(), offset, nread as usize);
+            Self::log_checksum("write", fid, offset, nread, result);
+        }
+        if let Some(ref bytes) = self.bytes {
+            bytes.record_written(nread as usize);
         }
+        self.wait_io_share(nread as usize);
         pp.read_done()?;
         pp.w32(nread)?;
         pp.write_done()
     }
 
+    // The fid is released as soon as its id has been read so that it is
+    // clunked even if the rest of the request fails.
     fn remove_fid(&mut self, pp: &mut PduParser) -> io::Result<Fid<T>> {
         let id = pp.r32()?;
+        let fid = self.fids.remove(id)?;
         pp.read_done()?;
-        self.fids.remove(id)
+        Ok(fid)
     }
 
     fn p9_clunk(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let fid = self.remove_fid(pp)?;
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_clunk({})", fid);
         }
         pp.write_done()
     }
 
+    /// Remove the file of a fid and clunk the fid. As with Tclunk the fid is
+    /// released whether or not the remove succeeds, and a failed remove is
+    /// reported with the errno from the host, for example `EACCES` or
+    /// `ENOTEMPTY`.
     fn p9_remove(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let fid = self.remove_fid(pp)?;
-        if self.debug {
+        if self.is_debug() {
             notify!("p9_remove({})", fid);
         }
-        if fid.is_dir() {
+        if fid.path() == self.root {
+            return system_error(libc::EBUSY);
+        }
+        // The qid of the fid was read when it was walked and the file may
+        // have been replaced since then.
+        let is_dir = self.filesystem.read_qid(fid.path())?.is_dir();
+        if is_dir {
             self.filesystem.remove_dir(fid.path())?;
         } else {
             self.filesystem.remove_file(fid.path())?;
@@ -733,3 +958,287 @@ impl <T: FileSystemOps> Server<T> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::ffi::OsString;
+    use std::fs::{self, OpenOptions};
+    use std::io;
+    use std::path::Path;
+    use std::process;
+
+    use crate::devices::virtio_9p::directory::Directory;
+    use crate::devices::virtio_9p::file::{Buffer, P9File, Qid, P9_QTDIR, P9_QTFILE};
+    use crate::devices::virtio_9p::filesystem::{FileStat, FileSystemOps, FsStat, FsTouch};
+    use crate::devices::virtio_9p::pdu::PduParser;
+    use crate::memory::{GuestRam, MemoryRegion};
+    use crate::virtio::Chain;
+
+    use super::{Server, P9_TATTACH, P9_TREMOVE, P9_TUNLINKAT, P9_TWALK};
+
+    const RAM_SIZE: usize = 0x4000;
+    const REPLY_ADDR: u64 = 0x2000;
+    const P9_RLERROR: u8 = 7;
+
+    const ROOT_FID: u32 = 1;
+    const FID: u32 = 2;
+
+    // A share in which names starting with `dir` are directories and every
+    // remove fails with `errno`, or succeeds if it is zero
+    #[derive(Clone)]
+    struct RemoveFs {
+        errno: libc::c_int,
+    }
+
+    fn unsupported<T>() -> io::Result<T> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    impl RemoveFs {
+        fn remove(&self) -> io::Result<()> {
+            match self.errno {
+                0 => Ok(()),
+                errno => Err(io::Error::from_raw_os_error(errno)),
+            }
+        }
+    }
+
+    impl FileSystemOps for RemoveFs {
+        fn read_qid(&self, path: &Path) -> io::Result<Qid> {
+            let is_dir = path == Path::new("/share") ||
+                path.file_name().map_or(false, |name| name.to_string_lossy().starts_with("dir"));
+            Ok(Qid::new(if is_dir { P9_QTDIR } else { P9_QTFILE }, 0, 0))
+        }
+        fn stat(&self, _: &Path) -> io::Result<FileStat> { unsupported() }
+        fn open(&self, _: &Path, _: u32) -> io::Result<P9File> { unsupported() }
+        fn create(&self, _: &Path, _: u32, _: u32) -> io::Result<P9File> { unsupported() }
+        fn statfs(&self, _: &Path) -> io::Result<FsStat> { unsupported() }
+        fn chown(&self, _: &Path, _: u32, _: u32) -> io::Result<()> { unsupported() }
+        fn set_mode(&self, _: &Path, _: u32) -> io::Result<()> { unsupported() }
+        fn touch(&self, _: &Path, _: FsTouch, _: (u64, u64)) -> io::Result<()> { unsupported() }
+        fn truncate(&self, _: &Path, _: u64) -> io::Result<()> { unsupported() }
+        fn readlink(&self, _: &Path) -> io::Result<OsString> { unsupported() }
+        fn symlink(&self, _: &Path, _: &Path) -> io::Result<()> { unsupported() }
+        fn link(&self, _: &Path, _: &Path) -> io::Result<()> { unsupported() }
+        fn rename(&self, _: &Path, _: &Path) -> io::Result<()> { unsupported() }
+        fn remove_file(&self, _: &Path) -> io::Result<()> { self.remove() }
+        fn remove_dir(&self, _: &Path) -> io::Result<()> { self.remove() }
+        fn create_dir(&self, _: &Path, _: u32) -> io::Result<()> { unsupported() }
+        fn readdir_populate(&self, _: &Path) -> io::Result<Directory> { unsupported() }
+    }
+
+    struct Request(Vec<u8>);
+
+    impl Request {
+        fn new(cmd: u8) -> Self {
+            let mut bytes = vec![0; 4];
+            bytes.push(cmd);
+            bytes.extend_from_slice(&1u16.to_le_bytes());
+            Request(bytes)
+        }
+
+        fn w32(mut self, val: u32) -> Self {
+            self.0.extend_from_slice(&val.to_le_bytes());
+            self
+        }
+
+        fn w16(mut self, val: u16) -> Self {
+            self.0.extend_from_slice(&val.to_le_bytes());
+            self
+        }
+
+        fn string(self, s: &str) -> Self {
+            let mut r = self.w16(s.len() as u16);
+            r.0.extend_from_slice(s.as_bytes());
+            r
+        }
+
+        fn bytes(mut self) -> Vec<u8> {
+            let size = self.0.len() as u32;
+            self.0[..4].copy_from_slice(&size.to_le_bytes());
+            self.0
+        }
+    }
+
+    // Serve `request` and return the command of the reply, with the errno
+    // if it is an Rlerror
+    fn send(server: &mut Server<RemoveFs>, request: Request) -> (u8, Option<u32>) {
+        let mut memory = GuestRam::new(RAM_SIZE);
+        memory.set_regions(vec![MemoryRegion::new(0, RAM_SIZE).unwrap()]);
+        let bytes = request.bytes();
+        memory.write_bytes(0, &bytes).unwrap();
+        let reply_size = RAM_SIZE - REPLY_ADDR as usize;
+        let mut chain = Chain::staged(memory.clone(), (0, bytes.len()), (REPLY_ADDR, reply_size));
+        server.handle(&mut PduParser::new(&mut chain, memory.clone()));
+
+        let cmd = memory.read_int::<u8>(REPLY_ADDR + 4).unwrap();
+        if cmd == P9_RLERROR {
+            (cmd, Some(memory.read_int::<u32>(REPLY_ADDR + 7).unwrap()))
+        } else {
+            (cmd, None)
+        }
+    }
+
+    // A server for a share with `FID` walked to `name` below the root
+    fn server_with_fid(errno: libc::c_int, name: &str) -> Server<RemoveFs> {
+        let mut server = Server::new(Path::new("/share"), RemoveFs { errno });
+        let attach = Request::new(P9_TATTACH).w32(ROOT_FID).w32(!0).string("user").string("").w32(0);
+        assert_eq!(send(&mut server, attach), (P9_TATTACH + 1, None));
+        let walk = Request::new(P9_TWALK).w32(ROOT_FID).w32(FID).w16(1).string(name);
+        assert_eq!(send(&mut server, walk), (P9_TWALK + 1, None));
+        server
+    }
+
+    fn remove(server: &mut Server<RemoveFs>, fid: u32) -> (u8, Option<u32>) {
+        send(server, Request::new(P9_TREMOVE).w32(fid))
+    }
+
+    #[test]
+    fn remove_succeeds_and_clunks_fid() {
+        let mut server = server_with_fid(0, "file");
+        assert_eq!(remove(&mut server, FID), (P9_TREMOVE + 1, None));
+        assert!(!server.fids.exists(FID));
+    }
+
+    #[test]
+    fn remove_of_non_empty_directory_fails_with_enotempty() {
+        let mut server = server_with_fid(libc::ENOTEMPTY, "dir");
+        assert_eq!(remove(&mut server, FID), (P9_RLERROR, Some(libc::ENOTEMPTY as u32)));
+        assert!(!server.fids.exists(FID), "fid of failed remove is clunked");
+    }
+
+    #[test]
+    fn remove_without_permission_fails_with_eacces() {
+        let mut server = server_with_fid(libc::EACCES, "file");
+        assert_eq!(remove(&mut server, FID), (P9_RLERROR, Some(libc::EACCES as u32)));
+        assert!(!server.fids.exists(FID), "fid of failed remove is clunked");
+    }
+
+    #[test]
+    fn remove_of_root_fails_with_ebusy() {
+        let mut server = server_with_fid(0, "file");
+        assert_eq!(remove(&mut server, ROOT_FID), (P9_RLERROR, Some(libc::EBUSY as u32)));
+        assert!(!server.fids.exists(ROOT_FID), "fid of failed remove is clunked");
+    }
+
+    #[test]
+    fn remove_of_unknown_fid_fails_with_ebadf() {
+        let mut server = server_with_fid(0, "file");
+        assert_eq!(remove(&mut server, 99), (P9_RLERROR, Some(libc::EBADF as u32)));
+        assert!(server.fids.exists(FID));
+    }
+
+    #[test]
+    fn unlinkat_reports_errno_of_host() {
+        for &(errno, name, flags) in &[
+            (libc::ENOTEMPTY, "dir", libc::AT_REMOVEDIR as u32),
+            (libc::EACCES, "file", 0),
+        ] {
+            let mut server = server_with_fid(errno, "dir");
+            let unlinkat = Request::new(P9_TUNLINKAT).w32(FID).string(name).w32(flags);
+            assert_eq!(send(&mut server, unlinkat), (P9_RLERROR, Some(errno as u32)));
+            assert!(server.fids.exists(FID), "directory fid of unlinkat is kept");
+        }
+    }
+
+    #[test]
+    fn unlinkat_of_directory_without_removedir_fails_with_eisdir() {
+        let mut server = server_with_fid(0, "dir");
+        let unlinkat = Request::new(P9_TUNLINKAT).w32(FID).string("dir").w32(0);
+        assert_eq!(send(&mut server, unlinkat), (P9_RLERROR, Some(libc::EISDIR as u32)));
+    }
+
+    // Descriptors of a few bytes each, so that both ends of a transfer and
+    // the boundaries between descriptors fall at odd offsets
+    const SPLIT: &[(u64, usize)] = &[(0x100, 7), (0x200, 1), (0x300, 16), (0x400, 5)];
+    const SPLIT_SIZE: usize = 29;
+
+    fn split_memory() -> GuestRam {
+        let mut memory = GuestRam::new(RAM_SIZE);
+        memory.set_regions(vec![MemoryRegion::new(0, RAM_SIZE).unwrap()]);
+        memory
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 1) as u8).collect()
+    }
+
+    // The bytes of the descriptors of `SPLIT` in the order of the chain
+    fn split_contents(memory: &GuestRam) -> Vec<u8> {
+        SPLIT.iter().flat_map(|&(addr, len)| memory.slice(addr, len).unwrap().to_vec()).collect()
+    }
+
+    fn read_split(file: &P9File, offset: u64, count: u32) -> (u32, Vec<u8>, usize) {
+        let memory = split_memory();
+        let mut chain = Chain::split(memory.clone(), &[], SPLIT);
+        let nread = {
+            let mut pp = PduParser::new(&mut chain, memory.clone());
+            Server::<RemoveFs>::read_into_chain(file, &mut pp, offset, count, &mut None).unwrap()
+        };
+        (nread, split_contents(&memory), chain.remaining_write())
+    }
+
+    #[test]
+    fn read_into_chain_fills_descriptors_in_turn() {
+        let data = pattern(64);
+        let file = P9File::from_buffer(Buffer::new(Cow::Owned(data.clone())));
+
+        // Ending inside the third descriptor
+        let (nread, contents, remaining) = read_split(&file, 3, 20);
+        assert_eq!(nread, 20);
+        assert_eq!(&contents[..20], &data[3..23]);
+        assert!(contents[20..].iter().all(|&b| b == 0), "bytes after count untouched");
+        assert_eq!(remaining, SPLIT_SIZE - 20);
+
+        // Ending exactly at the end of the first descriptor, then the second
+        for &count in &[7, 8] {
+            let (nread, contents, remaining) = read_split(&file, 0, count);
+            assert_eq!(nread, count);
+            assert_eq!(&contents[..count as usize], &data[..count as usize]);
+            assert_eq!(contents[count as usize], 0);
+            assert_eq!(remaining, SPLIT_SIZE - count as usize);
+        }
+
+        // More than the chain holds
+        let (nread, contents, remaining) = read_split(&file, 0, 40);
+        assert_eq!(nread, SPLIT_SIZE as u32);
+        assert_eq!(&contents[..], &data[..SPLIT_SIZE]);
+        assert_eq!(remaining, 0);
+
+        // Less data in the file than the chain holds
+        let (nread, contents, _) = read_split(&file, 50, SPLIT_SIZE as u32);
+        assert_eq!(nread, 14);
+        assert_eq!(&contents[..14], &data[50..]);
+        assert!(contents[14..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn write_from_chain_takes_descriptors_in_turn() {
+        let path = std::env::temp_dir().join(format!("ph-9p-split-{}", process::id()));
+        let data = pattern(SPLIT_SIZE);
+        for &count in &[20, 7, 8, SPLIT_SIZE as u32, 40] {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                .open(&path).unwrap();
+            let file = P9File::from_file(file);
+            let memory = split_memory();
+            let mut offset = 0;
+            for &(addr, len) in SPLIT {
+                memory.write_bytes(addr, &data[offset..offset + len]).unwrap();
+                offset += len;
+            }
+            let mut chain = Chain::split(memory.clone(), SPLIT, &[]);
+            let nwritten = {
+                let mut pp = PduParser::new(&mut chain, memory.clone());
+                Server::<RemoveFs>::write_from_chain(&file, &mut pp, 5, count, &mut None).unwrap()
+            };
+            let expected = (count as usize).min(SPLIT_SIZE);
+            assert_eq!(nwritten as usize, expected, "bytes written of {}", count);
+            let written = fs::read(&path).unwrap();
+            assert_eq!(&written[..5], &[0; 5]);
+            assert_eq!(&written[5..], &data[..expected], "data written of {}", count);
+            assert_eq!(chain.remaining_read(), SPLIT_SIZE - expected);
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
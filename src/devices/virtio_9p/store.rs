@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::io;
+use std::os::linux::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::devices::virtio_9p::directory::{Directory, P9DirEntry};
+use crate::devices::virtio_9p::filesystem::FileStat;
+use crate::vm::metrics::{self, Counter, Labels};
+
+// A cache which reaches this many entries is emptied and filled again
+const MAX_ENTRIES: usize = 1 << 20;
+const MAX_DIRECTORIES: usize = 1 << 16;
+
+// Inodes of filesystems mounted below the root of a store have the index of
+// the filesystem in the bits above this
+const DEVICE_SHIFT: u32 = 56;
+const INO_MASK: u64 = (1 << DEVICE_SHIFT) - 1;
+
+lazy_static! {
+    static ref CACHES: Mutex<HashMap<PathBuf, Arc<StoreCache>>> = Mutex::new(HashMap::new());
+}
+
+fn not_found() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOENT)
+}
+
+///
+/// Attributes, directory listings and symlink targets of a read-only host
+/// tree such as `/nix` or `/usr`, which are kept for as long as the process
+/// runs on the assumption that the tree does not change while it is
+/// exported. Names which do not exist are cached too, since a guest which
+/// searches `PATH` or a library path looks up many of them.
+///
+/// The qid of a file is derived from its host device and inode alone, so
+/// files which the host store deduplicated into hard links share a qid, and
+/// with it one inode and one copy in the page cache of a guest which mounts
+/// the share with caching.
+///
+pub struct StoreCache {
+    root_dev: u64,
+    // Other filesystems found below the root, in the order they were seen
+    devices: Mutex<Vec<u64>>,
+    attrs: RwLock<HashMap<PathBuf, Option<FileStat>>>,
+    links: RwLock<HashMap<PathBuf, OsString>>,
+    directories: RwLock<HashMap<PathBuf, Directory>>,
+    hits: Arc<Counter>,
+    misses: Arc<Counter>,
+}
+
+impl StoreCache {
+    /// The cache of the tree at `root`, shared by every share of `root` in
+    /// this process so that it survives a reboot of the VM.
+    pub fn for_root(root: &Path) -> Arc<StoreCache> {
+        let mut caches = CACHES.lock().unwrap();
+        if let Some(cache) = caches.get(root) {
+            return cache.clone();
+        }
+        // If the root cannot be read every lookup fails anyway
+        let root_dev = root.metadata().map(|meta| meta.st_dev()).unwrap_or(0);
+        let labels = Labels::new().with("root", root.display());
+        let cache = Arc::new(StoreCache {
+            root_dev,
+            devices: Mutex::new(Vec::new()),
+            attrs: RwLock::new(HashMap::new()),
+            links: RwLock::new(HashMap::new()),
+            directories: RwLock::new(HashMap::new()),
+            hits: metrics::counter("ph_9p_store_cache_hits_total",
+                                   "Lookups in read-only store shares answered from the cache", labels.clone()),
+            misses: metrics::counter("ph_9p_store_cache_misses_total",
+                                     "Lookups in read-only store shares which went to the host", labels),
+        });
+        caches.insert(root.to_path_buf(), cache.clone());
+        cache
+    }
+
+    // Files on the filesystem of the root keep their inode number
+    fn map_ino(&self, dev: u64, ino: u64) -> u64 {
+        if dev == self.root_dev {
+            return ino;
+        }
+        let mut devices = self.devices.lock().unwrap();
+        let idx = match devices.iter().position(|&d| d == dev) {
+            Some(idx) => idx,
+            None => {
+                devices.push(dev);
+                devices.len() - 1
+            }
+        };
+        ((idx as u64 + 1) << DEVICE_SHIFT) | (ino & INO_MASK)
+    }
+
+    fn file_stat(&self, meta: &Metadata) -> FileStat {
+        let mut stat = FileStat::from_metadata(meta);
+        stat.qid = stat.qid.for_store(self.map_ino(meta.st_dev(), meta.st_ino()));
+        stat.ino = stat.qid.path();
+        stat
+    }
+
+    fn insert_attr(&self, path: PathBuf, entry: Option<FileStat>) {
+        let mut attrs = self.attrs.write().unwrap();
+        if attrs.len() >= MAX_ENTRIES {
+            attrs.clear();
+        }
+        attrs.insert(path, entry);
+    }
+
+    /// The attributes of `path`, read with `metadata` if they are not cached.
+    pub fn stat<F>(&self, path: &Path, metadata: F) -> io::Result<FileStat>
+        where F: FnOnce(&Path) -> io::Result<Metadata>
+    {
+        if let Some(entry) = self.attrs.read().unwrap().get(path) {
+            self.hits.inc();
+            return entry.ok_or_else(not_found);
+        }
+        self.misses.inc();
+        let entry = match metadata(path) {
+            Ok(meta) => Some(self.file_stat(&meta)),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => None,
+            Err(e) => return Err(e),
+        };
+        self.insert_attr(path.to_path_buf(), entry);
+        entry.ok_or_else(not_found)
+    }
+
+    /// The target of the symlink `path`, read with `readlink` if it is not cached.
+    pub fn readlink<F>(&self, path: &Path, readlink: F) -> io::Result<OsString>
+        where F: FnOnce(&Path) -> io::Result<OsString>
+    {
+        if let Some(target) = self.links.read().unwrap().get(path) {
+            self.hits.inc();
+            return Ok(target.clone());
+        }
+        self.misses.inc();
+        let target = readlink(path)?;
+        let mut links = self.links.write().unwrap();
+        if links.len() >= MAX_ENTRIES {
+            links.clear();
+        }
+        links.insert(path.to_path_buf(), target.clone());
+        Ok(target)
+    }
+
+    /// The entries of the directory `path`, listed with `readdir` if they
+    /// are not cached. The attributes of each entry are cached as well, as
+    /// a listing is usually followed by a lookup of its entries.
+    pub fn readdir<F>(&self, path: &Path, readdir: F) -> io::Result<Directory>
+        where F: FnOnce(&Path) -> io::Result<Vec<(String, Metadata)>>
+    {
+        if let Some(directory) = self.directories.read().unwrap().get(path) {
+            self.hits.inc();
+            return Ok(directory.clone());
+        }
+        self.misses.inc();
+        let mut directory = Directory::new();
+        for (name, meta) in readdir(path)? {
+            let stat = self.file_stat(&meta);
+            directory.push_entry(P9DirEntry::from_metadata(stat.qid, &meta, &name));
+            self.insert_attr(path.join(&name), Some(stat));
+        }
+        let mut directories = self.directories.write().unwrap();
+        if directories.len() >= MAX_DIRECTORIES {
+            directories.clear();
+        }
+        directories.insert(path.to_path_buf(), directory.clone());
+        Ok(directory)
+    }
+}
@@ -1,20 +1,23 @@
+use std::borrow::Cow;
 use std::collections::{HashSet, BTreeMap};
 use std::collections::btree_map::Entry;
 use std::ffi::{OsString, OsStr};
+use std::fs::File;
 use std::io;
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf, Component};
 use std::process::{Command, Stdio};
+use std::sync::Arc;
 use std::time::{UNIX_EPOCH, SystemTime};
 
 use crate::devices::virtio_9p::{
     directory::{Directory, P9DirEntry},
     file::{P9File, Qid, P9_QTDIR, P9_QTFILE},
-    filesystem::{FileSystemOps, FsTouch, FileSystem},
-    pdu::PduParser,
+    filesystem::{FileSystemOps, FsTouch, FileSystem, FileStat, FsStat},
 };
 use crate::devices::virtio_9p::file::Buffer;
+use crate::system::MemoryFd;
 
 #[derive(Clone)]
 struct NodeData {
@@ -44,7 +47,8 @@ impl NodeData {
 #[derive(Clone)]
 enum Node {
     File(PathBuf, NodeData),
-    MemoryFile(Buffer<&'static [u8]>, NodeData),
+    MemoryFile(Buffer<Cow<'static, [u8]>>, NodeData),
+    SealedFile(Arc<MemoryFd>, NodeData),
     Dir(BTreeMap<OsString, Node>, NodeData),
 }
 
@@ -63,31 +67,38 @@ impl Node {
         Node::File(local, data)
     }
 
-    fn new_memory_file<S: Into<OsString>>(name: S, mode: u32, inode: u32, size: u64, bytes: &'static [u8]) -> Node {
+    fn new_memory_file<S: Into<OsString>>(name: S, mode: u32, inode: u32, size: u64, bytes: Cow<'static, [u8]>) -> Node {
         let mode = mode | libc::S_IFREG;
         let data = NodeData::new(name, P9_QTFILE, size, mode, inode);
         let buffer = Buffer::new(bytes);
         Node::MemoryFile(buffer, data)
     }
 
+    fn new_sealed_file<S: Into<OsString>>(name: S, mode: u32, inode: u32, memfd: MemoryFd) -> Node {
+        let mode = mode | libc::S_IFREG;
+        let data = NodeData::new(name, P9_QTFILE, memfd.size() as u64, mode, inode);
+        Node::SealedFile(Arc::new(memfd), data)
+    }
+
     fn node_data(&self) -> &NodeData {
         match self {
             Node::Dir(_, data) => data,
             Node::File(_, data) => data,
             Node::MemoryFile(_, data) => data,
+            Node::SealedFile(_, data) => data,
         }
     }
     fn qid(&self) -> Qid {
         self.node_data().qid
     }
 
-    fn write_stat(&self, pp: &mut PduParser) -> io::Result<()> {
-        self.node_data().write_stat(pp)
+    fn stat(&self) -> FileStat {
+        self.node_data().stat()
     }
 
-    fn create_directory_entry(&self, offset: u64) -> P9DirEntry {
+    fn create_directory_entry(&self) -> P9DirEntry {
         let data = self.node_data();
-        P9DirEntry::new(data.qid, offset, data.dtype(), data.name_str())
+        P9DirEntry::new(data.qid, data.dtype(), data.name_str())
     }
 
 
@@ -144,12 +155,9 @@ impl Node {
     fn populate_directory(&self) -> io::Result<Directory> {
         match self {
             Node::Dir(nodes, ..) => {
-                let mut offset = 0;
                 let mut directory = Directory::new();
                 for  node in nodes.values() {
-                    let entry = node.create_directory_entry(offset);
-                    offset = entry.offset();
-                    directory.push_entry(entry);
+                    directory.push_entry(node.create_directory_entry());
                 }
                 return Ok(directory)
             },
@@ -180,30 +188,23 @@ impl NodeData {
         }
     }
 
-    fn write_stat(&self, pp: &mut PduParser) -> io::Result<()> {
-        const P9_STATS_BASIC: u64 =  0x000007ff;
-        pp.w64(P9_STATS_BASIC)?;
-        self.qid.write(pp)?;
-
-        pp.w32(self.mode)?;
-        pp.w32(0)?;   // uid
-        pp.w32(0)?;   // gid
-        pp.w64(1)?;   // nlink
-        pp.w64(0)?;   // rdev
-        pp.w64(self.size)?;  // size
-        pp.w64(0)?;   // blksize
-        pp.w64(0)?;   // blocks
-        pp.w64(0)?;   // atime
-        pp.w64(0)?;   // atime nsec
-        pp.w64(0)?;   // mtime
-        pp.w64(0)?;   // mtime nsec
-        pp.w64(0)?;   // ctime
-        pp.w64(0)?;   // ctime nsec
-        pp.w64(0)?;   // btime
-        pp.w64(0)?;   // btime nsec
-        pp.w64(0)?;
-        pp.w64(0)?;
-        Ok(())
+    // Synthetic files belong to root and have no timestamps
+    fn stat(&self) -> FileStat {
+        FileStat {
+            qid: self.qid,
+            ino: u64::from(self.inode),
+            mode: self.mode,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            rdev: 0,
+            size: self.size,
+            blksize: 0,
+            blocks: 0,
+            atime: (0, 0),
+            mtime: (0, 0),
+            ctime: (0, 0),
+        }
     }
 }
 
@@ -290,10 +291,14 @@ impl SyntheticFS {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn add_memory_file<S: Into<OsString>, P: AsRef<Path>>(&mut self, dirpath: P, filename: S, mode: u32, bytes: &'static [u8]) -> io::Result<()> {
+    /// Add a file with the contents `bytes`, which may be static data or a
+    /// buffer generated at runtime.
+    pub fn add_memory_file<S, P, B>(&mut self, dirpath: P, filename: S, mode: u32, bytes: B) -> io::Result<()>
+        where S: Into<OsString>, P: AsRef<Path>, B: Into<Cow<'static, [u8]>>
+    {
         let dirpath = dirpath.as_ref();
         let filename = filename.into();
+        let bytes = bytes.into();
         self.mkdir(dirpath, 0o755);
         let inode = self.inodes.next_inode();
         let node = self.lookup_mut(dirpath)?;
@@ -302,6 +307,21 @@ impl SyntheticFS {
         Ok(())
 
     }
+    /// Add a file with the contents of `memfd`, which must be sealed against
+    /// writes so that the guest always reads the same contents.
+    pub fn add_sealed_file<S, P>(&mut self, dirpath: P, filename: S, mode: u32, memfd: MemoryFd) -> io::Result<()>
+        where S: Into<OsString>, P: AsRef<Path>
+    {
+        let dirpath = dirpath.as_ref();
+        let filename = filename.into();
+        self.mkdir(dirpath, 0o755);
+        let inode = self.inodes.next_inode();
+        let node = self.lookup_mut(dirpath)?;
+        let entries = node.entries_mut().ok_or(rawerr(libc::ENOTDIR))?;
+        entries.insert(filename.clone(), Node::new_sealed_file(filename, mode, inode, memfd));
+        Ok(())
+    }
+
     pub fn add_file<S: Into<OsString>, P: AsRef<Path>, Q: AsRef<Path>>(&mut self, dirpath: P, filename: S, mode: u32, realpath: Q) {
         let dirpath = dirpath.as_ref();
         let realpath = realpath.as_ref();
@@ -420,9 +440,9 @@ impl FileSystemOps for SyntheticFS {
         Ok(node.qid())
     }
 
-    fn write_stat(&self, path: &Path, pp: &mut PduParser) -> io::Result<()> {
+    fn stat(&self, path: &Path) -> io::Result<FileStat> {
         let node = self.lookup(path)?;
-        node.write_stat(pp)
+        Ok(node.stat())
     }
 
     fn open(&self, path: &Path, flags: u32) -> io::Result<P9File> {
@@ -438,6 +458,10 @@ impl FileSystemOps for SyntheticFS {
             Node::MemoryFile(buffer,..) => {
                 Ok(P9File::from_buffer(buffer.clone()))
             }
+            Node::SealedFile(memfd, ..) => {
+                let file = File::open(memfd.proc_path())?;
+                Ok(P9File::from_file(file))
+            }
         }
     }
 
@@ -445,19 +469,17 @@ impl FileSystemOps for SyntheticFS {
         syserr(libc::EROFS)
     }
 
-    fn write_statfs(&self, _path: &Path, pp: &mut PduParser) -> io::Result<()> {
-        //notify!("write_statfs({})", path.display());
-        let f_files = self.node_count() as u64;
-        pp.w32(0xABCD)?;  // f_type
-        pp.w32(512)?;     // f_bsize
-        pp.w64(0)?;       // f_blocks
-        pp.w64(0)?;       // f_bfree
-        pp.w64(0)?;       // f_bavail
-        pp.w64(f_files)?; // f_files
-        pp.w64(0)?;       // f_ffree
-        pp.w64(0)?;       //
-        pp.w32(4096)?;    // f_namelen
-        Ok(())
+    fn statfs(&self, _path: &Path) -> io::Result<FsStat> {
+        Ok(FsStat {
+            fs_type: 0xABCD,
+            bsize: 512,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: self.node_count() as u64,
+            ffree: 0,
+            namelen: 4096,
+        })
     }
 
     fn chown(&self, _path: &Path, _uid: u32, _gid: u32) -> io::Result<()> {
@@ -0,0 +1,117 @@
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::memory::GuestRam;
+use crate::system::{self, IoUring, IoRequest};
+
+const RING_ENTRIES: u32 = 64;
+
+// Older kernels refuse to register a buffer larger than 1 GiB
+const MAX_REGISTERED_BUFFER: usize = 1 << 30;
+
+///
+/// Reads and writes host files directly to and from the guest memory of a
+/// request through io_uring.
+///
+/// The buffers of a request, one for each descriptor, are submitted together
+/// with a single system call. If guest RAM could be registered with the ring
+/// the requests use the fixed buffer operations, which saves the kernel from
+/// pinning the guest pages on every request.
+///
+pub struct FileRing {
+    ring: IoUring,
+    // Host address and size of each registered buffer
+    buffers: Vec<(u64, usize)>,
+}
+
+impl FileRing {
+    pub fn new(memory: &GuestRam, register_buffers: bool) -> system::Result<FileRing> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let mut file_ring = FileRing { ring, buffers: Vec::new() };
+        if register_buffers {
+            file_ring.register_memory(memory);
+        }
+        Ok(file_ring)
+    }
+
+    fn register_memory(&mut self, memory: &GuestRam) {
+        let mut buffers = Vec::new();
+        for region in memory.regions() {
+            let base = region.base_address();
+            let size = region.guest_range().size();
+            let mut offset = 0;
+            while offset < size {
+                let len = (size - offset).min(MAX_REGISTERED_BUFFER);
+                buffers.push((base + offset as u64, len));
+                offset += len;
+            }
+        }
+        let iovecs: Vec<libc::iovec> = buffers.iter()
+            .map(|&(addr, len)| libc::iovec { iov_base: addr as *mut libc::c_void, iov_len: len })
+            .collect();
+        match self.ring.register_buffers(&iovecs) {
+            Ok(()) => self.buffers = buffers,
+            // Most likely RLIMIT_MEMLOCK is lower than the size of guest RAM
+            Err(e) => notify!("9p io_uring: not using registered buffers: {}", e),
+        }
+    }
+
+    fn buffer_index(&self, addr: u64, len: usize) -> Option<u16> {
+        self.buffers.iter()
+            .position(|&(start, size)| addr >= start && addr + len as u64 <= start + size as u64)
+            .map(|idx| idx as u16)
+    }
+
+    fn request(&self, request: IoRequest, addr: u64, len: usize) -> IoRequest {
+        match self.buffer_index(addr, len) {
+            Some(idx) => request.fixed(idx),
+            None => request,
+        }
+    }
+
+    /// Read from `fd` at `offset` into each of `buffers` in turn, as `preadv()` would.
+    pub fn read(&mut self, fd: RawFd, offset: u64, buffers: &mut [&mut [u8]]) -> io::Result<usize> {
+        let mut requests = Vec::with_capacity(buffers.len());
+        let mut pos = offset;
+        for buffer in buffers.iter_mut() {
+            let (addr, len) = (buffer.as_ptr() as u64, buffer.len());
+            requests.push(self.request(IoRequest::read(fd, pos, buffer), addr, len));
+            pos += len as u64;
+        }
+        let lengths: Vec<usize> = buffers.iter().map(|b| b.len()).collect();
+        self.submit(&requests, &lengths)
+    }
+
+    /// Write each of `buffers` in turn to `fd` at `offset`, as `pwritev()` would.
+    pub fn write(&mut self, fd: RawFd, offset: u64, buffers: &[&[u8]]) -> io::Result<usize> {
+        let mut requests = Vec::with_capacity(buffers.len());
+        let mut pos = offset;
+        for buffer in buffers {
+            let (addr, len) = (buffer.as_ptr() as u64, buffer.len());
+            requests.push(self.request(IoRequest::write(fd, pos, buffer), addr, len));
+            pos += len as u64;
+        }
+        let lengths: Vec<usize> = buffers.iter().map(|b| b.len()).collect();
+        self.submit(&requests, &lengths)
+    }
+
+    // The requests run concurrently, so the transfer ends at the first
+    // request which failed or was short even if later ones completed.
+    fn submit(&mut self, requests: &[IoRequest], lengths: &[usize]) -> io::Result<usize> {
+        let results = self.ring.submit_and_wait(requests)?;
+        let mut total = 0;
+        for (&res, &len) in results.iter().zip(lengths) {
+            if res < 0 {
+                if total == 0 {
+                    return Err(io::Error::from_raw_os_error(-res));
+                }
+                break;
+            }
+            total += res as usize;
+            if (res as usize) < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+}
@@ -0,0 +1,271 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::memory::MemoryManager;
+use crate::virtio::{Chain, DeviceConfigArea, VirtioBus, VirtioDeviceOps, VirtQueue, Result};
+use crate::util::JsonValue;
+
+const VIRTIO_ID_BALLOON: u16 = 5;
+
+const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1 << 1;
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 1 << 2;
+const VIRTIO_BALLOON_F_REPORTING: u64 = 1 << 5;
+
+// num_pages followed by actual, which is the only field the driver writes
+const VIRTIO_BALLOON_CONFIG_SIZE: usize = 8;
+const NUM_PAGES_OFFSET: usize = 0;
+const ACTUAL_OFFSET: usize = 4;
+
+// Pages in the balloon are always 4 KiB, whatever the guest page size
+const BALLOON_PAGE_SHIFT: u64 = 12;
+const BALLOON_PAGE_SIZE: usize = 1 << BALLOON_PAGE_SHIFT;
+
+// How often the guest is asked for fresh memory statistics
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Names of the statistics by tag
+const STAT_NAMES: &[&str] = &[
+    "swap-in", "swap-out", "major-faults", "minor-faults", "free-memory",
+    "total-memory", "available-memory", "disk-caches", "hugetlb-allocations",
+    "hugetlb-failures",
+];
+
+struct BalloonState {
+    ram_size: usize,
+    config: DeviceConfigArea,
+    stats: Vec<(u16, u64)>,
+    stats_time: Option<Instant>,
+    // Total size of the free pages reported by the guest since it started
+    // which were released on the host
+    reported_bytes: u64,
+    // A queue of the running device, used to raise config change interrupts
+    config_vq: Option<VirtQueue>,
+}
+
+///
+/// A handle for changing the size of the balloon while the guest runs.
+///
+#[derive(Clone)]
+pub struct BalloonHandle {
+    state: Arc<Mutex<BalloonState>>,
+}
+
+impl BalloonHandle {
+    /// Ask the guest to give up `bytes` of its RAM to the balloon, or to take
+    /// back memory from the balloon if it holds more than that.
+    pub fn set_target(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let bytes = bytes.min(state.ram_size as u64);
+        state.config.write_u32(NUM_PAGES_OFFSET, (bytes >> BALLOON_PAGE_SHIFT) as u32);
+        if let Some(ref vq) = state.config_vq {
+            vq.notify_config();
+        }
+    }
+
+    pub fn describe(&self) -> JsonValue {
+        let state = self.state.lock().unwrap();
+        let mut stats = JsonValue::object();
+        for &(tag, val) in &state.stats {
+            if let Some(name) = STAT_NAMES.get(tag as usize) {
+                stats.set(*name, val);
+            }
+        }
+        let mut json = JsonValue::object()
+            .with("target-bytes", u64::from(state.config.read_u32(NUM_PAGES_OFFSET)) << BALLOON_PAGE_SHIFT)
+            .with("actual-bytes", u64::from(state.config.read_u32(ACTUAL_OFFSET)) << BALLOON_PAGE_SHIFT)
+            .with("driver-active", state.config_vq.is_some())
+            .with("reported-bytes", state.reported_bytes);
+        if let Some(t) = state.stats_time {
+            json.set("stats", stats);
+            json.set("stats-age-ms", t.elapsed().as_millis() as u64);
+        }
+        json
+    }
+}
+
+///
+/// A virtio-balloon device with which the host reclaims guest memory.
+///
+/// The guest driver places pages in the balloon until it holds the number
+/// of pages set through `BalloonHandle`, and the memory backing them is
+/// released on the host. The guest also reports memory statistics on the
+/// stats queue, which are requested again every `STATS_INTERVAL`.
+///
+/// A driver which supports free page reporting also passes blocks of pages
+/// it has freed on the reporting queue, and their memory is released on the
+/// host without any change to the size of the balloon. The stats and
+/// reporting queues only exist when their features are negotiated.
+///
+pub struct VirtioBalloon {
+    state: Arc<Mutex<BalloonState>>,
+    stats_enabled: bool,
+    reporting_enabled: bool,
+}
+
+impl VirtioBalloon {
+    pub fn create(vbus: &mut VirtioBus, ram_size: usize) -> Result<BalloonHandle> {
+        let mut config = DeviceConfigArea::new(VIRTIO_BALLOON_CONFIG_SIZE);
+        config.set_writeable(ACTUAL_OFFSET, 4);
+        let state = Arc::new(Mutex::new(BalloonState {
+            ram_size,
+            config,
+            stats: Vec::new(),
+            stats_time: None,
+            reported_bytes: 0,
+            config_vq: None,
+        }));
+        let dev = Arc::new(RwLock::new(VirtioBalloon {
+            state: state.clone(),
+            stats_enabled: false,
+            reporting_enabled: false,
+        }));
+        vbus.new_virtio_device(VIRTIO_ID_BALLOON, dev)
+            .set_num_queues(4)
+            .set_required_queues(2)
+            .set_features(VIRTIO_BALLOON_F_STATS_VQ | VIRTIO_BALLOON_F_DEFLATE_ON_OOM | VIRTIO_BALLOON_F_REPORTING)
+            .set_config_size(VIRTIO_BALLOON_CONFIG_SIZE)
+            .register()?;
+        Ok(BalloonHandle { state })
+    }
+}
+
+impl VirtioDeviceOps for VirtioBalloon {
+    fn reset(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.config.write_u32(ACTUAL_OFFSET, 0);
+        state.stats.clear();
+        state.stats_time = None;
+        state.config_vq = None;
+    }
+
+    fn enable_features(&mut self, bits: u64) -> bool {
+        self.stats_enabled = bits & VIRTIO_BALLOON_F_STATS_VQ != 0;
+        self.reporting_enabled = bits & VIRTIO_BALLOON_F_REPORTING != 0;
+        true
+    }
+
+    fn write_config(&mut self, offset: usize, size: usize, val: u64) {
+        self.state.lock().unwrap().config.write_config(offset, size, val);
+    }
+
+    fn read_config(&mut self, offset: usize, size: usize) -> u64 {
+        self.state.lock().unwrap().config.read_config(offset, size)
+    }
+
+    fn start(&mut self, memory: &MemoryManager, queues: Vec<VirtQueue>) {
+        // Queues of features which were not negotiated are left out of the
+        // numbering, so the reporting queue follows the deflate queue when
+        // there is no stats queue
+        let mut queues = queues.into_iter();
+        let inflate_vq = queues.next().unwrap();
+        let deflate_vq = queues.next().unwrap();
+        let stats_vq = if self.stats_enabled { queues.next() } else { None };
+        let reporting_vq = if self.reporting_enabled { queues.next() } else { None };
+        self.state.lock().unwrap().config_vq = Some(inflate_vq.clone());
+        if !memory.can_discard_ram() {
+            warn!("virtio-balloon: guest RAM is backed by hugetlb pages, memory given up by the guest is not released on the host");
+        }
+
+        if let Some(vq) = reporting_vq {
+            let memory = memory.clone();
+            let state = self.state.clone();
+            thread::spawn(move || run_reporting(vq, memory, state));
+        }
+        let memory = memory.clone();
+        thread::spawn(move || run_inflate(inflate_vq, memory));
+        // Pages taken out of the balloon are faulted back in when the guest uses them
+        thread::spawn(move || deflate_vq.on_each_chain(|_| ()));
+        if let Some(vq) = stats_vq {
+            let state = self.state.clone();
+            thread::spawn(move || run_stats(vq, state));
+        }
+    }
+}
+
+fn run_inflate(vq: VirtQueue, memory: MemoryManager) {
+    vq.on_each_chain(|mut chain| {
+        if let Err(e) = discard_pages(&mut chain, &memory) {
+            warn!("virtio-balloon: error reading inflate request: {}", e);
+        }
+    });
+}
+
+// Release the pages listed in an inflate request, merging runs of adjacent pages
+fn discard_pages(chain: &mut Chain, memory: &MemoryManager) -> ::std::io::Result<()> {
+    let mut run: Option<(u64, usize)> = None;
+    while chain.remaining_read() >= 4 {
+        let address = (chain.r32()? as u64) << BALLOON_PAGE_SHIFT;
+        run = match run {
+            Some((start, len)) if start + len as u64 == address => Some((start, len + BALLOON_PAGE_SIZE)),
+            Some((start, len)) => {
+                discard_range(memory, start, len);
+                Some((address, BALLOON_PAGE_SIZE))
+            }
+            None => Some((address, BALLOON_PAGE_SIZE)),
+        };
+    }
+    if let Some((start, len)) = run {
+        discard_range(memory, start, len);
+    }
+    Ok(())
+}
+
+// Each chain holds blocks of free guest pages in its writeable buffers, and
+// is returned once they have been released. Only the blocks which were
+// released are counted.
+fn run_reporting(vq: VirtQueue, memory: MemoryManager, state: Arc<Mutex<BalloonState>>) {
+    vq.on_each_chain(|chain| {
+        let mut reported = 0;
+        for (address, size) in chain.write_buffers() {
+            if discard_range(&memory, address, size) {
+                reported += size as u64;
+            }
+        }
+        state.lock().unwrap().reported_bytes += reported;
+    });
+}
+
+// Release a range of guest pages and return true if the host memory which
+// backs it was released. Nothing is tried when the backing of guest RAM
+// cannot release memory, which is warned about when the device starts.
+fn discard_range(memory: &MemoryManager, address: u64, size: usize) -> bool {
+    if !memory.can_discard_ram() {
+        return false;
+    }
+    match memory.discard_ram(address, size) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("virtio-balloon: failed to discard {} bytes at 0x{:x}: {}", size, address, e);
+            false
+        }
+    }
+}
+
+// The driver places a single buffer of statistics on the queue and fills it
+// again with current values each time the device returns it.
+fn run_stats(vq: VirtQueue, state: Arc<Mutex<BalloonState>>) {
+    loop {
+        let mut chain = match vq.wait_next_chain() {
+            Ok(chain) => chain,
+            Err(e) => {
+                warn!("virtio-balloon: error waiting on stats queue: {}", e);
+                return;
+            }
+        };
+        let mut stats = Vec::new();
+        while chain.remaining_read() >= 10 {
+            match (chain.r16(), chain.r64()) {
+                (Ok(tag), Ok(val)) => stats.push((tag, val)),
+                _ => break,
+            }
+        }
+        {
+            let mut state = state.lock().unwrap();
+            state.stats = stats;
+            state.stats_time = Some(Instant::now());
+        }
+        thread::sleep(STATS_INTERVAL);
+        chain.flush_chain();
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crate::disk::{self, DiskImage, IoShare};
+use crate::system::{seccomp, SeccompProfile};
+use crate::vm::metrics::IoBytes;
+use super::Request;
+
+///
+/// Services the requests of a virtio-blk queue on a pool of worker threads.
+///
+/// The device thread reads the header of each request from the queue and
+/// hands it to `submit()`. Any idle worker takes the request, performs the
+/// IO and places the chain in the used ring as soon as it is complete, so
+/// a slow read on the host does not hold up the requests behind it and
+/// requests complete in whatever order the host finishes them.
+///
+/// A flush is a barrier. `flush()` waits until every request submitted
+/// before it has completed and then flushes the image on the device thread.
+///
+pub(super) struct RequestEngine<D: DiskImage+'static> {
+    sender: Sender<Request>,
+    context: Arc<RequestContext<D>>,
+}
+
+///
+/// State which workers share while processing requests.
+///
+pub(super) struct RequestContext<D: DiskImage> {
+    pub(super) disk: SharedDisk<D>,
+    pub(super) write_through: Arc<AtomicBool>,
+    pub(super) io_share: Option<IoShare>,
+    pub(super) bytes: IoBytes,
+    in_flight: InFlight,
+}
+
+impl <D: DiskImage+'static> RequestEngine<D> {
+    pub(super) fn new(disk: D, nthreads: usize, write_through: Arc<AtomicBool>, io_share: Option<IoShare>, bytes: IoBytes) -> Self {
+        let context = Arc::new(RequestContext {
+            disk: SharedDisk::new(disk),
+            write_through,
+            io_share,
+            bytes,
+            in_flight: InFlight::new(),
+        });
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..nthreads.max(1) {
+            let receiver = receiver.clone();
+            let context = context.clone();
+            thread::spawn(move || Self::run_worker(&receiver, &context));
+        }
+        RequestEngine { sender, context }
+    }
+
+    fn run_worker(receiver: &Mutex<Receiver<Request>>, context: &RequestContext<D>) {
+        if let Err(err) = seccomp::sandbox_thread(SeccompProfile::Block) {
+            warn!("Failed to install seccomp filter for virtio block worker: {}", err);
+        }
+        loop {
+            // The receiver is only locked while waiting so that other workers
+            // can take the next request while this one is processed.
+            let request = match receiver.lock().unwrap().recv() {
+                Ok(request) => request,
+                Err(_) => return,
+            };
+            request.process(context);
+            context.in_flight.finish();
+        }
+    }
+
+    /// Queue `request` to be processed by the next idle worker.
+    pub(super) fn submit(&self, request: Request) {
+        self.context.in_flight.start();
+        if let Err(err) = self.sender.send(request) {
+            // Every worker has exited, so complete the request here
+            self.context.in_flight.finish();
+            err.0.process(&self.context);
+        }
+    }
+
+    /// Process `request` once every request submitted before it is complete.
+    pub(super) fn flush(&self, request: Request) {
+        self.context.in_flight.wait_idle();
+        request.process(&self.context);
+    }
+}
+
+impl <D: DiskImage> RequestContext<D> {
+    pub(super) fn is_write_through(&self) -> bool {
+        self.write_through.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn wait_io_share(&self, len: usize) {
+        if let Some(ref io_share) = self.io_share {
+            io_share.acquire(len);
+        }
+    }
+}
+
+///
+/// A disk image which workers access together if the image supports
+/// concurrent IO and otherwise one at a time.
+///
+pub(super) struct SharedDisk<D: DiskImage> {
+    image: RwLock<D>,
+    concurrent: bool,
+}
+
+impl <D: DiskImage> SharedDisk<D> {
+    fn new(image: D) -> Self {
+        let concurrent = image.concurrent_io();
+        SharedDisk { image: RwLock::new(image), concurrent }
+    }
+
+    pub(super) fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> disk::Result<()> {
+        if self.concurrent {
+            self.image.read().unwrap().read_sectors_at(start_sector, buffer)
+        } else {
+            self.image.write().unwrap().read_sectors(start_sector, buffer)
+        }
+    }
+
+    pub(super) fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> disk::Result<()> {
+        if self.concurrent {
+            self.image.read().unwrap().write_sectors_at(start_sector, buffer)
+        } else {
+            self.image.write().unwrap().write_sectors(start_sector, buffer)
+        }
+    }
+
+    pub(super) fn flush(&self) -> disk::Result<()> {
+        self.image.write().unwrap().flush()
+    }
+
+    pub(super) fn disk_image_id(&self) -> Vec<u8> {
+        self.image.read().unwrap().disk_image_id().to_vec()
+    }
+}
+
+// Number of submitted requests which have not completed yet
+struct InFlight {
+    count: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl InFlight {
+    fn new() -> Self {
+        InFlight { count: Mutex::new(0), idle: Condvar::new() }
+    }
+
+    fn start(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    fn finish(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.idle.notify_all();
+        }
+    }
+
+    fn wait_idle(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            count = self.idle.wait(count).unwrap();
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! virtio-blk device.
+//!
+//! Ordering and durability guarantees provided to the guest:
+//!
+//! * Requests are processed concurrently by a pool of worker threads (see
+//!   `engine`) and complete in any order. A read always returns the data of
+//!   every write completed before it was submitted, but the guest must not
+//!   expect any ordering between requests which are in flight together.
+//!
+//! * By default the device has a volatile write cache. Completion of a write
+//!   means the data has reached the host page cache and it may be lost if the
+//!   host crashes. A `VIRTIO_BLK_T_FLUSH` request calls `fdatasync()` on the
+//!   image once every request submitted before it has completed, and completes
+//!   only after every previously completed write is on stable storage.
+//!
+//! * If the guest clears the `writeback` field of the configuration area
+//!   (`VIRTIO_BLK_F_CONFIG_WCE`) the device is write-through and every write
+//!   is flushed before it completes. The virtio-blk request header has no FUA
+//!   flag, so this is how a guest obtains FUA semantics.
+//!
+//! * Once a flush fails every later flush and write-through write fails as
+//!   well, since data from before the first failure may have been lost.
+//!
+//! * Each descriptor chain carries exactly one request. A chain with more
+//!   data after the header of a read, flush or get-id request is failed with
+//!   `VIRTIO_BLK_S_IOERR` rather than having the extra data ignored.
+//!
+//! Writes to an image opened with a memory overlay never reach the image and
+//! flushes of such an image succeed without doing anything.
+
+use std::io::Write;
+use std::sync::{RwLock, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{result, io, fmt, thread};
+
+use crate::{disk, virtio};
+use crate::virtio::{VirtioBus, VirtioDeviceOps, VirtQueue, DeviceConfigArea, Chain};
+use crate::memory::MemoryManager;
+use crate::disk::{DiskImage, IoShare};
+use crate::system::{seccomp, SeccompProfile};
+use crate::vm::metrics::IoBytes;
+
+mod engine;
+
+use self::engine::{RequestEngine, RequestContext};
+
+const VIRTIO_BLK_F_RO: u64 = (1 << 5);
+const VIRTIO_BLK_F_BLK_SIZE: u64 = (1 << 6);
+const VIRTIO_BLK_F_FLUSH: u64 = (1 << 9);
+const VIRTIO_BLK_F_TOPOLOGY: u64 = (1 << 10);
+const VIRTIO_BLK_F_CONFIG_WCE: u64 = (1 << 11);
+const VIRTIO_BLK_F_SEG_MAX: u64 = (1 << 2);
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const SECTOR_SHIFT: usize = 9;
+const SECTOR_SIZE: usize = 1 << SECTOR_SHIFT;
+
+const QUEUE_SIZE: usize = 256;
+
+enum Error {
+    IoChainError(io::Error),
+    DiskRead(disk::Error),
+    DiskWrite(disk::Error),
+    DiskFlush(disk::Error),
+    WriteThrough(disk::Error),
+    VirtQueueWait(virtio::Error),
+    InvalidReadDescriptor(usize),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IoChainError(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            IoChainError(e) => write!(f, "i/o error on virtio chain operation: {}", e),
+            DiskRead(e) => write!(f, "error reading disk image: {}", e),
+            DiskWrite(e) => write!(f, "error writing disk image: {}", e),
+            DiskFlush(e) => write!(f, "error flushing disk image: {}", e),
+            WriteThrough(e) => write!(f, "error flushing write in write-through mode: {}", e),
+            VirtQueueWait(e) =>write!(f, "error waiting on virtqueue: {}", e),
+            InvalidReadDescriptor(sz) => write!(f, "virtqueue read descriptor size ({}) is invalid. Not a multiple of sector size", sz),
+        }
+    }
+}
+type Result<T> = result::Result<T, Error>;
+
+pub struct VirtioBlock<D: DiskImage+'static> {
+    disk_image: Option<D>,
+    config: DeviceConfigArea,
+    enabled_features: u64,
+    write_through: Arc<AtomicBool>,
+    io_share: Option<IoShare>,
+    io_threads: usize,
+}
+
+const HEADER_SIZE: usize = 16;
+
+const VIRTIO_ID_BLOCK: u16 = 2;
+const CAPACITY_OFFSET: usize = 0;
+const SEG_MAX_OFFSET: usize = 12;
+const BLK_SIZE_OFFSET: usize = 20;
+const PHYSICAL_BLOCK_EXP_OFFSET: usize = 24;
+const ALIGNMENT_OFFSET_OFFSET: usize = 25;
+const MIN_IO_SIZE_OFFSET: usize = 26;
+const OPT_IO_SIZE_OFFSET: usize = 28;
+const WRITEBACK_OFFSET: usize = 32;
+const CONFIG_SIZE: usize = 36;
+impl <D: DiskImage + 'static> VirtioBlock<D> {
+
+    fn new(disk_image: D, io_share: Option<IoShare>, io_threads: usize) -> Self {
+        let mut config = DeviceConfigArea::new(CONFIG_SIZE);
+        config.write_u64(CAPACITY_OFFSET, disk_image.sector_count());
+        config.write_u32(SEG_MAX_OFFSET, QUEUE_SIZE as u32 - 2);
+        let topology = disk_image.topology();
+        config.write_u32(BLK_SIZE_OFFSET, topology.logical_block_size);
+        config.write_u8(PHYSICAL_BLOCK_EXP_OFFSET, topology.physical_block_exp());
+        config.write_u8(ALIGNMENT_OFFSET_OFFSET, 0);
+        config.write_u16(MIN_IO_SIZE_OFFSET, topology.min_io_blocks());
+        config.write_u32(OPT_IO_SIZE_OFFSET, topology.opt_io_blocks());
+        config.write_u8(WRITEBACK_OFFSET, 1);
+        config.set_writeable(WRITEBACK_OFFSET, 1);
+        VirtioBlock {
+            disk_image: Some(disk_image),
+            config,
+            enabled_features: 0,
+            write_through: Arc::new(AtomicBool::new(false)),
+            io_share,
+            io_threads,
+        }
+    }
+
+    /// Reads and writes of the disk image wait for `io_share` if it is given.
+    /// Requests are processed by `io_threads` worker threads.
+    pub fn create(vbus: &mut VirtioBus, disk_image: D, io_share: Option<IoShare>, io_threads: usize) -> virtio::Result<()> {
+        let feature_bits = VIRTIO_BLK_F_FLUSH |
+            VIRTIO_BLK_F_CONFIG_WCE |
+            VIRTIO_BLK_F_BLK_SIZE |
+            VIRTIO_BLK_F_SEG_MAX  |
+            VIRTIO_BLK_F_TOPOLOGY |
+            if disk_image.read_only() {
+                VIRTIO_BLK_F_RO
+            } else {
+                0
+            };
+
+        let dev = Arc::new(RwLock::new(VirtioBlock::new(disk_image, io_share, io_threads)));
+
+        vbus.new_virtio_device(VIRTIO_ID_BLOCK, dev)
+            .set_queue_sizes(&[QUEUE_SIZE])
+            .set_config_size(CONFIG_SIZE)
+            .set_features(feature_bits)
+            .register()
+    }
+}
+
+impl <D: DiskImage> VirtioDeviceOps for VirtioBlock<D> {
+    fn enable_features(&mut self, bits: u64) -> bool {
+        self.enabled_features = bits;
+        // A driver which does not negotiate flush support cannot ask for
+        // writes to be made durable, so every write must be durable.
+        self.write_through.store(bits & VIRTIO_BLK_F_FLUSH == 0, Ordering::Relaxed);
+        true
+    }
+
+    fn write_config(&mut self, offset: usize, size: usize, val: u64) {
+        if self.config.write_config(offset, size, val) && offset == WRITEBACK_OFFSET && self.enabled_features & VIRTIO_BLK_F_CONFIG_WCE != 0 {
+            let writeback = self.config.read_config(WRITEBACK_OFFSET, 1) != 0;
+            self.write_through.store(!writeback, Ordering::Relaxed);
+        }
+    }
+
+    fn read_config(&mut self, offset: usize, size: usize) -> u64 {
+        self.config.read_config(offset, size)
+    }
+
+    fn start(&mut self, _: &MemoryManager, mut queues: Vec<VirtQueue>) {
+        let vq = queues.pop().unwrap();
+
+        let mut disk = self.disk_image.take().expect("No disk image?");
+        if let Err(err) = disk.open() {
+            warn!("Unable to start virtio-block device: {}", err);
+            return;
+        }
+        let mut dev = VirtioBlockDevice::new(vq, disk, self.io_threads, self.write_through.clone(), self.io_share.clone());
+        thread::spawn(move || {
+            if let Err(err) = seccomp::sandbox_thread(SeccompProfile::Block) {
+                warn!("Failed to install seccomp filter for virtio block device: {}", err);
+            }
+            if let Err(err) = dev.run() {
+                warn!("Error running virtio block device: {}", err);
+            }
+        });
+    }
+}
+
+struct VirtioBlockDevice<D: DiskImage+'static> {
+    vq: VirtQueue,
+    engine: RequestEngine<D>,
+}
+
+impl <D: DiskImage+'static> VirtioBlockDevice<D> {
+    fn new(vq: VirtQueue, disk: D, io_threads: usize, write_through: Arc<AtomicBool>, io_share: Option<IoShare>) -> Self {
+        let bytes = IoBytes::new("block", "the disk image", vq.metric_labels());
+        let engine = RequestEngine::new(disk, io_threads, write_through, io_share, bytes);
+        VirtioBlockDevice { vq, engine }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        loop {
+            let chain = match self.vq.wait_next_chain() {
+                Ok(chain) => chain,
+                // The device was removed
+                Err(virtio::Error::QueueClosed) => return Ok(()),
+                Err(e) => return Err(Error::VirtQueueWait(e)),
+            };
+
+            if chain.remaining_read() < HEADER_SIZE {
+                warn!("virtio_block: request is too short for a header");
+                continue;
+            }
+            match Request::read_header(chain) {
+                Ok(mut request) => if request.has_extra_data() {
+                    warn!("virtio_block: {} bytes of unexpected data after request header", request.chain.remaining_read());
+                    request.write_status(VIRTIO_BLK_S_IOERR);
+                } else if request.msg_type == VIRTIO_BLK_T_FLUSH {
+                    self.engine.flush(request);
+                } else {
+                    self.engine.submit(request);
+                },
+                Err(e) => warn!("Error handling virtio_block message: {}", e),
+            }
+        }
+    }
+}
+
+///
+/// A request read from the queue, which is completed by `process()`.
+///
+struct Request {
+    chain: Chain,
+    msg_type: u32,
+    sector: u64,
+}
+
+impl Request {
+
+    fn read_header(mut chain: Chain) -> Result<Self> {
+        let msg_type = chain.r32()?;
+        let _ = chain.r32()?;
+        let sector = chain.r64()?;
+        Ok(Request { chain, msg_type, sector })
+    }
+
+    // Of the supported requests only a write carries data to the device
+    // after the header
+    fn has_extra_data(&self) -> bool {
+        match self.msg_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_FLUSH | VIRTIO_BLK_T_GET_ID => self.chain.remaining_read() > 0,
+            _ => false,
+        }
+    }
+
+    fn process<D: DiskImage>(mut self, ctx: &RequestContext<D>)  {
+        let r = match self.msg_type {
+            VIRTIO_BLK_T_IN => self.handle_io_in(ctx),
+            VIRTIO_BLK_T_OUT => self.handle_io_out(ctx),
+            VIRTIO_BLK_T_FLUSH => self.handle_io_flush(ctx),
+            VIRTIO_BLK_T_GET_ID => self.handle_get_id(ctx),
+            cmd => {
+                warn!("virtio_block: unexpected command: {}", cmd);
+                self.write_status(VIRTIO_BLK_S_UNSUPP);
+                return;
+            },
+        };
+        self.process_result(r);
+    }
+
+    fn process_result(&mut self, result: Result<()>) {
+        match result {
+            Ok(()) => self.write_status(VIRTIO_BLK_S_OK),
+            Err(e) => {
+                warn!("virtio_block: disk error: {}", e);
+                self.write_status(VIRTIO_BLK_S_IOERR);
+            }
+        }
+    }
+
+    fn handle_io_in<D: DiskImage>(&mut self, ctx: &RequestContext<D>) -> Result<()> {
+        loop {
+            let nsectors = self.chain.current_write_slice().len() >> SECTOR_SHIFT;
+            if nsectors == 0 {
+                return Ok(())
+            }
+            let len = nsectors << SECTOR_SHIFT;
+            ctx.wait_io_share(len);
+            let buffer = &mut self.chain.current_write_slice()[..len];
+
+            ctx.disk.read_sectors(self.sector, buffer)
+                .map_err(Error::DiskRead)?;
+            ctx.bytes.record_read(len);
+            self.chain.inc_write_offset(len);
+            self.sector += nsectors as u64;
+        }
+    }
+
+    fn handle_io_out<D: DiskImage>(&mut self, ctx: &RequestContext<D>) -> Result<()> {
+        loop {
+            let current = self.chain.current_read_slice();
+            if current.len() & (SECTOR_SIZE-1) != 0 {
+                return Err(Error::InvalidReadDescriptor(current.len()));
+            }
+            let nsectors = current.len() >> SECTOR_SHIFT;
+            if nsectors == 0 {
+                return Self::complete_write(ctx);
+            }
+            ctx.wait_io_share(current.len());
+            ctx.disk.write_sectors(self.sector, current)
+                .map_err(Error::DiskWrite)?;
+            ctx.bytes.record_written(current.len());
+
+            self.chain.inc_read_offset(nsectors << SECTOR_SHIFT);
+            self.sector += nsectors as u64;
+        }
+    }
+
+    fn complete_write<D: DiskImage>(ctx: &RequestContext<D>) -> Result<()> {
+        if ctx.is_write_through() {
+            ctx.disk.flush().map_err(Error::WriteThrough)?;
+        }
+        Ok(())
+    }
+
+    fn handle_io_flush<D: DiskImage>(&mut self, ctx: &RequestContext<D>) -> Result<()> {
+        ctx.disk.flush().map_err(Error::DiskFlush)
+    }
+
+    fn handle_get_id<D: DiskImage>(&mut self, ctx: &RequestContext<D>) -> Result<()> {
+        self.chain.write_all(&ctx.disk.disk_image_id())?;
+        Ok(())
+    }
+
+    fn write_status(&mut self, status: u8) {
+        if let Err(e) = self.chain.w8(status) {
+           warn!("Error writing block device status: {}", e);
+        }
+        self.chain.flush_chain();
+    }
+}
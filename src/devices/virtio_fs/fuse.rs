@@ -0,0 +1,266 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::time::Duration;
+
+use crate::devices::virtio_9p::{FileStat, FsStat};
+use crate::util::ByteBuffer;
+
+pub const FUSE_KERNEL_VERSION: u32 = 7;
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+pub const FUSE_ROOT_ID: u64 = 1;
+
+pub const FUSE_LOOKUP: u32 = 1;
+pub const FUSE_FORGET: u32 = 2;
+pub const FUSE_GETATTR: u32 = 3;
+pub const FUSE_SETATTR: u32 = 4;
+pub const FUSE_READLINK: u32 = 5;
+pub const FUSE_SYMLINK: u32 = 6;
+pub const FUSE_MKNOD: u32 = 8;
+pub const FUSE_MKDIR: u32 = 9;
+pub const FUSE_UNLINK: u32 = 10;
+pub const FUSE_RMDIR: u32 = 11;
+pub const FUSE_RENAME: u32 = 12;
+pub const FUSE_LINK: u32 = 13;
+pub const FUSE_OPEN: u32 = 14;
+pub const FUSE_READ: u32 = 15;
+pub const FUSE_WRITE: u32 = 16;
+pub const FUSE_STATFS: u32 = 17;
+pub const FUSE_RELEASE: u32 = 18;
+pub const FUSE_FSYNC: u32 = 20;
+pub const FUSE_FLUSH: u32 = 25;
+pub const FUSE_INIT: u32 = 26;
+pub const FUSE_OPENDIR: u32 = 27;
+pub const FUSE_READDIR: u32 = 28;
+pub const FUSE_RELEASEDIR: u32 = 29;
+pub const FUSE_FSYNCDIR: u32 = 30;
+pub const FUSE_INTERRUPT: u32 = 36;
+pub const FUSE_CREATE: u32 = 35;
+pub const FUSE_DESTROY: u32 = 38;
+pub const FUSE_BATCH_FORGET: u32 = 42;
+pub const FUSE_RENAME2: u32 = 45;
+
+// Flags of FUSE_INIT
+pub const FUSE_ASYNC_READ: u32 = 1 << 0;
+pub const FUSE_BIG_WRITES: u32 = 1 << 5;
+pub const FUSE_AUTO_INVAL_DATA: u32 = 1 << 12;
+
+// Valid fields of FUSE_SETATTR
+pub const FATTR_MODE: u32 = 1 << 0;
+pub const FATTR_UID: u32 = 1 << 1;
+pub const FATTR_GID: u32 = 1 << 2;
+pub const FATTR_SIZE: u32 = 1 << 3;
+pub const FATTR_ATIME: u32 = 1 << 4;
+pub const FATTR_MTIME: u32 = 1 << 5;
+pub const FATTR_ATIME_NOW: u32 = 1 << 7;
+pub const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+pub const FSYNC_FDATASYNC: u32 = 1;
+
+pub const OUT_HEADER_SIZE: usize = 16;
+
+// Size of struct fuse_attr
+const ATTR_SIZE: usize = 88;
+
+// Size of struct fuse_dirent without the name
+const DIRENT_SIZE: usize = 24;
+
+///
+/// The fixed header at the start of every FUSE request.
+///
+pub struct InHeader {
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+}
+
+///
+/// Reads the arguments of a FUSE request, which follow the header in the
+/// readable part of the chain. Reading past the end of the arguments fails
+/// with `EINVAL` instead of panicking.
+///
+pub struct Args {
+    bytes: Vec<u8>,
+    offset: usize,
+}
+
+impl Args {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Args { bytes, offset: 0 }
+    }
+
+    pub fn read_header(&mut self) -> io::Result<InHeader> {
+        let _len = self.r32()?;
+        let opcode = self.r32()?;
+        let unique = self.r64()?;
+        let nodeid = self.r64()?;
+        // uid, gid, pid, padding
+        self.skip(16)?;
+        Ok(InHeader { opcode, unique, nodeid })
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.bytes.len() - self.offset < n {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let start = self.offset;
+        self.offset += n;
+        Ok(&self.bytes[start..self.offset])
+    }
+
+    pub fn skip(&mut self, n: usize) -> io::Result<()> {
+        self.take(n).map(|_| ())
+    }
+
+    pub fn r32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn r64(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn bytes(&mut self, n: usize) -> io::Result<&[u8]> {
+        self.take(n)
+    }
+
+    /// Read a NUL terminated name.
+    pub fn name(&mut self) -> io::Result<&OsStr> {
+        let rest = &self.bytes[self.offset..];
+        let len = rest.iter().position(|&b| b == 0)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let name = self.take(len + 1)?;
+        Ok(OsStr::from_bytes(&name[..len]))
+    }
+}
+
+///
+/// The body of a reply, which is sent after a `fuse_out_header`.
+///
+pub struct Reply(ByteBuffer<Vec<u8>>);
+
+impl Reply {
+    pub fn new() -> Self {
+        Reply(ByteBuffer::new_empty().little_endian())
+    }
+
+    pub fn w16(&mut self, n: u16) -> &mut Self {
+        self.0.write(n);
+        self
+    }
+
+    pub fn w32(&mut self, n: u32) -> &mut Self {
+        self.0.write(n);
+        self
+    }
+
+    pub fn w64(&mut self, n: u64) -> &mut Self {
+        self.0.write(n);
+        self
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.write(bytes);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+
+    // struct fuse_attr
+    pub fn attr(&mut self, stat: &FileStat) -> &mut Self {
+        self.w64(stat.ino)
+            .w64(stat.size)
+            .w64(stat.blocks)
+            .w64(stat.atime.0)
+            .w64(stat.mtime.0)
+            .w64(stat.ctime.0)
+            .w32(stat.atime.1 as u32)
+            .w32(stat.mtime.1 as u32)
+            .w32(stat.ctime.1 as u32)
+            .w32(stat.mode)
+            .w32(stat.nlink as u32)
+            .w32(stat.uid)
+            .w32(stat.gid)
+            .w32(stat.rdev as u32)
+            .w32(stat.blksize as u32)
+            .w32(0)
+    }
+
+    // struct fuse_entry_out
+    pub fn entry(&mut self, nodeid: u64, stat: &FileStat, timeout: Duration) -> &mut Self {
+        self.w64(nodeid)
+            .w64(0)
+            .w64(timeout.as_secs())
+            .w64(timeout.as_secs())
+            .w32(timeout.subsec_nanos())
+            .w32(timeout.subsec_nanos())
+            .attr(stat)
+    }
+
+    // A fuse_entry_out with a nodeid of 0 which tells the guest to cache
+    // that the name does not exist.
+    pub fn negative_entry(&mut self, timeout: Duration) -> &mut Self {
+        self.w64(0)
+            .w64(0)
+            .w64(timeout.as_secs())
+            .w64(0)
+            .w32(timeout.subsec_nanos())
+            .w32(0)
+            .bytes(&[0u8; ATTR_SIZE])
+    }
+
+    // struct fuse_attr_out
+    pub fn attr_out(&mut self, stat: &FileStat, timeout: Duration) -> &mut Self {
+        self.w64(timeout.as_secs())
+            .w32(timeout.subsec_nanos())
+            .w32(0)
+            .attr(stat)
+    }
+
+    // struct fuse_open_out
+    pub fn open_out(&mut self, fh: u64, open_flags: u32) -> &mut Self {
+        self.w64(fh)
+            .w32(open_flags)
+            .w32(0)
+    }
+
+    // struct fuse_kstatfs
+    pub fn statfs(&mut self, st: &FsStat) -> &mut Self {
+        self.w64(st.blocks)
+            .w64(st.bfree)
+            .w64(st.bavail)
+            .w64(st.files)
+            .w64(st.ffree)
+            .w32(st.bsize)
+            .w32(st.namelen)
+            .w32(st.bsize)
+            .w32(0)
+            .bytes(&[0u8; 24])
+    }
+
+    // struct fuse_dirent followed by the name padded to 8 bytes
+    pub fn dirent(&mut self, ino: u64, off: u64, dtype: u8, name: &[u8]) -> &mut Self {
+        let padding = Self::dirent_size(name) - DIRENT_SIZE - name.len();
+        self.w64(ino)
+            .w64(off)
+            .w32(name.len() as u32)
+            .w32(u32::from(dtype))
+            .bytes(name)
+            .bytes(&[0u8; 8][..padding])
+    }
+
+    pub fn dirent_size(name: &[u8]) -> usize {
+        (DIRENT_SIZE + name.len() + 7) & !7
+    }
+}
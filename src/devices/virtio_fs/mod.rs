@@ -0,0 +1,124 @@
+use std::sync::{Arc,Mutex,RwLock};
+use std::thread;
+
+use std::path::{PathBuf, Path};
+use std::time::Duration;
+
+use crate::memory::MemoryManager;
+use crate::disk::IoShare;
+use crate::virtio::{self,VirtioBus,VirtioDeviceOps, VirtQueue, Result};
+use crate::devices::virtio_9p::{FileSystem, FileSystemOps};
+use self::server::Server;
+
+mod fuse;
+mod server;
+
+const VIRTIO_ID_FS: u16 = 26;
+
+// The mount tag is a NUL padded field in the device configuration
+const VIRTIO_FS_TAG_SIZE: usize = 36;
+const VIRTIO_FS_CONFIG_SIZE: usize = VIRTIO_FS_TAG_SIZE + 4;
+
+// How long the guest caches names and attributes before asking again
+const CACHE_TIMEOUT: Duration = Duration::from_secs(1);
+
+///
+/// A virtio-fs device which exports a directory to the guest with the FUSE
+/// protocol, as an alternative to `VirtioP9` for the same filesystem backends.
+///
+/// The guest mounts it with `mount -t virtiofs TAG DIR`. Unlike 9p, the guest
+/// keeps looked up names and attributes in its cache for `CACHE_TIMEOUT`, so
+/// walking a large tree needs far fewer requests.
+///
+/// There is one request queue. The high priority queue carries forget
+/// requests and has its own thread.
+///
+pub struct VirtioFs<T: FileSystemOps> {
+    server: Arc<Mutex<Server<T>>>,
+    config: Vec<u8>,
+}
+
+///
+/// Handle for a running `VirtioFs` device which exports a host directory.
+///
+#[derive(Clone)]
+pub struct VirtioFsShare {
+    server: Arc<Mutex<Server<FileSystem>>>,
+}
+
+impl VirtioFsShare {
+    /// Reads and writes of host files wait for `io_share`.
+    pub fn set_io_share(&self, io_share: IoShare) {
+        self.server.lock().unwrap().set_io_share(io_share)
+    }
+}
+
+impl <T: FileSystemOps+'static> VirtioFs<T> {
+    fn create_config(tag_name: &str) -> Vec<u8> {
+        let mut config = vec![0u8; VIRTIO_FS_CONFIG_SIZE];
+        let tag = tag_name.as_bytes();
+        let len = tag.len().min(VIRTIO_FS_TAG_SIZE);
+        config[..len].copy_from_slice(&tag[..len]);
+        // num_request_queues
+        config[VIRTIO_FS_TAG_SIZE] = 1;
+        config
+    }
+
+    fn create_server(filesystem: T, root_dir: &str, debug: bool) -> Arc<Mutex<Server<T>>> {
+        let mut server = Server::new(Path::new(root_dir), filesystem, CACHE_TIMEOUT);
+        if debug {
+            server.enable_debug();
+        }
+        Arc::new(Mutex::new(server))
+    }
+
+    fn register(vbus: &mut VirtioBus, server: Arc<Mutex<Server<T>>>, tag_name: &str) -> Result<()> {
+        if tag_name.len() > VIRTIO_FS_TAG_SIZE {
+            warn!("virtio-fs tag '{}' is longer than {} bytes and will be truncated", tag_name, VIRTIO_FS_TAG_SIZE);
+        }
+        let dev = Arc::new(RwLock::new(VirtioFs {
+            server,
+            config: VirtioFs::<T>::create_config(tag_name),
+        }));
+        vbus.new_virtio_device(VIRTIO_ID_FS, dev)
+            .set_num_queues(2)
+            .set_config_size(VIRTIO_FS_CONFIG_SIZE)
+            .register()
+    }
+
+    pub fn create_with_filesystem(filesystem: T, vbus: &mut VirtioBus, tag_name: &str, root_dir: &str, debug: bool) -> Result<()> {
+        let server = Self::create_server(filesystem, root_dir, debug);
+        Self::register(vbus, server, tag_name)
+    }
+}
+
+impl VirtioFs<FileSystem> {
+
+    /// Export `root_dir` from the host. Filesystem operations which take longer
+    /// than `timeout` fail in the guest with `EIO`.
+    pub fn create(vbus: &mut VirtioBus, tag_name: &str, root_dir: &str, read_only: bool, timeout: Option<Duration>, debug: bool) -> Result<VirtioFsShare> {
+        let filesystem = FileSystem::new(PathBuf::from(root_dir), read_only, timeout);
+        let server = Self::create_server(filesystem, root_dir, debug);
+        Self::register(vbus, server.clone(), tag_name)?;
+        Ok(VirtioFsShare { server })
+    }
+}
+
+impl <T: FileSystemOps+'static> VirtioDeviceOps for VirtioFs<T> {
+    fn read_config(&mut self, offset: usize, size: usize) -> u64 {
+        virtio::read_config_buffer(&self.config, offset, size)
+    }
+
+    fn start(&mut self, _: &MemoryManager, queues: Vec<VirtQueue>) {
+        for vq in queues {
+            let server = self.server.clone();
+            thread::spawn(move || run_queue(vq, server));
+        }
+    }
+}
+
+fn run_queue<T: FileSystemOps>(vq: VirtQueue, server: Arc<Mutex<Server<T>>>) {
+    vq.on_each_chain(|mut chain| {
+        server.lock().unwrap().handle(&mut chain);
+    });
+}
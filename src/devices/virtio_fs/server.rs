@@ -0,0 +1,584 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use crate::disk::IoShare;
+use crate::util::debug_flags::{self, DebugFlag};
+use crate::devices::virtio_9p::{Directory, FileSystemOps, FsTouch, P9File};
+use crate::devices::virtio_fs::fuse::*;
+use crate::virtio::Chain;
+
+// Largest read or write the guest is told it may send
+const MAX_WRITE: u32 = 128 * 1024;
+
+fn system_error<T>(errno: libc::c_int) -> io::Result<T> {
+    Err(io::Error::from_raw_os_error(errno))
+}
+
+///
+/// Paths of the nodes which the guest has looked up.
+///
+/// FUSE refers to files by a node id which the server hands out in reply to
+/// a lookup. The guest counts lookups of each node and returns them with a
+/// forget once it drops the node from its cache.
+///
+struct Nodes {
+    paths: BTreeMap<u64, (PathBuf, u64)>,
+    ids: HashMap<PathBuf, u64>,
+    next_id: u64,
+}
+
+impl Nodes {
+    fn new(root: &Path) -> Self {
+        let mut nodes = Nodes {
+            paths: BTreeMap::new(),
+            ids: HashMap::new(),
+            next_id: FUSE_ROOT_ID + 1,
+        };
+        nodes.paths.insert(FUSE_ROOT_ID, (root.to_path_buf(), 1));
+        nodes.ids.insert(root.to_path_buf(), FUSE_ROOT_ID);
+        nodes
+    }
+
+    fn path(&self, nodeid: u64) -> io::Result<&Path> {
+        match self.paths.get(&nodeid) {
+            Some((path, _)) => Ok(path),
+            None => system_error(libc::ESTALE),
+        }
+    }
+
+    // Return the node id of `path` and count one more lookup of it
+    fn lookup(&mut self, path: &Path) -> u64 {
+        if let Some(&nodeid) = self.ids.get(path) {
+            if let Some(node) = self.paths.get_mut(&nodeid) {
+                node.1 += 1;
+            }
+            return nodeid;
+        }
+        let nodeid = self.next_id;
+        self.next_id += 1;
+        self.paths.insert(nodeid, (path.to_path_buf(), 1));
+        self.ids.insert(path.to_path_buf(), nodeid);
+        nodeid
+    }
+
+    fn forget(&mut self, nodeid: u64, nlookup: u64) {
+        if nodeid == FUSE_ROOT_ID {
+            return;
+        }
+        let forgotten = match self.paths.get_mut(&nodeid) {
+            Some(node) => {
+                node.1 = node.1.saturating_sub(nlookup);
+                node.1 == 0
+            }
+            None => false,
+        };
+        if forgotten {
+            if let Some((path, _)) = self.paths.remove(&nodeid) {
+                self.ids.remove(&path);
+            }
+        }
+    }
+
+    // Move `from` and every node below it to `to`
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let mut moved = Vec::new();
+        for (&nodeid, node) in self.paths.iter_mut() {
+            let new_path = match node.0.strip_prefix(from) {
+                Ok(rest) if rest.as_os_str().is_empty() => to.to_path_buf(),
+                Ok(rest) => to.join(rest),
+                Err(_) => continue,
+            };
+            let old_path = mem::replace(&mut node.0, new_path.clone());
+            moved.push((nodeid, old_path, new_path));
+        }
+        for (_, old_path, _) in &moved {
+            self.ids.remove(old_path);
+        }
+        for (nodeid, _, new_path) in moved {
+            // A node which was replaced by the rename is now unreachable
+            if let Some(old_id) = self.ids.insert(new_path, nodeid) {
+                self.paths.remove(&old_id);
+            }
+        }
+    }
+}
+
+enum Handle {
+    File(P9File),
+    Dir(Directory),
+}
+
+///
+/// Serves FUSE requests from the guest on a `FileSystemOps` backend.
+///
+pub struct Server<T: FileSystemOps> {
+    root: PathBuf,
+    debug: bool,
+    filesystem: T,
+    nodes: Nodes,
+    handles: BTreeMap<u64, Handle>,
+    next_fh: u64,
+    timeout: Duration,
+    io_share: Option<IoShare>,
+}
+
+impl <T: FileSystemOps> Server<T> {
+
+    /// Attributes and names which the guest looks up are cached in the guest
+    /// for `timeout`.
+    pub fn new(root: &Path, filesystem: T, timeout: Duration) -> Self {
+        Server {
+            root: root.to_path_buf(),
+            debug: false,
+            filesystem,
+            nodes: Nodes::new(root),
+            handles: BTreeMap::new(),
+            next_fh: 1,
+            timeout,
+            io_share: None,
+        }
+    }
+
+    pub fn enable_debug(&mut self) {
+        self.debug = true;
+    }
+
+    fn is_debug(&self) -> bool {
+        self.debug || debug_flags::is_enabled(DebugFlag::VirtioFs)
+    }
+
+    pub fn set_io_share(&mut self, io_share: IoShare) {
+        self.io_share = Some(io_share);
+    }
+
+    fn wait_io_share(&self, len: usize) {
+        if let Some(ref io_share) = self.io_share {
+            io_share.acquire(len);
+        }
+    }
+
+    pub fn handle(&mut self, chain: &mut Chain) {
+        let mut bytes = vec![0u8; chain.remaining_read()];
+        if let Err(err) = chain.read_exact(&mut bytes) {
+            warn!("Error reading FUSE request: {}", err);
+            return;
+        }
+        let mut args = Args::new(bytes);
+        let header = match args.read_header() {
+            Ok(header) => header,
+            Err(err) => {
+                warn!("Error reading FUSE request header: {}", err);
+                return;
+            }
+        };
+        match self.dispatch(&header, &mut args) {
+            Ok(Some(reply)) => Self::send(chain, header.unique, 0, reply.as_bytes()),
+            Ok(None) => {},
+            Err(err) => {
+                if self.is_debug() {
+                    notify!("error handling FUSE opcode {}: {}", header.opcode, err);
+                }
+                let errno = err.raw_os_error().unwrap_or(libc::EIO);
+                Self::send(chain, header.unique, -errno, &[]);
+            }
+        }
+    }
+
+    // Write a fuse_out_header followed by `body`
+    fn send(chain: &mut Chain, unique: u64, error: i32, body: &[u8]) {
+        let mut header = Reply::new();
+        header.w32((OUT_HEADER_SIZE + body.len()) as u32)
+            .w32(error as u32)
+            .w64(unique);
+        if let Err(err) = chain.write_all(header.as_bytes()).and_then(|_| chain.write_all(body)) {
+            warn!("Error writing FUSE reply: {}", err);
+        }
+    }
+
+    // Requests which are answered with no reply return `None`
+    fn dispatch(&mut self, header: &InHeader, args: &mut Args) -> io::Result<Option<Reply>> {
+        let nodeid = header.nodeid;
+        let reply = match header.opcode {
+            FUSE_INIT => self.fuse_init(args)?,
+            FUSE_LOOKUP => self.fuse_lookup(nodeid, args)?,
+            FUSE_FORGET => {
+                self.fuse_forget(nodeid, args)?;
+                return Ok(None);
+            },
+            FUSE_BATCH_FORGET => {
+                self.fuse_batch_forget(args)?;
+                return Ok(None);
+            },
+            FUSE_INTERRUPT => return Ok(None),
+            FUSE_GETATTR => self.fuse_getattr(nodeid)?,
+            FUSE_SETATTR => self.fuse_setattr(nodeid, args)?,
+            FUSE_READLINK => self.fuse_readlink(nodeid)?,
+            FUSE_SYMLINK => self.fuse_symlink(nodeid, args)?,
+            // Device nodes cannot be created on a share, as with 9p
+            FUSE_MKNOD => return system_error(libc::EACCES),
+            FUSE_MKDIR => self.fuse_mkdir(nodeid, args)?,
+            FUSE_UNLINK => self.fuse_unlink(nodeid, args)?,
+            FUSE_RMDIR => self.fuse_rmdir(nodeid, args)?,
+            FUSE_RENAME => self.fuse_rename(nodeid, args, false)?,
+            FUSE_RENAME2 => self.fuse_rename(nodeid, args, true)?,
+            FUSE_LINK => self.fuse_link(nodeid, args)?,
+            FUSE_OPEN => self.fuse_open(nodeid, args)?,
+            FUSE_READ => self.fuse_read(args)?,
+            FUSE_WRITE => self.fuse_write(args)?,
+            FUSE_STATFS => self.fuse_statfs(nodeid)?,
+            FUSE_RELEASE | FUSE_RELEASEDIR => self.fuse_release(args)?,
+            FUSE_FSYNC => self.fuse_fsync(args)?,
+            FUSE_FLUSH | FUSE_FSYNCDIR => Reply::new(),
+            FUSE_OPENDIR => self.fuse_opendir(nodeid)?,
+            FUSE_READDIR => self.fuse_readdir(args)?,
+            FUSE_CREATE => self.fuse_create(nodeid, args)?,
+            FUSE_DESTROY => self.fuse_destroy(),
+            opcode => {
+                if self.is_debug() {
+                    notify!("unhandled FUSE opcode {}", opcode);
+                }
+                return system_error(libc::ENOSYS);
+            }
+        };
+        Ok(Some(reply))
+    }
+
+    fn node_path(&self, nodeid: u64) -> io::Result<PathBuf> {
+        self.nodes.path(nodeid).map(|p| p.to_path_buf())
+    }
+
+    // Join a name sent by the guest to the path of the directory `parent`
+    fn child_path(&self, parent: u64, name: &OsStr) -> io::Result<PathBuf> {
+        let name = Path::new(name);
+        let mut components = name.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => {},
+            _ => return system_error(libc::EINVAL),
+        }
+        Ok(self.nodes.path(parent)?.join(name))
+    }
+
+    fn new_handle(&mut self, handle: Handle) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, handle);
+        fh
+    }
+
+    fn file(&self, fh: u64) -> io::Result<&P9File> {
+        match self.handles.get(&fh) {
+            Some(Handle::File(file)) => Ok(file),
+            _ => system_error(libc::EBADF),
+        }
+    }
+
+    // Look up `path` and reply with a fuse_entry_out
+    fn entry_reply(&mut self, path: &Path) -> io::Result<Reply> {
+        let stat = self.filesystem.stat(path)?;
+        let nodeid = self.nodes.lookup(path);
+        let mut reply = Reply::new();
+        reply.entry(nodeid, &stat, self.timeout);
+        Ok(reply)
+    }
+
+    fn fuse_init(&mut self, args: &mut Args) -> io::Result<Reply> {
+        let major = args.r32()?;
+        let minor = args.r32()?;
+        let max_readahead = args.r32()?;
+        let flags = args.r32()?;
+        if major < FUSE_KERNEL_VERSION {
+            warn!("Unsupported FUSE version {}.{}", major, minor);
+            return system_error(libc::EPROTO);
+        }
+        let mut reply = Reply::new();
+        reply.w32(FUSE_KERNEL_VERSION)
+            .w32(minor.min(FUSE_KERNEL_MINOR_VERSION))
+            .w32(max_readahead)
+            .w32(flags & (FUSE_ASYNC_READ | FUSE_BIG_WRITES | FUSE_AUTO_INVAL_DATA))
+            // max_background, congestion_threshold
+            .w16(16)
+            .w16(12)
+            .w32(MAX_WRITE)
+            // time_gran
+            .w32(1)
+            // max_pages, padding, unused
+            .w16(0)
+            .w16(0)
+            .bytes(&[0u8; 32]);
+        Ok(reply)
+    }
+
+    fn fuse_lookup(&mut self, parent: u64, args: &mut Args) -> io::Result<Reply> {
+        let path = self.child_path(parent, args.name()?)?;
+        match self.entry_reply(&path) {
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => {
+                let mut reply = Reply::new();
+                reply.negative_entry(self.timeout);
+                Ok(reply)
+            },
+            result => result,
+        }
+    }
+
+    fn fuse_forget(&mut self, nodeid: u64, args: &mut Args) -> io::Result<()> {
+        let nlookup = args.r64()?;
+        self.nodes.forget(nodeid, nlookup);
+        Ok(())
+    }
+
+    fn fuse_batch_forget(&mut self, args: &mut Args) -> io::Result<()> {
+        let count = args.r32()?;
+        let _dummy = args.r32()?;
+        for _ in 0..count {
+            let nodeid = args.r64()?;
+            let nlookup = args.r64()?;
+            self.nodes.forget(nodeid, nlookup);
+        }
+        Ok(())
+    }
+
+    fn fuse_getattr(&mut self, nodeid: u64) -> io::Result<Reply> {
+        let stat = self.filesystem.stat(self.nodes.path(nodeid)?)?;
+        let mut reply = Reply::new();
+        reply.attr_out(&stat, self.timeout);
+        Ok(reply)
+    }
+
+    fn fuse_setattr(&mut self, nodeid: u64, args: &mut Args) -> io::Result<Reply> {
+        let valid = args.r32()?;
+        let _padding = args.r32()?;
+        let _fh = args.r64()?;
+        let size = args.r64()?;
+        let _lock_owner = args.r64()?;
+        let atime = args.r64()?;
+        let mtime = args.r64()?;
+        let _ctime = args.r64()?;
+        let atimensec = args.r32()?;
+        let mtimensec = args.r32()?;
+        let _ctimensec = args.r32()?;
+        let mode = args.r32()?;
+        let _unused = args.r32()?;
+        let uid = args.r32()?;
+        let gid = args.r32()?;
+
+        let path = self.node_path(nodeid)?;
+        if valid & FATTR_MODE != 0 {
+            self.filesystem.set_mode(&path, mode & 0o7777)?;
+        }
+        if valid & (FATTR_UID | FATTR_GID) != 0 {
+            let uid = if valid & FATTR_UID != 0 { uid } else { u32::max_value() };
+            let gid = if valid & FATTR_GID != 0 { gid } else { u32::max_value() };
+            self.filesystem.chown(&path, uid, gid)?;
+        }
+        if valid & FATTR_SIZE != 0 {
+            self.filesystem.truncate(&path, size)?;
+        }
+        if valid & FATTR_ATIME_NOW != 0 {
+            self.filesystem.touch(&path, FsTouch::AtimeNow, (0, 0))?;
+        } else if valid & FATTR_ATIME != 0 {
+            self.filesystem.touch(&path, FsTouch::Atime, (atime, u64::from(atimensec)))?;
+        }
+        if valid & FATTR_MTIME_NOW != 0 {
+            self.filesystem.touch(&path, FsTouch::MtimeNow, (0, 0))?;
+        } else if valid & FATTR_MTIME != 0 {
+            self.filesystem.touch(&path, FsTouch::Mtime, (mtime, u64::from(mtimensec)))?;
+        }
+        self.fuse_getattr(nodeid)
+    }
+
+    fn fuse_readlink(&mut self, nodeid: u64) -> io::Result<Reply> {
+        let target = self.filesystem.readlink(self.nodes.path(nodeid)?)?;
+        let mut reply = Reply::new();
+        reply.bytes(target.as_bytes());
+        Ok(reply)
+    }
+
+    fn fuse_symlink(&mut self, parent: u64, args: &mut Args) -> io::Result<Reply> {
+        let path = self.child_path(parent, args.name()?)?;
+        let target = PathBuf::from(args.name()?);
+        self.filesystem.symlink(&target, &path)?;
+        self.entry_reply(&path)
+    }
+
+    fn fuse_mkdir(&mut self, parent: u64, args: &mut Args) -> io::Result<Reply> {
+        let mode = args.r32()?;
+        let umask = args.r32()?;
+        let path = self.child_path(parent, args.name()?)?;
+        self.filesystem.create_dir(&path, mode & !umask)?;
+        self.entry_reply(&path)
+    }
+
+    fn fuse_unlink(&mut self, parent: u64, args: &mut Args) -> io::Result<Reply> {
+        let path = self.child_path(parent, args.name()?)?;
+        self.filesystem.remove_file(&path)?;
+        Ok(Reply::new())
+    }
+
+    fn fuse_rmdir(&mut self, parent: u64, args: &mut Args) -> io::Result<Reply> {
+        let path = self.child_path(parent, args.name()?)?;
+        self.filesystem.remove_dir(&path)?;
+        Ok(Reply::new())
+    }
+
+    fn fuse_rename(&mut self, parent: u64, args: &mut Args, rename2: bool) -> io::Result<Reply> {
+        let newdir = args.r64()?;
+        if rename2 {
+            let flags = args.r32()?;
+            let _padding = args.r32()?;
+            // RENAME_NOREPLACE and RENAME_EXCHANGE are not supported
+            if flags != 0 {
+                return system_error(libc::EINVAL);
+            }
+        }
+        let from = self.child_path(parent, args.name()?)?;
+        let to = self.child_path(newdir, args.name()?)?;
+        self.filesystem.rename(&from, &to)?;
+        self.nodes.rename(&from, &to);
+        Ok(Reply::new())
+    }
+
+    fn fuse_link(&mut self, parent: u64, args: &mut Args) -> io::Result<Reply> {
+        let oldnodeid = args.r64()?;
+        let target = self.node_path(oldnodeid)?;
+        let path = self.child_path(parent, args.name()?)?;
+        self.filesystem.link(&target, &path)?;
+        self.entry_reply(&path)
+    }
+
+    fn fuse_open(&mut self, nodeid: u64, args: &mut Args) -> io::Result<Reply> {
+        let flags = args.r32()?;
+        // FUSE open flags are the same as 9p dotl open flags
+        let file = self.filesystem.open(self.nodes.path(nodeid)?, flags)?;
+        let fh = self.new_handle(Handle::File(file));
+        let mut reply = Reply::new();
+        reply.open_out(fh, 0);
+        Ok(reply)
+    }
+
+    fn fuse_read(&mut self, args: &mut Args) -> io::Result<Reply> {
+        let fh = args.r64()?;
+        let offset = args.r64()?;
+        let size = args.r32()?.min(MAX_WRITE) as usize;
+
+        let mut buffer = vec![0u8; size];
+        let mut nread = 0;
+        {
+            let file = self.file(fh)?;
+            while nread < size {
+                match file.read_at(&mut buffer[nread..], offset + nread as u64)? {
+                    0 => break,
+                    n => nread += n,
+                }
+            }
+        }
+        self.wait_io_share(nread);
+        let mut reply = Reply::new();
+        reply.bytes(&buffer[..nread]);
+        Ok(reply)
+    }
+
+    fn fuse_write(&mut self, args: &mut Args) -> io::Result<Reply> {
+        let fh = args.r64()?;
+        let offset = args.r64()?;
+        let size = args.r32()? as usize;
+        let _write_flags = args.r32()?;
+        let _lock_owner = args.r64()?;
+        let _flags = args.r32()?;
+        let _padding = args.r32()?;
+        let data = args.bytes(size)?;
+
+        let mut nwritten = 0;
+        {
+            let file = self.file(fh)?;
+            while nwritten < size {
+                match file.write_at(&data[nwritten..], offset + nwritten as u64)? {
+                    0 => break,
+                    n => nwritten += n,
+                }
+            }
+        }
+        self.wait_io_share(nwritten);
+        let mut reply = Reply::new();
+        reply.w32(nwritten as u32).w32(0);
+        Ok(reply)
+    }
+
+    fn fuse_statfs(&mut self, nodeid: u64) -> io::Result<Reply> {
+        let statfs = self.filesystem.statfs(self.nodes.path(nodeid)?)?;
+        let mut reply = Reply::new();
+        reply.statfs(&statfs);
+        Ok(reply)
+    }
+
+    fn fuse_release(&mut self, args: &mut Args) -> io::Result<Reply> {
+        let fh = args.r64()?;
+        match self.handles.remove(&fh) {
+            Some(_) => Ok(Reply::new()),
+            None => system_error(libc::EBADF),
+        }
+    }
+
+    fn fuse_fsync(&mut self, args: &mut Args) -> io::Result<Reply> {
+        let fh = args.r64()?;
+        let fsync_flags = args.r32()?;
+        let file = self.file(fh)?;
+        if fsync_flags & FSYNC_FDATASYNC != 0 {
+            file.sync_data()?;
+        } else {
+            file.sync_all()?;
+        }
+        Ok(Reply::new())
+    }
+
+    fn fuse_opendir(&mut self, nodeid: u64) -> io::Result<Reply> {
+        let directory = self.filesystem.readdir_populate(self.nodes.path(nodeid)?)?;
+        let fh = self.new_handle(Handle::Dir(directory));
+        let mut reply = Reply::new();
+        reply.open_out(fh, 0);
+        Ok(reply)
+    }
+
+    fn fuse_readdir(&mut self, args: &mut Args) -> io::Result<Reply> {
+        let fh = args.r64()?;
+        let offset = args.r64()?;
+        let size = args.r32()? as usize;
+        let directory = match self.handles.get(&fh) {
+            Some(Handle::Dir(directory)) => directory,
+            _ => return system_error(libc::EBADF),
+        };
+        let mut reply = Reply::new();
+        for entry in directory.entries_after(offset) {
+            let name = entry.name().as_bytes();
+            if reply.len() + Reply::dirent_size(name) > size {
+                break;
+            }
+            reply.dirent(entry.ino(), entry.offset(), entry.dtype(), name);
+        }
+        Ok(reply)
+    }
+
+    fn fuse_create(&mut self, parent: u64, args: &mut Args) -> io::Result<Reply> {
+        let flags = args.r32()?;
+        let mode = args.r32()?;
+        let umask = args.r32()?;
+        let _padding = args.r32()?;
+        let path = self.child_path(parent, args.name()?)?;
+        let file = self.filesystem.create(&path, flags, mode & !umask)?;
+        let mut reply = self.entry_reply(&path)?;
+        let fh = self.new_handle(Handle::File(file));
+        reply.open_out(fh, 0);
+        Ok(reply)
+    }
+
+    // The guest unmounted the filesystem
+    fn fuse_destroy(&mut self) -> Reply {
+        self.handles.clear();
+        self.nodes = Nodes::new(&self.root);
+        Reply::new()
+    }
+}
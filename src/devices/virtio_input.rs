@@ -0,0 +1,291 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use crate::memory::MemoryManager;
+use crate::virtio::{self, DeviceConfigArea, VirtioBus, VirtioDeviceOps, VirtQueue, Result};
+use crate::util::JsonValue;
+
+const VIRTIO_ID_INPUT: u16 = 18;
+
+// Values of the select field of the configuration. The devices have no
+// serial number, input properties or absolute axes, so the other values
+// select an empty union.
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+
+// select, subsel, size and 5 reserved bytes, followed by a 128 byte union
+const VIRTIO_INPUT_CONFIG_SIZE: usize = 136;
+const VIRTIO_INPUT_CONFIG_DATA: usize = 8;
+const VIRTIO_INPUT_CONFIG_DATA_SIZE: usize = 128;
+
+const BUS_VIRTUAL: u16 = 0x06;
+
+// Event types and codes from linux/input-event-codes.h
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_LED: u16 = 0x11;
+const SYN_REPORT: u16 = 0;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_WHEEL: u16 = 0x08;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const KEY_ESC: u16 = 1;
+const KEY_MICMUTE: u16 = 248;
+const LED_NUML: u16 = 0;
+const LED_SCROLLL: u16 = 2;
+
+// Events queued while the driver has no buffers on the event queue
+const MAX_PENDING_EVENTS: usize = 1024;
+
+///
+/// An evdev event as it is passed to the guest driver.
+///
+#[derive(Copy,Clone,Debug)]
+pub struct InputEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+impl InputEvent {
+    pub fn new(event_type: u16, code: u16, value: u32) -> Self {
+        InputEvent { event_type, code, value }
+    }
+
+    fn syn() -> Self {
+        InputEvent::new(EV_SYN, SYN_REPORT, 0)
+    }
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum InputKind {
+    Keyboard,
+    Mouse,
+}
+
+impl InputKind {
+    fn name(self) -> &'static str {
+        match self {
+            InputKind::Keyboard => "pH virtio keyboard",
+            InputKind::Mouse => "pH virtio mouse",
+        }
+    }
+
+    fn product_id(self) -> u16 {
+        match self {
+            InputKind::Keyboard => 1,
+            InputKind::Mouse => 2,
+        }
+    }
+
+    // The event codes of each event type which the device reports
+    fn event_codes(self, event_type: u16) -> Vec<u16> {
+        match (self, event_type) {
+            (InputKind::Keyboard, EV_KEY) => (KEY_ESC..=KEY_MICMUTE).collect(),
+            (InputKind::Keyboard, EV_LED) => (LED_NUML..=LED_SCROLLL).collect(),
+            (InputKind::Mouse, EV_KEY) => vec![BTN_LEFT, BTN_RIGHT, BTN_MIDDLE],
+            (InputKind::Mouse, EV_REL) => vec![REL_X, REL_Y, REL_WHEEL],
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn bitmap(codes: &[u16]) -> Vec<u8> {
+    let mut bits = Vec::new();
+    for &code in codes {
+        let byte = (code / 8) as usize;
+        if bits.len() <= byte {
+            bits.resize(byte + 1, 0);
+        }
+        bits[byte] |= 1 << (code % 8);
+    }
+    bits
+}
+
+#[derive(Default)]
+struct InputState {
+    pending: VecDeque<InputEvent>,
+    dropped: u64,
+    delivered: u64,
+    eventq: Option<VirtQueue>,
+}
+
+impl InputState {
+    // Write pending events into the buffers the driver has placed on the
+    // event queue. Events wait for more buffers if it runs out.
+    fn flush(&mut self) {
+        let vq = match self.eventq {
+            Some(ref vq) => vq.clone(),
+            None => return,
+        };
+        while let Some(&event) = self.pending.front() {
+            let mut chain = match vq.next_chain() {
+                Some(chain) => chain,
+                None => return,
+            };
+            let written = chain.w16(event.event_type)
+                .and_then(|_| chain.w16(event.code))
+                .and_then(|_| chain.w32(event.value));
+            if let Err(e) = written {
+                warn!("virtio-input: failed to write event: {}", e);
+            }
+            self.pending.pop_front();
+            self.delivered += 1;
+        }
+    }
+}
+
+///
+/// A handle for sending input events to a keyboard or mouse device while the
+/// guest runs.
+///
+#[derive(Clone)]
+pub struct InputHandle {
+    kind: InputKind,
+    state: Arc<Mutex<InputState>>,
+}
+
+impl InputHandle {
+    /// Send `events` to the guest followed by a `SYN_REPORT`. If the driver
+    /// is not running or falls behind, events are queued up to a limit and
+    /// later events are dropped.
+    pub fn send_events(&self, events: &[InputEvent]) {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.len() + events.len() + 1 > MAX_PENDING_EVENTS {
+            state.dropped += events.len() as u64 + 1;
+            return;
+        }
+        state.pending.extend(events);
+        state.pending.push_back(InputEvent::syn());
+        state.flush();
+    }
+
+    /// Press or release the key or button `code`.
+    pub fn key(&self, code: u16, pressed: bool) {
+        self.send_events(&[InputEvent::new(EV_KEY, code, pressed as u32)]);
+    }
+
+    /// Move the pointer by `dx` and `dy`.
+    pub fn move_pointer(&self, dx: i32, dy: i32) {
+        self.send_events(&[
+            InputEvent::new(EV_REL, REL_X, dx as u32),
+            InputEvent::new(EV_REL, REL_Y, dy as u32),
+        ]);
+    }
+
+    /// Scroll the wheel by `clicks`, away from the user if positive.
+    pub fn scroll(&self, clicks: i32) {
+        self.send_events(&[InputEvent::new(EV_REL, REL_WHEEL, clicks as u32)]);
+    }
+
+    pub fn describe(&self) -> JsonValue {
+        let state = self.state.lock().unwrap();
+        JsonValue::object()
+            .with("device", self.kind.name())
+            .with("driver-active", state.eventq.is_some())
+            .with("pending", state.pending.len())
+            .with("delivered", state.delivered)
+            .with("dropped", state.dropped)
+    }
+}
+
+///
+/// A virtio-input keyboard or mouse which receives events from the host
+/// through an `InputHandle`.
+///
+/// The driver places empty buffers on the event queue and each event is
+/// written into one of them. LED changes which the driver sends on the
+/// status queue are discarded.
+///
+pub struct VirtioInput {
+    kind: InputKind,
+    // Holds select and subsel, the only fields written by the driver
+    config: DeviceConfigArea,
+    state: Arc<Mutex<InputState>>,
+}
+
+impl VirtioInput {
+    /// Add a keyboard and a mouse device.
+    pub fn create(vbus: &mut VirtioBus) -> Result<(InputHandle, InputHandle)> {
+        let keyboard = Self::create_device(vbus, InputKind::Keyboard)?;
+        let mouse = Self::create_device(vbus, InputKind::Mouse)?;
+        Ok((keyboard, mouse))
+    }
+
+    fn create_device(vbus: &mut VirtioBus, kind: InputKind) -> Result<InputHandle> {
+        let state = Arc::new(Mutex::new(InputState::default()));
+        let mut config = DeviceConfigArea::new(VIRTIO_INPUT_CONFIG_SIZE);
+        config.set_writeable(0, 2);
+        let dev = Arc::new(RwLock::new(VirtioInput { kind, config, state: state.clone() }));
+        vbus.new_virtio_device(VIRTIO_ID_INPUT, dev)
+            .set_num_queues(2)
+            .set_config_size(VIRTIO_INPUT_CONFIG_SIZE)
+            .register()?;
+        Ok(InputHandle { kind, state })
+    }
+
+    // The contents of the union selected by select and subsel
+    fn config_data(&self) -> Vec<u8> {
+        let mut data = match self.config.read_u8(0) {
+            VIRTIO_INPUT_CFG_ID_NAME => self.kind.name().as_bytes().to_vec(),
+            VIRTIO_INPUT_CFG_ID_DEVIDS => {
+                let mut ids = Vec::new();
+                for &id in &[BUS_VIRTUAL, 0, self.kind.product_id(), 1] {
+                    ids.extend_from_slice(&id.to_le_bytes());
+                }
+                ids
+            }
+            VIRTIO_INPUT_CFG_EV_BITS => bitmap(&self.kind.event_codes(u16::from(self.config.read_u8(1)))),
+            _ => Vec::new(),
+        };
+        data.truncate(VIRTIO_INPUT_CONFIG_DATA_SIZE);
+        data
+    }
+
+    fn config_bytes(&self) -> [u8; VIRTIO_INPUT_CONFIG_SIZE] {
+        let mut bytes = [0u8; VIRTIO_INPUT_CONFIG_SIZE];
+        let data = self.config_data();
+        bytes[0] = self.config.read_u8(0);
+        bytes[1] = self.config.read_u8(1);
+        bytes[2] = data.len() as u8;
+        bytes[VIRTIO_INPUT_CONFIG_DATA..VIRTIO_INPUT_CONFIG_DATA + data.len()].copy_from_slice(&data);
+        bytes
+    }
+}
+
+impl VirtioDeviceOps for VirtioInput {
+    fn reset(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.eventq = None;
+        state.pending.clear();
+    }
+
+    fn write_config(&mut self, offset: usize, size: usize, val: u64) {
+        self.config.write_config(offset, size, val);
+    }
+
+    fn read_config(&mut self, offset: usize, size: usize) -> u64 {
+        virtio::read_config_buffer(&self.config_bytes(), offset, size)
+    }
+
+    fn start(&mut self, _memory: &MemoryManager, mut queues: Vec<VirtQueue>) {
+        let statusq = queues.pop().unwrap();
+        let eventq = queues.pop().unwrap();
+        self.state.lock().unwrap().eventq = Some(eventq.clone());
+
+        let state = self.state.clone();
+        thread::spawn(move || {
+            // The driver notifies the queue when it adds buffers, which
+            // events waiting for a buffer are then written into.
+            while eventq.ioevent().read().is_ok() {
+                state.lock().unwrap().flush();
+            }
+        });
+        thread::spawn(move || statusq.on_each_chain(|_| ()));
+    }
+}
@@ -1,16 +1,29 @@
-use crate::virtio::{VirtioDeviceOps, VirtQueue, VirtioBus, Chain};
+use crate::virtio::{VirtioDeviceOps, VirtQueue, VirtioBus, Chain, DeviceConfigArea};
 use crate::memory::MemoryManager;
 use crate::{system, virtio};
 use std::sync::{RwLock, Arc};
-use std::{fmt, result, thread, io};
-use crate::system::{EPoll,Event};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, fs, process, result, thread, io};
+use crate::system::{EPoll,Event,seccomp,SeccompProfile};
 use std::io::{Read, Write};
 use std::os::unix::io::AsRawFd;
 use crate::system::Tap;
+use crate::util::Drbg;
+use crate::devices::vhost_net::VhostNet;
 
 const VIRTIO_ID_NET: u16 = 1;
 const MAC_ADDR_LEN: usize = 6;
 
+// mac, status and max_virtqueue_pairs precede the mtu field
+const CONFIG_MTU_OFFSET: usize = 10;
+const CONFIG_SIZE_WITH_MTU: usize = 12;
+
+/// The smallest MTU which can be set for the guest interface.
+pub const MIN_MTU: u16 = 68;
+
+// Output stream of the seeded generator used for MAC addresses
+const DRBG_STREAM: u64 = 2;
+
 #[derive(Debug)]
 pub enum Error {
     ChainWrite(io::Error),
@@ -41,6 +54,8 @@ type Result<T> = result::Result<T, Error>;
 
 const VIRTIO_NET_F_CSUM: u64 = 1;
 const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+const VIRTIO_NET_F_MTU: u64 = 1 << 3;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
 const VIRTIO_NET_F_GUEST_TSO4: u64 = 1 << 7;
 const VIRTIO_NET_F_GUEST_TSO6: u64 = 1 << 8;
 const VIRTIO_NET_F_GUEST_ECN : u64 = 1 << 9;
@@ -49,40 +64,273 @@ const VIRTIO_NET_F_HOST_TSO6: u64 = 1 << 12;
 const VIRTIO_NET_F_HOST_ECN: u64 = 1 << 13;
 
 const VIRTIO_NET_HDR_SIZE: i32 = 12;
+// Header size used by legacy drivers which do not negotiate VIRTIO_NET_F_MRG_RXBUF
+const VIRTIO_NET_LEGACY_HDR_SIZE: i32 = 10;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+// Offloads to try enabling on the tap device, from most to least capable
+const TAP_OFFLOAD_FALLBACKS: &[u32] = &[
+    TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_TSO_ECN,
+    TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6,
+    TUN_F_CSUM,
+    0,
+];
+
+///
+/// The header which precedes each frame on the virtqueues and on the tap
+/// device, describing a checksum left for the receiver to complete and how
+/// a large TCP segment is to be split.
+///
+struct VnetHeader {
+    flags: u8,
+    gso_type: u8,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VnetHeader {
+    fn parse(frame: &[u8]) -> Option<VnetHeader> {
+        if frame.len() < VIRTIO_NET_LEGACY_HDR_SIZE as usize {
+            return None;
+        }
+        let u16_at = |off: usize| u16::from_le_bytes([frame[off], frame[off + 1]]);
+        Some(VnetHeader {
+            flags: frame[0],
+            gso_type: frame[1],
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        })
+    }
+
+    /// Check a header sent by the guest against the offloads it negotiated,
+    /// where `packet_len` is the length of the frame after the header.
+    fn check_tx(&self, features: u64, packet_len: usize) -> result::Result<(), &'static str> {
+        if self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+            if features & VIRTIO_NET_F_CSUM == 0 {
+                return Err("partial checksum without VIRTIO_NET_F_CSUM");
+            }
+            if self.csum_start as usize + self.csum_offset as usize + 2 > packet_len {
+                return Err("checksum offset outside of frame");
+            }
+        }
+        let required = match self.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+            VIRTIO_NET_HDR_GSO_NONE => return Ok(()),
+            VIRTIO_NET_HDR_GSO_TCPV4 => VIRTIO_NET_F_HOST_TSO4,
+            VIRTIO_NET_HDR_GSO_TCPV6 => VIRTIO_NET_F_HOST_TSO6,
+            _ => return Err("unsupported segmentation offload type"),
+        };
+        if features & required == 0 {
+            return Err("segmentation offload which was not negotiated");
+        }
+        if self.gso_type & VIRTIO_NET_HDR_GSO_ECN != 0 && features & VIRTIO_NET_F_HOST_ECN == 0 {
+            return Err("ECN segmentation offload which was not negotiated");
+        }
+        if self.gso_size == 0 {
+            return Err("segmentation offload with a segment size of 0");
+        }
+        Ok(())
+    }
+}
+
+// Offloads of frames sent from the tap device to the guest which the guest
+// is able to handle with `features`.
+fn tap_offloads(features: u64) -> u32 {
+    let mut offloads = 0;
+    if features & VIRTIO_NET_F_GUEST_CSUM != 0 {
+        offloads |= TUN_F_CSUM;
+        if features & VIRTIO_NET_F_GUEST_TSO4 != 0 {
+            offloads |= TUN_F_TSO4;
+        }
+        if features & VIRTIO_NET_F_GUEST_TSO6 != 0 {
+            offloads |= TUN_F_TSO6;
+        }
+        if features & VIRTIO_NET_F_GUEST_ECN != 0 && offloads & (TUN_F_TSO4 | TUN_F_TSO6) != 0 {
+            offloads |= TUN_F_TSO_ECN;
+        }
+    }
+    offloads
+}
+
+// Guest receive features which can be offered when the tap device supports `offloads`.
+fn guest_offload_features(offloads: u32) -> u64 {
+    let mut features = 0;
+    if offloads & TUN_F_CSUM != 0 {
+        features |= VIRTIO_NET_F_GUEST_CSUM;
+    }
+    if offloads & TUN_F_TSO4 != 0 {
+        features |= VIRTIO_NET_F_GUEST_TSO4;
+    }
+    if offloads & TUN_F_TSO6 != 0 {
+        features |= VIRTIO_NET_F_GUEST_TSO6;
+    }
+    if offloads & TUN_F_TSO_ECN != 0 {
+        features |= VIRTIO_NET_F_GUEST_ECN;
+    }
+    features
+}
+
+///
+/// An ethernet hardware address for the guest network interface.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct MacAddress([u8; MAC_ADDR_LEN]);
+
+impl MacAddress {
+    pub fn new(octets: [u8; MAC_ADDR_LEN]) -> Self {
+        MacAddress(octets)
+    }
+
+    /// Parse an address written as six hex octets separated by colons.
+    pub fn parse(s: &str) -> Option<MacAddress> {
+        let mut octets = [0u8; MAC_ADDR_LEN];
+        let mut parts = s.split(':');
+        for octet in octets.iter_mut() {
+            let part = parts.next()?;
+            if part.len() != 2 {
+                return None;
+            }
+            *octet = u8::from_str_radix(part, 16).ok()?;
+        }
+        if parts.next().is_some() || octets[0] & 0x01 != 0 {
+            // Too many octets, or a multicast address
+            return None;
+        }
+        Some(MacAddress(octets))
+    }
+
+    /// Generate a random locally administered unicast address.
+    pub fn random() -> MacAddress {
+        let mut octets = [0u8; MAC_ADDR_LEN];
+        if Self::read_random(&mut octets).is_err() {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            let seed = nanos ^ process::id().rotate_left(16);
+            octets[2..].copy_from_slice(&seed.to_le_bytes());
+        }
+        octets[0] = (octets[0] & 0xFE) | 0x02;
+        MacAddress(octets)
+    }
+
+    /// Generate a locally administered unicast address from `seed`, for a
+    /// deterministic VM.
+    pub fn from_seed(seed: u64) -> MacAddress {
+        let mut octets = [0u8; MAC_ADDR_LEN];
+        Drbg::new(seed, DRBG_STREAM).fill(&mut octets);
+        octets[0] = (octets[0] & 0xFE) | 0x02;
+        MacAddress(octets)
+    }
+
+    /// Generate a locally administered unicast address from the name of a
+    /// VM, so that it keeps the same address each time it is started.
+    pub fn from_name(name: &str) -> MacAddress {
+        // FNV-1a
+        let seed = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+        Self::from_seed(seed)
+    }
+
+    fn read_random(octets: &mut [u8]) -> io::Result<()> {
+        fs::File::open("/dev/urandom")?.read_exact(octets)
+    }
+
+    pub fn octets(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let o = &self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", o[0], o[1], o[2], o[3], o[4], o[5])
+    }
+}
 
 pub struct VirtioNet {
     _features_supported: u64,
+    features: u64,
     tap: Option<Tap>,
+    vhost: Option<VhostNet>,
+    config: DeviceConfigArea,
 }
 
 impl VirtioNet {
-    fn new(tap: Tap, features_supported: u64) -> Self {
+    fn new(tap: Tap, mac: MacAddress, mtu: Option<u16>, vhost: Option<VhostNet>, features_supported: u64) -> Self {
+        let mut config = DeviceConfigArea::new(Self::config_size(mtu));
+        config.write_bytes(0, mac.octets());
+        if let Some(mtu) = mtu {
+            config.write_u16(CONFIG_MTU_OFFSET, mtu);
+        }
         VirtioNet{
             _features_supported: features_supported,
-            tap: Some(tap)
+            features: 0,
+            tap: Some(tap),
+            vhost,
+            config,
         }
     }
 
-    pub fn create(vbus: &mut VirtioBus, tap: Tap) -> virtio::Result<()> {
-        tap.set_offload(TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6| TUN_F_TSO_ECN).unwrap();
+    /// If `vhost` is given packets are processed by the host kernel, or in
+    /// userspace if the vhost device cannot be started.
+    ///
+    /// The tap device completes checksums and splits large segments in frames
+    /// sent by the guest, so the host offloads are always offered. The guest
+    /// receive offloads are only offered if the tap device can be configured
+    /// to pass frames with those offloads to the guest.
+    ///
+    /// If `mtu` is given it is offered to the guest driver in the device
+    /// configuration, otherwise the guest uses the ethernet default.
+    pub fn create(vbus: &mut VirtioBus, tap: Tap, mac: MacAddress, mtu: Option<u16>, vhost: Option<VhostNet>) -> virtio::Result<()> {
+        let offloads = Self::probe_offloads(&tap);
         tap.set_vnet_hdr_size(VIRTIO_NET_HDR_SIZE).unwrap();
-        let feature_bits =
+        let mut feature_bits =
                 VIRTIO_NET_F_CSUM |
-                VIRTIO_NET_F_GUEST_CSUM |
-                VIRTIO_NET_F_GUEST_TSO4 |
-                VIRTIO_NET_F_GUEST_TSO6 |
-                VIRTIO_NET_F_GUEST_ECN |
+                VIRTIO_NET_F_MAC |
                 VIRTIO_NET_F_HOST_TSO4 |
                 VIRTIO_NET_F_HOST_TSO6 |
-                VIRTIO_NET_F_HOST_ECN;
+                VIRTIO_NET_F_HOST_ECN |
+                guest_offload_features(offloads);
+        if mtu.is_some() {
+            feature_bits |= VIRTIO_NET_F_MTU;
+        }
 
-        let dev = Arc::new(RwLock::new(VirtioNet::new(tap, feature_bits)));
+        let dev = Arc::new(RwLock::new(VirtioNet::new(tap, mac, mtu, vhost, feature_bits)));
         vbus.new_virtio_device(VIRTIO_ID_NET, dev)
-            .set_queue_sizes(&[256, 256])
-            .set_config_size(MAC_ADDR_LEN)
+            .set_queue_pairs(1, 256)
+            .set_config_size(Self::config_size(mtu))
             .set_features(feature_bits)
             .register()
     }
+
+    fn config_size(mtu: Option<u16>) -> usize {
+        if mtu.is_some() { CONFIG_SIZE_WITH_MTU } else { MAC_ADDR_LEN }
+    }
+
+    // Find the most capable set of offloads which the tap device accepts,
+    // then disable them until the guest has chosen its features.
+    fn probe_offloads(tap: &Tap) -> u32 {
+        let offloads = TAP_OFFLOAD_FALLBACKS.iter()
+            .cloned()
+            .find(|&flags| tap.set_offload(flags).is_ok())
+            .unwrap_or(0);
+        if offloads != TAP_OFFLOAD_FALLBACKS[0] {
+            notify!("Tap device only supports offloads 0x{:x}, not offering the others to the guest", offloads);
+        }
+        if let Err(e) = tap.set_offload(0) {
+            warn!("Failed to disable offloads on tap device: {}", e);
+        }
+        offloads
+    }
 }
 
 pub const TUN_F_CSUM: u32 = 1;
@@ -91,9 +339,39 @@ pub const TUN_F_TSO6: u32 = 4;
 pub const TUN_F_TSO_ECN: u32 = 8;
 
 impl VirtioDeviceOps for VirtioNet {
-    fn start(&mut self, _memory: &MemoryManager, mut queues: Vec<VirtQueue>) {
-        let tx = queues.pop().unwrap();
-        let rx = queues.pop().unwrap();
+    fn enable_features(&mut self, bits: u64) -> bool {
+        self.features = bits;
+        let hdr_size = if bits & VIRTIO_F_VERSION_1 == 0 {
+            VIRTIO_NET_LEGACY_HDR_SIZE
+        } else {
+            VIRTIO_NET_HDR_SIZE
+        };
+        if let Some(ref tap) = self.tap {
+            if let Err(e) = tap.set_vnet_hdr_size(hdr_size) {
+                warn!("Failed to set vnet header size on tap device: {}", e);
+            }
+            // Without the offloads the host kernel completes checksums and
+            // splits segments before frames reach the guest.
+            if let Err(e) = tap.set_offload(tap_offloads(bits)) {
+                warn!("Failed to set offloads on tap device, guest receives plain frames: {}", e);
+            }
+        }
+        true
+    }
+
+    fn read_config(&mut self, offset: usize, size: usize) -> u64 {
+        self.config.read_config(offset, size)
+    }
+
+    fn start(&mut self, memory: &MemoryManager, queues: Vec<VirtQueue>) {
+        let (rx, tx) = VirtQueue::into_pairs(queues).remove(0);
+        if let Some(vhost) = self.vhost.take() {
+            let tap = self.tap.as_ref().unwrap();
+            match vhost.start(memory.guest_ram(), &rx, &tx, tap, self.features) {
+                Ok(()) => return,
+                Err(e) => warn!("Failed to start vhost-net, processing packets in userspace: {}", e),
+            }
+        }
         let tap = self.tap.take().unwrap();
         let poll = match EPoll::new() {
             Ok(poll) => poll,
@@ -102,8 +380,11 @@ impl VirtioDeviceOps for VirtioNet {
                 return;
             }
         };
-        let mut dev = VirtioNetDevice::new(rx, tx, tap, poll);
+        let mut dev = VirtioNetDevice::new(rx, tx, tap, poll, self.features);
         thread::spawn(move || {
+            if let Err(err) = seccomp::sandbox_thread(SeccompProfile::Net) {
+                warn!("Failed to install seccomp filter for virtio net device: {}", err);
+            }
             if let Err(err) = dev.run() {
                 warn!("error running virtio net device: {}", err);
             }
@@ -119,6 +400,9 @@ const RX_TAP:u64 = 3;
 struct VirtioNetDevice {
     tap: Tap,
     poll: EPoll,
+    features: u64,
+    hdr_size: usize,
+    tx_dropped: usize,
     tap_event_enabled: bool,
     rx: VirtQueue,
     tx: VirtQueue,
@@ -128,12 +412,20 @@ struct VirtioNetDevice {
 }
 
 impl VirtioNetDevice {
-    fn new(rx: VirtQueue, tx: VirtQueue, tap: Tap, poll: EPoll) -> Self {
+    fn new(rx: VirtQueue, tx: VirtQueue, tap: Tap, poll: EPoll, features: u64) -> Self {
+        let hdr_size = if features & VIRTIO_F_VERSION_1 == 0 {
+            VIRTIO_NET_LEGACY_HDR_SIZE
+        } else {
+            VIRTIO_NET_HDR_SIZE
+        };
         VirtioNetDevice {
             rx,
             tx,
             tap,
             poll,
+            features,
+            hdr_size: hdr_size as usize,
+            tx_dropped: 0,
             tap_event_enabled: false,
             rx_bytes: 0,
             rx_frame: [0; MAX_BUFFER_SIZE],
@@ -162,25 +454,64 @@ impl VirtioNetDevice {
     }
 
     fn handle_tx_queue(&mut self) -> Result<()> {
-        self.tx.ioevent()
-            .read()
+        self.tx.read_notifications()
             .map_err(Error::ChainIoEvent)?;
 
         while let Some(mut chain) = self.tx.next_chain() {
-            loop {
-                let n = chain.read(&mut self.tx_frame)
-                    .map_err(Error::ChainRead)?;
-                if n == 0 {
-                    break;
+            // Each write to the tap device is one frame, so the whole chain
+            // must be written at once.
+            let n = chain.read(&mut self.tx_frame)
+                .map_err(Error::ChainRead)?;
+            let oversized = chain.remaining_read() > 0;
+            chain.flush_chain();
+            if oversized {
+                self.drop_tx_frame("frame larger than the transmit buffer");
+            } else if let Err(reason) = self.check_tx_frame(n) {
+                self.drop_tx_frame(reason);
+            } else {
+                match self.tap.write(&self.tx_frame[..n]) {
+                    Ok(_) => {},
+                    // The tap device rejected the header
+                    Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => self.drop_tx_frame("rejected by tap device"),
+                    Err(e) => return Err(Error::TapWrite(e)),
                 }
-                self.tap.write_all(&self.tx_frame[..n])
-                    .map_err(Error::TapWrite)?;
             }
-            chain.flush_chain()
         }
         Ok(())
     }
 
+    fn check_tx_frame(&self, len: usize) -> result::Result<(), &'static str> {
+        if len < self.hdr_size {
+            return Err("frame shorter than the header");
+        }
+        match VnetHeader::parse(&self.tx_frame[..len]) {
+            Some(hdr) => hdr.check_tx(self.features, len - self.hdr_size),
+            None => Err("frame shorter than the header"),
+        }
+    }
+
+    fn drop_tx_frame(&mut self, reason: &str) {
+        self.tx_dropped += 1;
+        if self.tx_dropped.is_power_of_two() {
+            warn!("virtio_net: dropped transmitted frame: {} ({} dropped)", reason, self.tx_dropped);
+        }
+    }
+
+    // Frames from the tap device only carry the offloads which the guest
+    // accepted, but the header is cleaned up as the specification requires.
+    fn fix_rx_header(&mut self) {
+        if self.rx_bytes < self.hdr_size {
+            return;
+        }
+        if self.features & VIRTIO_NET_F_GUEST_CSUM == 0 {
+            self.rx_frame[0] = 0;
+        }
+        // The tap device leaves num_buffers unwritten
+        if self.hdr_size == VIRTIO_NET_HDR_SIZE as usize {
+            self.rx_frame[10..12].copy_from_slice(&1u16.to_le_bytes());
+        }
+    }
+
     fn pending_rx(&self) -> bool {
         self.rx_bytes != 0
     }
@@ -201,6 +532,7 @@ impl VirtioNetDevice {
         match self.tap.read(&mut self.rx_frame) {
             Ok(n) => {
                 self.rx_bytes = n;
+                self.fix_rx_header();
                 Ok(true)
             },
             Err(e) => if let Some(libc::EAGAIN) = e.raw_os_error() {
@@ -251,7 +583,7 @@ impl VirtioNetDevice {
     }
 
     fn handle_rx_queue(&mut self) -> Result<()> {
-        self.rx.ioevent().read().unwrap();
+        self.rx.read_notifications().unwrap();
         if !self.tap_event_enabled {
             self.enable_tap_poll();
         }
@@ -280,6 +612,11 @@ impl VirtioNetDevice {
 
         loop {
             let events = self.poll.wait().map_err(Error::PollWait)?;
+            // The device was removed, and the tap device is closed when this
+            // returns
+            if self.rx.is_closed() {
+                return Ok(());
+            }
 
             for ev in events.iter() {
                 if let Err(err) = self.handle_event(ev) {
@@ -2,20 +2,31 @@
 use std::sync::{Arc,RwLock};
 use std::thread;
 use std::fs::File;
+use std::io::Read;
 
 use crate::virtio::{VirtioDeviceOps,VirtioBus,VirtQueue,Result};
 use crate::memory::MemoryManager;
+use crate::util::Drbg;
 
 
 const VIRTIO_ID_RANDOM: u16 = 4;
 
-pub struct VirtioRandom;
+// Output stream of the seeded generator used by this device
+const DRBG_STREAM: u64 = 1;
+
+///
+/// Provides entropy from the host /dev/urandom, or from a generator seeded
+/// with a fixed value when the VM is configured to be deterministic.
+///
+pub struct VirtioRandom {
+    seed: Option<u64>,
+}
 
 impl VirtioRandom {
-    fn new() -> VirtioRandom { VirtioRandom }
+    fn new(seed: Option<u64>) -> VirtioRandom { VirtioRandom { seed } }
 
-    pub fn create(vbus: &mut VirtioBus) -> Result<()> {
-        let dev = Arc::new(RwLock::new(VirtioRandom::new()));
+    pub fn create(vbus: &mut VirtioBus, seed: Option<u64>) -> Result<()> {
+        let dev = Arc::new(RwLock::new(VirtioRandom::new(seed)));
         vbus.new_virtio_device(VIRTIO_ID_RANDOM, dev)
             .set_num_queues(1)
             .register()
@@ -25,19 +36,23 @@ impl VirtioRandom {
 impl VirtioDeviceOps for VirtioRandom {
 
     fn start(&mut self, _memory: &MemoryManager, mut queues: Vec<VirtQueue>) {
+        let seed = self.seed;
         thread::spawn(move|| {
-            run(queues.pop().unwrap())
+            run(queues.pop().unwrap(), seed)
         });
     }
 }
 
-fn run(q: VirtQueue) {
-    let random = File::open("/dev/urandom").unwrap();
+fn run(q: VirtQueue, seed: Option<u64>) {
+    let mut random: Box<dyn Read> = match seed {
+        Some(seed) => Box::new(Drbg::new(seed, DRBG_STREAM)),
+        None => Box::new(File::open("/dev/urandom").unwrap()),
+    };
 
     loop {
         q.on_each_chain(|mut chain| {
             while !chain.is_end_of_chain() {
-                let _ = chain.copy_from_reader(&random, 256).unwrap();
+                let _ = chain.copy_from_reader(&mut random, 256).unwrap();
             }
         });
     }
@@ -1,10 +1,16 @@
-use std::sync::{Arc,RwLock};
+use std::sync::{Arc,Mutex,RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::io::{self,Write,Read};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str;
 use std::thread::spawn;
 use termios::*;
 
-use crate::virtio::{VirtioDeviceOps,VirtioBus, VirtQueue,Result};
+use crate::virtio::{VirtioDeviceOps,VirtioBus, VirtQueue, Result, Error};
 use crate::memory::MemoryManager;
+use crate::util::remove_stale_socket;
+use crate::vm::{console, events};
 
 const VIRTIO_ID_CONSOLE: u16 = 3;
 
@@ -18,34 +24,57 @@ const VIRTIO_CONSOLE_PORT_READY: u16    = 3;
 const VIRTIO_CONSOLE_CONSOLE_PORT: u16  = 4;
 const VIRTIO_CONSOLE_RESIZE: u16        = 5;
 const VIRTIO_CONSOLE_PORT_OPEN: u16     = 6;
-const _VIRTIO_CONSOLE_PORT_NAME: u16     = 7;
+const VIRTIO_CONSOLE_PORT_NAME: u16     = 7;
+
+// Port 0 is the console, port 1 carries events from the guest agent, and
+// any further ports are bound to unix sockets on the host
+const CONSOLE_PORT_ID: u32 = 0;
+const AGENT_PORT_ID: u32 = 1;
+const FIRST_SOCKET_PORT_ID: u32 = 2;
+const AGENT_PORT_NAME: &str = "ph.agent";
+const MAX_AGENT_LINE: usize = 4096;
 
 pub struct VirtioSerial {
     feature_bits: u64,
+    socket_ports: Vec<SocketPort>,
 }
 
 impl VirtioSerial {
-    fn new() -> VirtioSerial {
-        VirtioSerial{feature_bits:0}
+    fn new(socket_ports: Vec<SocketPort>) -> VirtioSerial {
+        VirtioSerial{feature_bits:0, socket_ports}
     }
 
-    pub fn create(vbus: &mut VirtioBus) -> Result<()> {
-        let dev = Arc::new(RwLock::new(VirtioSerial::new()));
+    /// Create the console device with a named port for each of `socket_ports`,
+    /// which carries the stream of a client connected to the unix socket at
+    /// the path given for it.
+    pub fn create(vbus: &mut VirtioBus, socket_ports: &[(String, PathBuf)]) -> Result<()> {
+        let socket_ports = socket_ports.iter()
+            .map(|(name, path)| SocketPort::bind(name, path))
+            .collect::<Result<Vec<_>>>()?;
+        let max_ports = FIRST_SOCKET_PORT_ID as usize + socket_ports.len();
+        let dev = Arc::new(RwLock::new(VirtioSerial::new(socket_ports)));
         vbus.new_virtio_device(VIRTIO_ID_CONSOLE, dev)
-            .set_num_queues(4)
+            .set_num_queues(2 + 2 * max_ports)
             .set_device_class(0x0700)
             .set_config_size(12)
             .set_features(VIRTIO_CONSOLE_F_MULTIPORT|VIRTIO_CONSOLE_F_SIZE)
             .register()
     }
 
+    fn max_ports(&self) -> u32 {
+        FIRST_SOCKET_PORT_ID + self.socket_ports.len() as u32
+    }
+
     fn start_console(&self, _memory: &MemoryManager, q: VirtQueue) {
         spawn(move || {
             loop {
                 q.wait_ready().unwrap();
                 for mut chain in q.iter() {
-                    io::copy(&mut chain, &mut io::stdout()).unwrap();
-                    io::stdout().flush().unwrap();
+                    let mut buf = Vec::new();
+                    if let Err(e) = chain.read_to_end(&mut buf) {
+                        warn!("Error reading from console port: {}", e);
+                    }
+                    console::write_output(&buf);
                 }
             }
         });
@@ -81,52 +110,99 @@ impl VirtioDeviceOps for VirtioSerial {
 
     fn read_config(&mut self, offset: usize, _size: usize) -> u64 {
         if offset == 4 {
-            return 1;
+            return self.max_ports() as u64;
         }
         0
     }
 
     fn start(&mut self, memory: &MemoryManager, mut queues: Vec<VirtQueue>) {
-        let mut term = Terminal::create(queues.remove(0));
+        console::set_input_queue(queues.remove(0));
         self.start_console(memory, queues.remove(0));
 
-        spawn( move || {
-            term.read_loop();
-        });
+        // Input comes from the PTY or socket of the console otherwise
+        if console::is_stdio() {
+            let mut term = Terminal::create();
+            spawn( move || {
+                term.read_loop();
+            });
+        }
 
         if self.multiport() {
-            let mut control = Control::new(queues.remove(0), queues.remove(0));
+            let ports = self.socket_ports.iter()
+                .map(|p| p.state.clone())
+                .collect();
+            let mut control = Control::new(queues.remove(0), queues.remove(0), ports);
+            let control_rx = control.rx_vq.clone();
             spawn(move || {
                 control.run();
             });
+            events::set_command_queue(queues.remove(0));
+            let agent_tx = queues.remove(0);
+            spawn(move || {
+                agent_loop(agent_tx);
+            });
+            for (id, port) in (FIRST_SOCKET_PORT_ID..).zip(self.socket_ports.iter()) {
+                let rx = queues.remove(0);
+                let tx = queues.remove(0);
+                if let Err(e) = port.start(id, rx, tx, control_rx.clone()) {
+                    warn!("Error starting console port {}: {}", port.state.name, e);
+                }
+            }
         }
     }
 
 }
 
 struct Control {
-    rx_vq: VirtQueue,
+    // Shared with the socket ports, which send PORT_OPEN as clients come and go
+    rx_vq: Arc<Mutex<VirtQueue>>,
     tx_vq: VirtQueue,
+    socket_ports: Vec<PortState>,
 }
 
 impl Control {
-    fn new(rx: VirtQueue, tx: VirtQueue) -> Control {
-        Control { rx_vq: rx, tx_vq: tx }
+    fn new(rx: VirtQueue, tx: VirtQueue, socket_ports: Vec<PortState>) -> Control {
+        Control { rx_vq: Arc::new(Mutex::new(rx)), tx_vq: tx, socket_ports }
+    }
+
+    fn socket_port(&self, id: u32) -> Option<&PortState> {
+        id.checked_sub(FIRST_SOCKET_PORT_ID)
+            .and_then(|idx| self.socket_ports.get(idx as usize))
     }
 
     fn run(&mut self) {
-        let mut rx = self.rx_vq.clone();
+        let rx = self.rx_vq.clone();
+        let max_ports = FIRST_SOCKET_PORT_ID + self.socket_ports.len() as u32;
         self.tx_vq.on_each_chain(|mut chain| {
-            let _id = chain.r32().unwrap();
+            let mut rx = rx.lock().unwrap();
+            let id = chain.r32().unwrap();
             let event = chain.r16().unwrap();
             let _value = chain.r16().unwrap();
             if event == VIRTIO_CONSOLE_DEVICE_READY {
-                Control::send_msg(&mut rx,0, VIRTIO_CONSOLE_DEVICE_ADD, 1).unwrap();
+                for port_id in 0..max_ports {
+                    Control::send_msg(&mut rx,port_id, VIRTIO_CONSOLE_DEVICE_ADD, 1).unwrap();
+                }
+            }
+            if event == VIRTIO_CONSOLE_PORT_READY && id == CONSOLE_PORT_ID {
+                Control::send_msg(&mut rx,id, VIRTIO_CONSOLE_CONSOLE_PORT, 1).unwrap();
+                Control::send_msg(&mut rx,id, VIRTIO_CONSOLE_PORT_OPEN, 1).unwrap();
+                // stdin is a socket rather than a terminal when the console was passed by systemd
+                if console::is_stdio() && unsafe { libc::isatty(0) } == 1 {
+                    Control::send_resize(&mut rx, id).unwrap();
+                }
+            }
+            if event == VIRTIO_CONSOLE_PORT_READY && id == AGENT_PORT_ID {
+                Control::send_name(&mut rx, id, AGENT_PORT_NAME).unwrap();
+                Control::send_msg(&mut rx,id, VIRTIO_CONSOLE_PORT_OPEN, 1).unwrap();
             }
             if event == VIRTIO_CONSOLE_PORT_READY {
-                Control::send_msg(&mut rx,0, VIRTIO_CONSOLE_CONSOLE_PORT, 1).unwrap();
-                Control::send_msg(&mut rx,0, VIRTIO_CONSOLE_PORT_OPEN, 1).unwrap();
-                Control::send_resize(&mut rx, 0).unwrap();
+                if let Some(port) = self.socket_port(id) {
+                    Control::send_name(&mut rx, id, &port.name).unwrap();
+                    // A client may have connected before the guest added the port
+                    if port.is_connected() {
+                        Control::send_msg(&mut rx,id, VIRTIO_CONSOLE_PORT_OPEN, 1).unwrap();
+                    }
+                }
             }
             chain.flush_chain();
         });
@@ -142,6 +218,16 @@ impl Control {
         Ok(())
     }
 
+    fn send_name(vq: &mut VirtQueue, id: u32, name: &str) -> io::Result<()> {
+        let mut chain = vq.wait_next_chain().unwrap();
+        chain.w32(id)?;
+        chain.w16(VIRTIO_CONSOLE_PORT_NAME)?;
+        chain.w16(1)?;
+        chain.write_all(name.as_bytes())?;
+        chain.flush_chain();
+        Ok(())
+    }
+
     fn send_resize(vq: &mut VirtQueue, id: u32) -> io::Result<()> {
         let (cols, rows) = Control::stdin_terminal_size()?;
         let mut chain = vq.wait_next_chain().unwrap();
@@ -167,17 +253,152 @@ impl Control {
 
 }
 
+#[derive(Clone)]
+struct PortState {
+    name: String,
+    connected: Arc<AtomicBool>,
+}
+
+impl PortState {
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+}
+
+///
+/// A named console port which is bound to a unix socket on the host.
+///
+/// One client at a time is connected to the port. The guest sees the port
+/// open on the host side while a client is connected, and writes to the port
+/// block in the guest until one is. Other clients wait to be accepted until
+/// the connected client goes away.
+///
+struct SocketPort {
+    state: PortState,
+    listener: UnixListener,
+}
+
+impl SocketPort {
+    fn bind(name: &str, path: &Path) -> Result<Self> {
+        remove_stale_socket(path).map_err(Error::DeviceSocket)?;
+        let listener = UnixListener::bind(path).map_err(Error::DeviceSocket)?;
+        let state = PortState {
+            name: name.to_string(),
+            connected: Arc::new(AtomicBool::new(false)),
+        };
+        Ok(SocketPort { state, listener })
+    }
+
+    fn start(&self, id: u32, rx: VirtQueue, tx: VirtQueue, control: Arc<Mutex<VirtQueue>>) -> io::Result<()> {
+        let listener = self.listener.try_clone()?;
+        let client = Arc::new(Mutex::new(None));
+        spawn({
+            let client = client.clone();
+            move || port_output_loop(tx, client)
+        });
+        let state = self.state.clone();
+        spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => port_input_loop(id, &state, stream, &rx, &control, &client),
+                    Err(e) => warn!("Error accepting connection to console port {}: {}", state.name, e),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+// Forward data from a client of a socket port to the guest until the client disconnects
+fn port_input_loop(id: u32, state: &PortState, mut stream: UnixStream, rx: &VirtQueue, control: &Mutex<VirtQueue>, client: &Mutex<Option<UnixStream>>) {
+    match stream.try_clone() {
+        Ok(s) => *client.lock().unwrap() = Some(s),
+        Err(e) => {
+            warn!("Error accepting connection to console port {}: {}", state.name, e);
+            return;
+        }
+    }
+    let send_open = |open: u16| {
+        if let Err(e) = Control::send_msg(&mut control.lock().unwrap(), id, VIRTIO_CONSOLE_PORT_OPEN, open) {
+            warn!("Error sending open event for console port {}: {}", state.name, e);
+        }
+    };
+    state.connected.store(true, Ordering::SeqCst);
+    send_open(1);
+
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("Error reading from console port {} client: {}", state.name, e);
+                break;
+            }
+        };
+        let mut chain = match rx.wait_next_chain() {
+            Ok(chain) => chain,
+            Err(e) => {
+                warn!("Error waiting for console port {} buffer: {}", state.name, e);
+                break;
+            }
+        };
+        if let Err(e) = chain.write_all(&buf[..n]) {
+            warn!("Error writing to console port {}: {}", state.name, e);
+        }
+        chain.flush_chain();
+    }
+
+    *client.lock().unwrap() = None;
+    state.connected.store(false, Ordering::SeqCst);
+    send_open(0);
+}
+
+// Forward data written by the guest to a socket port to the connected client,
+// or discard it if no client is connected.
+fn port_output_loop(vq: VirtQueue, client: Arc<Mutex<Option<UnixStream>>>) {
+    vq.on_each_chain(|mut chain| {
+        let mut buf = Vec::new();
+        if let Err(e) = chain.read_to_end(&mut buf) {
+            warn!("Error reading from console port: {}", e);
+        }
+        if let Some(ref mut stream) = *client.lock().unwrap() {
+            // The input loop notices when the client has gone away
+            let _ = stream.write_all(&buf);
+        }
+    });
+}
+
+// Read lines written by the guest agent to its port and pass them to the event log
+fn agent_loop(vq: VirtQueue) {
+    let mut pending = Vec::new();
+    vq.on_each_chain(|mut chain| {
+        if let Err(e) = chain.read_to_end(&mut pending) {
+            warn!("Error reading from agent port: {}", e);
+        }
+        while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=pos).collect();
+            match str::from_utf8(&line) {
+                Ok(line) => events::guest_event(line.trim()),
+                Err(_) => warn!("Invalid UTF-8 in event from agent port"),
+            }
+        }
+        if pending.len() > MAX_AGENT_LINE {
+            warn!("Discarding {} bytes from agent port without a newline", pending.len());
+            pending.clear();
+        }
+    });
+}
+
 struct Terminal {
     saved: Option<Termios>,
-    vq: VirtQueue,
 }
 
 impl Terminal {
-    fn create(vq: VirtQueue) -> Terminal {
-        let termios = Termios::from_fd(0).unwrap();
+    fn create() -> Terminal {
         Terminal {
-            saved: Some(termios),
-            vq,
+            saved: Termios::from_fd(0).ok(),
         }
     }
 
@@ -197,22 +418,29 @@ impl Terminal {
     fn read_loop(&mut self) {
         self.setup_term();
         let mut abort_cnt = 0;
+        let mut escape = console::EscapeDetector::default();
         let mut buf = vec![0u8; 32];
         loop {
             let n = io::stdin().read(&mut buf).unwrap();
 
-            if n > 0 {
-                // XXX write_all
-                let mut chain = self.vq.wait_next_chain().unwrap();
-                chain.write_all(&mut buf[..n]).unwrap();
-                chain.flush_chain();
-                if n > 1 || buf[0] != 3 {
-                    abort_cnt = 0;
-                } else {
-                    abort_cnt += 1;
-                }
+            if n == 0 {
+                // console client disconnected
+                return;
+            }
+
+            let (input, detach) = escape.filter(&buf[..n]);
+            if let Err(e) = console::send_input(&input) {
+                warn!("Error sending console input: {}", e);
+            }
+            if detach {
+                self.restore_term();
+                console::detach_local();
+                return;
+            }
+            if n > 1 || buf[0] != 3 {
+                abort_cnt = 0;
             } else {
-                println!("n = {}", n);
+                abort_cnt += 1;
             }
 
             if abort_cnt == 3 {
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::process::{Child, Command, Stdio};
+
+use super::stream::{Direction, PcmParams, PcmFormat};
+
+///
+/// The host sound server which the PCM streams of the guest are played on
+/// and recorded from.
+///
+/// Each running stream is a child process reading raw samples from a pipe
+/// for playback or writing them to one for capture: `pw-cat` for PipeWire,
+/// or `aplay` and `arecord` for ALSA.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum SoundBackend {
+    PipeWire,
+    Alsa,
+}
+
+impl SoundBackend {
+    /// Parse `pipewire` or `alsa`.
+    pub fn parse(s: &str) -> Option<SoundBackend> {
+        match s {
+            "pipewire" => Some(SoundBackend::PipeWire),
+            "alsa" => Some(SoundBackend::Alsa),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SoundBackend::PipeWire => "pipewire",
+            SoundBackend::Alsa => "alsa",
+        }
+    }
+
+    fn command(self, direction: Direction, params: &PcmParams) -> Command {
+        let rate = params.rate.to_string();
+        let channels = params.channels.to_string();
+        let mut command = match self {
+            SoundBackend::PipeWire => {
+                let mut command = Command::new("pw-cat");
+                command.arg(match direction {
+                    Direction::Output => "--playback",
+                    Direction::Input => "--record",
+                });
+                command.args(&["--format", params.format.pipewire_name()])
+                    .args(&["--rate", &rate])
+                    .args(&["--channels", &channels]);
+                command
+            }
+            SoundBackend::Alsa => {
+                let mut command = Command::new(match direction {
+                    Direction::Output => "aplay",
+                    Direction::Input => "arecord",
+                });
+                command.args(&["-q", "-t", "raw"])
+                    .args(&["-f", params.format.alsa_name()])
+                    .args(&["-r", &rate])
+                    .args(&["-c", &channels]);
+                command
+            }
+        };
+        command.arg("-").stderr(Stdio::null());
+        command
+    }
+
+    /// Start playing or recording a stream with `params`. Returns the child
+    /// process and the pipe which samples are written to or read from.
+    pub fn spawn(self, direction: Direction, params: &PcmParams) -> io::Result<(Child, File)> {
+        let mut command = self.command(direction, params);
+        let mut child = match direction {
+            Direction::Output => command.stdin(Stdio::piped()).stdout(Stdio::null()).spawn()?,
+            Direction::Input => command.stdin(Stdio::null()).stdout(Stdio::piped()).spawn()?,
+        };
+        let fd = match direction {
+            Direction::Output => child.stdin.take().map(|p| p.into_raw_fd()),
+            Direction::Input => child.stdout.take().map(|p| p.into_raw_fd()),
+        };
+        match fd {
+            Some(fd) => Ok((child, unsafe { File::from_raw_fd(fd) })),
+            None => {
+                let _ = child.kill();
+                Err(io::Error::new(io::ErrorKind::Other, "no pipe to sound process"))
+            }
+        }
+    }
+}
+
+impl PcmFormat {
+    fn pipewire_name(self) -> &'static str {
+        match self {
+            PcmFormat::U8 => "u8",
+            PcmFormat::S16 => "s16",
+            PcmFormat::S32 => "s32",
+        }
+    }
+
+    fn alsa_name(self) -> &'static str {
+        match self {
+            PcmFormat::U8 => "U8",
+            PcmFormat::S16 => "S16_LE",
+            PcmFormat::S32 => "S32_LE",
+        }
+    }
+}
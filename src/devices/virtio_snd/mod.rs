@@ -0,0 +1,203 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use crate::memory::MemoryManager;
+use crate::virtio::{self, Chain, VirtioBus, VirtioDeviceOps, VirtQueue, Result};
+use self::stream::{Direction, PcmFormat, PcmParams, Streams, MAX_CHANNELS};
+use self::stream::{VIRTIO_SND_S_OK, VIRTIO_SND_S_BAD_MSG, VIRTIO_SND_S_NOT_SUPP};
+
+pub use self::backend::SoundBackend;
+
+mod backend;
+mod stream;
+
+const VIRTIO_ID_SOUND: u16 = 25;
+
+// The number of jacks, streams and channel maps
+const VIRTIO_SND_CONFIG_SIZE: usize = 12;
+
+// Control request codes
+const VIRTIO_SND_R_JACK_INFO: u32 = 1;
+const VIRTIO_SND_R_PCM_INFO: u32 = 0x0100;
+const VIRTIO_SND_R_PCM_SET_PARAMS: u32 = 0x0101;
+const VIRTIO_SND_R_PCM_PREPARE: u32 = 0x0102;
+const VIRTIO_SND_R_PCM_RELEASE: u32 = 0x0103;
+const VIRTIO_SND_R_PCM_START: u32 = 0x0104;
+const VIRTIO_SND_R_PCM_STOP: u32 = 0x0105;
+const VIRTIO_SND_R_CHMAP_INFO: u32 = 0x0200;
+
+const VIRTIO_SND_CHMAP_MAX_SIZE: usize = 18;
+const VIRTIO_SND_CHMAP_FL: u8 = 3;
+const VIRTIO_SND_CHMAP_FR: u8 = 4;
+
+// The status and latency which follow the samples of an I/O message
+const VIRTIO_SND_PCM_STATUS_SIZE: usize = 8;
+
+// One playback and one capture stream
+const STREAMS: &[Direction] = &[Direction::Output, Direction::Input];
+
+///
+/// A virtio-snd device with a playback and a capture stream which are
+/// bridged to the host sound server by a `SoundBackend`.
+///
+/// Control requests are handled on the control queue. The guest sends
+/// samples for playback on the transmit queue and buffers to record into
+/// on the receive queue, and each message is completed once its samples
+/// have been passed to or read from the sound process. The device has no
+/// jacks and sends no events.
+///
+pub struct VirtioSound {
+    streams: Streams,
+}
+
+impl VirtioSound {
+    pub fn create(vbus: &mut VirtioBus, backend: SoundBackend) -> Result<()> {
+        let streams = Streams::new(backend, STREAMS);
+        let dev = Arc::new(RwLock::new(VirtioSound { streams }));
+        vbus.new_virtio_device(VIRTIO_ID_SOUND, dev)
+            .set_num_queues(4)
+            .set_config_size(VIRTIO_SND_CONFIG_SIZE)
+            .register()
+    }
+
+    fn config_bytes(&self) -> [u8; VIRTIO_SND_CONFIG_SIZE] {
+        let streams = STREAMS.len() as u32;
+        let mut bytes = [0u8; VIRTIO_SND_CONFIG_SIZE];
+        // No jacks, and a channel map for each stream
+        bytes[4..8].copy_from_slice(&streams.to_le_bytes());
+        bytes[8..].copy_from_slice(&streams.to_le_bytes());
+        bytes
+    }
+}
+
+impl VirtioDeviceOps for VirtioSound {
+    fn reset(&mut self) {
+        self.streams.reset();
+    }
+
+    fn read_config(&mut self, offset: usize, size: usize) -> u64 {
+        virtio::read_config_buffer(&self.config_bytes(), offset, size)
+    }
+
+    fn start(&mut self, _memory: &MemoryManager, mut queues: Vec<VirtQueue>) {
+        let rx_vq = queues.pop().unwrap();
+        let tx_vq = queues.pop().unwrap();
+        // Nothing is ever sent on the event queue
+        let _event_vq = queues.pop().unwrap();
+        let control_vq = queues.pop().unwrap();
+
+        let streams = self.streams.clone();
+        thread::spawn(move || control_vq.on_each_chain(|mut chain| {
+            if let Err(e) = control_request(&streams, &mut chain) {
+                warn!("virtio-snd: error handling control request: {}", e);
+            }
+        }));
+        let streams = self.streams.clone();
+        thread::spawn(move || tx_vq.on_each_chain(|mut chain| {
+            if let Err(e) = transfer_output(&streams, &mut chain) {
+                warn!("virtio-snd: error handling playback message: {}", e);
+            }
+        }));
+        let streams = self.streams.clone();
+        thread::spawn(move || rx_vq.on_each_chain(|mut chain| {
+            if let Err(e) = transfer_input(&streams, &mut chain) {
+                warn!("virtio-snd: error handling capture message: {}", e);
+            }
+        }));
+    }
+}
+
+fn pcm_info(direction: Direction) -> Vec<u8> {
+    let mut info = Vec::new();
+    // hda_fn_nid and features
+    info.extend_from_slice(&[0u8; 8]);
+    info.extend_from_slice(&PcmFormat::supported().to_le_bytes());
+    info.extend_from_slice(&stream::supported_rates().to_le_bytes());
+    info.extend_from_slice(&[direction.code(), 1, MAX_CHANNELS, 0, 0, 0, 0, 0]);
+    info
+}
+
+fn chmap_info(direction: Direction) -> Vec<u8> {
+    let mut info = vec![0u8; 4];
+    info.extend_from_slice(&[direction.code(), MAX_CHANNELS]);
+    let mut positions = [0u8; VIRTIO_SND_CHMAP_MAX_SIZE];
+    positions[0] = VIRTIO_SND_CHMAP_FL;
+    positions[1] = VIRTIO_SND_CHMAP_FR;
+    info.extend_from_slice(&positions);
+    info
+}
+
+// The response to a query is the status followed by the information of each
+// item, padded or cut to the size the driver asked for.
+fn query_info(streams: &Streams, code: u32, chain: &mut Chain) -> io::Result<()> {
+    let start = chain.r32()? as usize;
+    let count = chain.r32()? as usize;
+    let size = chain.r32()? as usize;
+    let items: Vec<Vec<u8>> = match code {
+        VIRTIO_SND_R_PCM_INFO => streams.directions().into_iter().map(pcm_info).collect(),
+        VIRTIO_SND_R_CHMAP_INFO => streams.directions().into_iter().map(chmap_info).collect(),
+        _ => Vec::new(),
+    };
+    let end = match start.checked_add(count) {
+        Some(end) if count > 0 && end <= items.len() => end,
+        _ => return chain.w32(VIRTIO_SND_S_BAD_MSG),
+    };
+    chain.w32(VIRTIO_SND_S_OK)?;
+    for item in &items[start..end] {
+        let mut item = item.clone();
+        item.resize(size, 0);
+        chain.write_all(&item)?;
+    }
+    Ok(())
+}
+
+fn control_request(streams: &Streams, chain: &mut Chain) -> io::Result<()> {
+    let code = chain.r32()?;
+    match code {
+        VIRTIO_SND_R_JACK_INFO | VIRTIO_SND_R_PCM_INFO | VIRTIO_SND_R_CHMAP_INFO => {
+            return query_info(streams, code, chain);
+        }
+        VIRTIO_SND_R_PCM_SET_PARAMS ..= VIRTIO_SND_R_PCM_STOP => {},
+        _ => return chain.w32(VIRTIO_SND_S_NOT_SUPP),
+    }
+    let id = chain.r32()?;
+    let status = match code {
+        VIRTIO_SND_R_PCM_SET_PARAMS => {
+            // buffer_bytes, period_bytes and features are not needed, since
+            // samples are passed on as each message arrives
+            let mut fields = [0u8; 16];
+            chain.read_exact(&mut fields)?;
+            match PcmParams::new(fields[12], fields[13], fields[14]) {
+                Some(params) => streams.set_params(id, params),
+                None => VIRTIO_SND_S_NOT_SUPP,
+            }
+        }
+        VIRTIO_SND_R_PCM_PREPARE => streams.prepare(id),
+        VIRTIO_SND_R_PCM_RELEASE => streams.release(id),
+        VIRTIO_SND_R_PCM_START => streams.start(id),
+        _ => streams.stop(id),
+    };
+    chain.w32(status)
+}
+
+fn transfer_output(streams: &Streams, chain: &mut Chain) -> io::Result<()> {
+    let id = chain.r32()?;
+    let mut data = vec![0u8; chain.remaining_read()];
+    chain.read_exact(&mut data)?;
+    let status = streams.write(id, &data);
+    chain.w32(status)?;
+    chain.w32(0)
+}
+
+fn transfer_input(streams: &Streams, chain: &mut Chain) -> io::Result<()> {
+    let id = chain.r32()?;
+    let len = chain.remaining_write().saturating_sub(VIRTIO_SND_PCM_STATUS_SIZE);
+    let mut data = vec![0u8; len];
+    // The status always follows the whole buffer, which is silence if
+    // nothing was recorded
+    let status = streams.read(id, &mut data);
+    chain.write_all(&data)?;
+    chain.w32(status)?;
+    chain.w32(0)
+}
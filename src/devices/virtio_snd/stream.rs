@@ -0,0 +1,298 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process::Child;
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::backend::SoundBackend;
+
+// Status codes of control requests and I/O messages
+pub const VIRTIO_SND_S_OK: u32 = 0x8000;
+pub const VIRTIO_SND_S_BAD_MSG: u32 = 0x8001;
+pub const VIRTIO_SND_S_NOT_SUPP: u32 = 0x8002;
+pub const VIRTIO_SND_S_IO_ERR: u32 = 0x8003;
+
+// Sample formats, by the bit of each in the formats of a stream
+const VIRTIO_SND_PCM_FMT_U8: u8 = 4;
+const VIRTIO_SND_PCM_FMT_S16: u8 = 5;
+const VIRTIO_SND_PCM_FMT_S32: u8 = 17;
+
+// Frame rates, by the bit of each in the rates of a stream
+const RATES: &[(u8, u32)] = &[
+    (1, 8000), (2, 11025), (3, 16000), (4, 22050),
+    (5, 32000), (6, 44100), (7, 48000), (10, 96000),
+];
+
+// Streams are mono or stereo
+pub const MAX_CHANNELS: u8 = 2;
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Direction {
+    Output,
+    Input,
+}
+
+impl Direction {
+    pub fn code(self) -> u8 {
+        match self {
+            Direction::Output => 0,
+            Direction::Input => 1,
+        }
+    }
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum PcmFormat {
+    U8,
+    S16,
+    S32,
+}
+
+impl PcmFormat {
+    fn from_code(code: u8) -> Option<PcmFormat> {
+        match code {
+            VIRTIO_SND_PCM_FMT_U8 => Some(PcmFormat::U8),
+            VIRTIO_SND_PCM_FMT_S16 => Some(PcmFormat::S16),
+            VIRTIO_SND_PCM_FMT_S32 => Some(PcmFormat::S32),
+            _ => None,
+        }
+    }
+
+    /// The formats field of the information of each stream.
+    pub fn supported() -> u64 {
+        [VIRTIO_SND_PCM_FMT_U8, VIRTIO_SND_PCM_FMT_S16, VIRTIO_SND_PCM_FMT_S32]
+            .iter()
+            .fold(0, |bits, &code| bits | (1 << code))
+    }
+}
+
+/// The rates field of the information of each stream.
+pub fn supported_rates() -> u64 {
+    RATES.iter().fold(0, |bits, &(code, _)| bits | (1 << code))
+}
+
+#[derive(Copy,Clone,Debug)]
+pub struct PcmParams {
+    pub format: PcmFormat,
+    pub rate: u32,
+    pub channels: u8,
+}
+
+impl PcmParams {
+    /// Parameters from the fields of a set parameters request, or `None` if
+    /// the format, rate or number of channels is not supported.
+    pub fn new(channels: u8, format: u8, rate: u8) -> Option<PcmParams> {
+        if channels == 0 || channels > MAX_CHANNELS {
+            return None;
+        }
+        let format = PcmFormat::from_code(format)?;
+        let rate = RATES.iter().find(|&&(code, _)| code == rate)?.1;
+        Some(PcmParams { format, rate, channels })
+    }
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum StreamState {
+    Idle,
+    Prepared,
+    Running,
+}
+
+struct PcmStream {
+    direction: Direction,
+    params: Option<PcmParams>,
+    state: StreamState,
+    // The sound process of a running stream and the pipe to it
+    process: Option<(Child, Arc<File>)>,
+}
+
+impl PcmStream {
+    fn new(direction: Direction) -> Self {
+        PcmStream { direction, params: None, state: StreamState::Idle, process: None }
+    }
+
+    fn kill_process(&mut self) {
+        if let Some((mut child, _)) = self.process.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    fn pipe(&self) -> Option<Arc<File>> {
+        self.process.as_ref().map(|(_, pipe)| pipe.clone())
+    }
+}
+
+///
+/// The PCM streams of a sound device, shared by the threads of the control
+/// queue and the transmit and receive queues.
+///
+/// A stream is given parameters, prepared, and then started and stopped any
+/// number of times before it is released. While it runs, samples go to or
+/// come from a host process started by the `SoundBackend`. Messages for a
+/// prepared stream wait until it is started, and a message for a stream
+/// which is not running is completed without playing or recording anything.
+///
+#[derive(Clone)]
+pub struct Streams {
+    backend: SoundBackend,
+    shared: Arc<(Mutex<Vec<PcmStream>>, Condvar)>,
+}
+
+impl Streams {
+    pub fn new(backend: SoundBackend, directions: &[Direction]) -> Self {
+        let streams = directions.iter().map(|&d| PcmStream::new(d)).collect();
+        Streams { backend, shared: Arc::new((Mutex::new(streams), Condvar::new())) }
+    }
+
+    pub fn directions(&self) -> Vec<Direction> {
+        self.shared.0.lock().unwrap().iter().map(|s| s.direction).collect()
+    }
+
+    // Apply `f` to stream `id` and wake any message waiting for a stream to start
+    fn update<F>(&self, id: u32, f: F) -> u32
+        where F: FnOnce(&mut PcmStream) -> u32
+    {
+        let (lock, cvar) = &*self.shared;
+        let mut streams = lock.lock().unwrap();
+        let status = match streams.get_mut(id as usize) {
+            Some(stream) => f(stream),
+            None => VIRTIO_SND_S_BAD_MSG,
+        };
+        cvar.notify_all();
+        status
+    }
+
+    pub fn set_params(&self, id: u32, params: PcmParams) -> u32 {
+        self.update(id, |stream| {
+            if stream.state == StreamState::Running {
+                return VIRTIO_SND_S_BAD_MSG;
+            }
+            stream.params = Some(params);
+            stream.state = StreamState::Idle;
+            VIRTIO_SND_S_OK
+        })
+    }
+
+    pub fn prepare(&self, id: u32) -> u32 {
+        self.update(id, |stream| {
+            if stream.params.is_none() || stream.state == StreamState::Running {
+                return VIRTIO_SND_S_BAD_MSG;
+            }
+            stream.state = StreamState::Prepared;
+            VIRTIO_SND_S_OK
+        })
+    }
+
+    pub fn start(&self, id: u32) -> u32 {
+        let backend = self.backend;
+        self.update(id, |stream| {
+            let params = match (stream.state, stream.params) {
+                (StreamState::Prepared, Some(params)) => params,
+                _ => return VIRTIO_SND_S_BAD_MSG,
+            };
+            match backend.spawn(stream.direction, &params) {
+                Ok((child, pipe)) => {
+                    stream.process = Some((child, Arc::new(pipe)));
+                    stream.state = StreamState::Running;
+                    VIRTIO_SND_S_OK
+                }
+                Err(e) => {
+                    warn!("virtio-snd: failed to start {} sound process: {}", backend.name(), e);
+                    VIRTIO_SND_S_IO_ERR
+                }
+            }
+        })
+    }
+
+    pub fn stop(&self, id: u32) -> u32 {
+        self.update(id, |stream| {
+            if stream.state != StreamState::Running {
+                return VIRTIO_SND_S_BAD_MSG;
+            }
+            stream.kill_process();
+            stream.state = StreamState::Prepared;
+            VIRTIO_SND_S_OK
+        })
+    }
+
+    pub fn release(&self, id: u32) -> u32 {
+        self.update(id, |stream| {
+            stream.kill_process();
+            stream.state = StreamState::Idle;
+            VIRTIO_SND_S_OK
+        })
+    }
+
+    /// Stop every stream and forget its parameters when the device is reset.
+    pub fn reset(&self) {
+        let (lock, cvar) = &*self.shared;
+        for stream in lock.lock().unwrap().iter_mut() {
+            stream.kill_process();
+            stream.params = None;
+            stream.state = StreamState::Idle;
+        }
+        cvar.notify_all();
+    }
+
+    // Wait while stream `id` is prepared and return the pipe to its process
+    // once it runs. Returns `Err` with a status if the message is completed
+    // without waiting.
+    fn wait_running(&self, id: u32, direction: Direction) -> Result<Arc<File>, u32> {
+        let (lock, cvar) = &*self.shared;
+        let mut streams = lock.lock().unwrap();
+        loop {
+            let stream = match streams.get(id as usize) {
+                Some(stream) if stream.direction == direction => stream,
+                _ => return Err(VIRTIO_SND_S_BAD_MSG),
+            };
+            match (stream.state, stream.pipe()) {
+                (StreamState::Running, Some(pipe)) => return Ok(pipe),
+                (StreamState::Prepared, _) => streams = cvar.wait(streams).unwrap(),
+                _ => return Err(VIRTIO_SND_S_OK),
+            }
+        }
+    }
+
+    // An error on the pipe is expected if the stream stopped meanwhile
+    fn io_status(&self, id: u32, pipe: &Arc<File>, err: ::std::io::Error) -> u32 {
+        let streams = self.shared.0.lock().unwrap();
+        match streams.get(id as usize).and_then(|s| s.pipe()) {
+            Some(ref current) if Arc::ptr_eq(current, pipe) => {
+                warn!("virtio-snd: sound process of stream {} failed: {}", id, err);
+                VIRTIO_SND_S_IO_ERR
+            }
+            _ => VIRTIO_SND_S_OK,
+        }
+    }
+
+    /// Play `data` on output stream `id`. Returns once the sound process has
+    /// taken the samples, which paces the guest.
+    pub fn write(&self, id: u32, data: &[u8]) -> u32 {
+        let pipe = match self.wait_running(id, Direction::Output) {
+            Ok(pipe) => pipe,
+            Err(status) => return status,
+        };
+        match (&*pipe).write_all(data) {
+            Ok(()) => VIRTIO_SND_S_OK,
+            Err(e) => self.io_status(id, &pipe, e),
+        }
+    }
+
+    /// Fill `buf` with samples recorded on input stream `id`, or with
+    /// silence if none could be read.
+    pub fn read(&self, id: u32, buf: &mut [u8]) -> u32 {
+        let pipe = match self.wait_running(id, Direction::Input) {
+            Ok(pipe) => pipe,
+            Err(status) => return status,
+        };
+        match (&*pipe).read_exact(buf) {
+            Ok(()) => VIRTIO_SND_S_OK,
+            Err(e) => {
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+                self.io_status(id, &pipe, e)
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use crate::memory::MemoryManager;
+use crate::virtio::{self, VirtioBus, VirtioDeviceOps, VirtQueue, Result, Error};
+use self::muxer::Muxer;
+
+mod muxer;
+mod packet;
+
+const VIRTIO_ID_VSOCK: u16 = 19;
+
+// The configuration is the 64 bit guest CID
+const VIRTIO_VSOCK_CONFIG_SIZE: usize = 8;
+
+///
+/// A virtio-vsock device which lets host processes and guest services talk
+/// over `AF_VSOCK` stream sockets without a network device.
+///
+/// Guest connections are carried to and from unix sockets on the host by a
+/// `Muxer` thread. Host processes connect to the socket at `socket_path`, and
+/// guest connections to the host go to sockets next to it.
+///
+pub struct VirtioSock {
+    guest_cid: u64,
+    socket_path: PathBuf,
+    listener: UnixListener,
+}
+
+impl VirtioSock {
+    pub fn create<P: AsRef<Path>>(vbus: &mut VirtioBus, guest_cid: u64, socket_path: P) -> Result<()> {
+        let socket_path = socket_path.as_ref().to_path_buf();
+        if socket_path.exists() {
+            fs::remove_file(&socket_path).map_err(Error::DeviceSocket)?;
+        }
+        let listener = UnixListener::bind(&socket_path).map_err(Error::DeviceSocket)?;
+        listener.set_nonblocking(true).map_err(Error::DeviceSocket)?;
+        let dev = Arc::new(RwLock::new(VirtioSock { guest_cid, socket_path, listener }));
+        vbus.new_virtio_device(VIRTIO_ID_VSOCK, dev)
+            .set_num_queues(3)
+            .set_config_size(VIRTIO_VSOCK_CONFIG_SIZE)
+            .register()
+    }
+}
+
+impl VirtioDeviceOps for VirtioSock {
+    fn read_config(&mut self, offset: usize, size: usize) -> u64 {
+        virtio::read_config_buffer(&self.guest_cid.to_le_bytes(), offset, size)
+    }
+
+    fn start(&mut self, _: &MemoryManager, mut queues: Vec<VirtQueue>) {
+        let listener = match self.listener.try_clone() {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Error starting virtio-vsock device: {}", e);
+                return;
+            }
+        };
+        let event_vq = queues.pop().unwrap();
+        let tx_vq = queues.pop().unwrap();
+        let rx_vq = queues.pop().unwrap();
+        let guest_cid = self.guest_cid;
+        let socket_path = self.socket_path.clone();
+        thread::spawn(move || {
+            let result = Muxer::new(guest_cid, socket_path, listener, rx_vq, tx_vq, event_vq)
+                .and_then(|mut muxer| muxer.run());
+            if let Err(e) = result {
+                warn!("Error running virtio-vsock device: {}", e);
+            }
+        });
+    }
+}
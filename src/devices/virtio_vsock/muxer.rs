@@ -0,0 +1,645 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str;
+
+use libc::{EPOLLIN, EPOLLOUT};
+
+use crate::system::EPoll;
+use crate::virtio::{Chain, VirtQueue};
+use crate::devices::virtio_vsock::packet::*;
+
+pub const VSOCK_HOST_CID: u64 = 2;
+
+// Stream data from the guest which may be waiting to be written to the host
+// on each connection. Advertised to the guest as buf_alloc.
+const BUF_ALLOC: u32 = 256 * 1024;
+
+// Largest amount of host data sent to the guest in one packet
+const MAX_PACKET_DATA: usize = 64 * 1024;
+
+// Local ports of connections from the host are allocated from here up
+const FIRST_HOST_PORT: u32 = 1 << 30;
+
+// A host client which sends a longer line than this is disconnected
+const MAX_CONNECT_LINE: usize = 32;
+
+enum State {
+    // A host client has connected and has not yet sent "CONNECT <port>"
+    HostConnecting,
+    // A request has been sent to the guest for a host client
+    AwaitingResponse,
+    Established,
+}
+
+struct Connection {
+    stream: UnixStream,
+    state: State,
+    local_port: u32,
+    peer_port: u32,
+    // Events the stream is registered with epoll for, or 0 if not registered
+    interest: u32,
+    // Data from the guest which has not yet been written to the host
+    tx_buf: Vec<u8>,
+    // Data from the host which was read along with the connect line
+    rx_buf: Vec<u8>,
+    // Bytes from the guest written to the host, and the count last sent to the guest
+    fwd_cnt: u32,
+    fwd_cnt_sent: u32,
+    // Bytes sent to the guest, and the guest receive buffer it last reported
+    rx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    // The guest will send no more data, so shut down the host stream once
+    // tx_buf has been written.
+    peer_shutdown: bool,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, state: State, local_port: u32, peer_port: u32) -> Self {
+        Connection {
+            stream, state, local_port, peer_port,
+            interest: 0,
+            tx_buf: Vec::new(),
+            rx_buf: Vec::new(),
+            fwd_cnt: 0,
+            fwd_cnt_sent: 0,
+            rx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            peer_shutdown: false,
+        }
+    }
+
+    fn key(&self) -> (u32, u32) {
+        (self.local_port, self.peer_port)
+    }
+
+    // Every packet sent to the guest reports the free space in tx_buf
+    fn header(&mut self, guest_cid: u64, op: u16) -> PacketHeader {
+        self.fwd_cnt_sent = self.fwd_cnt;
+        PacketHeader {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: guest_cid,
+            src_port: self.local_port,
+            dst_port: self.peer_port,
+            ptype: VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt: self.fwd_cnt,
+            ..Default::default()
+        }
+    }
+
+    fn update_peer_credit(&mut self, hdr: &PacketHeader) {
+        self.peer_buf_alloc = hdr.buf_alloc;
+        self.peer_fwd_cnt = hdr.fwd_cnt;
+    }
+
+    // How many more bytes the guest has room to receive
+    fn peer_credit(&self) -> usize {
+        let in_flight = self.rx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight) as usize
+    }
+
+    // Queue data from the guest to be written to the host. A guest which sends
+    // more than the buf_alloc it was given could otherwise grow tx_buf without
+    // limit, so the data is refused.
+    fn queue_tx(&mut self, data: &[u8]) -> bool {
+        if self.tx_buf.len() + data.len() > BUF_ALLOC as usize {
+            return false;
+        }
+        self.tx_buf.extend_from_slice(data);
+        true
+    }
+
+    // The guest should be told about space freed in tx_buf before it runs out
+    fn needs_credit_update(&self) -> bool {
+        self.fwd_cnt.wrapping_sub(self.fwd_cnt_sent) >= BUF_ALLOC / 2
+    }
+
+    fn wanted_events(&self, paused: bool) -> u32 {
+        let events = match self.state {
+            State::HostConnecting => EPOLLIN,
+            State::AwaitingResponse => 0,
+            State::Established => {
+                let mut events = 0;
+                if !paused {
+                    events |= EPOLLIN;
+                }
+                if !self.tx_buf.is_empty() {
+                    events |= EPOLLOUT;
+                }
+                events
+            }
+        };
+        events as u32
+    }
+
+    // Write as much of tx_buf to the host as it will take without blocking
+    fn flush_tx(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        while written < self.tx_buf.len() {
+            match self.stream.write(&self.tx_buf[written..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        self.tx_buf.drain(..written);
+        self.fwd_cnt = self.fwd_cnt.wrapping_add(written as u32);
+        if self.tx_buf.is_empty() && self.peer_shutdown {
+            self.stream.shutdown(Shutdown::Write)?;
+        }
+        Ok(())
+    }
+
+    // Read host data for the guest, starting with anything left over from
+    // the connect line.
+    fn read_host(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.rx_buf.is_empty() {
+            return self.stream.read(buf);
+        }
+        let n = buf.len().min(self.rx_buf.len());
+        buf[..n].copy_from_slice(&self.rx_buf[..n]);
+        self.rx_buf.drain(..n);
+        Ok(n)
+    }
+
+    // Read "CONNECT <port>\n" from a host client. Returns `None` until the
+    // whole line has arrived.
+    fn read_connect_line(&mut self) -> io::Result<Option<u32>> {
+        let mut buf = [0u8; MAX_CONNECT_LINE];
+        let avail = MAX_CONNECT_LINE - self.rx_buf.len();
+        let n = match self.stream.read(&mut buf[..avail]) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.rx_buf.extend_from_slice(&buf[..n]);
+        let end = match self.rx_buf.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None if self.rx_buf.len() < MAX_CONNECT_LINE => return Ok(None),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "connect line is too long")),
+        };
+        let port = parse_connect_line(&self.rx_buf[..end])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "expected CONNECT <port>"))?;
+        self.rx_buf.drain(..=end);
+        Ok(Some(port))
+    }
+}
+
+fn parse_connect_line(line: &[u8]) -> Option<u32> {
+    let line = str::from_utf8(line).ok()?;
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("CONNECT"), Some(port), None) => port.parse().ok(),
+        _ => None,
+    }
+}
+
+///
+/// Carries the packets of every vsock connection between the guest and
+/// host unix sockets on a single thread.
+///
+/// A host process reaches a guest service by connecting to the device socket
+/// and sending `CONNECT <port>\n`. Once the guest accepts, the muxer replies
+/// `OK <local port>\n` and the socket carries the stream. If the guest
+/// refuses the connection the socket is closed.
+///
+/// When the guest connects to port P of the host (CID 2), the muxer connects
+/// to the unix socket `<socket path>_P`, and resets the guest connection if
+/// nothing is listening there.
+///
+pub struct Muxer {
+    guest_cid: u64,
+    socket_path: PathBuf,
+    listener: UnixListener,
+    rx_vq: VirtQueue,
+    tx_vq: VirtQueue,
+    event_vq: VirtQueue,
+    epoll: EPoll,
+    connections: HashMap<u64, Connection>,
+    ports: HashMap<(u32, u32), u64>,
+    // Packets without data waiting for a buffer on the rx queue
+    control: VecDeque<PacketHeader>,
+    // Connections with host data ready for the guest. Their streams are not
+    // polled for input until the data has been sent.
+    readable: BTreeSet<u64>,
+    // A buffer taken from the rx queue which has not been filled
+    rx_chain: Option<Chain>,
+    next_id: u64,
+    next_port: u32,
+}
+
+impl Muxer {
+    const RX_TOKEN: u64 = 0;
+    const TX_TOKEN: u64 = 1;
+    const EVENT_TOKEN: u64 = 2;
+    const LISTENER_TOKEN: u64 = 3;
+    // Connections are polled with their id as the token
+    const FIRST_CONNECTION_ID: u64 = 4;
+
+    pub fn new(guest_cid: u64, socket_path: PathBuf, listener: UnixListener, rx_vq: VirtQueue, tx_vq: VirtQueue, event_vq: VirtQueue) -> io::Result<Self> {
+        let epoll = EPoll::new()?;
+        epoll.add_read(rx_vq.ioevent().as_raw_fd(), Self::RX_TOKEN)?;
+        epoll.add_read(tx_vq.ioevent().as_raw_fd(), Self::TX_TOKEN)?;
+        epoll.add_read(event_vq.ioevent().as_raw_fd(), Self::EVENT_TOKEN)?;
+        epoll.add_read(listener.as_raw_fd(), Self::LISTENER_TOKEN)?;
+        Ok(Muxer {
+            guest_cid, socket_path, listener, rx_vq, tx_vq, event_vq, epoll,
+            connections: HashMap::new(),
+            ports: HashMap::new(),
+            control: VecDeque::new(),
+            readable: BTreeSet::new(),
+            rx_chain: None,
+            next_id: Self::FIRST_CONNECTION_ID,
+            next_port: FIRST_HOST_PORT,
+        })
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let events = self.epoll.wait()?;
+            for ev in events.iter() {
+                match ev.id() {
+                    Self::RX_TOKEN => {
+                        // New buffers are filled by process_rx() below
+                        self.rx_vq.read_notifications()?;
+                    },
+                    Self::TX_TOKEN => {
+                        self.tx_vq.read_notifications()?;
+                        self.process_tx();
+                    },
+                    Self::EVENT_TOKEN => {
+                        // Only used for transport reset events, which are never sent
+                        self.event_vq.read_notifications()?;
+                    },
+                    Self::LISTENER_TOKEN => self.accept(),
+                    id => self.connection_ready(id, ev.is_readable() || ev.is_hangup(), ev.is_writable()),
+                }
+            }
+            self.process_rx();
+        }
+    }
+
+    fn accept(&mut self) {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                warn!("virtio_vsock: error accepting connection: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = stream.set_nonblocking(true) {
+            warn!("virtio_vsock: error accepting connection: {}", e);
+            return;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.connections.insert(id, Connection::new(stream, State::HostConnecting, 0, 0));
+        self.update_interest(id);
+    }
+
+    fn connection_ready(&mut self, id: u64, readable: bool, writable: bool) {
+        let connecting = match self.connections.get(&id) {
+            Some(conn) => match conn.state {
+                State::HostConnecting => true,
+                _ => false,
+            },
+            None => return,
+        };
+        if writable {
+            self.flush_tx(id);
+        }
+        if readable {
+            if connecting {
+                self.read_connect_line(id);
+            } else {
+                self.readable.insert(id);
+            }
+        }
+        self.update_interest(id);
+    }
+
+    fn read_connect_line(&mut self, id: u64) {
+        let result = match self.connections.get_mut(&id) {
+            Some(conn) => conn.read_connect_line(),
+            None => return,
+        };
+        let peer_port = match result {
+            Ok(Some(port)) => port,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("virtio_vsock: closing host connection: {}", e);
+                self.remove(id);
+                return;
+            }
+        };
+        let local_port = self.allocate_port(peer_port);
+        let guest_cid = self.guest_cid;
+        if let Some(conn) = self.connections.get_mut(&id) {
+            conn.local_port = local_port;
+            conn.peer_port = peer_port;
+            conn.state = State::AwaitingResponse;
+            self.control.push_back(conn.header(guest_cid, VSOCK_OP_REQUEST));
+            self.ports.insert(conn.key(), id);
+        }
+    }
+
+    fn allocate_port(&mut self, peer_port: u32) -> u32 {
+        loop {
+            let port = self.next_port;
+            self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_HOST_PORT);
+            if !self.ports.contains_key(&(port, peer_port)) {
+                return port;
+            }
+        }
+    }
+
+    fn process_tx(&mut self) {
+        while let Some(mut chain) = self.tx_vq.next_chain() {
+            let hdr = match PacketHeader::read(&mut chain) {
+                Ok(hdr) => hdr,
+                Err(e) => {
+                    warn!("virtio_vsock: error reading packet header: {}", e);
+                    continue;
+                }
+            };
+            let mut data = vec![0u8; (hdr.len as usize).min(chain.remaining_read())];
+            if let Err(e) = chain.read_exact(&mut data) {
+                warn!("virtio_vsock: error reading packet: {}", e);
+                continue;
+            }
+            self.handle_packet(hdr, &data);
+        }
+    }
+
+    fn handle_packet(&mut self, hdr: PacketHeader, data: &[u8]) {
+        if hdr.src_cid != self.guest_cid || hdr.dst_cid != VSOCK_HOST_CID || hdr.ptype != VSOCK_TYPE_STREAM {
+            self.reset_reply(&hdr);
+            return;
+        }
+        let key = (hdr.dst_port, hdr.src_port);
+        if hdr.op == VSOCK_OP_REQUEST {
+            if self.ports.contains_key(&key) {
+                self.reset_reply(&hdr);
+            } else {
+                self.connect_host(&hdr);
+            }
+            return;
+        }
+        let id = match self.ports.get(&key) {
+            Some(&id) => id,
+            None => {
+                self.reset_reply(&hdr);
+                return;
+            }
+        };
+        if let Some(conn) = self.connections.get_mut(&id) {
+            conn.update_peer_credit(&hdr);
+        }
+        match hdr.op {
+            VSOCK_OP_RESPONSE => self.established(id),
+            VSOCK_OP_RW => {
+                let queued = self.connections.get_mut(&id)
+                    .map_or(true, |conn| conn.queue_tx(data));
+                if queued {
+                    self.flush_tx(id);
+                } else {
+                    warn!("virtio_vsock: guest port {} sent more data than its credit allows", hdr.src_port);
+                    self.reset(id);
+                }
+            },
+            VSOCK_OP_CREDIT_REQUEST => self.credit_update(id),
+            // process_rx() sends more data if the guest now has room for it
+            VSOCK_OP_CREDIT_UPDATE => {},
+            VSOCK_OP_SHUTDOWN if hdr.flags & VSOCK_SHUTDOWN_BOTH == VSOCK_SHUTDOWN_BOTH => self.reset(id),
+            VSOCK_OP_SHUTDOWN => {
+                if hdr.flags & VSOCK_SHUTDOWN_SEND != 0 {
+                    if let Some(conn) = self.connections.get_mut(&id) {
+                        conn.peer_shutdown = true;
+                    }
+                    self.flush_tx(id);
+                }
+            },
+            VSOCK_OP_RST => self.remove(id),
+            op => {
+                warn!("virtio_vsock: unexpected packet op {}", op);
+                self.reset(id);
+            },
+        }
+        self.update_interest(id);
+    }
+
+    // The guest connected to a port on the host
+    fn connect_host(&mut self, hdr: &PacketHeader) {
+        let mut path = self.socket_path.clone().into_os_string();
+        path.push(format!("_{}", hdr.dst_port));
+        let stream = match UnixStream::connect(&path).and_then(|s| s.set_nonblocking(true).map(|_| s)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("virtio_vsock: refusing connection to host port {}: {}", hdr.dst_port, e);
+                self.reset_reply(hdr);
+                return;
+            }
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut conn = Connection::new(stream, State::Established, hdr.dst_port, hdr.src_port);
+        conn.update_peer_credit(hdr);
+        self.control.push_back(conn.header(self.guest_cid, VSOCK_OP_RESPONSE));
+        self.ports.insert(conn.key(), id);
+        self.connections.insert(id, conn);
+        self.update_interest(id);
+    }
+
+    // The guest accepted a connection from a host client
+    fn established(&mut self, id: u64) {
+        let result = match self.connections.get_mut(&id) {
+            Some(conn) => match conn.state {
+                State::AwaitingResponse => {
+                    conn.state = State::Established;
+                    let reply = format!("OK {}\n", conn.local_port);
+                    conn.stream.write_all(reply.as_bytes())
+                },
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected response")),
+            },
+            None => return,
+        };
+        match result {
+            // Send anything which was read along with the connect line
+            Ok(()) => { self.readable.insert(id); },
+            Err(e) => {
+                debug!("virtio_vsock: resetting connection: {}", e);
+                self.reset(id);
+            },
+        }
+    }
+
+    fn flush_tx(&mut self, id: u64) {
+        let result = match self.connections.get_mut(&id) {
+            Some(conn) => conn.flush_tx().map(|_| conn.needs_credit_update()),
+            None => return,
+        };
+        match result {
+            Ok(true) => self.credit_update(id),
+            Ok(false) => {},
+            Err(e) => {
+                debug!("virtio_vsock: error writing to host connection: {}", e);
+                self.reset(id);
+            }
+        }
+    }
+
+    fn credit_update(&mut self, id: u64) {
+        let guest_cid = self.guest_cid;
+        if let Some(conn) = self.connections.get_mut(&id) {
+            self.control.push_back(conn.header(guest_cid, VSOCK_OP_CREDIT_UPDATE));
+        }
+    }
+
+    // Reply to a packet which does not belong to a connection
+    fn reset_reply(&mut self, hdr: &PacketHeader) {
+        if hdr.op != VSOCK_OP_RST {
+            self.control.push_back(hdr.reply(VSOCK_OP_RST));
+        }
+    }
+
+    fn reset(&mut self, id: u64) {
+        self.send_close(id, VSOCK_OP_RST, 0);
+    }
+
+    fn send_close(&mut self, id: u64, op: u16, flags: u32) {
+        let guest_cid = self.guest_cid;
+        if let Some(conn) = self.connections.get_mut(&id) {
+            // The guest has not been told about a host client which has not
+            // sent its connect line
+            let known_to_guest = match conn.state {
+                State::HostConnecting => false,
+                _ => true,
+            };
+            if known_to_guest {
+                let mut hdr = conn.header(guest_cid, op);
+                hdr.flags = flags;
+                self.control.push_back(hdr);
+            }
+        }
+        self.remove(id);
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(conn) = self.connections.remove(&id) {
+            if conn.interest != 0 {
+                let _ = self.epoll.delete(conn.stream.as_raw_fd());
+            }
+            self.ports.remove(&conn.key());
+        }
+        self.readable.remove(&id);
+    }
+
+    fn update_interest(&mut self, id: u64) {
+        let paused = self.readable.contains(&id);
+        let conn = match self.connections.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let events = conn.wanted_events(paused);
+        if events == conn.interest {
+            return;
+        }
+        let fd = conn.stream.as_raw_fd();
+        let result = if conn.interest == 0 {
+            self.epoll.add(fd, events, id)
+        } else if events == 0 {
+            self.epoll.delete(fd)
+        } else {
+            self.epoll.modify(fd, events, id)
+        };
+        match result {
+            Ok(()) => conn.interest = events,
+            Err(e) => warn!("virtio_vsock: failed to poll host connection: {}", e),
+        }
+    }
+
+    fn take_rx_chain(&mut self) -> Option<Chain> {
+        self.rx_chain.take().or_else(|| self.rx_vq.next_chain())
+    }
+
+    // Fill rx queue buffers with control packets and then with host data for
+    // connections which the guest has room for.
+    fn process_rx(&mut self) {
+        loop {
+            if let Some(hdr) = self.control.pop_front() {
+                match self.take_rx_chain() {
+                    Some(mut chain) => if let Err(e) = hdr.write(&mut chain) {
+                        warn!("virtio_vsock: error writing packet: {}", e);
+                    },
+                    None => {
+                        self.control.push_front(hdr);
+                        return;
+                    }
+                }
+                continue;
+            }
+            let connections = &self.connections;
+            let id = match self.readable.iter()
+                .find(|id| connections.get(id).map(|c| c.peer_credit() > 0).unwrap_or(false)) {
+                Some(&id) => id,
+                None => return,
+            };
+            let chain = match self.take_rx_chain() {
+                Some(chain) => chain,
+                None => return,
+            };
+            self.send_host_data(id, chain);
+        }
+    }
+
+    fn send_host_data(&mut self, id: u64, mut chain: Chain) {
+        let guest_cid = self.guest_cid;
+        let conn = match self.connections.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let size = chain.remaining_write().saturating_sub(HDR_SIZE)
+            .min(MAX_PACKET_DATA)
+            .min(conn.peer_credit());
+        if size == 0 {
+            warn!("virtio_vsock: rx queue buffer is too small");
+            return;
+        }
+        let mut data = vec![0u8; size];
+        match conn.read_host(&mut data) {
+            Ok(0) => {
+                self.rx_chain = Some(chain);
+                self.send_close(id, VSOCK_OP_SHUTDOWN, VSOCK_SHUTDOWN_BOTH);
+            },
+            Ok(n) => {
+                let mut hdr = conn.header(guest_cid, VSOCK_OP_RW);
+                hdr.len = n as u32;
+                conn.rx_cnt = conn.rx_cnt.wrapping_add(n as u32);
+                if let Err(e) = hdr.write(&mut chain).and_then(|_| chain.write_all(&data[..n])) {
+                    warn!("virtio_vsock: error writing packet: {}", e);
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.rx_chain = Some(chain);
+                self.readable.remove(&id);
+                self.update_interest(id);
+            },
+            Err(e) => {
+                self.rx_chain = Some(chain);
+                debug!("virtio_vsock: error reading from host connection: {}", e);
+                self.reset(id);
+            }
+        }
+    }
+}
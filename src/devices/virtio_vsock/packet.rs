@@ -0,0 +1,82 @@
+use std::io;
+
+use crate::virtio::Chain;
+
+// Size of struct virtio_vsock_hdr
+pub const HDR_SIZE: usize = 44;
+
+pub const VSOCK_TYPE_STREAM: u16 = 1;
+
+pub const VSOCK_OP_REQUEST: u16 = 1;
+pub const VSOCK_OP_RESPONSE: u16 = 2;
+pub const VSOCK_OP_RST: u16 = 3;
+pub const VSOCK_OP_SHUTDOWN: u16 = 4;
+pub const VSOCK_OP_RW: u16 = 5;
+pub const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+pub const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+// Flags of VSOCK_OP_SHUTDOWN
+pub const VSOCK_SHUTDOWN_RCV: u32 = 1;
+pub const VSOCK_SHUTDOWN_SEND: u32 = 2;
+pub const VSOCK_SHUTDOWN_BOTH: u32 = VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND;
+
+///
+/// The header at the start of every packet on the rx and tx queues. For a
+/// `VSOCK_OP_RW` packet, `len` bytes of stream data follow the header.
+///
+#[derive(Clone,Copy,Default,Debug)]
+pub struct PacketHeader {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub len: u32,
+    pub ptype: u16,
+    pub op: u16,
+    pub flags: u32,
+    pub buf_alloc: u32,
+    pub fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    pub fn read(chain: &mut Chain) -> io::Result<Self> {
+        Ok(PacketHeader {
+            src_cid: chain.r64()?,
+            dst_cid: chain.r64()?,
+            src_port: chain.r32()?,
+            dst_port: chain.r32()?,
+            len: chain.r32()?,
+            ptype: chain.r16()?,
+            op: chain.r16()?,
+            flags: chain.r32()?,
+            buf_alloc: chain.r32()?,
+            fwd_cnt: chain.r32()?,
+        })
+    }
+
+    pub fn write(&self, chain: &mut Chain) -> io::Result<()> {
+        chain.w64(self.src_cid)?;
+        chain.w64(self.dst_cid)?;
+        chain.w32(self.src_port)?;
+        chain.w32(self.dst_port)?;
+        chain.w32(self.len)?;
+        chain.w16(self.ptype)?;
+        chain.w16(self.op)?;
+        chain.w32(self.flags)?;
+        chain.w32(self.buf_alloc)?;
+        chain.w32(self.fwd_cnt)
+    }
+
+    /// A reply to this packet with the source and destination swapped.
+    pub fn reply(&self, op: u16) -> Self {
+        PacketHeader {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            ptype: VSOCK_TYPE_STREAM,
+            op,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::devices::virtio_wl::consts::*;
+use crate::system::{self, EventFd};
+use crate::util::JsonValue;
+use crate::util::debug_flags::{self, DebugFlag};
+
+// How long to wait for the device thread to answer a status request
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Bytes of message data shown when messages are dumped
+const DUMP_BYTES: usize = 64;
+
+///
+/// Handle for requesting a status snapshot from the virtio_wl device thread.
+///
+/// The VFD table is owned by the device thread, so a request is made by
+/// signaling an eventfd that the device polls, and the device thread
+/// stores the snapshot and wakes up the requester.
+///
+#[derive(Clone)]
+pub struct WaylandDebug {
+    request_evt: Arc<EventFd>,
+    response: Arc<(Mutex<Option<JsonValue>>, Condvar)>,
+}
+
+impl WaylandDebug {
+    pub fn new() -> system::Result<Self> {
+        let request_evt = EventFd::new_nonblocking()?;
+        Ok(WaylandDebug {
+            request_evt: Arc::new(request_evt),
+            response: Arc::new((Mutex::new(None), Condvar::new())),
+        })
+    }
+
+    pub fn request_fd(&self) -> RawFd {
+        self.request_evt.as_raw_fd()
+    }
+
+    /// Called from the device thread when the request eventfd is readable.
+    pub fn complete_request(&self, status: JsonValue) {
+        let _ = self.request_evt.try_read();
+        let (lock, cvar) = &*self.response;
+        *lock.lock().unwrap() = Some(status);
+        cvar.notify_all();
+    }
+
+    /// Request a status snapshot from the device thread, returning
+    /// an error message if the device does not respond.
+    pub fn status(&self) -> ::std::result::Result<JsonValue, String> {
+        let (lock, cvar) = &*self.response;
+        let mut response = lock.lock().unwrap();
+        *response = None;
+        self.request_evt.write(1)
+            .map_err(|e| format!("failed to signal wayland device: {}", e))?;
+
+        while response.is_none() {
+            let (guard, timeout) = cvar.wait_timeout(response, STATUS_TIMEOUT).unwrap();
+            response = guard;
+            if timeout.timed_out() {
+                return Err("wayland device did not respond (not started?)".to_string());
+            }
+        }
+        Ok(response.take().unwrap())
+    }
+}
+
+fn message_name(msg_type: u32) -> &'static str {
+    match msg_type {
+        VIRTIO_WL_CMD_VFD_NEW => "VFD_NEW",
+        VIRTIO_WL_CMD_VFD_CLOSE => "VFD_CLOSE",
+        VIRTIO_WL_CMD_VFD_SEND => "VFD_SEND",
+        VIRTIO_WL_CMD_VFD_RECV => "VFD_RECV",
+        VIRTIO_WL_CMD_VFD_NEW_CTX => "VFD_NEW_CTX",
+        VIRTIO_WL_CMD_VFD_NEW_PIPE => "VFD_NEW_PIPE",
+        VIRTIO_WL_CMD_VFD_HUP => "VFD_HUP",
+        VIRTIO_WL_CMD_VFD_NEW_DMABUF => "VFD_NEW_DMABUF",
+        VIRTIO_WL_CMD_VFD_DMABUF_SYNC => "VFD_DMABUF_SYNC",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Log a message exchanged with the guest if the `wl` debug flag is set,
+/// with the start of its data in hex.
+pub fn dump_message(to_guest: bool, msg_type: u32, vfd_id: u32, data: &[u8]) {
+    if !debug_flags::is_enabled(DebugFlag::Wayland) {
+        return;
+    }
+    let hex: Vec<String> = data.iter().take(DUMP_BYTES).map(|b| format!("{:02x}", b)).collect();
+    notify!("virtio_wl: {} {}({}) vfd={} len={} [{}{}]",
+            if to_guest { "->" } else { "<-" },
+            message_name(msg_type), msg_type, vfd_id, data.len(),
+            hex.join(" "), if data.len() > DUMP_BYTES { " ..." } else { "" });
+}
@@ -3,11 +3,13 @@ use std::sync::{RwLock, Arc};
 use std::thread;
 
 use crate::{system, virtio};
-use crate::system::{EPoll,EventFd};
-use crate::memory::{MemoryManager, DrmDescriptor};
+use crate::system::{EPoll,EventFd,seccomp,SeccompProfile};
+use crate::memory::{MemoryManager, DrmDescriptor, SharedMemoryRegion};
 use crate::virtio::{VirtQueue, VirtioBus, VirtioDeviceOps, Chain};
 
-use crate::devices::virtio_wl::{vfd::VfdManager, consts::*, Error, Result, VfdObject};
+use crate::devices::virtio_wl::{vfd::VfdManager, consts::*, Error, Result, VfdObject, WaylandDebug, dump_message};
+use crate::devices::virtio_wl::watch::{CompositorWatch, WATCH_INTERVAL};
+use crate::vm::events;
 use crate::system::ioctl::ioctl_with_ref;
 use std::os::raw::{c_ulong, c_uint, c_ulonglong};
 
@@ -20,28 +22,40 @@ const DMA_BUF_IOCTL_SYNC: c_ulong = iow!(DMA_BUF_IOCTL_BASE, 0, ::std::mem::size
 
 pub struct VirtioWayland {
     feature_bits: u64,
+    shm: SharedMemoryRegion,
+    debug: WaylandDebug,
 }
 
 impl VirtioWayland {
-    fn new() -> Self {
-        VirtioWayland { feature_bits: 0 }
-    }
-
-    pub fn create(vbus: &mut VirtioBus) -> virtio::Result<()> {
-        let dev = Arc::new(RwLock::new(VirtioWayland::new()));
+    fn new(shm: SharedMemoryRegion, debug: WaylandDebug) -> Self {
+        VirtioWayland { feature_bits: 0, shm, debug }
+    }
+
+    /// Create the device and return a handle which can be used to inspect
+    /// the device state once it is running.
+    pub fn create(vbus: &mut VirtioBus) -> virtio::Result<WaylandDebug> {
+        let debug = WaylandDebug::new()
+            .map_err(virtio::Error::CreateEventFd)?;
+        let shm = vbus.memory().create_shared_region(VIRTIO_WL_SHMEM_ID, "virtio-wl", VIRTIO_WL_SHMEM_SIZE)
+            .map_err(virtio::Error::CreateSharedRegion)?;
+        // Buffers hold memory of the guest rather than files of the host
+        shm.set_scrub(true);
+        let dev = Arc::new(RwLock::new(VirtioWayland::new(shm.clone(), debug.clone())));
         vbus.new_virtio_device(VIRTIO_ID_WL, dev)
             .set_num_queues(2)
             .set_features(VIRTIO_WL_F_TRANS_FLAGS as u64)
-            .register()
+            .add_shared_memory(&shm)
+            .register()?;
+        Ok(debug)
     }
 
     fn transition_flags(&self) -> bool {
         self.feature_bits & VIRTIO_WL_F_TRANS_FLAGS as u64 != 0
     }
 
-    fn create_device(memory: MemoryManager, in_vq: VirtQueue, out_vq: VirtQueue, transition: bool) -> Result<WaylandDevice> {
+    fn create_device(memory: MemoryManager, shm: SharedMemoryRegion, in_vq: VirtQueue, out_vq: VirtQueue, transition: bool, debug: WaylandDebug) -> Result<WaylandDevice> {
         let kill_evt = EventFd::new().map_err(Error::EventFdCreate)?;
-        let dev = WaylandDevice::new(memory, in_vq, out_vq, kill_evt, transition)?;
+        let dev = WaylandDevice::new(memory, shm, in_vq, out_vq, kill_evt, transition, debug)?;
         Ok(dev)
     }
 }
@@ -55,17 +69,22 @@ impl VirtioDeviceOps for VirtioWayland {
     fn start(&mut self, memory: &MemoryManager, mut queues: Vec<VirtQueue>) {
         thread::spawn({
             let memory = memory.clone();
+            let shm = self.shm.clone();
             let transition = self.transition_flags();
+            let debug = self.debug.clone();
             move || {
                 let out_vq = queues.pop().unwrap();
                 let in_vq = queues.pop().unwrap();
-                let mut dev = match Self::create_device(memory.clone(), in_vq, out_vq,transition) {
+                let mut dev = match Self::create_device(memory.clone(), shm, in_vq, out_vq,transition, debug) {
                     Err(e) => {
                         warn!("Error creating virtio wayland device: {}", e);
                         return;
                     }
                     Ok(dev) => dev,
                 };
+                if let Err(e) = seccomp::sandbox_thread(SeccompProfile::Wayland) {
+                    warn!("Failed to install seccomp filter for virtio wayland device: {}", e);
+                }
                 if let Err(e) = dev.run() {
                     warn!("Error running virtio-wl device: {}", e);
                 };
@@ -76,8 +95,10 @@ impl VirtioDeviceOps for VirtioWayland {
 
 struct WaylandDevice {
     vfd_manager: VfdManager,
+    watch: CompositorWatch,
     out_vq: VirtQueue,
     kill_evt: EventFd,
+    debug: WaylandDebug,
 }
 
 impl WaylandDevice {
@@ -85,13 +106,17 @@ impl WaylandDevice {
     const OUT_VQ_TOKEN:u64 = 1;
     const KILL_TOKEN: u64 = 2;
     const VFDS_TOKEN: u64 = 3;
+    const DEBUG_TOKEN: u64 = 4;
 
-    fn new(mm: MemoryManager, in_vq: VirtQueue, out_vq: VirtQueue, kill_evt: EventFd, use_transition: bool) -> Result<Self> {
-        let vfd_manager = VfdManager::new(mm, use_transition, in_vq, "/run/user/1000/wayland-0")?;
+    fn new(mm: MemoryManager, shm: SharedMemoryRegion, in_vq: VirtQueue, out_vq: VirtQueue, kill_evt: EventFd, use_transition: bool, debug: WaylandDebug) -> Result<Self> {
+        let vfd_manager = VfdManager::new(mm, shm, use_transition, in_vq, "/run/user/1000/wayland-0")?;
+        let watch = CompositorWatch::new(vfd_manager.wayland_path());
         Ok(WaylandDevice {
             vfd_manager,
+            watch,
             out_vq,
-            kill_evt
+            kill_evt,
+            debug,
         })
     }
 
@@ -109,13 +134,17 @@ impl WaylandDevice {
         poll.add_read(self.out_vq.ioevent().as_raw_fd(), Self::OUT_VQ_TOKEN as u64)?;
         poll.add_read(self.kill_evt.as_raw_fd(), Self::KILL_TOKEN as u64)?;
         poll.add_read(self.vfd_manager.poll_fd(), Self::VFDS_TOKEN as u64)?;
+        poll.add_read(self.debug.request_fd(), Self::DEBUG_TOKEN as u64)?;
         Ok(poll)
     }
     fn run(&mut self) -> Result<()> {
         let mut poll = self.setup_poll().map_err(Error::FailedPollContextCreate)?;
 
         'poll: loop {
-            let events = match poll.wait() {
+            if self.watch.check() {
+                self.compositor_restarted();
+            }
+            let events = match poll.wait_timeout(WATCH_INTERVAL) {
                 Ok(v) => v,
                 Err(e) => {
                     warn!("virtio_wl: error waiting for poll events: {}", e);
@@ -146,12 +175,24 @@ impl WaylandDevice {
                     },
                     Self::KILL_TOKEN => break 'poll,
                     Self::VFDS_TOKEN => self.vfd_manager.process_poll_events(),
+                    Self::DEBUG_TOKEN => self.debug.complete_request(self.vfd_manager.debug_status()),
                     _ =>  warn!("virtio_wl: unexpected poll token value"),
                 }
             };
         }
         Ok(())
     }
+
+    // Connections to the old compositor are closed, and the guest agent is
+    // asked to restart the services which made them so that they connect
+    // to the new one.
+    fn compositor_restarted(&mut self) {
+        let closed = self.vfd_manager.hangup_sockets();
+        notify!("Wayland compositor restarted, closed {} guest connections", closed);
+        if !events::send_command("restart-wayland") {
+            warn!("Could not ask the guest to restart its wayland services, the agent port is not ready");
+        }
+    }
 }
 
 struct MessageHandler<'a> {
@@ -170,6 +211,9 @@ impl <'a> MessageHandler<'a> {
         let msg_type = self.chain.r32()?;
         // Flags are always zero
         let _flags = self.chain.r32()?;
+        let body = self.chain.current_read_slice();
+        let vfd_id = body.get(..4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).unwrap_or(0);
+        dump_message(false, msg_type, vfd_id, body.get(4..).unwrap_or(&[]));
         match msg_type {
             VIRTIO_WL_CMD_VFD_NEW => self.cmd_new_alloc(),
             VIRTIO_WL_CMD_VFD_CLOSE => self.cmd_close(),
@@ -284,6 +328,7 @@ impl <'a> MessageHandler<'a> {
 
         let send_fds = self.read_vfd_ids()?;
         let data = self.chain.current_read_slice();
+        let len = data.len();
 
         let vfd = match self.device.get_mut_vfd(id) {
             Some(vfd) => vfd,
@@ -295,6 +340,7 @@ impl <'a> MessageHandler<'a> {
         } else {
             vfd.send(data)?;
         }
+        self.device.vfd_manager.record_send(id, len);
         self.send_ok()
     }
 
@@ -10,6 +10,8 @@ mod shm;
 mod pipe;
 mod socket;
 mod device;
+mod debug;
+mod watch;
 
 mod consts {
     use std::mem;
@@ -42,6 +44,11 @@ mod consts {
     pub const VIRTIO_WL_VFD_CONTROL: u32 = 0x4;
     pub const VIRTIO_WL_F_TRANS_FLAGS: u32 = 0x01;
 
+    // The shared memory region into which buffers are mapped, which is as
+    // large as the one of other hypervisors
+    pub const VIRTIO_WL_SHMEM_ID: u8 = 0;
+    pub const VIRTIO_WL_SHMEM_SIZE: usize = 1 << 32;
+
     pub const NEXT_VFD_ID_BASE: u32 = 0x40000000;
     pub const VFD_ID_HOST_MASK: u32 = NEXT_VFD_ID_BASE;
 
@@ -51,6 +58,7 @@ mod consts {
 }
 
 pub use device::VirtioWayland;
+pub use debug::{WaylandDebug, dump_message};
 pub type Result<T> = result::Result<T, Error>;
 
 pub struct VfdRecv {
@@ -69,6 +77,7 @@ impl VfdRecv {
 
 pub trait VfdObject {
     fn id(&self) -> u32;
+    fn type_name(&self) -> &'static str;
     fn send_fd(&self) -> Option<RawFd> { None }
     fn poll_fd(&self) -> Option<RawFd> { None }
     fn recv(&mut self) -> Result<Option<VfdRecv>> { Ok(None) }
@@ -47,6 +47,10 @@ impl VfdObject for VfdPipe {
         self.vfd_id
     }
 
+    fn type_name(&self) -> &'static str {
+        "pipe"
+    }
+
     fn send_fd(&self) -> Option<RawFd> {
         self.remote.as_ref().map(|p| p.as_raw_fd())
     }
@@ -1,6 +1,6 @@
 use std::os::unix::io::{AsRawFd,RawFd};
 
-use crate::memory::{MemoryManager, DrmDescriptor};
+use crate::memory::{MemoryManager, DrmDescriptor, SharedMemoryRegion};
 use crate::system::MemoryFd;
 
 use crate::devices::virtio_wl::{
@@ -11,9 +11,9 @@ use crate::devices::virtio_wl::{
 pub struct VfdSharedMemory {
     vfd_id: u32,
     flags: u32,
-    mm: MemoryManager,
+    shm: SharedMemoryRegion,
     memfd: Option<MemoryFd>,
-    slot: u32,
+    offset: usize,
     pfn: u64,
 }
 
@@ -23,27 +23,29 @@ impl VfdSharedMemory {
         (n + mask) & !mask
     }
 
-    pub fn new(vfd_id: u32, transition_flags: bool, mm: MemoryManager, memfd: MemoryFd, slot: u32, pfn: u64) -> Self {
+    /// Map `memfd` into the shared memory region `shm` of the device, where
+    /// the guest finds it at the returned pfn.
+    pub fn new(vfd_id: u32, transition_flags: bool, shm: &SharedMemoryRegion, memfd: MemoryFd) -> Result<Self> {
         let flags = if transition_flags { 0 } else { VIRTIO_WL_VFD_WRITE | VIRTIO_WL_VFD_MAP};
+        let (offset, addr) = shm.map_free(memfd.as_raw_fd(), memfd.size())
+            .map_err(Error::RegisterMemoryFailed)?;
         let memfd = Some(memfd);
-        VfdSharedMemory { vfd_id, flags, mm, memfd, slot, pfn }
+        Ok(VfdSharedMemory { vfd_id, flags, shm: shm.clone(), memfd, offset, pfn: addr >> 12 })
     }
 
-    pub fn create(vfd_id: u32, transition_flags: bool, size: u32, mm: &MemoryManager) -> Result<Self> {
+    pub fn create(vfd_id: u32, transition_flags: bool, size: u32, shm: &SharedMemoryRegion) -> Result<Self> {
         let size = Self::round_to_page_size(size as usize);
         let memfd = MemoryFd::new_memfd(size, true)
             .map_err(Error::ShmAllocFailed)?;
-        let (pfn, slot) = mm.register_device_memory(memfd.as_raw_fd(), size)
-            .map_err(Error::RegisterMemoryFailed)?;
-        Ok(Self::new(vfd_id, transition_flags, mm.clone(), memfd, slot, pfn))
+        Self::new(vfd_id, transition_flags, shm, memfd)
     }
 
-    pub fn create_dmabuf(vfd_id: u32, tflags: bool, width: u32, height: u32, format: u32, mm: &MemoryManager) -> Result<(Self, DrmDescriptor)> {
-        let (pfn, slot, fd, desc)  = mm.allocate_drm_buffer(width, height, format)
+    pub fn create_dmabuf(vfd_id: u32, tflags: bool, width: u32, height: u32, format: u32, mm: &MemoryManager, shm: &SharedMemoryRegion) -> Result<(Self, DrmDescriptor)> {
+        let (fd, desc)  = mm.allocate_drm_buffer(width, height, format)
             .map_err(Error::DmaBuf)?;
         let memfd = MemoryFd::from_filedesc(fd)
             .map_err(Error::DmaBufSize)?;
-        let vfd = Self::new(vfd_id, tflags, mm.clone(), memfd, slot, pfn);
+        let vfd = Self::new(vfd_id, tflags, shm, memfd)?;
         Ok((vfd, desc))
     }
 }
@@ -53,6 +55,10 @@ impl VfdObject for VfdSharedMemory {
         self.vfd_id
     }
 
+    fn type_name(&self) -> &'static str {
+        "shm"
+    }
+
     fn send_fd(&self) -> Option<RawFd> {
         self.memfd.as_ref().map(AsRawFd::as_raw_fd)
     }
@@ -71,7 +77,7 @@ impl VfdObject for VfdSharedMemory {
 
     fn close(&mut self) -> Result<()> {
         if let Some(_) = self.memfd.take() {
-            self.mm.unregister_device_memory(self.slot)
+            self.shm.unmap(self.offset)
                 .map_err(Error::RegisterMemoryFailed)?;
         }
         Ok(())
@@ -45,6 +45,10 @@ impl VfdObject for VfdSocket {
         self.vfd_id
     }
 
+    fn type_name(&self) -> &'static str {
+        "socket"
+    }
+
     fn send_fd(&self) -> Option<RawFd> {
         self.socket.as_ref().map(|s| s.as_raw_fd())
     }
@@ -1,44 +1,51 @@
 use std::collections::{HashMap, VecDeque};
 use std::io::{Write, SeekFrom};
 use std::os::unix::io::{AsRawFd,RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use crate::memory::{MemoryManager, DrmDescriptor};
+use crate::memory::{MemoryManager, DrmDescriptor, SharedMemoryRegion};
 use crate::system::{FileDesc, FileFlags,EPoll,MemoryFd};
 use crate::virtio::{VirtQueue, Chain};
+use crate::util::JsonValue;
 
 use crate::devices::virtio_wl::{
-    consts::*, Error, Result, shm::VfdSharedMemory, pipe::VfdPipe, socket::VfdSocket, VfdObject
+    consts::*, Error, Result, shm::VfdSharedMemory, pipe::VfdPipe, socket::VfdSocket, VfdObject, dump_message
 };
 
 pub struct VfdManager {
     wayland_path: PathBuf,
     mm: MemoryManager,
+    // Shared memory buffers are mapped into this region of the device
+    shm: SharedMemoryRegion,
     use_transition_flags: bool,
     vfd_map: HashMap<u32, Box<dyn VfdObject>>,
     next_vfd_id: u32,
     poll_ctx: EPoll,
     in_vq: VirtQueue,
     in_queue_pending: VecDeque<PendingInput>,
+    stats: HashMap<u32, VfdStats>,
 }
 
-impl VfdManager {
-    fn round_to_page_size(n: usize) -> usize {
-        let mask = 4096 - 1;
-        (n + mask) & !mask
-    }
+// Byte counts are from the point of view of the guest.
+#[derive(Default)]
+struct VfdStats {
+    bytes_in: u64,
+    bytes_out: u64,
+}
 
-    pub fn new<P: Into<PathBuf>>(mm: MemoryManager, use_transition_flags: bool, in_vq: VirtQueue, wayland_path: P) -> Result<Self> {
+impl VfdManager {
+    pub fn new<P: Into<PathBuf>>(mm: MemoryManager, shm: SharedMemoryRegion, use_transition_flags: bool, in_vq: VirtQueue, wayland_path: P) -> Result<Self> {
         let poll_ctx = EPoll::new().map_err(Error::FailedPollContextCreate)?;
         Ok(VfdManager {
             wayland_path: wayland_path.into(),
-            mm, use_transition_flags,
+            mm, shm, use_transition_flags,
             vfd_map: HashMap::new(),
             next_vfd_id: NEXT_VFD_ID_BASE,
             poll_ctx,
             in_vq,
             in_queue_pending: VecDeque::new(),
+            stats: HashMap::new(),
         })
     }
 
@@ -62,14 +69,14 @@ impl VfdManager {
     }
 
     pub fn create_shm(&mut self, vfd_id: u32, size: u32) -> Result<(u64,u64)> {
-        let shm = VfdSharedMemory::create(vfd_id, self.use_transition_flags, size, &self.mm)?;
+        let shm = VfdSharedMemory::create(vfd_id, self.use_transition_flags, size, &self.shm)?;
         let (pfn,size) = shm.pfn_and_size().unwrap();
         self.vfd_map.insert(vfd_id, Box::new(shm));
         Ok((pfn,size))
     }
 
     pub fn create_dmabuf(&mut self, vfd_id: u32, width: u32, height: u32, format: u32) -> Result<(u64, u64, DrmDescriptor)> {
-        let (vfd, desc) = VfdSharedMemory::create_dmabuf(vfd_id, self.use_transition_flags, width, height, format, &self.mm)?;
+        let (vfd, desc) = VfdSharedMemory::create_dmabuf(vfd_id, self.use_transition_flags, width, height, format, &self.mm, &self.shm)?;
         let (pfn, size) = vfd.pfn_and_size().unwrap();
         self.vfd_map.insert(vfd_id, Box::new(vfd));
         Ok((pfn, size, desc))
@@ -85,6 +92,72 @@ impl VfdManager {
 
     }
 
+    pub fn wayland_path(&self) -> &Path {
+        &self.wayland_path
+    }
+
+    /// Hang up every socket which is connected to the compositor because the
+    /// compositor has gone away. Returns the number of sockets closed.
+    pub fn hangup_sockets(&mut self) -> usize {
+        let ids: Vec<u32> = self.vfd_map.iter()
+            .filter(|(_, vfd)| vfd.type_name() == "socket" && vfd.send_fd().is_some())
+            .map(|(&id, _)| id)
+            .collect();
+        for &id in &ids {
+            self.process_hangup_event(id);
+            if let Some(vfd) = self.vfd_map.get_mut(&id) {
+                if let Err(e) = vfd.close() {
+                    warn!("Error closing wayland socket vfd(0x{:08x}): {}", id, e);
+                }
+            }
+        }
+        if let Err(e) = self.drain_pending() {
+            warn!("Error sending pending input: {}", e);
+        }
+        ids.len()
+    }
+
+    /// Record `len` bytes sent by the guest to `vfd_id`
+    pub fn record_send(&mut self, vfd_id: u32, len: usize) {
+        self.stats.entry(vfd_id).or_default().bytes_out += len as u64;
+    }
+
+    fn queue_depth(&self, vfd_id: u32) -> usize {
+        self.in_queue_pending.iter()
+            .filter(|p| p.vfd_id == vfd_id)
+            .count()
+    }
+
+    /// Describe the current set of VFDs for debugging.
+    pub fn debug_status(&self) -> JsonValue {
+        let mut ids: Vec<&u32> = self.vfd_map.keys().collect();
+        ids.sort();
+        let vfds = ids.into_iter().map(|&id| {
+            let vfd = &self.vfd_map[&id];
+            let (bytes_in, bytes_out) = self.stats.get(&id)
+                .map(|s| (s.bytes_in, s.bytes_out))
+                .unwrap_or((0, 0));
+            let mut v = JsonValue::object()
+                .with("id", id)
+                .with("type", vfd.type_name())
+                .with("flags", vfd.flags())
+                .with("fd", vfd.poll_fd().or(vfd.send_fd()))
+                .with("bytes_in", bytes_in)
+                .with("bytes_out", bytes_out)
+                .with("queue_depth", self.queue_depth(id));
+            if vfd.type_name() == "socket" {
+                let state = if vfd.send_fd().is_some() { "connected" } else { "closed" };
+                v.set("state", state);
+            }
+            v
+        }).collect::<Vec<_>>();
+
+        JsonValue::object()
+            .with("wayland_path", self.wayland_path.display().to_string())
+            .with("pending_input", self.in_queue_pending.len())
+            .with("vfds", vfds)
+    }
+
     pub fn poll_fd(&self) -> RawFd {
         self.poll_ctx.as_raw_fd()
     }
@@ -154,6 +227,8 @@ impl VfdManager {
             }
         };
 
+        self.stats.entry(vfd_id).or_default().bytes_in += recv.buf.len() as u64;
+
         if let Some(fds) = recv.fds {
             let mut vfd_ids = Vec::new();
             for fd in fds {
@@ -197,13 +272,9 @@ impl VfdManager {
 
     fn vfd_from_file(&self, vfd_id: u32, fd: FileDesc) -> Result<Box<dyn VfdObject>> {
         match fd.seek(SeekFrom::End(0)) {
-            Ok(size) => {
-                let size = Self::round_to_page_size(size as usize) as u64;
-                let (pfn,slot) = self.mm.register_device_memory(fd.as_raw_fd(), size as usize)
-                    .map_err(Error::RegisterMemoryFailed)?;
-
+            Ok(_) => {
                 let memfd = MemoryFd::from_filedesc(fd).map_err(Error::ShmAllocFailed)?;
-                return Ok(Box::new(VfdSharedMemory::new(vfd_id, self.use_transition_flags,self.mm.clone(), memfd, slot, pfn)));
+                return Ok(Box::new(VfdSharedMemory::new(vfd_id, self.use_transition_flags, &self.shm, memfd)?));
             }
             _ => {
                 let flags = match fd.flags() {
@@ -218,6 +289,7 @@ impl VfdManager {
     }
 
     pub fn close_vfd(&mut self, vfd_id: u32) -> Result<()> {
+        self.stats.remove(&vfd_id);
         if let Some(mut vfd) = self.vfd_map.remove(&vfd_id) {
             vfd.close()?;
         }
@@ -278,6 +350,7 @@ impl PendingInput {
     }
 
     fn send_hup_message(&self, chain: &mut Chain) -> Result<bool> {
+        dump_message(true, VIRTIO_WL_CMD_VFD_HUP, self.vfd_id, &[]);
         chain.w32(VIRTIO_WL_CMD_VFD_HUP)?;
         chain.w32(0)?;
         chain.w32(self.vfd_id)?;
@@ -286,6 +359,7 @@ impl PendingInput {
     }
 
     fn send_vfd_new_message(&self, chain: &mut Chain, vfd: &dyn VfdObject) -> Result<()> {
+        dump_message(true, VIRTIO_WL_CMD_VFD_NEW, vfd.id(), &[]);
         chain.w32(VIRTIO_WL_CMD_VFD_NEW)?;
         chain.w32(0)?;
         chain.w32(vfd.id())?;
@@ -300,6 +374,7 @@ impl PendingInput {
     }
 
     fn send_recv_message(&self, chain: &mut Chain) -> Result<bool> {
+        dump_message(true, VIRTIO_WL_CMD_VFD_RECV, self.vfd_id, self.buf.as_ref().map(|b| b.as_slice()).unwrap_or(&[]));
         chain.w32(VIRTIO_WL_CMD_VFD_RECV)?;
         chain.w32(0)?;
         chain.w32(self.vfd_id)?;
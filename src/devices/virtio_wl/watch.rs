@@ -0,0 +1,53 @@
+use std::fs;
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// How often the wayland socket of the host compositor is checked
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+///
+/// Notices when the host compositor restarts by checking the wayland socket
+/// it listens on. A restarted compositor binds a new socket at the same
+/// path, so the socket is told apart from the old one by its inode.
+///
+pub struct CompositorWatch {
+    path: PathBuf,
+    socket: Option<(u64, u64)>,
+    last_check: Instant,
+}
+
+impl CompositorWatch {
+    pub fn new(path: &Path) -> Self {
+        CompositorWatch {
+            path: path.to_path_buf(),
+            socket: Self::identify(path),
+            last_check: Instant::now(),
+        }
+    }
+
+    fn identify(path: &Path) -> Option<(u64, u64)> {
+        fs::metadata(path).ok().map(|meta| (meta.dev(), meta.ino()))
+    }
+
+    /// Returns `true` if a new compositor socket has appeared since the
+    /// last check. Does nothing until `WATCH_INTERVAL` has passed since then.
+    pub fn check(&mut self) -> bool {
+        if self.last_check.elapsed() < WATCH_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let socket = Self::identify(&self.path);
+        if socket == self.socket {
+            return false;
+        }
+        match mem::replace(&mut self.socket, socket) {
+            Some(_) if socket.is_none() => {
+                notify!("Wayland socket {} removed, waiting for the compositor to restart", self.path.display());
+                false
+            }
+            _ => socket.is_some(),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{FileExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{io, process, thread};
+
+use crate::util::JsonValue;
+
+const GROUP_DIR: &str = "/run/ph";
+const GROUP_FILE: &str = "/run/ph/io-share";
+
+// Number of pH instances which can take part in a sharing group
+const GROUP_SLOTS: usize = 64;
+// Each slot holds a pid and the time in milliseconds at which it last did IO
+const SLOT_SIZE: usize = 16;
+
+// An instance counts as active if it did IO this recently
+const ACTIVE_WINDOW_MS: u64 = 1000;
+// How often the share of each instance is recalculated
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+// Unused allowance which can accumulate, as a fraction of a second
+const BURST_DIVISOR: u64 = 4;
+
+///
+/// Divides a host IO bandwidth limit fairly between every pH instance which
+/// is doing IO on the host.
+///
+/// The instances share a table of slots in `/run/ph/io-share`. Each instance
+/// claims a slot when it starts and records in it the last time it did IO.
+/// An instance which did IO in the last second is active, and each active
+/// instance may transfer `bytes_per_sec` divided by the number of active
+/// instances. The table is read again every 200 milliseconds while IO is
+/// being done, so an instance which becomes idle leaves its share to the
+/// others within a second.
+///
+/// The virtio-blk and virtio-9p devices of a VM share one `IoShare` and wait
+/// in `acquire()` before each transfer to or from the host. If the table
+/// cannot be opened the instance is limited to the whole bandwidth on its own.
+///
+#[derive(Clone)]
+pub struct IoShare {
+    state: Arc<Mutex<ShareState>>,
+}
+
+struct ShareState {
+    group: Option<GroupTable>,
+    total_rate: u64,
+    rate: u64,
+    active_members: usize,
+    // Bytes which may be transferred now, negative while paying off a large transfer
+    tokens: i64,
+    last_refill: Instant,
+    last_refresh: Option<Instant>,
+    waited: Duration,
+    transferred: u64,
+}
+
+impl IoShare {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        let group = match GroupTable::open() {
+            Ok(group) => Some(group),
+            Err(e) => {
+                warn!("Cannot open {}, IO bandwidth will not be shared with other instances: {}", GROUP_FILE, e);
+                None
+            }
+        };
+        let state = ShareState {
+            group,
+            total_rate: bytes_per_sec,
+            rate: bytes_per_sec,
+            active_members: 1,
+            tokens: 0,
+            last_refill: Instant::now(),
+            last_refresh: None,
+            waited: Duration::from_secs(0),
+            transferred: 0,
+        };
+        IoShare { state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Wait until this instance may transfer `bytes` to or from the host.
+    pub fn acquire(&self, bytes: usize) {
+        let delay = self.state.lock().unwrap().take(bytes as u64);
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+    }
+
+    pub fn describe(&self) -> JsonValue {
+        let state = self.state.lock().unwrap();
+        JsonValue::object()
+            .with("total-bytes-per-sec", state.total_rate)
+            .with("bytes-per-sec", state.rate)
+            .with("active-instances", state.active_members)
+            .with("shared", state.group.is_some())
+            .with("bytes-transferred", state.transferred)
+            .with("wait-ms", state.waited.as_millis() as u64)
+    }
+}
+
+impl ShareState {
+    // Take `bytes` from the bucket and return how long to wait before
+    // the transfer may start.
+    fn take(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let stale = self.last_refresh
+            .map(|t| now.duration_since(t) >= REFRESH_INTERVAL)
+            .unwrap_or(true);
+        if stale {
+            self.refresh(now);
+        }
+        self.refill(now);
+        self.tokens -= bytes as i64;
+        self.transferred += bytes;
+        if self.tokens >= 0 {
+            return Duration::from_secs(0);
+        }
+        let delay = Duration::from_micros((-self.tokens) as u64 * 1_000_000 / self.rate);
+        self.waited += delay;
+        delay
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        self.last_refill = now;
+        let earned = elapsed.as_micros() as u64 * self.rate / 1_000_000;
+        let burst = (self.rate / BURST_DIVISOR) as i64;
+        self.tokens = (self.tokens + earned as i64).min(burst);
+    }
+
+    fn refresh(&mut self, now: Instant) {
+        self.last_refresh = Some(now);
+        let active = match self.group {
+            Some(ref group) => match group.mark_active() {
+                Ok(active) => active,
+                Err(e) => {
+                    warn!("Failed to update {}: {}", GROUP_FILE, e);
+                    return;
+                }
+            },
+            None => 1,
+        };
+        // Tokens already earned are kept at the old rate
+        self.refill(now);
+        self.active_members = active;
+        self.rate = (self.total_rate / active as u64).max(1);
+    }
+}
+
+struct GroupTable {
+    file: File,
+    slot: usize,
+}
+
+impl GroupTable {
+    fn open() -> io::Result<GroupTable> {
+        if !Path::new(GROUP_DIR).exists() {
+            fs::create_dir_all(GROUP_DIR)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o666)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(GROUP_FILE)?;
+        // Instances running as other users must be able to join
+        let _ = fs::set_permissions(GROUP_FILE, fs::Permissions::from_mode(0o666));
+        let slot = Self::claim_slot(&file)?;
+        Ok(GroupTable { file, slot })
+    }
+
+    // Take the first slot which is unused or belongs to an instance which exited
+    fn claim_slot(file: &File) -> io::Result<usize> {
+        let _lock = FileLock::lock(file)?;
+        if file.metadata()?.len() < (GROUP_SLOTS * SLOT_SIZE) as u64 {
+            file.set_len((GROUP_SLOTS * SLOT_SIZE) as u64)?;
+        }
+        for slot in 0..GROUP_SLOTS {
+            let (pid, _) = Self::read_slot(file, slot)?;
+            if pid == 0 || !Self::is_running(pid) {
+                Self::write_slot(file, slot, process::id(), 0)?;
+                return Ok(slot);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::Other, "every slot is in use"))
+    }
+
+    fn is_running(pid: u32) -> bool {
+        if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+            return true;
+        }
+        io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    fn now_ms() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    fn read_slot(file: &File, slot: usize) -> io::Result<(u32, u64)> {
+        let mut buf = [0u8; SLOT_SIZE];
+        file.read_exact_at(&mut buf, (slot * SLOT_SIZE) as u64)?;
+        let mut pid = [0u8; 4];
+        let mut last = [0u8; 8];
+        pid.copy_from_slice(&buf[..4]);
+        last.copy_from_slice(&buf[8..]);
+        Ok((u32::from_le_bytes(pid), u64::from_le_bytes(last)))
+    }
+
+    fn write_slot(file: &File, slot: usize, pid: u32, last_active: u64) -> io::Result<()> {
+        let mut buf = [0u8; SLOT_SIZE];
+        buf[..4].copy_from_slice(&pid.to_le_bytes());
+        buf[8..].copy_from_slice(&last_active.to_le_bytes());
+        file.write_all_at(&buf, (slot * SLOT_SIZE) as u64)
+    }
+
+    // Record that this instance is doing IO and return the number of active
+    // instances, including this one.
+    fn mark_active(&self) -> io::Result<usize> {
+        let _lock = FileLock::lock(&self.file)?;
+        let now = Self::now_ms();
+        Self::write_slot(&self.file, self.slot, process::id(), now)?;
+        let mut active = 0;
+        for slot in 0..GROUP_SLOTS {
+            let (pid, last_active) = Self::read_slot(&self.file, slot)?;
+            if pid != 0 && now.saturating_sub(last_active) < ACTIVE_WINDOW_MS {
+                active += 1;
+            }
+        }
+        Ok(active.max(1))
+    }
+}
+
+impl Drop for GroupTable {
+    fn drop(&mut self) {
+        if let Ok(_lock) = FileLock::lock(&self.file) {
+            let _ = Self::write_slot(&self.file, self.slot, 0, 0);
+        }
+    }
+}
+
+struct FileLock<'a> {
+    file: &'a File,
+}
+
+impl <'a> FileLock<'a> {
+    fn lock(file: &'a File) -> io::Result<Self> {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FileLock { file })
+    }
+}
+
+impl <'a> Drop for FileLock<'a> {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN); }
+    }
+}
@@ -1,19 +1,120 @@
-use crate::system::MemoryFd;
+use crate::system::{MemoryFd, FileDesc};
 use crate::util::BitSet;
 use crate::disk::{Result, Error, SECTOR_SIZE, DiskImage};
-use std::io::SeekFrom;
+use std::fs::OpenOptions;
+use std::io::{self, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::IntoRawFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Size of the blocks copied from memory to the spill file
+const SPILL_COPY_SIZE: usize = 64 * 1024;
+
+// Warn when an overlay without a spill directory reaches this percentage of its limit
+const WARN_PERCENT: u64 = 90;
+
+///
+/// Bounds the memory used to hold writes to a disk image opened with
+/// `OpenType::MemoryOverlay`.
+///
+/// When the overlay grows beyond `max_bytes` its contents are moved to an
+/// anonymous temporary file in `spill_dir` and later writes go to that file.
+/// Without a `spill_dir` writes which would exceed the limit fail.
+///
+#[derive(Clone,Debug,Default)]
+pub struct OverlayLimit {
+    max_bytes: Option<u64>,
+    spill_dir: Option<PathBuf>,
+}
+
+impl OverlayLimit {
+    pub fn new(max_bytes: Option<u64>, spill_dir: Option<PathBuf>) -> Self {
+        OverlayLimit { max_bytes, spill_dir }
+    }
+}
+
+struct OverlayCounters {
+    sectors: AtomicUsize,
+    limit: AtomicUsize,
+    spilled: AtomicBool,
+}
+
+///
+/// Memory usage of a disk overlay which can be read from other threads.
+///
+#[derive(Clone)]
+pub struct OverlayStats {
+    name: String,
+    counters: Arc<OverlayCounters>,
+}
+
+impl OverlayStats {
+    pub fn new(name: &str) -> Self {
+        let counters = OverlayCounters {
+            sectors: AtomicUsize::new(0),
+            limit: AtomicUsize::new(0),
+            spilled: AtomicBool::new(false),
+        };
+        OverlayStats { name: name.to_string(), counters: Arc::new(counters) }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Bytes written to the overlay, counting each sector once.
+    pub fn used_bytes(&self) -> u64 {
+        (self.counters.sectors.load(Ordering::Relaxed) * SECTOR_SIZE) as u64
+    }
+
+    /// Configured limit in bytes, or 0 if the overlay is unbounded.
+    pub fn limit_bytes(&self) -> u64 {
+        self.counters.limit.load(Ordering::Relaxed) as u64
+    }
+
+    /// Returns `true` if the overlay has been moved from memory to a file.
+    pub fn is_spilled(&self) -> bool {
+        self.counters.spilled.load(Ordering::Relaxed)
+    }
+}
+
+enum OverlayStore {
+    Memory(MemoryFd),
+    Spill(FileDesc),
+}
+
+impl OverlayStore {
+    fn fd(&mut self) -> &mut FileDesc {
+        match self {
+            OverlayStore::Memory(memory) => memory.fd_mut(),
+            OverlayStore::Spill(fd) => fd,
+        }
+    }
+}
 
 pub struct MemoryOverlay {
-    memory: MemoryFd,
+    store: OverlayStore,
     written_sectors: BitSet,
+    limit: OverlayLimit,
+    stats: OverlayStats,
+    warned: bool,
 }
 
 impl MemoryOverlay {
-    pub fn new() -> Result<Self> {
+    pub fn new(limit: OverlayLimit, stats: OverlayStats) -> Result<Self> {
         let memory = MemoryFd::new_memfd(0, false)
             .map_err(Error::MemoryOverlayCreate)?;
         let written_sectors = BitSet::new();
-        Ok(MemoryOverlay { memory, written_sectors })
+        stats.counters.limit.store(limit.max_bytes.unwrap_or(0) as usize, Ordering::Relaxed);
+        Ok(MemoryOverlay {
+            store: OverlayStore::Memory(memory),
+            written_sectors,
+            limit,
+            stats,
+            warned: false,
+        })
     }
 
     pub fn write_sectors(&mut self, start: u64, buffer: &[u8]) -> Result<()> {
@@ -21,11 +122,16 @@ impl MemoryOverlay {
         let len = sector_count * SECTOR_SIZE;
         let seek_offset = SeekFrom::Start(start * SECTOR_SIZE as u64);
 
-        self.memory.fd_mut()
+        let new_sectors = (0..sector_count)
+            .filter(|&n| !self.written_sectors.get(start as usize + n))
+            .count();
+        self.check_limit(new_sectors)?;
+
+        self.store.fd()
             .seek(seek_offset)
             .map_err(Error::DiskSeek)?;
 
-        self.memory.fd_mut()
+        self.store.fd()
             .write_all(&buffer[..len])
             .map_err(Error::DiskWrite)?;
 
@@ -33,12 +139,66 @@ impl MemoryOverlay {
             let idx = start as usize + n;
             self.written_sectors.insert(idx);
         }
+        self.stats.counters.sectors.fetch_add(new_sectors, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn check_limit(&mut self, new_sectors: usize) -> Result<()> {
+        let max_bytes = match (&self.store, self.limit.max_bytes) {
+            (OverlayStore::Memory(_), Some(max_bytes)) => max_bytes,
+            _ => return Ok(()),
+        };
+        let used = self.stats.used_bytes() + (new_sectors * SECTOR_SIZE) as u64;
+        if used <= max_bytes {
+            if !self.warned && self.limit.spill_dir.is_none() && used * 100 >= max_bytes * WARN_PERCENT {
+                warn!("Memory overlay for {} is {}% full ({} of {} bytes)",
+                      self.stats.name(), used * 100 / max_bytes, used, max_bytes);
+                self.warned = true;
+            }
+            return Ok(());
+        }
+        match self.limit.spill_dir.clone() {
+            Some(dir) => self.spill(dir).map_err(Error::OverlaySpill),
+            None => Err(Error::OverlayLimit(max_bytes)),
+        }
+    }
+
+    // Move the overlay contents from memory to an unlinked file in `dir`
+    fn spill(&mut self, dir: PathBuf) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_TMPFILE)
+            .open(&dir)?;
+        let spill = FileDesc::new(file.into_raw_fd());
+
+        let size = self.store.fd().seek(SeekFrom::End(0))? as usize;
+        let mut buffer = vec![0u8; SPILL_COPY_SIZE];
+        let sectors_per_block = SPILL_COPY_SIZE / SECTOR_SIZE;
+        let mut offset = 0;
+        while offset < size {
+            let first = offset / SECTOR_SIZE;
+            if (first..first + sectors_per_block).any(|s| self.written_sectors.get(s)) {
+                let len = (size - offset).min(SPILL_COPY_SIZE);
+                self.store.fd().seek(SeekFrom::Start(offset as u64))?;
+                self.store.fd().read_exact(&mut buffer[..len])?;
+                spill.seek(SeekFrom::Start(offset as u64))?;
+                spill.write_all(&buffer[..len])?;
+            }
+            offset += SPILL_COPY_SIZE;
+        }
+
+        notify!("Memory overlay for {} reached {} bytes, moved to {}",
+                self.stats.name(), self.stats.used_bytes(), dir.display());
+        self.store = OverlayStore::Spill(spill);
+        self.stats.counters.spilled.store(true, Ordering::Relaxed);
         Ok(())
     }
 
     pub fn read_sectors<D: DiskImage>(&mut self, disk: &mut D, start: u64, buffer: &mut [u8]) -> Result<()> {
         let sector_count = buffer.len() / SECTOR_SIZE;
-        if (0..sector_count).all(|i| !self.written_sectors.get(i)) {
+        if (0..sector_count).all(|i| !self.written_sectors.get(start as usize + i)) {
             return disk.read_sectors(start, buffer);
         }
 
@@ -58,11 +218,11 @@ impl MemoryOverlay {
     fn read_single_sector(&mut self, sector: u64, buffer: &mut [u8]) -> Result<()> {
         assert_eq!(buffer.len(), SECTOR_SIZE);
         let offset = SeekFrom::Start(sector * SECTOR_SIZE as u64);
-        self.memory.fd_mut().seek(offset)
+        self.store.fd().seek(offset)
             .map_err(Error::DiskSeek)?;
-        self.memory.fd_mut().read_exact(buffer)
+        self.store.fd().read_exact(buffer)
             .map_err(Error::DiskRead)?;
         Ok(())
     }
 
-}
\ No newline at end of file
+}
@@ -1,4 +1,5 @@
 use std::{io, error, fmt, result, cmp};
+use std::convert::TryFrom;
 use std::fs::File;
 use std::os::linux::fs::MetadataExt;
 use std::io::{SeekFrom, Seek};
@@ -8,13 +9,98 @@ use crate::system;
 mod realmfs;
 mod raw;
 mod memory;
+mod partition;
+mod io_share;
 
 pub use raw::RawDiskImage;
 pub use realmfs::RealmFSImage;
+pub use partition::Partition;
+pub use memory::{OverlayLimit, OverlayStats};
+pub use io_share::IoShare;
 use std::path::PathBuf;
 
 const SECTOR_SIZE: usize = 512;
 
+///
+/// Block size and I/O size hints which are presented to the guest in the
+/// topology fields of the virtio-blk configuration area.
+///
+/// All sizes are in bytes. `logical_block_size` and `physical_block_size` must
+/// be powers of two and `physical_block_size` must be a multiple of the logical size.
+/// `min_io_size` must be no more than 65535 logical blocks, the largest value
+/// of the 16 bit field it is presented in.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct BlockTopology {
+    pub logical_block_size: u32,
+    pub physical_block_size: u32,
+    pub min_io_size: u32,
+    pub opt_io_size: u32,
+}
+
+impl BlockTopology {
+    pub fn new(logical_block_size: u32, physical_block_size: u32) -> Self {
+        BlockTopology {
+            logical_block_size,
+            physical_block_size,
+            min_io_size: physical_block_size,
+            opt_io_size: 0,
+        }
+    }
+
+    /// Topology for images stored on storage with 4096 byte native sectors.
+    pub fn native_4k() -> Self {
+        BlockTopology::new(4096, 4096)
+    }
+
+    pub fn with_io_sizes(mut self, min_io_size: u32, opt_io_size: u32) -> Self {
+        self.min_io_size = min_io_size;
+        self.opt_io_size = opt_io_size;
+        self
+    }
+
+    /// Log2 of the number of logical blocks per physical block
+    pub fn physical_block_exp(&self) -> u8 {
+        let ratio = self.physical_block_size / self.logical_block_size;
+        if ratio == 0 {
+            0
+        } else {
+            ratio.trailing_zeros() as u8
+        }
+    }
+
+    /// Minimum I/O size expressed in logical blocks, or `None` if it does
+    /// not fit in the field of the configuration area.
+    fn checked_min_io_blocks(&self) -> Option<u16> {
+        u16::try_from(self.min_io_size / self.logical_block_size).ok()
+    }
+
+    /// Minimum I/O size expressed in logical blocks. Only a topology which
+    /// `is_valid()` has a size which fits.
+    pub fn min_io_blocks(&self) -> u16 {
+        self.checked_min_io_blocks().unwrap_or(u16::max_value())
+    }
+
+    /// Optimal I/O size expressed in logical blocks
+    pub fn opt_io_blocks(&self) -> u32 {
+        self.opt_io_size / self.logical_block_size
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.logical_block_size >= SECTOR_SIZE as u32 &&
+            self.logical_block_size.is_power_of_two() &&
+            self.physical_block_size.is_power_of_two() &&
+            self.physical_block_size >= self.logical_block_size &&
+            self.checked_min_io_blocks().is_some()
+    }
+}
+
+impl Default for BlockTopology {
+    fn default() -> Self {
+        BlockTopology::new(1024, 1024)
+    }
+}
+
 #[derive(Debug,PartialEq)]
 pub enum OpenType {
     ReadOnly,
@@ -40,9 +126,23 @@ pub trait DiskImage: Sync+Send {
     }
     fn write_sectors(&mut self, start_sector: u64, buffer: &[u8]) -> Result<()>;
     fn read_sectors(&mut self, start_sector: u64, buffer: &mut [u8]) -> Result<()>;
+
+    /// True if the image can be read and written with `read_sectors_at()`
+    /// and `write_sectors_at()` from several threads at once.
+    fn concurrent_io(&self) -> bool { false }
+    fn read_sectors_at(&self, _start_sector: u64, _buffer: &mut [u8]) -> Result<()> {
+        Err(Error::NoConcurrentIo)
+    }
+    fn write_sectors_at(&self, _start_sector: u64, _buffer: &[u8]) -> Result<()> {
+        Err(Error::NoConcurrentIo)
+    }
+
+    /// Make every completed write durable on the host storage.
     fn flush(&mut self) -> Result<()> { Ok(()) }
 
     fn disk_image_id(&self) -> &[u8];
+
+    fn topology(&self) -> BlockTopology { BlockTopology::default() }
 }
 
 fn generate_disk_image_id(disk_file: &File) -> Vec<u8> {
@@ -68,8 +168,15 @@ pub enum Error {
     DiskRead(io::Error),
     DiskWrite(io::Error),
     DiskSeek(io::Error),
+    DiskFlush(io::Error),
+    FlushFailedEarlier,
     BadSectorOffset(u64),
+    NoPartitionTable(PathBuf),
+    BadPartition(PathBuf, usize),
     MemoryOverlayCreate(system::Error),
+    OverlayLimit(u64),
+    OverlaySpill(io::Error),
+    NoConcurrentIo,
     NotOpen,
 }
 
@@ -86,8 +193,15 @@ impl fmt::Display for Error {
             DiskRead(err) => write!(f, "error reading from disk image: {}", err),
             DiskWrite(err) => write!(f, "error writing to disk image: {}", err),
             DiskSeek(err) => write!(f, "error seeking to offset on disk image: {}", err),
+            DiskFlush(err) => write!(f, "error flushing disk image to storage: {}", err),
+            FlushFailedEarlier => write!(f, "an earlier flush of the disk image failed, writes may have been lost"),
             BadSectorOffset(sector) => write!(f, "attempt to access invalid sector offset {}", sector),
+            NoPartitionTable(path) => write!(f, "disk image {} does not have an MBR or GPT partition table", path.display()),
+            BadPartition(path, index) => write!(f, "disk image {} does not have a valid partition {}", path.display(), index),
             MemoryOverlayCreate(err) => write!(f, "failed to create memory overlay: {}", err),
+            OverlayLimit(limit) => write!(f, "memory overlay is full ({} bytes)", limit),
+            OverlaySpill(err) => write!(f, "failed to move memory overlay to disk: {}", err),
+            NoConcurrentIo => write!(f, "disk image does not support concurrent IO"),
             NotOpen => write!(f, "disk not open"),
         }
     }
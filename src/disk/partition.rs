@@ -0,0 +1,144 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::disk::{Result, Error};
+
+const MBR_SIZE: usize = 512;
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_ENTRY_COUNT: usize = 4;
+const MBR_SECTOR_SIZE: u64 = 512;
+
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPES_EXTENDED: &[u8] = &[0x05, 0x0F, 0x85];
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+// Logical block sizes which are tried when looking for the GPT header at LBA 1
+const GPT_BLOCK_SIZES: &[u64] = &[512, 4096];
+const GPT_MAX_ENTRIES: u32 = 1024;
+const GPT_MIN_ENTRY_SIZE: u32 = 128;
+
+///
+/// Location of a partition in a disk image, in bytes from the start of the image.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Partition {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl Partition {
+    /// Read the GPT or MBR partition table of the disk image at `path` and
+    /// return partition number `index`, counting from 1. Logical partitions
+    /// inside an MBR extended partition are not supported.
+    pub fn find(path: &Path, index: usize) -> Result<Partition> {
+        let mut file = File::open(path)
+            .map_err(|e| Error::DiskOpen(path.to_path_buf(), e))?;
+        let image_size = file.metadata()
+            .map_err(|e| Error::DiskOpen(path.to_path_buf(), e))?
+            .len();
+
+        let partition = PartitionTable { file: &mut file, path }.find(index)?;
+        if partition.length == 0 || partition.offset.checked_add(partition.length).map_or(true, |end| end > image_size) {
+            return Err(Error::BadPartition(path.to_path_buf(), index));
+        }
+        Ok(partition)
+    }
+}
+
+struct PartitionTable<'a> {
+    file: &'a mut File,
+    path: &'a Path,
+}
+
+impl <'a> PartitionTable<'a> {
+    fn find(&mut self, index: usize) -> Result<Partition> {
+        if index == 0 {
+            return Err(Error::BadPartition(self.path.to_path_buf(), index));
+        }
+        let mbr = self.read(0, MBR_SIZE)?;
+        if mbr[MBR_SIGNATURE_OFFSET..] != [0x55, 0xAA] {
+            return Err(Error::NoPartitionTable(self.path.to_path_buf()));
+        }
+        let entries: Vec<&[u8]> = mbr[MBR_ENTRIES_OFFSET..MBR_SIGNATURE_OFFSET]
+            .chunks(MBR_ENTRY_SIZE)
+            .take(MBR_ENTRY_COUNT)
+            .collect();
+
+        if entries.iter().any(|e| e[4] == MBR_TYPE_GPT_PROTECTIVE) {
+            self.find_gpt(index)
+        } else {
+            self.find_mbr(&entries, index)
+        }
+    }
+
+    fn find_mbr(&self, entries: &[&[u8]], index: usize) -> Result<Partition> {
+        let entry = match entries.get(index - 1) {
+            Some(entry) if entry[4] != MBR_TYPE_EMPTY && !MBR_TYPES_EXTENDED.contains(&entry[4]) => entry,
+            _ => return Err(Error::BadPartition(self.path.to_path_buf(), index)),
+        };
+        let start = u64::from(le32(&entry[8..]));
+        let count = u64::from(le32(&entry[12..]));
+        Ok(Partition {
+            offset: start * MBR_SECTOR_SIZE,
+            length: count * MBR_SECTOR_SIZE,
+        })
+    }
+
+    fn find_gpt(&mut self, index: usize) -> Result<Partition> {
+        for &block_size in GPT_BLOCK_SIZES {
+            let header = self.read(block_size, GPT_HEADER_SIZE)?;
+            if &header[..8] == GPT_SIGNATURE {
+                return self.find_gpt_entry(&header, block_size, index);
+            }
+        }
+        Err(Error::NoPartitionTable(self.path.to_path_buf()))
+    }
+
+    fn find_gpt_entry(&mut self, header: &[u8], block_size: u64, index: usize) -> Result<Partition> {
+        let entries_lba = le64(&header[72..]);
+        let entry_count = le32(&header[80..]);
+        let entry_size = le32(&header[84..]);
+        if entry_count > GPT_MAX_ENTRIES || entry_size < GPT_MIN_ENTRY_SIZE || !entry_size.is_power_of_two() {
+            return Err(Error::NoPartitionTable(self.path.to_path_buf()));
+        }
+        if index > entry_count as usize {
+            return Err(Error::BadPartition(self.path.to_path_buf(), index));
+        }
+        let offset = entries_lba * block_size + (index as u64 - 1) * u64::from(entry_size);
+        let entry = self.read(offset, entry_size as usize)?;
+
+        // An entry with a zero partition type GUID is unused
+        let first = le64(&entry[32..]);
+        let last = le64(&entry[40..]);
+        if entry[..16].iter().all(|&b| b == 0) || last < first {
+            return Err(Error::BadPartition(self.path.to_path_buf(), index));
+        }
+        Ok(Partition {
+            offset: first * block_size,
+            length: (last - first + 1) * block_size,
+        })
+    }
+
+    fn read(&mut self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; len];
+        self.file.seek(SeekFrom::Start(offset))
+            .map_err(Error::DiskSeek)?;
+        self.file.read_exact(&mut buffer)
+            .map_err(Error::DiskRead)?;
+        Ok(buffer)
+    }
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
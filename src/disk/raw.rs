@@ -1,8 +1,9 @@
-use crate::disk::{Result, Error, DiskImage, SECTOR_SIZE, generate_disk_image_id, OpenType};
+use crate::disk::{Result, Error, DiskImage, SECTOR_SIZE, generate_disk_image_id, OpenType, BlockTopology, Partition};
 use std::fs::{File, OpenOptions};
 use std::io::{Write, Read, SeekFrom, Seek};
+use std::os::unix::fs::FileExt;
 use crate::disk::Error::DiskRead;
-use crate::disk::memory::MemoryOverlay;
+use crate::disk::memory::{MemoryOverlay, OverlayLimit, OverlayStats};
 use std::path::{PathBuf, Path};
 
 
@@ -14,6 +15,10 @@ pub struct RawDiskImage {
     nsectors: u64,
     disk_image_id: Vec<u8>,
     overlay: Option<MemoryOverlay>,
+    overlay_limit: OverlayLimit,
+    overlay_stats: OverlayStats,
+    topology: BlockTopology,
+    flush_failed: bool,
 }
 
 impl RawDiskImage {
@@ -25,7 +30,6 @@ impl RawDiskImage {
         }
     }
 
-    #[allow(dead_code)]
     pub fn new<P: Into<PathBuf>>(path: P, open_type: OpenType) -> Result<Self> {
         Self::new_with_offset(path, open_type, 0)
     }
@@ -33,6 +37,7 @@ impl RawDiskImage {
     pub fn new_with_offset<P: Into<PathBuf>>(path: P, open_type: OpenType, offset: usize) -> Result<Self> {
         let path = path.into();
         let nsectors = Self::get_nsectors(&path, offset)?;
+        let overlay_stats = OverlayStats::new(&path.display().to_string());
         Ok(RawDiskImage {
             path,
             open_type,
@@ -41,9 +46,55 @@ impl RawDiskImage {
             nsectors,
             disk_image_id: Vec::new(),
             overlay: None,
+            overlay_limit: OverlayLimit::default(),
+            overlay_stats,
+            topology: BlockTopology::default(),
+            flush_failed: false,
         })
     }
 
+    /// Open partition number `partition` (counting from 1) of a disk image
+    /// with an MBR or GPT partition table. Access is limited to the sectors
+    /// of the partition.
+    pub fn new_with_partition<P: Into<PathBuf>>(path: P, open_type: OpenType, partition: usize) -> Result<Self> {
+        let path = path.into();
+        let Partition { offset, length } = Partition::find(&path, partition)?;
+        let mut image = Self::new_with_offset(path, open_type, offset as usize)?;
+        image.nsectors = length / SECTOR_SIZE as u64;
+        Ok(image)
+    }
+
+    pub fn set_topology(&mut self, topology: BlockTopology) {
+        self.topology = topology;
+    }
+
+    pub fn set_overlay_limit(&mut self, limit: OverlayLimit) {
+        self.overlay_limit = limit;
+    }
+
+    /// Usage statistics for the memory overlay if the image is opened
+    /// with `OpenType::MemoryOverlay`.
+    pub fn overlay_stats(&self) -> Option<OverlayStats> {
+        if self.open_type == OpenType::MemoryOverlay {
+            Some(self.overlay_stats.clone())
+        } else {
+            None
+        }
+    }
+
+    // An image which is a partition of a larger file must not access the
+    // sectors which follow the end of the partition. The sector comes from
+    // the guest, so the end of the range is computed without overflowing.
+    fn check_range(&self, start_sector: u64, len: usize) -> Result<()> {
+        match start_sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.nsectors => Ok(()),
+            _ => Err(Error::BadSectorOffset(start_sector)),
+        }
+    }
+
+    fn file_offset(&self, sector: u64) -> u64 {
+        sector * SECTOR_SIZE as u64 + self.offset as u64
+    }
 }
 
 impl DiskImage for RawDiskImage {
@@ -65,7 +116,7 @@ impl DiskImage for RawDiskImage {
         self.file = Some(file);
 
         if self.open_type == OpenType::MemoryOverlay {
-            let overlay = MemoryOverlay::new()?;
+            let overlay = MemoryOverlay::new(self.overlay_limit.clone(), self.overlay_stats.clone())?;
             self.overlay = Some(overlay);
         }
         Ok(())
@@ -95,6 +146,7 @@ impl DiskImage for RawDiskImage {
     }
 
     fn write_sectors(&mut self, start_sector: u64, buffer: &[u8]) -> Result<()> {
+        self.check_range(start_sector, buffer.len())?;
         if let Some(ref mut overlay) = self.overlay {
             return overlay.write_sectors(start_sector, buffer);
         }
@@ -110,6 +162,7 @@ impl DiskImage for RawDiskImage {
     }
 
     fn read_sectors(&mut self, start_sector: u64, buffer: &mut [u8]) -> Result<()> {
+        self.check_range(start_sector, buffer.len())?;
         if let Some(mut overlay) = self.overlay.take() {
             let ret = overlay.read_sectors(self, start_sector, buffer);
             self.overlay.replace(overlay);
@@ -124,7 +177,60 @@ impl DiskImage for RawDiskImage {
         Ok(())
     }
 
+    // Reads and writes of the image file at an offset do not move the file
+    // position, but a memory overlay must be accessed by one thread at a time.
+    fn concurrent_io(&self) -> bool {
+        self.file.is_some() && self.overlay.is_none()
+    }
+
+    fn read_sectors_at(&self, start_sector: u64, buffer: &mut [u8]) -> Result<()> {
+        if self.overlay.is_some() {
+            return Err(Error::NoConcurrentIo);
+        }
+        self.check_range(start_sector, buffer.len())?;
+        let len = (buffer.len() / SECTOR_SIZE) * SECTOR_SIZE;
+        let file = self.file.as_ref().ok_or(Error::NotOpen)?;
+        file.read_exact_at(&mut buffer[..len], self.file_offset(start_sector))
+            .map_err(DiskRead)
+    }
+
+    fn write_sectors_at(&self, start_sector: u64, buffer: &[u8]) -> Result<()> {
+        if self.overlay.is_some() {
+            return Err(Error::NoConcurrentIo);
+        }
+        if self.read_only() {
+            return Err(Error::ReadOnly)
+        }
+        self.check_range(start_sector, buffer.len())?;
+        let len = (buffer.len() / SECTOR_SIZE) * SECTOR_SIZE;
+        let file = self.file.as_ref().ok_or(Error::NotOpen)?;
+        file.write_all_at(&buffer[..len], self.file_offset(start_sector))
+            .map_err(Error::DiskWrite)
+    }
+
+    // After a failed fsync the kernel may have dropped the dirty pages and a
+    // later fsync can succeed without having written them, so once a flush
+    // fails every later flush fails too.
+    fn flush(&mut self) -> Result<()> {
+        if self.overlay.is_some() || self.read_only() {
+            return Ok(());
+        }
+        if self.flush_failed {
+            return Err(Error::FlushFailedEarlier);
+        }
+        let file = self.disk_file()?;
+        if let Err(err) = file.sync_data() {
+            self.flush_failed = true;
+            return Err(Error::DiskFlush(err));
+        }
+        Ok(())
+    }
+
     fn disk_image_id(&self) -> &[u8] {
         &self.disk_image_id
     }
+
+    fn topology(&self) -> BlockTopology {
+        self.topology
+    }
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-use crate::disk::{Result, DiskImage, SECTOR_SIZE, RawDiskImage, OpenType};
+use crate::disk::{Result, DiskImage, SECTOR_SIZE, RawDiskImage, OpenType, BlockTopology, OverlayLimit, OverlayStats};
 use std::fs::File;
 use std::path::PathBuf;
 
@@ -14,9 +14,22 @@ impl RealmFSImage {
     pub fn new<P: Into<PathBuf>>(path: P, open_type: OpenType) -> Result<Self> {
         assert_ne!(open_type, OpenType::ReadWrite);
         let offset = HEADER_SECTOR_COUNT * SECTOR_SIZE;
-        let raw = RawDiskImage::new_with_offset(path, open_type, offset)?;
+        let mut raw = RawDiskImage::new_with_offset(path, open_type, offset)?;
+        raw.set_topology(BlockTopology::default().with_io_sizes(4096, 0));
         Ok(RealmFSImage { raw })
     }
+
+    pub fn set_topology(&mut self, topology: BlockTopology) {
+        self.raw.set_topology(topology);
+    }
+
+    pub fn set_overlay_limit(&mut self, limit: OverlayLimit) {
+        self.raw.set_overlay_limit(limit);
+    }
+
+    pub fn overlay_stats(&self) -> Option<OverlayStats> {
+        self.raw.overlay_stats()
+    }
 }
 
 impl DiskImage for RealmFSImage {
@@ -43,7 +56,27 @@ impl DiskImage for RealmFSImage {
         self.raw.read_sectors(start_sector, buffer)
     }
 
+    fn concurrent_io(&self) -> bool {
+        self.raw.concurrent_io()
+    }
+
+    fn read_sectors_at(&self, start_sector: u64, buffer: &mut [u8]) -> Result<()> {
+        self.raw.read_sectors_at(start_sector, buffer)
+    }
+
+    fn write_sectors_at(&self, start_sector: u64, buffer: &[u8]) -> Result<()> {
+        self.raw.write_sectors_at(start_sector, buffer)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.raw.flush()
+    }
+
     fn disk_image_id(&self) -> &[u8] {
         self.raw.disk_image_id()
     }
+
+    fn topology(&self) -> BlockTopology {
+        self.raw.topology()
+    }
 }
@@ -11,6 +11,8 @@ pub enum Error {
     BadVersion,
     IoctlError(&'static str, ErrnoError),
     IoEventCreate(SysError),
+    NoGsiAvailable,
+    TooManyIrqRoutes,
 }
 
 impl Error {
@@ -31,6 +33,8 @@ impl fmt::Display for Error {
             BadVersion => write!(f, "unexpected kvm api version"),
             IoctlError(name, err) => write!(f, "failed to call {} ioctl: {}", name, err),
             IoEventCreate(e) => write!(f, "failed to create ioeventfd: {}", e),
+            NoGsiAvailable => write!(f, "no free GSI available for allocation"),
+            TooManyIrqRoutes => write!(f, "irq routing table exceeds maximum number of entries"),
         }
     }
 }
@@ -6,6 +6,7 @@ use crate::system::ioctl::{ioctl_with_val,ioctl_with_ref,ioctl_with_mut_ref};
 
 use crate::kvm::{Result, Error};
 use crate::system::ErrnoError;
+#[cfg(target_arch = "x86_64")]
 use crate::vm::arch::KvmRegs;
 use std::result;
 
@@ -19,11 +20,31 @@ const KVM_GET_VCPU_MMAP_SIZE: c_ulong        = io!     (KVMIO, 0x04);
 const KVM_CREATE_VCPU: c_ulong               = io!     (KVMIO, 0x41);
 const KVM_SET_USER_MEMORY_REGION: c_ulong    = iow!    (KVMIO, 0x46, 32);
 const KVM_IRQ_LINE: c_ulong                  = iow!    (KVMIO, 0x61, 8);
+const KVM_SET_GSI_ROUTING: c_ulong           = iow!    (KVMIO, 0x6a, 8);
 const KVM_IRQFD: c_ulong                     = iow!    (KVMIO, 0x76, 32);
 const KVM_IOEVENTFD: c_ulong                 = iow!    (KVMIO, 0x79, 64);
 const KVM_RUN: c_ulong                       = io!     (KVMIO, 0x80);
+#[cfg(target_arch = "x86_64")]
 const KVM_GET_REGS: c_ulong                  = ior!    (KVMIO, 0x81, 144);
+#[cfg(target_arch = "x86_64")]
 const KVM_SET_REGS: c_ulong                  = iow!    (KVMIO, 0x82, 144);
+#[cfg(target_arch = "x86_64")]
+const KVM_TRANSLATE: c_ulong                 = iorw!   (KVMIO, 0x85, 24);
+#[cfg(target_arch = "x86_64")]
+const KVM_INTERRUPT: c_ulong                 = iow!    (KVMIO, 0x86, 4);
+const KVM_GET_MP_STATE: c_ulong              = ior!    (KVMIO, 0x98, 4);
+const KVM_SET_MP_STATE: c_ulong              = iow!    (KVMIO, 0x99, 4);
+#[cfg(target_arch = "x86_64")]
+const KVM_NMI: c_ulong                       = io!     (KVMIO, 0x9a);
+#[cfg(target_arch = "x86_64")]
+const KVM_SET_GUEST_DEBUG: c_ulong           = iow!    (KVMIO, 0x9b, 72);
+#[cfg(target_arch = "x86_64")]
+const KVM_GET_VCPU_EVENTS: c_ulong           = ior!    (KVMIO, 0x9f, 64);
+#[cfg(target_arch = "x86_64")]
+const KVM_SET_VCPU_EVENTS: c_ulong           = iow!    (KVMIO, 0xa0, 64);
+const KVM_ENABLE_CAP: c_ulong                = iow!    (KVMIO, 0xa3, 104);
+#[cfg(target_arch = "x86_64")]
+const KVM_SMI: c_ulong                       = io!     (KVMIO, 0xb7);
 
 struct InnerFd(RawFd);
 impl InnerFd {
@@ -177,6 +198,69 @@ pub fn kvm_irqfd(vmfd: &VmFd, irqfd: &KvmIrqFd) -> Result<()> {
     call_ioctl_with_ref("KVM_IRQFD", vmfd.raw(), KVM_IRQFD, irqfd)
 }
 
+const KVM_IRQ_ROUTING_IRQCHIP: u32 = 1;
+const KVM_IRQ_ROUTING_MSI: u32 = 2;
+
+#[repr(C)]
+#[derive(Copy,Clone,Default)]
+pub struct KvmIrqRoutingEntry {
+    gsi: u32,
+    route_type: u32,
+    flags: u32,
+    pad: u32,
+    u: [u32; 8],
+}
+
+impl KvmIrqRoutingEntry {
+    pub fn new_irqchip(gsi: u32, irqchip: u32, pin: u32) -> KvmIrqRoutingEntry {
+        let mut u = [0u32; 8];
+        u[0] = irqchip;
+        u[1] = pin;
+        KvmIrqRoutingEntry { gsi, route_type: KVM_IRQ_ROUTING_IRQCHIP, flags: 0, pad: 0, u }
+    }
+
+    pub fn new_msi(gsi: u32, address: u64, data: u32) -> KvmIrqRoutingEntry {
+        let mut u = [0u32; 8];
+        u[0] = address as u32;
+        u[1] = (address >> 32) as u32;
+        u[2] = data;
+        KvmIrqRoutingEntry { gsi, route_type: KVM_IRQ_ROUTING_MSI, flags: 0, pad: 0, u }
+    }
+}
+
+const KVM_MAX_IRQ_ROUTES: usize = 1024;
+
+#[repr(C)]
+pub struct KvmIrqRouting {
+    nr: u32,
+    flags: u32,
+    entries: [KvmIrqRoutingEntry; KVM_MAX_IRQ_ROUTES],
+}
+
+impl KvmIrqRouting {
+    pub fn new() -> Box<KvmIrqRouting> {
+        Box::new(KvmIrqRouting {
+            nr: 0,
+            flags: 0,
+            entries: [Default::default(); KVM_MAX_IRQ_ROUTES],
+        })
+    }
+
+    pub fn add_entry(&mut self, entry: KvmIrqRoutingEntry) -> bool {
+        let idx = self.nr as usize;
+        if idx >= KVM_MAX_IRQ_ROUTES {
+            return false;
+        }
+        self.entries[idx] = entry;
+        self.nr += 1;
+        true
+    }
+}
+
+pub fn kvm_set_gsi_routing(vmfd: &VmFd, routing: &KvmIrqRouting) -> Result<()> {
+    call_ioctl_with_ref("KVM_SET_GSI_ROUTING", vmfd.raw(), KVM_SET_GSI_ROUTING, routing)
+}
+
 pub const IOEVENTFD_FLAG_DATAMATCH: u32 = 1;
 pub const _IOEVENTFD_FLAG_PIO : u32 = 2;
 pub const IOEVENTFD_FLAG_DEASSIGN: u32 = 4;
@@ -200,7 +284,6 @@ impl KvmIoEventFd {
         KvmIoEventFd{datamatch, addr, len, fd, flags, padding: [0;36]}
     }
 
-    #[allow(dead_code)]
     pub fn set_datamatch(&mut self, datamatch: u64, len: u32) {
         self.flags |= IOEVENTFD_FLAG_DATAMATCH;
         self.datamatch = datamatch;
@@ -216,10 +299,12 @@ pub fn kvm_ioeventfd(vmfd: &VmFd, ioeventfd: &KvmIoEventFd) -> Result<()> {
     call_ioctl_with_ref("KVM_IOEVENTFD", vmfd.raw(), KVM_IOEVENTFD, ioeventfd)
 }
 
+#[cfg(target_arch = "x86_64")]
 pub fn kvm_get_regs(cpufd: &VcpuFd, regs: &mut KvmRegs) -> Result<()> {
     call_ioctl_with_mut_ref("KVM_GET_REGS", cpufd.raw(), KVM_GET_REGS, regs)
 }
 
+#[cfg(target_arch = "x86_64")]
 pub fn kvm_set_regs(cpufd: &VcpuFd, regs: &KvmRegs) -> Result<()> {
     call_ioctl_with_ref("KVM_SET_REGS", cpufd.raw(), KVM_SET_REGS, regs)
 }
@@ -228,6 +313,174 @@ pub fn kvm_run(cpufd: &VcpuFd) -> Result<()> {
     call_ioctl_with_val("KVM_RUN", cpufd.raw(), KVM_RUN, 0)
 }
 
+/// `struct kvm_translation`
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy,Clone,Default,Debug)]
+pub struct KvmTranslation {
+    pub linear_address: u64,
+    pub physical_address: u64,
+    pub valid: u8,
+    pub writeable: u8,
+    pub usermode: u8,
+    pad: [u8; 5],
+}
+
+#[cfg(target_arch = "x86_64")]
+impl KvmTranslation {
+    pub fn new(linear_address: u64) -> KvmTranslation {
+        KvmTranslation { linear_address, ..Default::default() }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn kvm_translate(cpufd: &VcpuFd, translation: &mut KvmTranslation) -> Result<()> {
+    call_ioctl_with_mut_ref("KVM_TRANSLATE", cpufd.raw(), KVM_TRANSLATE, translation)
+}
+
+#[repr(C)]
+#[derive(Copy,Clone,Default,Debug)]
+pub struct KvmMpState {
+    pub mp_state: u32,
+}
+
+pub fn kvm_get_mp_state(cpufd: &VcpuFd, mp_state: &mut KvmMpState) -> Result<()> {
+    call_ioctl_with_mut_ref("KVM_GET_MP_STATE", cpufd.raw(), KVM_GET_MP_STATE, mp_state)
+}
+
+pub fn kvm_set_mp_state(cpufd: &VcpuFd, mp_state: &KvmMpState) -> Result<()> {
+    call_ioctl_with_ref("KVM_SET_MP_STATE", cpufd.raw(), KVM_SET_MP_STATE, mp_state)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy,Clone,Default,Debug)]
+pub struct KvmVcpuEventsException {
+    pub injected: u8,
+    pub nr: u8,
+    pub has_error_code: u8,
+    pub pending: u8,
+    pub error_code: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy,Clone,Default,Debug)]
+pub struct KvmVcpuEventsInterrupt {
+    pub injected: u8,
+    pub nr: u8,
+    pub soft: u8,
+    pub shadow: u8,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy,Clone,Default,Debug)]
+pub struct KvmVcpuEventsNmi {
+    pub injected: u8,
+    pub pending: u8,
+    pub masked: u8,
+    pub pad: u8,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy,Clone,Default,Debug)]
+pub struct KvmVcpuEventsSmi {
+    pub smm: u8,
+    pub pending: u8,
+    pub smm_inside_nmi: u8,
+    pub latched_init: u8,
+}
+
+/// `struct kvm_vcpu_events`
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy,Clone,Default,Debug)]
+pub struct KvmVcpuEvents {
+    pub exception: KvmVcpuEventsException,
+    pub interrupt: KvmVcpuEventsInterrupt,
+    pub nmi: KvmVcpuEventsNmi,
+    pub sipi_vector: u32,
+    pub flags: u32,
+    pub smi: KvmVcpuEventsSmi,
+    reserved: [u8; 27],
+    pub exception_has_payload: u8,
+    pub exception_payload: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn kvm_get_vcpu_events(cpufd: &VcpuFd, events: &mut KvmVcpuEvents) -> Result<()> {
+    call_ioctl_with_mut_ref("KVM_GET_VCPU_EVENTS", cpufd.raw(), KVM_GET_VCPU_EVENTS, events)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn kvm_set_vcpu_events(cpufd: &VcpuFd, events: &KvmVcpuEvents) -> Result<()> {
+    call_ioctl_with_ref("KVM_SET_VCPU_EVENTS", cpufd.raw(), KVM_SET_VCPU_EVENTS, events)
+}
+
+/// `struct kvm_guest_debug`, with the x86 debug registers
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy,Clone,Default,Debug)]
+pub struct KvmGuestDebug {
+    pub control: u32,
+    pad: u32,
+    pub debugreg: [u64; 8],
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn kvm_set_guest_debug(cpufd: &VcpuFd, debug: &KvmGuestDebug) -> Result<()> {
+    call_ioctl_with_ref("KVM_SET_GUEST_DEBUG", cpufd.raw(), KVM_SET_GUEST_DEBUG, debug)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+pub struct KvmInterrupt {
+    irq: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl KvmInterrupt {
+    pub fn new(irq: u32) -> KvmInterrupt {
+        KvmInterrupt { irq }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn kvm_interrupt(cpufd: &VcpuFd, interrupt: &KvmInterrupt) -> Result<()> {
+    call_ioctl_with_ref("KVM_INTERRUPT", cpufd.raw(), KVM_INTERRUPT, interrupt)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn kvm_nmi(cpufd: &VcpuFd) -> Result<()> {
+    call_ioctl_with_val("KVM_NMI", cpufd.raw(), KVM_NMI, 0)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn kvm_smi(cpufd: &VcpuFd) -> Result<()> {
+    call_ioctl_with_val("KVM_SMI", cpufd.raw(), KVM_SMI, 0)
+}
+
+/// `struct kvm_enable_cap`
+#[repr(C)]
+pub struct KvmEnableCap {
+    cap: u32,
+    flags: u32,
+    args: [u64; 4],
+    pad: [u8; 64],
+}
+
+impl KvmEnableCap {
+    pub fn new(cap: u32) -> KvmEnableCap {
+        KvmEnableCap { cap, flags: 0, args: [0; 4], pad: [0; 64] }
+    }
+}
+
+pub fn kvm_enable_vcpu_cap(cpufd: &VcpuFd, cap: &KvmEnableCap) -> Result<()> {
+    call_ioctl_with_ref("KVM_ENABLE_CAP", cpufd.raw(), KVM_ENABLE_CAP, cap)
+}
+
 fn call_ioctl(name: &'static str, result: result::Result<u32, ErrnoError>) -> Result<()> {
     result.map_err(|e| Error::IoctlError(name, e))?;
     Ok(())
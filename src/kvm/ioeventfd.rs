@@ -1,24 +1,39 @@
-use std::sync::Arc;
 use std::os::unix::io::{AsRawFd,RawFd};
 
 use crate::kvm::{Kvm,Result,Error};
 use crate::system::EventFd;
 use crate::system;
 
+///
+/// An `EventFd` which KVM signals when the guest writes to an address, and
+/// which is unbound from the address when dropped.
+///
 pub struct IoEventFd {
     kvm: Kvm,
     addr: u64,
-    evt: Arc<EventFd>
+    datamatch: Option<u32>,
+    evt: EventFd,
 }
 
 impl IoEventFd {
     pub fn new(kvm: &Kvm, address: u64) -> Result<IoEventFd> {
+        Self::create(kvm, address, None)
+    }
+
+    /// Only signal the `EventFd` when the guest writes the 32 bit `value`
+    /// to `address`, so that several `IoEventFd`s can share one address.
+    pub fn with_datamatch(kvm: &Kvm, address: u64, value: u32) -> Result<IoEventFd> {
+        Self::create(kvm, address, Some(value))
+    }
+
+    fn create(kvm: &Kvm, address: u64, datamatch: Option<u32>) -> Result<IoEventFd> {
         let evt = EventFd::new().map_err(Error::IoEventCreate)?;
-        kvm.ioeventfd_add(address, evt.as_raw_fd())?;
+        kvm.ioeventfd_add(address, evt.as_raw_fd(), datamatch)?;
         Ok(IoEventFd {
             kvm: kvm.clone(),
             addr: address,
-            evt: evt.into(),
+            datamatch,
+            evt,
         })
     }
     pub fn read(&self) -> system::Result<u64> {
@@ -32,7 +47,7 @@ impl IoEventFd {
 
 impl Drop for IoEventFd {
     fn drop(&mut self) {
-        let _ = self.kvm.ioeventfd_del(self.addr, self.evt.as_raw_fd());
+        let _ = self.kvm.ioeventfd_del(self.addr, self.evt.as_raw_fd(), self.datamatch);
     }
 }
 
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use crate::kvm::{Result, Error};
+use crate::util::BitSet;
+use crate::kvm::ioctl::{self, VmFd, KvmIrqRouting, KvmIrqRoutingEntry};
+
+#[cfg(target_arch = "x86_64")]
+const KVM_IRQCHIP_PIC_MASTER: u32 = 0;
+#[cfg(target_arch = "x86_64")]
+const KVM_IRQCHIP_PIC_SLAVE: u32 = 1;
+#[cfg(target_arch = "x86_64")]
+const KVM_IRQCHIP_IOAPIC: u32 = 2;
+
+// The only irqchip of arm64 is the GIC, whose pins are its shared interrupts
+#[cfg(target_arch = "aarch64")]
+const KVM_IRQCHIP_GIC: u32 = 0;
+
+// Number of pins on the in-kernel IOAPIC. On arm64 this is the number of
+// shared interrupts of the GIC which are handed out for device interrupt lines.
+pub const IOAPIC_NUM_PINS: u32 = 24;
+
+// Upper limit of GSI numbers handed out by the allocator
+const MAX_GSI: u32 = 1024;
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum IrqRoute {
+    IrqChip { chip: u32, pin: u32 },
+    Msi { address: u64, data: u32 },
+}
+
+impl IrqRoute {
+    fn to_entry(&self, gsi: u32) -> KvmIrqRoutingEntry {
+        match *self {
+            IrqRoute::IrqChip { chip, pin } => KvmIrqRoutingEntry::new_irqchip(gsi, chip, pin),
+            IrqRoute::Msi { address, data } => KvmIrqRoutingEntry::new_msi(gsi, address, data),
+        }
+    }
+}
+
+///
+/// Tracks the set of allocated GSIs and the routes associated with each of them,
+/// and installs the complete table into the kernel with `KVM_SET_GSI_ROUTING`.
+///
+/// The table is initialized with the same routes that the kernel installs by default
+/// when the irqchip is created so that replacing the table does not change the
+/// delivery of legacy interrupts.
+///
+pub struct IrqRoutingTable {
+    routes: BTreeMap<u32, Vec<IrqRoute>>,
+    allocated: BitSet,
+}
+
+impl IrqRoutingTable {
+    pub fn new() -> Self {
+        let mut table = IrqRoutingTable {
+            routes: BTreeMap::new(),
+            allocated: BitSet::new(),
+        };
+        table.add_default_routes();
+        table
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn add_default_routes(&mut self) {
+        for gsi in 0..IOAPIC_NUM_PINS {
+            let mut v = vec![IrqRoute::IrqChip { chip: KVM_IRQCHIP_IOAPIC, pin: gsi }];
+            if gsi < 16 {
+                let chip = if gsi < 8 { KVM_IRQCHIP_PIC_MASTER } else { KVM_IRQCHIP_PIC_SLAVE };
+                v.push(IrqRoute::IrqChip { chip, pin: gsi % 8 });
+            }
+            self.routes.insert(gsi, v);
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn add_default_routes(&mut self) {
+        for gsi in 0..IOAPIC_NUM_PINS {
+            self.routes.insert(gsi, vec![IrqRoute::IrqChip { chip: KVM_IRQCHIP_GIC, pin: gsi }]);
+        }
+    }
+
+    /// Mark `gsi` as in use so that it will not be returned by a later allocation.
+    pub fn reserve(&mut self, gsi: u32) {
+        self.allocated.insert(gsi as usize);
+    }
+
+    pub fn is_allocated(&self, gsi: u32) -> bool {
+        self.allocated.get(gsi as usize)
+    }
+
+    /// Allocate the lowest free GSI wired to an IOAPIC pin, starting from `first`.
+    pub fn allocate_ioapic_line(&mut self, first: u32) -> Result<u32> {
+        for gsi in first..IOAPIC_NUM_PINS {
+            if !self.is_allocated(gsi) {
+                self.reserve(gsi);
+                return Ok(gsi);
+            }
+        }
+        Err(Error::NoGsiAvailable)
+    }
+
+    /// Allocate the first free GSI in `lines`, each of which must be wired to an IOAPIC pin.
+    pub fn allocate_ioapic_line_in(&mut self, lines: &[u32]) -> Result<u32> {
+        for &gsi in lines {
+            if gsi < IOAPIC_NUM_PINS && !self.is_allocated(gsi) {
+                self.reserve(gsi);
+                return Ok(gsi);
+            }
+        }
+        Err(Error::NoGsiAvailable)
+    }
+
+    /// Allocate a GSI above the IOAPIC range which has no route yet.
+    pub fn allocate_gsi(&mut self) -> Result<u32> {
+        for gsi in IOAPIC_NUM_PINS..MAX_GSI {
+            if !self.is_allocated(gsi) {
+                self.reserve(gsi);
+                return Ok(gsi);
+            }
+        }
+        Err(Error::NoGsiAvailable)
+    }
+
+    pub fn free_gsi(&mut self, gsi: u32) {
+        if gsi >= IOAPIC_NUM_PINS {
+            self.routes.remove(&gsi);
+        }
+        self.allocated.remove(gsi as usize);
+    }
+
+    /// Replace all routes for `gsi` with `route`.
+    pub fn set_route(&mut self, gsi: u32, route: IrqRoute) {
+        self.routes.insert(gsi, vec![route]);
+    }
+
+    pub fn routes(&self) -> Vec<(u32, IrqRoute)> {
+        self.routes.iter()
+            .flat_map(|(&gsi, v)| v.iter().map(move |&r| (gsi, r)))
+            .collect()
+    }
+
+    pub fn commit(&self, vmfd: &VmFd) -> Result<()> {
+        let mut routing = KvmIrqRouting::new();
+        for (gsi, route) in self.routes() {
+            if !routing.add_entry(route.to_entry(gsi)) {
+                return Err(Error::TooManyIrqRoutes);
+            }
+        }
+        ioctl::kvm_set_gsi_routing(vmfd, &routing)
+    }
+}
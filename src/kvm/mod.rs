@@ -1,29 +1,62 @@
 use std::os::unix::io::RawFd;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 mod ioctl;
 mod ioeventfd;
+mod irq_routing;
 mod error;
 
 pub use error::{Result,Error};
 pub use ioeventfd::IoEventFd;
+pub use irq_routing::{IrqRoute, IrqRoutingTable};
+#[cfg(target_arch = "x86_64")]
+pub use ioctl::{KvmVcpuEvents, KvmGuestDebug};
 
+#[cfg(target_arch = "x86_64")]
 use crate::vm::arch::KvmRegs;
 
 pub const KVM_CAP_IRQCHIP: u32 = 0;
+#[cfg(target_arch = "x86_64")]
 pub const KVM_CAP_HLT: u32 = 1;
 pub const KVM_CAP_USER_MEMORY: u32 = 3;
+#[cfg(target_arch = "x86_64")]
 pub const KVM_CAP_SET_TSS_ADDR: u32 = 4;
+#[cfg(target_arch = "x86_64")]
 pub const KVM_CAP_EXT_CPUID: u32 = 7;
 pub const KVM_CAP_IRQ_ROUTING: u32 = 25;
+#[cfg(target_arch = "x86_64")]
 pub const KVM_CAP_IRQ_INJECT_STATUS: u32 = 26;
+#[cfg(target_arch = "x86_64")]
 pub const KVM_CAP_PIT2: u32 = 33;
 pub const KVM_CAP_IOEVENTFD: u32 = 36;
+#[cfg(target_arch = "x86_64")]
+pub const KVM_CAP_XSAVE: u32 = 55;
+#[cfg(target_arch = "x86_64")]
+pub const KVM_CAP_XCRS: u32 = 56;
+#[cfg(target_arch = "x86_64")]
+pub const KVM_CAP_HYPERV_SYNIC: u32 = 123;
+#[cfg(target_arch = "x86_64")]
+pub const KVM_CAP_HYPERV_CPUID: u32 = 167;
+
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GUESTDBG_ENABLE: u32 = 0x0000_0001;
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GUESTDBG_SINGLESTEP: u32 = 0x0000_0002;
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GUESTDBG_USE_HW_BP: u32 = 0x0002_0000;
+
+// On arm64 KVM_IRQ_LINE takes the interrupt type in bits 24-31, and the
+// number of a shared interrupt counts the 32 private interrupts below it
+#[cfg(target_arch = "aarch64")]
+const KVM_ARM_IRQ_TYPE_SPI: u32 = 1 << 24;
+#[cfg(target_arch = "aarch64")]
+const GIC_SPI_BASE: u32 = 32;
 
 #[derive(Clone)]
 pub struct Kvm {
     sysfd: Arc<ioctl::SysFd>,
     vmfd: Arc<ioctl::VmFd>,
+    irq_routing: Arc<Mutex<IrqRoutingTable>>,
 }
 
 fn check_extensions(sysfd: &ioctl::SysFd, extensions: &[u32]) -> Result<()> {
@@ -63,6 +96,7 @@ impl Kvm {
         Ok(Kvm{
             sysfd: Arc::new(sysfd),
             vmfd: Arc::new(vmfd),
+            irq_routing: Arc::new(Mutex::new(IrqRoutingTable::new())),
         })
     }
 
@@ -84,7 +118,56 @@ impl Kvm {
         Ok(())
     }
 
+    /// Reserve a legacy GSI (such as one used by an ISA device) so that it will
+    /// not be handed out by `allocate_irq_line()`.
+    pub fn reserve_gsi(&self, gsi: u32) {
+        self.irq_routing.lock().unwrap().reserve(gsi);
+    }
+
+    /// Allocate a free GSI routed to an IOAPIC pin, searching upwards from `first`.
+    pub fn allocate_irq_line(&self, first: u32) -> Result<u32> {
+        let mut routing = self.irq_routing.lock().unwrap();
+        let gsi = routing.allocate_ioapic_line(first)?;
+        routing.commit(&self.vmfd)?;
+        Ok(gsi)
+    }
+
+    /// Allocate the first free GSI in `lines`, which are routed to IOAPIC pins.
+    pub fn allocate_irq_line_in(&self, lines: &[u32]) -> Result<u32> {
+        let mut routing = self.irq_routing.lock().unwrap();
+        let gsi = routing.allocate_ioapic_line_in(lines)?;
+        routing.commit(&self.vmfd)?;
+        Ok(gsi)
+    }
+
+    /// Allocate a GSI outside of the IOAPIC range and install `route` for it.
+    pub fn allocate_routed_gsi(&self, route: IrqRoute) -> Result<u32> {
+        let mut routing = self.irq_routing.lock().unwrap();
+        let gsi = routing.allocate_gsi()?;
+        routing.set_route(gsi, route);
+        if let Err(e) = routing.commit(&self.vmfd) {
+            routing.free_gsi(gsi);
+            return Err(e);
+        }
+        Ok(gsi)
+    }
+
+    /// Change the route of a GSI previously returned from `allocate_routed_gsi()`.
+    pub fn update_gsi_route(&self, gsi: u32, route: IrqRoute) -> Result<()> {
+        let mut routing = self.irq_routing.lock().unwrap();
+        routing.set_route(gsi, route);
+        routing.commit(&self.vmfd)
+    }
+
+    pub fn free_gsi(&self, gsi: u32) -> Result<()> {
+        let mut routing = self.irq_routing.lock().unwrap();
+        routing.free_gsi(gsi);
+        routing.commit(&self.vmfd)
+    }
+
     pub fn irq_line(&self, irq: u32, level: u32) -> Result<()> {
+        #[cfg(target_arch = "aarch64")]
+        let irq = KVM_ARM_IRQ_TYPE_SPI | (irq + GIC_SPI_BASE);
         let irq_level = ioctl::KvmIrqLevel::new(irq, level);
         ioctl::kvm_irq_line(&self.vmfd, &irq_level)?;
         Ok(())
@@ -95,14 +178,20 @@ impl Kvm {
         ioctl::kvm_irqfd(&self.vmfd, &irqfd)
     }
 
-    pub fn ioeventfd_add(&self, address: u64, fd: RawFd) -> Result<()> {
+    pub fn ioeventfd_add(&self, address: u64, fd: RawFd, datamatch: Option<u32>) -> Result<()> {
         // XXX check for zero length capability
-        let ioeventfd = ioctl::KvmIoEventFd::new_with_addr_fd(address, fd);
+        let mut ioeventfd = ioctl::KvmIoEventFd::new_with_addr_fd(address, fd);
+        if let Some(value) = datamatch {
+            ioeventfd.set_datamatch(value as u64, 4);
+        }
         ioctl::kvm_ioeventfd(&self.vmfd, &ioeventfd)
     }
 
-    pub fn ioeventfd_del(&self, address: u64, fd: RawFd) -> Result<()> {
+    pub fn ioeventfd_del(&self, address: u64, fd: RawFd, datamatch: Option<u32>) -> Result<()> {
         let mut ioeventfd = ioctl::KvmIoEventFd::new_with_addr_fd(address, fd);
+        if let Some(value) = datamatch {
+            ioeventfd.set_datamatch(value as u64, 4);
+        }
         ioeventfd.set_deassign();
         ioctl::kvm_ioeventfd(&self.vmfd, &ioeventfd)
     }
@@ -129,6 +218,11 @@ impl KvmVcpu {
         KvmVcpu { id, cpufd, sysfd }
     }
 
+    /// The index of this vcpu, which is also its APIC ID.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
     pub fn raw_fd(&self) -> RawFd {
         self.cpufd.raw()
     }
@@ -137,6 +231,42 @@ impl KvmVcpu {
         self.sysfd.raw()
     }
 
+    /// Returns `true` if the kvm device supports `extension`
+    pub fn has_extension(&self, extension: u32) -> bool {
+        match ioctl::kvm_check_extension(&self.sysfd, extension) {
+            Ok(ret) => ret != 0,
+            Err(_) => false,
+        }
+    }
+
+    /// Enable a per-vcpu capability which takes no arguments
+    pub fn enable_cap(&self, cap: u32) -> Result<()> {
+        ioctl::kvm_enable_vcpu_cap(&self.cpufd, &ioctl::KvmEnableCap::new(cap))
+    }
+
+    pub fn run(&self) -> Result<()> {
+        ioctl::kvm_run(&self.cpufd)?;
+        Ok(())
+    }
+
+    pub fn get_mp_state(&self) -> Result<MpState> {
+        let mut mp_state = ioctl::KvmMpState::default();
+        ioctl::kvm_get_mp_state(&self.cpufd, &mut mp_state)?;
+        Ok(MpState::from_raw(mp_state.mp_state))
+    }
+
+    pub fn set_mp_state(&self, state: MpState) -> Result<()> {
+        let mp_state = ioctl::KvmMpState { mp_state: state.to_raw() };
+        ioctl::kvm_set_mp_state(&self.cpufd, &mp_state)
+    }
+
+    pub fn get_vcpu_mmap_size(&self) -> Result<usize> {
+        Ok(ioctl::kvm_get_vcpu_mmap_size(&self.sysfd)? as usize)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl KvmVcpu {
     pub fn get_regs(&self) -> Result<KvmRegs> {
         let mut regs = KvmRegs::new();
         ioctl::kvm_get_regs(&self.cpufd, &mut regs)?;
@@ -148,13 +278,105 @@ impl KvmVcpu {
         Ok(())
     }
 
-    pub fn run(&self) -> Result<()> {
-        ioctl::kvm_run(&self.cpufd)?;
-        Ok(())
+    /// The guest physical address which the guest virtual address `address`
+    /// maps to in the current address space of the vcpu, or `None` if it is
+    /// not mapped.
+    pub fn translate(&self, address: u64) -> Result<Option<u64>> {
+        let mut translation = ioctl::KvmTranslation::new(address);
+        ioctl::kvm_translate(&self.cpufd, &mut translation)?;
+        if translation.valid == 0 {
+            return Ok(None);
+        }
+        Ok(Some(translation.physical_address))
     }
 
-    pub fn get_vcpu_mmap_size(&self) -> Result<usize> {
-        Ok(ioctl::kvm_get_vcpu_mmap_size(&self.sysfd)? as usize)
+    /// Set the `KVM_GUESTDBG_*` flags and the debug registers which KVM uses
+    /// to stop the vcpu for a debugger.
+    pub fn set_guest_debug(&self, debug: &KvmGuestDebug) -> Result<()> {
+        ioctl::kvm_set_guest_debug(&self.cpufd, debug)
+    }
+
+    pub fn get_vcpu_events(&self) -> Result<KvmVcpuEvents> {
+        let mut events = KvmVcpuEvents::default();
+        ioctl::kvm_get_vcpu_events(&self.cpufd, &mut events)?;
+        Ok(events)
+    }
+
+    pub fn set_vcpu_events(&self, events: &KvmVcpuEvents) -> Result<()> {
+        ioctl::kvm_set_vcpu_events(&self.cpufd, events)
+    }
+
+    /// Queue exception `vector` for delivery on the next entry into the guest.
+    pub fn inject_exception(&self, vector: u8, error_code: Option<u32>) -> Result<()> {
+        let mut events = self.get_vcpu_events()?;
+        events.exception.injected = 1;
+        events.exception.nr = vector;
+        events.exception.has_error_code = error_code.is_some() as u8;
+        events.exception.error_code = error_code.unwrap_or(0);
+        // Only update the exception state
+        events.flags = 0;
+        self.set_vcpu_events(&events)
+    }
+
+    /// Inject an external interrupt. This is only valid when the vm does not
+    /// use the in-kernel irqchip.
+    pub fn inject_interrupt(&self, irq: u32) -> Result<()> {
+        ioctl::kvm_interrupt(&self.cpufd, &ioctl::KvmInterrupt::new(irq))
+    }
+
+    pub fn inject_nmi(&self) -> Result<()> {
+        ioctl::kvm_nmi(&self.cpufd)
+    }
+
+    pub fn inject_smi(&self) -> Result<()> {
+        ioctl::kvm_smi(&self.cpufd)
     }
 }
 
+
+/// Multiprocessing state of a vcpu (`KVM_MP_STATE_*`)
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum MpState {
+    Runnable,
+    Uninitialized,
+    InitReceived,
+    Halted,
+    SipiReceived,
+    Stopped,
+    CheckStop,
+    Operating,
+    Load,
+    Unknown(u32),
+}
+
+impl MpState {
+    fn from_raw(val: u32) -> MpState {
+        match val {
+            0 => MpState::Runnable,
+            1 => MpState::Uninitialized,
+            2 => MpState::InitReceived,
+            3 => MpState::Halted,
+            4 => MpState::SipiReceived,
+            5 => MpState::Stopped,
+            6 => MpState::CheckStop,
+            7 => MpState::Operating,
+            8 => MpState::Load,
+            n => MpState::Unknown(n),
+        }
+    }
+
+    fn to_raw(self) -> u32 {
+        match self {
+            MpState::Runnable => 0,
+            MpState::Uninitialized => 1,
+            MpState::InitReceived => 2,
+            MpState::Halted => 3,
+            MpState::SipiReceived => 4,
+            MpState::Stopped => 5,
+            MpState::CheckStop => 6,
+            MpState::Operating => 7,
+            MpState::Load => 8,
+            MpState::Unknown(n) => n,
+        }
+    }
+}
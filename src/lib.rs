@@ -12,4 +12,11 @@ mod virtio;
 mod disk;
 
 pub use util::{Logger,LogLevel};
-pub use vm::VmConfig;
+pub use vm::{VmConfig, MinimalRoot, Bundle, ControlClient, RamPolicy, ConsoleMode, ExitStatus, FailureKind, RebootAction, VmEvent, BootTables, PlatformProfile, warm_boot_cache};
+pub use disk::{OpenType, BlockTopology};
+pub use virtio::{PciIdentity, IrqPolicy};
+pub use devices::{MacAddress, SoundBackend, CacheHint, VhostUserKind, VhostUserSpec, UartBackend};
+pub use system::SeccompMode;
+pub use memory::HugePages;
+#[cfg(feature = "fuzzing")]
+pub use virtio::fuzz_chain_layout;
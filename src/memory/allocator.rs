@@ -20,6 +20,10 @@ impl SystemAllocator {
     pub fn allocate_device_memory(&self, size: usize) -> Option<u64> {
         self.device_memory.allocate(size)
     }
+
+    pub fn allocate_device_memory_aligned(&self, size: usize, alignment: usize) -> Option<u64> {
+        self.device_memory.allocate_aligned(size, alignment)
+    }
 }
 
 #[derive(Clone)]
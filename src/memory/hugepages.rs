@@ -0,0 +1,132 @@
+use std::fs;
+
+use crate::util::JsonValue;
+
+const MB: usize = 1 << 20;
+const GB: usize = 1 << 30;
+
+// The fields of /proc/self/smaps which count memory mapped with huge pages
+const SMAPS_HUGE_FIELDS: &[&str] = &[
+    "AnonHugePages", "ShmemPmdMapped", "FilePmdMapped", "Shared_Hugetlb", "Private_Hugetlb",
+];
+
+///
+/// The pages which back guest RAM. Huge pages need fewer TLB entries and
+/// shorter page table walks in both the host and the nested page tables of
+/// the guest.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum HugePages {
+    /// Ordinary pages of the host.
+    None,
+    /// Transparent huge pages, asked for with `madvise(MADV_HUGEPAGE)`,
+    /// which the host kernel uses where it can find them.
+    Transparent,
+    /// Pages of this size taken from the hugetlb pool of the host when the
+    /// VM starts, which is 2 MiB or 1 GiB.
+    Hugetlb(usize),
+}
+
+impl HugePages {
+    /// Parse `none`, `thp`, `2M` or `1G`.
+    pub fn parse(s: &str) -> Option<HugePages> {
+        match s {
+            "none" => Some(HugePages::None),
+            "thp" => Some(HugePages::Transparent),
+            "2M" => Some(HugePages::Hugetlb(2 * MB)),
+            "1G" => Some(HugePages::Hugetlb(GB)),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HugePages::None => "none",
+            HugePages::Transparent => "thp",
+            HugePages::Hugetlb(size) if size == GB => "1G",
+            HugePages::Hugetlb(_) => "2M",
+        }
+    }
+
+    /// Size of the pages, or `None` for ordinary and transparent huge pages.
+    pub fn page_size(self) -> Option<usize> {
+        match self {
+            HugePages::Hugetlb(size) => Some(size),
+            _ => None,
+        }
+    }
+}
+
+impl Default for HugePages {
+    fn default() -> Self {
+        HugePages::None
+    }
+}
+
+///
+/// The huge pages asked for guest RAM and those which back it, which are
+/// fewer if the host could not provide them.
+///
+#[derive(Clone,Debug)]
+pub struct RamBacking {
+    requested: HugePages,
+    pages: HugePages,
+    fallbacks: Vec<String>,
+}
+
+impl RamBacking {
+    pub fn new(requested: HugePages) -> RamBacking {
+        RamBacking { requested, pages: requested, fallbacks: Vec::new() }
+    }
+
+    pub fn pages(&self) -> HugePages {
+        self.pages
+    }
+
+    /// Back guest RAM with `pages` instead because of `reason`.
+    pub fn fall_back(&mut self, pages: HugePages, reason: String) {
+        self.fallbacks.push(format!("{} instead of {}: {}", pages.name(), self.pages.name(), reason));
+        self.pages = pages;
+    }
+
+    /// Describe the backing of guest RAM, with the bytes of the `mappings`
+    /// of guest RAM which the host currently maps with huge pages.
+    pub fn describe(&self, mappings: &[(u64, usize)]) -> JsonValue {
+        let mut v = JsonValue::object()
+            .with("requested", self.requested.name())
+            .with("backing", self.pages.name())
+            .with("fallbacks", self.fallbacks.iter().map(|s| JsonValue::from(s.as_str())).collect::<Vec<_>>());
+        if let Some(bytes) = huge_mapped_bytes(mappings) {
+            v = v.with("huge-mapped-bytes", bytes);
+        }
+        v
+    }
+}
+
+// Sum the huge page fields of the entries in /proc/self/smaps which lie
+// within `mappings`, given as the host address and size of each.
+fn huge_mapped_bytes(mappings: &[(u64, usize)]) -> Option<u64> {
+    let smaps = fs::read_to_string("/proc/self/smaps").ok()?;
+    let mut inside = false;
+    let mut kb = 0u64;
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        let first = match fields.next() {
+            Some(first) => first,
+            None => continue,
+        };
+        if let Some(start) = vma_start(first) {
+            inside = mappings.iter().any(|&(base, size)| start >= base && start < base + size as u64);
+        } else if inside && SMAPS_HUGE_FIELDS.iter().any(|f| first.len() == f.len() + 1 && first.starts_with(f)) {
+            kb += fields.next().and_then(|n| n.parse::<u64>().ok()).unwrap_or(0);
+        }
+    }
+    Some(kb * 1024)
+}
+
+// The start address of a line such as `7f1c00000000-7f1c40000000 rw-s ...`
+// which begins an entry
+fn vma_start(field: &str) -> Option<u64> {
+    let dash = field.find('-')?;
+    u64::from_str_radix(&field[..dash], 16).ok()
+}
@@ -1,14 +1,14 @@
-use std::collections::HashMap;
-use std::os::unix::io::{AsRawFd,RawFd};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
-use crate::memory::{GuestRam, SystemAllocator, Mapping, Error, Result};
+use crate::memory::{GuestRam, SystemAllocator, Error, Result};
 use crate::kvm::Kvm;
-use crate::system::FileDesc;
+use crate::system::{self, FileDesc};
 use crate::util::BitSet;
 use crate::memory::drm::{DrmBufferAllocator, DrmDescriptor};
-use std::io::SeekFrom;
 use crate::memory::ram::MemoryRegion;
+use crate::memory::{AddressRange, SharedMemoryRegion, HugePages, RamBacking};
+use crate::memory::shared::{RegionState, round_to_page_size};
+use crate::util::JsonValue;
 
 #[derive(Clone)]
 pub struct MemoryManager {
@@ -16,6 +16,7 @@ pub struct MemoryManager {
     ram: GuestRam,
     device_memory: Arc<RwLock<DeviceMemory>>,
     drm_allocator: Option<DrmBufferAllocator>,
+    ram_backing: RamBacking,
 }
 
 impl MemoryManager {
@@ -30,6 +31,7 @@ impl MemoryManager {
         Ok(MemoryManager {
             kvm, ram, device_memory,
             drm_allocator,
+            ram_backing: RamBacking::new(HugePages::None),
         })
     }
 
@@ -47,47 +49,111 @@ impl MemoryManager {
         self.ram.set_regions(regions);
     }
 
-    pub fn register_device_memory(&self, fd: RawFd, size: usize) -> Result<(u64, u32)> {
-        let mut devmem = self.device_memory.write().unwrap();
-        devmem.register(self.kvm(), fd, size)
+    pub fn set_ram_backing(&mut self, backing: RamBacking) {
+        self.ram_backing = backing;
     }
 
-    pub fn unregister_device_memory(&self, slot: u32) -> Result<()> {
-        let mut devmem = self.device_memory.write().unwrap();
-        devmem.unregister(self.kvm(), slot)
+    /// Describe the pages which back guest RAM and how much of it the host
+    /// currently maps with huge pages.
+    pub fn describe_ram_backing(&self) -> JsonValue {
+        let mappings = self.ram.regions().iter()
+            .map(|r| (r.base_address(), r.guest_range().size()))
+            .collect::<Vec<_>>();
+        self.ram_backing.describe(&mappings)
     }
 
-    pub fn drm_available(&self) -> bool {
-        self.drm_allocator.is_some()
+    /// Reserve a window of device memory for a shared memory region of
+    /// `size` bytes with shared memory id `id` on the device `name`. The
+    /// window is a power of two in size and naturally aligned.
+    pub fn create_shared_region(&self, id: u8, name: &str, size: usize) -> Result<SharedMemoryRegion> {
+        let size = round_to_page_size(size);
+        if size == 0 {
+            return Err(Error::DeviceMemoryAllocFailed);
+        }
+        let window = self.allocate_device_window(size)?;
+        let region = SharedMemoryRegion::new(self.clone(), id, name, window, size);
+        self.device_memory.write().unwrap().shared_regions.push(region.state());
+        Ok(region)
+    }
+
+    /// Reserve a naturally aligned window of at least `size` bytes in the
+    /// device memory above guest RAM, for a 64 bit PCI BAR.
+    pub fn allocate_device_window(&self, size: usize) -> Result<AddressRange> {
+        let window_size = size.next_power_of_two();
+        let devmem = self.device_memory.read().unwrap();
+        let base = devmem.allocator.allocate_device_memory_aligned(window_size, window_size)
+            .ok_or(Error::DeviceMemoryAllocFailed)?;
+        Ok(AddressRange::new(base, window_size))
     }
 
-    pub fn allocate_drm_buffer(&self, width: u32, height: u32, format: u32) -> Result<(u64, u32, FileDesc, DrmDescriptor)> {
-        if let Some(drm_allocator) = self.drm_allocator.as_ref() {
-            let (fd, desc) = drm_allocator.allocate(width, height, format)?;
-            let size = fd.seek(SeekFrom::End(0)).map_err(Error::CreateBuffer)?;
+    pub(super) fn free_shared_window(&self, base: u64) {
+        let devmem = self.device_memory.read().unwrap();
+        devmem.allocator.free_device_memory(base);
+    }
 
-            let (pfn, slot) = self.register_device_memory(fd.as_raw_fd(), size as usize)?;
-            Ok((pfn, slot, fd, desc))
-        } else {
-            Err(Error::NoDrmAllocator)
+    pub(super) fn allocate_slot(&self) -> u32 {
+        self.device_memory.write().unwrap().allocate_slot()
+    }
+
+    pub(super) fn free_slot(&self, slot: u32) {
+        self.device_memory.write().unwrap().free_slot(slot)
+    }
+
+    /// The shared memory regions of devices which have not been removed.
+    pub fn describe_device_memory(&self) -> JsonValue {
+        let regions = {
+            let mut devmem = self.device_memory.write().unwrap();
+            devmem.shared_regions.retain(|r| !r.lock().unwrap().is_removed());
+            devmem.shared_regions.clone()
+        };
+        let regions: Vec<JsonValue> = regions.iter()
+            .map(|r| r.lock().unwrap().describe())
+            .collect();
+        JsonValue::object()
+            .with("shared-regions", regions)
+    }
+
+    /// True if memory which the guest gives up can be released on the host.
+    /// Huge pages from the hugetlb pool cannot be released in smaller pieces.
+    pub fn can_discard_ram(&self) -> bool {
+        self.ram_backing.pages().page_size().is_none()
+    }
+
+    /// Release the host memory backing a range of guest RAM which the guest
+    /// has given up, such as pages placed in the balloon. Fails with
+    /// `EOPNOTSUPP` if guest RAM is backed by hugetlb pages.
+    pub fn discard_ram(&self, guest_address: u64, size: usize) -> system::Result<()> {
+        if !self.can_discard_ram() {
+            return Err(system::Error::from_raw_os_error(libc::EOPNOTSUPP));
         }
+        self.ram.discard(guest_address, size)
     }
-}
 
-pub struct MemoryRegistration {
-    guest_addr: u64,
-    _mapping: Mapping,
-}
+    /// Zero guest RAM and the shared memory regions which are marked with
+    /// `set_scrub()`. Other shared memory regions map host files and are not
+    /// zeroed.
+    pub fn scrub(&self) {
+        self.ram.scrub();
+        let devmem = self.device_memory.read().unwrap();
+        devmem.scrub();
+    }
 
-impl MemoryRegistration {
-    fn new(guest_addr: u64, mapping: Mapping)-> Self {
-        MemoryRegistration { guest_addr, _mapping: mapping }
+    pub fn drm_available(&self) -> bool {
+        self.drm_allocator.is_some()
+    }
+
+    /// Allocate a DRM buffer, which the caller maps into the guest.
+    pub fn allocate_drm_buffer(&self, width: u32, height: u32, format: u32) -> Result<(FileDesc, DrmDescriptor)> {
+        match self.drm_allocator.as_ref() {
+            Some(drm_allocator) => drm_allocator.allocate(width, height, format),
+            None => Err(Error::NoDrmAllocator),
+        }
     }
 }
 
 struct DeviceMemory {
     slots: BitSet,
-    mappings: HashMap<u32, MemoryRegistration>,
+    shared_regions: Vec<Arc<Mutex<RegionState>>>,
     allocator: SystemAllocator,
 }
 
@@ -95,7 +161,7 @@ impl DeviceMemory {
     fn new(ram_region_count: usize, allocator: SystemAllocator) -> DeviceMemory {
         let mut devmem = DeviceMemory {
             slots: BitSet::new(),
-            mappings: HashMap::new(),
+            shared_regions: Vec::new(),
             allocator
         };
         devmem.set_slots_occupied(0, ram_region_count);
@@ -108,39 +174,10 @@ impl DeviceMemory {
         }
     }
 
-    fn register(&mut self, kvm: &Kvm, fd: RawFd, size: usize) -> Result<(u64, u32)> {
-        let mapping = Mapping::new_from_fd(fd, size)
-            .map_err(Error::MappingFailed)?;
-
-        let (addr, slot) = self.allocate_addr_and_slot(size)?;
-
-        if let Err(e) = kvm.add_memory_region(slot, addr, mapping.address(), size) {
-            self.free_addr_and_slot(addr, slot);
-            Err(Error::RegisterMemoryFailed(e))
-        } else {
-            self.mappings.insert(slot, MemoryRegistration::new(addr, mapping));
-            Ok((addr >> 12, slot))
-        }
-    }
-
-    fn unregister(&mut self, kvm: &Kvm, slot: u32) -> Result<()> {
-        if let Some(registration) = self.mappings.remove(&slot) {
-            kvm.remove_memory_region(slot)
-                .map_err(Error::UnregisterMemoryFailed)?;
-            self.free_addr_and_slot(registration.guest_addr, slot);
+    fn scrub(&self) {
+        for region in self.shared_regions.iter() {
+            region.lock().unwrap().scrub();
         }
-        Ok(())
-    }
-
-    fn allocate_addr_and_slot(&mut self, size: usize) -> Result<(u64, u32)> {
-        let addr = self.allocator.allocate_device_memory(size)
-            .ok_or(Error::DeviceMemoryAllocFailed)?;
-        Ok((addr, self.allocate_slot()))
-    }
-
-    fn free_addr_and_slot(&mut self, addr: u64, slot: u32) {
-        self.allocator.free_device_memory(addr);
-        self.free_slot(slot);
     }
 
     fn allocate_slot(&mut self) -> u32 {
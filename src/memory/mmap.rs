@@ -2,11 +2,15 @@ use libc;
 use std::ptr;
 use std::slice;
 use std::mem;
+use std::sync::atomic;
 use std::io::Write;
 use std::os::unix::io::RawFd;
 
 use crate::system::{Result,Error};
 
+// Not defined by the libc crate version in use
+const MADV_PAGEOUT: libc::c_int = 21;
+
 pub struct Mapping {
     ptr: *mut u8,
     size: usize,
@@ -34,7 +38,7 @@ impl Mapping {
     /// the system error which occurred.
     ///
     pub fn new(size: usize) -> Result<Mapping> {
-        Mapping::_new(size,libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE, -1)
+        Mapping::_new(size,libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE, -1, 0)
     }
 
     /// Creates a new mapping of `size` bytes from the object referenced by file descriptor `fd`
@@ -44,12 +48,22 @@ impl Mapping {
     /// the system error which occurred.
     ///
     pub fn new_from_fd(fd: RawFd, size: usize) -> Result<Mapping> {
-        Mapping::_new(size, libc::MAP_SHARED, fd)
+        Mapping::_new(size, libc::MAP_SHARED, fd, 0)
     }
 
+    /// Creates a new mapping of `size` bytes starting at `offset` into the file
+    /// referenced by file descriptor `fd`
+    ///
+    /// # Errors
+    /// Returns [`Err`] if the `mmap()` system call fails and returns an `Error` representing
+    /// the system error which occurred.
+    ///
+    pub fn new_from_fd_offset(fd: RawFd, offset: usize, size: usize) -> Result<Mapping> {
+        Mapping::_new(size, libc::MAP_SHARED, fd, offset)
+    }
 
-    fn _new(size: usize, flags: libc::c_int, fd: RawFd) -> Result<Mapping> {
-        let p = unsafe { mmap_allocate(size, flags, fd)? };
+    fn _new(size: usize, flags: libc::c_int, fd: RawFd, offset: usize) -> Result<Mapping> {
+        let p = unsafe { mmap_allocate(size, flags, fd, offset)? };
         Ok(Mapping {
             ptr: p,
             size
@@ -76,6 +90,11 @@ impl Mapping {
         self.ptr as u64
     }
 
+    /// Return the size in bytes of this allocation.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
     /// Read and return an integer value in native byte order from `offset` into the memory allocation
     ///
     /// # Errors
@@ -85,7 +104,13 @@ impl Mapping {
     pub fn read_int<T: Serializable>(&self, offset: usize) -> Result<T> {
         self.check_offset(offset + mem::size_of::<T>())?;
         unsafe {
-           Ok(ptr::read_volatile(&self.as_slice()[offset..] as *const _ as *const T))
+            let p = &self.as_slice()[offset..] as *const _ as *const T;
+            // Fields of guest structures such as 9p headers need not be aligned
+            if p as usize % mem::align_of::<T>() == 0 {
+                Ok(ptr::read_volatile(p))
+            } else {
+                Ok(ptr::read_unaligned(p))
+            }
         }
     }
 
@@ -97,7 +122,14 @@ impl Mapping {
     ///
     pub fn write_int<T: Serializable>(&self, offset: usize, val: T) -> Result<()> {
         self.check_offset(offset + mem::size_of::<T>())?;
-        unsafe { ptr::write_volatile(&mut self.as_mut_slice()[offset..] as *mut _ as *mut T, val); }
+        unsafe {
+            let p = &mut self.as_mut_slice()[offset..] as *mut _ as *mut T;
+            if p as usize % mem::align_of::<T>() == 0 {
+                ptr::write_volatile(p, val);
+            } else {
+                ptr::write_unaligned(p, val);
+            }
+        }
         Ok(())
     }
 
@@ -144,6 +176,72 @@ impl Mapping {
         Ok(())
     }
 
+    /// Ask the host kernel to back the mapping with transparent huge pages.
+    pub fn set_hugepage(&self) -> Result<()> {
+        unsafe {
+            if libc::madvise(self.ptr as *mut libc::c_void, self.size, libc::MADV_HUGEPAGE) == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write any modified pages of a file mapping back to the file.
+    pub fn sync(&self) -> Result<()> {
+        unsafe {
+            if libc::msync(self.ptr as *mut libc::c_void, self.size, libc::MS_SYNC) == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write any modified pages of a file mapping back to the file and ask the
+    /// kernel to reclaim the memory backing this mapping. Pages of an anonymous
+    /// mapping are moved to swap. The pages are faulted back in when accessed.
+    ///
+    pub fn page_out(&self) -> Result<()> {
+        unsafe {
+            if libc::msync(self.ptr as *mut libc::c_void, self.size, libc::MS_SYNC) == -1 {
+                return Err(Error::last_os_error());
+            }
+            if libc::madvise(self.ptr as *mut libc::c_void, self.size, MADV_PAGEOUT) == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Free the memory, or the blocks of the file, which back `size` bytes at
+    /// `offset`, which must be page aligned. The pages read as zero when they
+    /// are next accessed. `MADV_DONTNEED` would only drop the page table
+    /// entries of a shared mapping and leave the pages in the page cache, so
+    /// a hole is punched in the shared memory or file behind the mapping
+    /// instead, which fails if its file system cannot punch holes.
+    ///
+    pub fn discard(&self, offset: usize, size: usize) -> Result<()> {
+        self.check_offset(offset + size)?;
+        unsafe {
+            let addr = self.ptr.add(offset) as *mut libc::c_void;
+            if libc::madvise(addr, size, libc::MADV_REMOVE) == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Overwrite the entire mapping with zero bytes.
+    ///
+    /// Used to scrub guest memory contents before the mapping is released
+    /// so that data does not linger in pages recycled by the host kernel.
+    ///
+    pub fn zero(&self) {
+        unsafe {
+            ptr::write_bytes(self.ptr, 0, self.size);
+        }
+        atomic::compiler_fence(atomic::Ordering::SeqCst);
+    }
+
     unsafe fn as_slice(&self) -> &[u8] {
         slice::from_raw_parts(self.ptr, self.size)
     }
@@ -160,10 +258,10 @@ impl Drop for Mapping {
     }
 }
 
-unsafe fn mmap_allocate(size: usize, flags: libc::c_int, fd: libc::c_int) -> Result<*mut u8> {
+unsafe fn mmap_allocate(size: usize, flags: libc::c_int, fd: libc::c_int, offset: usize) -> Result<*mut u8> {
     let p = libc::mmap(ptr::null_mut(),
                    size, libc::PROT_READ|libc::PROT_WRITE,
-                    flags, fd, 0);
+                    flags, fd, offset as libc::off_t);
 
     if p.is_null() || p == libc::MAP_FAILED {
         return Err(Error::last_os_error());
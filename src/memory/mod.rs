@@ -4,12 +4,16 @@ mod manager;
 mod mmap;
 mod address;
 mod allocator;
+mod shared;
+mod hugepages;
 
 pub use self::allocator::SystemAllocator;
 pub use self::address::AddressRange;
 pub use self::mmap::Mapping;
 pub use self::ram::{GuestRam,MemoryRegion};
 pub use manager::MemoryManager;
+pub use shared::SharedMemoryRegion;
+pub use hugepages::{HugePages, RamBacking};
 
 pub use drm::{DrmDescriptor,DrmPlaneDescriptor};
 
@@ -28,6 +32,10 @@ pub enum Error {
     PrimeHandleToFD(system::ErrnoError),
     CreateBuffer(io::Error),
     NoDrmAllocator,
+    InvalidSharedMapping(usize, usize),
+    SharedMappingNotFound(usize),
+    SharedRegionResize(usize),
+    SharedRegionRemoved,
 }
 
 impl fmt::Display for Error {
@@ -44,6 +52,10 @@ impl fmt::Display for Error {
             OpenRenderNode(err) => write!(f, "error opening render node: {}", err),
             CreateBuffer(err) => write!(f, "failed to create buffer: {}", err),
             NoDrmAllocator => write!(f, "no DRM allocator is available"),
+            InvalidSharedMapping(offset, size) => write!(f, "invalid mapping of {} bytes at offset 0x{:x} of shared memory region", size, offset),
+            SharedMappingNotFound(offset) => write!(f, "no mapping at offset 0x{:x} of shared memory region", offset),
+            SharedRegionResize(size) => write!(f, "cannot resize shared memory region to {} bytes", size),
+            SharedRegionRemoved => write!(f, "shared memory region has been removed"),
         }
     }
 }
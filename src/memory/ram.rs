@@ -1,9 +1,10 @@
 use std::sync::Arc;
 use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use crate::memory::{Mapping,AddressRange};
 use crate::memory::mmap::Serializable;
-use crate::system::{Result, Error};
+use crate::system::{Result, Error, FileDesc};
 use crate::util::ByteBuffer;
 
 #[derive(Clone)]
@@ -74,6 +75,65 @@ impl GuestRam {
             .map_or(0, |r| r.guest_range.end())
     }
 
+    /// Zero the contents of every memory region.
+    pub fn scrub(&self) {
+        for r in self.regions.iter() {
+            r.mapping.zero();
+        }
+    }
+
+    /// Write modified guest pages back to the file which backs guest RAM.
+    pub fn sync(&self) -> Result<()> {
+        for r in self.regions.iter() {
+            r.mapping.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Ask the host kernel to reclaim the memory backing every memory region.
+    pub fn page_out(&self) -> Result<()> {
+        for r in self.regions.iter() {
+            r.mapping.page_out()?;
+        }
+        Ok(())
+    }
+
+    /// Release the host memory backing `size` bytes of guest memory at
+    /// `guest_address`, which the guest will read back as zero.
+    pub fn discard(&self, guest_address: u64, size: usize) -> Result<()> {
+        let region = self.find_region(guest_address, size)?;
+        let offset = region.checked_offset(guest_address, size)?;
+        region.mapping.discard(offset, size)
+    }
+
+    /// Remove every memory region from the address space of this process,
+    /// for a child process which must not be able to reach guest memory.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may access guest memory through any `GuestRam` afterwards, and
+    /// the process must exit without dropping the regions.
+    pub unsafe fn unmap(&self) -> Result<()> {
+        for r in self.regions.iter() {
+            if libc::munmap(r.mapping.address() as *mut libc::c_void, r.mapping.size()) == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    /// Address in the host process at which `size` bytes of guest memory at
+    /// `guest_address` are mapped.
+    pub fn host_address(&self, guest_address: u64, size: usize) -> Result<u64> {
+        let region = self.find_region(guest_address, size)?;
+        let offset = region.checked_offset(guest_address, size)?;
+        Ok(region.base_address() + offset as u64)
+    }
+
     pub fn is_valid_range(&self, guest_address: u64, size: usize) -> bool {
         self.find_region(guest_address, size).is_ok()
     }
@@ -89,6 +149,8 @@ impl GuestRam {
 pub struct MemoryRegion {
     guest_range: AddressRange,
     mapping: Mapping,
+    // A copy of the descriptor of the file which backs the region and the offset into it
+    backing: Option<(FileDesc, usize)>,
 }
 
 impl MemoryRegion {
@@ -96,13 +158,43 @@ impl MemoryRegion {
         Ok(MemoryRegion{
             guest_range: AddressRange::new(guest_base, size),
             mapping: Mapping::new(size)?,
+            backing: None,
         })
     }
 
+    /// Create a memory region backed by `size` bytes at `offset` into the file `fd`.
+    pub fn new_from_file(guest_base: u64, size: usize, fd: RawFd, offset: usize) -> Result<MemoryRegion> {
+        let mapping = Mapping::new_from_fd_offset(fd, offset, size)?;
+        let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if copy == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(MemoryRegion{
+            guest_range: AddressRange::new(guest_base, size),
+            mapping,
+            backing: Some((FileDesc::new(copy), offset)),
+        })
+    }
+
+    /// The file which backs the region and the offset of the region into
+    /// it, so that another process can map the same memory.
+    pub fn backing_file(&self) -> Option<(RawFd, usize)> {
+        self.backing.as_ref().map(|(fd, offset)| (fd.as_raw_fd(), *offset))
+    }
+
     pub fn base_address(&self) -> u64 {
         self.mapping.address()
     }
 
+    /// Ask the host kernel to back the region with transparent huge pages.
+    pub fn advise_hugepages(&self) -> Result<()> {
+        self.mapping.set_hugepage()
+    }
+
+    pub fn guest_range(&self) -> AddressRange {
+        self.guest_range
+    }
+
     fn contains(&self, guest_addr: u64, size: usize) -> bool { self.guest_range.contains(guest_addr, size) }
 
     fn checked_offset(&self, guest_addr: u64, size: usize) -> Result<usize> {
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+use crate::memory::{AddressRange, Mapping, MemoryManager, Error, Result};
+use crate::util::JsonValue;
+
+const PAGE_SIZE: usize = 4096;
+
+///
+/// A window of guest physical address space owned by a device, into which
+/// the device maps host memory while the guest is running.
+///
+/// This is the virtio shared memory region used by devices such as virtio-fs
+/// with DAX, virtio-pmem or virtio-gpu blobs, and into which virtio-wl maps
+/// its buffers. The window is reserved from
+/// device memory when the region is created and is naturally aligned so that
+/// it can be exposed to the guest as a 64 bit PCI BAR with
+/// `VirtioDeviceConfig::add_shared_memory()`.
+///
+/// Each `map()` places part of a host file at an offset into the window and
+/// occupies a KVM memory slot until it is unmapped. Clones refer to the same
+/// region, and after `remove()` every mapping is gone and the window is
+/// returned to the allocator.
+///
+#[derive(Clone)]
+pub struct SharedMemoryRegion {
+    mm: MemoryManager,
+    state: Arc<Mutex<RegionState>>,
+}
+
+pub(super) struct RegionState {
+    id: u8,
+    name: String,
+    window: AddressRange,
+    size: usize,
+    mappings: BTreeMap<usize, SharedMapping>,
+    // Mappings are zeroed with guest RAM when the VM exits
+    scrub: bool,
+    removed: bool,
+}
+
+struct SharedMapping {
+    slot: u32,
+    mapping: Mapping,
+}
+
+impl SharedMemoryRegion {
+    pub(super) fn new(mm: MemoryManager, id: u8, name: &str, window: AddressRange, size: usize) -> Self {
+        let state = RegionState {
+            id,
+            name: name.to_string(),
+            window,
+            size,
+            mappings: BTreeMap::new(),
+            scrub: false,
+            removed: false,
+        };
+        SharedMemoryRegion { mm, state: Arc::new(Mutex::new(state)) }
+    }
+
+    pub(super) fn state(&self) -> Arc<Mutex<RegionState>> {
+        self.state.clone()
+    }
+
+    /// Shared memory id of the region on its device
+    pub fn id(&self) -> u8 {
+        self.state.lock().unwrap().id
+    }
+
+    /// Guest physical address range reserved for the region. It is at least
+    /// as large as `size()` so that the region can grow up to its end.
+    pub fn window(&self) -> AddressRange {
+        self.state.lock().unwrap().window
+    }
+
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+
+    /// Map `size` bytes of `fd` starting at `fd_offset` at `offset` into the
+    /// region and return the guest physical address of the mapping.
+    pub fn map(&self, offset: usize, fd: RawFd, fd_offset: usize, size: usize) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        state.check_map(offset, size)?;
+        let mapping = Mapping::new_from_fd_offset(fd, fd_offset, size)
+            .map_err(Error::MappingFailed)?;
+        let addr = state.window.base() + offset as u64;
+        let slot = self.mm.allocate_slot();
+        if let Err(e) = self.mm.kvm().add_memory_region(slot, addr, mapping.address(), size) {
+            self.mm.free_slot(slot);
+            return Err(Error::RegisterMemoryFailed(e));
+        }
+        state.mappings.insert(offset, SharedMapping { slot, mapping });
+        Ok(addr)
+    }
+
+    /// Map `size` bytes of `fd` at the lowest offset into the region where
+    /// they fit, and return the offset and the guest physical address of the
+    /// mapping. `size` is rounded up to the page size.
+    pub fn map_free(&self, fd: RawFd, size: usize) -> Result<(usize, u64)> {
+        let size = round_to_page_size(size);
+        let offset = self.state.lock().unwrap().free_offset(size)
+            .ok_or(Error::DeviceMemoryAllocFailed)?;
+        let addr = self.map(offset, fd, 0, size)?;
+        Ok((offset, addr))
+    }
+
+    /// Zero the mappings of the region along with guest RAM when the VM
+    /// exits. Only for regions which hold memory of the guest, since other
+    /// regions map files of the host.
+    pub fn set_scrub(&self, scrub: bool) {
+        self.state.lock().unwrap().scrub = scrub;
+    }
+
+    /// Remove the mapping which was placed at `offset` with `map()`.
+    pub fn unmap(&self, offset: usize) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mapping = state.mappings.remove(&offset)
+            .ok_or(Error::SharedMappingNotFound(offset))?;
+        self.release(mapping)
+    }
+
+    fn release(&self, mapping: SharedMapping) -> Result<()> {
+        self.mm.kvm().remove_memory_region(mapping.slot)
+            .map_err(Error::UnregisterMemoryFailed)?;
+        self.mm.free_slot(mapping.slot);
+        Ok(())
+    }
+
+    /// Change the size of the region. It cannot grow beyond the window
+    /// reserved when it was created or shrink below the end of a mapping.
+    /// The size which the guest sees in the PCI capability is the size when
+    /// the device was registered.
+    pub fn resize(&self, size: usize) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.removed {
+            return Err(Error::SharedRegionRemoved);
+        }
+        let size = round_to_page_size(size);
+        let mapped_end = state.mappings.iter()
+            .map(|(offset, m)| offset + m.mapping.size())
+            .max()
+            .unwrap_or(0);
+        if size == 0 || size > state.window.size() || size < mapped_end {
+            return Err(Error::SharedRegionResize(size));
+        }
+        state.size = size;
+        Ok(())
+    }
+
+    /// Unmap every mapping and return the window to the device memory allocator.
+    pub fn remove(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.removed {
+            return Ok(());
+        }
+        state.removed = true;
+        let mappings = mem::replace(&mut state.mappings, BTreeMap::new());
+        for (_, mapping) in mappings {
+            self.release(mapping)?;
+        }
+        self.mm.free_shared_window(state.window.base());
+        Ok(())
+    }
+}
+
+impl RegionState {
+    fn check_map(&self, offset: usize, size: usize) -> Result<()> {
+        if self.removed {
+            return Err(Error::SharedRegionRemoved);
+        }
+        let aligned = offset % PAGE_SIZE == 0 && size % PAGE_SIZE == 0;
+        let end = offset.checked_add(size).unwrap_or(usize::max_value());
+        if !aligned || size == 0 || end > self.size {
+            return Err(Error::InvalidSharedMapping(offset, size));
+        }
+        // A mapping may not overlap the mapping before it or the one after it
+        let before = self.mappings.range(..=offset).next_back()
+            .map(|(&start, m)| start + m.mapping.size() > offset)
+            .unwrap_or(false);
+        let after = self.mappings.range(offset..).next()
+            .map(|(&start, _)| start < end)
+            .unwrap_or(false);
+        if before || after {
+            return Err(Error::InvalidSharedMapping(offset, size));
+        }
+        Ok(())
+    }
+
+    // The lowest offset of a gap between mappings which holds `size` bytes
+    fn free_offset(&self, size: usize) -> Option<usize> {
+        let mut offset = 0;
+        for (&start, m) in self.mappings.iter() {
+            if start - offset >= size {
+                break;
+            }
+            offset = start + m.mapping.size();
+        }
+        if size <= self.size && offset <= self.size - size {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    pub(super) fn scrub(&self) {
+        if self.scrub {
+            for m in self.mappings.values() {
+                m.mapping.zero();
+            }
+        }
+    }
+
+    fn mapped_bytes(&self) -> usize {
+        self.mappings.values().map(|m| m.mapping.size()).sum()
+    }
+
+    pub(super) fn is_removed(&self) -> bool {
+        self.removed
+    }
+
+    pub(super) fn describe(&self) -> JsonValue {
+        JsonValue::object()
+            .with("id", u64::from(self.id))
+            .with("device", self.name.as_str())
+            .with("address", format!("0x{:x}", self.window.base()))
+            .with("window-bytes", self.window.size() as u64)
+            .with("size-bytes", self.size as u64)
+            .with("mappings", self.mappings.len() as u64)
+            .with("mapped-bytes", self.mapped_bytes() as u64)
+    }
+}
+
+pub(super) fn round_to_page_size(n: usize) -> usize {
+    (n + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
@@ -0,0 +1,59 @@
+use std::env;
+use std::os::unix::io::RawFd;
+
+// First file descriptor passed by the service manager (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: RawFd = 3;
+
+///
+/// Sockets passed to this process by systemd socket activation.
+///
+/// The `LISTEN_PID`, `LISTEN_FDS`, and `LISTEN_FDNAMES` environment variables
+/// are read and removed from the environment as described in `sd_listen_fds(3)`.
+/// Each socket is identified by the name assigned with `FileDescriptorName=` in
+/// the socket unit, or `unknown` if no name was assigned.
+///
+pub struct ListenFds {
+    fds: Vec<(String, RawFd)>,
+}
+
+impl ListenFds {
+    pub fn from_env() -> ListenFds {
+        let fds = Self::parse_env().unwrap_or_default();
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        // Not inherited by child processes such as the ldd helper used by the boot filesystem
+        for (_, fd) in &fds {
+            unsafe { libc::ioctl(*fd, libc::FIOCLEX); }
+        }
+        ListenFds { fds }
+    }
+
+    fn parse_env() -> Option<Vec<(String, RawFd)>> {
+        let pid = env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+        if pid != std::process::id() {
+            return None;
+        }
+        let count = env::var("LISTEN_FDS").ok()?.parse::<RawFd>().ok()?;
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+        let mut names = names.split(':');
+        let fds = (0..count).map(|i| {
+            let name = names.next()
+                .filter(|n| !n.is_empty())
+                .unwrap_or("unknown");
+            (name.to_string(), LISTEN_FDS_START + i)
+        }).collect();
+        Some(fds)
+    }
+
+    /// Remove and return the socket with `name`. If only a single unnamed socket
+    /// was passed and `name` is `control` that socket is returned.
+    pub fn take(&mut self, name: &str) -> Option<RawFd> {
+        let idx = match self.fds.iter().position(|(n,_)| n == name) {
+            Some(idx) => idx,
+            None if name == "control" && self.fds.len() == 1 && self.fds[0].0 == "unknown" => 0,
+            None => return None,
+        };
+        Some(self.fds.remove(idx).1)
+    }
+}
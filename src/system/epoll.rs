@@ -3,7 +3,7 @@ use std::ptr;
 use crate::system::{Result,Error};
 use std::time::Duration;
 
-use libc::{epoll_event, c_int, EPOLLIN, EPOLLHUP, EPOLL_CTL_DEL, EPOLL_CTL_ADD, EPOLL_CLOEXEC, EINTR, EINVAL};
+use libc::{epoll_event, c_int, EPOLLIN, EPOLLOUT, EPOLLHUP, EPOLL_CTL_DEL, EPOLL_CTL_ADD, EPOLL_CTL_MOD, EPOLL_CLOEXEC, EINTR, EINVAL};
 
 const MAX_EVENTS: usize = 32;
 
@@ -17,6 +17,10 @@ impl Event {
         self.is_event(EPOLLIN)
     }
 
+    pub fn is_writable(&self) -> bool {
+        self.is_event(EPOLLOUT)
+    }
+
     pub fn is_hangup(&self) -> bool {
         self.is_event(EPOLLHUP)
     }
@@ -45,11 +49,25 @@ impl EPoll {
     }
 
     pub fn add_read(&self, fd: RawFd, id: u64) -> Result<()> {
+        self.add(fd, EPOLLIN as u32, id)
+    }
+
+    /// Add `fd` waiting for a set of events such as `EPOLLIN | EPOLLOUT`.
+    pub fn add(&self, fd: RawFd, events: u32, id: u64) -> Result<()> {
+        self.ctl(EPOLL_CTL_ADD, fd, events, id)
+    }
+
+    /// Change the events which an added `fd` waits for.
+    pub fn modify(&self, fd: RawFd, events: u32, id: u64) -> Result<()> {
+        self.ctl(EPOLL_CTL_MOD, fd, events, id)
+    }
+
+    fn ctl(&self, op: c_int, fd: RawFd, events: u32, id: u64) -> Result<()> {
         let mut evt = epoll_event {
-            events: EPOLLIN as u32,
+            events,
             u64: id
         };
-        match unsafe { libc::epoll_ctl(self.fd, EPOLL_CTL_ADD, fd, &mut evt) } {
+        match unsafe { libc::epoll_ctl(self.fd, op, fd, &mut evt) } {
             -1 => Err(Error::last_os_error()),
             _ => Ok(()),
         }
@@ -4,13 +4,28 @@ use libc;
 
 use crate::system::{Result,Error};
 
+///
+/// An eventfd counter. Every eventfd is created close-on-exec so that it is
+/// not inherited by processes which pH runs. Eventfds which are bound to KVM
+/// are wrapped by `kvm::IoEventFd` and by the interrupt lines of virtqueues.
+///
 pub struct EventFd(RawFd);
 
 const U64_SZ: usize = 8;
 
 impl EventFd {
     pub fn new() -> Result<EventFd> {
-        let fd = unsafe { libc::eventfd(0, 0) };
+        Self::with_flags(libc::EFD_CLOEXEC)
+    }
+
+    /// Create an eventfd which does not block in `read()` when the counter
+    /// is zero. Use `try_read()` to read it.
+    pub fn new_nonblocking() -> Result<EventFd> {
+        Self::with_flags(libc::EFD_CLOEXEC | libc::EFD_NONBLOCK)
+    }
+
+    fn with_flags(flags: libc::c_int) -> Result<EventFd> {
+        let fd = unsafe { libc::eventfd(0, flags) };
         if fd < 0 {
             return Err(Error::last_os_error());
         }
@@ -39,6 +54,16 @@ impl EventFd {
         }
         Ok(v)
     }
+
+    /// Read and clear the counter of a non-blocking eventfd, returning
+    /// `None` if it was zero.
+    pub fn try_read(&self) -> Result<Option<u64>> {
+        match self.read() {
+            Ok(v) => Ok(Some(v)),
+            Err(Error::Errno(ref e)) if e.errno() == libc::EAGAIN => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 }
 
 impl Drop for EventFd {
@@ -0,0 +1,319 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::{mem, ptr};
+
+use libc;
+
+use crate::system::{Result, Error};
+
+// x86_64 system call numbers, which libc does not define yet
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+const SYS_IO_URING_REGISTER: libc::c_long = 427;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x0800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_BUFFERS: u32 = 0;
+
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+const IORING_OP_READ_FIXED: u8 = 4;
+const IORING_OP_WRITE_FIXED: u8 = 5;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct IoUringParams {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+// A region mapped from the ring file descriptor
+struct RingMapping {
+    ptr: *mut u8,
+    size: usize,
+}
+
+impl RingMapping {
+    fn new(fd: RawFd, size: usize, offset: libc::off_t) -> Result<RingMapping> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        Ok(RingMapping { ptr: ptr as *mut u8, size })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+
+    fn atomic(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*self.at::<AtomicU32>(offset) }
+    }
+}
+
+impl Drop for RingMapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.size); }
+    }
+}
+
+///
+/// A read or write of one buffer at an offset in a file, submitted to an
+/// `IoUring`.
+///
+#[derive(Clone,Copy)]
+pub struct IoRequest {
+    write: bool,
+    fd: RawFd,
+    offset: u64,
+    addr: u64,
+    len: u32,
+    buf_index: Option<u16>,
+}
+
+impl IoRequest {
+    pub fn read(fd: RawFd, offset: u64, buffer: &mut [u8]) -> Self {
+        IoRequest { write: false, fd, offset, addr: buffer.as_mut_ptr() as u64, len: buffer.len() as u32, buf_index: None }
+    }
+
+    pub fn write(fd: RawFd, offset: u64, buffer: &[u8]) -> Self {
+        IoRequest { write: true, fd, offset, addr: buffer.as_ptr() as u64, len: buffer.len() as u32, buf_index: None }
+    }
+
+    /// The buffer lies inside the buffer registered at `index`.
+    pub fn fixed(mut self, index: u16) -> Self {
+        self.buf_index = Some(index);
+        self
+    }
+}
+
+///
+/// A minimal io_uring instance (Linux 5.1 or later) which submits batches of
+/// file reads and writes with a single system call and waits for all of them
+/// to complete.
+///
+pub struct IoUring {
+    fd: RawFd,
+    sq_ring: RingMapping,
+    cq_ring: RingMapping,
+    sqes: RingMapping,
+    params: IoUringParams,
+}
+
+// The rings are only accessed through &mut self
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    pub fn new(entries: u32) -> Result<IoUring> {
+        let mut params = IoUringParams::default();
+        let fd = unsafe { libc::syscall(SYS_IO_URING_SETUP, entries, &mut params as *mut IoUringParams) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+        let rings = Self::map_rings(fd, &params);
+        match rings {
+            Ok((sq_ring, cq_ring, sqes)) => Ok(IoUring { fd, sq_ring, cq_ring, sqes, params }),
+            Err(e) => {
+                unsafe { libc::close(fd); }
+                Err(e)
+            }
+        }
+    }
+
+    fn map_rings(fd: RawFd, params: &IoUringParams) -> Result<(RingMapping, RingMapping, RingMapping)> {
+        let sq_size = params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+        let cq_size = params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let sqes_size = params.sq_entries as usize * mem::size_of::<Sqe>();
+        let sq_ring = RingMapping::new(fd, sq_size, IORING_OFF_SQ_RING)?;
+        let cq_ring = RingMapping::new(fd, cq_size, IORING_OFF_CQ_RING)?;
+        let sqes = RingMapping::new(fd, sqes_size, IORING_OFF_SQES)?;
+        Ok((sq_ring, cq_ring, sqes))
+    }
+
+    /// Register buffers with the kernel so that requests for memory inside
+    /// them can be marked `IoRequest::fixed`. The pages are pinned in memory
+    /// for as long as the ring exists, and the memory must stay mapped.
+    pub fn register_buffers(&self, buffers: &[libc::iovec]) -> Result<()> {
+        let ret = unsafe {
+            libc::syscall(SYS_IO_URING_REGISTER, self.fd, IORING_REGISTER_BUFFERS,
+                          buffers.as_ptr(), buffers.len() as u32)
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Submit `requests` and wait until every one of them has completed.
+    /// Returns the number of bytes transferred or a negative errno for each
+    /// request, in the order they were given.
+    ///
+    /// The caller must ensure that the buffer of each request stays valid
+    /// until this returns.
+    pub fn submit_and_wait(&mut self, requests: &[IoRequest]) -> Result<Vec<i32>> {
+        let mut results = vec![0; requests.len()];
+        // readv and writev need their iovec until the request completes
+        let iovecs: Vec<libc::iovec> = requests.iter()
+            .map(|r| libc::iovec { iov_base: r.addr as *mut libc::c_void, iov_len: r.len as usize })
+            .collect();
+        let batch_size = self.params.sq_entries as usize;
+        for start in (0..requests.len()).step_by(batch_size) {
+            let end = (start + batch_size).min(requests.len());
+            for i in start..end {
+                self.push_sqe(&requests[i], &iovecs[i], i as u64);
+            }
+            self.enter_and_reap(end - start, &mut results)?;
+        }
+        Ok(results)
+    }
+
+    fn push_sqe(&mut self, request: &IoRequest, iovec: &libc::iovec, user_data: u64) {
+        let off = &self.params.sq_off;
+        let mask = unsafe { *self.sq_ring.at::<u32>(off.ring_mask) };
+        let tail = self.sq_ring.atomic(off.tail).load(Ordering::Acquire);
+        let index = tail & mask;
+
+        let mut sqe = Sqe::default();
+        sqe.fd = request.fd;
+        sqe.off = request.offset;
+        sqe.user_data = user_data;
+        match request.buf_index {
+            Some(buf_index) => {
+                sqe.opcode = if request.write { IORING_OP_WRITE_FIXED } else { IORING_OP_READ_FIXED };
+                sqe.addr = request.addr;
+                sqe.len = request.len;
+                sqe.buf_index = buf_index;
+            }
+            None => {
+                sqe.opcode = if request.write { IORING_OP_WRITEV } else { IORING_OP_READV };
+                sqe.addr = iovec as *const libc::iovec as u64;
+                sqe.len = 1;
+            }
+        }
+        unsafe {
+            ptr::write(self.sqes.at::<Sqe>(0).add(index as usize), sqe);
+            ptr::write(self.sq_ring.at::<u32>(off.array).add(index as usize), index);
+        }
+        self.sq_ring.atomic(off.tail).store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    fn enter_and_reap(&mut self, count: usize, results: &mut [i32]) -> Result<()> {
+        let mut to_submit = count as u32;
+        let mut remaining = count;
+        while remaining > 0 {
+            let ret = unsafe {
+                libc::syscall(SYS_IO_URING_ENTER, self.fd, to_submit, 1u32, IORING_ENTER_GETEVENTS,
+                              ptr::null::<libc::sigset_t>(), 0usize)
+            };
+            if ret < 0 {
+                let err = Error::last_os_error();
+                if err.is_interrupted() {
+                    continue;
+                }
+                return Err(err);
+            }
+            to_submit -= ret as u32;
+            remaining -= self.reap(results);
+        }
+        Ok(())
+    }
+
+    fn reap(&mut self, results: &mut [i32]) -> usize {
+        let off = &self.params.cq_off;
+        let mask = unsafe { *self.cq_ring.at::<u32>(off.ring_mask) };
+        let head_atomic = self.cq_ring.atomic(off.head);
+        let mut head = head_atomic.load(Ordering::Acquire);
+        let tail = self.cq_ring.atomic(off.tail).load(Ordering::Acquire);
+        let mut count = 0;
+        while head != tail {
+            let cqe = unsafe { &*self.cq_ring.at::<Cqe>(off.cqes).add((head & mask) as usize) };
+            if let Some(result) = results.get_mut(cqe.user_data as usize) {
+                *result = cqe.res;
+            }
+            head = head.wrapping_add(1);
+            count += 1;
+        }
+        head_atomic.store(head, Ordering::Release);
+        count
+    }
+}
+
+impl Drop for IoUring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+impl AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
@@ -1,14 +1,19 @@
 use std::ffi::CString;
 use std::io::SeekFrom;
 use std::os::unix::io::{RawFd,AsRawFd};
+use std::path::PathBuf;
+use std::process;
 
 use crate::system::{Error,Result, FileDesc};
 
 use libc::{
     self, c_char, c_uint, c_int, c_long,SYS_memfd_create,
-    MFD_CLOEXEC, MFD_ALLOW_SEALING, F_SEAL_GROW,F_SEAL_SHRINK, F_SEAL_SEAL
+    MFD_CLOEXEC, MFD_ALLOW_SEALING, MFD_HUGETLB, F_SEAL_GROW,F_SEAL_SHRINK, F_SEAL_SEAL, F_SEAL_WRITE
 };
 
+// Not defined by the libc crate version in use
+const MFD_HUGE_SHIFT: c_uint = 26;
+
 
 #[derive(Debug)]
 pub struct MemoryFd {
@@ -39,6 +44,33 @@ impl MemoryFd {
         Ok(memfd)
     }
 
+    /// Create a memfd of `size` bytes backed by huge pages of `page_size`
+    /// bytes from the hugetlb pool of the host. `size` must be a multiple of
+    /// `page_size`.
+    pub fn new_hugetlb(size: usize, page_size: usize, name: &str) -> Result<MemoryFd> {
+        let page_shift = page_size.trailing_zeros() as c_uint;
+        let fd = Self::memfd_create(name, MFD_CLOEXEC | MFD_HUGETLB | (page_shift << MFD_HUGE_SHIFT))?;
+        fd.set_size(size)?;
+        Ok(MemoryFd { fd, size })
+    }
+
+    /// Create a memfd holding a copy of `bytes`, sealed so that neither its
+    /// size nor its contents can change.
+    pub fn new_sealed_with_contents(name: &str, bytes: &[u8]) -> Result<MemoryFd> {
+        let fd = Self::memfd_create(name, MFD_CLOEXEC | MFD_ALLOW_SEALING)?;
+        fd.write_all(bytes)?;
+
+        let memfd = MemoryFd { fd, size: bytes.len() };
+        memfd.add_seals(F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE | F_SEAL_SEAL)?;
+        Ok(memfd)
+    }
+
+    /// A path through which this process and its children can open the
+    /// memfd for as long as it is held open.
+    pub fn proc_path(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/{}/fd/{}", process::id(), self.fd.as_raw_fd()))
+    }
+
     pub fn size(&self) -> usize {
         self.size
     }
@@ -1,20 +1,26 @@
 #[macro_use]pub mod ioctl;
+mod activation;
 mod epoll;
 mod errno;
 mod eventfd;
 mod socket;
 mod filedesc;
+mod io_uring;
 mod memfd;
 mod tap;
 pub mod netlink;
+pub mod seccomp;
 
 pub use filedesc::{FileDesc, FileFlags};
+pub use activation::ListenFds;
 pub use eventfd::EventFd;
+pub use io_uring::{IoUring, IoRequest};
 pub use memfd::MemoryFd;
 pub use epoll::{EPoll,Event};
 pub use socket::ScmSocket;
 pub use netlink::NetlinkSocket;
 pub use tap::Tap;
+pub use seccomp::{SeccompMode, SeccompProfile};
 use std::{fmt, result, io};
 
 pub use errno::Error as ErrnoError;
@@ -13,6 +13,7 @@ use libc::{
 const NETLINK_ROUTE: i32 = 0;
 
 const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
 const IFLA_MASTER: u16 = 10;
 const IFLA_LINKINFO: u16 = 18;
 const IFLA_INFO_KIND: u16 = 1;
@@ -151,6 +152,18 @@ impl NetlinkSocket {
         self.send_message(msg)
     }
 
+    pub fn set_interface_mtu(&self, iface: &str, mtu: u32) -> Result<()> {
+        let idx = self.name_to_index(iface)?;
+        let msg = self.message(RTM_NEWLINK)
+            .with_ifinfomsg(AF_UNSPEC, |hdr| {
+                hdr.index(idx);
+            })
+            .attr_u32(IFLA_MTU, mtu)
+            .done();
+
+        self.send_message(msg)
+    }
+
     #[allow(dead_code)]
     pub fn add_ip_address(&self, iface: &str, ip: Ipv4Addr, netmask_bits: u32) -> Result<()> {
         let idx = self.name_to_index(iface)?;
@@ -0,0 +1,340 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc::c_long;
+use crate::system::{Error, Result};
+
+// Newer than the syscall numbers in the libc crate, and the same on every
+// architecture
+const SYS_IO_URING_ENTER: c_long = 426;
+const SYS_CLONE3: c_long = 435;
+
+const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+// Offsets into struct seccomp_data
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARGS: u32 = 16;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_ALU_AND_K: u16 = 0x54;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+// The type byte of the ioctl requests which each profile may make
+const KVMIO: u8 = 0xAE;
+const DRM_IOCTL_BASE: u8 = b'd';
+const DMA_BUF_IOCTL_BASE: u8 = b'b';
+
+///
+/// What happens when a device thread makes a system call which is not on
+/// the allowlist of its profile.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum SeccompMode {
+    /// Device threads are not sandboxed.
+    Disabled,
+    /// The whole process is killed.
+    Enforce,
+    /// The call is allowed and logged by the kernel, to find calls which
+    /// are missing from a profile.
+    Log,
+}
+
+impl SeccompMode {
+    /// Parse `off`, `enforce` or `log`.
+    pub fn parse(s: &str) -> Option<SeccompMode> {
+        match s {
+            "off" => Some(SeccompMode::Disabled),
+            "enforce" => Some(SeccompMode::Enforce),
+            "log" => Some(SeccompMode::Log),
+            _ => None,
+        }
+    }
+
+    fn from_index(idx: usize) -> SeccompMode {
+        match idx {
+            1 => SeccompMode::Enforce,
+            2 => SeccompMode::Log,
+            _ => SeccompMode::Disabled,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            SeccompMode::Disabled => 0,
+            SeccompMode::Enforce => 1,
+            SeccompMode::Log => 2,
+        }
+    }
+}
+
+impl Default for SeccompMode {
+    fn default() -> Self {
+        SeccompMode::Enforce
+    }
+}
+
+static MODE: AtomicUsize = AtomicUsize::new(0);
+
+/// Set how device threads started from now on are sandboxed.
+pub fn set_mode(mode: SeccompMode) {
+    MODE.store(mode.index(), Ordering::SeqCst);
+}
+
+///
+/// The system calls which the threads of a device may make once they have
+/// been set up.
+///
+/// Every profile allows what any thread needs (memory allocation, futexes,
+/// reading and writing file descriptors it already holds, polling) and the
+/// KVM ioctls used to raise interrupts. Threads started by a sandboxed thread
+/// inherit its filter.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum SeccompProfile {
+    /// Disk image IO, syncs, and spilling a memory overlay to a file.
+    Block,
+    /// Operations on the files of an exported directory, through io_uring
+    /// and the threads which give up on operations after a timeout.
+    P9,
+    /// Connections to the compositor, shared memory and DRM buffers.
+    Wayland,
+    /// Frames moved between the virtqueues and a TAP device.
+    Net,
+}
+
+impl SeccompProfile {
+    fn rules(self) -> Vec<Rule> {
+        let mut rules = Rule::allow_all(BASE_SYSCALLS);
+        rules.extend(Rule::allow_all(arch::BASE_SYSCALLS));
+        rules.push(Rule::ioctl_type(KVMIO));
+        match self {
+            SeccompProfile::Block => {
+                rules.extend(Rule::allow_all(BLOCK_SYSCALLS));
+                // IO sharing only checks whether other instances are alive
+                rules.push(Rule::with_arg(libc::SYS_kill, 1, 0));
+            }
+            SeccompProfile::P9 => {
+                rules.extend(Rule::allow_all(P9_SYSCALLS));
+                rules.extend(Rule::allow_all(arch::P9_SYSCALLS));
+            }
+            SeccompProfile::Wayland => {
+                rules.extend(Rule::allow_all(WAYLAND_SYSCALLS));
+                rules.push(Rule::with_arg(libc::SYS_socket, 0, libc::AF_UNIX as u32));
+                rules.push(Rule::ioctl_type(DRM_IOCTL_BASE));
+                rules.push(Rule::ioctl_type(DMA_BUF_IOCTL_BASE));
+            }
+            SeccompProfile::Net => {},
+        }
+        rules
+    }
+}
+
+// The system calls whose numbers differ between architectures, and those
+// which arm64 only has in their *at, ppoll and epoll_pwait forms
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use libc::c_long;
+
+    pub const AUDIT_ARCH: u32 = 0xc000_003e;
+
+    // Newer than the syscall numbers in the libc crate
+    pub const SYS_RSEQ: c_long = 334;
+
+    pub const SYS_STATFS: c_long = libc::SYS_statfs;
+    pub const SYS_FSTATFS: c_long = libc::SYS_fstatfs;
+    pub const SYS_TRUNCATE: c_long = libc::SYS_truncate;
+    pub const SYS_FTRUNCATE: c_long = libc::SYS_ftruncate;
+    pub const SYS_FADVISE64: c_long = libc::SYS_fadvise64;
+    pub const SYS_STATX: c_long = libc::SYS_statx;
+
+    pub const BASE_SYSCALLS: &[c_long] = &[
+        libc::SYS_poll, libc::SYS_epoll_wait,
+    ];
+
+    pub const P9_SYSCALLS: &[c_long] = &[
+        libc::SYS_open, libc::SYS_stat, libc::SYS_lstat, libc::SYS_access,
+        libc::SYS_mkdir, libc::SYS_rmdir, libc::SYS_unlink, libc::SYS_rename,
+        libc::SYS_link, libc::SYS_symlink, libc::SYS_readlink, libc::SYS_mknod,
+        libc::SYS_chmod, libc::SYS_chown, libc::SYS_lchown,
+    ];
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use libc::c_long;
+
+    pub const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    // Not defined for aarch64 by the libc crate version in use
+    pub const SYS_RSEQ: c_long = 293;
+    pub const SYS_STATFS: c_long = 43;
+    pub const SYS_FSTATFS: c_long = 44;
+    pub const SYS_TRUNCATE: c_long = 45;
+    pub const SYS_FTRUNCATE: c_long = 46;
+    pub const SYS_FADVISE64: c_long = 223;
+    pub const SYS_STATX: c_long = 291;
+
+    pub const BASE_SYSCALLS: &[c_long] = &[];
+
+    pub const P9_SYSCALLS: &[c_long] = &[];
+}
+
+use self::arch::{SYS_RSEQ, SYS_STATFS, SYS_FSTATFS, SYS_TRUNCATE, SYS_FTRUNCATE, SYS_FADVISE64, SYS_STATX};
+
+const BASE_SYSCALLS: &[c_long] = &[
+    libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev,
+    libc::SYS_pread64, libc::SYS_pwrite64, libc::SYS_preadv, libc::SYS_pwritev,
+    libc::SYS_lseek, libc::SYS_close, libc::SYS_fstat, libc::SYS_fcntl,
+    libc::SYS_eventfd2, libc::SYS_ppoll, libc::SYS_epoll_pwait, libc::SYS_epoll_ctl,
+    libc::SYS_futex, libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect,
+    libc::SYS_mremap, libc::SYS_madvise, libc::SYS_brk,
+    libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+    libc::SYS_sched_yield, libc::SYS_nanosleep, libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime, libc::SYS_gettimeofday, libc::SYS_getrandom,
+    libc::SYS_getpid, libc::SYS_gettid, libc::SYS_restart_syscall,
+    libc::SYS_exit, libc::SYS_exit_group,
+];
+
+const BLOCK_SYSCALLS: &[c_long] = &[
+    libc::SYS_fsync, libc::SYS_fdatasync, libc::SYS_fallocate, SYS_FTRUNCATE,
+    libc::SYS_msync, libc::SYS_flock, libc::SYS_openat, SYS_STATX,
+];
+
+const P9_SYSCALLS: &[c_long] = &[
+    libc::SYS_openat, libc::SYS_newfstatat, SYS_STATX, SYS_STATFS, SYS_FSTATFS,
+    libc::SYS_getdents64, libc::SYS_faccessat,
+    libc::SYS_mkdirat, libc::SYS_unlinkat,
+    libc::SYS_renameat, libc::SYS_renameat2, libc::SYS_linkat,
+    libc::SYS_symlinkat, libc::SYS_readlinkat, libc::SYS_mknodat,
+    libc::SYS_fchmod, libc::SYS_fchmodat, libc::SYS_fchown, libc::SYS_fchownat,
+    libc::SYS_utimensat, SYS_TRUNCATE, SYS_FTRUNCATE, libc::SYS_fallocate,
+    libc::SYS_fsync, libc::SYS_fdatasync, libc::SYS_flock, SYS_FADVISE64,
+    libc::SYS_getxattr, libc::SYS_lgetxattr, libc::SYS_fgetxattr,
+    libc::SYS_setxattr, libc::SYS_lsetxattr, libc::SYS_fsetxattr,
+    libc::SYS_listxattr, libc::SYS_llistxattr, libc::SYS_flistxattr,
+    libc::SYS_removexattr, libc::SYS_lremovexattr, libc::SYS_fremovexattr,
+    libc::SYS_geteuid, SYS_IO_URING_ENTER,
+    // Starting the timeout worker threads
+    libc::SYS_clone, SYS_CLONE3, libc::SYS_set_robust_list, SYS_RSEQ,
+];
+
+const WAYLAND_SYSCALLS: &[c_long] = &[
+    libc::SYS_connect, libc::SYS_sendmsg, libc::SYS_recvmsg, libc::SYS_shutdown,
+    libc::SYS_memfd_create, SYS_FTRUNCATE, libc::SYS_pipe2,
+    libc::SYS_dup, libc::SYS_dup3, libc::SYS_openat, SYS_STATX,
+];
+
+// Allow a system call, if `arg` is given only when the low 32 bits of the
+// argument at that index masked with `mask` equal `value`.
+struct Rule {
+    nr: c_long,
+    arg: Option<(u32, u32, u32)>,
+}
+
+impl Rule {
+    fn allow_all(syscalls: &[c_long]) -> Vec<Rule> {
+        syscalls.iter().map(|&nr| Rule { nr, arg: None }).collect()
+    }
+
+    fn with_arg(nr: c_long, index: u32, value: u32) -> Rule {
+        Rule { nr, arg: Some((index, !0, value)) }
+    }
+
+    fn ioctl_type(ioctl_type: u8) -> Rule {
+        Rule { nr: libc::SYS_ioctl, arg: Some((1, 0xff00, u32::from(ioctl_type) << 8)) }
+    }
+
+    fn compile(&self, prog: &mut Vec<SockFilter>) {
+        match self.arg {
+            None => {
+                prog.push(SockFilter::jeq(self.nr as u32, 0, 1));
+                prog.push(SockFilter::ret(SECCOMP_RET_ALLOW));
+            }
+            Some((index, mask, value)) => {
+                // The number is loaded again if the argument does not match
+                prog.push(SockFilter::jeq(self.nr as u32, 0, 5));
+                prog.push(SockFilter::load(DATA_ARGS + index * 8));
+                prog.push(SockFilter::and(mask));
+                prog.push(SockFilter::jeq(value, 0, 1));
+                prog.push(SockFilter::ret(SECCOMP_RET_ALLOW));
+                prog.push(SockFilter::load(DATA_NR));
+            }
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy,Clone)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+impl SockFilter {
+    fn load(offset: u32) -> Self {
+        SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: offset }
+    }
+
+    fn and(mask: u32) -> Self {
+        SockFilter { code: BPF_ALU_AND_K, jt: 0, jf: 0, k: mask }
+    }
+
+    fn jeq(value: u32, jt: u8, jf: u8) -> Self {
+        SockFilter { code: BPF_JEQ_K, jt, jf, k: value }
+    }
+
+    fn ret(action: u32) -> Self {
+        SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: action }
+    }
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+fn build_filter(profile: SeccompProfile, violation: u32) -> Vec<SockFilter> {
+    let mut prog = vec![
+        SockFilter::load(DATA_ARCH),
+        SockFilter::jeq(arch::AUDIT_ARCH, 1, 0),
+        SockFilter::ret(SECCOMP_RET_KILL_PROCESS),
+        SockFilter::load(DATA_NR),
+    ];
+    for rule in profile.rules() {
+        rule.compile(&mut prog);
+    }
+    prog.push(SockFilter::ret(violation));
+    prog
+}
+
+fn install_filter(prog: &[SockFilter]) -> Result<()> {
+    let fprog = SockFprog { len: prog.len() as u16, filter: prog.as_ptr() };
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+            return Err(Error::last_os_error());
+        }
+        if libc::prctl(libc::PR_SET_SECCOMP, SECCOMP_MODE_FILTER, &fprog as *const SockFprog) < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Restrict the calling thread to the system calls of `profile`, unless
+/// sandboxing is disabled. Call once a device thread has been set up and
+/// before it processes anything from the guest.
+pub fn sandbox_thread(profile: SeccompProfile) -> Result<()> {
+    let violation = match SeccompMode::from_index(MODE.load(Ordering::SeqCst)) {
+        SeccompMode::Disabled => return Ok(()),
+        SeccompMode::Enforce => SECCOMP_RET_KILL_PROCESS,
+        SeccompMode::Log => SECCOMP_RET_LOG,
+    };
+    install_filter(&build_filter(profile, violation))
+}
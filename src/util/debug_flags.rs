@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::util::JsonValue;
+
+// Bit for each enabled `DebugFlag`, checked by the devices on each request
+// so that output can be switched on and off while the VM is running
+static ENABLED: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Extra logging or checking of a device which is too expensive or too
+/// noisy to leave on, and which can be switched on with the `debug`
+/// control method when a problem shows up.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum DebugFlag {
+    /// Each 9p request handled by the servers of the 9p shares.
+    P9,
+    /// Each FUSE request handled by the servers of the virtio-fs shares.
+    VirtioFs,
+    /// Each message exchanged with the guest by the virtio_wl device.
+    Wayland,
+    /// Check each descriptor chain taken from a virtqueue and report the
+    /// problems found with it.
+    Vring,
+}
+
+impl DebugFlag {
+    pub const ALL: &'static [DebugFlag] = &[
+        DebugFlag::P9, DebugFlag::VirtioFs, DebugFlag::Wayland, DebugFlag::Vring,
+    ];
+
+    /// Parse `9p`, `virtiofs`, `wl` or `vring`.
+    pub fn parse(s: &str) -> Option<DebugFlag> {
+        Self::ALL.iter().cloned().find(|flag| flag.name() == s)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugFlag::P9 => "9p",
+            DebugFlag::VirtioFs => "virtiofs",
+            DebugFlag::Wayland => "wl",
+            DebugFlag::Vring => "vring",
+        }
+    }
+
+    fn bit(self) -> usize {
+        1 << (self as usize)
+    }
+}
+
+pub fn is_enabled(flag: DebugFlag) -> bool {
+    ENABLED.load(Ordering::Relaxed) & flag.bit() != 0
+}
+
+pub fn set_enabled(flag: DebugFlag, enabled: bool) {
+    if enabled {
+        ENABLED.fetch_or(flag.bit(), Ordering::Relaxed);
+    } else {
+        ENABLED.fetch_and(!flag.bit(), Ordering::Relaxed);
+    }
+}
+
+/// The state of each flag, for the `debug` control method.
+pub fn describe() -> JsonValue {
+    DebugFlag::ALL.iter()
+        .fold(JsonValue::object(), |obj, &flag| obj.with(flag.name(), is_enabled(flag)))
+}
@@ -0,0 +1,84 @@
+use std::io::{self, Read};
+
+const BLOCK_SIZE: usize = 64;
+
+// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+///
+/// A deterministic random bit generator which produces the ChaCha20
+/// keystream for a key derived from a 64 bit seed.
+///
+/// Used in place of host entropy when a VM is configured to be
+/// reproducible. Separate `stream` numbers give independent outputs from
+/// the same seed so that each consumer can have its own generator.
+///
+pub struct Drbg {
+    state: [u32; 16],
+    block: [u8; BLOCK_SIZE],
+    offset: usize,
+}
+
+impl Drbg {
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut state = [0u32; 16];
+        state[..4].copy_from_slice(&CHACHA_CONSTANTS);
+        // The seed fills the first two key words and the rest of the key is zero
+        state[4] = seed as u32;
+        state[5] = (seed >> 32) as u32;
+        state[14] = stream as u32;
+        state[15] = (stream >> 32) as u32;
+        Drbg { state, block: [0; BLOCK_SIZE], offset: BLOCK_SIZE }
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            if self.offset == BLOCK_SIZE {
+                self.next_block();
+            }
+            let n = (buf.len() - done).min(BLOCK_SIZE - self.offset);
+            buf[done..done + n].copy_from_slice(&self.block[self.offset..self.offset + n]);
+            self.offset += n;
+            done += n;
+        }
+    }
+
+    fn next_block(&mut self) {
+        let mut x = self.state;
+        for _ in 0..10 {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+        for (i, word) in x.iter().enumerate() {
+            let word = word.wrapping_add(self.state[i]);
+            self.block[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        // 64 bit block counter in words 12 and 13
+        self.state[12] = self.state[12].wrapping_add(1);
+        if self.state[12] == 0 {
+            self.state[13] = self.state[13].wrapping_add(1);
+        }
+        self.offset = 0;
+    }
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+impl Read for Drbg {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill(buf);
+        Ok(buf.len())
+    }
+}
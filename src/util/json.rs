@@ -0,0 +1,467 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+// Arrays and objects nested deeper than this are refused by the parser, which
+// would otherwise recurse without bound on input from a control socket client.
+const MAX_DEPTH: usize = 64;
+
+/// A JSON value which can be serialized with `Display`.
+///
+/// Objects preserve the order in which members are added so that
+/// output is stable and easy to read.
+///
+#[derive(Clone,Debug,PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    /// Create an empty JSON object
+    pub fn object() -> JsonValue {
+        JsonValue::Object(Vec::new())
+    }
+
+    /// Add a member to an object value and return it. If this value
+    /// is not an object it is returned unchanged.
+    pub fn with<K: Into<String>, V: Into<JsonValue>>(mut self, key: K, val: V) -> JsonValue {
+        self.set(key, val);
+        self
+    }
+
+    /// Add or replace a member of an object value.
+    pub fn set<K: Into<String>, V: Into<JsonValue>>(&mut self, key: K, val: V) {
+        if let JsonValue::Object(ref mut members) = *self {
+            let key = key.into();
+            let val = val.into();
+            match members.iter_mut().find(|(k,_)| *k == key) {
+                Some(member) => member.1 = val,
+                None => members.push((key, val)),
+            }
+        }
+    }
+
+    /// Look up a member of an object value by name.
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter()
+                .find(|(k,_)| k == key)
+                .map(|(_,v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            JsonValue::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            JsonValue::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            JsonValue::Array(v) => Some(v.as_slice()),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == JsonValue::Null
+    }
+
+    /// Parse a JSON document. Numbers with a fraction or exponent are not
+    /// supported since `Number` only holds integers.
+    pub fn parse(s: &str) -> Result<JsonValue, String> {
+        let mut parser = Parser { chars: s.chars().collect(), pos: 0, depth: 0 };
+        let val = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.chars.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(val)
+    }
+
+    /// Render the value for people to read, with one `key: value` line for
+    /// each member of an object and nested values indented below their key.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        self.write_text(&mut out, 0);
+        out
+    }
+
+    fn write_text(&self, out: &mut String, indent: usize) {
+        let pad = " ".repeat(indent);
+        match self {
+            JsonValue::Object(members) => for (k, v) in members {
+                if v.is_scalar() {
+                    out.push_str(&format!("{}{}: {}\n", pad, k, v.scalar_text()));
+                } else {
+                    out.push_str(&format!("{}{}:\n", pad, k));
+                    v.write_text(out, indent + 2);
+                }
+            },
+            JsonValue::Array(values) => for v in values {
+                if v.is_scalar() {
+                    out.push_str(&format!("{}- {}\n", pad, v.scalar_text()));
+                } else {
+                    out.push_str(&format!("{}-\n", pad));
+                    v.write_text(out, indent + 2);
+                }
+            },
+            v => out.push_str(&format!("{}{}\n", pad, v.scalar_text())),
+        }
+    }
+
+    fn is_scalar(&self) -> bool {
+        match self {
+            JsonValue::Object(members) => members.is_empty(),
+            JsonValue::Array(values) => values.is_empty(),
+            _ => true,
+        }
+    }
+
+    // Strings are shown without quotes and empty containers as `{}` or `[]`
+    fn scalar_text(&self) -> String {
+        match self {
+            JsonValue::String(s) => s.clone(),
+            v => v.to_string(),
+        }
+    }
+
+    fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in s.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\r' => write!(f, "\\r")?,
+                '\t' => write!(f, "\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> String {
+        format!("{} at offset {}", msg, self.pos)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self.peek().ok_or_else(|| self.error("unexpected end of input"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        if self.next()? != expected {
+            self.pos -= 1;
+            return Err(self.error(&format!("expected '{}'", expected)));
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("nesting too deep"));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.enter()?;
+                let val = self.parse_object();
+                self.depth -= 1;
+                val
+            }
+            Some('[') => {
+                self.enter()?;
+                let val = self.parse_array();
+                self.depth -= 1;
+                val
+            }
+            Some('"') => Ok(JsonValue::String(self.parse_string()?)),
+            Some('t') => self.parse_literal("true", JsonValue::Bool(true)),
+            Some('f') => self.parse_literal("false", JsonValue::Bool(false)),
+            Some('n') => self.parse_literal("null", JsonValue::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn parse_literal(&mut self, literal: &str, val: JsonValue) -> Result<JsonValue, String> {
+        for c in literal.chars() {
+            self.expect(c)?;
+        }
+        Ok(val)
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue, String> {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        while let Some(c) = self.peek() {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            self.pos += 1;
+        }
+        if let Some('.') | Some('e') | Some('E') = self.peek() {
+            return Err(self.error("non-integer numbers are not supported"));
+        }
+        let s: String = self.chars[start..self.pos].iter().collect();
+        s.parse::<i64>()
+            .map(JsonValue::Number)
+            .map_err(|_| self.error("invalid number"))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let mut n = 0;
+        for _ in 0..4 {
+            let digit = self.next()?.to_digit(16)
+                .ok_or_else(|| self.error("invalid unicode escape"))?;
+            n = (n << 4) | digit;
+        }
+        Ok(n)
+    }
+
+    fn parse_escape(&mut self) -> Result<char, String> {
+        let c = match self.next()? {
+            '"' => '"',
+            '\\' => '\\',
+            '/' => '/',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let mut n = self.parse_hex4()?;
+                // surrogate pair
+                if n >= 0xd800 && n < 0xdc00 {
+                    self.expect('\\')?;
+                    self.expect('u')?;
+                    let lo = self.parse_hex4()?;
+                    n = 0x10000 + ((n - 0xd800) << 10) + (lo.wrapping_sub(0xdc00) & 0x3ff);
+                }
+                ::std::char::from_u32(n).ok_or_else(|| self.error("invalid unicode escape"))?
+            },
+            _ => return Err(self.error("invalid escape")),
+        };
+        Ok(c)
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => s.push(self.parse_escape()?),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(JsonValue::Array(values));
+        }
+        loop {
+            values.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                ']' => return Ok(JsonValue::Array(values)),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("expected ',' or ']'"));
+                }
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<JsonValue, String> {
+        self.expect('{')?;
+        let mut obj = JsonValue::object();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(obj);
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            let val = self.parse_value()?;
+            obj.set(key, val);
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                '}' => return Ok(obj),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("expected ',' or '}'"));
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(b) => write!(f, "{}", b),
+            JsonValue::Number(n) => write!(f, "{}", n),
+            JsonValue::String(s) => Self::write_string(f, s),
+            JsonValue::Array(values) => {
+                write!(f, "[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            },
+            JsonValue::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    Self::write_string(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+impl From<bool> for JsonValue {
+    fn from(b: bool) -> Self { JsonValue::Bool(b) }
+}
+
+impl From<&str> for JsonValue {
+    fn from(s: &str) -> Self { JsonValue::String(s.to_string()) }
+}
+
+impl From<String> for JsonValue {
+    fn from(s: String) -> Self { JsonValue::String(s) }
+}
+
+impl From<Vec<JsonValue>> for JsonValue {
+    fn from(v: Vec<JsonValue>) -> Self { JsonValue::Array(v) }
+}
+
+impl <T: Into<JsonValue>> From<Option<T>> for JsonValue {
+    fn from(v: Option<T>) -> Self {
+        match v {
+            Some(v) => v.into(),
+            None => JsonValue::Null,
+        }
+    }
+}
+
+macro_rules! json_from_int {
+    ($($t:ty),*) => {
+        $(impl From<$t> for JsonValue {
+            fn from(n: $t) -> Self { JsonValue::Number(n as i64) }
+        })*
+    }
+}
+
+// Values which do not fit in a `Number` are given as a string of their
+// decimal digits rather than wrapping around to a negative number.
+macro_rules! json_from_uint {
+    ($($t:ty),*) => {
+        $(impl From<$t> for JsonValue {
+            fn from(n: $t) -> Self {
+                i64::try_from(n)
+                    .map(JsonValue::Number)
+                    .unwrap_or_else(|_| JsonValue::String(n.to_string()))
+            }
+        })*
+    }
+}
+
+json_from_int!(i32, i64, u8, u16, u32);
+json_from_uint!(u64, usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested(depth: usize) -> String {
+        "[".repeat(depth) + &"]".repeat(depth)
+    }
+
+    #[test]
+    fn parse_refuses_deep_nesting() {
+        assert!(JsonValue::parse(&nested(MAX_DEPTH)).is_ok());
+        let err = JsonValue::parse(&nested(MAX_DEPTH + 1)).unwrap_err();
+        assert!(err.contains("nesting too deep"), "{}", err);
+        let err = JsonValue::parse(&"{\"a\":".repeat(100_000)).unwrap_err();
+        assert!(err.contains("nesting too deep"), "{}", err);
+    }
+
+    #[test]
+    fn large_unsigned_values_do_not_wrap() {
+        assert_eq!(JsonValue::from(i64::max_value() as u64), JsonValue::Number(i64::max_value()));
+        assert_eq!(JsonValue::from(u64::max_value()).to_string(), "\"18446744073709551615\"");
+        assert_eq!(JsonValue::from(1u64 << 63), JsonValue::String("9223372036854775808".into()));
+    }
+}
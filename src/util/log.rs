@@ -44,6 +44,27 @@ pub enum LogLevel {
     Debug,
 }
 
+impl LogLevel {
+    pub const ALL: &'static [LogLevel] = &[
+        LogLevel::Warn, LogLevel::Notice, LogLevel::Info, LogLevel::Verbose, LogLevel::Debug,
+    ];
+
+    /// Parse `warn`, `notice`, `info`, `verbose` or `debug`.
+    pub fn parse(s: &str) -> Option<LogLevel> {
+        Self::ALL.iter().cloned().find(|level| level.name() == s)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Warn => "warn",
+            LogLevel::Notice => "notice",
+            LogLevel::Info => "info",
+            LogLevel::Verbose => "verbose",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
 pub trait LogOutput: Send {
     fn log_output(&mut self, level: LogLevel, line: &str) -> io::Result<()>;
 }
@@ -59,6 +80,10 @@ impl Logger {
         logger.level = level;
     }
 
+    pub fn log_level() -> LogLevel {
+        LOGGER.lock().unwrap().level
+    }
+
     pub fn set_log_output(output: Box<dyn LogOutput>) {
         let mut logger = LOGGER.lock().unwrap();
         logger.output = output;
@@ -1,8 +1,16 @@
 mod bitvec;
 mod buffer;
+mod json;
+mod drbg;
+mod socket;
+pub mod debug_flags;
+pub mod trace;
 #[macro_use]
 mod log;
 
 pub use bitvec::BitSet;
 pub use buffer::ByteBuffer;
+pub use json::JsonValue;
+pub use drbg::Drbg;
+pub(crate) use socket::remove_stale_socket;
 pub use log::{Logger,LogLevel};
@@ -0,0 +1,17 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+/// Remove a socket left at `path` by an earlier run so that a new one can
+/// be bound there. Anything else at the path is refused rather than deleted,
+/// since the path may have been given by mistake.
+pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                    format!("{} exists and is not a socket", path.display()))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
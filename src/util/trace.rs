@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::util::JsonValue;
+
+pub const DEFAULT_TRACE_BUFFER: usize = 4096;
+pub const MAX_TRACE_BUFFER: usize = 1 << 20;
+
+lazy_static! {
+    static ref TRACER: Mutex<Tracer> = Mutex::new(Tracer::new());
+}
+
+// Bit for each enabled `TraceSubsystem`, read without taking the lock so
+// that a disabled trace point costs one load
+static ENABLED: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// A source of trace events which can be switched on and off on its own.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum TraceSubsystem {
+    /// Every exit from `KVM_RUN`, with the vcpu and the exit reason.
+    Exit,
+    /// I/O port accesses, with the port, size and value.
+    Pio,
+    /// MMIO accesses, with the address, size and value.
+    Mmio,
+    /// Notifications, available buffers and completions of each virtqueue.
+    Virtqueue,
+}
+
+impl TraceSubsystem {
+    pub const ALL: &'static [TraceSubsystem] = &[
+        TraceSubsystem::Exit, TraceSubsystem::Pio, TraceSubsystem::Mmio, TraceSubsystem::Virtqueue,
+    ];
+
+    /// Parse `exit`, `pio`, `mmio` or `virtqueue`.
+    pub fn parse(s: &str) -> Option<TraceSubsystem> {
+        Self::ALL.iter().cloned().find(|sub| sub.name() == s)
+    }
+
+    /// Parse a comma separated list of subsystems, `all` or `none`.
+    pub fn parse_list(s: &str) -> Option<Vec<TraceSubsystem>> {
+        match s {
+            "all" => Some(Self::ALL.to_vec()),
+            "none" => Some(Vec::new()),
+            s => s.split(',').map(Self::parse).collect(),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceSubsystem::Exit => "exit",
+            TraceSubsystem::Pio => "pio",
+            TraceSubsystem::Mmio => "mmio",
+            TraceSubsystem::Virtqueue => "virtqueue",
+        }
+    }
+
+    fn bit(self) -> usize {
+        1 << (self as usize)
+    }
+}
+
+struct TraceRecord {
+    at: Duration,
+    subsystem: TraceSubsystem,
+    fields: JsonValue,
+}
+
+impl TraceRecord {
+    fn describe(&self) -> JsonValue {
+        let mut record = JsonValue::object()
+            .with("time-us", self.at.as_micros() as u64)
+            .with("subsystem", self.subsystem.name());
+        if let JsonValue::Object(ref fields) = self.fields {
+            for (name, value) in fields {
+                record.set(name.as_str(), value.clone());
+            }
+        }
+        record
+    }
+
+    // `[    1.234567] mmio address=0xfe003000 size=4 write=true value=0x1`
+    fn to_text(&self) -> String {
+        let mut line = format!("[{:5}.{:06}] {}", self.at.as_secs(), self.at.subsec_micros(), self.subsystem.name());
+        if let JsonValue::Object(ref fields) = self.fields {
+            for (name, value) in fields {
+                match value {
+                    JsonValue::String(s) => line.push_str(&format!(" {}={}", name, s)),
+                    value => line.push_str(&format!(" {}={}", name, value)),
+                }
+            }
+        }
+        line
+    }
+}
+
+///
+/// Records timestamped events from the subsystems which are enabled in a
+/// ring buffer, which keeps the most recent events so that they can be
+/// dumped when something goes wrong, and optionally writes every event to
+/// a file as it is recorded.
+///
+/// Trace points call `trace::event()` with a function which builds the
+/// fields of the event, and the function is only called if the subsystem of
+/// the event is enabled.
+///
+pub struct Tracer {
+    start: Instant,
+    records: VecDeque<TraceRecord>,
+    capacity: usize,
+    dropped: u64,
+    stream: Option<BufWriter<File>>,
+}
+
+impl Tracer {
+    fn new() -> Self {
+        Tracer {
+            start: Instant::now(),
+            records: VecDeque::new(),
+            capacity: DEFAULT_TRACE_BUFFER,
+            dropped: 0,
+            stream: None,
+        }
+    }
+
+    fn push(&mut self, record: TraceRecord) {
+        if let Some(ref mut stream) = self.stream {
+            if let Err(e) = writeln!(stream, "{}", record.to_text()) {
+                eprintln!("Error writing trace file, no longer streaming trace events: {}", e);
+                self.stream = None;
+            }
+        }
+        while self.records.len() >= self.capacity {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        self.records.push_back(record);
+    }
+}
+
+/// Returns `true` if events of `subsystem` are recorded.
+pub fn is_enabled(subsystem: TraceSubsystem) -> bool {
+    ENABLED.load(Ordering::Relaxed) & subsystem.bit() != 0
+}
+
+/// Record events of exactly the subsystems in `subsystems`.
+pub fn set_enabled(subsystems: &[TraceSubsystem]) {
+    let bits = subsystems.iter().fold(0, |bits, sub| bits | sub.bit());
+    ENABLED.store(bits, Ordering::Relaxed);
+}
+
+pub fn enabled() -> Vec<TraceSubsystem> {
+    TraceSubsystem::ALL.iter().cloned().filter(|&sub| is_enabled(sub)).collect()
+}
+
+/// Keep the most recent `capacity` events in the ring buffer.
+pub fn set_capacity(capacity: usize) {
+    let mut tracer = TRACER.lock().unwrap();
+    tracer.capacity = capacity.max(1).min(MAX_TRACE_BUFFER);
+    while tracer.records.len() > tracer.capacity {
+        tracer.records.pop_front();
+        tracer.dropped += 1;
+    }
+}
+
+/// Append every event to the file at `path` as it is recorded.
+pub fn stream_to(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    TRACER.lock().unwrap().stream = Some(BufWriter::new(file));
+    Ok(())
+}
+
+/// Record an event of `subsystem` with the fields returned by `fields` if
+/// the subsystem is enabled.
+pub fn event<F: FnOnce() -> JsonValue>(subsystem: TraceSubsystem, fields: F) {
+    if !is_enabled(subsystem) {
+        return;
+    }
+    let fields = fields();
+    let mut tracer = TRACER.lock().unwrap();
+    let at = tracer.start.elapsed();
+    tracer.push(TraceRecord { at, subsystem, fields });
+}
+
+/// Write buffered events to the trace file if events are streamed to one.
+pub fn flush() {
+    if let Some(ref mut stream) = TRACER.lock().unwrap().stream {
+        let _ = stream.flush();
+    }
+}
+
+/// The filter and the most recent `limit` events in the ring buffer.
+pub fn describe(limit: usize) -> JsonValue {
+    let tracer = TRACER.lock().unwrap();
+    let skip = tracer.records.len().saturating_sub(limit);
+    let records: Vec<JsonValue> = tracer.records.iter().skip(skip).map(|r| r.describe()).collect();
+    let enabled: Vec<JsonValue> = enabled().iter().map(|sub| sub.name().into()).collect();
+    JsonValue::object()
+        .with("enabled", enabled)
+        .with("buffered", tracer.records.len())
+        .with("capacity", tracer.capacity)
+        .with("dropped", tracer.dropped)
+        .with("streaming", tracer.stream.is_some())
+        .with("records", records)
+}
+
+/// Print the events in the ring buffer to stderr after `reason`, and empty
+/// the buffer.
+pub fn dump(reason: &str) {
+    let mut tracer = TRACER.lock().unwrap();
+    if let Some(ref mut stream) = tracer.stream {
+        let _ = stream.flush();
+    }
+    if tracer.records.is_empty() {
+        return;
+    }
+    let stderr = io::stderr();
+    let mut out = stderr.lock();
+    let _ = writeln!(out, "Last {} trace events before {}:", tracer.records.len(), reason);
+    for record in tracer.records.drain(..) {
+        let _ = writeln!(out, "{}", record.to_text());
+    }
+}
@@ -0,0 +1,352 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::consts::*;
+use super::quirks::Quirk;
+use crate::util::JsonValue;
+
+// Accesses kept for each device since it was last reset
+const MAX_ENTRIES: usize = 512;
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ConfigSpace {
+    /// The common configuration structure of the modern interface
+    Common,
+    /// The registers of the legacy I/O port interface
+    Legacy,
+    /// The registers of the virtio-mmio transport
+    Mmio,
+    /// The device specific configuration, through any interface
+    Device,
+}
+
+impl ConfigSpace {
+    fn name(self) -> &'static str {
+        match self {
+            ConfigSpace::Common => "common",
+            ConfigSpace::Legacy => "legacy",
+            ConfigSpace::Mmio => "mmio",
+            ConfigSpace::Device => "device",
+        }
+    }
+}
+
+fn register_name(space: ConfigSpace, offset: usize) -> Option<&'static str> {
+    let name = match space {
+        ConfigSpace::Common => match offset {
+            VIRTIO_PCI_COMMON_DFSELECT => "device_feature_select",
+            VIRTIO_PCI_COMMON_DF => "device_feature",
+            VIRTIO_PCI_COMMON_GFSELECT => "driver_feature_select",
+            VIRTIO_PCI_COMMON_GF => "driver_feature",
+            VIRTIO_PCI_COMMON_MSIX => "msix_config",
+            VIRTIO_PCI_COMMON_NUMQ => "num_queues",
+            VIRTIO_PCI_COMMON_STATUS => "device_status",
+            VIRTIO_PCI_COMMON_CFGGENERATION => "config_generation",
+            VIRTIO_PCI_COMMON_Q_SELECT => "queue_select",
+            VIRTIO_PCI_COMMON_Q_SIZE => "queue_size",
+            VIRTIO_PCI_COMMON_Q_MSIX => "queue_msix_vector",
+            VIRTIO_PCI_COMMON_Q_ENABLE => "queue_enable",
+            VIRTIO_PCI_COMMON_Q_NOFF => "queue_notify_off",
+            VIRTIO_PCI_COMMON_Q_DESCLO => "queue_desc_lo",
+            VIRTIO_PCI_COMMON_Q_DESCHI => "queue_desc_hi",
+            VIRTIO_PCI_COMMON_Q_AVAILLO => "queue_avail_lo",
+            VIRTIO_PCI_COMMON_Q_AVAILHI => "queue_avail_hi",
+            VIRTIO_PCI_COMMON_Q_USEDLO => "queue_used_lo",
+            VIRTIO_PCI_COMMON_Q_USEDHI => "queue_used_hi",
+            _ => return None,
+        },
+        ConfigSpace::Legacy => match offset {
+            VIRTIO_PCI_LEGACY_HOST_FEATURES => "host_features",
+            VIRTIO_PCI_LEGACY_GUEST_FEATURES => "guest_features",
+            VIRTIO_PCI_LEGACY_QUEUE_PFN => "queue_pfn",
+            VIRTIO_PCI_LEGACY_QUEUE_NUM => "queue_num",
+            VIRTIO_PCI_LEGACY_QUEUE_SEL => "queue_sel",
+            VIRTIO_PCI_LEGACY_STATUS => "status",
+            VIRTIO_PCI_LEGACY_MSIX_CONFIG => "config_msix_vector",
+            VIRTIO_PCI_LEGACY_MSIX_QUEUE => "queue_msix_vector",
+            _ => return None,
+        },
+        ConfigSpace::Mmio => match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => "magic_value",
+            VIRTIO_MMIO_VERSION => "version",
+            VIRTIO_MMIO_DEVICE_ID => "device_id",
+            VIRTIO_MMIO_VENDOR_ID => "vendor_id",
+            VIRTIO_MMIO_DEVICE_FEATURES => "device_features",
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => "device_features_sel",
+            VIRTIO_MMIO_DRIVER_FEATURES => "driver_features",
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => "driver_features_sel",
+            VIRTIO_MMIO_QUEUE_SEL => "queue_sel",
+            VIRTIO_MMIO_QUEUE_NUM_MAX => "queue_num_max",
+            VIRTIO_MMIO_QUEUE_NUM => "queue_num",
+            VIRTIO_MMIO_QUEUE_READY => "queue_ready",
+            VIRTIO_MMIO_STATUS => "status",
+            VIRTIO_MMIO_QUEUE_DESC_LOW => "queue_desc_low",
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => "queue_desc_high",
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => "queue_avail_low",
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => "queue_avail_high",
+            VIRTIO_MMIO_QUEUE_USED_LOW => "queue_used_low",
+            VIRTIO_MMIO_QUEUE_USED_HIGH => "queue_used_high",
+            VIRTIO_MMIO_SHM_LEN_LOW => "shm_len_low",
+            VIRTIO_MMIO_SHM_LEN_HIGH => "shm_len_high",
+            VIRTIO_MMIO_CONFIG_GENERATION => "config_generation",
+            _ => return None,
+        },
+        ConfigSpace::Device => return None,
+    };
+    Some(name)
+}
+
+struct AuditEntry {
+    ms: u64,
+    space: ConfigSpace,
+    write: bool,
+    offset: usize,
+    size: usize,
+    value: u64,
+}
+
+impl AuditEntry {
+    fn describe(&self) -> JsonValue {
+        JsonValue::object()
+            .with("ms", self.ms)
+            .with("op", if self.write { "write" } else { "read" })
+            .with("space", self.space.name())
+            .with("register", register_name(self.space, self.offset))
+            .with("offset", self.offset)
+            .with("size", self.size)
+            .with("value", format!("0x{:x}", self.value))
+    }
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = if self.write { "write" } else { "read " };
+        write!(f, "{:>6}ms {} {}", self.ms, op, self.space.name())?;
+        match register_name(self.space, self.offset) {
+            Some(name) => write!(f, " {}", name)?,
+            None => write!(f, " +0x{:x}", self.offset)?,
+        }
+        write!(f, " ({} bytes) = 0x{:x}", self.size, self.value)
+    }
+}
+
+// Feature bits which belong to the transport rather than the device type
+const TRANSPORT_FEATURES: &[(u32, &str)] = &[
+    (24, "notify-on-empty"),
+    (27, "any-layout"),
+    (28, "indirect-desc"),
+    (29, "event-idx"),
+    (32, "version-1"),
+    (33, "access-platform"),
+    (34, "ring-packed"),
+    (35, "in-order"),
+    (36, "order-platform"),
+    (37, "sr-iov"),
+    (38, "notification-data"),
+];
+
+const STATUS_BITS: &[(u8, &str)] = &[
+    (1, "acknowledge"),
+    (2, "driver"),
+    (4, "driver-ok"),
+    (8, "features-ok"),
+    (0x40, "needs-reset"),
+    (0x80, "failed"),
+];
+
+// Names of the bits set in `features`. Bits specific to the device type are
+// named by number.
+fn feature_names(features: u64) -> Vec<JsonValue> {
+    (0..64).filter(|bit| features & (1u64 << bit) != 0).map(|bit| {
+        match TRANSPORT_FEATURES.iter().find(|&&(b, _)| b == bit) {
+            Some(&(_, name)) => JsonValue::from(name),
+            None => JsonValue::from(format!("bit-{}", bit)),
+        }
+    }).collect()
+}
+
+fn status_names(status: u8) -> Vec<JsonValue> {
+    STATUS_BITS.iter()
+        .filter(|&&(bit, _)| status & bit != 0)
+        .map(|&(_, name)| JsonValue::from(name))
+        .collect()
+}
+
+#[derive(Default)]
+struct AuditState {
+    entries: Vec<AuditEntry>,
+    dropped: usize,
+    resets: usize,
+    quirks: u32,
+    status: u8,
+    device_features: u64,
+    guest_features: u64,
+}
+
+///
+/// The configuration space accesses of one virtio device since the driver
+/// last reset it, which is the whole negotiation sequence of the current
+/// driver, and the driver quirks which the device has worked around.
+///
+/// Accesses are only recorded when auditing is enabled. Queue notifications
+/// and ISR reads are not recorded.
+///
+pub struct DeviceAudit {
+    name: String,
+    enabled: bool,
+    start: Instant,
+    state: Mutex<AuditState>,
+}
+
+impl DeviceAudit {
+    pub fn new(name: &str, enabled: bool) -> Self {
+        DeviceAudit {
+            name: name.to_string(),
+            enabled,
+            start: Instant::now(),
+            state: Mutex::new(AuditState::default()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn record(&self, space: ConfigSpace, write: bool, offset: usize, size: usize, value: u64) {
+        if !self.enabled {
+            return;
+        }
+        let ms = self.start.elapsed().as_millis() as u64;
+        let mut state = self.state.lock().unwrap();
+        if state.entries.len() < MAX_ENTRIES {
+            state.entries.push(AuditEntry { ms, space, write, offset, size, value });
+        } else {
+            state.dropped += 1;
+        }
+    }
+
+    /// The driver reset the device, which begins a new negotiation sequence.
+    pub fn record_reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.resets += 1;
+        state.entries.clear();
+        state.dropped = 0;
+    }
+
+    /// The features the device offers to the driver.
+    pub fn set_device_features(&self, features: u64) {
+        self.state.lock().unwrap().device_features = features;
+    }
+
+    pub fn record_status(&self, status: u8, guest_features: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.status = status;
+        state.guest_features = guest_features;
+    }
+
+    /// Record that `quirk` was worked around. Returns `true` the first time
+    /// it is seen on this device.
+    pub fn record_quirk(&self, quirk: Quirk) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let first = state.quirks & quirk.bit() == 0;
+        state.quirks |= quirk.bit();
+        first
+    }
+
+    /// Log the negotiation sequence after it failed with `reason`.
+    pub fn report_failure(&self, reason: &str) {
+        if !self.enabled {
+            return;
+        }
+        let state = self.state.lock().unwrap();
+        warn!("virtio-audit: {}: {}, negotiation sequence:", self.name, reason);
+        for entry in &state.entries {
+            warn!("virtio-audit: {}: {}", self.name, entry);
+        }
+        if state.dropped > 0 {
+            warn!("virtio-audit: {}: ({} later accesses not recorded)", self.name, state.dropped);
+        }
+    }
+
+    fn describe(&self) -> JsonValue {
+        let state = self.state.lock().unwrap();
+        let quirks: Vec<JsonValue> = Quirk::from_bits(state.quirks).map(|q| {
+            JsonValue::object()
+                .with("name", q.name())
+                .with("workaround", q.workaround())
+        }).collect();
+        let mut device = JsonValue::object()
+            .with("device", self.name.as_str())
+            .with("status", format!("0x{:02x}", state.status))
+            .with("guest-features", format!("0x{:x}", state.guest_features))
+            .with("resets", state.resets)
+            .with("quirks", quirks);
+        if self.enabled {
+            let entries: Vec<JsonValue> = state.entries.iter().map(|e| e.describe()).collect();
+            device = device
+                .with("accesses", entries)
+                .with("dropped", state.dropped);
+        }
+        device
+    }
+
+    // The features which the device offered and which the driver accepted
+    // as of the last status change, with the status of the device
+    fn describe_features(&self) -> JsonValue {
+        let state = self.state.lock().unwrap();
+        let declined = state.device_features & !state.guest_features;
+        JsonValue::object()
+            .with("device", self.name.as_str())
+            .with("status", format!("0x{:02x}", state.status))
+            .with("status-bits", status_names(state.status))
+            .with("offered", format!("0x{:x}", state.device_features))
+            .with("accepted", format!("0x{:x}", state.guest_features))
+            .with("declined", format!("0x{:x}", declined))
+            .with("accepted-features", feature_names(state.guest_features))
+            .with("declined-features", feature_names(declined))
+            .with("resets", state.resets)
+    }
+}
+
+///
+/// The `DeviceAudit` of every virtio device on the bus.
+///
+#[derive(Clone)]
+pub struct VirtioAudit {
+    enabled: bool,
+    devices: Arc<Mutex<Vec<Arc<DeviceAudit>>>>,
+}
+
+impl VirtioAudit {
+    pub fn new(enabled: bool) -> Self {
+        VirtioAudit { enabled, devices: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    pub fn add_device(&self, name: &str) -> Arc<DeviceAudit> {
+        let audit = Arc::new(DeviceAudit::new(name, self.enabled));
+        self.devices.lock().unwrap().push(audit.clone());
+        audit
+    }
+
+    pub fn describe(&self) -> JsonValue {
+        let devices: Vec<JsonValue> = self.devices.lock().unwrap()
+            .iter()
+            .map(|d| d.describe())
+            .collect();
+        JsonValue::object()
+            .with("enabled", self.enabled)
+            .with("devices", devices)
+    }
+
+    /// The offered and accepted features and the status of every device,
+    /// which are kept whether or not auditing is enabled.
+    pub fn describe_features(&self) -> JsonValue {
+        let devices: Vec<JsonValue> = self.devices.lock().unwrap()
+            .iter()
+            .map(|d| d.describe_features())
+            .collect();
+        JsonValue::object()
+            .with("devices", devices)
+    }
+}
@@ -1,11 +1,20 @@
+use std::collections::HashMap;
 use std::sync::{Arc,RwLock};
+use std::time::Duration;
 use crate::vm::io::IoDispatcher;
 use crate::kvm::Kvm;
-use crate::memory::{AddressRange, MemoryManager};
-use super::{VirtioDevice,VirtioDeviceOps,PciIrq};
+use crate::memory::{AddressRange, MemoryManager, SharedMemoryRegion};
+use super::{VirtioDevice,VirtioDeviceOps,PciIrq,PciIdentity};
 use super::consts::*;
 use super::pci::PciBus;
-use crate::virtio::Result;
+use super::identity::device_type_name;
+use super::irq::{IrqCounters, IrqPolicy, IrqStats};
+use super::audit::{DeviceAudit, VirtioAudit};
+use super::msix::MsixVectors;
+use super::hotplug::{HotplugSlots, PciHotplug};
+use crate::util::JsonValue;
+use crate::vm::metrics::Labels;
+use crate::virtio::{Result, Error};
 use std::iter;
 
 
@@ -15,67 +24,240 @@ pub struct VirtioBus {
     io_dispatcher: Arc<IoDispatcher>,
     pci_bus: Arc<RwLock<PciBus>>,
     devices: Vec<Arc<RwLock<VirtioDevice>>>,
+    identities: HashMap<u16, PciIdentity>,
+    interrupt_windows: HashMap<u16, Duration>,
+    audit: VirtioAudit,
+    msix: bool,
+    hotplug: Option<PciHotplug>,
+    mmio_transport: bool,
+    mmio_devices: Vec<(AddressRange, u8)>,
 }
 
 impl VirtioBus {
     pub fn new(memory: MemoryManager, io_dispatcher: Arc<IoDispatcher>, kvm: Kvm) -> VirtioBus {
         VirtioBus {
+            pci_bus: PciBus::new(&io_dispatcher, &kvm),
             kvm,
             memory,
             io_dispatcher: io_dispatcher.clone(),
-            pci_bus: PciBus::new(&io_dispatcher),
             devices: Vec::new(),
+            identities: HashMap::new(),
+            interrupt_windows: HashMap::new(),
+            audit: VirtioAudit::new(false),
+            msix: false,
+            hotplug: None,
+            mmio_transport: false,
+            mmio_devices: Vec::new(),
         }
     }
 
+    pub fn memory(&self) -> &MemoryManager {
+        &self.memory
+    }
+
+    /// Override the PCI identity of devices of type `device_type` created after this call.
+    pub fn set_pci_identity(&mut self, device_type: u16, identity: PciIdentity) {
+        self.identities.insert(device_type, identity);
+    }
+
+    /// Hold back the queue interrupts of devices of type `device_type`
+    /// created after this call for up to `window`, so that completions which
+    /// arrive within the window share one interrupt.
+    pub fn set_interrupt_window(&mut self, device_type: u16, window: Duration) {
+        self.interrupt_windows.insert(device_type, window);
+    }
+
     pub fn new_virtio_device(&mut self, device_type: u16, ops: Arc<RwLock<dyn VirtioDeviceOps>>) -> VirtioDeviceConfig {
         VirtioDeviceConfig::new(self, device_type, ops)
     }
 
+    /// Set the policy for assigning interrupt lines to devices created after this call.
+    pub fn set_irq_policy(&mut self, policy: IrqPolicy) {
+        self.pci_bus.write().unwrap().set_irq_policy(policy);
+    }
+
     pub fn pci_irqs(&self) -> Vec<PciIrq> {
         self.pci_bus.read().unwrap().pci_irqs()
     }
+
+    pub fn irq_stats(&self) -> IrqStats {
+        self.pci_bus.read().unwrap().irq_stats()
+    }
+
+    /// Record the configuration space accesses of devices created after this call.
+    pub fn set_config_audit(&mut self, enabled: bool) {
+        self.audit = VirtioAudit::new(enabled);
+    }
+
+    pub fn config_audit(&self) -> VirtioAudit {
+        self.audit.clone()
+    }
+
+    /// Give devices created after this call an MSI-X capability with a
+    /// vector for each queue.
+    pub fn set_msix(&mut self, enabled: bool) {
+        self.msix = enabled;
+    }
+
+    /// Register devices created after this call with the virtio-mmio
+    /// transport at a fixed address instead of on the PCI bus.
+    pub fn set_mmio_transport(&mut self, enabled: bool) {
+        self.mmio_transport = enabled;
+    }
+
+    /// The register area and interrupt line of each virtio-mmio device,
+    /// which must be described to the guest before it boots.
+    pub fn mmio_devices(&self) -> &[(AddressRange, u8)] {
+        &self.mmio_devices
+    }
+
+    /// Add a PCI hotplug controller. Devices registered after this call can
+    /// be removed from the running VM.
+    pub fn enable_hotplug(&mut self) -> Result<()> {
+        let hotplug = PciHotplug::new(&self.kvm, self.pci_bus.clone(), self.io_dispatcher.clone())?;
+        self.hotplug = Some(hotplug);
+        Ok(())
+    }
+
+    /// The interrupt lines and empty slots of the hotplug controller, which
+    /// must be described to the guest before it boots.
+    pub fn hotplug_slots(&self) -> Option<HotplugSlots> {
+        self.hotplug.as_ref().map(|h| h.slots())
+    }
+
+    /// Called once the devices present at boot are registered. Devices
+    /// registered after this call are announced to the guest.
+    pub fn start_hotplug(&self) {
+        if let Some(ref hotplug) = self.hotplug {
+            hotplug.start();
+        }
+    }
+
+    /// Add a device to the running VM. `create` registers the device, as
+    /// the `create` function of each device type does before boot.
+    pub fn attach_device_live<F>(&mut self, create: F) -> Result<()>
+        where F: FnOnce(&mut VirtioBus) -> Result<()>
+    {
+        match self.hotplug {
+            Some(ref hotplug) if hotplug.is_started() => {},
+            _ => return Err(Error::HotplugNotEnabled),
+        }
+        create(self)
+    }
+
+    /// Ask the guest to release the device in PCI slot `slot`. The device is
+    /// removed from the bus when the guest ejects it.
+    pub fn detach_device_live(&mut self, slot: u8) -> Result<()> {
+        match self.hotplug {
+            Some(ref hotplug) => hotplug.request_removal(slot),
+            None => Err(Error::HotplugNotEnabled),
+        }
+    }
+
+    pub fn describe_hotplug(&self) -> JsonValue {
+        match self.hotplug {
+            Some(ref hotplug) => hotplug.describe(),
+            None => JsonValue::object().with("enabled", false),
+        }
+    }
 }
 
 pub struct VirtioDeviceConfig<'a> {
     virtio_bus: &'a mut VirtioBus,
     device_type: u16,
     irq: u8,
+    irq_counters: Arc<IrqCounters>,
+    interrupt_window: Option<Duration>,
+    audit: Arc<DeviceAudit>,
+    msix: Option<Arc<MsixVectors>>,
     kvm: Kvm,
     ops: Arc<RwLock<dyn VirtioDeviceOps>>,
     mmio: AddressRange,
     queue_sizes: Vec<usize>,
+    required_queues: Option<usize>,
     config_size: usize,
     device_class: u16,
     features: u64,
-
+    legacy_io: Option<(u16, usize)>,
+    shared_regions: Vec<SharedMemoryRegion>,
+    mmio_transport: bool,
+    pci_id: u8,
+    name: String,
 }
 
 impl <'a> VirtioDeviceConfig<'a> {
     fn new(virtio_bus: &mut VirtioBus, device_type: u16, ops: Arc<RwLock<dyn VirtioDeviceOps>>) -> VirtioDeviceConfig {
         let kvm = virtio_bus.kvm.clone();
-        let mmio = virtio_bus.pci_bus.write().unwrap().allocate_mmio_space(VIRTIO_MMIO_AREA_SIZE);
+        // Allocated when the device is registered and its interface is known
+        let mmio = AddressRange::new(0, VIRTIO_MMIO_AREA_SIZE);
+        let interrupt_window = virtio_bus.interrupt_windows.get(&device_type).cloned();
         VirtioDeviceConfig {
             virtio_bus,
             device_type,
             irq: 0,
+            irq_counters: Arc::new(IrqCounters::default()),
+            interrupt_window,
+            audit: Arc::new(DeviceAudit::new("", false)),
+            msix: None,
             kvm,
             ops,
             mmio,
             queue_sizes: Vec::new(),
+            required_queues: None,
             config_size: 0,
             features: 0,
             device_class: 0x0880,
+            legacy_io: None,
+            shared_regions: Vec::new(),
+            mmio_transport: false,
+            pci_id: 0,
+            name: String::new(),
         }
     }
 
     pub fn kvm(&self) -> &Kvm { &self.kvm }
 
+    pub fn device_type(&self) -> u16 { self.device_type }
+
+    /// Base port and size of the legacy I/O BAR if the legacy interface is enabled
+    pub fn legacy_io(&self) -> Option<(u16, usize)> { self.legacy_io }
+
     pub fn ops(&self) -> Arc<RwLock<dyn VirtioDeviceOps>> {
         self.ops.clone()
     }
     pub fn irq(&self) -> u8 { self.irq }
 
+    pub fn irq_counters(&self) -> Arc<IrqCounters> { self.irq_counters.clone() }
+
+    pub fn interrupt_window(&self) -> Option<Duration> { self.interrupt_window }
+
+    pub fn audit(&self) -> Arc<DeviceAudit> { self.audit.clone() }
+
+    /// Labels which identify the counters of this device in `metrics`.
+    pub fn metric_labels(&self) -> Labels {
+        let name = device_type_name(self.device_type)
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| format!("virtio-{}", self.device_type));
+        let slot = match self.mmio_transport() {
+            Some(transport) => format!("mmio@{:x}", transport.base()),
+            None => format!("{:02x}", self.pci_id),
+        };
+        Labels::new()
+            .with("device", name)
+            .with("slot", slot)
+    }
+
+    pub fn msix(&self) -> Option<Arc<MsixVectors>> { self.msix.clone() }
+
+    /// The registers of the virtio-mmio transport if the device is not on the PCI bus
+    pub fn mmio_transport(&self) -> Option<AddressRange> {
+        if self.mmio_transport {
+            Some(self.mmio)
+        } else {
+            None
+        }
+    }
+
     pub fn common_cfg_mmio(&self) -> AddressRange {
         self.mmio.subrange(VIRTIO_MMIO_OFFSET_COMMON_CFG, VIRTIO_MMIO_COMMON_CFG_SIZE).unwrap()
     }
@@ -88,6 +270,14 @@ impl <'a> VirtioDeviceConfig<'a> {
         self.mmio.subrange(VIRTIO_MMIO_OFFSET_ISR, VIRTIO_MMIO_ISR_SIZE).unwrap()
     }
 
+    pub fn msix_table_mmio(&self) -> AddressRange {
+        self.mmio.subrange(VIRTIO_MMIO_OFFSET_MSIX_TABLE, VIRTIO_MMIO_MSIX_TABLE_SIZE).unwrap()
+    }
+
+    pub fn msix_pba_mmio(&self) -> AddressRange {
+        self.mmio.subrange(VIRTIO_MMIO_OFFSET_MSIX_PBA, VIRTIO_MMIO_MSIX_PBA_SIZE).unwrap()
+    }
+
     pub fn device_cfg_mmio(&self) -> Option<AddressRange> {
         if self.config_size > 0 {
             Some(self.mmio.subrange(VIRTIO_MMIO_OFFSET_DEV_CFG, self.config_size).unwrap())
@@ -108,7 +298,12 @@ impl <'a> VirtioDeviceConfig<'a> {
         &self.queue_sizes
     }
 
-    #[allow(dead_code)]
+    /// Number of queues which the driver must enable before the device
+    /// starts. Queues after these are optional.
+    pub fn required_queues(&self) -> usize {
+        self.required_queues.unwrap_or(self.queue_sizes.len())
+    }
+
     pub fn config_size(&self) -> usize {
         self.config_size
     }
@@ -116,12 +311,32 @@ impl <'a> VirtioDeviceConfig<'a> {
     pub fn set_queue_sizes(&mut self, sizes: &[usize]) -> &'a mut VirtioDeviceConfig {
         self.queue_sizes.clear();
         self.queue_sizes.extend_from_slice(sizes);
+        self.required_queues = None;
         self
     }
 
     pub fn set_num_queues(&mut self, n: usize) -> &'a mut VirtioDeviceConfig {
         self.queue_sizes.clear();
         self.queue_sizes.extend(iter::repeat(DEFAULT_QUEUE_SIZE as usize).take(n));
+        self.required_queues = None;
+        self
+    }
+
+    /// Offer `pairs` pairs of queues with `size` entries, ordered as receive
+    /// and transmit queue of the first pair, then of the second pair and so
+    /// on. The driver may enable fewer pairs than offered, but must enable
+    /// the first pair.
+    pub fn set_queue_pairs(&mut self, pairs: usize, size: usize) -> &'a mut VirtioDeviceConfig {
+        self.queue_sizes.clear();
+        self.queue_sizes.extend(iter::repeat(size).take(2 * pairs));
+        self.required_queues = Some(2);
+        self
+    }
+
+    /// Let the driver leave the queues after the first `n` disabled, for
+    /// queues which only exist when some feature has been negotiated.
+    pub fn set_required_queues(&mut self, n: usize) -> &'a mut VirtioDeviceConfig {
+        self.required_queues = Some(n);
         self
     }
 
@@ -140,22 +355,131 @@ impl <'a> VirtioDeviceConfig<'a> {
         self
     }
 
+    /// Expose `region` to the guest with a PCI shared memory capability.
+    /// Each region takes a 64 bit BAR of its own.
+    pub fn add_shared_memory(&mut self, region: &SharedMemoryRegion) -> &'a mut VirtioDeviceConfig {
+        self.shared_regions.push(region.clone());
+        self
+    }
+
     pub fn register(&mut self) -> Result<()> {
-        self.create_pci_device();
+        if self.queue_sizes.len() > VIRTIO_MAX_QUEUES {
+            return Err(Error::TooManyQueues(self.queue_sizes.len()));
+        }
+        if self.shared_regions.len() > VIRTIO_SHM_BARS.len() {
+            return Err(Error::TooManySharedRegions(self.shared_regions.len()));
+        }
+        if self.virtio_bus.mmio_transport {
+            self.create_mmio_device()?;
+        } else {
+            self.create_pci_device()?;
+        }
         self.features |= VIRTIO_F_VERSION_1;
         //self.features |= VIRTIO_F_EVENT_IDX;
         let dev = VirtioDevice::new(self.virtio_bus.memory.clone(), &self)?;
         self.virtio_bus.io_dispatcher.register_mmio(self.mmio, dev.clone());
+        if let Some((port, size)) = self.legacy_io {
+            self.virtio_bus.io_dispatcher.register_ioports(port, size, dev.clone());
+        }
+        if self.mmio_transport {
+            self.virtio_bus.mmio_devices.push((self.mmio, self.irq));
+        } else if let Some(ref hotplug) = self.virtio_bus.hotplug {
+            let legacy_port = self.legacy_io.map(|(port, _)| port);
+            hotplug.add_device(self.pci_id, &self.name, dev.clone(), self.mmio, legacy_port);
+        }
         self.virtio_bus.devices.push(dev);
         Ok(())
     }
 
-    fn create_pci_device(&mut self) {
+    // The device takes an area of the PCI MMIO window with the registers of
+    // the virtio-mmio transport at the start and the device configuration
+    // after them. It gets an interrupt line as a PCI device would, but has
+    // no MSI-X vectors or shared memory regions.
+    fn create_mmio_device(&mut self) -> Result<()> {
+        if VIRTIO_MMIO_CONFIG + self.config_size > VIRTIO_MMIO_AREA_SIZE {
+            return Err(Error::MmioConfigTooLarge(self.config_size));
+        }
         let mut pci_bus = self.virtio_bus.pci_bus.write().unwrap();
-        let mut pci = pci_bus.create_device(PCI_VENDOR_ID_REDHAT, PCI_VIRTIO_DEVICE_ID_BASE + self.device_type, self.device_class);
-        pci.add_virtio_caps(self.config_size);
-        pci.set_mmio_bar(VIRTIO_MMIO_BAR, self.mmio);
+        self.mmio = pci_bus.allocate_mmio_space(VIRTIO_MMIO_AREA_SIZE)
+            .ok_or(Error::MmioSpaceExhausted)?;
+        self.mmio_transport = true;
+        self.irq = pci_bus.allocate_irq()?;
+        let name = match device_type_name(self.device_type) {
+            Some(name) => format!("mmio@{:x} {}", self.mmio.base(), name),
+            None => format!("mmio@{:x} virtio-{}", self.mmio.base(), self.device_type),
+        };
+        self.irq_counters = pci_bus.irq_stats().add_device(self.irq, &name);
+        self.audit = self.virtio_bus.audit.add_device(&name);
+        self.name = name;
+        Ok(())
+    }
+
+    fn create_pci_device(&mut self) -> Result<()> {
+        let identity = self.virtio_bus.identities.get(&self.device_type)
+            .cloned()
+            .unwrap_or(PciIdentity::new());
+        let device_id = identity.device_id(self.device_type)?;
+        let revision = identity.revision_id(self.device_type)?;
+        let subsystem_id = identity.subsystem_id(self.device_type)?;
+
+        let mut pci_bus = self.virtio_bus.pci_bus.write().unwrap();
+        let mut pci = pci_bus.create_device(PCI_VENDOR_ID_REDHAT, device_id, self.device_class)?;
+        pci.set_revision(revision);
+        pci.set_subsystem(identity.subsystem_vendor_id(), subsystem_id);
+
+        // The virtio MMIO area is placed below 4GB while there is room and
+        // then above guest RAM with a 64 bit BAR. The legacy interface moves
+        // the area to BAR 1, where a 64 bit BAR would overlap the first
+        // shared memory BAR, so those devices only use the window below 4GB.
+        let (mmio, mmio64) = match pci_bus.allocate_mmio_space(VIRTIO_MMIO_AREA_SIZE) {
+            Some(mmio) => (mmio, false),
+            None if !identity.has_legacy_interface() => {
+                let mmio = self.virtio_bus.memory.allocate_device_window(VIRTIO_MMIO_AREA_SIZE)
+                    .map_err(|_| Error::MmioSpaceExhausted)?;
+                (mmio, true)
+            }
+            None => return Err(Error::MmioSpaceExhausted),
+        };
+        self.mmio = mmio;
+
+        let mmio_bar = if identity.has_legacy_interface() {
+            // Room for the device configuration after the MSI-X vector registers
+            let config_base = if self.virtio_bus.msix { VIRTIO_PCI_LEGACY_CONFIG_MSIX } else { VIRTIO_PCI_LEGACY_CONFIG };
+            let size = (config_base + self.config_size).next_power_of_two();
+            let port = pci_bus.allocate_io_space(size);
+            pci.set_io_bar(VIRTIO_LEGACY_IO_BAR, port, size);
+            self.legacy_io = Some((port, size));
+            VIRTIO_LEGACY_MMIO_BAR
+        } else {
+            VIRTIO_MMIO_BAR
+        };
+        pci.add_virtio_caps(self.config_size, mmio_bar);
+        if mmio64 {
+            pci.set_mmio_bar64(mmio_bar, self.mmio, false);
+        } else {
+            pci.set_mmio_bar(mmio_bar, self.mmio);
+        }
+        if self.virtio_bus.msix {
+            // One vector for configuration changes and one for each queue
+            let vectors = (self.queue_sizes.len() + 1).min(VIRTIO_MSIX_MAX_VECTORS);
+            let msix = Arc::new(MsixVectors::new(&self.kvm, vectors, self.queue_sizes.len()));
+            pci.add_msix_cap(mmio_bar, VIRTIO_MMIO_OFFSET_MSIX_TABLE, VIRTIO_MMIO_OFFSET_MSIX_PBA, msix.clone());
+            self.msix = Some(msix);
+        }
+        for (region, &bar) in self.shared_regions.iter().zip(VIRTIO_SHM_BARS) {
+            pci.set_mmio_bar64(bar, region.window(), true);
+            pci.add_shared_memory_cap(bar, region.id(), region.size() as u64);
+        }
         self.irq = pci.get_irq();
+        let name = match device_type_name(self.device_type) {
+            Some(name) => format!("{:02x} {}", pci.id(), name),
+            None => format!("{:02x} virtio-{}", pci.id(), self.device_type),
+        };
+        self.irq_counters = pci_bus.irq_stats().add_device(self.irq, &name);
+        self.audit = self.virtio_bus.audit.add_device(&name);
+        self.pci_id = pci.id();
+        self.name = name;
         pci_bus.store_device(pci);
+        Ok(())
     }
 }
\ No newline at end of file
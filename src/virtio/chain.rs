@@ -4,6 +4,9 @@ use std::io::{self,Read,Write};
 use crate::memory::GuestRam;
 use crate::virtio::VirtQueue;
 use crate::virtio::vring::Descriptor;
+#[cfg(feature = "fuzzing")]
+use crate::virtio::vring::Vring;
+use crate::vm::replay;
 
 struct DescriptorList {
     memory: GuestRam,
@@ -38,6 +41,12 @@ impl DescriptorList {
         self.offset = 0;
     }
 
+    // Skip the remaining buffers as if they had been consumed
+    fn discard(&mut self) {
+        self.clear();
+        self.consumed_size = self.total_size;
+    }
+
     fn is_empty(&self) -> bool {
         self.descriptors.is_empty()
     }
@@ -131,6 +140,49 @@ impl DescriptorList {
     fn remaining(&self) -> usize {
         self.total_size - self.consumed_size
     }
+
+    // Guest address and size of each remaining buffer, up to `max` bytes in total
+    fn buffers(&self, max: usize) -> Vec<(u64, usize)> {
+        let mut buffers = Vec::new();
+        let mut offset = self.offset;
+        let mut remaining = max;
+        for d in self.descriptors.iter().rev() {
+            if remaining == 0 {
+                break;
+            }
+            let size = ::std::cmp::min(d.remaining(offset), remaining);
+            if size > 0 {
+                buffers.push((d.addr + offset as u64, size));
+            }
+            remaining -= size;
+            offset = 0;
+        }
+        buffers
+    }
+
+    fn slices(&self, max: usize) -> Vec<&[u8]> {
+        self.buffers(max).into_iter()
+            .map(|(addr, size)| self.memory.slice(addr, size).unwrap_or(&[]))
+            .collect()
+    }
+
+    fn mut_slices(&self, max: usize) -> Vec<&mut [u8]> {
+        self.buffers(max).into_iter()
+            .map(|(addr, size)| self.memory.mut_slice(addr, size).unwrap_or(&mut []))
+            .collect()
+    }
+
+    // Like inc() but `len` may extend over several descriptors
+    fn advance(&mut self, mut len: usize) {
+        while len > 0 {
+            let n = match self.current() {
+                Some(d) => ::std::cmp::min(d.remaining(self.offset), len),
+                None => return,
+            };
+            self.inc(n);
+            len -= n;
+        }
+    }
 }
 
 impl fmt::Debug for DescriptorList {
@@ -143,31 +195,119 @@ impl fmt::Debug for DescriptorList {
     }
 }
 
+///
+/// A descriptor chain taken from the available ring, read from and written to
+/// through `Read` and `Write` or the slice methods, and placed on the used ring
+/// with the number of bytes written when it is dropped.
+///
+/// A chain which does not follow the layout required by the specification is
+/// handled as follows:
+///
+/// * Zero-length descriptors are skipped, and do not end the chain.
+///
+/// * Readable descriptors which follow a writeable descriptor are ignored.
+///
+/// * The chain ends at a descriptor which is outside of the descriptor table
+///   or whose buffer is not in guest memory, and after as many descriptors as
+///   the queue has entries, so a chain which loops ends as well.
+///
+/// * Once the device has written through `inc_write_offset()` or
+///   `advance_write()` the rest of the readable buffers are skipped and
+///   `remaining_read()` is 0.
+///
+/// The length placed on the used ring is the number of bytes written, and
+/// `remaining_write()` is the size of the writeable buffers less that length.
+///
 pub struct Chain {
     head: Option<u16>,
-    vq: VirtQueue,
+    // Not set for a chain which is not taken from a queue
+    vq: Option<VirtQueue>,
     readable: DescriptorList,
     writeable: DescriptorList,
+    // Copy of the writeable descriptors, only kept while a replay hook is active
+    replay_descriptors: Vec<Descriptor>,
 }
 
 impl Chain {
     pub fn new(memory: GuestRam, vq: VirtQueue, head: u16, ttl: u16) -> Self {
-        let (readable,writeable) = Self::load_descriptors(memory, &vq, head, ttl);
+        let (readable,writeable) = Self::load_descriptors(memory, head, ttl, |idx| vq.load_descriptor(idx));
+        Self::from_descriptors(Some(vq), head, readable, writeable)
+    }
+
+    /// Load the chain at `head` of `vring` without a queue to complete it
+    /// on, to exercise the handling of chain layouts.
+    #[cfg(feature = "fuzzing")]
+    pub(crate) fn detached(memory: GuestRam, vring: &Vring, head: u16) -> Self {
+        let (readable, writeable) = Self::load_descriptors(memory, head, vring.size(), |idx| vring.load_descriptor(idx));
+        Self::from_descriptors(None, head, readable, writeable)
+    }
+
+    /// A chain of a readable buffer of `request.1` bytes at `request.0` and a
+    /// writeable buffer of `reply.1` bytes at `reply.0` in `memory`, which is
+    /// not taken from a queue. A device which serves requests copied out of
+    /// guest memory handles them in a buffer of its own with this.
+    pub(crate) fn staged(memory: GuestRam, request: (u64, usize), reply: (u64, usize)) -> Self {
+        let mut readable = DescriptorList::new(memory.clone());
+        let mut writeable = DescriptorList::new(memory);
+        if request.1 > 0 {
+            readable.add_descriptor(Descriptor::buffer(request.0, request.1 as u32, false));
+        }
+        if reply.1 > 0 {
+            writeable.add_descriptor(Descriptor::buffer(reply.0, reply.1 as u32, true));
+        }
+        Self::from_descriptors(None, 0, readable, writeable)
+    }
+
+    /// A chain of the readable buffers `readable` followed by the writeable
+    /// buffers `writeable`, each given as a guest address and a size, to test
+    /// how devices handle requests split across descriptors.
+    #[cfg(test)]
+    pub(crate) fn split(memory: GuestRam, readable: &[(u64, usize)], writeable: &[(u64, usize)]) -> Self {
+        let mut rlist = DescriptorList::new(memory.clone());
+        let mut wlist = DescriptorList::new(memory);
+        for &(addr, size) in readable {
+            rlist.add_descriptor(Descriptor::buffer(addr, size as u32, false));
+        }
+        for &(addr, size) in writeable {
+            wlist.add_descriptor(Descriptor::buffer(addr, size as u32, true));
+        }
+        rlist.reverse();
+        wlist.reverse();
+        Self::from_descriptors(None, 0, rlist, wlist)
+    }
+
+    fn from_descriptors(vq: Option<VirtQueue>, head: u16, readable: DescriptorList, writeable: DescriptorList) -> Self {
+        let replay_descriptors = if replay::is_active() {
+            writeable.descriptors.iter().rev().cloned().collect()
+        } else {
+            Vec::new()
+        };
         Chain {
             head: Some(head),
             vq,
             readable,
             writeable,
+            replay_descriptors,
         }
     }
 
-    fn load_descriptors(memory: GuestRam, vq: &VirtQueue, head: u16, ttl: u16) -> (DescriptorList, DescriptorList) {
+    fn load_descriptors<F>(memory: GuestRam, head: u16, ttl: u16, mut load: F) -> (DescriptorList, DescriptorList)
+        where F: FnMut(u16) -> Option<Descriptor>
+    {
         let mut readable = DescriptorList::new(memory.clone());
         let mut writeable = DescriptorList::new(memory);
+        let mut seen_writeable = false;
         let mut idx = head;
         let mut ttl = ttl;
 
-        while let Some(d) = vq.load_descriptor(idx) {
+        loop {
+            let d = match load(idx) {
+                Some(d) => d,
+                None => {
+                    warn!("Descriptor chain ends at invalid descriptor {}", idx);
+                    break;
+                }
+            };
             if ttl == 0 {
                 warn!("Descriptor chain length exceeded ttl");
                 break;
@@ -176,11 +316,13 @@ impl Chain {
             }
 
             if d.is_write() {
-                writeable.add_descriptor(d);
-            } else {
-                if !writeable.is_empty() {
-                    warn!("Guest sent readable virtqueue descriptor after writeable descriptor in violation of specification");
+                seen_writeable = true;
+                if d.len > 0 {
+                    writeable.add_descriptor(d);
                 }
+            } else if seen_writeable {
+                warn!("Guest sent readable virtqueue descriptor after writeable descriptor in violation of specification, ignoring it");
+            } else if d.len > 0 {
                 readable.add_descriptor(d);
             }
             if !d.has_next() {
@@ -227,11 +369,40 @@ impl Chain {
         Ok(u64::from_le_bytes(buf))
     }
 
+    // Pass the bytes written to the guest through the replay hook, and write
+    // back the recorded bytes if a log is being replayed.
+    fn replay_written_data(&mut self) {
+        let memory = &self.writeable.memory;
+        let mut remaining = self.writeable.consumed_size;
+        let mut data = Vec::with_capacity(remaining);
+        for d in &self.replay_descriptors {
+            let n = ::std::cmp::min(remaining, d.len as usize);
+            data.extend_from_slice(memory.slice(d.addr, n).unwrap_or(&[]));
+            remaining -= n;
+        }
+
+        let table = self.vq.as_ref().map_or(0, |vq| vq.descriptor_table());
+        replay::chain_data(table, &mut data);
+
+        let mut offset = 0;
+        for d in &self.replay_descriptors {
+            if offset >= data.len() {
+                break;
+            }
+            offset += d.write_to(memory, 0, &data[offset..]);
+        }
+    }
+
     pub fn flush_chain(&mut self) {
         if let Some(head) = self.head.take() {
+            if !self.replay_descriptors.is_empty() {
+                self.replay_written_data();
+            }
             self.readable.clear();
             self.writeable.clear();
-            self.vq.put_used(head, self.writeable.consumed_size as u32);
+            if let Some(ref vq) = self.vq {
+                vq.put_used(head, self.writeable.consumed_size as u32);
+            }
         }
     }
 
@@ -264,9 +435,7 @@ impl Chain {
     }
 
     pub fn inc_write_offset(&mut self, sz: usize) {
-        if !self.readable.is_empty() {
-            self.readable.clear();
-        }
+        self.readable.discard();
         self.writeable.inc(sz);
     }
 
@@ -274,6 +443,32 @@ impl Chain {
         self.writeable.current_mut_slice()
     }
 
+    /// The remaining readable buffers of the chain, up to `max` bytes in total.
+    pub fn read_slices(&self, max: usize) -> Vec<&[u8]> {
+        self.readable.slices(max)
+    }
+
+    /// The remaining writeable buffers of the chain, up to `max` bytes in total.
+    pub fn write_slices(&mut self, max: usize) -> Vec<&mut [u8]> {
+        self.writeable.mut_slices(max)
+    }
+
+    /// Guest address and size of each remaining writeable buffer of the chain.
+    pub fn write_buffers(&self) -> Vec<(u64, usize)> {
+        self.writeable.buffers(self.writeable.remaining())
+    }
+
+    /// Advance past `sz` bytes of the readable buffers returned by `read_slices()`
+    pub fn advance_read(&mut self, sz: usize) {
+        self.readable.advance(sz);
+    }
+
+    /// Advance past `sz` bytes of the writeable buffers returned by `write_slices()`
+    pub fn advance_write(&mut self, sz: usize) {
+        self.readable.discard();
+        self.writeable.advance(sz);
+    }
+
     pub fn copy_from_reader<R>(&mut self, r: R, size: usize) -> io::Result<usize>
     where R: Read+Sized
     {
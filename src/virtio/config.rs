@@ -5,30 +5,50 @@ use super::VirtQueue;
 use super::vring::Vring;
 use super::virtqueue::InterruptLine;
 use super::bus::VirtioDeviceConfig;
+use super::consts::{VIRTIO_PCI_LEGACY_QUEUE_ADDR_SHIFT, VIRTIO_PCI_LEGACY_VRING_ALIGN, VIRTIO_NOTIFY_OFF_MULTIPLIER, VIRTIO_MMIO_QUEUE_NOTIFY};
 use crate::virtio::{Result, Error};
-use crate::kvm::IoEventFd;
+use crate::kvm::{IoEventFd, Kvm};
+use crate::vm::metrics::{self, Labels};
 
 ///
 /// Manages a set of virtqueues during device intitialization.
 ///
+/// The ioeventfd for the notify address of a queue is registered the first
+/// time the queue is started, so that a device which offers many queues
+/// only uses KVM I/O bus entries for those which the driver enables.
+///
 pub struct VirtQueueConfig {
     num_queues: usize,
+    required_queues: usize,
     selected_queue: u16,
     enabled_features: u64,
     vrings: Vec<Vring>,
     interrupt: Arc<InterruptLine>,
-    events: Vec<Arc<IoEventFd>>,
+    kvm: Kvm,
+    notify_base: u64,
+    // Every queue is notified at `notify_base` with its index as the value
+    shared_notify: bool,
+    events: Vec<Option<Arc<IoEventFd>>>,
+    labels: Labels,
 }
 
 impl VirtQueueConfig {
     pub fn new(memory: &GuestRam, dev_config: &VirtioDeviceConfig) -> Result<VirtQueueConfig> {
         Ok(VirtQueueConfig {
             num_queues: dev_config.num_queues(),
+            required_queues: dev_config.required_queues(),
             selected_queue: 0,
             enabled_features: 0,
             vrings: create_vrings(memory,dev_config.queue_sizes()),
             interrupt: InterruptLine::from_config(&dev_config)?,
-            events: create_ioeventfds(&dev_config)?,
+            kvm: dev_config.kvm().clone(),
+            notify_base: match dev_config.mmio_transport() {
+                Some(transport) => transport.base() + VIRTIO_MMIO_QUEUE_NOTIFY as u64,
+                None => dev_config.notify_mmio().base(),
+            },
+            shared_notify: dev_config.mmio_transport().is_some(),
+            events: vec![None; dev_config.num_queues()],
+            labels: dev_config.metric_labels(),
         })
     }
 
@@ -36,6 +56,14 @@ impl VirtQueueConfig {
         self.interrupt.isr_read()
     }
 
+    pub fn isr_status(&self) -> u64 {
+        self.interrupt.isr_status()
+    }
+
+    pub fn isr_ack(&self, bits: u64) {
+        self.interrupt.isr_ack(bits)
+    }
+
     pub fn notify_config(&self) {
         self.interrupt.notify_config();
     }
@@ -47,7 +75,7 @@ impl VirtQueueConfig {
 
     pub fn reset(&mut self) {
         self.selected_queue = 0;
-        let _ = self.interrupt.isr_read();
+        let _ = self.interrupt.isr_clear();
         for vr in &mut self.vrings {
             vr.reset();
         }
@@ -84,44 +112,75 @@ impl VirtQueueConfig {
     }
 
     pub fn vring_get_size(&self) -> u16 { self.with_vring(0, |vr| vr.size() ) }
+    pub fn vring_get_max_size(&self) -> u16 { self.with_vring(0, |vr| vr.default_size() ) }
     pub fn vring_set_size(&mut self, sz: u16) { self.with_vring_mut(|vr| vr.set_size(sz)) }
     pub fn vring_enable(&mut self) { self.with_vring_mut(|vr| vr.enable() ) }
     pub fn vring_is_enabled(&self) -> bool { self.with_vring(false, |vr| vr.is_enabled() ) }
 
+    /// Configure and enable the selected queue from the page frame number
+    /// written by a legacy driver. A value of 0 is ignored.
+    pub fn vring_set_legacy_pfn(&mut self, pfn: u32) {
+        if pfn == 0 {
+            return;
+        }
+        let base = (pfn as u64) << VIRTIO_PCI_LEGACY_QUEUE_ADDR_SHIFT;
+        self.with_vring_mut(|vr| {
+            vr.set_legacy_layout(base, VIRTIO_PCI_LEGACY_VRING_ALIGN);
+            vr.enable();
+        })
+    }
+
+    pub fn vring_get_legacy_pfn(&self) -> u32 {
+        self.with_vring(0, |vr| (vr.descriptors >> VIRTIO_PCI_LEGACY_QUEUE_ADDR_SHIFT) as u32)
+    }
+
     pub fn notify(&self, vq: u16) {
         match self.events.get(vq as usize) {
-            Some(ref ev) => ev.write(1).expect("ioeventfd write failed in notify"),
-            None => (),
+            Some(Some(ref ev)) => ev.write(1).expect("ioeventfd write failed in notify"),
+            _ => (),
+        }
+    }
+
+    fn ioeventfd(&mut self, idx: usize) -> Result<Arc<IoEventFd>> {
+        if let Some(ref ev) = self.events[idx] {
+            return Ok(ev.clone());
         }
+        let ev = if self.shared_notify {
+            IoEventFd::with_datamatch(&self.kvm, self.notify_base, idx as u32)
+        } else {
+            let address = self.notify_base + (VIRTIO_NOTIFY_OFF_MULTIPLIER * idx) as u64;
+            IoEventFd::new(&self.kvm, address)
+        };
+        let ev = Arc::new(ev.map_err(Error::CreateIoEventFd)?);
+        self.events[idx] = Some(ev.clone());
+        Ok(ev)
     }
 
-    fn create_vq(&self, memory: &GuestRam, idx: usize) -> Result<VirtQueue> {
+    fn create_vq(&mut self, memory: &GuestRam, idx: usize) -> Result<VirtQueue> {
         let vring = self.vrings[idx].clone();
         vring.validate()?;
-        Ok(VirtQueue::new(memory.clone(), vring, self.interrupt.clone(), self.events[idx].clone()))
+        let ioeventfd = self.ioeventfd(idx)?;
+        let notifications = metrics::counter("ph_virtqueue_notifications_total",
+                                             "Notifications of a virtqueue by the guest driver",
+                                             self.labels.clone().with("queue", idx));
+        Ok(VirtQueue::new(memory.clone(), idx as u16, vring, self.interrupt.clone(), ioeventfd, notifications, self.labels.clone()))
     }
 
-    pub fn create_queues(&self, memory: &GuestRam) -> Result<Vec<VirtQueue>> {
+    /// Create the queues the driver has enabled. Every required queue must be
+    /// enabled, and the optional queues after them are used up to the first
+    /// one which the driver left disabled.
+    pub fn create_queues(&mut self, memory: &GuestRam) -> Result<Vec<VirtQueue>> {
         let mut v = Vec::with_capacity(self.num_queues);
         for i in 0..self.num_queues {
+            if i >= self.required_queues && !self.vrings[i].is_enabled() {
+                break;
+            }
             v.push(self.create_vq(memory, i)?);
         }
         Ok(v)
     }
 }
 
-fn create_ioeventfds(conf: &VirtioDeviceConfig) -> Result<Vec<Arc<IoEventFd>>> {
-    let mut v = Vec::with_capacity(conf.num_queues());
-    let notify_base = conf.notify_mmio().base();
-
-    for i in 0..conf.num_queues() {
-        let evt = IoEventFd::new(conf.kvm(), notify_base + (4 * i as u64))
-            .map_err(Error::CreateIoEventFd)?;
-        v.push(Arc::new(evt));
-    }
-    Ok(v)
-}
-
 fn create_vrings(memory: &GuestRam, queue_sizes: &[usize]) -> Vec<Vring> {
     let mut v = Vec::with_capacity(queue_sizes.len());
     for &sz in queue_sizes {
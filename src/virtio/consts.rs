@@ -11,6 +11,13 @@ pub const PCI_CONFIG_DATA:    u16 = 0xcfc;
 // Vendor specific PCI capabilities
 
 pub const PCI_CAP_ID_VENDOR: u8 = 0x09;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+// MSI-X capability Message Control bits
+
+pub const PCI_MSIX_FLAGS_QSIZE: u16 = 0x07FF;
+pub const PCI_MSIX_FLAGS_MASKALL: u16 = 0x4000;
+pub const PCI_MSIX_FLAGS_ENABLE: u16 = 0x8000;
 
 pub const PCI_CONFIG_SPACE_SIZE: usize = 256;
 pub const PCI_CAP_BASE_OFFSET: usize = 0x40;
@@ -28,8 +35,11 @@ pub const PCI_CLASS_DEVICE: usize = 0x0a;
 pub const PCI_CACHE_LINE_SIZE: usize = 0x0c;
 pub const PCI_LATENCY_TIMER: usize = 0x0d;
 
-pub const _PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
 pub const PCI_SUBSYSTEM_ID: usize = 0x2e;
+pub const PCI_BASE_ADDRESS_SPACE_IO: u32 = 0x01;
+pub const PCI_BASE_ADDRESS_MEM_TYPE_64: u32 = 0x04;
+pub const PCI_BASE_ADDRESS_MEM_PREFETCH: u32 = 0x08;
 pub const PCI_CAPABILITY_LIST: usize = 0x34;
 pub const PCI_INTERRUPT_LINE: usize = 0x3C;
 pub const PCI_INTERRUPT_PIN: usize = 0x3D;
@@ -40,6 +50,7 @@ pub const VIRTIO_PCI_CAP_COMMON_CFG : u8 = 1;
 pub const VIRTIO_PCI_CAP_NOTIFY_CFG : u8 = 2;
 pub const VIRTIO_PCI_CAP_ISR_CFG    : u8 = 3;
 pub const VIRTIO_PCI_CAP_DEVICE_CFG : u8 = 4;
+pub const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG : u8 = 8;
 
 // Indicates that no MSIX vector is configured
 
@@ -49,9 +60,9 @@ pub const VIRTIO_NO_MSI_VECTOR: u16 = 0xFFFF;
 
 pub const VIRTIO_MMIO_BAR: usize = 0;
 
-// Virtio MMIO area is one page
+// Virtio MMIO area is two pages, the second holds the MSI-X table
 
-pub const VIRTIO_MMIO_AREA_SIZE: usize = 4096;
+pub const VIRTIO_MMIO_AREA_SIZE: usize = 0x2000;
 
 // Offsets and sizes for each structure in MMIO area
 
@@ -59,10 +70,24 @@ pub const VIRTIO_MMIO_OFFSET_COMMON_CFG : usize = 0;     // Common configuration
 pub const VIRTIO_MMIO_OFFSET_ISR        : usize = 56;    // ISR register offset
 pub const VIRTIO_MMIO_OFFSET_NOTIFY     : usize = 0x400; // Notify area offset
 pub const VIRTIO_MMIO_OFFSET_DEV_CFG    : usize = 0x800; // Device specific configuration offset
+pub const VIRTIO_MMIO_OFFSET_MSIX_TABLE : usize = 0x1000; // MSI-X table offset
+pub const VIRTIO_MMIO_OFFSET_MSIX_PBA   : usize = 0x1800; // MSI-X pending bit array offset
 
 pub const VIRTIO_MMIO_COMMON_CFG_SIZE: usize = 56;       // Common configuration size
 pub const VIRTIO_MMIO_NOTIFY_SIZE    : usize = 0x400;    // Notify area size
 pub const VIRTIO_MMIO_ISR_SIZE       : usize = 4;        // ISR register size
+pub const VIRTIO_MMIO_MSIX_TABLE_SIZE: usize = 0x800;    // MSI-X table size
+pub const VIRTIO_MMIO_MSIX_PBA_SIZE  : usize = 16;       // MSI-X pending bit array size
+
+// Largest MSI-X table which fits in the MSI-X area, devices with more
+// queues than this have queues share vectors
+
+pub const VIRTIO_MSIX_MAX_VECTORS: usize = VIRTIO_MMIO_MSIX_TABLE_SIZE / 16;
+
+// Each queue is notified at its own offset in the notify area
+
+pub const VIRTIO_NOTIFY_OFF_MULTIPLIER: usize = 4;
+pub const VIRTIO_MAX_QUEUES: usize = VIRTIO_MMIO_NOTIFY_SIZE / VIRTIO_NOTIFY_OFF_MULTIPLIER;
 
 // Common configuration header offsets
 
@@ -86,6 +111,78 @@ pub const VIRTIO_PCI_COMMON_Q_AVAILHI     : usize = 44;
 pub const VIRTIO_PCI_COMMON_Q_USEDLO      : usize = 48;
 pub const VIRTIO_PCI_COMMON_Q_USEDHI      : usize = 52;
 
+// Register offsets in the legacy (virtio 0.9.5) I/O port BAR
+
+pub const VIRTIO_PCI_LEGACY_HOST_FEATURES  : usize = 0;
+pub const VIRTIO_PCI_LEGACY_GUEST_FEATURES : usize = 4;
+pub const VIRTIO_PCI_LEGACY_QUEUE_PFN      : usize = 8;
+pub const VIRTIO_PCI_LEGACY_QUEUE_NUM      : usize = 12;
+pub const VIRTIO_PCI_LEGACY_QUEUE_SEL      : usize = 14;
+pub const VIRTIO_PCI_LEGACY_QUEUE_NOTIFY   : usize = 16;
+pub const VIRTIO_PCI_LEGACY_STATUS         : usize = 18;
+pub const VIRTIO_PCI_LEGACY_ISR            : usize = 19;
+pub const VIRTIO_PCI_LEGACY_CONFIG         : usize = 20;
+
+// While the driver has MSI-X enabled the vector registers take the place of
+// the start of the device configuration, which moves up by 4 bytes
+pub const VIRTIO_PCI_LEGACY_MSIX_CONFIG    : usize = 20;
+pub const VIRTIO_PCI_LEGACY_MSIX_QUEUE     : usize = 22;
+pub const VIRTIO_PCI_LEGACY_CONFIG_MSIX    : usize = 24;
+
+// Register offsets of the virtio-mmio transport (version 2), which is used
+// instead of PCI when devices are described on the kernel command line
+
+pub const VIRTIO_MMIO_MAGIC_VALUE          : usize = 0x000;
+pub const VIRTIO_MMIO_VERSION              : usize = 0x004;
+pub const VIRTIO_MMIO_DEVICE_ID            : usize = 0x008;
+pub const VIRTIO_MMIO_VENDOR_ID            : usize = 0x00c;
+pub const VIRTIO_MMIO_DEVICE_FEATURES      : usize = 0x010;
+pub const VIRTIO_MMIO_DEVICE_FEATURES_SEL  : usize = 0x014;
+pub const VIRTIO_MMIO_DRIVER_FEATURES      : usize = 0x020;
+pub const VIRTIO_MMIO_DRIVER_FEATURES_SEL  : usize = 0x024;
+pub const VIRTIO_MMIO_QUEUE_SEL            : usize = 0x030;
+pub const VIRTIO_MMIO_QUEUE_NUM_MAX        : usize = 0x034;
+pub const VIRTIO_MMIO_QUEUE_NUM            : usize = 0x038;
+pub const VIRTIO_MMIO_QUEUE_READY          : usize = 0x044;
+pub const VIRTIO_MMIO_QUEUE_NOTIFY         : usize = 0x050;
+pub const VIRTIO_MMIO_INTERRUPT_STATUS     : usize = 0x060;
+pub const VIRTIO_MMIO_INTERRUPT_ACK        : usize = 0x064;
+pub const VIRTIO_MMIO_STATUS               : usize = 0x070;
+pub const VIRTIO_MMIO_QUEUE_DESC_LOW       : usize = 0x080;
+pub const VIRTIO_MMIO_QUEUE_DESC_HIGH      : usize = 0x084;
+pub const VIRTIO_MMIO_QUEUE_AVAIL_LOW      : usize = 0x090;
+pub const VIRTIO_MMIO_QUEUE_AVAIL_HIGH     : usize = 0x094;
+pub const VIRTIO_MMIO_QUEUE_USED_LOW       : usize = 0x0a0;
+pub const VIRTIO_MMIO_QUEUE_USED_HIGH      : usize = 0x0a4;
+pub const VIRTIO_MMIO_SHM_LEN_LOW          : usize = 0x0b0;
+pub const VIRTIO_MMIO_SHM_LEN_HIGH         : usize = 0x0b4;
+pub const VIRTIO_MMIO_CONFIG_GENERATION    : usize = 0x0fc;
+pub const VIRTIO_MMIO_CONFIG               : usize = 0x100;
+
+// "virt" read from the magic value register
+pub const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976;
+pub const VIRTIO_MMIO_TRANSPORT_VERSION: u32 = 2;
+
+// Legacy queue address is a page frame number and the used ring is page aligned
+
+pub const VIRTIO_PCI_LEGACY_QUEUE_ADDR_SHIFT: u32 = 12;
+pub const VIRTIO_PCI_LEGACY_VRING_ALIGN: u64 = 4096;
+
+// When the legacy interface is enabled it occupies BAR 0 and the
+// virtio MMIO area is moved to BAR 1
+
+pub const VIRTIO_LEGACY_IO_BAR: usize = 0;
+pub const VIRTIO_LEGACY_MMIO_BAR: usize = 1;
+
+// Shared memory regions are 64 bit BARs which each take a pair of BAR
+// registers after the ones used for the MMIO area and legacy I/O
+
+pub const VIRTIO_SHM_BARS: &[usize] = &[2, 4];
+
+// First I/O port allocated to legacy I/O BARs
+
+pub const PCI_IO_BASE: u16 = 0xc000;
+
 // Common configuration status bits
 
 pub const _VIRTIO_CONFIG_S_ACKNOWLEDGE : u8 = 1;
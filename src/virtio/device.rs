@@ -6,7 +6,11 @@ use super::bus::VirtioDeviceConfig;
 use super::VirtQueue;
 use super::config::VirtQueueConfig;
 use super::consts::*;
-use crate::vm::io::MmioOps;
+use super::audit::{ConfigSpace, DeviceAudit};
+use super::msix::MsixVectors;
+use super::quirks::Quirk;
+use super::DeviceConfigArea;
+use crate::vm::io::{MmioOps, IoPortOps};
 use crate::virtio::Result;
 
 pub trait VirtioDeviceOps: Send+Sync {
@@ -19,17 +23,31 @@ pub trait VirtioDeviceOps: Send+Sync {
 
 pub struct VirtioDevice {
     memory: MemoryManager,
+    device_type: u16,
     vq_config: VirtQueueConfig,
     common_cfg_mmio: AddressRange,
     isr_mmio: AddressRange,
     notify_mmio: AddressRange,
     device_cfg_mmio: Option<AddressRange>,
+    config_size: usize,
     device_ops: Arc<RwLock<dyn VirtioDeviceOps>>,
     dfselect: u32,
     gfselect: u32,
     device_features: u64,
     guest_features: u64,
     status: u8,
+    legacy_port: Option<u16>,
+    // Set when the driver is using the legacy I/O port interface
+    legacy_active: bool,
+    audit: Arc<DeviceAudit>,
+    msix: Option<Arc<MsixVectors>>,
+    msix_table_mmio: AddressRange,
+    msix_pba_mmio: AddressRange,
+    // Registers of the virtio-mmio transport when the device is not on the PCI bus
+    mmio_transport: Option<AddressRange>,
+    // Every queue passed to the device, closed when it is detached so that
+    // the threads serving them exit
+    started_queues: Vec<VirtQueue>,
 }
 
 const MASK_LOW_32: u64 = (1u64 << 32) - 1;
@@ -44,13 +62,16 @@ fn get_hi32(val: u64) -> u32 { (val >> 32) as u32 }
 
 impl VirtioDevice {
     pub fn new(memory: MemoryManager, config: &VirtioDeviceConfig) -> Result<Arc<RwLock<VirtioDevice>>> {
+        config.audit().set_device_features(config.feature_bits());
         Ok(Arc::new(RwLock::new(VirtioDevice {
             memory: memory.clone(),
+            device_type: config.device_type(),
             vq_config: VirtQueueConfig::new(memory.guest_ram(),&config)?,
             common_cfg_mmio: config.common_cfg_mmio(),
             isr_mmio: config.isr_mmio(),
             notify_mmio: config.notify_mmio(),
             device_cfg_mmio: config.device_cfg_mmio(),
+            config_size: config.config_size(),
 
             device_ops: config.ops(),
             dfselect: 0,
@@ -59,6 +80,14 @@ impl VirtioDevice {
             device_features: config.feature_bits(),
             guest_features: 0,
             status: 0,
+            legacy_port: config.legacy_io().map(|(port,_)| port),
+            legacy_active: false,
+            audit: config.audit(),
+            msix: config.msix(),
+            msix_table_mmio: config.msix_table_mmio(),
+            msix_pba_mmio: config.msix_pba_mmio(),
+            mmio_transport: config.mmio_transport(),
+            started_queues: Vec::new(),
         })))
     }
 
@@ -67,10 +96,31 @@ impl VirtioDevice {
         self.gfselect = 0;
         self.guest_features = 0;
         self.status = 0;
+        self.legacy_active = false;
         self.vq_config.reset();
+        if let Some(ref msix) = self.msix {
+            msix.reset_vectors();
+        }
+        self.audit.record_reset();
+    }
+
+    /// Reset the device and its backend after it was removed from the bus,
+    /// and close its queues so that the threads serving them exit and drop
+    /// what they hold of the backend.
+    pub fn detach(&mut self) {
+        for vq in self.started_queues.drain(..) {
+            vq.set_closed();
+        }
+        self.reset();
+        self.with_ops(|ops| ops.reset());
     }
 
     fn status_write(&mut self, val: u8) {
+        self.update_status(val);
+        self.audit.record_status(self.status, self.guest_features);
+    }
+
+    fn update_status(&mut self, mut val: u8) {
 
         // 4.1.4.3.1 The device MUST reset when 0 is written to device status
         if val == 0 {
@@ -79,16 +129,29 @@ impl VirtioDevice {
         }
         // 2.1.1 The driver MUST NOT clear a device status bit
         if self.status & !val != 0 {
-            return;
+            self.apply_quirk(Quirk::StatusBitsCleared);
+            val |= self.status;
         }
 
         let new_bits = val & !self.status;
 
+        // Legacy drivers do not set FEATURES_OK, the features are final when DRIVER_OK is set
+        if new_bits & VIRTIO_CONFIG_S_DRIVER_OK != 0 && self.legacy_active {
+            self.with_ops(|ops| ops.enable_features(self.guest_features));
+        } else if new_bits & VIRTIO_CONFIG_S_DRIVER_OK != 0 && val & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+            self.apply_quirk(Quirk::DriverOkWithoutFeaturesOk);
+            self.with_ops(|ops| ops.enable_features(self.guest_features));
+        }
+
         if new_bits & VIRTIO_CONFIG_S_DRIVER_OK != 0 {
             match self.vq_config.create_queues(self.memory.guest_ram()) {
-                Ok(queues) => self.with_ops(|ops| ops.start(&self.memory, queues)),
+                Ok(queues) => {
+                    self.started_queues.extend(queues.iter().cloned());
+                    self.with_ops(|ops| ops.start(&self.memory, queues));
+                }
                 Err(e) => {
                     println!("creating virtqueues failed {}", e);
+                    self.audit.report_failure(&format!("creating virtqueues failed: {}", e));
                     self.status |= VIRTIO_CONFIG_S_NEEDS_RESET;
                     self.vq_config.notify_config();
                     return;
@@ -97,6 +160,12 @@ impl VirtioDevice {
         }
 
         if new_bits & VIRTIO_CONFIG_S_FEATURES_OK != 0 {
+            // 7.1 A device MAY fail to operate further if VIRTIO_F_VERSION_1 is not accepted
+            if self.guest_features & VIRTIO_F_VERSION_1 == 0 {
+                self.reject_legacy_driver();
+                self.audit.report_failure("VIRTIO_F_VERSION_1 not accepted");
+                return;
+            }
             if !self.with_ops(|ops| ops.enable_features(self.guest_features)) {
                 self.vq_config.enable_features(self.guest_features);
                return;
@@ -106,22 +175,19 @@ impl VirtioDevice {
         self.status |= new_bits;
     }
 
-    fn common_config_write(&mut self, offset: usize, _size: usize, val: u32) {
+    fn common_config_write(&mut self, offset: usize, size: usize, val: u32) {
+        self.audit.record(ConfigSpace::Common, true, offset, size, val as u64);
         match offset {
             VIRTIO_PCI_COMMON_DFSELECT => self.dfselect = val,
             VIRTIO_PCI_COMMON_GFSELECT => self.gfselect = val,
-            VIRTIO_PCI_COMMON_GF => {
-                match self.gfselect {
-                    0 => set_lo32(&mut self.guest_features, val),
-                    1 => set_hi32(&mut self.guest_features, val),
-                    _ => {},
-                }
-                // 2.2.1
-                //   The driver MUST NOT accept a feature which the device did
-                //   not offer.
-                self.guest_features &= self.device_features;
-            },
+            VIRTIO_PCI_COMMON_GF => self.guest_features_write(val),
             VIRTIO_PCI_COMMON_STATUS => self.status_write(val as u8),
+            VIRTIO_PCI_COMMON_MSIX => if let Some(ref msix) = self.msix {
+                msix.set_config_vector(val as u16)
+            },
+            VIRTIO_PCI_COMMON_Q_MSIX => if let Some(ref msix) = self.msix {
+                msix.set_queue_vector(self.vq_config.selected_queue(), val as u16)
+            },
             VIRTIO_PCI_COMMON_Q_SELECT=> self.vq_config.select_queue(val as u16),
             VIRTIO_PCI_COMMON_Q_SIZE => self.vq_config.vring_set_size(val as u16),
             VIRTIO_PCI_COMMON_Q_ENABLE=> if val == 1 { self.vq_config.vring_enable() } ,
@@ -135,7 +201,29 @@ impl VirtioDevice {
         }
     }
 
-    fn common_config_read(&mut self, offset: usize, _size: usize) -> u32 {
+    fn guest_features_write(&mut self, val: u32) {
+        if self.status & VIRTIO_CONFIG_S_FEATURES_OK != 0 {
+            self.apply_quirk(Quirk::FeaturesAfterFeaturesOk);
+            return;
+        }
+        match self.gfselect {
+            0 => set_lo32(&mut self.guest_features, val),
+            1 => set_hi32(&mut self.guest_features, val),
+            _ => {},
+        }
+        // 2.2.1
+        //   The driver MUST NOT accept a feature which the device did
+        //   not offer.
+        self.guest_features &= self.device_features;
+    }
+
+    fn common_config_read(&mut self, offset: usize, size: usize) -> u32 {
+        let val = self.common_config_value(offset);
+        self.audit.record(ConfigSpace::Common, false, offset, size, val as u64);
+        val
+    }
+
+    fn common_config_value(&self, offset: usize) -> u32 {
         match offset {
             VIRTIO_PCI_COMMON_DFSELECT => self.dfselect,
             VIRTIO_PCI_COMMON_DF=> match self.dfselect {
@@ -149,13 +237,17 @@ impl VirtioDevice {
                 1 => get_hi32(self.guest_features),
                 _ => 0,
             },
-            VIRTIO_PCI_COMMON_MSIX => VIRTIO_NO_MSI_VECTOR as u32,
+            VIRTIO_PCI_COMMON_MSIX => self.msix.as_ref()
+                .map(|msix| msix.config_vector())
+                .unwrap_or(VIRTIO_NO_MSI_VECTOR) as u32,
             VIRTIO_PCI_COMMON_NUMQ => self.vq_config.num_queues() as u32,
             VIRTIO_PCI_COMMON_STATUS => self.status as u32,
             VIRTIO_PCI_COMMON_CFGGENERATION => 0,
             VIRTIO_PCI_COMMON_Q_SELECT => self.vq_config.selected_queue() as u32,
             VIRTIO_PCI_COMMON_Q_SIZE => self.vq_config.vring_get_size() as u32,
-            VIRTIO_PCI_COMMON_Q_MSIX => VIRTIO_NO_MSI_VECTOR as u32,
+            VIRTIO_PCI_COMMON_Q_MSIX => self.msix.as_ref()
+                .map(|msix| msix.queue_vector(self.vq_config.selected_queue()))
+                .unwrap_or(VIRTIO_NO_MSI_VECTOR) as u32,
             VIRTIO_PCI_COMMON_Q_ENABLE => if self.vq_config.vring_is_enabled() {1} else {0},
             VIRTIO_PCI_COMMON_Q_NOFF => self.vq_config.selected_queue() as u32,
             VIRTIO_PCI_COMMON_Q_DESCLO => self.vq_config.with_vring(0, |vr| get_lo32(vr.descriptors)),
@@ -168,12 +260,184 @@ impl VirtioDevice {
         }
     }
 
+    fn reject_legacy_driver(&self) {
+        if self.legacy_port.is_some() {
+            warn!("Driver for virtio device (type {}) did not accept VIRTIO_F_VERSION_1 on modern interface", self.device_type);
+        } else {
+            warn!("Legacy driver for virtio device (type {}) is not supported. Configure the device with a legacy PCI identity (--pci-identity <device>=legacy)", self.device_type);
+        }
+    }
+
+    // Offset of the device configuration in the legacy I/O BAR, which
+    // follows the MSI-X vector registers while the driver has MSI-X enabled
+    fn legacy_config_base(&self) -> usize {
+        match self.msix {
+            Some(ref msix) if msix.is_enabled() => VIRTIO_PCI_LEGACY_CONFIG_MSIX,
+            _ => VIRTIO_PCI_LEGACY_CONFIG,
+        }
+    }
+
+    fn legacy_read(&mut self, offset: usize, size: usize) -> u32 {
+        self.legacy_active = true;
+        let val = self.legacy_value(offset, size);
+        let config_base = self.legacy_config_base();
+        match offset {
+            VIRTIO_PCI_LEGACY_ISR => {},
+            n if n >= config_base => self.audit.record(ConfigSpace::Device, false, n - config_base, size, val as u64),
+            n => self.audit.record(ConfigSpace::Legacy, false, n, size, val as u64),
+        }
+        val
+    }
+
+    fn legacy_value(&mut self, offset: usize, size: usize) -> u32 {
+        let config_base = self.legacy_config_base();
+        match offset {
+            VIRTIO_PCI_LEGACY_HOST_FEATURES => get_lo32(self.device_features),
+            VIRTIO_PCI_LEGACY_GUEST_FEATURES => get_lo32(self.guest_features),
+            VIRTIO_PCI_LEGACY_QUEUE_PFN => self.vq_config.vring_get_legacy_pfn(),
+            VIRTIO_PCI_LEGACY_QUEUE_NUM => self.vq_config.vring_get_size() as u32,
+            VIRTIO_PCI_LEGACY_QUEUE_SEL => self.vq_config.selected_queue() as u32,
+            VIRTIO_PCI_LEGACY_STATUS => self.status as u32,
+            VIRTIO_PCI_LEGACY_ISR => self.isr_read() as u32,
+            n if n >= config_base => {
+                let offset = n - config_base;
+                if !self.is_valid_config_access(offset, size) {
+                    return 0;
+                }
+                self.with_ops(|ops| ops.read_config(offset, size)) as u32
+            },
+            VIRTIO_PCI_LEGACY_MSIX_CONFIG => self.common_config_value(VIRTIO_PCI_COMMON_MSIX),
+            VIRTIO_PCI_LEGACY_MSIX_QUEUE => self.common_config_value(VIRTIO_PCI_COMMON_Q_MSIX),
+            _ => 0,
+        }
+    }
+
+    fn legacy_write(&mut self, offset: usize, size: usize, val: u32) {
+        self.legacy_active = true;
+        let config_base = self.legacy_config_base();
+        match offset {
+            VIRTIO_PCI_LEGACY_QUEUE_NOTIFY => {},
+            n if n >= config_base => self.audit.record(ConfigSpace::Device, true, n - config_base, size, val as u64),
+            n => self.audit.record(ConfigSpace::Legacy, true, n, size, val as u64),
+        }
+        match offset {
+            VIRTIO_PCI_LEGACY_GUEST_FEATURES => {
+                set_lo32(&mut self.guest_features, val);
+                self.guest_features &= self.device_features;
+            },
+            VIRTIO_PCI_LEGACY_QUEUE_PFN => self.vq_config.vring_set_legacy_pfn(val),
+            VIRTIO_PCI_LEGACY_QUEUE_SEL => self.vq_config.select_queue(val as u16),
+            VIRTIO_PCI_LEGACY_QUEUE_NOTIFY => self.vq_config.notify(val as u16),
+            VIRTIO_PCI_LEGACY_STATUS => self.status_write(val as u8),
+            n if n >= config_base => {
+                let offset = n - config_base;
+                if self.is_valid_config_access(offset, size) {
+                    self.with_ops(|ops| ops.write_config(offset, size, val as u64))
+                }
+            },
+            VIRTIO_PCI_LEGACY_MSIX_CONFIG => if let Some(ref msix) = self.msix {
+                msix.set_config_vector(val as u16)
+            },
+            VIRTIO_PCI_LEGACY_MSIX_QUEUE => if let Some(ref msix) = self.msix {
+                msix.set_queue_vector(self.vq_config.selected_queue(), val as u16)
+            },
+            _ => {},
+        }
+    }
+
+    fn transport_read(&mut self, offset: usize, size: usize) -> u64 {
+        if offset >= VIRTIO_MMIO_CONFIG {
+            return self.device_config_read(offset - VIRTIO_MMIO_CONFIG, size);
+        }
+        // 4.2.2.2 The driver MUST only use 32 bit wide and aligned reads and
+        // writes to access the control registers
+        if size != 4 {
+            return 0;
+        }
+        let val = self.transport_value(offset);
+        if offset != VIRTIO_MMIO_INTERRUPT_STATUS {
+            self.audit.record(ConfigSpace::Mmio, false, offset, size, val as u64);
+        }
+        val as u64
+    }
+
+    fn transport_value(&mut self, offset: usize) -> u32 {
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            VIRTIO_MMIO_VERSION => VIRTIO_MMIO_TRANSPORT_VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.device_type as u32,
+            VIRTIO_MMIO_VENDOR_ID => PCI_VENDOR_ID_REDHAT as u32,
+            VIRTIO_MMIO_DEVICE_FEATURES => match self.dfselect {
+                0 => get_lo32(self.device_features),
+                1 => get_hi32(self.device_features),
+                _ => 0,
+            },
+            VIRTIO_MMIO_QUEUE_NUM_MAX => self.vq_config.vring_get_max_size() as u32,
+            VIRTIO_MMIO_QUEUE_READY => if self.vq_config.vring_is_enabled() {1} else {0},
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.vq_config.isr_status() as u32,
+            VIRTIO_MMIO_STATUS => self.status as u32,
+            // A length of all ones tells the driver there is no shared memory region
+            VIRTIO_MMIO_SHM_LEN_LOW | VIRTIO_MMIO_SHM_LEN_HIGH => 0xFFFF_FFFF,
+            VIRTIO_MMIO_CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn transport_write(&mut self, offset: usize, size: usize, val: u32) {
+        if offset >= VIRTIO_MMIO_CONFIG {
+            self.device_config_write(offset - VIRTIO_MMIO_CONFIG, size, val as u64);
+            return;
+        }
+        if size != 4 {
+            return;
+        }
+        match offset {
+            VIRTIO_MMIO_QUEUE_NOTIFY | VIRTIO_MMIO_INTERRUPT_ACK => {},
+            n => self.audit.record(ConfigSpace::Mmio, true, n, size, val as u64),
+        }
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.dfselect = val,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.gfselect = val,
+            VIRTIO_MMIO_DRIVER_FEATURES => self.guest_features_write(val),
+            VIRTIO_MMIO_QUEUE_SEL => self.vq_config.select_queue(val as u16),
+            VIRTIO_MMIO_QUEUE_NUM => self.vq_config.vring_set_size(val as u16),
+            VIRTIO_MMIO_QUEUE_READY => if val == 1 { self.vq_config.vring_enable() },
+            VIRTIO_MMIO_QUEUE_NOTIFY => self.vq_config.notify(val as u16),
+            VIRTIO_MMIO_INTERRUPT_ACK => self.vq_config.isr_ack(val as u64),
+            VIRTIO_MMIO_STATUS => self.status_write(val as u8),
+            VIRTIO_MMIO_QUEUE_DESC_LOW => self.vq_config.with_vring_mut(|vr| set_lo32(&mut vr.descriptors, val)),
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => self.vq_config.with_vring_mut(|vr| set_hi32(&mut vr.descriptors, val)),
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => self.vq_config.with_vring_mut(|vr| set_lo32(&mut vr.avail_ring, val)),
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => self.vq_config.with_vring_mut(|vr| set_hi32(&mut vr.avail_ring, val)),
+            VIRTIO_MMIO_QUEUE_USED_LOW => self.vq_config.with_vring_mut(|vr| set_lo32(&mut vr.used_ring, val)),
+            VIRTIO_MMIO_QUEUE_USED_HIGH => self.vq_config.with_vring_mut(|vr| set_hi32(&mut vr.used_ring, val)),
+            _ => {},
+        }
+    }
+
+    fn device_config_read(&mut self, offset: usize, size: usize) -> u64 {
+        let val = if self.is_valid_config_access(offset, size) {
+            self.with_ops(|ops| ops.read_config(offset, size))
+        } else {
+            0
+        };
+        self.audit.record(ConfigSpace::Device, false, offset, size, val);
+        val
+    }
+
+    fn device_config_write(&mut self, offset: usize, size: usize, val: u64) {
+        self.audit.record(ConfigSpace::Device, true, offset, size, val);
+        if self.is_valid_config_access(offset, size) {
+            self.with_ops(|ops| ops.write_config(offset, size, val))
+        }
+    }
+
     fn notify_read(&mut self, _offset: usize, _size: usize) -> u64 {
         0
     }
 
     fn notify_write(&mut self, offset: usize, _size: usize, _val: u64) {
-        let vq = (offset / 4) as u16;
+        let vq = (offset / VIRTIO_NOTIFY_OFF_MULTIPLIER) as u16;
         self.vq_config.notify(vq);
     }
 
@@ -181,6 +445,22 @@ impl VirtioDevice {
         self.vq_config.isr_read()
     }
 
+    // Accesses to the device configuration which are misaligned, of an odd
+    // size or past its end never reach the device
+    fn is_valid_config_access(&self, offset: usize, size: usize) -> bool {
+        if DeviceConfigArea::is_valid_access(self.config_size, offset, size) {
+            return true;
+        }
+        self.apply_quirk(Quirk::InvalidConfigAccess);
+        false
+    }
+
+    fn apply_quirk(&self, quirk: Quirk) {
+        if self.audit.record_quirk(quirk) {
+            notify!("{}: working around driver quirk {}: {}", self.audit.name(), quirk.name(), quirk.workaround());
+        }
+    }
+
     fn with_ops<U,F>(&self, f: F) -> U
       where F: FnOnce(&mut dyn VirtioDeviceOps) -> U {
         let mut ops = self.device_ops.write().unwrap();
@@ -190,7 +470,11 @@ impl VirtioDevice {
 
 impl MmioOps for VirtioDevice {
     fn mmio_read(&mut self, address: u64, size: usize) -> u64 {
-        if self.common_cfg_mmio.contains(address, size) {
+        if let Some(transport) = self.mmio_transport.filter(|r| r.contains(address, size)) {
+            let offset = transport.offset_of(address);
+            self.transport_read(offset, size)
+
+        } else if self.common_cfg_mmio.contains(address, size) {
             let offset = self.common_cfg_mmio.offset_of(address);
             self.common_config_read(offset,size) as u64
 
@@ -201,9 +485,17 @@ impl MmioOps for VirtioDevice {
         } else if self.isr_mmio.contains(address, size) {
             self.isr_read()
 
-        } else if let Some(ref dev_cfg_mmio) = self.device_cfg_mmio {
+        } else if self.msix_table_mmio.contains(address, size) {
+            let offset = self.msix_table_mmio.offset_of(address);
+            self.msix.as_ref().map(|msix| msix.table_read(offset, size)).unwrap_or(0)
+
+        } else if self.msix_pba_mmio.contains(address, size) {
+            let offset = self.msix_pba_mmio.offset_of(address);
+            self.msix.as_ref().map(|msix| msix.pba_read(offset, size)).unwrap_or(0)
+
+        } else if let Some(dev_cfg_mmio) = self.device_cfg_mmio.filter(|r| r.contains(address, size)) {
             let offset = dev_cfg_mmio.offset_of(address);
-            self.with_ops(|ops| ops.read_config(offset, size))
+            self.device_config_read(offset, size)
 
         } else {
             0
@@ -211,7 +503,11 @@ impl MmioOps for VirtioDevice {
     }
 
     fn mmio_write(&mut self, address: u64, size: usize, val: u64) {
-        if self.common_cfg_mmio.contains(address, size) {
+        if let Some(transport) = self.mmio_transport.filter(|r| r.contains(address, size)) {
+            let offset = transport.offset_of(address);
+            self.transport_write(offset, size, val as u32)
+
+        } else if self.common_cfg_mmio.contains(address, size) {
             let offset = self.common_cfg_mmio.offset_of(address);
             self.common_config_write(offset,size, val as u32)
 
@@ -219,10 +515,31 @@ impl MmioOps for VirtioDevice {
             let offset = self.notify_mmio.offset_of(address);
             self.notify_write(offset, size, val)
 
-        } else if let Some(ref dev_cfg_mmio) = self.device_cfg_mmio {
+        } else if self.msix_table_mmio.contains(address, size) {
+            let offset = self.msix_table_mmio.offset_of(address);
+            if let Some(ref msix) = self.msix {
+                msix.table_write(offset, size, val);
+            }
+
+        } else if let Some(dev_cfg_mmio) = self.device_cfg_mmio.filter(|r| r.contains(address, size)) {
             let offset = dev_cfg_mmio.offset_of(address);
-            self.with_ops(|ops| ops.write_config(offset, size, val))
+            self.device_config_write(offset, size, val)
         }
     }
 }
 
+
+impl IoPortOps for VirtioDevice {
+    fn io_in(&mut self, port: u16, size: usize) -> u32 {
+        match self.legacy_port {
+            Some(base) => self.legacy_read((port - base) as usize, size),
+            None => 0,
+        }
+    }
+
+    fn io_out(&mut self, port: u16, size: usize, val: u32) {
+        if let Some(base) = self.legacy_port {
+            self.legacy_write((port - base) as usize, size, val)
+        }
+    }
+}
@@ -1,10 +1,17 @@
-
 use byteorder::{ByteOrder,LittleEndian};
-use std::ops::Range;
 
+///
+/// The device-specific configuration space of a virtio device.
+///
+/// Every access from the driver is checked here. An access must be 1, 2, 4 or
+/// 8 bytes wide, naturally aligned and inside the configuration space, and
+/// is otherwise ignored, reads returning 0. The driver may only write bytes
+/// which the device has declared writeable with `set_writeable()`, and a
+/// write which touches any other byte changes nothing.
+///
 pub struct DeviceConfigArea {
     buffer: Vec<u8>,
-    write_filter: DeviceConfigWriteFilter,
+    writeable: Vec<bool>,
 }
 
 
@@ -13,37 +20,66 @@ impl DeviceConfigArea {
     pub fn new(size: usize) -> Self {
         DeviceConfigArea{
             buffer: vec![0u8; size],
-            write_filter: DeviceConfigWriteFilter::new(size),
+            writeable: vec![false; size],
+        }
+    }
+
+    /// Returns `true` if an access of `size` bytes at `offset` is well formed
+    /// and inside a configuration space of `config_size` bytes.
+    pub fn is_valid_access(config_size: usize, offset: usize, size: usize) -> bool {
+        let end = match offset.checked_add(size) {
+            Some(end) => end,
+            None => return false,
+        };
+        match size {
+            1 | 2 | 4 | 8 => offset % size == 0 && end <= config_size,
+            _ => false,
         }
     }
 
     pub fn read_config(&self, offset: usize, size: usize) -> u64 {
-        if offset + size > self.buffer.len() {
+        if !Self::is_valid_access(self.buffer.len(), offset, size) {
             return 0;
         }
         match size {
             1 => self.buffer[offset] as u64,
             2 => LittleEndian::read_u16(&self.buffer[offset..]) as u64,
             4 => LittleEndian::read_u32(&self.buffer[offset..]) as u64,
-            8 => LittleEndian::read_u64(&self.buffer[offset..]),
-            _ => 0,
+            _ => LittleEndian::read_u64(&self.buffer[offset..]),
         }
     }
 
-    pub fn write_config(&mut self, offset: usize, size: usize, val: u64) {
-        if self.write_filter.is_writeable(offset, size) {
-            match size {
-                1 => self.write_u8(offset, val as u8),
-                2 => self.write_u16(offset, val as u16),
-                4 => self.write_u32(offset, val as u32),
-                8 => self.write_u64(offset, val as u64),
-                _ => {},
-            }
+    /// Apply a write from the driver. Returns `false` if the write was
+    /// ignored because it is malformed or touches a byte which is not
+    /// writeable.
+    pub fn write_config(&mut self, offset: usize, size: usize, val: u64) -> bool {
+        if !self.is_writeable(offset, size) {
+            return false;
         }
+        match size {
+            1 => self.write_u8(offset, val as u8),
+            2 => self.write_u16(offset, val as u16),
+            4 => self.write_u32(offset, val as u32),
+            _ => self.write_u64(offset, val),
+        }
+        true
     }
 
+    fn is_writeable(&self, offset: usize, size: usize) -> bool {
+        Self::is_valid_access(self.buffer.len(), offset, size) &&
+            self.writeable[offset..offset + size].iter().all(|&w| w)
+    }
+
+    /// Let the driver write the `size` bytes at `offset`.
     pub fn set_writeable(&mut self, offset: usize, size: usize) {
-        self.write_filter.set_writable(offset, size)
+        assert!(offset + size <= self.buffer.len());
+        for w in &mut self.writeable[offset..offset + size] {
+            *w = true;
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.buffer.len()
     }
 
     pub fn write_u8(&mut self, offset: usize, val: u8) {
@@ -70,29 +106,12 @@ impl DeviceConfigArea {
         assert!(offset + bytes.len() <= self.buffer.len());
         self.buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
     }
-}
-
-struct DeviceConfigWriteFilter {
-    size: usize,
-    ranges: Vec<Range<usize>>,
-}
-
-impl DeviceConfigWriteFilter {
-    fn new(size: usize) -> Self {
-        DeviceConfigWriteFilter { size, ranges: Vec::new() }
-    }
 
-    fn set_writable(&mut self, offset: usize, size: usize) {
-        let end = offset + size;
-        self.ranges.push(offset..end);
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        self.buffer[offset]
     }
 
-    fn is_writeable(&self, offset: usize, size: usize) -> bool {
-        if offset + size > self.size {
-            false
-        } else {
-            let last = offset + size - 1;
-            self.ranges.iter().any(|r| r.contains(&offset) && r.contains(&last))
-        }
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        LittleEndian::read_u32(&self.buffer[offset..])
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,177 @@
+use std::io::{Read, Write};
+
+use crate::memory::{GuestRam, MemoryRegion};
+use crate::virtio::Chain;
+use crate::virtio::vring::Vring;
+
+const RAM_SIZE: usize = 0x4000;
+const QUEUE_SIZE: u16 = 8;
+const DESCRIPTOR_SIZE: usize = 16;
+
+const VRING_DESC_F_NEXT: u8 = 1;
+const VRING_DESC_F_WRITE: u8 = 2;
+
+// Head index, then address, length, flags and next of each descriptor
+const LAYOUT_SIZE: usize = 1 + QUEUE_SIZE as usize * 6;
+
+#[derive(Copy,Clone)]
+struct FuzzDescriptor {
+    addr: u64,
+    len: usize,
+    flags: u8,
+    next: u16,
+}
+
+impl FuzzDescriptor {
+    fn parse(bytes: &[u8]) -> Self {
+        FuzzDescriptor {
+            addr: u64::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+            len: usize::from(u16::from_le_bytes([bytes[2], bytes[3]])),
+            flags: bytes[4] & (VRING_DESC_F_NEXT | VRING_DESC_F_WRITE),
+            next: u16::from(bytes[5]),
+        }
+    }
+
+    fn in_ram(&self) -> bool {
+        self.addr as usize + self.len <= RAM_SIZE
+    }
+}
+
+// The readable and writeable buffers of the chain at `head` as the `Chain`
+// documentation describes them
+fn expected_buffers(descriptors: &[FuzzDescriptor], head: u16) -> (Vec<FuzzDescriptor>, Vec<FuzzDescriptor>) {
+    let mut readable = Vec::new();
+    let mut writeable = Vec::new();
+    let mut seen_writeable = false;
+    let mut idx = head;
+    for _ in 0..QUEUE_SIZE {
+        let d = match descriptors.get(idx as usize) {
+            Some(d) if d.len == 0 || d.in_ram() => *d,
+            _ => break,
+        };
+        if d.flags & VRING_DESC_F_WRITE != 0 {
+            seen_writeable = true;
+            if d.len > 0 {
+                writeable.push(d);
+            }
+        } else if !seen_writeable && d.len > 0 {
+            readable.push(d);
+        }
+        if d.flags & VRING_DESC_F_NEXT == 0 {
+            break;
+        }
+        idx = d.next;
+    }
+    (readable, writeable)
+}
+
+fn create_ram() -> GuestRam {
+    let mut memory = GuestRam::new(RAM_SIZE);
+    let region = MemoryRegion::new(0, RAM_SIZE).expect("failed to map memory");
+    memory.set_regions(vec![region]);
+    memory
+}
+
+fn write_descriptors(memory: &GuestRam, descriptors: &[FuzzDescriptor]) {
+    for (i, d) in descriptors.iter().enumerate() {
+        let base = (i * DESCRIPTOR_SIZE) as u64;
+        memory.write_int(base, d.addr).unwrap();
+        memory.write_int(base + 8, d.len as u32).unwrap();
+        memory.write_int(base + 12, u16::from(d.flags)).unwrap();
+        memory.write_int(base + 14, d.next).unwrap();
+    }
+    // Fill the rest of memory with a pattern to read back
+    let table_end = descriptors.len() * DESCRIPTOR_SIZE;
+    let pattern: Vec<u8> = (table_end..RAM_SIZE).map(|a| a as u8).collect();
+    memory.write_bytes(table_end as u64, &pattern).unwrap();
+}
+
+///
+/// Load a descriptor chain with a layout taken from `data` and apply the
+/// reads and writes which follow the layout in `data` to it, panicking if the
+/// chain does not behave as its documentation describes.
+///
+/// The layout is the index of the head descriptor followed by the address,
+/// length, flags and next index of each descriptor in a table of eight.
+/// Addresses and lengths may reach outside of guest memory and indexes
+/// outside of the table. Each operation is two bytes, the kind of operation
+/// and a size.
+///
+pub fn fuzz_chain_layout(data: &[u8]) {
+    if data.len() < LAYOUT_SIZE {
+        return;
+    }
+    let head = u16::from(data[0]);
+    let descriptors: Vec<FuzzDescriptor> = data[1..LAYOUT_SIZE]
+        .chunks(6)
+        .map(FuzzDescriptor::parse)
+        .collect();
+    let (readable, writeable) = expected_buffers(&descriptors, head);
+    let read_size: usize = readable.iter().map(|d| d.len).sum();
+    let write_size: usize = writeable.iter().map(|d| d.len).sum();
+
+    let memory = create_ram();
+    write_descriptors(&memory, &descriptors);
+    // Buffers may overlap each other and the descriptor table
+    let expected_data: Vec<u8> = readable.iter()
+        .flat_map(|d| memory.slice(d.addr, d.len).unwrap().to_vec())
+        .collect();
+    let mut vring = Vring::new(memory.clone(), QUEUE_SIZE);
+    vring.descriptors = 0;
+    vring.enable();
+
+    let mut chain = Chain::detached(memory, &vring, head);
+    assert_eq!(chain.remaining_read(), read_size, "readable size of {:?}", chain);
+    assert_eq!(chain.remaining_write(), write_size, "writeable size of {:?}", chain);
+
+    let mut nread = 0;
+    let mut nwritten = 0;
+    let mut reads_skipped = false;
+    for op in data[LAYOUT_SIZE..].chunks(2) {
+        let size = usize::from(*op.get(1).unwrap_or(&0)) * 16;
+        let mut buf = vec![0xAAu8; size];
+        match op[0] % 7 {
+            0 => {
+                let n = chain.read(&mut buf).unwrap();
+                assert!(reads_skipped || n == size.min(read_size - nread), "short read");
+                // Until a buffer which overlaps a readable one is written
+                if nwritten == 0 && !reads_skipped {
+                    assert_eq!(&buf[..n], &expected_data[nread..nread + n], "data read");
+                }
+                nread += n;
+            }
+            1 => nwritten += chain.write(&buf).unwrap(),
+            2 => {
+                let n: usize = chain.read_slices(size).iter().map(|s| s.len()).sum();
+                chain.advance_read(n);
+                nread += n;
+            }
+            3 => {
+                let n: usize = chain.write_slices(size).iter().map(|s| s.len()).sum();
+                chain.advance_write(n);
+                nwritten += n;
+                reads_skipped = true;
+            }
+            4 => {
+                let n = chain.current_read_slice().len().min(size);
+                chain.inc_read_offset(n);
+                nread += n;
+            }
+            5 => {
+                let n = chain.current_write_slice().len().min(size);
+                chain.inc_write_offset(n);
+                nwritten += n;
+                reads_skipped = true;
+            }
+            _ => nwritten += chain.copy_from_reader(&buf[..], size).unwrap(),
+        }
+        assert!(nwritten <= write_size, "wrote more than the writeable size");
+        assert_eq!(chain.get_wlen(), nwritten, "bytes written to {:?}", chain);
+        assert_eq!(chain.remaining_write(), write_size - nwritten, "remaining writeable size");
+        if reads_skipped {
+            assert_eq!(chain.remaining_read(), 0, "readable size after writing");
+        } else {
+            assert_eq!(chain.remaining_read(), read_size - nread, "remaining readable size");
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use crate::kvm::Kvm;
+use crate::memory::AddressRange;
+use crate::util::JsonValue;
+use crate::vm::io::{IoDispatcher, IoPortOps};
+use crate::virtio::{Result, Error};
+use super::device::VirtioDevice;
+use super::irq::PCI_FIRST_IRQ;
+use super::consts::PCI_MAX_DEVICES;
+use super::pci::PciBus;
+
+// Registers of the hotplug controller, which the ACPI tables describe as
+// an operation region of the PCI root bridge. Each register has one bit
+// for each PCI slot.
+pub const PCI_HOTPLUG_IO_BASE: u16 = 0xae00;
+pub const PCI_HOTPLUG_IO_SIZE: usize = 12;
+// Slots with a device added since the last read, cleared when read
+pub const PCI_HOTPLUG_UP: u16 = 0;
+// Slots with a removal request since the last read, cleared when read
+pub const PCI_HOTPLUG_DOWN: u16 = 4;
+// The guest writes the bit of a slot once it has released the device
+pub const PCI_HOTPLUG_EJECT: u16 = 8;
+// Every slot but slot 0, which is the host bridge, can take a device
+pub const PCI_HOTPLUG_SLOTS: u8 = PCI_MAX_DEVICES as u8;
+
+///
+/// The interrupt lines and slots of the hotplug controller which are
+/// described to the guest in the ACPI tables.
+///
+pub struct HotplugSlots {
+    event_irq: u8,
+    slot_irq: u8,
+    empty_slots: Vec<u8>,
+}
+
+impl HotplugSlots {
+    /// The line of the generic event device which runs the hotplug check.
+    pub fn event_irq(&self) -> u8 {
+        self.event_irq
+    }
+
+    /// The line shared by every device added after boot.
+    pub fn slot_irq(&self) -> u8 {
+        self.slot_irq
+    }
+
+    /// Device numbers which have no device at boot.
+    pub fn empty_slots(&self) -> &[u8] {
+        &self.empty_slots
+    }
+}
+
+// What is needed to remove a virtio device from the bus
+struct SlotDevice {
+    name: String,
+    device: Arc<RwLock<VirtioDevice>>,
+    mmio: AddressRange,
+    legacy_io: Option<u16>,
+    removing: bool,
+}
+
+#[derive(Default)]
+struct HotplugState {
+    // Set once the VM is running and devices are added to empty slots
+    started: bool,
+    up: u32,
+    down: u32,
+    slots: BTreeMap<u8, SlotDevice>,
+}
+
+///
+/// A PCI hotplug controller in the style of the ACPI PCI hotplug interface
+/// of other hypervisors.
+///
+/// When a device is added or a removal is requested, the bit of its slot is
+/// set in the up or down register and the interrupt of an ACPI generic
+/// event device is raised. The AML of the event device reads the registers
+/// and notifies the slot devices, and the guest scans the slot for the new
+/// device or releases the device and ejects it by writing the eject
+/// register. Only then is the device removed from the bus.
+///
+#[derive(Clone)]
+pub struct PciHotplug {
+    kvm: Kvm,
+    pci_bus: Arc<RwLock<PciBus>>,
+    io_dispatcher: Arc<IoDispatcher>,
+    event_irq: u8,
+    slot_irq: u8,
+    state: Arc<Mutex<HotplugState>>,
+}
+
+impl PciHotplug {
+    pub fn new(kvm: &Kvm, pci_bus: Arc<RwLock<PciBus>>, io_dispatcher: Arc<IoDispatcher>) -> Result<PciHotplug> {
+        // Neither line is shared with the devices present at boot
+        let event_irq = Self::allocate_irq(kvm)?;
+        let slot_irq = Self::allocate_irq(kvm)?;
+        let hotplug = PciHotplug {
+            kvm: kvm.clone(),
+            pci_bus,
+            io_dispatcher: io_dispatcher.clone(),
+            event_irq,
+            slot_irq,
+            state: Arc::new(Mutex::new(HotplugState::default())),
+        };
+        let ports = Arc::new(RwLock::new(HotplugPorts { hotplug: hotplug.clone() }));
+        io_dispatcher.register_ioports(PCI_HOTPLUG_IO_BASE, PCI_HOTPLUG_IO_SIZE, ports);
+        Ok(hotplug)
+    }
+
+    fn allocate_irq(kvm: &Kvm) -> Result<u8> {
+        let gsi = kvm.allocate_irq_line(u32::from(PCI_FIRST_IRQ))
+            .map_err(Error::IrqAllocate)?;
+        Ok(gsi as u8)
+    }
+
+    pub fn slots(&self) -> HotplugSlots {
+        let bus = self.pci_bus.read().unwrap();
+        HotplugSlots {
+            event_irq: self.event_irq,
+            slot_irq: self.slot_irq,
+            empty_slots: bus.empty_slots(),
+        }
+    }
+
+    /// Devices created from now on are added to a running VM and use the
+    /// interrupt line routed for empty slots.
+    pub fn start(&self) {
+        self.pci_bus.write().unwrap().set_slot_irq(self.slot_irq);
+        self.state.lock().unwrap().started = true;
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.state.lock().unwrap().started
+    }
+
+    /// Record a device so that it can be removed later, and tell the guest
+    /// about it if the VM is already running.
+    pub fn add_device(&self, slot: u8, name: &str, device: Arc<RwLock<VirtioDevice>>, mmio: AddressRange, legacy_io: Option<u16>) {
+        let started = {
+            let mut state = self.state.lock().unwrap();
+            state.slots.insert(slot, SlotDevice {
+                name: name.to_string(), device, mmio, legacy_io, removing: false,
+            });
+            if state.started {
+                state.up |= 1 << slot;
+            }
+            state.started
+        };
+        if started {
+            notify!("Added PCI device {}", name);
+            self.raise_event();
+        }
+    }
+
+    /// Ask the guest to release the device in `slot`. The device is removed
+    /// once the guest ejects it.
+    pub fn request_removal(&self, slot: u8) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            match state.slots.get_mut(&slot) {
+                Some(dev) => dev.removing = true,
+                None => return Err(Error::NoHotplugDevice(slot)),
+            }
+            state.down |= 1 << slot;
+        }
+        self.raise_event();
+        Ok(())
+    }
+
+    fn raise_event(&self) {
+        let irq = u32::from(self.event_irq);
+        if let Err(e) = self.kvm.irq_line(irq, 1).and_then(|_| self.kvm.irq_line(irq, 0)) {
+            warn!("Failed to raise PCI hotplug interrupt: {}", e);
+        }
+    }
+
+    fn read_register(&self, offset: u16) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        match offset {
+            PCI_HOTPLUG_UP => ::std::mem::replace(&mut state.up, 0),
+            PCI_HOTPLUG_DOWN => ::std::mem::replace(&mut state.down, 0),
+            _ => 0,
+        }
+    }
+
+    // Called on a vcpu thread while the I/O dispatcher is locked, so the
+    // devices are removed from the bus on another thread.
+    fn eject(&self, slots: u32) {
+        let hotplug = self.clone();
+        thread::spawn(move || {
+            for slot in 1..PCI_HOTPLUG_SLOTS {
+                if slots & (1 << slot) != 0 {
+                    hotplug.remove_slot(slot);
+                }
+            }
+        });
+    }
+
+    fn remove_slot(&self, slot: u8) {
+        let dev = match self.state.lock().unwrap().slots.remove(&slot) {
+            Some(dev) => dev,
+            None => return,
+        };
+        if !dev.removing {
+            notify!("Guest ejected PCI device {}", dev.name);
+        }
+        self.pci_bus.write().unwrap().remove_device(slot);
+        self.io_dispatcher.unregister_mmio(dev.mmio);
+        if let Some(port) = dev.legacy_io {
+            self.io_dispatcher.unregister_ioports(port);
+        }
+        dev.device.write().unwrap().detach();
+        notify!("Removed PCI device {}", dev.name);
+    }
+
+    pub fn describe(&self) -> JsonValue {
+        let state = self.state.lock().unwrap();
+        let devices: Vec<JsonValue> = state.slots.iter().map(|(&slot, dev)| {
+            JsonValue::object()
+                .with("slot", slot)
+                .with("device", dev.name.as_str())
+                .with("removing", dev.removing)
+        }).collect();
+        JsonValue::object()
+            .with("event-irq", self.event_irq)
+            .with("slot-irq", self.slot_irq)
+            .with("devices", devices)
+    }
+}
+
+struct HotplugPorts {
+    hotplug: PciHotplug,
+}
+
+impl IoPortOps for HotplugPorts {
+    fn io_in(&mut self, port: u16, _size: usize) -> u32 {
+        self.hotplug.read_register(port - PCI_HOTPLUG_IO_BASE)
+    }
+
+    fn io_out(&mut self, port: u16, _size: usize, val: u32) {
+        if port - PCI_HOTPLUG_IO_BASE == PCI_HOTPLUG_EJECT {
+            self.hotplug.eject(val);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use crate::virtio::{Error, Result};
+use crate::virtio::consts::PCI_VIRTIO_DEVICE_ID_BASE;
+
+// Default PCI subsystem ID for non-transitional devices. The virtio
+// specification requires 0x40 or higher.
+const DEFAULT_SUBSYSTEM_ID: u16 = 0x40;
+
+const DEVICE_TYPES: &[(&str, u16)] = &[
+    ("net", 1),
+    ("block", 2),
+    ("console", 3),
+    ("rng", 4),
+    ("balloon", 5),
+    ("9p", 9),
+    ("input", 18),
+    ("vsock", 19),
+    ("sound", 25),
+    ("fs", 26),
+    ("wl", 30),
+];
+
+/// Look up a virtio device type by the short name used in configuration
+/// (`net`, `block`, `console`, `rng`, `balloon`, `9p`, `input`, `vsock`,
+/// `sound`, `fs`, or `wl`).
+pub fn device_type_by_name(name: &str) -> Option<u16> {
+    DEVICE_TYPES.iter()
+        .find(|(n,_)| *n == name)
+        .map(|(_,t)| *t)
+}
+
+/// The short name of a virtio device type, or `None` for an unknown type.
+pub fn device_type_name(device_type: u16) -> Option<&'static str> {
+    DEVICE_TYPES.iter()
+        .find(|(_,t)| *t == device_type)
+        .map(|(n,_)| *n)
+}
+
+// PCI device IDs from the virtio specification for transitional devices
+fn transitional_device_id(device_type: u16) -> Option<u16> {
+    match device_type {
+        1 => Some(0x1000),
+        2 => Some(0x1001),
+        5 => Some(0x1002),
+        3 => Some(0x1003),
+        8 => Some(0x1004),
+        4 => Some(0x1005),
+        9 => Some(0x1009),
+        _ => None,
+    }
+}
+
+///
+/// The identity a virtio device presents on the PCI bus.
+///
+/// The vendor ID is always 0x1AF4 as required by the virtio specification.
+/// A non-transitional device (the default) has device ID `0x1040 + device type`,
+/// a revision of at least 1, and a subsystem ID of at least 0x40. A transitional
+/// device uses the legacy device ID from the specification, revision 0, and the
+/// device type as the subsystem ID, so the subsystem ID cannot be overridden.
+///
+/// A transitional device may also present the legacy (virtio 0.9.5) interface
+/// in an I/O port BAR for guest kernels which do not support virtio 1.0.
+///
+#[derive(Copy,Clone,Debug)]
+pub struct PciIdentity {
+    transitional: bool,
+    legacy: bool,
+    revision: Option<u8>,
+    subsystem_vendor_id: u16,
+    subsystem_id: Option<u16>,
+}
+
+impl PciIdentity {
+    pub fn new() -> PciIdentity {
+        PciIdentity {
+            transitional: false,
+            legacy: false,
+            revision: None,
+            subsystem_vendor_id: 0,
+            subsystem_id: None,
+        }
+    }
+
+    pub fn transitional(mut self, transitional: bool) -> Self {
+        self.transitional = transitional;
+        self
+    }
+
+    /// Present the legacy virtio interface in addition to the modern
+    /// interface. This implies a transitional device.
+    pub fn legacy(mut self, legacy: bool) -> Self {
+        self.legacy = legacy;
+        if legacy {
+            self.transitional = true;
+        }
+        self
+    }
+
+    pub fn revision(mut self, revision: u8) -> Self {
+        self.revision = Some(revision);
+        self
+    }
+
+    pub fn subsystem(mut self, vendor_id: u16, subsystem_id: u16) -> Self {
+        self.subsystem_vendor_id = vendor_id;
+        self.subsystem_id = Some(subsystem_id);
+        self
+    }
+
+    pub fn is_transitional(&self) -> bool {
+        self.transitional
+    }
+
+    pub fn has_legacy_interface(&self) -> bool {
+        self.transitional && self.legacy
+    }
+
+    /// Parse an identity from a configuration string which is either
+    /// `transitional`, `legacy`, or `<subsystem vendor>:<subsystem id>` in hex.
+    pub fn parse(s: &str) -> Option<PciIdentity> {
+        match s {
+            "transitional" => return Some(PciIdentity::new().transitional(true)),
+            "legacy" => return Some(PciIdentity::new().legacy(true)),
+            _ => {},
+        }
+        let mut parts = s.splitn(2, ':');
+        let vendor = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let id = u16::from_str_radix(parts.next()?, 16).ok()?;
+        Some(PciIdentity::new().subsystem(vendor, id))
+    }
+
+    pub fn device_id(&self, device_type: u16) -> Result<u16> {
+        if !self.transitional {
+            return Ok(PCI_VIRTIO_DEVICE_ID_BASE + device_type);
+        }
+        transitional_device_id(device_type)
+            .ok_or(Error::InvalidPciIdentity(device_type, "device type has no transitional device ID"))
+    }
+
+    pub fn revision_id(&self, device_type: u16) -> Result<u8> {
+        match (self.transitional, self.revision) {
+            (true, Some(rev)) if rev != 0 => Err(Error::InvalidPciIdentity(device_type, "transitional devices must have revision 0")),
+            (false, Some(0)) => Err(Error::InvalidPciIdentity(device_type, "non-transitional devices must have revision 1 or higher")),
+            (_, Some(rev)) => Ok(rev),
+            (true, None) => Ok(0),
+            (false, None) => Ok(1),
+        }
+    }
+
+    pub fn subsystem_vendor_id(&self) -> u16 {
+        self.subsystem_vendor_id
+    }
+
+    pub fn subsystem_id(&self, device_type: u16) -> Result<u16> {
+        match (self.transitional, self.subsystem_id) {
+            (true, Some(_)) => Err(Error::InvalidPciIdentity(device_type, "subsystem ID of a transitional device is the device type")),
+            (true, None) => Ok(device_type),
+            (false, Some(id)) if id < DEFAULT_SUBSYSTEM_ID => Err(Error::InvalidPciIdentity(device_type, "subsystem ID must be 0x40 or higher")),
+            (false, Some(id)) => Ok(id),
+            (false, None) => Ok(DEFAULT_SUBSYSTEM_ID),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::util::JsonValue;
+
+// First line considered when allocating PCI interrupt lines. Lower
+// numbers are left to legacy ISA devices.
+pub const PCI_FIRST_IRQ: u8 = 5;
+
+// Unless the guest is booted with ACPI tables it is booted with `noapic`,
+// so only the 16 lines of the two 8259 PICs are delivered to it.
+const PIC_NUM_LINES: u8 = 16;
+
+// ISA lines which the guest kernel may claim for legacy devices: keyboard,
+// cascade, serial ports, floppy, parallel port, RTC, PS/2 mouse, FPU and IDE.
+const LEGACY_IRQS: &[u8] = &[0, 1, 2, 3, 4, 6, 7, 8, 12, 13, 14, 15];
+
+///
+/// How interrupt lines are assigned to virtio PCI devices.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum IrqPolicy {
+    /// Each device gets the lowest free line from 5 and device creation
+    /// fails once the lines run out.
+    Linear,
+    /// Only lines which no legacy ISA device uses are assigned, and they
+    /// are shared between devices once each one has been assigned.
+    AvoidLegacy,
+    /// Every free line from 5 to 15 is assigned before any line is shared,
+    /// then devices are spread evenly across them.
+    Spread,
+}
+
+impl IrqPolicy {
+    /// Parse `linear`, `avoid-legacy` or `spread`.
+    pub fn parse(s: &str) -> Option<IrqPolicy> {
+        match s {
+            "linear" => Some(IrqPolicy::Linear),
+            "avoid-legacy" => Some(IrqPolicy::AvoidLegacy),
+            "spread" => Some(IrqPolicy::Spread),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IrqPolicy::Linear => "linear",
+            IrqPolicy::AvoidLegacy => "avoid-legacy",
+            IrqPolicy::Spread => "spread",
+        }
+    }
+
+    /// Lines which may be assigned under a policy which shares lines, in
+    /// order of preference.
+    pub fn lines(self) -> Vec<u8> {
+        match self {
+            IrqPolicy::Linear | IrqPolicy::Spread => (PCI_FIRST_IRQ..PIC_NUM_LINES).collect(),
+            IrqPolicy::AvoidLegacy => (PCI_FIRST_IRQ..PIC_NUM_LINES)
+                .filter(|irq| !LEGACY_IRQS.contains(irq))
+                .collect(),
+        }
+    }
+
+    /// Returns `true` if devices share a line when no free line is left.
+    pub fn shares_lines(self) -> bool {
+        self != IrqPolicy::Linear
+    }
+}
+
+impl Default for IrqPolicy {
+    fn default() -> Self {
+        IrqPolicy::Linear
+    }
+}
+
+///
+/// Counts of interrupts raised on a line by one device, and of reads of
+/// the device ISR which found no interrupt pending. The guest reads the ISR
+/// of every device on a line when the line is raised, so spurious reads
+/// show the cost of sharing the line.
+///
+/// Devices with an interrupt batching window also count the completions
+/// which joined an interrupt already waiting, and the delay the window added.
+///
+#[derive(Default)]
+pub struct IrqCounters {
+    raised: AtomicUsize,
+    spurious: AtomicUsize,
+    batch_window_us: AtomicUsize,
+    batches: AtomicUsize,
+    coalesced: AtomicUsize,
+    batch_delay_us: AtomicUsize,
+}
+
+impl IrqCounters {
+    pub fn record_raised(&self) {
+        self.raised.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_isr_read(&self, isr: u64) {
+        if isr == 0 {
+            self.spurious.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_batch_window(&self, window: Duration) {
+        self.batch_window_us.store(window.as_micros() as usize, Ordering::Relaxed);
+    }
+
+    /// A completion was folded into an interrupt which is waiting for the
+    /// batching window to end.
+    pub fn record_coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A batch of interrupts was raised `delay` after the first of them was
+    /// requested.
+    pub fn record_batch(&self, delay: Duration) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.batch_delay_us.fetch_add(delay.as_micros() as usize, Ordering::Relaxed);
+    }
+
+    pub fn raised(&self) -> usize {
+        self.raised.load(Ordering::Relaxed)
+    }
+
+    pub fn spurious(&self) -> usize {
+        self.spurious.load(Ordering::Relaxed)
+    }
+}
+
+struct IrqDevice {
+    name: String,
+    counters: Arc<IrqCounters>,
+}
+
+impl IrqDevice {
+    fn describe_batching(&self) -> JsonValue {
+        let c = &self.counters;
+        let batches = c.batches.load(Ordering::Relaxed);
+        let mean_delay = c.batch_delay_us.load(Ordering::Relaxed).checked_div(batches);
+        JsonValue::object()
+            .with("device", self.name.as_str())
+            .with("window-us", c.batch_window_us.load(Ordering::Relaxed))
+            .with("batches", batches)
+            .with("coalesced", c.coalesced.load(Ordering::Relaxed))
+            .with("mean-delay-us", mean_delay)
+    }
+}
+
+///
+/// The final assignment of interrupt lines to virtio PCI devices and the
+/// interrupt counts for each line, which can be read after the VM starts.
+///
+#[derive(Clone)]
+pub struct IrqStats {
+    policy: IrqPolicy,
+    lines: Arc<Mutex<BTreeMap<u8, Vec<IrqDevice>>>>,
+}
+
+impl IrqStats {
+    pub fn new(policy: IrqPolicy) -> Self {
+        IrqStats { policy, lines: Arc::new(Mutex::new(BTreeMap::new())) }
+    }
+
+    /// Record that device `name` was assigned line `irq` and return the
+    /// counters which its interrupt line updates.
+    pub fn add_device(&self, irq: u8, name: &str) -> Arc<IrqCounters> {
+        let counters = Arc::new(IrqCounters::default());
+        self.lines.lock().unwrap()
+            .entry(irq)
+            .or_insert_with(Vec::new)
+            .push(IrqDevice { name: name.to_string(), counters: counters.clone() });
+        counters
+    }
+
+    /// The line from `lines` which has been assigned to the fewest devices.
+    pub fn least_used(&self, lines: &[u8]) -> Option<u8> {
+        let assigned = self.lines.lock().unwrap();
+        lines.iter()
+            .filter_map(|irq| assigned.get(irq).map(|devices| (devices.len(), *irq)))
+            .min()
+            .map(|(_, irq)| irq)
+    }
+
+    pub fn describe(&self) -> JsonValue {
+        let devices_by_line = self.lines.lock().unwrap();
+        let lines: Vec<JsonValue> = devices_by_line.iter().map(|(&irq, devices)| {
+            let names: Vec<JsonValue> = devices.iter().map(|d| d.name.as_str().into()).collect();
+            JsonValue::object()
+                .with("irq", irq)
+                .with("devices", names)
+                .with("shared", devices.len() > 1)
+                .with("interrupts", devices.iter().map(|d| d.counters.raised()).sum::<usize>())
+                .with("spurious", devices.iter().map(|d| d.counters.spurious()).sum::<usize>())
+        }).collect();
+        let batching: Vec<JsonValue> = devices_by_line.values()
+            .flat_map(|devices| devices.iter())
+            .filter(|d| d.counters.batch_window_us.load(Ordering::Relaxed) > 0)
+            .map(|d| d.describe_batching())
+            .collect();
+        JsonValue::object()
+            .with("policy", self.policy.name())
+            .with("lines", lines)
+            .with("batching", batching)
+    }
+}
@@ -7,17 +7,32 @@ mod pci;
 mod virtqueue;
 mod vring;
 mod device_config;
+mod identity;
+mod irq;
+mod audit;
+mod quirks;
+mod msix;
+mod hotplug;
+#[cfg(feature = "fuzzing")]
+mod fuzz;
 
 pub use self::virtqueue::VirtQueue;
 pub use self::pci::PciIrq;
 pub use self::bus::VirtioBus;
 pub use self::device::{VirtioDevice,VirtioDeviceOps};
 pub use self::chain::Chain;
+#[cfg(feature = "fuzzing")]
+pub use self::fuzz::fuzz_chain_layout;
 pub use self::device_config::DeviceConfigArea;
+pub use self::identity::{PciIdentity, device_type_by_name};
+pub use self::irq::IrqPolicy;
+pub use self::hotplug::HotplugSlots;
+#[cfg(target_arch = "x86_64")]
+pub use self::hotplug::{PCI_HOTPLUG_IO_BASE, PCI_HOTPLUG_IO_SIZE, PCI_HOTPLUG_SLOTS};
 
 use byteorder::{ByteOrder,LittleEndian};
-use std::{result, fmt};
-use crate::{system, kvm};
+use std::{result, fmt, io};
+use crate::{system, kvm, memory};
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -27,10 +42,22 @@ pub enum Error {
     CreateIoEventFd(kvm::Error),
     ReadIoEventFd(system::Error),
     IrqFd(kvm::Error),
+    IrqAllocate(kvm::Error),
     VringNotEnabled,
     VringRangeInvalid(u64),
     VringAvailInvalid(u64),
     VringUsedInvalid(u64),
+    InvalidPciIdentity(u16, &'static str),
+    TooManyQueues(usize),
+    TooManySharedRegions(usize),
+    MmioSpaceExhausted,
+    MmioConfigTooLarge(usize),
+    PciSlotsExhausted,
+    HotplugNotEnabled,
+    NoHotplugDevice(u8),
+    DeviceSocket(io::Error),
+    QueueClosed,
+    CreateSharedRegion(memory::Error),
 }
 
 impl fmt::Display for Error {
@@ -41,17 +68,29 @@ impl fmt::Display for Error {
             CreateEventFd(e) => write!(f, "failed to create EventFd for VirtQueue: {}", e),
             ReadIoEventFd(e) => write!(f, "failed to read from IoEventFd: {}", e),
             IrqFd(e) => write!(f, "VirtQueue: {}", e),
+            IrqAllocate(e) => write!(f, "failed to allocate irq for PCI device: {}", e),
             VringNotEnabled => write!(f, "vring is not enabled"),
             VringRangeInvalid(addr) => write!(f, "vring descriptor table range is invalid 0x{:x}", addr),
             VringAvailInvalid(addr) => write!(f, "vring avail ring range range is invalid 0x{:x}", addr),
             VringUsedInvalid(addr) => write!(f, "vring used ring range is invalid 0x{:x}", addr),
+            InvalidPciIdentity(device_type, msg) => write!(f, "invalid PCI identity for virtio device type {}: {}", device_type, msg),
+            TooManyQueues(n) => write!(f, "virtio device requested {} queues, the maximum is {}", n, consts::VIRTIO_MAX_QUEUES),
+            TooManySharedRegions(n) => write!(f, "virtio device requested {} shared memory regions, the maximum is {}", n, consts::VIRTIO_SHM_BARS.len()),
+            MmioSpaceExhausted => write!(f, "no PCI MMIO space is left for virtio device"),
+            MmioConfigTooLarge(size) => write!(f, "device configuration of {} bytes does not fit in the virtio-mmio register area", size),
+            PciSlotsExhausted => write!(f, "no PCI slot is left for virtio device"),
+            HotplugNotEnabled => write!(f, "PCI hotplug is not enabled"),
+            NoHotplugDevice(slot) => write!(f, "no device in PCI slot {}", slot),
+            DeviceSocket(e) => write!(f, "failed to create socket for virtio device: {}", e),
+            QueueClosed => write!(f, "virtqueue was closed because its device was removed"),
+            CreateSharedRegion(e) => write!(f, "failed to create shared memory region: {}", e),
 
         }
     }
 }
 
 pub fn read_config_buffer(config: &[u8], offset: usize, size: usize) -> u64 {
-    if offset + size > config.len() {
+    if !DeviceConfigArea::is_valid_access(config.len(), offset, size) {
         return 0;
     }
     match size {
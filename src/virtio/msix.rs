@@ -0,0 +1,255 @@
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use crate::kvm::{Kvm, IrqRoute};
+use crate::system::EventFd;
+use super::consts::*;
+
+// Each entry of the MSI-X table is the message address, data and vector control
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDR_LO: usize = 0;
+const MSIX_ENTRY_ADDR_HI: usize = 4;
+const MSIX_ENTRY_DATA: usize = 8;
+const MSIX_ENTRY_CONTROL: usize = 12;
+const MSIX_VECTOR_MASKED: u32 = 1;
+
+#[derive(Copy,Clone)]
+struct MsixEntry {
+    address: u64,
+    data: u32,
+    control: u32,
+}
+
+impl MsixEntry {
+    fn is_masked(&self) -> bool {
+        self.control & MSIX_VECTOR_MASKED != 0
+    }
+
+    fn route(&self) -> IrqRoute {
+        IrqRoute::Msi { address: self.address, data: self.data }
+    }
+}
+
+struct MsixVector {
+    entry: MsixEntry,
+    // Eventfd bound with an irqfd to a GSI routed to the message of the entry
+    irqfd: Option<(EventFd, u32)>,
+    pending: bool,
+}
+
+impl MsixVector {
+    fn new() -> Self {
+        // 6.8.2.9 Every vector is masked after reset
+        let entry = MsixEntry { address: 0, data: 0, control: MSIX_VECTOR_MASKED };
+        MsixVector { entry, irqfd: None, pending: false }
+    }
+
+    // The GSI is allocated the first time the vector fires, so that only
+    // vectors which the driver uses take a route in the KVM routing table.
+    fn deliver(&mut self, kvm: &Kvm) {
+        if self.irqfd.is_none() {
+            match Self::bind_irqfd(kvm, self.entry.route()) {
+                Ok(irqfd) => self.irqfd = Some(irqfd),
+                Err(e) => {
+                    warn!("Failed to set up MSI-X vector: {}", e);
+                    return;
+                }
+            }
+        }
+        if let Some((ref evt, _)) = self.irqfd {
+            if let Err(e) = evt.write(1) {
+                warn!("Failed to signal MSI-X vector: {}", e);
+            }
+        }
+    }
+
+    fn bind_irqfd(kvm: &Kvm, route: IrqRoute) -> ::std::result::Result<(EventFd, u32), String> {
+        let evt = EventFd::new().map_err(|e| e.to_string())?;
+        let gsi = kvm.allocate_routed_gsi(route).map_err(|e| e.to_string())?;
+        if let Err(e) = kvm.irqfd(evt.as_raw_fd() as u32, gsi) {
+            let _ = kvm.free_gsi(gsi);
+            return Err(e.to_string());
+        }
+        Ok((evt, gsi))
+    }
+
+    fn update_route(&self, kvm: &Kvm) {
+        if let Some((_, gsi)) = self.irqfd {
+            if let Err(e) = kvm.update_gsi_route(gsi, self.entry.route()) {
+                warn!("Failed to update route of MSI-X vector: {}", e);
+            }
+        }
+    }
+}
+
+///
+/// The MSI-X table of a virtio PCI device and the vector assigned to the
+/// configuration change interrupt and to each queue.
+///
+/// The table and pending bit array are in the virtio MMIO BAR. While the
+/// driver has MSI-X enabled, interrupts are sent as messages on the vector
+/// of the queue or configuration change and the ISR is not used. A message
+/// on a masked vector is held in the pending bit array until the vector is
+/// unmasked.
+///
+pub struct MsixVectors {
+    kvm: Kvm,
+    enabled: AtomicBool,
+    function_masked: AtomicBool,
+    vectors: Mutex<Vec<MsixVector>>,
+    config_vector: AtomicU16,
+    queue_vectors: Vec<AtomicU16>,
+}
+
+impl MsixVectors {
+    pub fn new(kvm: &Kvm, num_vectors: usize, num_queues: usize) -> Self {
+        MsixVectors {
+            kvm: kvm.clone(),
+            enabled: AtomicBool::new(false),
+            function_masked: AtomicBool::new(false),
+            vectors: Mutex::new((0..num_vectors).map(|_| MsixVector::new()).collect()),
+            config_vector: AtomicU16::new(VIRTIO_NO_MSI_VECTOR),
+            queue_vectors: (0..num_queues).map(|_| AtomicU16::new(VIRTIO_NO_MSI_VECTOR)).collect(),
+        }
+    }
+
+    /// Number of entries in the MSI-X table
+    pub fn num_vectors(&self) -> usize {
+        self.vectors.lock().unwrap().len()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Called when the driver writes the Message Control register of the
+    /// MSI-X capability.
+    pub fn set_control(&self, enabled: bool, function_masked: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+        self.function_masked.store(function_masked, Ordering::SeqCst);
+        if enabled && !function_masked {
+            let mut vectors = self.vectors.lock().unwrap();
+            for v in vectors.iter_mut() {
+                self.deliver_pending(v);
+            }
+        }
+    }
+
+    fn deliver_pending(&self, v: &mut MsixVector) {
+        if v.pending && !v.entry.is_masked() {
+            v.pending = false;
+            v.deliver(&self.kvm);
+        }
+    }
+
+    /// Send the message of `vector`, or set its pending bit if it is masked.
+    pub fn signal(&self, vector: u16) {
+        let mut vectors = self.vectors.lock().unwrap();
+        if let Some(v) = vectors.get_mut(vector as usize) {
+            if v.entry.is_masked() || self.function_masked.load(Ordering::SeqCst) {
+                v.pending = true;
+            } else {
+                v.deliver(&self.kvm);
+            }
+        }
+    }
+
+    // 4.1.5.1.2 A vector which the device cannot use reads back as NO_VECTOR
+    fn valid_vector(&self, vector: u16) -> u16 {
+        if (vector as usize) < self.num_vectors() {
+            vector
+        } else {
+            VIRTIO_NO_MSI_VECTOR
+        }
+    }
+
+    pub fn config_vector(&self) -> u16 {
+        self.config_vector.load(Ordering::SeqCst)
+    }
+
+    pub fn set_config_vector(&self, vector: u16) {
+        self.config_vector.store(self.valid_vector(vector), Ordering::SeqCst);
+    }
+
+    pub fn queue_vector(&self, queue: u16) -> u16 {
+        self.queue_vectors.get(queue as usize)
+            .map(|v| v.load(Ordering::SeqCst))
+            .unwrap_or(VIRTIO_NO_MSI_VECTOR)
+    }
+
+    pub fn set_queue_vector(&self, queue: u16, vector: u16) {
+        let vector = self.valid_vector(vector);
+        if let Some(v) = self.queue_vectors.get(queue as usize) {
+            v.store(vector, Ordering::SeqCst);
+        }
+    }
+
+    /// Unassign the vectors of the queues and configuration changes when
+    /// the device is reset. The table itself belongs to the PCI function and
+    /// is not reset.
+    pub fn reset_vectors(&self) {
+        self.config_vector.store(VIRTIO_NO_MSI_VECTOR, Ordering::SeqCst);
+        for v in &self.queue_vectors {
+            v.store(VIRTIO_NO_MSI_VECTOR, Ordering::SeqCst);
+        }
+    }
+
+    pub fn table_read(&self, offset: usize, size: usize) -> u64 {
+        let vectors = self.vectors.lock().unwrap();
+        let entry = match vectors.get(offset / MSIX_ENTRY_SIZE) {
+            Some(v) => v.entry,
+            None => return 0,
+        };
+        let val = match offset % MSIX_ENTRY_SIZE {
+            MSIX_ENTRY_ADDR_LO if size == 8 => return entry.address,
+            MSIX_ENTRY_ADDR_LO => entry.address as u32,
+            MSIX_ENTRY_ADDR_HI => (entry.address >> 32) as u32,
+            MSIX_ENTRY_DATA if size == 8 => return u64::from(entry.data) | (u64::from(entry.control) << 32),
+            MSIX_ENTRY_DATA => entry.data,
+            MSIX_ENTRY_CONTROL => entry.control,
+            _ => 0,
+        };
+        u64::from(val)
+    }
+
+    pub fn table_write(&self, offset: usize, size: usize, val: u64) {
+        let mut vectors = self.vectors.lock().unwrap();
+        let v = match vectors.get_mut(offset / MSIX_ENTRY_SIZE) {
+            Some(v) => v,
+            None => return,
+        };
+        let old = v.entry;
+        match offset % MSIX_ENTRY_SIZE {
+            MSIX_ENTRY_ADDR_LO if size == 8 => v.entry.address = val,
+            MSIX_ENTRY_ADDR_LO => v.entry.address = (old.address & !0xFFFF_FFFF) | (val & 0xFFFF_FFFF),
+            MSIX_ENTRY_ADDR_HI => v.entry.address = (old.address & 0xFFFF_FFFF) | (val << 32),
+            MSIX_ENTRY_DATA if size == 8 => {
+                v.entry.data = val as u32;
+                v.entry.control = (val >> 32) as u32 & MSIX_VECTOR_MASKED;
+            }
+            MSIX_ENTRY_DATA => v.entry.data = val as u32,
+            MSIX_ENTRY_CONTROL => v.entry.control = val as u32 & MSIX_VECTOR_MASKED,
+            _ => {},
+        }
+        if v.entry.address != old.address || v.entry.data != old.data {
+            v.update_route(&self.kvm);
+        }
+        if !self.function_masked.load(Ordering::SeqCst) {
+            self.deliver_pending(v);
+        }
+    }
+
+    /// Read the pending bit array, which has one bit for each vector.
+    pub fn pba_read(&self, offset: usize, size: usize) -> u64 {
+        let vectors = self.vectors.lock().unwrap();
+        let first = offset * 8;
+        let mut bits = 0u64;
+        for i in 0..(size * 8) {
+            if vectors.get(first + i).map(|v| v.pending).unwrap_or(false) {
+                bits |= 1 << i;
+            }
+        }
+        bits
+    }
+}
@@ -2,8 +2,12 @@ use std::sync::{Arc,RwLock};
 use byteorder::{ByteOrder,LittleEndian};
 
 use crate::vm::io::{IoDispatcher,IoPortOps};
-use crate::vm::arch::PCI_MMIO_RESERVED_BASE;
+use crate::vm::arch::{PCI_MMIO_RESERVED_BASE, PCI_MMIO_RESERVED_SIZE};
 use crate::memory::AddressRange;
+use crate::kvm::Kvm;
+use crate::virtio::{Result,Error};
+use crate::virtio::irq::{IrqPolicy, IrqStats, PCI_FIRST_IRQ};
+use crate::virtio::msix::MsixVectors;
 use super::consts::*;
 
 struct PciConfigAddress(u32);
@@ -37,6 +41,16 @@ impl PciIrq {
         }
     }
 
+    /// The PCI device number on bus 0.
+    pub fn device_number(&self) -> u8 {
+        self.pci_id
+    }
+
+    /// The interrupt pin of the device, where 0 is INTA.
+    pub fn pin(&self) -> u8 {
+        self.int_pin - 1
+    }
+
     pub fn src_bus_irq(&self) -> u8 {
         (self.pci_id << 2) | (self.int_pin - 1)
     }
@@ -47,21 +61,31 @@ impl PciIrq {
 }
 
 pub struct PciBus {
+    kvm: Kvm,
     devices: Vec<Option<PciDevice>>,
-    mmio_next_alloc: u32,
-    next_irq: u8,
-    next_dev: u8,
+    mmio_next_alloc: u64,
+    io_next_alloc: u16,
     config_address: PciConfigAddress,
+    irq_policy: IrqPolicy,
+    irq_stats: IrqStats,
+    // Line given to every device created once hotplug has started
+    slot_irq: Option<u8>,
+    // Line of each slot in the routing tables which the guest booted with
+    routed_irqs: Vec<Option<u8>>,
 }
 
 impl PciBus {
-    pub fn new(io: &IoDispatcher) -> Arc<RwLock<PciBus>> {
+    pub fn new(io: &IoDispatcher, kvm: &Kvm) -> Arc<RwLock<PciBus>> {
         let bus = Arc::new(RwLock::new(PciBus {
+            kvm: kvm.clone(),
             devices: PciBus::create_device_vec(PCI_MAX_DEVICES),
-            mmio_next_alloc: PCI_MMIO_RESERVED_BASE as u32,
-            next_irq: 5,
-            next_dev: 1,
+            mmio_next_alloc: PCI_MMIO_RESERVED_BASE,
+            io_next_alloc: PCI_IO_BASE,
             config_address: PciConfigAddress::new(),
+            irq_policy: IrqPolicy::default(),
+            irq_stats: IrqStats::new(IrqPolicy::default()),
+            slot_irq: None,
+            routed_irqs: vec![None; PCI_MAX_DEVICES],
         }));
 
         io.register_ioports(PCI_CONFIG_ADDRESS, 8, bus.clone());
@@ -81,30 +105,80 @@ impl PciBus {
         v
     }
 
-    fn allocate_irq(&mut self) -> u8 {
-        let irq = self.next_irq;
-        self.next_irq += 1;
-        irq
+    /// Set the policy for assigning interrupt lines to devices created after this call.
+    pub fn set_irq_policy(&mut self, policy: IrqPolicy) {
+        self.irq_policy = policy;
+        self.irq_stats = IrqStats::new(policy);
     }
 
-    fn allocate_id(&mut self) -> u8 {
-        let id = self.next_dev;
-        self.next_dev += 1;
-        id
+    pub fn irq_stats(&self) -> IrqStats {
+        self.irq_stats.clone()
     }
 
-    pub fn create_device(&mut self, vendor: u16, device: u16, class_id: u16) -> PciDevice {
-        let irq = self.allocate_irq();
-        let id = self.allocate_id();
+    pub fn allocate_irq(&mut self) -> Result<u8> {
+        if !self.irq_policy.shares_lines() {
+            let gsi = self.kvm.allocate_irq_line(u32::from(PCI_FIRST_IRQ))
+                .map_err(Error::IrqAllocate)?;
+            return Ok(gsi as u8);
+        }
+        let lines = self.irq_policy.lines();
+        let gsis: Vec<u32> = lines.iter().map(|&irq| u32::from(irq)).collect();
+        match self.kvm.allocate_irq_line_in(&gsis) {
+            Ok(gsi) => Ok(gsi as u8),
+            // Every line is taken, so share the one with the fewest devices
+            Err(err) => self.irq_stats.least_used(&lines)
+                .ok_or(Error::IrqAllocate(err)),
+        }
+    }
+
+    /// Give devices created after this call `irq` instead of allocating a
+    /// line for each device.
+    pub fn set_slot_irq(&mut self, irq: u8) {
+        self.slot_irq = Some(irq);
+    }
+
+    fn allocate_id(&mut self) -> Result<u8> {
+        self.devices.iter()
+            .position(|d| d.is_none())
+            .map(|id| id as u8)
+            .ok_or(Error::PciSlotsExhausted)
+    }
+
+    /// Device numbers with no device.
+    pub fn empty_slots(&self) -> Vec<u8> {
+        self.devices.iter()
+            .enumerate()
+            .filter(|&(_, d)| d.is_none())
+            .map(|(id, _)| id as u8)
+            .collect()
+    }
+
+    pub fn create_device(&mut self, vendor: u16, device: u16, class_id: u16) -> Result<PciDevice> {
+        let id = self.allocate_id()?;
+        // A device added to a slot which had a device at boot keeps the
+        // line of the slot, since the guest routes the slot to that line.
+        let irq = match self.slot_irq {
+            Some(irq) => self.routed_irqs[id as usize].unwrap_or(irq),
+            None => self.allocate_irq()?,
+        };
         let pci = PciDevice::new(id, irq, vendor, device, class_id);
-        pci
+        Ok(pci)
     }
 
     pub fn store_device(&mut self, pci: PciDevice) {
         let id = pci.id as usize;
+        if self.slot_irq.is_none() {
+            self.routed_irqs[id] = Some(pci.irq);
+        }
         self.devices[id] = Some(pci)
     }
 
+    pub fn remove_device(&mut self, id: u8) {
+        if let Some(slot) = self.devices.get_mut(id as usize) {
+            *slot = None;
+        }
+    }
+
     fn create_device_vec(sz: usize) -> Vec<Option<PciDevice>> {
         let mut v = Vec::with_capacity(sz);
         for _ in 0..sz {
@@ -113,11 +187,23 @@ impl PciBus {
         v
     }
 
-    pub fn allocate_mmio_space(&mut self, sz: usize) -> AddressRange {
-        let mask = (sz - 1) as u32;
+    /// Allocate `sz` bytes in the MMIO window below 4GB, or return `None`
+    /// if the window is full.
+    pub fn allocate_mmio_space(&mut self, sz: usize) -> Option<AddressRange> {
+        let mask = (sz - 1) as u64;
         let aligned = (self.mmio_next_alloc + mask) & !mask;
-        self.mmio_next_alloc = aligned + (sz as u32);
-        AddressRange::new(aligned as u64, sz)
+        if aligned + sz as u64 > PCI_MMIO_RESERVED_BASE + PCI_MMIO_RESERVED_SIZE as u64 {
+            return None;
+        }
+        self.mmio_next_alloc = aligned + sz as u64;
+        Some(AddressRange::new(aligned, sz))
+    }
+
+    pub fn allocate_io_space(&mut self, sz: usize) -> u16 {
+        let mask = (sz - 1) as u16;
+        let aligned = (self.io_next_alloc + mask) & !mask;
+        self.io_next_alloc = aligned + (sz as u16);
+        aligned
     }
 
     fn is_in_range(base: u16, port: u16, len: usize) -> bool {
@@ -205,6 +291,9 @@ pub struct PciDevice {
     irq: u8,
     config_buffer: [u8; PCI_CONFIG_SPACE_SIZE],
     bar_write_masks: [u32; 6],
+    bar_flags: [u32; 6],
+    // Offset of the MSI-X capability and the table it controls
+    msix: Option<(usize, Arc<MsixVectors>)>,
 }
 
 impl PciDevice {
@@ -216,6 +305,8 @@ impl PciDevice {
             irq,
             config_buffer: [0; PCI_CONFIG_SPACE_SIZE],
             bar_write_masks: [0; 6],
+            bar_flags: [0; 6],
+            msix: None,
         };
         d.w16(PCI_VENDOR_ID, vendor);
         d.w16(PCI_DEVICE_ID, device);
@@ -232,6 +323,19 @@ impl PciDevice {
         self.irq
     }
 
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn set_revision(&mut self, revision: u8) {
+        self.w8(PCI_CLASS_REVISION, revision);
+    }
+
+    pub fn set_subsystem(&mut self, vendor_id: u16, subsystem_id: u16) {
+        self.w16(PCI_SUBSYSTEM_VENDOR_ID, vendor_id);
+        self.w16(PCI_SUBSYSTEM_ID, subsystem_id);
+    }
+
     fn is_valid_write(&self, offset: usize, size: usize) -> bool {
         if offset + size > PCI_CONFIG_SPACE_SIZE {
             return false;
@@ -268,7 +372,7 @@ impl PciDevice {
 
         // apply write mask to whatever was written
         let v = self.r32(offset);
-        self.w32(offset, v & write_mask);
+        self.w32(offset, (v & write_mask) | self.bar_flags[bar]);
     }
 
     fn write_config(&mut self, offset: usize, size: usize, data: u32) {
@@ -281,6 +385,14 @@ impl PciDevice {
             return;
         }
 
+        if let Some((cap, _)) = self.msix {
+            match (offset, size) {
+                (o, 2) if o == cap + 2 => return self.write_msix_control(data as u16),
+                (o, 4) if o == cap => return self.write_msix_control((data >> 16) as u16),
+                _ => {},
+            }
+        }
+
         match offset {
             PCI_COMMAND if size == 2 => self.w16(PCI_COMMAND, data as u16),
             PCI_STATUS if size == 2 => self.w16(PCI_STATUS, data as u16),
@@ -290,6 +402,18 @@ impl PciDevice {
         }
     }
 
+    // Only the enable and function mask bits of Message Control are writable
+    fn write_msix_control(&mut self, data: u16) {
+        let (cap, msix) = match self.msix {
+            Some((cap, ref msix)) => (cap, msix.clone()),
+            None => return,
+        };
+        let writable = PCI_MSIX_FLAGS_ENABLE | PCI_MSIX_FLAGS_MASKALL;
+        let control = (self.r16(cap + 2) & !writable) | (data & writable);
+        self.w16(cap + 2, control);
+        msix.set_control(control & PCI_MSIX_FLAGS_ENABLE != 0, control & PCI_MSIX_FLAGS_MASKALL != 0);
+    }
+
     fn w32(&mut self, off: usize, val: u32) { LittleEndian::write_u32(&mut self.config_buffer[off..], val); }
     fn w16(&mut self, off: usize, val: u16) { LittleEndian::write_u16(&mut self.config_buffer[off..], val); }
     fn w8(&mut self, off: usize, val: u8) { self.config_buffer[off] = val; }
@@ -322,25 +446,91 @@ impl PciDevice {
         self.w32(bar_to_offset(bar), range.base() as u32);
     }
 
-    pub fn add_virtio_caps(&mut self, config_size: usize) {
-        self.new_virtio_cap(VIRTIO_PCI_CAP_COMMON_CFG)
+    /// Set a 64 bit memory BAR, which uses the BAR registers `bar` and
+    /// `bar + 1`. Only memory without side effects on reads, such as shared
+    /// memory, may be `prefetchable`.
+    pub fn set_mmio_bar64(&mut self, bar: usize, range: AddressRange, prefetchable: bool) {
+        assert!(range.is_naturally_aligned(), "cannot set_mmio_bar64() because mmio range is not naturally aligned");
+        assert!(bar < 5, "bar is invalid value in set_mmio_bar64()");
+        let mask = !((range.size() as u64) - 1);
+        let mut flags = PCI_BASE_ADDRESS_MEM_TYPE_64;
+        if prefetchable {
+            flags |= PCI_BASE_ADDRESS_MEM_PREFETCH;
+        }
+        self.bar_write_masks[bar] = mask as u32;
+        self.bar_flags[bar] = flags;
+        self.bar_write_masks[bar + 1] = (mask >> 32) as u32;
+        self.w32(bar_to_offset(bar), range.base() as u32 | flags);
+        self.w32(bar_to_offset(bar + 1), (range.base() >> 32) as u32);
+    }
+
+    pub fn set_io_bar(&mut self, bar: usize, port: u16, size: usize) {
+        assert!(size.is_power_of_two(), "cannot set_io_bar() because size is not a power of 2");
+        assert!(bar < 5, "bar is invalid value in set_io_bar()");
+        self.bar_write_masks[bar] = !((size as u32) - 1);
+        self.bar_flags[bar] = PCI_BASE_ADDRESS_SPACE_IO;
+        self.w32(bar_to_offset(bar), port as u32 | PCI_BASE_ADDRESS_SPACE_IO);
+    }
+
+    pub fn add_virtio_caps(&mut self, config_size: usize, bar: usize) {
+        self.new_virtio_cap(VIRTIO_PCI_CAP_COMMON_CFG, bar)
             .set_mmio_range(VIRTIO_MMIO_OFFSET_COMMON_CFG, VIRTIO_MMIO_COMMON_CFG_SIZE).add(self);
 
-        self.new_virtio_cap(VIRTIO_PCI_CAP_ISR_CFG)
+        self.new_virtio_cap(VIRTIO_PCI_CAP_ISR_CFG, bar)
             .set_mmio_range(VIRTIO_MMIO_OFFSET_ISR, VIRTIO_MMIO_ISR_SIZE).add(self);
 
-        self.new_virtio_cap(VIRTIO_PCI_CAP_NOTIFY_CFG)
+        self.new_virtio_cap(VIRTIO_PCI_CAP_NOTIFY_CFG, bar)
             .set_mmio_range(VIRTIO_MMIO_OFFSET_NOTIFY, VIRTIO_MMIO_NOTIFY_SIZE)
-            .set_extra_word(4).add(self);
+            .set_extra_word(VIRTIO_NOTIFY_OFF_MULTIPLIER as u32).add(self);
 
         if config_size > 0 {
-            self.new_virtio_cap(VIRTIO_PCI_CAP_DEVICE_CFG)
+            self.new_virtio_cap(VIRTIO_PCI_CAP_DEVICE_CFG, bar)
                 .set_mmio_range(VIRTIO_MMIO_OFFSET_DEV_CFG,config_size).add(self);
         }
     }
 
-    pub fn new_virtio_cap(&mut self, vtype: u8) -> VirtioCap {
-        VirtioCap::new(self.next_cap, vtype)
+    /// Add an MSI-X capability for the table of `msix`, which is at
+    /// `table_offset` in `bar` and followed by the pending bit array at
+    /// `pba_offset`.
+    pub fn add_msix_cap(&mut self, bar: usize, table_offset: usize, pba_offset: usize, msix: Arc<MsixVectors>) {
+        /*
+         * struct msix_cap {
+         *     u8 cap_id;
+         *     u8 cap_next;
+         *     le16 message_control;  /* table size - 1, function mask, enable */
+         *     le32 table;            /* offset | BIR */
+         *     le32 pba;              /* offset | BIR */
+         * };
+         */
+        let offset = self.next_cap;
+        let table_size = (msix.num_vectors() as u16 - 1) & PCI_MSIX_FLAGS_QSIZE;
+        self.w8(offset, PCI_CAP_ID_MSIX);
+        self.w16(offset + 2, table_size);
+        self.w32(offset + 4, table_offset as u32 | bar as u32);
+        self.w32(offset + 8, pba_offset as u32 | bar as u32);
+        self.inc_cap(12);
+        self.msix = Some((offset, msix));
+    }
+
+    pub fn add_shared_memory_cap(&mut self, bar: usize, id: u8, size: u64) {
+        /*
+         * struct virtio_pci_cap64 {
+         *     struct virtio_pci_cap cap;  /* cap.padding[0] is the shared memory id */
+         *     le32 offset_hi;
+         *     le32 length_hi;
+         * };
+         */
+        let offset = self.next_cap;
+        self.new_virtio_cap(VIRTIO_PCI_CAP_SHARED_MEMORY_CFG, bar)
+            .set_mmio_range(0, size as u32 as usize)
+            .set_extra_word(0)
+            .set_extra_word((size >> 32) as u32)
+            .add(self);
+        self.w8(offset + 5, id);
+    }
+
+    pub fn new_virtio_cap(&mut self, vtype: u8, bar: usize) -> VirtioCap {
+        VirtioCap::new(self.next_cap, vtype, bar as u8)
     }
 
     fn inc_cap(&mut self, size: usize) {
@@ -376,21 +566,23 @@ fn offset_to_bar(offset: usize) -> usize {
 pub struct VirtioCap {
     offset: usize,
     vtype: u8,
+    bar: u8,
     size: u8,
     mmio_offset: u32,
     mmio_len: u32,
-    extra_word: Option<u32>,
+    extra_words: Vec<u32>,
 }
 
 impl VirtioCap {
-    fn new(offset: usize, vtype: u8) -> VirtioCap {
+    fn new(offset: usize, vtype: u8, bar: u8) -> VirtioCap {
         VirtioCap {
             vtype,
+            bar,
             offset,
             size: 16,
             mmio_offset: 0,
             mmio_len: 0,
-            extra_word: None,
+            extra_words: Vec::new(),
         }
     }
 
@@ -402,7 +594,7 @@ impl VirtioCap {
 
     pub fn set_extra_word(&mut self, val: u32) -> &mut VirtioCap {
         self.size += 4;
-        self.extra_word = Some(val);
+        self.extra_words.push(val);
         self
     }
 
@@ -422,13 +614,13 @@ impl VirtioCap {
         dev.w8(self.offset, PCI_CAP_ID_VENDOR);
         dev.w8(self.offset + 2, self.size);
         dev.w8(self.offset + 3, self.vtype);
-        dev.w8(self.offset + 4, VIRTIO_MMIO_BAR as u8);
+        dev.w8(self.offset + 4, self.bar);
         if self.mmio_len > 0 {
             dev.w32(self.offset + 8, self.mmio_offset);
             dev.w32(self.offset + 12, self.mmio_len);
         }
-        if let Some(word) = self.extra_word {
-            dev.w32(self.offset + 16, word);
+        for (i, &word) in self.extra_words.iter().enumerate() {
+            dev.w32(self.offset + 16 + i * 4, word);
         }
 
         dev.inc_cap(self.size as usize);
@@ -0,0 +1,82 @@
+///
+/// Driver behavior which does not follow the virtio specification but which
+/// guest kernels in use are known to show, and which the device works around
+/// instead of failing.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Quirk {
+    /// A driver on the modern interface sets DRIVER_OK without ever setting
+    /// FEATURES_OK. The guest features are accepted when DRIVER_OK is set,
+    /// as they are for a legacy driver.
+    DriverOkWithoutFeaturesOk,
+    /// The driver writes the guest feature bits after setting FEATURES_OK.
+    /// The write is ignored and the features already accepted by the device
+    /// are kept.
+    FeaturesAfterFeaturesOk,
+    /// The driver writes a device status which clears bits which are set
+    /// without writing 0 to reset the device first. The new bits are set
+    /// and the cleared bits are kept.
+    StatusBitsCleared,
+    /// The driver accesses the device configuration with a size other than
+    /// 1, 2, 4 or 8 bytes, at an offset which is not a multiple of the size,
+    /// or past the end of the configuration. The access is ignored and a
+    /// read returns 0.
+    InvalidConfigAccess,
+}
+
+struct QuirkEntry {
+    quirk: Quirk,
+    name: &'static str,
+    workaround: &'static str,
+}
+
+const QUIRKS: &[QuirkEntry] = &[
+    QuirkEntry {
+        quirk: Quirk::DriverOkWithoutFeaturesOk,
+        name: "driver-ok-without-features-ok",
+        workaround: "features accepted at DRIVER_OK",
+    },
+    QuirkEntry {
+        quirk: Quirk::FeaturesAfterFeaturesOk,
+        name: "features-after-features-ok",
+        workaround: "feature write ignored",
+    },
+    QuirkEntry {
+        quirk: Quirk::StatusBitsCleared,
+        name: "status-bits-cleared",
+        workaround: "new status bits merged with current status",
+    },
+    QuirkEntry {
+        quirk: Quirk::InvalidConfigAccess,
+        name: "invalid-config-access",
+        workaround: "access ignored",
+    },
+];
+
+impl Quirk {
+    fn entry(self) -> &'static QuirkEntry {
+        QUIRKS.iter()
+            .find(|e| e.quirk == self)
+            .expect("every quirk has an entry")
+    }
+
+    pub fn name(self) -> &'static str {
+        self.entry().name
+    }
+
+    pub fn workaround(self) -> &'static str {
+        self.entry().workaround
+    }
+
+    // Bit recording that the quirk was seen on a device
+    pub fn bit(self) -> u32 {
+        1 << QUIRKS.iter().position(|e| e.quirk == self).unwrap_or(0)
+    }
+
+    /// Each quirk in the table whose bit is set in `bits`.
+    pub fn from_bits(bits: u32) -> impl Iterator<Item=Quirk> {
+        QUIRKS.iter()
+            .map(|e| e.quirk)
+            .filter(move |q| bits & q.bit() != 0)
+    }
+}
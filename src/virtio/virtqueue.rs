@@ -1,62 +1,127 @@
 use std::sync::atomic::{Ordering, AtomicUsize, AtomicBool};
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::memory::GuestRam;
 use crate::kvm::Kvm;
 use crate::virtio::{Result,Error};
-use crate::system::EventFd;
+use crate::system::{self, EventFd};
 use crate::kvm::IoEventFd;
 use super::consts::*;
 use super::vring::{Vring,Descriptor};
 use super::bus::VirtioDeviceConfig;
+use super::irq::IrqCounters;
+use super::msix::MsixVectors;
 use crate::virtio::chain::Chain;
+use crate::vm::replay;
+use crate::vm::metrics::{Counter, Labels};
+use crate::util::JsonValue;
+use crate::util::debug_flags::{self, DebugFlag};
+use crate::util::trace::{self, TraceSubsystem};
 
 #[derive(Clone)]
 pub struct VirtQueue {
     memory: GuestRam,
+    index: u16,
     vring: Vring,
     features: u64,
     ioeventfd: Arc<IoEventFd>,
     interrupt: Arc<InterruptLine>,
     closed: Arc<AtomicBool>,
+    notifications: Arc<Counter>,
+    labels: Arc<Labels>,
+    // Chains may be completed by several threads at once and each entry must
+    // be placed in the used ring before the used index is advanced past it.
+    used_lock: Arc<Mutex<()>>,
 }
 
 impl VirtQueue {
-    pub fn new(memory: GuestRam, vring: Vring, interrupt: Arc<InterruptLine>, ioeventfd: Arc<IoEventFd>) -> VirtQueue {
+    pub fn new(memory: GuestRam, index: u16, vring: Vring, interrupt: Arc<InterruptLine>, ioeventfd: Arc<IoEventFd>, notifications: Arc<Counter>, labels: Labels) -> VirtQueue {
         VirtQueue {
             memory,
+            index,
             vring,
             features: 0,
             ioeventfd,
             interrupt,
             closed: Arc::new(AtomicBool::new(false)),
+            notifications,
+            labels: Arc::new(labels),
+            used_lock: Arc::new(Mutex::new(())),
         }
     }
 
-    #[allow(dead_code)]
+    /// Split the queues of a device registered with `set_queue_pairs()` into
+    /// (receive, transmit) pairs, one for each pair the driver enabled.
+    pub fn into_pairs(queues: Vec<VirtQueue>) -> Vec<(VirtQueue, VirtQueue)> {
+        let mut pairs = Vec::with_capacity(queues.len() / 2);
+        let mut iter = queues.into_iter();
+        while let (Some(rx), Some(tx)) = (iter.next(), iter.next()) {
+            pairs.push((rx, tx));
+        }
+        pairs
+    }
+
+    /// Close the queue of a device which was removed from the bus. Threads
+    /// waiting on the queue wake up and `wait_ready()` fails from then on.
     pub fn set_closed(&self) {
         self.closed.store(true, Ordering::SeqCst);
         self.ioeventfd.write(1).unwrap();
     }
 
-    #[allow(dead_code)]
     pub fn is_closed(&self) -> bool {
         self.closed.load(Ordering::SeqCst)
     }
 
+    /// Raise a configuration change interrupt on the interrupt line shared
+    /// by every queue of the device, or on the configuration vector when
+    /// the driver uses MSI-X.
+    pub fn notify_config(&self) {
+        self.interrupt.notify_config();
+    }
+
     fn use_event_idx(&self) -> bool {
         self.features & VIRTIO_F_EVENT_IDX != 0
     }
 
     pub fn wait_ready(&self) -> Result<()> {
         if self.vring.is_empty() {
-            let _ = self.ioeventfd.read()
+            let _ = self.read_notifications()
                 .map_err(Error::ReadIoEventFd)?;
         }
+        if self.is_closed() {
+            return Err(Error::QueueClosed);
+        }
         Ok(())
     }
 
+    /// Wait for the driver to notify the queue and return the number of
+    /// notifications since the last call. Devices which poll the ioeventfd
+    /// of the queue call this when it is readable so that the notifications
+    /// are counted.
+    pub fn read_notifications(&self) -> system::Result<u64> {
+        let n = self.ioeventfd.read()?;
+        self.notifications.add(n as usize);
+        self.trace("notify", |event| event.with("count", n));
+        Ok(n)
+    }
+
+    fn trace<F: FnOnce(JsonValue) -> JsonValue>(&self, what: &str, fields: F) {
+        trace::event(TraceSubsystem::Virtqueue, || fields(JsonValue::object()
+            .with("device", self.labels.get("device").unwrap_or(""))
+            .with("slot", self.labels.get("slot").unwrap_or(""))
+            .with("queue", self.index)
+            .with("event", what)));
+    }
+
+    /// Labels which identify the device of this queue in `metrics`, for
+    /// counters which the device keeps itself.
+    pub fn metric_labels(&self) -> Labels {
+        (*self.labels).clone()
+    }
+
     pub fn wait_next_chain(&self) -> Result<Chain> {
         loop {
             self.wait_ready()?;
@@ -71,10 +136,15 @@ impl VirtQueue {
             .map(|idx| Chain::new(self.memory.clone(), self.clone(), idx, self.vring.size()))
     }
 
+    /// Call `f` with each chain the driver makes available until the queue
+    /// is closed.
     pub fn on_each_chain<F>(&self, mut f: F)
         where F: FnMut(Chain) {
         loop {
-            self.wait_ready().unwrap();
+            match self.wait_ready() {
+                Err(Error::QueueClosed) => return,
+                r => r.unwrap(),
+            }
             for chain in self.iter() {
                 f(chain);
             }
@@ -99,10 +169,13 @@ impl VirtQueue {
     }
 
     pub fn put_used(&self, idx: u16, len: u32) {
+        let _guard = self.used_lock.lock().unwrap();
         let used = self.vring.next_used();
         self.vring.put_used(idx, len);
-        if self.need_interrupt(used, 1) {
-            self.interrupt.notify_queue();
+        let interrupt = self.need_interrupt(used, 1);
+        self.trace("used", |event| event.with("descriptor", idx).with("len", len).with("interrupt", interrupt));
+        if interrupt {
+            self.interrupt.notify_queue(self.index);
         }
     }
 
@@ -111,15 +184,93 @@ impl VirtQueue {
             if self.use_event_idx() {
                 self.vring.write_avail_event(self.vring.next_avail());
             }
+            self.trace("avail", |event| event.with("descriptor", idx));
+            if debug_flags::is_enabled(DebugFlag::Vring) {
+                self.check_chain(idx);
+            }
             return Some(idx)
         }
         None
     }
 
+    // Walk the descriptor chain at `head` and warn about each way in which it
+    // breaks the rules of the specification. Chains are loaded leniently, so
+    // without this check a bad chain is only noticed when a request fails.
+    fn check_chain(&self, head: u16) {
+        let mut problems = Vec::new();
+        if let Err(e) = self.vring.validate() {
+            problems.push(e.to_string());
+        }
+        let size = self.vring.size();
+        let mut seen = vec![false; size as usize];
+        let mut seen_writeable = false;
+        let mut idx = head;
+        loop {
+            if idx >= size {
+                problems.push(format!("descriptor index {} is beyond the queue size {}", idx, size));
+                break;
+            }
+            if seen[idx as usize] {
+                problems.push(format!("chain loops back to descriptor {}", idx));
+                break;
+            }
+            seen[idx as usize] = true;
+            let d = match self.vring.load_descriptor(idx) {
+                Some(d) => d,
+                None => {
+                    problems.push(format!("descriptor {} is outside of guest memory", idx));
+                    break;
+                }
+            };
+            if d.is_indirect() {
+                problems.push(format!("descriptor {} is indirect", idx));
+            }
+            if d.is_write() {
+                seen_writeable = true;
+            } else if seen_writeable {
+                problems.push(format!("readable descriptor {} follows a writeable descriptor", idx));
+            }
+            if !d.has_next() {
+                break;
+            }
+            idx = d.next;
+        }
+        if !problems.is_empty() {
+            warn!("{} (slot {}) queue {}: chain at descriptor {}: {}",
+                  self.labels.get("device").unwrap_or("virtio"), self.labels.get("slot").unwrap_or("?"),
+                  self.index, head, problems.join("; "));
+        }
+    }
+
+    /// Raise the interrupt for this queue after the host kernel has placed
+    /// entries in the used ring of a queue which it processes directly.
+    pub fn notify_used(&self) {
+        self.interrupt.notify_queue(self.index);
+    }
+
+    pub fn size(&self) -> u16 {
+        self.vring.size()
+    }
+
+    /// Index in the available ring of the next entry to be processed.
+    pub fn next_avail(&self) -> u16 {
+        self.vring.next_avail()
+    }
+
+    /// Guest addresses of the descriptor table, available ring and used ring.
+    pub fn ring_addresses(&self) -> (u64, u64, u64) {
+        (self.vring.descriptors, self.vring.avail_ring, self.vring.used_ring)
+    }
+
     pub fn load_descriptor(&self, idx: u16) -> Option<Descriptor> {
         self.vring.load_descriptor(idx)
     }
 
+    /// Guest address of the descriptor table, which identifies this queue in replay logs.
+    pub fn descriptor_table(&self) -> u64 {
+        self.vring.descriptors
+    }
+
     pub fn ioevent(&self) -> &IoEventFd {
         &self.ioeventfd
     }
@@ -140,37 +291,182 @@ impl Iterator for QueueIter {
 }
 
 
+// How often the thread of an interrupt batch checks whether the device is gone
+const BATCH_IDLE_CHECK: Duration = Duration::from_secs(1);
+
+///
+/// Queue interrupts held back until the batching window of a device ends,
+/// so that a burst of completions raises one interrupt per queue instead of
+/// one for each completion.
+///
+/// The window starts at the first completion after the last interrupt, so
+/// no completion waits longer than the window for its interrupt.
+///
+struct InterruptBatch {
+    window: Duration,
+    // The time the first held back interrupt was requested and the queues
+    // which have completions
+    pending: Mutex<Option<(Instant, Vec<u16>)>>,
+    cond: Condvar,
+}
+
+impl InterruptBatch {
+    // Returns `false` if the interrupt joined a batch already waiting
+    fn add(&self, queue: u16) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        match *pending {
+            Some((_, ref mut queues)) => {
+                if !queues.contains(&queue) {
+                    queues.push(queue);
+                }
+                false
+            }
+            None => {
+                *pending = Some((Instant::now(), vec![queue]));
+                self.cond.notify_one();
+                true
+            }
+        }
+    }
+
+    fn run(&self, line: Weak<InterruptLine>) {
+        loop {
+            let start = {
+                let mut pending = self.pending.lock().unwrap();
+                while pending.is_none() {
+                    pending = self.cond.wait_timeout(pending, BATCH_IDLE_CHECK).unwrap().0;
+                    if pending.is_none() && line.upgrade().is_none() {
+                        return;
+                    }
+                }
+                pending.as_ref().map(|&(start, _)| start).unwrap()
+            };
+            let elapsed = start.elapsed();
+            if elapsed < self.window {
+                thread::sleep(self.window - elapsed);
+            }
+            let queues = match self.pending.lock().unwrap().take() {
+                Some((_, queues)) => queues,
+                None => continue,
+            };
+            let line = match line.upgrade() {
+                Some(line) => line,
+                None => return,
+            };
+            line.counters.record_batch(start.elapsed());
+            for queue in queues {
+                line.raise_queue(queue);
+            }
+        }
+    }
+}
+
 pub struct InterruptLine {
     irqfd: EventFd,
+    irq: u8,
     isr: AtomicUsize,
+    counters: Arc<IrqCounters>,
+    msix: Option<Arc<MsixVectors>>,
+    batch: Option<Arc<InterruptBatch>>,
 }
 
 impl InterruptLine {
     pub fn from_config(conf: &VirtioDeviceConfig) -> Result<Arc<InterruptLine>> {
-        InterruptLine::new(conf.kvm(), conf.irq())
+        InterruptLine::new(conf.kvm(), conf.irq(), conf.irq_counters(), conf.msix(), conf.interrupt_window())
     }
 
-    fn new(kvm: &Kvm, irq: u8) -> Result<Arc<InterruptLine>> {
+    fn new(kvm: &Kvm, irq: u8, counters: Arc<IrqCounters>, msix: Option<Arc<MsixVectors>>, window: Option<Duration>) -> Result<Arc<InterruptLine>> {
         let irqfd = EventFd::new().map_err(Error::CreateEventFd)?;
         kvm.irqfd(irqfd.as_raw_fd() as u32, irq as u32)
             .map_err(Error::IrqFd)?;
-        Ok(Arc::new(InterruptLine{
+        let batch = window.map(|window| {
+            counters.set_batch_window(window);
+            Arc::new(InterruptBatch { window, pending: Mutex::new(None), cond: Condvar::new() })
+        });
+        let line = Arc::new(InterruptLine{
             irqfd,
-            isr: AtomicUsize::new(0)
-        }))
+            irq,
+            isr: AtomicUsize::new(0),
+            counters,
+            msix,
+            batch: batch.clone(),
+        });
+        if let Some(batch) = batch {
+            let weak = Arc::downgrade(&line);
+            thread::spawn(move || batch.run(weak));
+        }
+        Ok(line)
     }
 
+    /// Read and clear the ISR from the guest interrupt handler.
     pub fn isr_read(&self) -> u64 {
+        let isr = self.isr_clear();
+        self.counters.record_isr_read(isr);
+        isr
+    }
+
+    pub fn isr_clear(&self) -> u64 {
         self.isr.swap(0, Ordering::SeqCst) as u64
     }
 
-    pub fn notify_queue(&self) {
+    /// Read the ISR without clearing it, as the virtio-mmio transport does.
+    /// The driver clears the bits it has handled with `isr_ack()`.
+    pub fn isr_status(&self) -> u64 {
+        let isr = self.isr.load(Ordering::SeqCst) as u64;
+        self.counters.record_isr_read(isr);
+        isr
+    }
+
+    pub fn isr_ack(&self, bits: u64) {
+        self.isr.fetch_and(!(bits as usize), Ordering::SeqCst);
+    }
+
+    // While MSI-X is enabled the interrupt is a message on the vector the
+    // driver assigned, and the ISR and the INTx line are not used. Returns
+    // false if the driver uses the interrupt line instead.
+    fn notify_msix<F>(&self, vector: F) -> bool
+        where F: FnOnce(&MsixVectors) -> u16
+    {
+        let msix = match self.msix {
+            Some(ref msix) if msix.is_enabled() => msix,
+            _ => return false,
+        };
+        let vector = vector(msix);
+        if vector != VIRTIO_NO_MSI_VECTOR {
+            self.counters.record_raised();
+            msix.signal(vector);
+        }
+        true
+    }
+
+    /// Raise the interrupt of `queue`, at the end of the batching window if
+    /// the device has one.
+    pub fn notify_queue(&self, queue: u16) {
+        match self.batch {
+            Some(ref batch) => if !batch.add(queue) {
+                self.counters.record_coalesced();
+            },
+            None => self.raise_queue(queue),
+        }
+    }
+
+    fn raise_queue(&self, queue: u16) {
+        if self.notify_msix(|msix| msix.queue_vector(queue)) {
+            return;
+        }
+        replay::interrupt(self.irq, 0x1);
         self.isr.fetch_or(0x1, Ordering::SeqCst);
+        self.counters.record_raised();
         self.irqfd.write(1).unwrap();
     }
 
     pub fn notify_config(&self) {
+        if self.notify_msix(|msix| msix.config_vector()) {
+            return;
+        }
+        replay::interrupt(self.irq, 0x2);
         self.isr.fetch_or(0x2, Ordering::SeqCst);
+        self.counters.record_raised();
         self.irqfd.write(1).unwrap();
     }
 }
@@ -98,6 +98,13 @@ impl Vring {
         self.queue_size
     }
 
+    ///
+    /// Queue size offered by the device, the largest size the driver may set
+    ///
+    pub fn default_size(&self) -> u16 {
+        self.default_size
+    }
+
     ///
     /// Set the queue size of this `Vring`.  If `sz` is an invalid value
     /// ignore the request.  It is illegal to change the queue size after
@@ -113,6 +120,24 @@ impl Vring {
         self.queue_size = sz;
     }
 
+    ///
+    /// Set the ring addresses from the base address of a legacy (virtio 0.9.5)
+    /// vring where the descriptor table, available ring, and used ring are laid
+    /// out contiguously and the used ring is aligned to `align` bytes. Ignored
+    /// if the `Vring` has been enabled.
+    ///
+    pub fn set_legacy_layout(&mut self, base: u64, align: u64) {
+        if self.enabled {
+            return;
+        }
+        let size = self.queue_size as u64;
+        self.descriptors = base;
+        self.avail_ring = base + 16 * size;
+        // flags, idx, ring[size], used_event
+        let avail_end = self.avail_ring + 6 + 2 * size;
+        self.used_ring = (avail_end + align - 1) & !(align - 1);
+    }
+
     ///
     /// Reset `Vring` to the initial state.  `queue_size` is set to the `default_size`
     /// and all other fields are cleared.  `enabled` is set to false.
@@ -253,9 +278,14 @@ impl Vring {
     ///
     /// Load the descriptor table entry at `idx` from guest memory and return it.
     ///
+    /// Returns `None` if `idx` is outside of the descriptor table, or if the
+    /// buffer of the descriptor is not in guest memory. The address of a
+    /// zero-length buffer is never used so it is not checked, and `next` is
+    /// only checked when the chain is followed.
+    ///
     pub fn load_descriptor(&self, idx: u16) -> Option<Descriptor> {
         if idx >= self.queue_size {
-            panic!("load_descriptor called with index larger than queue size");
+            return None;
         }
         let head = self.descriptors + (idx as u64 * 16);
 
@@ -264,7 +294,7 @@ impl Vring {
         let flags = self.memory.read_int::<u16>(head + 12).unwrap();
         let next = self.memory.read_int::<u16>(head + 14).unwrap();
 
-        if self.memory.is_valid_range(addr, len as usize) && next < self.queue_size {
+        if len == 0 || self.memory.is_valid_range(addr, len as usize) {
             return Some(Descriptor::new(idx, addr, len, flags, next));
         }
         None
@@ -312,6 +342,12 @@ impl Descriptor {
         Descriptor{ idx, addr, len, flags, next }
     }
 
+    /// A descriptor for a buffer which is not in a descriptor table.
+    pub(crate) fn buffer(addr: u64, len: u32, writeable: bool) -> Descriptor {
+        let flags = if writeable { VRING_DESC_F_WRITE } else { 0 };
+        Descriptor::new(0, addr, len, flags, 0)
+    }
+
     ///
     /// Test if `flag` is set in `self.flags`
     ///
@@ -336,7 +372,6 @@ impl Descriptor {
     ///
     /// Is VRING_DESC_F_INDIRECT set in `self.flags`?
     ///
-    #[allow(dead_code)]
     pub fn is_indirect(&self) -> bool {
         self.has_flag(VRING_DESC_F_INDIRECT)
     }
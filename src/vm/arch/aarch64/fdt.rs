@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::memory::AddressRange;
+use crate::vm::arch::aarch64::gic::GIC_NR_IRQS;
+use crate::vm::arch::aarch64::memory::{GIC_DIST_BASE, GIC_DIST_SIZE, GIC_REDIST_BASE, GIC_REDIST_SIZE, RAM_BASE};
+use crate::vm::kernel_cmdline::KernelCmdLine;
+
+// Documentation/devicetree/booting-without-of.txt and the devicetree
+// specification
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+// An empty memory reservation map is a single entry of zeros
+const FDT_RSVMAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const GIC_PHANDLE: u32 = 1;
+
+// The cells of an interrupt of the GIC are its type, number and flags
+const GIC_SPI: u32 = 0;
+const GIC_PPI: u32 = 1;
+const IRQ_TYPE_EDGE_RISING: u32 = 1;
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+const GIC_MAINTENANCE_PPI: u32 = 9;
+// Secure, non-secure, virtual and hypervisor timers
+const TIMER_PPIS: [u32; 4] = [13, 14, 11, 10];
+
+///
+/// The tables describing the platform which are written into guest memory
+/// for the guest kernel. An arm64 guest is only given a device tree.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum BootTables {
+    DeviceTree,
+}
+
+impl BootTables {
+    /// Parse `fdt`.
+    pub fn parse(s: &str) -> Option<BootTables> {
+        match s {
+            "fdt" => Some(BootTables::DeviceTree),
+            _ => None,
+        }
+    }
+
+    pub fn has_mptable(self) -> bool {
+        false
+    }
+
+    pub fn has_acpi(self) -> bool {
+        false
+    }
+}
+
+impl Default for BootTables {
+    fn default() -> Self {
+        BootTables::DeviceTree
+    }
+}
+
+// KVM gives vcpu N an MPIDR with the low four bits of N in affinity level 0
+// and the rest in level 1
+fn vcpu_mpidr(id: usize) -> u32 {
+    let id = id as u32;
+    ((id >> 4) & 0xff) << 8 | (id & 0xf)
+}
+
+/// Create the flattened device tree describing a VM with `ram_size` bytes
+/// of RAM and `ncpus` vcpus which boots with `cmdline` and `initrd`.
+pub fn create_fdt(ram_size: usize, ncpus: usize, cmdline: &KernelCmdLine, initrd: Option<(u64, usize)>, mmio_devices: &[(AddressRange, u8)]) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_string("compatible", "linux,dummy-virt");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE);
+
+    fdt.begin_node("chosen");
+    let mut bootargs = cmdline.as_bytes().to_vec();
+    bootargs.push(0);
+    fdt.property("bootargs", &bootargs);
+    if let Some((address, size)) = initrd {
+        fdt.property_u64s("linux,initrd-start", &[address]);
+        fdt.property_u64s("linux,initrd-end", &[address + size as u64]);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", RAM_BASE));
+    fdt.property_string("device_type", "memory");
+    fdt.property_u64s("reg", &[RAM_BASE, ram_size as u64]);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    for id in 0..ncpus {
+        let mpidr = vcpu_mpidr(id);
+        fdt.begin_node(&format!("cpu@{:x}", mpidr));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_string("compatible", "arm,arm-v8");
+        fdt.property_string("enable-method", "psci");
+        fdt.property_u32("reg", mpidr);
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("psci");
+    fdt.property_string("compatible", "arm,psci-0.2");
+    fdt.property_string("method", "hvc");
+    fdt.end_node();
+
+    fdt.begin_node(&format!("intc@{:x}", GIC_DIST_BASE));
+    fdt.property_string("compatible", "arm,gic-v3");
+    fdt.property_null("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 3);
+    fdt.property_u64s("reg", &[GIC_DIST_BASE, GIC_DIST_SIZE, GIC_REDIST_BASE, GIC_REDIST_SIZE * ncpus as u64]);
+    fdt.property_cells("interrupts", &[GIC_PPI, GIC_MAINTENANCE_PPI, IRQ_TYPE_LEVEL_HIGH]);
+    fdt.property_u32("phandle", GIC_PHANDLE);
+    fdt.end_node();
+
+    fdt.begin_node("timer");
+    fdt.property_string("compatible", "arm,armv8-timer");
+    let cells: Vec<u32> = TIMER_PPIS.iter()
+        .flat_map(|&ppi| vec![GIC_PPI, ppi, IRQ_TYPE_LEVEL_HIGH])
+        .collect();
+    fdt.property_cells("interrupts", &cells);
+    fdt.property_null("always-on");
+    fdt.end_node();
+
+    // The interrupt line of a device is signalled with an irqfd, which KVM
+    // delivers as an edge on the shared interrupt of the same number
+    for &(area, irq) in mmio_devices {
+        debug_assert!(u32::from(irq) < GIC_NR_IRQS - 32);
+        fdt.begin_node(&format!("virtio_mmio@{:x}", area.base()));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_u64s("reg", &[area.base(), area.size() as u64]);
+        fdt.property_cells("interrupts", &[GIC_SPI, u32::from(irq), IRQ_TYPE_EDGE_RISING]);
+        fdt.property_null("dma-coherent");
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+// Writes the structure block and strings block of a device tree, which are
+// joined with a header by `finish()`
+struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+}
+
+impl FdtWriter {
+    fn new() -> FdtWriter {
+        FdtWriter { structure: Vec::new(), strings: Vec::new(), string_offsets: HashMap::new() }
+    }
+
+    fn w32(&mut self, val: u32) {
+        self.structure.write_u32::<BigEndian>(val).unwrap();
+    }
+
+    // Each token starts on a 4 byte boundary
+    fn align(&mut self) {
+        while self.structure.len() % 4 != 0 {
+            self.structure.push(0);
+        }
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.w32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    fn end_node(&mut self) {
+        self.w32(FDT_END_NODE);
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(&offset) = self.string_offsets.get(name) {
+            return offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.w32(FDT_PROP);
+        self.w32(value.len() as u32);
+        self.w32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    fn property_string(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+
+    fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let mut bytes = Vec::with_capacity(cells.len() * 4);
+        for &cell in cells {
+            bytes.write_u32::<BigEndian>(cell).unwrap();
+        }
+        self.property(name, &bytes);
+    }
+
+    // With #address-cells and #size-cells of 2 each address and size is
+    // a pair of cells, the same as a big endian u64
+    fn property_u64s(&mut self, name: &str, values: &[u64]) {
+        let mut bytes = Vec::with_capacity(values.len() * 8);
+        for &value in values {
+            bytes.write_u64::<BigEndian>(value).unwrap();
+        }
+        self.property(name, &bytes);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.w32(FDT_END);
+        let off_rsvmap = FDT_HEADER_SIZE;
+        let off_struct = off_rsvmap + FDT_RSVMAP_SIZE;
+        let off_strings = off_struct + self.structure.len();
+        let total = off_strings + self.strings.len();
+
+        let mut fdt = Vec::with_capacity(total);
+        for &val in &[
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0,      // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            fdt.write_u32::<BigEndian>(val).unwrap();
+        }
+        fdt.extend_from_slice(&[0; FDT_RSVMAP_SIZE]);
+        fdt.extend_from_slice(&self.structure);
+        fdt.extend_from_slice(&self.strings);
+        fdt
+    }
+}
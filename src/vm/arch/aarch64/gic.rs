@@ -0,0 +1,82 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::kvm::Kvm;
+use crate::system::FileDesc;
+use crate::vm::arch::Result;
+use crate::vm::arch::aarch64::ioctl::{
+    call_ioctl_with_ref, call_ioctl_with_mut_ref, KVM_CREATE_DEVICE, KVM_SET_DEVICE_ATTR,
+};
+use crate::vm::arch::aarch64::memory::{GIC_DIST_BASE, GIC_REDIST_BASE};
+
+const KVM_DEV_TYPE_ARM_VGIC_V3: u32 = 7;
+
+const KVM_DEV_ARM_VGIC_GRP_ADDR: u32 = 0;
+const KVM_DEV_ARM_VGIC_GRP_NR_IRQS: u32 = 3;
+const KVM_DEV_ARM_VGIC_GRP_CTRL: u32 = 4;
+
+const KVM_VGIC_V3_ADDR_TYPE_DIST: u64 = 2;
+const KVM_VGIC_V3_ADDR_TYPE_REDIST: u64 = 3;
+const KVM_DEV_ARM_VGIC_CTRL_INIT: u64 = 0;
+
+/// Interrupts handled by the GIC, including the 32 private interrupts of
+/// each vcpu. The interrupt lines of devices are the shared interrupts
+/// above those.
+pub const GIC_NR_IRQS: u32 = 128;
+
+#[repr(C)]
+#[derive(Default)]
+struct KvmCreateDevice {
+    dtype: u32,
+    fd: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct KvmDeviceAttr {
+    flags: u32,
+    group: u32,
+    attr: u64,
+    addr: u64,
+}
+
+///
+/// The GICv3 interrupt controller emulated by KVM, with the distributor at
+/// `GIC_DIST_BASE` and a redistributor for each vcpu from `GIC_REDIST_BASE`.
+///
+/// The controller is created before the vcpus and initialized with
+/// `finalize()` once all of them have been created.
+///
+pub struct Gic {
+    fd: FileDesc,
+}
+
+impl Gic {
+    pub fn create(kvm: &Kvm) -> Result<Gic> {
+        let mut device = KvmCreateDevice { dtype: KVM_DEV_TYPE_ARM_VGIC_V3, ..Default::default() };
+        call_ioctl_with_mut_ref("KVM_CREATE_DEVICE", kvm.vmfd(), KVM_CREATE_DEVICE, &mut device)?;
+        let gic = Gic { fd: FileDesc::new(device.fd as RawFd) };
+
+        gic.set_address(KVM_VGIC_V3_ADDR_TYPE_DIST, GIC_DIST_BASE)?;
+        gic.set_address(KVM_VGIC_V3_ADDR_TYPE_REDIST, GIC_REDIST_BASE)?;
+        let nr_irqs = GIC_NR_IRQS;
+        gic.set_attr(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0, &nr_irqs as *const u32 as u64)?;
+        Ok(gic)
+    }
+
+    fn set_address(&self, addr_type: u64, address: u64) -> Result<()> {
+        self.set_attr(KVM_DEV_ARM_VGIC_GRP_ADDR, addr_type, &address as *const u64 as u64)
+    }
+
+    // `addr` is the address of the value in this process
+    fn set_attr(&self, group: u32, attr: u64, addr: u64) -> Result<()> {
+        let attr = KvmDeviceAttr { flags: 0, group, attr, addr };
+        call_ioctl_with_ref("KVM_SET_DEVICE_ATTR", self.fd.as_raw_fd(), KVM_SET_DEVICE_ATTR, &attr)
+    }
+
+    /// Initialize the controller, which KVM only allows once every vcpu
+    /// has been created and before any of them runs.
+    pub fn finalize(&self) -> Result<()> {
+        self.set_attr(KVM_DEV_ARM_VGIC_GRP_CTRL, KVM_DEV_ARM_VGIC_CTRL_INIT, 0)
+    }
+}
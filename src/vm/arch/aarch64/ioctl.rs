@@ -0,0 +1,12 @@
+use libc::c_ulong;
+
+pub use crate::vm::arch::ioctl::{call_ioctl_with_ref, call_ioctl_with_mut_ref};
+
+const KVMIO:     u64 = 0xAE;
+
+pub const KVM_GET_ONE_REG: c_ulong               = iow!    (KVMIO, 0xab, 16);
+pub const KVM_SET_ONE_REG: c_ulong               = iow!    (KVMIO, 0xac, 16);
+pub const KVM_ARM_VCPU_INIT: c_ulong             = iow!    (KVMIO, 0xae, 32);
+pub const KVM_ARM_PREFERRED_TARGET: c_ulong      = ior!    (KVMIO, 0xaf, 32);
+pub const KVM_CREATE_DEVICE: c_ulong             = iorw!   (KVMIO, 0xe0, 12);
+pub const KVM_SET_DEVICE_ATTR: c_ulong           = iow!    (KVMIO, 0xe1, 24);
@@ -0,0 +1,90 @@
+use crate::memory::GuestRam;
+use crate::util::ByteBuffer;
+use crate::vm::arch::{Error, Result};
+use crate::vm::arch::images::BootImages;
+use crate::vm::arch::aarch64::memory::RAM_BASE;
+
+// Documentation/arm64/booting.rst
+
+const HDR_TEXT_OFFSET: usize = 0x08;   // u64
+const HDR_IMAGE_SIZE: usize  = 0x10;   // u64
+const HDR_MAGIC: usize       = 0x38;   // u32
+const HDR_SIZE: usize        = 0x40;
+
+const ARM64_IMAGE_MAGIC: u32 = 0x644d5241;
+// Kernels older than 3.17 leave image_size at 0 and expect this offset
+const DEFAULT_TEXT_OFFSET: u64 = 0x80000;
+// COMMAND_LINE_SIZE of arm64 less the terminating nul
+const CMDLINE_MAX: usize = 2047;
+
+const MB: u64 = 1 << 20;
+// The device tree may be at most 2 MiB and must not cross a 2 MiB boundary
+pub const FDT_MAX_SIZE: u64 = 2 * MB;
+
+/// How the boot vcpu enters the kernel.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct BootEntry {
+    /// Address of the first instruction of the kernel image.
+    pub pc: u64,
+    /// Address of the device tree, passed in x0.
+    pub fdt: u64,
+}
+
+pub struct LoadedKernel {
+    pub entry: BootEntry,
+    /// Address and size of the initrd, if there is one.
+    pub initrd: Option<(u64, usize)>,
+}
+
+/// Load the arm64 `Image` kernel of `images` at the start of RAM and the
+/// initrd below the device tree at the end of RAM. `cmdline_size` includes
+/// the terminating nul.
+pub fn load_kernel(memory: &GuestRam, images: &BootImages, cmdline_size: usize) -> Result<LoadedKernel> {
+    let image = images.kernel();
+    if image.len() < HDR_SIZE {
+        return Err(Error::InvalidKernel("not an arm64 Image kernel"));
+    }
+    let hdr = ByteBuffer::from_bytes(image);
+    if hdr.read_at::<u32>(HDR_MAGIC) != ARM64_IMAGE_MAGIC {
+        return Err(Error::InvalidKernel("not an arm64 Image kernel"));
+    }
+    if cmdline_size - 1 > CMDLINE_MAX {
+        return Err(Error::CmdlineTooLong(cmdline_size - 1, CMDLINE_MAX));
+    }
+    let (text_offset, image_size) = match hdr.read_at::<u64>(HDR_IMAGE_SIZE) {
+        0 => (DEFAULT_TEXT_OFFSET, image.len() as u64),
+        size => (hdr.read_at::<u64>(HDR_TEXT_OFFSET), size),
+    };
+
+    let ram_end = RAM_BASE + memory.ram_size() as u64;
+    let fdt = ram_end.saturating_sub(FDT_MAX_SIZE) & !(FDT_MAX_SIZE - 1);
+    // RAM_BASE is 2 MiB aligned, as the kernel requires
+    let pc = RAM_BASE + text_offset;
+    let kernel_end = pc + image_size;
+    if kernel_end > fdt {
+        return Err(Error::InvalidKernel("kernel does not fit in guest memory"));
+    }
+    memory.write_bytes(pc, image)
+        .map_err(Error::LoadKernel)?;
+
+    let initrd = match images.initrd() {
+        Some(initrd) => Some(load_initrd(memory, initrd, kernel_end, fdt)?),
+        None => None,
+    };
+    Ok(LoadedKernel { entry: BootEntry { pc, fdt }, initrd })
+}
+
+// The initrd is placed as high as it fits below the device tree, on a page
+// boundary.
+fn load_initrd(memory: &GuestRam, initrd: &[u8], kernel_end: u64, top: u64) -> Result<(u64, usize)> {
+    let address = match top.checked_sub(initrd.len() as u64) {
+        Some(address) => address & !0xfff,
+        None => return Err(Error::InitrdTooLarge(initrd.len())),
+    };
+    if address < kernel_end {
+        return Err(Error::InitrdTooLarge(initrd.len()));
+    }
+    memory.write_bytes(address, initrd)
+        .map_err(Error::LoadKernel)?;
+    Ok((address, initrd.len()))
+}
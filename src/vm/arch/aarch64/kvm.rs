@@ -0,0 +1,25 @@
+use crate::kvm::{Kvm, KVM_CAP_IOEVENTFD, KVM_CAP_IRQ_ROUTING, KVM_CAP_USER_MEMORY, KVM_CAP_IRQCHIP};
+use crate::vm::arch::{Result,Error};
+
+const KVM_CAP_IRQFD: u32 = 32;
+const KVM_CAP_ONE_REG: u32 = 70;
+const KVM_CAP_DEVICE_CTRL: u32 = 89;
+const KVM_CAP_ARM_PSCI_0_2: u32 = 102;
+
+static REQUIRED_EXTENSIONS: &[u32] = &[
+    KVM_CAP_IRQCHIP,
+    KVM_CAP_USER_MEMORY,
+    KVM_CAP_IRQ_ROUTING,
+    KVM_CAP_IRQFD,
+    KVM_CAP_IOEVENTFD,
+    KVM_CAP_ONE_REG,
+    KVM_CAP_DEVICE_CTRL,
+    KVM_CAP_ARM_PSCI_0_2,
+];
+
+// The interrupt controller is created separately, as its registers are
+// placed according to the number of vcpus
+pub fn aarch64_open_kvm() -> Result<Kvm> {
+    Kvm::open(REQUIRED_EXTENSIONS)
+        .map_err(Error::OpenKvm)
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+
+use crate::memory::{AddressRange, MemoryManager, HugePages};
+use crate::vm::arch::{Error, Result};
+use crate::vm::arch::images::BootImages;
+use crate::vm::arch::ram::setup_ram_regions;
+use crate::vm::arch::aarch64::fdt::create_fdt;
+use crate::vm::arch::aarch64::kernel::{load_kernel, BootEntry};
+use crate::vm::kernel_cmdline::KernelCmdLine;
+
+// The devices are placed below RAM as on the QEMU virt machine, so that
+// guest kernels configured for it find nothing unexpected.
+
+pub const GIC_DIST_BASE: u64 = 0x0800_0000;
+pub const GIC_DIST_SIZE: u64 = 0x1_0000;
+pub const GIC_REDIST_BASE: u64 = 0x080a_0000;
+// Two 64K frames for each vcpu
+pub const GIC_REDIST_SIZE: u64 = 0x2_0000;
+
+pub const PCI_MMIO_RESERVED_BASE: u64 = 0x1000_0000;
+pub const PCI_MMIO_RESERVED_SIZE: usize = 512 << 20;
+
+pub const RAM_BASE: u64 = 0x8000_0000;
+
+/// Create the guest RAM region, which starts at `RAM_BASE`. See
+/// `setup_ram_regions()`.
+pub fn aarch64_setup_memory_regions(memory: &mut MemoryManager, ram_size: usize, ram_file: Option<&Path>, keep_file: bool, share_ram: bool, huge_pages: HugePages) -> Result<()> {
+    setup_ram_regions(memory, &[(RAM_BASE, ram_size)], ram_file, keep_file, share_ram, huge_pages)
+}
+
+/// Load the kernel and initrd and write the device tree which describes
+/// the vcpus, RAM, interrupt controller and virtio-mmio devices.
+pub fn aarch64_setup_memory(memory: &mut MemoryManager, images: &BootImages, cmdline: &KernelCmdLine, ncpus: usize, mmio_devices: &[(AddressRange, u8)]) -> Result<BootEntry> {
+    let ram = memory.guest_ram();
+    let loaded = load_kernel(ram, images, cmdline.size())?;
+    let fdt = create_fdt(ram.ram_size(), ncpus, cmdline, loaded.initrd, mmio_devices);
+    ram.write_bytes(loaded.entry.fdt, &fdt)
+        .map_err(Error::LoadKernel)?;
+    Ok(loaded.entry)
+}
@@ -0,0 +1,14 @@
+mod fdt;
+mod gic;
+mod ioctl;
+mod kernel;
+mod kvm;
+mod memory;
+mod registers;
+mod setup;
+mod state;
+
+pub use setup::Aarch64ArchSetup;
+pub use fdt::BootTables;
+pub use memory::{PCI_MMIO_RESERVED_BASE, PCI_MMIO_RESERVED_SIZE};
+pub use state::VcpuState;
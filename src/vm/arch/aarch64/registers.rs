@@ -0,0 +1,132 @@
+use std::fmt;
+use std::os::unix::io::RawFd;
+
+use crate::kvm::{Kvm, KvmVcpu};
+use crate::vm::arch::Result;
+use crate::vm::arch::aarch64::ioctl::{
+    call_ioctl_with_ref, call_ioctl_with_mut_ref, KVM_GET_ONE_REG, KVM_SET_ONE_REG,
+    KVM_ARM_VCPU_INIT, KVM_ARM_PREFERRED_TARGET,
+};
+use crate::vm::arch::aarch64::kernel::BootEntry;
+
+const KVM_ARM_VCPU_POWER_OFF: u32 = 0;
+const KVM_ARM_VCPU_PSCI_0_2: u32 = 2;
+
+// The id of a register in `struct kvm_regs` is its offset in 32 bit words
+const KVM_REG_ARM64: u64 = 0x6000_0000_0000_0000;
+const KVM_REG_SIZE_U64: u64 = 0x0030_0000_0000_0000;
+const KVM_REG_ARM_CORE: u64 = 0x0010 << 16;
+
+const CORE_REG_X0: u64 = 0;
+const CORE_REG_SP: u64 = 31 * 8;
+const CORE_REG_PC: u64 = 32 * 8;
+const CORE_REG_PSTATE: u64 = 33 * 8;
+
+// EL1h with debug, SError, IRQ and FIQ exceptions masked
+const PSTATE_BOOT: u64 = 0x3c5;
+
+fn core_reg_id(offset: u64) -> u64 {
+    KVM_REG_ARM64 | KVM_REG_SIZE_U64 | KVM_REG_ARM_CORE | (offset / 4)
+}
+
+#[repr(C)]
+struct KvmOneReg {
+    id: u64,
+    addr: u64,
+}
+
+fn kvm_get_one_reg(cpufd: RawFd, offset: u64) -> Result<u64> {
+    let mut value = 0u64;
+    let reg = KvmOneReg { id: core_reg_id(offset), addr: &mut value as *mut u64 as u64 };
+    call_ioctl_with_ref("KVM_GET_ONE_REG", cpufd, KVM_GET_ONE_REG, &reg)?;
+    Ok(value)
+}
+
+fn kvm_set_one_reg(cpufd: RawFd, offset: u64, value: u64) -> Result<()> {
+    let reg = KvmOneReg { id: core_reg_id(offset), addr: &value as *const u64 as u64 };
+    call_ioctl_with_ref("KVM_SET_ONE_REG", cpufd, KVM_SET_ONE_REG, &reg)
+}
+
+/// `struct kvm_vcpu_init`
+#[repr(C)]
+#[derive(Default)]
+struct KvmVcpuInit {
+    target: u32,
+    features: [u32; 7],
+}
+
+///
+/// Initialize `vcpu` as the cpu type KVM prefers for this host with PSCI 0.2.
+/// Every vcpu except the boot vcpu starts powered off until the guest kernel
+/// turns it on with a PSCI `CPU_ON` call.
+///
+pub fn setup_vcpu_init(kvm: &Kvm, vcpu: &KvmVcpu) -> Result<()> {
+    let mut init = KvmVcpuInit::default();
+    call_ioctl_with_mut_ref("KVM_ARM_PREFERRED_TARGET", kvm.vmfd(), KVM_ARM_PREFERRED_TARGET, &mut init)?;
+    init.features = [0; 7];
+    init.features[0] |= 1 << KVM_ARM_VCPU_PSCI_0_2;
+    if vcpu.id() > 0 {
+        init.features[0] |= 1 << KVM_ARM_VCPU_POWER_OFF;
+    }
+    call_ioctl_with_ref("KVM_ARM_VCPU_INIT", vcpu.raw_fd(), KVM_ARM_VCPU_INIT, &init)
+}
+
+/// Enter the kernel as arm64 `booting.rst` requires, with the address of
+/// the device tree in x0 and x1-x3 zero.
+pub fn setup_regs(vcpu: &KvmVcpu, entry: BootEntry) -> Result<()> {
+    kvm_set_one_reg(vcpu.raw_fd(), CORE_REG_PSTATE, PSTATE_BOOT)?;
+    kvm_set_one_reg(vcpu.raw_fd(), CORE_REG_PC, entry.pc)?;
+    kvm_set_one_reg(vcpu.raw_fd(), CORE_REG_X0, entry.fdt)?;
+    for n in 1..4 {
+        kvm_set_one_reg(vcpu.raw_fd(), CORE_REG_X0 + n * 8, 0)?;
+    }
+    Ok(())
+}
+
+/// The general purpose registers of a vcpu, which KVM on arm64 only exposes
+/// one at a time.
+#[derive(Copy, Clone, Default)]
+pub struct KvmRegs {
+    pub regs: [u64; 31],
+    pub sp: u64,
+    pub pc: u64,
+    pub pstate: u64,
+}
+
+impl fmt::Debug for KvmRegs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (n, chunk) in self.regs.chunks(4).enumerate() {
+            for (i, reg) in chunk.iter().enumerate() {
+                write!(f, "x{:<2} 0x{:x} ", n * 4 + i, reg)?;
+            }
+            write!(f, "\n")?;
+        }
+        write!(f, "sp 0x{:x} pc 0x{:x} pstate 0x{:x}\n", self.sp, self.pc, self.pstate)
+    }
+}
+
+impl KvmRegs {
+    pub fn new() -> KvmRegs {
+        KvmRegs { ..Default::default() }
+    }
+}
+
+pub fn kvm_get_regs(cpufd: RawFd) -> Result<KvmRegs> {
+    let mut regs = KvmRegs::new();
+    for n in 0..regs.regs.len() {
+        regs.regs[n] = kvm_get_one_reg(cpufd, CORE_REG_X0 + n as u64 * 8)?;
+    }
+    regs.sp = kvm_get_one_reg(cpufd, CORE_REG_SP)?;
+    regs.pc = kvm_get_one_reg(cpufd, CORE_REG_PC)?;
+    regs.pstate = kvm_get_one_reg(cpufd, CORE_REG_PSTATE)?;
+    Ok(regs)
+}
+
+pub fn kvm_set_regs(cpufd: RawFd, regs: &KvmRegs) -> Result<()> {
+    for (n, &value) in regs.regs.iter().enumerate() {
+        kvm_set_one_reg(cpufd, CORE_REG_X0 + n as u64 * 8, value)?;
+    }
+    kvm_set_one_reg(cpufd, CORE_REG_SP, regs.sp)?;
+    kvm_set_one_reg(cpufd, CORE_REG_PC, regs.pc)?;
+    kvm_set_one_reg(cpufd, CORE_REG_PSTATE, regs.pstate)
+}
@@ -0,0 +1,103 @@
+use std::path::PathBuf;
+
+use crate::memory::{MemoryManager, GuestRam, SystemAllocator, AddressRange, HugePages};
+use crate::vm::VmConfig;
+use crate::vm::arch::{ArchSetup, Error, Result};
+use crate::vm::kernel_cmdline::KernelCmdLine;
+use crate::virtio::{HotplugSlots, PciIrq};
+use crate::kvm::{Kvm, KvmVcpu};
+use crate::vm::arch::images::BootImages;
+use crate::vm::arch::aarch64::gic::Gic;
+use crate::vm::arch::aarch64::kvm::aarch64_open_kvm;
+use crate::vm::arch::aarch64::kernel::BootEntry;
+use crate::vm::arch::aarch64::memory::{aarch64_setup_memory_regions, aarch64_setup_memory, RAM_BASE};
+use crate::vm::arch::aarch64::registers::{setup_vcpu_init, setup_regs};
+
+pub struct Aarch64ArchSetup {
+    ram_size: usize,
+    use_drm: bool,
+    ncpus: usize,
+    ram_file: Option<PathBuf>,
+    keep_ram_file: bool,
+    share_ram: bool,
+    huge_pages: HugePages,
+    kernel_path: Option<PathBuf>,
+    initrd_path: Option<PathBuf>,
+    kvm: Option<Kvm>,
+    gic: Option<Gic>,
+    kernel_entry: Option<BootEntry>,
+    memory: Option<MemoryManager>,
+}
+
+impl Aarch64ArchSetup {
+    pub fn create(config: &VmConfig) -> Self {
+        let ram_size = config.ram_size();
+        let use_drm = config.is_wayland_enabled() && config.is_dmabuf_enabled();
+        Aarch64ArchSetup {
+            ram_size,
+            use_drm,
+            ncpus: config.ncpus(),
+            ram_file: config.ram_backing_file().map(|p| p.to_path_buf()),
+            keep_ram_file: config.get_ram_file().is_some(),
+            share_ram: !config.vhost_user_devices().is_empty(),
+            huge_pages: config.get_huge_pages(),
+            kernel_path: config.get_kernel_path().map(|p| p.to_path_buf()),
+            initrd_path: config.get_initrd_path().map(|p| p.to_path_buf()),
+            kvm: None,
+            gic: None,
+            kernel_entry: None,
+            memory: None,
+        }
+    }
+}
+
+// Device memory starts at the first 2MB boundary after RAM
+fn get_base_dev_address(mem_size: u64) -> u64 {
+    const MB: u64 = 1024 * 1024;
+    let mem_end = RAM_BASE + mem_size;
+    (mem_end + 2 * MB - 1) / (2 * MB) * (2 * MB)
+}
+
+impl ArchSetup for Aarch64ArchSetup {
+    fn open_kvm(&mut self) -> Result<Kvm> {
+        let kvm = aarch64_open_kvm()?;
+        self.gic = Some(Gic::create(&kvm)?);
+        self.kvm = Some(kvm.clone());
+        Ok(kvm)
+    }
+
+    fn create_memory(&mut self, kvm: &Kvm) -> Result<MemoryManager> {
+        let ram = GuestRam::new(self.ram_size);
+        let dev_addr_start = get_base_dev_address(self.ram_size as u64);
+        let dev_addr_size = u64::max_value() - dev_addr_start;
+        let allocator = SystemAllocator::new(AddressRange::new(dev_addr_start,dev_addr_size as usize));
+        let mut mm = MemoryManager::new(kvm.clone(), ram, allocator, self.use_drm)
+            .map_err(Error::MemoryManagerCreate)?;
+        aarch64_setup_memory_regions(&mut mm, self.ram_size, self.ram_file.as_ref().map(|p| p.as_path()), self.keep_ram_file, self.share_ram, self.huge_pages)?;
+        self.memory = Some(mm.clone());
+        Ok(mm)
+    }
+
+    // There is no PCI bus, so only the virtio-mmio devices are described
+    fn setup_memory(&mut self, cmdline: &KernelCmdLine, _pci_irqs: &[PciIrq], _hotplug: Option<&HotplugSlots>, mmio_devices: &[(AddressRange, u8)]) -> Result<()> {
+        let images = BootImages::load(self.kernel_path.as_ref().map(|p| p.as_path()), self.initrd_path.as_ref().map(|p| p.as_path()))?;
+        let memory = self.memory.as_mut().expect("No memory created");
+        self.kernel_entry = Some(aarch64_setup_memory(memory, &images, cmdline, self.ncpus, mmio_devices)?);
+        Ok(())
+    }
+
+    fn setup_vcpu(&self, vcpu: &KvmVcpu) -> Result<()> {
+        let kvm = self.kvm.as_ref().expect("KVM not opened");
+        setup_vcpu_init(kvm, vcpu)?;
+        if vcpu.id() == 0 {
+            let entry = self.kernel_entry.expect("No kernel loaded");
+            setup_regs(vcpu, entry)?;
+        }
+        // The vcpus are created in order, and the GIC can only be
+        // initialized once all of them exist
+        if vcpu.id() == self.ncpus - 1 {
+            self.gic.as_ref().expect("No GIC created").finalize()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use std::fmt;
+
+use crate::kvm::{KvmVcpu, MpState};
+use crate::vm::arch::{Result, Error};
+use crate::vm::arch::aarch64::registers::{KvmRegs, kvm_get_regs, kvm_set_regs};
+
+///
+/// The architectural state of a vcpu which is not stored in guest memory.
+///
+/// Only the core registers and the power state are captured. The system
+/// registers are left as KVM holds them.
+///
+pub struct VcpuState {
+    regs: KvmRegs,
+    mp_state: MpState,
+}
+
+impl VcpuState {
+    pub fn capture(vcpu: &KvmVcpu) -> Result<VcpuState> {
+        let regs = kvm_get_regs(vcpu.raw_fd())?;
+        let mp_state = vcpu.get_mp_state().map_err(Error::KvmError)?;
+        Ok(VcpuState { regs, mp_state })
+    }
+
+    #[allow(dead_code)]
+    pub fn restore(&self, vcpu: &KvmVcpu) -> Result<()> {
+        kvm_set_regs(vcpu.raw_fd(), &self.regs)?;
+        vcpu.set_mp_state(self.mp_state).map_err(Error::KvmError)
+    }
+
+    #[allow(dead_code)]
+    pub fn regs(&self) -> &KvmRegs {
+        &self.regs
+    }
+}
+
+impl fmt::Debug for VcpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}mp_state: {:?}\n", self.regs, self.mp_state)
+    }
+}
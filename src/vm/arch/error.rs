@@ -1,18 +1,40 @@
 use crate::{kvm, system, memory};
 use crate::system::ErrnoError;
-use std::{fmt, result};
+use crate::vm::exits::FailureKind;
+use std::path::PathBuf;
+use std::{fmt, io, result};
 
 #[derive(Debug)]
 pub enum Error {
     MemoryManagerCreate(memory::Error),
     MemoryRegister(kvm::Error),
     MemoryRegionCreate(system::Error),
+    RamBackingFile(io::Error),
     LoadKernel(system::Error),
+    ReadBootFile(PathBuf, io::Error),
+    InvalidKernel(&'static str),
+    InitrdTooLarge(usize),
+    CmdlineTooLong(usize, usize),
+    AcpiTablesTooLarge(u64),
+    OpenKvm(kvm::Error),
     KvmError(kvm::Error),
     SystemError(system::Error),
     IoctlError(&'static str, ErrnoError),
 }
 
+impl Error {
+    pub fn failure_kind(&self) -> FailureKind {
+        use Error::*;
+        match self {
+            OpenKvm(_) => FailureKind::KvmUnavailable,
+            LoadKernel(_) | ReadBootFile(..) | InvalidKernel(_) | InitrdTooLarge(_) | CmdlineTooLong(..) => FailureKind::Kernel,
+            MemoryManagerCreate(_) | MemoryRegionCreate(_) | RamBackingFile(_) => FailureKind::HostResources,
+            AcpiTablesTooLarge(_) => FailureKind::Config,
+            MemoryRegister(_) | KvmError(_) | SystemError(_) | IoctlError(..) => FailureKind::Other,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use Error::*;
@@ -20,8 +42,14 @@ impl fmt::Display for Error {
             MemoryManagerCreate(err) => write!(f, "failed to create memory manager: {}", err),
             MemoryRegister(err) => write!(f, "failed to register memory region: {}", err),
             MemoryRegionCreate(err) => write!(f, "failed to create memory region: {}", err),
+            RamBackingFile(err) => write!(f, "failed to create guest RAM backing file: {}", err),
             LoadKernel(err) => write!(f, "error loading kernel: {}", err),
-            KvmError(e) => e.fmt(f),
+            ReadBootFile(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            InvalidKernel(msg) => write!(f, "cannot boot kernel image: {}", msg),
+            InitrdTooLarge(size) => write!(f, "initrd of {} bytes does not fit in guest memory", size),
+            CmdlineTooLong(len, max) => write!(f, "kernel command line of {} bytes is longer than the {} bytes the kernel accepts", len, max),
+            AcpiTablesTooLarge(size) => write!(f, "ACPI tables of {} bytes do not fit in the BIOS area, try fewer vcpus or devices", size),
+            OpenKvm(e) | KvmError(e) => e.fmt(f),
             SystemError(e) => e.fmt(f),
             IoctlError(name, err) => write!(f, "failed to call {} ioctl: {}", name, err),
         }
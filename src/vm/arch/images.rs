@@ -0,0 +1,38 @@
+use std::fs;
+use std::path::Path;
+
+use crate::vm::arch::{Error, Result};
+use crate::vm::KERNEL;
+
+///
+/// The kernel and initial ramdisk to boot, read from the files given in the
+/// configuration or the kernel built into pH.
+///
+pub struct BootImages {
+    kernel: Option<Vec<u8>>,
+    initrd: Option<Vec<u8>>,
+}
+
+impl BootImages {
+    pub fn load(kernel_path: Option<&Path>, initrd_path: Option<&Path>) -> Result<Self> {
+        let read = |path: &Path| fs::read(path)
+            .map_err(|e| Error::ReadBootFile(path.to_path_buf(), e));
+        let kernel = match kernel_path {
+            Some(path) => Some(read(path)?),
+            None => None,
+        };
+        let initrd = match initrd_path {
+            Some(path) => Some(read(path)?),
+            None => None,
+        };
+        Ok(BootImages { kernel, initrd })
+    }
+
+    pub fn kernel(&self) -> &[u8] {
+        self.kernel.as_ref().map(|k| k.as_slice()).unwrap_or(KERNEL)
+    }
+
+    pub fn initrd(&self) -> Option<&[u8]> {
+        self.initrd.as_ref().map(|i| i.as_slice())
+    }
+}
@@ -0,0 +1,31 @@
+use std::os::unix::io::RawFd;
+use libc::c_ulong;
+
+use crate::system::ioctl::{ioctl_with_ref, ioctl_with_mut_ref, ioctl_with_val};
+use crate::vm::arch::{Error,Result};
+
+pub fn call_ioctl_with_ref<T>(name: &'static str, fd: RawFd, request: c_ulong, arg: &T) -> Result<()> {
+    unsafe {
+        ioctl_with_ref(fd, request, arg)
+            .map_err(|e| Error::IoctlError(name, e))?;
+        Ok(())
+    }
+}
+
+pub fn call_ioctl_with_mut_ref<T>(name: &'static str, fd: RawFd, request: c_ulong, arg: &mut T) -> Result<()> {
+    unsafe {
+        ioctl_with_mut_ref(fd, request, arg)
+            .map_err(|e| Error::IoctlError(name, e))?;
+        Ok(())
+    }
+}
+
+// Only needed by the x86 ioctls
+#[allow(dead_code)]
+pub fn call_ioctl_with_val(name: &'static str, fd: RawFd, request: c_ulong, val: c_ulong) -> Result<()> {
+    unsafe {
+        ioctl_with_val(fd, request, val)
+            .map_err(|e| Error::IoctlError(name, e))?;
+        Ok(())
+    }
+}
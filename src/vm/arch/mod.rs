@@ -1,27 +1,34 @@
 use crate::kvm::{KvmVcpu, Kvm};
-pub use crate::vm::arch::x86::X86ArchSetup;
-use crate::memory::MemoryManager;
+use crate::memory::{AddressRange, MemoryManager};
 
 mod error;
+mod images;
+mod ioctl;
+mod ram;
+#[cfg(target_arch = "x86_64")]
 mod x86;
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
 
-pub use x86::PCI_MMIO_RESERVED_BASE;
+#[cfg(target_arch = "x86_64")]
+pub use x86::{X86ArchSetup as PlatformSetup, PCI_MMIO_RESERVED_BASE, PCI_MMIO_RESERVED_SIZE, KvmRegs, VcpuState, BootTables};
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::{Aarch64ArchSetup as PlatformSetup, PCI_MMIO_RESERVED_BASE, PCI_MMIO_RESERVED_SIZE, VcpuState, BootTables};
 
-pub use x86::KvmRegs;
 pub use error::{Error,Result};
 use crate::vm::kernel_cmdline::KernelCmdLine;
 use crate::vm::VmConfig;
-use crate::virtio::PciIrq;
+use crate::virtio::{HotplugSlots, PciIrq};
 
-pub fn create_setup(config: &VmConfig) -> X86ArchSetup {
-    X86ArchSetup::create(config)
+pub fn create_setup(config: &VmConfig) -> PlatformSetup {
+    PlatformSetup::create(config)
 }
 
 pub trait ArchSetup {
-    fn open_kvm(&self) -> Result<Kvm>;
+    fn open_kvm(&mut self) -> Result<Kvm>;
     fn create_memory(&mut self, kvm: &Kvm) -> Result<MemoryManager>;
-    fn setup_memory(&mut self, cmdline: &KernelCmdLine, pci_irqs: &[PciIrq]) -> Result<()>;
+    /// Load the kernel and describe the VM to it. `mmio_devices` are the
+    /// area and interrupt line of each virtio-mmio device.
+    fn setup_memory(&mut self, cmdline: &KernelCmdLine, pci_irqs: &[PciIrq], hotplug: Option<&HotplugSlots>, mmio_devices: &[(AddressRange, u8)]) -> Result<()>;
     fn setup_vcpu(&self, vcpu: &KvmVcpu) -> Result<()>;
 }
-
-
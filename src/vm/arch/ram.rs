@@ -0,0 +1,156 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::result;
+
+use crate::memory::{MemoryManager, MemoryRegion, HugePages, RamBacking};
+use crate::system::{self, MemoryFd};
+use crate::vm::arch::{Error, Result};
+
+const SHMEM_THP_ENABLED: &str = "/sys/kernel/mm/transparent_hugepage/shmem_enabled";
+
+///
+/// Create a guest RAM region for each `(guest address, size)` in `ranges`,
+/// which together hold all of guest RAM in order. If `ram_file` is given
+/// guest RAM is backed by a file at that path instead of anonymous memory.
+/// Unless `keep_file` is set the file must not exist yet and is unlinked once
+/// it has been mapped so that guest memory does not remain on disk after the
+/// VM exits. Otherwise if `share_ram` is set guest RAM is backed by a memfd,
+/// so that the regions have a file which can be passed to another process.
+///
+/// Guest RAM is backed by the `huge_pages` asked for where the host can
+/// provide them, falling back from hugetlb pages to transparent huge pages
+/// and from those to ordinary pages with a warning for each step.
+///
+pub fn setup_ram_regions(memory: &mut MemoryManager, ranges: &[(u64, usize)], ram_file: Option<&Path>, keep_file: bool, share_ram: bool, huge_pages: HugePages) -> Result<()> {
+    let ram_size = ranges.iter().map(|&(_, size)| size).sum();
+    let mut backing = RamBacking::new(huge_pages);
+    let file = match ram_file {
+        Some(path) => Some(create_ram_file(path, ram_size, keep_file).map_err(Error::RamBackingFile)?),
+        None => None,
+    };
+    if file.is_some() && huge_pages != HugePages::None {
+        fall_back(&mut backing, HugePages::None, "guest RAM is backed by a file".to_string());
+    }
+
+    let mut hugetlb = None;
+    if let Some(page_size) = backing.pages().page_size() {
+        match map_hugetlb(ranges, ram_size, page_size) {
+            Ok(regions) => hugetlb = Some(regions),
+            Err(reason) => fall_back(&mut backing, HugePages::Transparent, reason),
+        }
+    }
+
+    // The regions keep a copy of the hugetlb memfd, which serves as the file
+    // shared with other processes
+    let regions = match hugetlb {
+        Some(regions) => regions,
+        None => {
+            let memfd = if file.is_none() && share_ram {
+                Some(MemoryFd::new_memfd_with_name(ram_size, false, "ph-guest-ram").map_err(Error::MemoryRegionCreate)?)
+            } else {
+                None
+            };
+            let fd = file.as_ref().map(|f| f.as_raw_fd())
+                .or_else(|| memfd.as_ref().map(|m| m.as_raw_fd()));
+            map_regions(ranges, fd).map_err(Error::MemoryRegionCreate)?
+        }
+    };
+
+    if backing.pages() == HugePages::Transparent {
+        if let Err(reason) = advise_hugepages(&regions) {
+            fall_back(&mut backing, HugePages::None, reason);
+        }
+    }
+
+    for (slot, mr) in regions.iter().enumerate() {
+        let range = mr.guest_range();
+        memory.kvm().add_memory_region(slot as u32, range.base(), mr.base_address(), range.size())
+            .map_err(Error::MemoryRegister)?;
+    }
+    memory.set_ram_regions(regions);
+    memory.set_ram_backing(backing);
+
+    if let Some(path) = ram_file {
+        if !keep_file {
+            fs::remove_file(path).map_err(Error::RamBackingFile)?;
+        }
+    }
+    Ok(())
+}
+
+fn fall_back(backing: &mut RamBacking, pages: HugePages, reason: String) {
+    warn!("Cannot back guest RAM with {} pages, using {} pages: {}", backing.pages().name(), pages.name(), reason);
+    backing.fall_back(pages, reason);
+}
+
+// Every range must start and end on a huge page boundary. The pages are
+// reserved from the hugetlb pool when they are mapped, so mapping fails if
+// the pool holds too few free pages rather than the guest faulting later.
+fn map_hugetlb(ranges: &[(u64, usize)], ram_size: usize, page_size: usize) -> result::Result<Vec<MemoryRegion>, String> {
+    let unaligned = ranges.iter()
+        .find(|&&(base, size)| base % page_size as u64 != 0 || size % page_size != 0);
+    if let Some(&(base, size)) = unaligned {
+        return Err(format!("guest RAM at 0x{:x} of {} bytes is not aligned to the page size", base, size));
+    }
+    let memfd = MemoryFd::new_hugetlb(ram_size, page_size, "ph-guest-ram")
+        .map_err(|e| format!("failed to create hugetlb memfd: {}", e))?;
+    map_regions(ranges, Some(memfd.as_raw_fd()))
+        .map_err(|e| format!("failed to map {} bytes of huge pages: {}", ram_size, e))
+}
+
+// Guest RAM is a shared mapping, so transparent huge pages are only used if
+// they are enabled for shared memory on the host.
+fn advise_hugepages(regions: &[MemoryRegion]) -> result::Result<(), String> {
+    let enabled = fs::read_to_string(SHMEM_THP_ENABLED)
+        .map_err(|e| format!("cannot read {}: {}", SHMEM_THP_ENABLED, e))?;
+    if enabled.contains("[never]") || enabled.contains("[deny]") {
+        return Err(format!("{} is {}", SHMEM_THP_ENABLED, enabled.trim()));
+    }
+    for mr in regions {
+        mr.advise_hugepages()
+            .map_err(|e| format!("madvise(MADV_HUGEPAGE) failed: {}", e))?;
+    }
+    Ok(())
+}
+
+fn map_regions(ranges: &[(u64, usize)], fd: Option<RawFd>) -> system::Result<Vec<MemoryRegion>> {
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    for &(base, size) in ranges {
+        regions.push(match fd {
+            Some(fd) => MemoryRegion::new_from_file(base, size, fd, offset)?,
+            None => MemoryRegion::new(base, size)?,
+        });
+        offset += size;
+    }
+    Ok(regions)
+}
+
+// A file which is kept may already exist, and is mapped without reading it
+// so that its pages are only faulted in when the guest touches them. The
+// file holds guest memory, so it is only readable by the user running the
+// VM and an existing file must belong to that user.
+fn create_ram_file(path: &Path, ram_size: usize, keep_file: bool) -> io::Result<fs::File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(keep_file)
+        .create_new(!keep_file)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    let meta = file.metadata()?;
+    let uid = unsafe { libc::getuid() };
+    if !meta.is_file() || meta.uid() != uid {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{} is not a file owned by uid {}", path.display(), uid)));
+    }
+    if meta.mode() & 0o077 != 0 {
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.set_len(ram_size as u64)?;
+    Ok(file)
+}
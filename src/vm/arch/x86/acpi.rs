@@ -0,0 +1,619 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::Write;
+
+use crate::memory::GuestRam;
+use crate::virtio::{HotplugSlots, PciIrq, PCI_HOTPLUG_IO_BASE, PCI_HOTPLUG_IO_SIZE, PCI_HOTPLUG_SLOTS};
+use crate::vm::arch::{Error, Result};
+use crate::vm::exits::PVPANIC_PORT;
+
+// The guest kernel searches the BIOS area from 0xE0000 to 0xFFFFF for the
+// RSDP. The other tables follow it in the same area, which is not RAM in
+// the e820 map.
+pub const RSDP_ADDRESS: u64 = 0xe0000;
+const ACPI_AREA_END: u64 = 0x100000;
+
+const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee00000;
+const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec00000;
+
+const OEM_ID: &[u8] = b"SGRAPH";
+const OEM_TABLE_ID: &[u8] = b"PH      ";
+const CREATOR_ID: &[u8] = b"SGPH";
+
+const HEADER_SIZE: usize = 36;
+const HEADER_CHECKSUM_OFFSET: usize = 9;
+
+// FADT flags
+const FADT_PWR_BUTTON: u32 = 1 << 4;
+const FADT_SLP_BUTTON: u32 = 1 << 5;
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+// IA-PC boot architecture flags in the FADT
+const FADT_VGA_NOT_PRESENT: u16 = 1 << 2;
+
+// MADT entries
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_CPU_ENABLED: u32 = 1;
+
+// AML opcodes
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+const AML_STRING_PREFIX: u8 = 0x0d;
+const AML_SCOPE_OP: u8 = 0x10;
+const AML_BUFFER_OP: u8 = 0x11;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_METHOD_OP: u8 = 0x14;
+const AML_DUAL_NAME_PREFIX: u8 = 0x2e;
+const AML_MULTI_NAME_PREFIX: u8 = 0x2f;
+const AML_EXT_OP_PREFIX: u8 = 0x5b;
+const AML_ROOT_CHAR: u8 = 0x5c;
+const AML_LOCAL0_OP: u8 = 0x60;
+const AML_STORE_OP: u8 = 0x70;
+const AML_AND_OP: u8 = 0x7b;
+const AML_NOTIFY_OP: u8 = 0x86;
+const AML_IF_OP: u8 = 0xa0;
+
+// AML opcodes following AML_EXT_OP_PREFIX
+const AML_OP_REGION_OP: u8 = 0x80;
+const AML_FIELD_OP: u8 = 0x81;
+const AML_DEVICE_OP: u8 = 0x82;
+
+const AML_REGION_SYSTEM_IO: u8 = 0x01;
+// DWordAcc, NoLock, WriteAsZeros
+const AML_FIELD_DWORD_ACC: u8 = 0x03;
+
+// Notify values for a slot device
+const NOTIFY_DEVICE_CHECK: u64 = 1;
+const NOTIFY_EJECT_REQUEST: u64 = 3;
+
+// Extended interrupt resource descriptor flags
+const RES_IRQ_CONSUMER: u8 = 1 << 0;
+const RES_IRQ_EDGE: u8 = 1 << 1;
+const RES_IRQ_SHARED: u8 = 1 << 3;
+
+///
+/// Which tables describing the CPUs and interrupt routing of the VM are
+/// written into guest memory for the guest kernel.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum BootTables {
+    /// An Intel MP table. Interrupts are delivered through the 8259 PICs,
+    /// and the guest is booted with `noapic`.
+    MpTable,
+    /// ACPI tables for a hardware reduced ACPI platform. Interrupts are
+    /// delivered through the IOAPIC and the guest has no PIT or 8259 PICs.
+    /// The guest kernel must be built with `CONFIG_ACPI`.
+    Acpi,
+    /// Both the MP table and the ACPI tables, so that kernels built with or
+    /// without ACPI can boot. Both route interrupts through the IOAPIC.
+    Both,
+}
+
+impl BootTables {
+    /// Parse `mptable`, `acpi` or `both`.
+    pub fn parse(s: &str) -> Option<BootTables> {
+        match s {
+            "mptable" => Some(BootTables::MpTable),
+            "acpi" => Some(BootTables::Acpi),
+            "both" => Some(BootTables::Both),
+            _ => None,
+        }
+    }
+
+    pub fn has_mptable(self) -> bool {
+        self != BootTables::Acpi
+    }
+
+    pub fn has_acpi(self) -> bool {
+        self != BootTables::MpTable
+    }
+}
+
+impl Default for BootTables {
+    fn default() -> Self {
+        BootTables::MpTable
+    }
+}
+
+struct Buffer {
+    vec: Vec<u8>,
+}
+
+impl Buffer {
+    fn new() -> Buffer {
+        Buffer { vec: Vec::new() }
+    }
+
+    fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    fn w8(&mut self, val: u8) -> &mut Self {
+        self.vec.push(val);
+        self
+    }
+    fn w16(&mut self, data: u16) -> &mut Self {
+        self.vec.write_u16::<LittleEndian>(data).unwrap();
+        self
+    }
+    fn w32(&mut self, data: u32) -> &mut Self {
+        self.vec.write_u32::<LittleEndian>(data).unwrap();
+        self
+    }
+    fn w64(&mut self, data: u64) -> &mut Self {
+        self.vec.write_u64::<LittleEndian>(data).unwrap();
+        self
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.vec.write_all(data).unwrap();
+        self
+    }
+
+    fn pad(&mut self, count: usize) -> &mut Self {
+        self.vec.resize(self.vec.len() + count, 0);
+        self
+    }
+
+    // A system description table header with the length and checksum filled
+    // in by finish_table()
+    fn table_header(&mut self, signature: &[u8], revision: u8) -> &mut Self {
+        self.bytes(signature)
+            .w32(0)                 // length
+            .w8(revision)
+            .w8(0)                  // checksum
+            .bytes(OEM_ID)
+            .bytes(OEM_TABLE_ID)
+            .w32(1)                 // OEM revision
+            .bytes(CREATOR_ID)
+            .w32(1)                 // creator revision
+    }
+
+    fn finish_table(&mut self) -> &mut Self {
+        let len = self.vec.len() as u32;
+        (&mut self.vec[4..8]).write_u32::<LittleEndian>(len).unwrap();
+        self.checksum(0, self.vec.len(), HEADER_CHECKSUM_OFFSET)
+    }
+
+    fn checksum(&mut self, start: usize, len: usize, csum_off: usize) -> &mut Self {
+        let slice = &mut self.vec[start..start + len];
+        slice[csum_off] = 0;
+        let sum = slice.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+        slice[csum_off] = sum.wrapping_neg();
+        self
+    }
+}
+
+// ACPI 2.0 Root System Description Pointer
+fn rsdp(xsdt_address: u64) -> Buffer {
+    let mut b = Buffer::new();
+    b.bytes(b"RSD PTR ")
+        .w8(0)                      // checksum of the first 20 bytes
+        .bytes(OEM_ID)
+        .w8(2)                      // revision
+        .w32(0)                     // RSDT address
+        .w32(36)                    // length
+        .w64(xsdt_address)
+        .w8(0)                      // extended checksum
+        .pad(3)
+        .checksum(0, 20, 8)
+        .checksum(0, 36, 32);
+    b
+}
+
+fn xsdt(tables: &[u64]) -> Buffer {
+    let mut b = Buffer::new();
+    b.table_header(b"XSDT", 1);
+    for &address in tables {
+        b.w64(address);
+    }
+    b.finish_table();
+    b
+}
+
+// Fixed ACPI Description Table for a hardware reduced platform, which has
+// no fixed hardware registers, SCI or FACS
+fn fadt(dsdt_address: u64) -> Buffer {
+    let mut b = Buffer::new();
+    b.table_header(b"FACP", 6)
+        .w32(0)                     // 36 FACS address
+        .w32(dsdt_address as u32)   // 40 DSDT address
+        .pad(109 - 44)              // 44 fixed hardware blocks and lengths
+        .w16(FADT_VGA_NOT_PRESENT)  // 109 IA-PC boot architecture flags
+        .w8(0)                      // 111 reserved
+        .w32(FADT_HW_REDUCED_ACPI | FADT_PWR_BUTTON | FADT_SLP_BUTTON)
+        .pad(12)                    // 116 reset register
+        .w8(0)                      // 128 reset value
+        .w16(0)                     // 129 ARM boot architecture flags
+        .w8(0)                      // 131 minor version
+        .w64(0)                     // 132 X_FACS address
+        .w64(dsdt_address)          // 140 X_DSDT address
+        .pad(268 - 148)             // 148 extended fixed hardware blocks, sleep registers
+        .bytes(b"PH      ")         // 268 hypervisor vendor identity
+        .finish_table();
+    b
+}
+
+// Multiple APIC Description Table. The local APIC ID of each CPU is its
+// index, and the IOAPIC ID follows them as in the MP table. ISA interrupts
+// are identity mapped to the IOAPIC pins as in the default KVM routing, so
+// no interrupt source overrides are needed.
+fn madt(ncpus: usize) -> Buffer {
+    let mut b = Buffer::new();
+    b.table_header(b"APIC", 3)
+        .w32(APIC_DEFAULT_PHYS_BASE)
+        .w32(0);                    // flags, no 8259 PICs
+    for i in 0..ncpus {
+        b.w8(MADT_LOCAL_APIC)
+            .w8(8)                  // length
+            .w8(i as u8)            // ACPI processor UID
+            .w8(i as u8)            // APIC ID
+            .w32(MADT_CPU_ENABLED);
+    }
+    b.w8(MADT_IO_APIC)
+        .w8(12)                     // length
+        .w8((ncpus + 1) as u8)      // IOAPIC ID
+        .w8(0)                      // reserved
+        .w32(IO_APIC_DEFAULT_PHYS_BASE)
+        .w32(0)                     // global system interrupt base
+        .finish_table();
+    b
+}
+
+fn dsdt(ncpus: usize, pci_irqs: &[PciIrq], hotplug: Option<&HotplugSlots>) -> Buffer {
+    let mut sb = Vec::new();
+    for i in 0..ncpus {
+        sb.extend(device(&format!("C{:03X}", i), &[
+            name("_HID", string("ACPI0007")),
+            name("_UID", integer(i as u64)),
+        ]));
+    }
+
+    // Each device interrupt pin is routed to an interrupt link with the
+    // line assigned to the device. Lines may be shared, so they are edge
+    // triggered like the ISA interrupts they are identity mapped to.
+    let mut prt = Vec::new();
+    for irq in pci_irqs {
+        let link = format!("LK{:02X}", irq.device_number());
+        let resources = irq_resource(irq.irq_line());
+        sb.extend(device(&link, &[
+            name("_HID", eisa_id("PNP0C0F")),
+            name("_UID", integer(u64::from(irq.device_number()))),
+            name("_PRS", resources.clone()),
+            name("_CRS", resources),
+            method("_SRS", 1, &[]),
+        ]));
+        prt.push(package(&[
+            integer((u64::from(irq.device_number()) << 16) | 0xffff),
+            integer(u64::from(irq.pin())),
+            name_string(&link),
+            integer(0),
+        ]));
+    }
+    let mut pci0 = vec![
+        name("_HID", eisa_id("PNP0A03")),
+        name("_UID", integer(0)),
+        name("_ADR", integer(0)),
+        name("_BBN", integer(0)),
+    ];
+    if let Some(hotplug) = hotplug {
+        sb.extend(hotplug_link(hotplug, &mut prt));
+        pci0.extend(hotplug_slots());
+    }
+    pci0.push(name("_PRT", package(&prt)));
+    sb.extend(device("PCI0", &pci0));
+    // The pvpanic driver reports a kernel panic of the guest on its port
+    sb.extend(device("PEVT", &[
+        name("_HID", string("QEMU0001")),
+        name("_CRS", io_resource(PVPANIC_PORT, 1)),
+    ]));
+    if let Some(hotplug) = hotplug {
+        sb.extend(device("GED0", &[
+            name("_HID", string("ACPI0013")),
+            name("_UID", integer(0)),
+            name("_CRS", irq_resource(hotplug.event_irq())),
+            method("_EVT", 1, &[name_string("\\_SB_.PCI0.PCNT")]),
+        ]));
+    }
+
+    let mut b = Buffer::new();
+    b.table_header(b"DSDT", 2)
+        .bytes(&scope("\\_SB_", &sb))
+        .finish_table();
+    b
+}
+
+// An interrupt link with the line shared by devices added after boot, and
+// the routing of each empty slot to it
+fn hotplug_link(hotplug: &HotplugSlots, prt: &mut Vec<Vec<u8>>) -> Vec<u8> {
+    let resources = irq_resource(hotplug.slot_irq());
+    for &slot in hotplug.empty_slots() {
+        prt.push(package(&[
+            integer((u64::from(slot) << 16) | 0xffff),
+            integer(0),
+            name_string("LKHP"),
+            integer(0),
+        ]));
+    }
+    device("LKHP", &[
+        name("_HID", eisa_id("PNP0C0F")),
+        name("_UID", integer(0xff)),
+        name("_PRS", resources.clone()),
+        name("_CRS", resources),
+        method("_SRS", 1, &[]),
+    ])
+}
+
+// The registers of the hotplug controller, a device for each slot which the
+// guest ejects by writing the bit of the slot, and the method which the
+// generic event device calls to notify the slots with a pending change.
+fn hotplug_slots() -> Vec<Vec<u8>> {
+    let mut terms = vec![
+        op_region("PCST", AML_REGION_SYSTEM_IO, u64::from(PCI_HOTPLUG_IO_BASE), PCI_HOTPLUG_IO_SIZE as u64),
+        field("PCST", AML_FIELD_DWORD_ACC, &[("PCIU", 32), ("PCID", 32), ("B0EJ", 32)]),
+    ];
+    let slots = 1..u64::from(PCI_HOTPLUG_SLOTS);
+    for slot in slots.clone() {
+        terms.push(device(&format!("S{:02X}", slot), &[
+            name("_ADR", integer(slot << 16)),
+            name("_SUN", integer(slot)),
+            method("_EJ0", 1, &[store(integer(1 << slot), name_string("B0EJ"))]),
+        ]));
+    }
+    let mut pcnt = vec![store(name_string("PCIU"), vec![AML_LOCAL0_OP])];
+    for slot in slots.clone() {
+        pcnt.push(notify_if_set(slot, NOTIFY_DEVICE_CHECK));
+    }
+    pcnt.push(store(name_string("PCID"), vec![AML_LOCAL0_OP]));
+    for slot in slots {
+        pcnt.push(notify_if_set(slot, NOTIFY_EJECT_REQUEST));
+    }
+    terms.push(method("PCNT", 0, &pcnt));
+    terms
+}
+
+// If (And (Local0, 1 << slot)) { Notify (Sxx, value) }
+fn notify_if_set(slot: u64, value: u64) -> Vec<u8> {
+    let mut predicate = vec![AML_AND_OP, AML_LOCAL0_OP];
+    predicate.extend(integer(1 << slot));
+    predicate.push(AML_ZERO_OP);                // no target
+    let mut notify = vec![AML_NOTIFY_OP];
+    notify.extend(name_string(&format!("S{:02X}", slot)));
+    notify.extend(integer(value));
+    if_op(predicate, &[notify])
+}
+
+fn pkg_length(len: usize) -> Vec<u8> {
+    // The encoded length includes the bytes which encode it
+    if len + 1 < 1 << 6 {
+        return vec![(len + 1) as u8];
+    }
+    let (count, total) = if len + 2 < 1 << 12 {
+        (1, len + 2)
+    } else if len + 3 < 1 << 20 {
+        (2, len + 3)
+    } else {
+        (3, len + 4)
+    };
+    let mut v = vec![((count as u8) << 6) | (total & 0xf) as u8];
+    for i in 0..count {
+        v.push((total >> (4 + 8 * i)) as u8);
+    }
+    v
+}
+
+fn name_seg(name: &str) -> Vec<u8> {
+    let mut seg = name.as_bytes().to_vec();
+    seg.resize(4, b'_');
+    seg
+}
+
+fn name_string(name: &str) -> Vec<u8> {
+    let (mut v, path) = if name.starts_with('\\') {
+        (vec![AML_ROOT_CHAR], &name[1..])
+    } else {
+        (Vec::new(), name)
+    };
+    let segs: Vec<&str> = path.split('.').collect();
+    match segs.len() {
+        1 => {},
+        2 => v.push(AML_DUAL_NAME_PREFIX),
+        n => v.extend(&[AML_MULTI_NAME_PREFIX, n as u8]),
+    }
+    for seg in segs {
+        v.extend(name_seg(seg));
+    }
+    v
+}
+
+fn integer(n: u64) -> Vec<u8> {
+    let mut v = Vec::new();
+    match n {
+        0 => v.push(AML_ZERO_OP),
+        1 => v.push(AML_ONE_OP),
+        n if n <= 0xff => v.extend(&[AML_BYTE_PREFIX, n as u8]),
+        n if n <= 0xffff => {
+            v.push(AML_WORD_PREFIX);
+            v.write_u16::<LittleEndian>(n as u16).unwrap();
+        }
+        n => {
+            v.push(AML_DWORD_PREFIX);
+            v.write_u32::<LittleEndian>(n as u32).unwrap();
+        }
+    }
+    v
+}
+
+fn string(s: &str) -> Vec<u8> {
+    let mut v = vec![AML_STRING_PREFIX];
+    v.extend(s.as_bytes());
+    v.push(0);
+    v
+}
+
+// A compressed EISA ID such as PNP0A03 as an integer
+fn eisa_id(id: &str) -> Vec<u8> {
+    let b = id.as_bytes();
+    let vendor = b[..3].iter().fold(0u32, |acc, &c| (acc << 5) | u32::from(c - 0x40));
+    let product = u32::from_str_radix(&id[3..], 16).unwrap_or(0);
+    integer(u64::from(((vendor << 16) | product).swap_bytes()))
+}
+
+fn name(name: &str, value: Vec<u8>) -> Vec<u8> {
+    let mut v = vec![AML_NAME_OP];
+    v.extend(name_string(name));
+    v.extend(value);
+    v
+}
+
+fn package(elements: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = vec![elements.len() as u8];
+    for e in elements {
+        body.extend(e);
+    }
+    let mut v = vec![AML_PACKAGE_OP];
+    v.extend(pkg_length(body.len()));
+    v.extend(body);
+    v
+}
+
+fn scope(name: &str, terms: &[u8]) -> Vec<u8> {
+    let mut body = name_string(name);
+    body.extend(terms);
+    let mut v = vec![AML_SCOPE_OP];
+    v.extend(pkg_length(body.len()));
+    v.extend(body);
+    v
+}
+
+fn device(name: &str, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = name_string(name);
+    for t in terms {
+        body.extend(t);
+    }
+    let mut v = vec![AML_EXT_OP_PREFIX, AML_DEVICE_OP];
+    v.extend(pkg_length(body.len()));
+    v.extend(body);
+    v
+}
+
+fn method(name: &str, nargs: u8, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = name_string(name);
+    body.push(nargs & 0x7);
+    for t in terms {
+        body.extend(t);
+    }
+    let mut v = vec![AML_METHOD_OP];
+    v.extend(pkg_length(body.len()));
+    v.extend(body);
+    v
+}
+
+fn op_region(name: &str, space: u8, offset: u64, len: u64) -> Vec<u8> {
+    let mut v = vec![AML_EXT_OP_PREFIX, AML_OP_REGION_OP];
+    v.extend(name_string(name));
+    v.push(space);
+    v.extend(integer(offset));
+    v.extend(integer(len));
+    v
+}
+
+// A field list of named fields, each given as a name and a size in bits
+fn field(region: &str, flags: u8, fields: &[(&str, u8)]) -> Vec<u8> {
+    let mut body = name_string(region);
+    body.push(flags);
+    for &(name, bits) in fields {
+        body.extend(name_seg(name));
+        body.push(bits);
+    }
+    let mut v = vec![AML_EXT_OP_PREFIX, AML_FIELD_OP];
+    v.extend(pkg_length(body.len()));
+    v.extend(body);
+    v
+}
+
+fn store(source: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    let mut v = vec![AML_STORE_OP];
+    v.extend(source);
+    v.extend(target);
+    v
+}
+
+fn if_op(predicate: Vec<u8>, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = predicate;
+    for t in terms {
+        body.extend(t);
+    }
+    let mut v = vec![AML_IF_OP];
+    v.extend(pkg_length(body.len()));
+    v.extend(body);
+    v
+}
+
+// A resource template buffer with a single extended interrupt descriptor
+fn irq_resource(line: u8) -> Vec<u8> {
+    let mut res = vec![0x89];                   // extended interrupt descriptor
+    res.write_u16::<LittleEndian>(6).unwrap();  // length
+    res.push(RES_IRQ_CONSUMER | RES_IRQ_EDGE | RES_IRQ_SHARED);
+    res.push(1);                                // interrupt count
+    res.write_u32::<LittleEndian>(u32::from(line)).unwrap();
+    res.extend(&[0x79, 0]);                     // end tag
+
+    let mut body = integer(res.len() as u64);
+    body.extend(res);
+    let mut v = vec![AML_BUFFER_OP];
+    v.extend(pkg_length(body.len()));
+    v.extend(body);
+    v
+}
+
+// A resource template buffer with a single fixed range of I/O ports
+fn io_resource(port: u16, len: u8) -> Vec<u8> {
+    let mut res = vec![0x47, 0x01];             // I/O port descriptor, 16 bit decode
+    res.write_u16::<LittleEndian>(port).unwrap();   // minimum base
+    res.write_u16::<LittleEndian>(port).unwrap();   // maximum base
+    res.extend(&[1, len]);                      // alignment and length
+    res.extend(&[0x79, 0]);                     // end tag
+
+    let mut body = integer(res.len() as u64);
+    body.extend(res);
+    let mut v = vec![AML_BUFFER_OP];
+    v.extend(pkg_length(body.len()));
+    v.extend(body);
+    v
+}
+
+fn align(sz: u64, n: u64) -> u64 {
+    (sz + (n - 1)) & !(n - 1)
+}
+
+///
+/// Write the RSDP, XSDT, FADT, MADT and DSDT into the BIOS area of guest
+/// memory.
+///
+pub fn setup_acpi_tables(memory: &GuestRam, ncpus: usize, pci_irqs: &[PciIrq], hotplug: Option<&HotplugSlots>) -> Result<()> {
+    let xsdt_address = align(RSDP_ADDRESS + 36, 16);
+
+    // The XSDT has two entries
+    let dsdt_address = align(xsdt_address + HEADER_SIZE as u64 + 16, 16);
+    let dsdt = dsdt(ncpus, pci_irqs, hotplug);
+    let fadt_address = align(dsdt_address + dsdt.len() as u64, 16);
+    let fadt = fadt(dsdt_address);
+    let madt_address = align(fadt_address + fadt.len() as u64, 16);
+    let madt = madt(ncpus);
+    let tables_end = madt_address + madt.len() as u64;
+    if tables_end > ACPI_AREA_END {
+        return Err(Error::AcpiTablesTooLarge(tables_end - RSDP_ADDRESS));
+    }
+
+    let write = |address, bytes: &[u8]| memory.write_bytes(address, bytes).map_err(Error::SystemError);
+    write(RSDP_ADDRESS, &rsdp(xsdt_address).vec)?;
+    write(xsdt_address, &xsdt(&[fadt_address, madt_address]).vec)?;
+    write(dsdt_address, &dsdt.vec)?;
+    write(fadt_address, &fadt.vec)?;
+    write(madt_address, &madt.vec)
+}
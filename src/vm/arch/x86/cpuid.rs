@@ -2,19 +2,118 @@
 use std::os::unix::io::RawFd;
 use crate::vm::arch::Result;
 use crate::kvm::KvmVcpu;
+use crate::vm::arch::x86::hyperv::setup_hyperv_cpuid;
 use crate::vm::arch::x86::ioctl::{KVM_GET_SUPPORTED_CPUID, KVM_SET_CPUID2, call_ioctl_with_ref, call_ioctl_with_mut_ref};
 
 const EBX_CLFLUSH_CACHELINE: u32 = 8; // Flush a cache line size.
 const EBX_CLFLUSH_SIZE_SHIFT: u32 = 8; // Bytes flushed when executing CLFLUSH.
-const _EBX_CPU_COUNT_SHIFT: u32 = 16; // Index of this CPU.
+const EBX_CPU_COUNT_SHIFT: u32 = 16; // Logical processors in the package.
 const EBX_CPUID_SHIFT: u32 = 24; // Index of this CPU.
 const _ECX_EPB_SHIFT: u32 = 3; // "Energy Performance Bias" bit.
 const _ECX_HYPERVISOR_SHIFT: u32 = 31; // Flag to be set when the cpu is running on a hypervisor.
-const _EDX_HTT_SHIFT: u32 = 28; // Hyper Threading Enabled.
+const EDX_HTT_SHIFT: u32 = 28; // Hyper Threading Enabled.
 
-pub fn setup_cpuid(vcpu: &KvmVcpu) -> Result<()> {
+const EAX_CACHE_LEVEL_SHIFT: u32 = 5; // Leaf 4: level of this cache.
+const EAX_CACHE_SHARING_SHIFT: u32 = 14; // Leaf 4: logical processors sharing this cache, minus one.
+const EAX_CORES_SHIFT: u32 = 26; // Leaf 4: cores in the package, minus one.
+
+const ECX_LEVEL_TYPE_SHIFT: u32 = 8; // Leaves 0xB and 0x1F: type of this level.
+const LEVEL_TYPE_SMT: u32 = 1;
+const LEVEL_TYPE_CORE: u32 = 2;
+
+const ECX_CMP_LEGACY: u32 = 1 << 1; // Leaf 0x80000001: AMD multi-core.
+const ECX_APIC_ID_SIZE_SHIFT: u32 = 12; // Leaf 0x80000008: APIC ID bits for cores.
+
+// The index of a leaf selects a sub-leaf
+const KVM_CPUID_FLAG_SIGNIFCANT_INDEX: u32 = 1;
+
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+const KVM_FEATURE_STEAL_TIME: u32 = 1 << 5;
+const KVM_FEATURE_PV_EOI: u32 = 1 << 6;
+
+///
+/// KVM paravirtual features which can be withheld from the guest, for
+/// example to measure what they gain. Each one is only advertised if KVM
+/// also reports it as supported.
+///
+/// With steal time the guest registers a page per vcpu in which KVM counts
+/// the time the vcpu was ready but not running on the host, so the guest
+/// scheduler does not charge it to the task that was interrupted. With PV
+/// EOI the guest acknowledges most interrupts by clearing a flag in memory
+/// instead of writing the APIC EOI register, which saves an exit for each.
+///
+#[derive(Copy,Clone,Debug)]
+pub struct PvFeatures {
+    pub steal_time: bool,
+    pub pv_eoi: bool,
+}
+
+impl PvFeatures {
+    fn mask(&self) -> u32 {
+        let mut mask = !0;
+        if !self.steal_time {
+            mask &= !KVM_FEATURE_STEAL_TIME;
+        }
+        if !self.pv_eoi {
+            mask &= !KVM_FEATURE_PV_EOI;
+        }
+        mask
+    }
+}
+
+///
+/// The position of a vcpu in the processor topology seen by the guest.
+///
+/// Every vcpu is a core of its own with a single thread, and all of them are
+/// in one package. The APIC ID of a vcpu is its index, which is also the ID
+/// given to it in the MP table and the MADT.
+///
+struct Topology {
+    apic_id: u32,
+    ncpus: u32,
+}
+
+impl Topology {
+    fn new(apic_id: usize, ncpus: usize) -> Self {
+        Topology { apic_id: apic_id as u32, ncpus: ncpus.max(1) as u32 }
+    }
+
+    // Bits of the APIC ID which number the cores of the package
+    fn core_bits(&self) -> u32 {
+        32 - (self.ncpus - 1).leading_zeros()
+    }
+
+    fn threads_per_core(&self) -> u32 {
+        1
+    }
+
+    // A sub-leaf of leaf 0xB or 0x1F
+    fn extended_level(&self, function: u32, index: u32) -> KvmCpuIdEntry {
+        let (shift, count, level_type) = match index {
+            0 => (0, self.threads_per_core(), LEVEL_TYPE_SMT),
+            1 => (self.core_bits(), self.ncpus, LEVEL_TYPE_CORE),
+            _ => (0, 0, 0),
+        };
+        KvmCpuIdEntry {
+            function,
+            index,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            eax: shift,
+            ebx: count,
+            ecx: (level_type << ECX_LEVEL_TYPE_SHIFT) | index,
+            edx: self.apic_id,
+            ..Default::default()
+        }
+    }
+}
+
+/// Configure the CPUID of `vcpu`, one of `ncpus` vcpus. If `hide_rdrand` is
+/// set the RDRAND and RDSEED instructions are not advertised so that the
+/// guest only gets entropy from devices.
+pub fn setup_cpuid(vcpu: &KvmVcpu, ncpus: usize, hyperv: bool, hide_rdrand: bool, pv: PvFeatures) -> Result<()> {
     let mut cpuid = kvm_get_supported_cpuid(vcpu.sys_raw_fd())?;
-    let cpu_id = 0u32; // first vcpu
+    let topology = Topology::new(vcpu.id(), ncpus);
+    let package_size = 1 << topology.core_bits();
 
     for e in &mut cpuid {
         match e.function {
@@ -27,19 +126,37 @@ pub fn setup_cpuid(vcpu: &KvmVcpu) -> Result<()> {
                 if e.index == 0 {
                     e.ecx |= 1<<31;
                 }
-                e.ebx = (cpu_id << EBX_CPUID_SHIFT) as u32 |
+                if hide_rdrand {
+                    e.ecx &= !(1<<30);
+                }
+                e.ebx = (topology.apic_id << EBX_CPUID_SHIFT) as u32 |
                     (EBX_CLFLUSH_CACHELINE << EBX_CLFLUSH_SIZE_SHIFT);
-                /*
-                if cpu_count > 1 {
-                    entry.ebx |= (cpu_count as u32) << EBX_CPU_COUNT_SHIFT;
-                    entry.edx |= 1 << EDX_HTT_SHIFT;
+                if topology.ncpus > 1 {
+                    e.ebx |= package_size << EBX_CPU_COUNT_SHIFT;
+                    e.edx |= 1 << EDX_HTT_SHIFT;
+                } else {
+                    e.edx &= !(1 << EDX_HTT_SHIFT);
+                }
+            }
+            4 => {
+                // Caches below the last level belong to a single core
+                let level = (e.eax >> EAX_CACHE_LEVEL_SHIFT) & 0x7;
+                let sharing = if level >= 3 { package_size } else { topology.threads_per_core() };
+                e.eax &= (1 << EAX_CACHE_SHARING_SHIFT) - 1;
+                if e.eax != 0 {
+                    e.eax |= (sharing - 1) << EAX_CACHE_SHARING_SHIFT;
+                    e.eax |= (package_size - 1) << EAX_CORES_SHIFT;
                 }
-                */
             }
             6 => {
                 e.ecx &= !(1<<3);
 
             }
+            7 => {
+                if hide_rdrand && e.index == 0 {
+                    e.ebx &= !(1<<18);
+                }
+            }
             10 => {
                 if e.eax > 0 {
                     let version = e.eax & 0xFF;
@@ -50,12 +167,68 @@ pub fn setup_cpuid(vcpu: &KvmVcpu) -> Result<()> {
                 }
 
             }
+            0x8000_0001 => {
+                if topology.ncpus > 1 {
+                    e.ecx |= ECX_CMP_LEGACY;
+                } else {
+                    e.ecx &= !ECX_CMP_LEGACY;
+                }
+            }
+            0x8000_0008 => {
+                e.ecx = (topology.core_bits() << ECX_APIC_ID_SIZE_SHIFT) | (topology.ncpus - 1);
+            }
+            0x8000_001E => {
+                e.eax = topology.apic_id;
+                e.ebx = ((topology.threads_per_core() - 1) << 8) | topology.apic_id;
+                e.ecx = 0;
+            }
+            KVM_CPUID_FEATURES => {
+                if topology.apic_id == 0 {
+                    log_pv_features(e.eax, pv);
+                }
+                e.eax &= pv.mask();
+            }
             _ => {}
         }
     }
+    setup_extended_topology(&mut cpuid, &topology);
+    if hyperv {
+        setup_hyperv_cpuid(vcpu, &mut cpuid)?;
+    }
     kvm_set_cpuid2(vcpu.raw_fd(), cpuid)
 }
 
+fn log_pv_features(supported: u32, pv: PvFeatures) {
+    let state = |bit: u32, enabled: bool| match (supported & bit != 0, enabled) {
+        (false, _) => "unsupported by KVM",
+        (true, false) => "disabled",
+        (true, true) => "enabled",
+    };
+    verbose!("KVM steal time {}, PV EOI {}",
+             state(KVM_FEATURE_STEAL_TIME, pv.steal_time), state(KVM_FEATURE_PV_EOI, pv.pv_eoi));
+}
+
+// Replace the sub-leaves of leaf 0xB, and of leaf 0x1F if the host has it,
+// with the topology of this vcpu. The guest uses these to find its x2APIC ID
+// and how many threads and cores there are.
+fn setup_extended_topology(cpuid: &mut Vec<KvmCpuIdEntry>, topology: &Topology) {
+    let max_leaf = cpuid.iter()
+        .find(|e| e.function == 0)
+        .map(|e| e.eax)
+        .unwrap_or(0);
+    let has_v2 = cpuid.iter().any(|e| e.function == 0x1F);
+    cpuid.retain(|e| e.function != 0xB && e.function != 0x1F);
+    if max_leaf < 0xB {
+        return;
+    }
+    for index in 0..3 {
+        cpuid.push(topology.extended_level(0xB, index));
+        if has_v2 && max_leaf >= 0x1F {
+            cpuid.push(topology.extended_level(0x1F, index));
+        }
+    }
+}
+
 
 pub fn kvm_get_supported_cpuid(sysfd: RawFd) -> Result<Vec<KvmCpuIdEntry>> {
     let mut cpuid = KvmCpuId2::new();
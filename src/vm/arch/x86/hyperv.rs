@@ -0,0 +1,114 @@
+use crate::kvm::{KvmVcpu, KVM_CAP_HYPERV_CPUID, KVM_CAP_HYPERV_SYNIC};
+use crate::vm::arch::Result;
+use crate::vm::arch::x86::cpuid::{KvmCpuIdEntry, KvmCpuId2};
+use crate::vm::arch::x86::ioctl::{call_ioctl_with_mut_ref, KVM_GET_SUPPORTED_HV_CPUID};
+
+const HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS: u32 = 0x40000000;
+const HYPERV_CPUID_FEATURES: u32                 = 0x40000003;
+const HYPERV_CPUID_ENLIGHTMENT_INFO: u32         = 0x40000004;
+const HYPERV_CPUID_IMPLEMENT_LIMITS: u32         = 0x40000005;
+
+// When Hyper-V leaves are present the KVM leaves are moved up to this
+// offset, where Linux guests still find them.
+const KVM_CPUID_SIGNATURE_OFFSET: u32 = 0x100;
+
+// HYPERV_CPUID_FEATURES eax
+const HV_MSR_VP_RUNTIME_AVAILABLE: u32    = 1 << 0;
+const HV_MSR_TIME_REF_COUNT_AVAILABLE: u32 = 1 << 1;
+const HV_MSR_SYNIC_AVAILABLE: u32         = 1 << 2;
+const HV_MSR_SYNTIMER_AVAILABLE: u32      = 1 << 3;
+const HV_MSR_APIC_ACCESS_AVAILABLE: u32   = 1 << 4;
+const HV_MSR_HYPERCALL_AVAILABLE: u32     = 1 << 5;
+const HV_MSR_VP_INDEX_AVAILABLE: u32      = 1 << 6;
+
+// HYPERV_CPUID_ENLIGHTMENT_INFO eax
+const HV_X64_APIC_ACCESS_RECOMMENDED: u32   = 1 << 3;
+const HV_X64_RELAXED_TIMING_RECOMMENDED: u32 = 1 << 5;
+const HV_X64_CLUSTER_IPI_RECOMMENDED: u32   = 1 << 10;
+
+const SUPPORTED_FEATURES: u32 = HV_MSR_VP_RUNTIME_AVAILABLE |
+    HV_MSR_TIME_REF_COUNT_AVAILABLE |
+    HV_MSR_SYNIC_AVAILABLE |
+    HV_MSR_SYNTIMER_AVAILABLE |
+    HV_MSR_APIC_ACCESS_AVAILABLE |
+    HV_MSR_HYPERCALL_AVAILABLE |
+    HV_MSR_VP_INDEX_AVAILABLE;
+
+const SUPPORTED_RECOMMENDATIONS: u32 = HV_X64_APIC_ACCESS_RECOMMENDED |
+    HV_X64_RELAXED_TIMING_RECOMMENDED |
+    HV_X64_CLUSTER_IPI_RECOMMENDED;
+
+///
+/// Add Hyper-V enlightenment leaves to `cpuid`.
+///
+/// The leaves are taken from what KVM reports as supported, limited to the
+/// subset of features which do not need any further emulation in userspace:
+/// relaxed timing, the hypercall page, reference time counter, VP index and
+/// runtime MSRs, APIC access MSRs, cluster IPI hypercalls, and SynIC with
+/// synthetic timers if the SynIC capability can be enabled on this vcpu.
+///
+/// If KVM does not support `KVM_CAP_HYPERV_CPUID` a warning is displayed and
+/// `cpuid` is left unchanged.
+///
+pub fn setup_hyperv_cpuid(vcpu: &KvmVcpu, cpuid: &mut Vec<KvmCpuIdEntry>) -> Result<()> {
+    if !vcpu.has_extension(KVM_CAP_HYPERV_CPUID) {
+        warn!("Hyper-V enlightenments requested but not supported by KVM");
+        return Ok(());
+    }
+
+    let mut features = SUPPORTED_FEATURES;
+    if !enable_synic(vcpu) {
+        features &= !(HV_MSR_SYNIC_AVAILABLE | HV_MSR_SYNTIMER_AVAILABLE);
+    }
+
+    let mut hv_entries = kvm_get_supported_hv_cpuid(vcpu)?;
+    hv_entries.retain(|e| e.function <= HYPERV_CPUID_IMPLEMENT_LIMITS);
+    for e in &mut hv_entries {
+        match e.function {
+            HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS => {
+                e.eax = HYPERV_CPUID_IMPLEMENT_LIMITS;
+            }
+            HYPERV_CPUID_FEATURES => {
+                e.eax &= features;
+                e.ebx = 0;
+                e.edx = 0;
+            }
+            HYPERV_CPUID_ENLIGHTMENT_INFO => {
+                e.eax &= SUPPORTED_RECOMMENDATIONS;
+            }
+            _ => {}
+        }
+    }
+
+    for e in cpuid.iter_mut() {
+        if is_hypervisor_leaf(e.function) {
+            e.function += KVM_CPUID_SIGNATURE_OFFSET;
+        }
+    }
+    cpuid.extend(hv_entries);
+    Ok(())
+}
+
+fn is_hypervisor_leaf(function: u32) -> bool {
+    function >= HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS &&
+        function < HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS + KVM_CPUID_SIGNATURE_OFFSET
+}
+
+fn enable_synic(vcpu: &KvmVcpu) -> bool {
+    if !vcpu.has_extension(KVM_CAP_HYPERV_SYNIC) {
+        return false;
+    }
+    match vcpu.enable_cap(KVM_CAP_HYPERV_SYNIC) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to enable Hyper-V SynIC: {}", e);
+            false
+        }
+    }
+}
+
+fn kvm_get_supported_hv_cpuid(vcpu: &KvmVcpu) -> Result<Vec<KvmCpuIdEntry>> {
+    let mut cpuid = KvmCpuId2::new();
+    call_ioctl_with_mut_ref("KVM_GET_SUPPORTED_HV_CPUID", vcpu.raw_fd(), KVM_GET_SUPPORTED_HV_CPUID, &mut cpuid)?;
+    Ok(cpuid.get_entries())
+}
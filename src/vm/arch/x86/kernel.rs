@@ -1,24 +1,36 @@
-use std::io;
+use std::cmp;
 
 use crate::memory::GuestRam;
 use crate::system;
 use crate::util::ByteBuffer;
-use crate::vm::arch::PCI_MMIO_RESERVED_BASE;
+use crate::vm::arch::{Error, Result, PCI_MMIO_RESERVED_BASE};
+use crate::vm::arch::images::BootImages;
 use crate::vm::arch::x86::memory::HIMEM_BASE;
-use crate::vm::KERNEL;
 
 pub const KVM_KERNEL_LOAD_ADDRESS: u64 = 0x1000000;
 pub const KERNEL_CMDLINE_ADDRESS: u64 = 0x20000;
 pub const KERNEL_ZERO_PAGE: u64 = 0x7000;
+pub const PVH_START_INFO: u64 = 0x6000;
 
 // Documentation/x86/boot.txt
 
+const HDR_SETUP_SECTS: usize         = 0x1f1;  // u8
 const HDR_BOOT_FLAG: usize           = 0x1fe;  // u16
+const HDR_JUMP: usize                = 0x200;  // u16
 const HDR_HEADER: usize              = 0x202;  // u32
+const HDR_VERSION: usize             = 0x206;  // u16
 const HDR_TYPE_LOADER: usize         = 0x210;  // u8
+const HDR_RAMDISK_IMAGE: usize       = 0x218;  // u32
+const HDR_RAMDISK_SIZE: usize        = 0x21c;  // u32
 const HDR_CMDLINE_PTR: usize         = 0x228;  // u32
-const HDR_CMDLINE_SIZE: usize        = 0x238;  // u32
+const HDR_INITRD_ADDR_MAX: usize     = 0x22c;  // u32
 const HDR_KERNEL_ALIGNMENT: usize    = 0x230;  // u32
+const HDR_RELOCATABLE_KERNEL: usize  = 0x234;  // u8
+const HDR_XLOADFLAGS: usize          = 0x236;  // u16
+const HDR_CMDLINE_SIZE: usize        = 0x238;  // u32
+const HDR_PREF_ADDRESS: usize        = 0x258;  // u64
+const HDR_INIT_SIZE: usize           = 0x260;  // u32
+const HDR_MIN_SIZE: usize            = 0x264;
 
 // Documentation/x86/zero-page.txt
 
@@ -31,20 +43,54 @@ const KERNEL_HDR_MAGIC: u32 = 0x53726448;
 const KERNEL_LOADER_OTHER: u8 = 0xff;
 const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x1000000;
 
+// Boot protocol 2.12 added xloadflags, which tell whether the kernel has a
+// 64-bit entry point 0x200 bytes into the protected mode code
+const KERNEL_MIN_BOOT_PROTOCOL: u16 = 0x020c;
+const XLF_KERNEL_64: u16 = 1 << 0;
+const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
+const DEFAULT_INITRD_ADDR_MAX: u64 = 0x37ffffff;
+// COMMAND_LINE_SIZE of x86 less the terminating nul, for kernels which do
+// not give their own limit in a bzImage header. The kernel silently drops
+// anything past the limit.
+const DEFAULT_CMDLINE_MAX: usize = 2047;
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_HEADER_SIZE: usize = 64;
+const ELF_PHDR_SIZE: usize = 56;
+
+// xen/include/public/elfnote.h: a kernel which can be started at the PVH
+// entry point gives its 32-bit physical address in an ELF note
+const PT_NOTE: u32 = 4;
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+const XEN_ELFNOTE_NAME: &[u8] = b"Xen\0";
+
+// xen/include/public/arch-x86/hvm/start_info.h
+
+const PVH_START_MAGIC: u32 = 0x336ec578;
+// Version 1 added the memory map
+const PVH_START_VERSION: u32 = 1;
+const PVH_MODLIST: u64 = PVH_START_INFO + 0x40;
+const PVH_MEMMAP: u64 = PVH_START_INFO + 0x80;
+
 const E820_RAM: u32 = 1;
 
-fn setup_e820(memory: &GuestRam, mut zero: ByteBuffer<&mut [u8]>) -> system::Result<()> {
+// The RAM ranges given to the kernel as (address, size)
+fn ram_ranges(memory: &GuestRam) -> Vec<(u64, u64)> {
     let ram_size = memory.ram_size() as u64;
 
-    let mut e820_ranges = Vec::new();
-    e820_ranges.push((0u64, EBDA_START));
+    let mut ranges = Vec::new();
+    ranges.push((0u64, EBDA_START));
 
     if ram_size < PCI_MMIO_RESERVED_BASE {
-        e820_ranges.push((KVM_KERNEL_LOAD_ADDRESS, ram_size - KVM_KERNEL_LOAD_ADDRESS));
+        ranges.push((KVM_KERNEL_LOAD_ADDRESS, ram_size - KVM_KERNEL_LOAD_ADDRESS));
     } else {
-        e820_ranges.push((KVM_KERNEL_LOAD_ADDRESS, PCI_MMIO_RESERVED_BASE - KVM_KERNEL_LOAD_ADDRESS));
-        e820_ranges.push((HIMEM_BASE, ram_size - HIMEM_BASE));
+        ranges.push((KVM_KERNEL_LOAD_ADDRESS, PCI_MMIO_RESERVED_BASE - KVM_KERNEL_LOAD_ADDRESS));
+        ranges.push((HIMEM_BASE, ram_size - HIMEM_BASE));
     }
+    ranges
+}
+
+fn setup_e820(memory: &GuestRam, mut zero: ByteBuffer<&mut [u8]>) -> system::Result<()> {
+    let e820_ranges = ram_ranges(memory);
     zero.write_at(BOOT_PARAM_E820_ENTRIES , e820_ranges.len() as u8);
 
     zero.set_offset(BOOT_PARAM_E820_MAP);
@@ -56,46 +102,277 @@ fn setup_e820(memory: &GuestRam, mut zero: ByteBuffer<&mut [u8]>) -> system::Res
     Ok(())
 }
 
-fn setup_zero_page(memory: &GuestRam, cmdline_addr: u64, cmdline_size: usize) -> system::Result<()> {
+// The setup header of a bzImage is copied to the zero page, and a kernel
+// loaded from an ELF image is given a minimal header.
+fn setup_zero_page(memory: &GuestRam, kernel: &LoadedKernel, initrd: Option<(u64, usize)>, cmdline_addr: u64, cmdline_size: usize) -> system::Result<()> {
     let mut zero = memory.mut_buffer(KERNEL_ZERO_PAGE, 4096)?;
-    zero.write_at(HDR_BOOT_FLAG, KERNEL_BOOT_FLAG_MAGIC)
-        .write_at(HDR_HEADER, KERNEL_HDR_MAGIC)
-        .write_at(HDR_TYPE_LOADER, KERNEL_LOADER_OTHER)
+    match kernel.setup_header {
+        Some(ref header) => {
+            zero.mut_at(HDR_SETUP_SECTS, header.len()).copy_from_slice(header);
+        }
+        None => {
+            zero.write_at(HDR_BOOT_FLAG, KERNEL_BOOT_FLAG_MAGIC)
+                .write_at(HDR_HEADER, KERNEL_HDR_MAGIC)
+                .write_at(HDR_KERNEL_ALIGNMENT, KERNEL_MIN_ALIGNMENT_BYTES);
+        }
+    }
+    zero.write_at(HDR_TYPE_LOADER, KERNEL_LOADER_OTHER)
         .write_at(HDR_CMDLINE_PTR, cmdline_addr as u32)
-        .write_at(HDR_CMDLINE_SIZE, cmdline_size as u32)
-        .write_at(HDR_KERNEL_ALIGNMENT, KERNEL_MIN_ALIGNMENT_BYTES);
+        .write_at(HDR_CMDLINE_SIZE, cmdline_size as u32);
+    if let Some((addr, size)) = initrd {
+        zero.write_at(HDR_RAMDISK_IMAGE, addr as u32)
+            .write_at(HDR_RAMDISK_SIZE, size as u32);
+    }
 
     setup_e820(memory, zero)
 }
 
-pub fn load_pm_kernel(memory: &GuestRam, cmdline_addr: u64, cmdline_size: usize) -> system::Result<()> {
-    load_elf_kernel(memory)?;
-    setup_zero_page(memory,  cmdline_addr, cmdline_size)
+// A PVH kernel is passed the command line, the initrd as the only module and
+// the memory map in the start info instead of the zero page.
+fn setup_pvh_start_info(memory: &GuestRam, initrd: Option<(u64, usize)>, cmdline_addr: u64, rsdp_addr: Option<u64>) -> system::Result<()> {
+    let ranges = ram_ranges(memory);
+    let mut info = memory.mut_buffer(PVH_START_INFO, 0x1000)?;
+    info.write(PVH_START_MAGIC)
+        .write(PVH_START_VERSION)
+        .write(0u32)
+        .write(if initrd.is_some() { 1u32 } else { 0u32 })
+        .write(if initrd.is_some() { PVH_MODLIST } else { 0u64 })
+        .write(cmdline_addr)
+        .write(rsdp_addr.unwrap_or(0))
+        .write(PVH_MEMMAP)
+        .write(ranges.len() as u32)
+        .write(0u32);
+
+    if let Some((addr, size)) = initrd {
+        info.set_offset((PVH_MODLIST - PVH_START_INFO) as usize);
+        info.write(addr)
+            .write(size as u64)
+            .write(0u64)
+            .write(0u64);
+    }
+
+    info.set_offset((PVH_MEMMAP - PVH_START_INFO) as usize);
+    for &(addr, size) in &ranges {
+        info.write(addr)
+            .write(size)
+            .write(E820_RAM)
+            .write(0u32);
+    }
+    Ok(())
+}
+
+/// How the kernel is entered once it has been loaded.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum BootEntry {
+    /// The 64-bit entry point of the Linux boot protocol, entered in long
+    /// mode with the zero page in %rsi.
+    Linux64(u64),
+    /// The PVH entry point, entered in 32-bit protected mode without paging
+    /// and with the start info in %ebx.
+    Pvh(u64),
+}
+
+struct LoadedKernel {
+    entry: BootEntry,
+    // First address above the memory the kernel uses while it boots
+    end: u64,
+    // Header fields from the setup_sects byte onwards
+    setup_header: Option<Vec<u8>>,
+    initrd_addr_max: u64,
+    // Longest command line the kernel accepts, not counting the nul
+    cmdline_max: usize,
+}
+
+/// Load the kernel and initrd of `images` and write the zero page, or the
+/// PVH start info if `pvh` is set and the kernel has a PVH entry point.
+/// `rsdp_addr` is the address of the ACPI tables if there are any.
+pub fn load_kernel(memory: &GuestRam, images: &BootImages, cmdline_addr: u64, cmdline_size: usize, pvh: bool, rsdp_addr: Option<u64>) -> Result<BootEntry> {
+    let image = images.kernel();
+    let kernel = if image.starts_with(ELF_MAGIC) {
+        load_elf_kernel(memory, image, pvh)?
+    } else {
+        load_bzimage(memory, image)?
+    };
+    // `cmdline_size` includes the terminating nul
+    if cmdline_size - 1 > kernel.cmdline_max {
+        return Err(Error::CmdlineTooLong(cmdline_size - 1, kernel.cmdline_max));
+    }
+    let initrd = match images.initrd() {
+        Some(initrd) => Some(load_initrd(memory, &kernel, initrd)?),
+        None => None,
+    };
+    match kernel.entry {
+        BootEntry::Pvh(_) => setup_pvh_start_info(memory, initrd, cmdline_addr, rsdp_addr),
+        BootEntry::Linux64(_) => setup_zero_page(memory, &kernel, initrd, cmdline_addr, cmdline_size),
+    }.map_err(Error::LoadKernel)?;
+    Ok(kernel.entry)
+}
+
+// The end of the RAM below the PCI MMIO window, where the kernel and initrd
+// are placed
+fn low_ram_end(memory: &GuestRam) -> u64 {
+    cmp::min(memory.ram_size() as u64, PCI_MMIO_RESERVED_BASE)
 }
 
-fn load_elf_segment(memory: &GuestRam, hdr: ElfPhdr) {
-    let addr = hdr.p_paddr + KVM_KERNEL_LOAD_ADDRESS;
+fn elf_segment_data<'a>(image: &'a [u8], hdr: &ElfPhdr) -> Result<&'a [u8]> {
     let size = hdr.p_filesz as usize;
     let off = hdr.p_offset as usize;
-    let dst = memory.mut_slice(addr, size).unwrap();
-    let src = &KERNEL[off..off+size];
-    dst.copy_from_slice(src);
+    off.checked_add(size)
+        .and_then(|end| image.get(off..end))
+        .ok_or(Error::InvalidKernel("ELF segment extends past the end of the file"))
 }
 
-pub fn load_elf_kernel(memory: &GuestRam) -> io::Result<()> {
-    let mut k = ByteBuffer::from_bytes(KERNEL);
-    let phoff = k.read_at::<u64>(32);
-    let phnum = k.read_at::<u16>(56);
+// Find the PVH entry point in the notes of a PT_NOTE segment. Each note is
+// the name size, descriptor size and type followed by the name and the
+// descriptor, both padded to 4 bytes.
+fn find_pvh_entry(notes: &[u8]) -> Option<u64> {
+    let align = |n: usize| (n + 3) & !3;
+    let mut off = 0;
+    while off + 12 <= notes.len() {
+        let buf = ByteBuffer::from_bytes(&notes[off..]);
+        let namesz = buf.read_at::<u32>(0) as usize;
+        let descsz = buf.read_at::<u32>(4) as usize;
+        let ntype = buf.read_at::<u32>(8);
+        let name_off = off + 12;
+        let desc_off = name_off + align(namesz);
+        let desc = notes.get(desc_off..desc_off.checked_add(descsz)?)?;
+        if ntype == XEN_ELFNOTE_PHYS32_ENTRY && notes.get(name_off..name_off + namesz) == Some(XEN_ELFNOTE_NAME) {
+            // Linux gives the address as a 64-bit value
+            let desc = ByteBuffer::from_bytes(desc);
+            return match descsz {
+                4 => Some(desc.read_at::<u32>(0) as u64),
+                8 => Some(desc.read_at::<u64>(0)),
+                _ => None,
+            };
+        }
+        off = desc_off + align(descsz);
+    }
+    None
+}
 
-    k.set_offset(phoff as usize);
+// An ELF kernel is loaded 16 MiB above the physical addresses of its
+// segments and entered at 16 MiB, unless it is started at its PVH entry
+// point. It is then loaded at the physical addresses, so the PVH entry point
+// is only used when none of them is below the memory pH gives the kernel.
+fn load_elf_kernel(memory: &GuestRam, image: &[u8], pvh: bool) -> Result<LoadedKernel> {
+    if image.len() < ELF_HEADER_SIZE {
+        return Err(Error::InvalidKernel("truncated ELF header"));
+    }
+    let mut k = ByteBuffer::from_bytes(image);
+    let phoff = k.read_at::<u64>(32) as usize;
+    let phnum = k.read_at::<u16>(56) as usize;
+    match phoff.checked_add(phnum * ELF_PHDR_SIZE) {
+        Some(end) if end <= image.len() => {},
+        _ => return Err(Error::InvalidKernel("ELF program headers extend past the end of the file")),
+    }
+
+    k.set_offset(phoff);
+    let phdrs: Vec<ElfPhdr> = (0..phnum).map(|_| ElfPhdr::load_from(&mut k)).collect();
 
-    for _ in 0..phnum {
-        let hdr = ElfPhdr::load_from(&mut k);
-        if hdr.is_pt_load() {
-            load_elf_segment(memory, hdr);
+    let mut pvh_entry = None;
+    if pvh && phdrs.iter().filter(|h| h.is_pt_load()).all(|h| h.p_paddr >= KVM_KERNEL_LOAD_ADDRESS) {
+        for hdr in phdrs.iter().filter(|h| h.p_type == PT_NOTE) {
+            pvh_entry = find_pvh_entry(elf_segment_data(image, hdr)?);
+            if pvh_entry.is_some() {
+                break;
+            }
         }
     }
-    Ok(())
+    let (entry, offset) = match pvh_entry {
+        Some(entry) => (BootEntry::Pvh(entry), 0),
+        None => (BootEntry::Linux64(KVM_KERNEL_LOAD_ADDRESS), KVM_KERNEL_LOAD_ADDRESS),
+    };
+
+    let mut end = KVM_KERNEL_LOAD_ADDRESS;
+    for hdr in phdrs.iter().filter(|h| h.is_pt_load()) {
+        let addr = hdr.p_paddr + offset;
+        memory.write_bytes(addr, elf_segment_data(image, hdr)?)
+            .map_err(Error::LoadKernel)?;
+        end = cmp::max(end, addr + hdr.p_memsz);
+    }
+    Ok(LoadedKernel {
+        entry,
+        end,
+        setup_header: None,
+        initrd_addr_max: DEFAULT_INITRD_ADDR_MAX,
+        cmdline_max: DEFAULT_CMDLINE_MAX,
+    })
+}
+
+// Documentation/x86/boot.txt: the protected mode code follows the real mode
+// setup sectors, and is loaded at pref_address unless that is below the low
+// memory which pH gives the kernel or does not fit. A relocatable kernel is
+// then loaded at the start of that memory instead.
+fn load_bzimage(memory: &GuestRam, image: &[u8]) -> Result<LoadedKernel> {
+    if image.len() < HDR_MIN_SIZE {
+        return Err(Error::InvalidKernel("not an ELF or bzImage kernel"));
+    }
+    let hdr = ByteBuffer::from_bytes(image);
+    if hdr.read_at::<u32>(HDR_HEADER) != KERNEL_HDR_MAGIC {
+        return Err(Error::InvalidKernel("not an ELF or bzImage kernel"));
+    }
+    if hdr.read_at::<u16>(HDR_VERSION) < KERNEL_MIN_BOOT_PROTOCOL {
+        return Err(Error::InvalidKernel("bzImage boot protocol is older than 2.12"));
+    }
+    if hdr.read_at::<u16>(HDR_XLOADFLAGS) & XLF_KERNEL_64 == 0 {
+        return Err(Error::InvalidKernel("bzImage has no 64-bit entry point"));
+    }
+
+    let setup_sects = match image[HDR_SETUP_SECTS] {
+        0 => 4,
+        n => n as usize,
+    };
+    let code = image.get((setup_sects + 1) * 512..)
+        .ok_or(Error::InvalidKernel("truncated bzImage"))?;
+    let init_size = cmp::max(hdr.read_at::<u32>(HDR_INIT_SIZE) as u64, code.len() as u64);
+    let alignment = cmp::max(hdr.read_at::<u32>(HDR_KERNEL_ALIGNMENT) as u64, 1);
+    let relocatable = image[HDR_RELOCATABLE_KERNEL] != 0;
+    // The preferred address is read from the image and may be anywhere
+    let fits = |addr: u64| addr >= KVM_KERNEL_LOAD_ADDRESS &&
+        addr.checked_add(init_size).map_or(false, |end| end <= low_ram_end(memory));
+
+    let pref_address = hdr.read_at::<u64>(HDR_PREF_ADDRESS);
+    let load_address = if fits(pref_address) {
+        pref_address
+    } else if relocatable {
+        let address = (KVM_KERNEL_LOAD_ADDRESS + alignment - 1) / alignment * alignment;
+        if !fits(address) {
+            return Err(Error::InvalidKernel("kernel does not fit in guest memory"));
+        }
+        address
+    } else {
+        return Err(Error::InvalidKernel("kernel is not relocatable and its preferred address is not usable"));
+    };
+    memory.write_bytes(load_address, code)
+        .map_err(Error::LoadKernel)?;
+
+    // The header ends at the offset in the byte after the jump instruction
+    let header_end = HDR_JUMP + 2 + image[HDR_JUMP + 1] as usize;
+    let header_end = cmp::min(header_end, image.len());
+    Ok(LoadedKernel {
+        entry: BootEntry::Linux64(load_address + KERNEL_64BIT_ENTRY_OFFSET),
+        end: load_address + init_size,
+        setup_header: Some(image[HDR_SETUP_SECTS..header_end].to_vec()),
+        initrd_addr_max: hdr.read_at::<u32>(HDR_INITRD_ADDR_MAX) as u64,
+        cmdline_max: hdr.read_at::<u32>(HDR_CMDLINE_SIZE) as usize,
+    })
+}
+
+// The initrd is placed as high in low memory as the kernel allows, on a page
+// boundary.
+fn load_initrd(memory: &GuestRam, kernel: &LoadedKernel, initrd: &[u8]) -> Result<(u64, usize)> {
+    let top = cmp::min(kernel.initrd_addr_max + 1, low_ram_end(memory));
+    let size = initrd.len() as u64;
+    let address = match top.checked_sub(size) {
+        Some(address) => address & !0xfff,
+        None => return Err(Error::InitrdTooLarge(initrd.len())),
+    };
+    if address < kernel.end {
+        return Err(Error::InitrdTooLarge(initrd.len()));
+    }
+    memory.write_bytes(address, initrd)
+        .map_err(Error::LoadKernel)?;
+    Ok((address, initrd.len()))
 }
 
 struct ElfPhdr {
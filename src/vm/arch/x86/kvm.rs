@@ -19,12 +19,15 @@ static REQUIRED_EXTENSIONS: &[u32] = &[
     KVM_CAP_IOEVENTFD,
 ];
 
-pub fn x86_open_kvm() -> Result<Kvm> {
+// The PIT is left out with the minimal platform profile
+pub fn x86_open_kvm(create_pit: bool) -> Result<Kvm> {
     let kvm = Kvm::open(REQUIRED_EXTENSIONS)
-        .map_err(Error::KvmError)?;
-    kvm.create_irqchip().map_err(Error::KvmError)?;
+        .map_err(Error::OpenKvm)?;
+    kvm.create_irqchip().map_err(Error::OpenKvm)?;
     kvm_set_tss_addr(kvm.vmfd(), 0xFFFbd000)?;
-    kvm_create_pit2(kvm.vmfd())?;
+    if create_pit {
+        kvm_create_pit2(kvm.vmfd())?;
+    }
     Ok(kvm)
 }
 
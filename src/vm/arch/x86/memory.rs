@@ -1,37 +1,30 @@
-use crate::kvm::Kvm;
-use crate::memory::{MemoryManager, MemoryRegion, GuestRam};
+use crate::memory::{MemoryManager, GuestRam, HugePages};
 use crate::vm::arch::{Error, Result};
+use crate::vm::arch::ram::setup_ram_regions;
 use std::cmp;
+use std::path::Path;
 use crate::vm::kernel_cmdline::KernelCmdLine;
-use crate::vm::arch::x86::kernel::{load_pm_kernel, KERNEL_CMDLINE_ADDRESS};
+use crate::vm::arch::images::BootImages;
+use crate::vm::arch::x86::kernel::{load_kernel, BootEntry, KERNEL_CMDLINE_ADDRESS};
 use crate::system;
 use crate::vm::arch::x86::mptable::setup_mptable;
-use crate::virtio::PciIrq;
+use crate::vm::arch::x86::acpi::{setup_acpi_tables, BootTables, RSDP_ADDRESS};
+use crate::virtio::{HotplugSlots, PciIrq};
 
 pub const HIMEM_BASE: u64 = (1 << 32);
 pub const PCI_MMIO_RESERVED_SIZE: usize = (512 << 20);
 pub const PCI_MMIO_RESERVED_BASE: u64 = HIMEM_BASE - PCI_MMIO_RESERVED_SIZE as u64;
 
 
-pub fn x86_setup_memory_regions(memory: &mut MemoryManager, ram_size: usize) -> Result<()> {
-    let mut regions = Vec::new();
+/// Create the guest RAM regions, with the RAM above the PCI MMIO window
+/// moved up to start at 4gb. See `setup_ram_regions()`.
+pub fn x86_setup_memory_regions(memory: &mut MemoryManager, ram_size: usize, ram_file: Option<&Path>, keep_file: bool, share_ram: bool, huge_pages: HugePages) -> Result<()> {
     let lowmem_sz = cmp::min(ram_size, PCI_MMIO_RESERVED_BASE as usize);
-    regions.push(create_region(memory.kvm(),  0, lowmem_sz, 0)?);
-
+    let mut ranges = vec![(0, lowmem_sz)];
     if lowmem_sz < ram_size {
-        let himem_sz = ram_size - lowmem_sz;
-        regions.push(create_region(memory.kvm(), HIMEM_BASE, himem_sz, 1)?);
+        ranges.push((HIMEM_BASE, ram_size - lowmem_sz));
     }
-    memory.set_ram_regions(regions);
-    Ok(())
-}
-
-fn create_region(kvm: &Kvm, base: u64, size: usize, slot: u32) -> Result<MemoryRegion> {
-    let mr = MemoryRegion::new(base, size)
-        .map_err(Error::MemoryRegionCreate)?;
-    kvm.add_memory_region(slot, base, mr.base_address(), size)
-        .map_err(Error::MemoryRegister)?;
-    Ok(mr)
+    setup_ram_regions(memory, &ranges, ram_file, keep_file, share_ram, huge_pages)
 }
 
 const BOOT_GDT_OFFSET: usize = 0x500;
@@ -41,14 +34,25 @@ const BOOT_PML4: u64 = 0x9000;
 const BOOT_PDPTE: u64 = 0xA000;
 const BOOT_PDE: u64 = 0xB000;
 
-pub fn x86_setup_memory(memory: &mut MemoryManager, cmdline: &KernelCmdLine, ncpus: usize, pci_irqs: &[PciIrq]) -> Result<()> {
-    load_pm_kernel(memory.guest_ram(), KERNEL_CMDLINE_ADDRESS, cmdline.size())
-        .map_err(Error::LoadKernel)?;
-    setup_gdt(memory.guest_ram())?;
-    setup_boot_pagetables(memory.guest_ram()).map_err(Error::SystemError)?;
-    setup_mptable(memory.guest_ram(), ncpus, pci_irqs).map_err(Error::SystemError)?;
+/// Load the kernel and write the boot tables, returning the entry point of
+/// the kernel. The PVH entry point is used if `pvh` is set and the kernel
+/// has one.
+pub fn x86_setup_memory(memory: &mut MemoryManager, images: &BootImages, cmdline: &KernelCmdLine, ncpus: usize, pci_irqs: &[PciIrq], hotplug: Option<&HotplugSlots>, boot_tables: BootTables, pvh: bool) -> Result<BootEntry> {
+    let rsdp_addr = if boot_tables.has_acpi() { Some(RSDP_ADDRESS) } else { None };
+    let entry = load_kernel(memory.guest_ram(), images, KERNEL_CMDLINE_ADDRESS, cmdline.size(), pvh, rsdp_addr)?;
+    setup_gdt(memory.guest_ram(), entry)?;
+    // A PVH kernel starts without paging and sets up its own page tables
+    if let BootEntry::Linux64(_) = entry {
+        setup_boot_pagetables(memory.guest_ram()).map_err(Error::SystemError)?;
+    }
+    if boot_tables.has_mptable() {
+        setup_mptable(memory.guest_ram(), ncpus, pci_irqs).map_err(Error::SystemError)?;
+    }
+    if boot_tables.has_acpi() {
+        setup_acpi_tables(memory.guest_ram(), ncpus, pci_irqs, hotplug)?;
+    }
     write_cmdline(memory.guest_ram(), cmdline).map_err(Error::SystemError)?;
-    Ok(())
+    Ok(entry)
 }
 
 fn setup_boot_pagetables(memory: &GuestRam) -> system::Result<()> {
@@ -74,10 +78,14 @@ pub fn gdt_entry(flags: u16, base: u32, limit: u32) -> u64 {
         (((base as u64) & 0x00ffffffu64) << 16) | ((limit as u64) & 0x0000ffffu64))
 }
 
-pub fn setup_gdt(memory: &GuestRam) -> Result<()> {
+pub fn setup_gdt(memory: &GuestRam, entry: BootEntry) -> Result<()> {
+    let code = match entry {
+        BootEntry::Linux64(_) => gdt_entry(0xa09b,0,0xfffff),
+        BootEntry::Pvh(_) => gdt_entry(0xc09b,0,0xfffff),
+    };
     let table = [
         gdt_entry(0,0,0),
-        gdt_entry(0xa09b,0,0xfffff),
+        code,
         gdt_entry(0xc093,0,0xfffff),
         gdt_entry(0x808b,0,0xfffff),
     ];
@@ -1,4 +1,6 @@
+mod acpi;
 mod cpuid;
+mod hyperv;
 mod interrupts;
 mod kvm;
 mod memory;
@@ -7,7 +9,10 @@ mod registers;
 mod kernel;
 mod ioctl;
 mod setup;
+mod state;
 
 pub use setup::X86ArchSetup;
-pub use memory::PCI_MMIO_RESERVED_BASE;
+pub use acpi::BootTables;
+pub use memory::{PCI_MMIO_RESERVED_BASE, PCI_MMIO_RESERVED_SIZE};
 pub use registers::KvmRegs;
+pub use state::VcpuState;
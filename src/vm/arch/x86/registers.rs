@@ -1,11 +1,13 @@
 use std::fmt;
 use std::os::unix::io::RawFd;
 
-use crate::kvm::KvmVcpu;
+use crate::kvm::{KvmVcpu, KVM_CAP_XCRS};
 use crate::vm::arch::{Result, Error};
-use crate::vm::arch::x86::kernel::KERNEL_ZERO_PAGE;
+use crate::vm::arch::x86::cpuid::kvm_get_supported_cpuid;
+use crate::vm::arch::x86::kernel::{BootEntry, KERNEL_ZERO_PAGE, PVH_START_INFO};
 use crate::vm::arch::x86::ioctl::{
-    call_ioctl_with_ref, KVM_SET_FPU, KVM_SET_MSRS, call_ioctl_with_mut_ref, KVM_GET_SREGS, KVM_SET_SREGS
+    call_ioctl_with_ref, KVM_GET_FPU, KVM_SET_FPU, KVM_SET_MSRS, call_ioctl_with_mut_ref, KVM_GET_SREGS, KVM_SET_SREGS,
+    KVM_GET_XSAVE, KVM_SET_XSAVE, KVM_GET_XCRS, KVM_SET_XCRS,
 };
 
 const MSR_IA32_SYSENTER_CS: u32  = 0x00000174;
@@ -29,6 +31,37 @@ pub fn setup_fpu(vcpu: &KvmVcpu) -> Result<()> {
     Ok(())
 }
 
+const XCR_XFEATURE_ENABLED_MASK: u32 = 0;
+
+const XSTATE_FP: u64  = 1 << 0;
+const XSTATE_SSE: u64 = 1 << 1;
+const XSTATE_YMM: u64 = 1 << 2;
+
+///
+/// Set the initial value of XCR0 to enable x87, SSE and AVX state
+/// (as far as KVM supports them) so that extended state is valid from
+/// the first instruction rather than relying on the KVM default of x87 only.
+///
+pub fn setup_xcrs(vcpu: &KvmVcpu) -> Result<()> {
+    if !vcpu.has_extension(KVM_CAP_XCRS) {
+        return Ok(());
+    }
+    let supported = supported_xcr0(vcpu)?;
+    let mut xcrs = KvmXcrs::new();
+    xcrs.add(XCR_XFEATURE_ENABLED_MASK, supported & (XSTATE_FP | XSTATE_SSE | XSTATE_YMM));
+    kvm_set_xcrs(vcpu.raw_fd(), &xcrs)
+}
+
+// CPUID leaf 0xD subleaf 0 reports the XCR0 bits which may be enabled
+fn supported_xcr0(vcpu: &KvmVcpu) -> Result<u64> {
+    let cpuid = kvm_get_supported_cpuid(vcpu.sys_raw_fd())?;
+    let xcr0 = cpuid.iter()
+        .find(|e| e.function == 0xd && e.index == 0)
+        .map(|e| (e.edx as u64) << 32 | e.eax as u64)
+        .unwrap_or(XSTATE_FP | XSTATE_SSE);
+    Ok(xcr0 | XSTATE_FP)
+}
+
 pub fn setup_msrs(vcpu: &KvmVcpu) -> Result<()> {
     let mut msrs = KvmMsrs::new();
     msrs.add(MSR_IA32_SYSENTER_CS, 0);
@@ -58,7 +91,21 @@ const X86_CR4_PAE: u64 = 0x20;
 const EFER_LME: u64 = 0x100;
 const EFER_LMA: u64 = (1 << 10);
 
-pub fn setup_pm_sregs(vcpu: &KvmVcpu) -> Result<()> {
+pub fn setup_sregs(vcpu: &KvmVcpu, entry: BootEntry) -> Result<()> {
+    match entry {
+        BootEntry::Linux64(_) => setup_pm_sregs(vcpu),
+        BootEntry::Pvh(_) => setup_pvh_sregs(vcpu),
+    }
+}
+
+pub fn setup_regs(vcpu: &KvmVcpu, entry: BootEntry) -> Result<()> {
+    match entry {
+        BootEntry::Linux64(rip) => setup_pm_regs(vcpu, rip),
+        BootEntry::Pvh(rip) => setup_pvh_regs(vcpu, rip),
+    }
+}
+
+fn setup_pm_sregs(vcpu: &KvmVcpu) -> Result<()> {
 
     let code = KvmSegment::new(0, 0xfffff, 1 * 8, 0xa09b);
     let data = KvmSegment::new(0, 0xfffff, 2 * 8, 0xc093);
@@ -93,7 +140,7 @@ pub fn setup_pm_sregs(vcpu: &KvmVcpu) -> Result<()> {
     Ok(())
 }
 
-pub fn setup_pm_regs(vcpu: &KvmVcpu, kernel_entry: u64) -> Result<()> {
+fn setup_pm_regs(vcpu: &KvmVcpu, kernel_entry: u64) -> Result<()> {
     let mut regs = KvmRegs::new();
     regs.rflags = 0x0000000000000002;
     regs.rip = kernel_entry;
@@ -105,6 +152,49 @@ pub fn setup_pm_regs(vcpu: &KvmVcpu, kernel_entry: u64) -> Result<()> {
     Ok(())
 }
 
+// Documentation of the PVH boot ABI in xen/docs/misc/pvh.pandoc: flat 32-bit
+// segments, protected mode without paging and every other bit of CR0 and
+// CR4 clear.
+fn setup_pvh_sregs(vcpu: &KvmVcpu) -> Result<()> {
+    let code = KvmSegment::new(0, 0xfffff, 1 * 8, 0xc09b);
+    let data = KvmSegment::new(0, 0xfffff, 2 * 8, 0xc093);
+    let tss = KvmSegment::new(0, 0xfffff, 3 * 8, 0x808b);
+
+    let mut regs = kvm_get_sregs(vcpu.raw_fd())?;
+
+    regs.gdt.base = BOOT_GDT_OFFSET as u64;
+    regs.gdt.limit = 32 - 1;
+
+    regs.itd.base = BOOT_IDT_OFFSET as u64;
+    regs.itd.limit = 8 - 1;
+
+    regs.cs = code;
+    regs.ds = data;
+    regs.es = data;
+    regs.fs = data;
+    regs.gs = data;
+    regs.ss = data;
+    regs.tr = tss;
+
+    regs.cr0 = X86_CR0_PE;
+    regs.cr3 = 0;
+    regs.cr4 = 0;
+    regs.efer = 0;
+
+    kvm_set_sregs(vcpu.raw_fd(), &regs)?;
+    Ok(())
+}
+
+fn setup_pvh_regs(vcpu: &KvmVcpu, kernel_entry: u64) -> Result<()> {
+    let mut regs = KvmRegs::new();
+    regs.rflags = 0x0000000000000002;
+    regs.rip = kernel_entry;
+    regs.rbx = PVH_START_INFO;
+    vcpu.set_regs(&regs)
+        .map_err(Error::KvmError)?;
+    Ok(())
+}
+
 #[derive(Copy)]
 #[repr(C)]
 pub struct KvmFpu {
@@ -141,10 +231,113 @@ impl KvmFpu {
     }
 }
 
+pub fn kvm_get_fpu(cpufd: RawFd) -> Result<KvmFpu> {
+    let mut fpu = KvmFpu::new();
+    call_ioctl_with_mut_ref("KVM_GET_FPU", cpufd, KVM_GET_FPU, &mut fpu)?;
+    Ok(fpu)
+}
+
 pub fn kvm_set_fpu(cpufd: RawFd, fpu: &KvmFpu) -> Result<()> {
     call_ioctl_with_ref("KVM_SET_FPU", cpufd, KVM_SET_FPU, fpu)
 }
 
+/// The XSAVE area of a vcpu in the format used by the `XSAVE` instruction.
+#[derive(Copy)]
+#[repr(C)]
+pub struct KvmXsave {
+    region: [u32; 1024],
+}
+
+impl Clone for KvmXsave {
+    fn clone(&self) -> KvmXsave { *self }
+}
+
+impl KvmXsave {
+    pub fn new() -> KvmXsave {
+        KvmXsave { region: [0; 1024] }
+    }
+
+    /// The XSTATE_BV field of the XSAVE header, which lists the state
+    /// components present in this area.
+    pub fn xstate_bv(&self) -> u64 {
+        (self.region[129] as u64) << 32 | self.region[128] as u64
+    }
+
+    #[cfg(test)]
+    pub fn region_mut(&mut self) -> &mut [u32; 1024] {
+        &mut self.region
+    }
+
+    #[cfg(test)]
+    pub fn region(&self) -> &[u32; 1024] {
+        &self.region
+    }
+}
+
+pub fn kvm_get_xsave(cpufd: RawFd) -> Result<KvmXsave> {
+    let mut xsave = KvmXsave::new();
+    call_ioctl_with_mut_ref("KVM_GET_XSAVE", cpufd, KVM_GET_XSAVE, &mut xsave)?;
+    Ok(xsave)
+}
+
+pub fn kvm_set_xsave(cpufd: RawFd, xsave: &KvmXsave) -> Result<()> {
+    call_ioctl_with_ref("KVM_SET_XSAVE", cpufd, KVM_SET_XSAVE, xsave)
+}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct KvmXcr {
+    xcr: u32,
+    reserved: u32,
+    value: u64,
+}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct KvmXcrs {
+    nr_xcrs: u32,
+    flags: u32,
+    xcrs: [KvmXcr; 16],
+    padding: [u64; 16],
+}
+
+impl KvmXcrs {
+    pub fn new() -> KvmXcrs {
+        KvmXcrs { ..Default::default() }
+    }
+
+    pub fn add(&mut self, xcr: u32, value: u64) {
+        self.xcrs[self.nr_xcrs as usize].xcr = xcr;
+        self.xcrs[self.nr_xcrs as usize].value = value;
+        self.nr_xcrs += 1;
+    }
+
+    pub fn get(&self, xcr: u32) -> Option<u64> {
+        self.xcrs[..self.nr_xcrs as usize].iter()
+            .find(|x| x.xcr == xcr)
+            .map(|x| x.value)
+    }
+}
+
+impl fmt::Debug for KvmXcrs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for x in &self.xcrs[..self.nr_xcrs as usize] {
+            write!(f, "xcr{}: {:x} ", x.xcr, x.value)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn kvm_get_xcrs(cpufd: RawFd) -> Result<KvmXcrs> {
+    let mut xcrs = KvmXcrs::new();
+    call_ioctl_with_mut_ref("KVM_GET_XCRS", cpufd, KVM_GET_XCRS, &mut xcrs)?;
+    Ok(xcrs)
+}
+
+pub fn kvm_set_xcrs(cpufd: RawFd, xcrs: &KvmXcrs) -> Result<()> {
+    call_ioctl_with_ref("KVM_SET_XCRS", cpufd, KVM_SET_XCRS, xcrs)
+}
+
 #[derive(Copy, Clone, Default)]
 #[repr(C)]
 struct KvmMsrEntry {
@@ -201,6 +394,10 @@ impl KvmSegment {
         seg
     }
 
+    pub fn selector(&self) -> u16 {
+        self.selector
+    }
+
     pub fn setup(&mut self, base: u64, limit: u32, selector: u16, flags: u16) {
         self.base = base;
         self.limit = limit;
@@ -1,20 +1,37 @@
-use crate::memory::{MemoryManager, GuestRam, SystemAllocator, AddressRange};
+use std::path::PathBuf;
+
+use crate::memory::{MemoryManager, GuestRam, SystemAllocator, AddressRange, HugePages};
 use crate::vm::VmConfig;
 use crate::vm::arch::{ArchSetup, Error, Result};
 use crate::vm::kernel_cmdline::KernelCmdLine;
-use crate::virtio::PciIrq;
+use crate::virtio::{HotplugSlots, PciIrq};
 use crate::kvm::{Kvm, KvmVcpu};
 use crate::vm::arch::x86::kvm::x86_open_kvm;
-use crate::vm::arch::x86::memory::{x86_setup_memory_regions, x86_setup_memory};
-use crate::vm::arch::x86::cpuid::setup_cpuid;
-use crate::vm::arch::x86::registers::{setup_pm_sregs, setup_pm_regs, setup_fpu, setup_msrs};
+use crate::vm::arch::x86::memory::{x86_setup_memory_regions, x86_setup_memory, HIMEM_BASE, PCI_MMIO_RESERVED_BASE};
+use crate::vm::arch::x86::cpuid::{setup_cpuid, PvFeatures};
+use crate::vm::arch::x86::registers::{setup_sregs, setup_regs, setup_fpu, setup_xcrs, setup_msrs};
 use crate::vm::arch::x86::interrupts::setup_lapic;
-use crate::vm::arch::x86::kernel::KVM_KERNEL_LOAD_ADDRESS;
+use crate::vm::arch::images::BootImages;
+use crate::vm::arch::x86::kernel::{BootEntry, KVM_KERNEL_LOAD_ADDRESS};
+use crate::vm::arch::x86::acpi::BootTables;
 
 pub struct X86ArchSetup {
     ram_size: usize,
     use_drm: bool,
     ncpus: usize,
+    hyperv: bool,
+    pv_features: PvFeatures,
+    deterministic: bool,
+    boot_tables: BootTables,
+    create_pit: bool,
+    ram_file: Option<PathBuf>,
+    keep_ram_file: bool,
+    share_ram: bool,
+    huge_pages: HugePages,
+    kernel_path: Option<PathBuf>,
+    initrd_path: Option<PathBuf>,
+    pvh: bool,
+    kernel_entry: BootEntry,
     memory: Option<MemoryManager>,
 }
 
@@ -26,6 +43,22 @@ impl X86ArchSetup {
             ram_size,
             use_drm,
             ncpus: config.ncpus(),
+            hyperv: config.is_hyperv_enabled(),
+            pv_features: PvFeatures {
+                steal_time: config.is_steal_time_enabled(),
+                pv_eoi: config.is_pv_eoi_enabled(),
+            },
+            deterministic: config.deterministic_seed().is_some(),
+            boot_tables: config.get_boot_tables(),
+            create_pit: config.get_platform_profile().has_legacy_devices(),
+            ram_file: config.ram_backing_file().map(|p| p.to_path_buf()),
+            keep_ram_file: config.get_ram_file().is_some(),
+            share_ram: !config.vhost_user_devices().is_empty(),
+            huge_pages: config.get_huge_pages(),
+            kernel_path: config.get_kernel_path().map(|p| p.to_path_buf()),
+            initrd_path: config.get_initrd_path().map(|p| p.to_path_buf()),
+            pvh: config.is_pvh_enabled(),
+            kernel_entry: BootEntry::Linux64(KVM_KERNEL_LOAD_ADDRESS),
             memory: None,
         }
     }
@@ -33,15 +66,20 @@ impl X86ArchSetup {
 
 fn get_base_dev_pfn(mem_size: u64) -> u64 {
     // Put device memory at a 2MB boundary after physical memory or 4gb, whichever is greater.
+    // RAM above the PCI MMIO window below 4gb is moved up to start at 4gb.
     const MB: u64 = 1024 * 1024;
-    const GB: u64 = 1024 * MB;
-    let mem_size_round_2mb = (mem_size + 2 * MB - 1) / (2 * MB) * (2 * MB);
-    std::cmp::max(mem_size_round_2mb, 4 * GB) / 4096
+    let mem_end = if mem_size > PCI_MMIO_RESERVED_BASE {
+        HIMEM_BASE + (mem_size - PCI_MMIO_RESERVED_BASE)
+    } else {
+        mem_size
+    };
+    let mem_end_round_2mb = (mem_end + 2 * MB - 1) / (2 * MB) * (2 * MB);
+    std::cmp::max(mem_end_round_2mb, HIMEM_BASE) / 4096
 }
 
 impl ArchSetup for X86ArchSetup {
-    fn open_kvm(&self) -> Result<Kvm> {
-        x86_open_kvm()
+    fn open_kvm(&mut self) -> Result<Kvm> {
+        x86_open_kvm(self.create_pit)
     }
 
     fn create_memory(&mut self, kvm: &Kvm) -> Result<MemoryManager> {
@@ -51,22 +89,25 @@ impl ArchSetup for X86ArchSetup {
         let allocator = SystemAllocator::new(AddressRange::new(dev_addr_start,dev_addr_size as usize));
         let mut mm = MemoryManager::new(kvm.clone(), ram, allocator, self.use_drm)
             .map_err(Error::MemoryManagerCreate)?;
-        x86_setup_memory_regions(&mut mm, self.ram_size)?;
+        x86_setup_memory_regions(&mut mm, self.ram_size, self.ram_file.as_ref().map(|p| p.as_path()), self.keep_ram_file, self.share_ram, self.huge_pages)?;
         self.memory = Some(mm.clone());
         Ok(mm)
     }
 
-    fn setup_memory(&mut self, cmdline: &KernelCmdLine, pci_irqs: &[PciIrq]) -> Result<()> {
+    // The virtio-mmio devices are given on the kernel command line
+    fn setup_memory(&mut self, cmdline: &KernelCmdLine, pci_irqs: &[PciIrq], hotplug: Option<&HotplugSlots>, _mmio_devices: &[(AddressRange, u8)]) -> Result<()> {
+        let images = BootImages::load(self.kernel_path.as_ref().map(|p| p.as_path()), self.initrd_path.as_ref().map(|p| p.as_path()))?;
         let memory = self.memory.as_mut().expect("No memory created");
-        x86_setup_memory(memory, cmdline, self.ncpus, pci_irqs)?;
+        self.kernel_entry = x86_setup_memory(memory, &images, cmdline, self.ncpus, pci_irqs, hotplug, self.boot_tables, self.pvh)?;
         Ok(())
     }
 
     fn setup_vcpu(&self, vcpu: &KvmVcpu) -> Result<()> {
-        setup_cpuid(vcpu)?;
-        setup_pm_sregs(vcpu)?;
-        setup_pm_regs(&vcpu, KVM_KERNEL_LOAD_ADDRESS)?;
+        setup_cpuid(vcpu, self.ncpus, self.hyperv, self.deterministic, self.pv_features)?;
+        setup_sregs(vcpu, self.kernel_entry)?;
+        setup_regs(vcpu, self.kernel_entry)?;
         setup_fpu(vcpu)?;
+        setup_xcrs(vcpu)?;
         setup_msrs(vcpu)?;
         setup_lapic(vcpu.raw_fd())
     }
@@ -0,0 +1,181 @@
+use std::fmt;
+
+use crate::kvm::{KvmVcpu, KvmVcpuEvents, MpState, KVM_CAP_XSAVE, KVM_CAP_XCRS};
+use crate::vm::arch::{Result, Error};
+use crate::vm::arch::x86::registers::{
+    KvmRegs, KvmSRegs, KvmFpu, KvmXsave, KvmXcrs, kvm_get_sregs, kvm_set_sregs, kvm_get_fpu, kvm_set_fpu,
+    kvm_get_xsave, kvm_set_xsave, kvm_get_xcrs, kvm_set_xcrs,
+};
+
+///
+/// The architectural state of a vcpu which is not stored in guest memory.
+///
+/// When KVM supports `KVM_CAP_XSAVE` the floating point and vector state is
+/// captured as a complete XSAVE area which includes AVX and later extended
+/// state components, otherwise only the legacy FXSAVE image is captured.
+///
+pub struct VcpuState {
+    regs: KvmRegs,
+    sregs: KvmSRegs,
+    fpu: FpuState,
+    xcrs: Option<KvmXcrs>,
+    mp_state: MpState,
+    events: KvmVcpuEvents,
+}
+
+enum FpuState {
+    Legacy(KvmFpu),
+    Xsave(Box<KvmXsave>),
+}
+
+impl VcpuState {
+    pub fn capture(vcpu: &KvmVcpu) -> Result<VcpuState> {
+        let regs = vcpu.get_regs().map_err(Error::KvmError)?;
+        let sregs = kvm_get_sregs(vcpu.raw_fd())?;
+        let fpu = if vcpu.has_extension(KVM_CAP_XSAVE) {
+            FpuState::Xsave(Box::new(kvm_get_xsave(vcpu.raw_fd())?))
+        } else {
+            FpuState::Legacy(kvm_get_fpu(vcpu.raw_fd())?)
+        };
+        let xcrs = if vcpu.has_extension(KVM_CAP_XCRS) {
+            Some(kvm_get_xcrs(vcpu.raw_fd())?)
+        } else {
+            None
+        };
+        let mp_state = vcpu.get_mp_state().map_err(Error::KvmError)?;
+        let events = vcpu.get_vcpu_events().map_err(Error::KvmError)?;
+        Ok(VcpuState { regs, sregs, fpu, xcrs, mp_state, events })
+    }
+
+    /// Load the captured state into `vcpu`, which may be a different vcpu
+    /// than the one it was captured from, as when restoring a snapshot.
+    #[allow(dead_code)]
+    pub fn restore(&self, vcpu: &KvmVcpu) -> Result<()> {
+        kvm_set_sregs(vcpu.raw_fd(), &self.sregs)?;
+        vcpu.set_regs(&self.regs).map_err(Error::KvmError)?;
+        // XCR0 must be restored first, since the XSAVE area may contain
+        // components which are only valid once enabled in XCR0
+        if let Some(ref xcrs) = self.xcrs {
+            kvm_set_xcrs(vcpu.raw_fd(), xcrs)?;
+        }
+        match self.fpu {
+            FpuState::Legacy(ref fpu) => kvm_set_fpu(vcpu.raw_fd(), fpu)?,
+            FpuState::Xsave(ref xsave) => kvm_set_xsave(vcpu.raw_fd(), xsave)?,
+        }
+        vcpu.set_mp_state(self.mp_state).map_err(Error::KvmError)?;
+        vcpu.set_vcpu_events(&self.events).map_err(Error::KvmError)
+    }
+
+    pub fn regs(&self) -> &KvmRegs {
+        &self.regs
+    }
+
+    pub fn sregs(&self) -> &KvmSRegs {
+        &self.sregs
+    }
+
+    /// The value of XCR0 if it was captured
+    pub fn xcr0(&self) -> Option<u64> {
+        self.xcrs.as_ref().and_then(|xcrs| xcrs.get(0))
+    }
+
+    /// The set of state components present in the captured XSAVE area
+    pub fn xstate_bv(&self) -> Option<u64> {
+        match self.fpu {
+            FpuState::Xsave(ref xsave) => Some(xsave.xstate_bv()),
+            FpuState::Legacy(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for VcpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}{:?}", self.regs, self.sregs)?;
+        if let Some(xcr0) = self.xcr0() {
+            write!(f, "xcr0: {:x} ", xcr0)?;
+        }
+        if let Some(xstate_bv) = self.xstate_bv() {
+            write!(f, "xstate_bv: {:x} ", xstate_bv)?;
+        }
+        write!(f, "mp_state: {:?}\n", self.mp_state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvm::{Kvm, MpState};
+    use crate::kvm::KvmVcpu;
+    use crate::vm::arch::x86::cpuid::{setup_cpuid, PvFeatures};
+    use crate::vm::arch::x86::registers::setup_xcrs;
+    use super::{FpuState, VcpuState};
+
+    // The upper halves of the YMM registers follow the legacy area and the
+    // XSAVE header in the standard format of the XSAVE area
+    const YMM_HI128_OFFSET: usize = 576 / 4;
+    const XSTATE_YMM: u32 = 1 << 2;
+
+    fn open_kvm() -> Option<Kvm> {
+        match Kvm::open(&[]) {
+            Ok(kvm) => {
+                kvm.create_irqchip().unwrap();
+                Some(kvm)
+            }
+            Err(e) => {
+                eprintln!("skipping test which needs /dev/kvm: {}", e);
+                None
+            }
+        }
+    }
+
+    // KVM checks XCR0 against the CPUID of the vcpu
+    fn new_vcpu(kvm: &Kvm, id: usize) -> KvmVcpu {
+        let vcpu = kvm.new_vcpu(id).unwrap();
+        let pv = PvFeatures { steal_time: false, pv_eoi: false };
+        setup_cpuid(&vcpu, 2, false, false, pv).unwrap();
+        vcpu
+    }
+
+    #[test]
+    fn restore_of_captured_state_round_trips() {
+        let kvm = match open_kvm() {
+            Some(kvm) => kvm,
+            None => return,
+        };
+        let source = new_vcpu(&kvm, 0);
+        setup_xcrs(&source).unwrap();
+        let mut state = VcpuState::capture(&source).unwrap();
+
+        state.regs.rax = 0x1122_3344_5566_7788;
+        state.regs.r15 = 0x0123_4567_89ab_cdef;
+        state.regs.rip = 0xfff0;
+        state.sregs.gdt.base = 0x1000;
+        state.mp_state = MpState::Halted;
+        state.events.nmi.masked = 1;
+        let avx = state.xcr0().map_or(false, |xcr0| xcr0 & u64::from(XSTATE_YMM) != 0);
+        if let (true, FpuState::Xsave(ref mut xsave)) = (avx, &mut state.fpu) {
+            let region = xsave.region_mut();
+            region[128] |= XSTATE_YMM;
+            for (i, word) in region[YMM_HI128_OFFSET..YMM_HI128_OFFSET + 8].iter_mut().enumerate() {
+                *word = 0xa5a5_0000 | i as u32;
+            }
+        }
+
+        let target = new_vcpu(&kvm, 1);
+        state.restore(&target).unwrap();
+        let restored = VcpuState::capture(&target).unwrap();
+
+        assert_eq!(restored.regs.rax, state.regs.rax);
+        assert_eq!(restored.regs.r15, state.regs.r15);
+        assert_eq!(restored.regs.rip, state.regs.rip);
+        assert_eq!(restored.sregs.gdt.base, state.sregs.gdt.base);
+        assert_eq!(restored.xcr0(), state.xcr0());
+        assert_eq!(restored.mp_state, MpState::Halted);
+        assert_eq!(restored.events.nmi.masked, 1);
+        if let (true, FpuState::Xsave(ref xsave)) = (avx, &restored.fpu) {
+            assert_ne!(xsave.xstate_bv() & u64::from(XSTATE_YMM), 0, "AVX state is present");
+            let ymm = &xsave.region()[YMM_HI128_OFFSET..YMM_HI128_OFFSET + 8];
+            let expected: Vec<u32> = (0..8).map(|i| 0xa5a5_0000 | i).collect();
+            assert_eq!(ymm, &expected[..], "upper halves of ymm0 and ymm1");
+        }
+    }
+}
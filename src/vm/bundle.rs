@@ -0,0 +1,245 @@
+use std::{fmt, fs, io, result};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::util::JsonValue;
+
+const MANIFEST_NAME: &str = "manifest.json";
+const FILES_DIR: &str = "files";
+const BUNDLE_FORMAT: i64 = 1;
+
+// Options with a value which names a file on the host. `--disk` and
+// `--add-disk` may be followed by disk options after a comma, and the host
+// file of `--boot-file` follows the guest path and '='.
+const FILE_OPTIONS: &[&str] = &[
+    "--disk", "--add-disk", "--kernel", "--initrd", "--module-key", "--boot-file",
+];
+
+// Options with a value which refers to sockets, logs, directories, files or
+// commands of the host the VM was started on. They are left out of a bundle
+// and refused in the manifest of one. Guest RAM is left out as well, since
+// booting from a bundle does not restore it.
+const HOST_OPTIONS: &[&str] = &[
+    "--control-socket", "--vsock", "--serial-port", "--console-log", "--record-events",
+    "--replay-events", "--overlay-spill-dir", "--vhost-user", "--uart", "--metrics-listen",
+    "--trace-file", "--gdb", "--store", "--notify-command", "--console", "--home",
+    "--ram-file", "--suspend-ram",
+];
+
+// Options which find images on the host by realm name
+const REALM_OPTIONS: &[&str] = &["--realm", "--realmfs"];
+
+#[derive(Debug)]
+pub enum Error {
+    Exists(PathBuf),
+    CreateDir(PathBuf, io::Error),
+    CopyFile(PathBuf, io::Error),
+    WriteManifest(io::Error),
+    ReadManifest(PathBuf, io::Error),
+    InvalidManifest(String),
+    UnsupportedFormat(i64),
+    MissingValue(String),
+    RealmOption(String),
+    HostOption(String),
+    FilePath(String),
+    ResolveFile(PathBuf, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Exists(path) => write!(f, "{} already exists", path.display()),
+            CreateDir(path, err) => write!(f, "failed to create {}: {}", path.display(), err),
+            CopyFile(path, err) => write!(f, "failed to copy {} into bundle: {}", path.display(), err),
+            WriteManifest(err) => write!(f, "failed to write bundle manifest: {}", err),
+            ReadManifest(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            InvalidManifest(msg) => write!(f, "invalid bundle manifest: {}", msg),
+            UnsupportedFormat(format) => write!(f, "bundle format {} is not supported by this version of pH", format),
+            MissingValue(opt) => write!(f, "expected value for {} argument", opt),
+            RealmOption(opt) => write!(f, "{} names images of this host, pass the image with --disk instead", opt),
+            HostOption(opt) => write!(f, "{} refers to the host and cannot be stored in a bundle", opt),
+            FilePath(path) => write!(f, "{} is not a path inside the bundle directory", path),
+            ResolveFile(path, err) => write!(f, "failed to find {} in bundle: {}", path.display(), err),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+///
+/// A directory holding the command line options of a VM together with
+/// copies of the disk images, kernel and other files which they name, so
+/// that the VM can be started on another machine.
+///
+/// `manifest.json` holds the options with each file path replaced by the
+/// path of its copy below `files/`, relative to the bundle directory.
+/// Options which refer to sockets, logs and other resources of the host,
+/// including a file backing guest RAM, are dropped and listed in the manifest.
+///
+pub struct Bundle {
+    dir: PathBuf,
+    manifest: JsonValue,
+}
+
+impl Bundle {
+    /// Create a bundle in the new directory `dir` from the command line
+    /// options `args`. The disk images named in `args` are copied as they
+    /// are, so the VM which uses them should not be running.
+    pub fn export<P: AsRef<Path>>(dir: P, args: &[String]) -> Result<Bundle> {
+        let dir = dir.as_ref().to_path_buf();
+        if dir.exists() {
+            return Err(Error::Exists(dir));
+        }
+        let files_dir = dir.join(FILES_DIR);
+        fs::create_dir_all(&files_dir)
+            .map_err(|e| Error::CreateDir(files_dir.clone(), e))?;
+
+        let mut exporter = Exporter { dir: dir.clone(), files: Vec::new(), dropped: Vec::new() };
+        let mut bundle_args = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let opt = arg.as_str();
+            if REALM_OPTIONS.contains(&opt) {
+                return Err(Error::RealmOption(opt.to_string()));
+            }
+            if !FILE_OPTIONS.contains(&opt) && !HOST_OPTIONS.contains(&opt) {
+                bundle_args.push(arg.clone());
+                continue;
+            }
+            let value = iter.next()
+                .ok_or_else(|| Error::MissingValue(opt.to_string()))?;
+            if HOST_OPTIONS.contains(&opt) {
+                exporter.dropped.push(JsonValue::from(opt));
+                continue;
+            }
+            bundle_args.push(arg.clone());
+            bundle_args.push(exporter.copy_option_file(opt, value)?);
+        }
+
+        let manifest = JsonValue::object()
+            .with("format", BUNDLE_FORMAT)
+            .with("ph", env!("CARGO_PKG_VERSION"))
+            .with("created", SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+            .with("args", bundle_args.into_iter().map(JsonValue::from).collect::<Vec<_>>())
+            .with("files", exporter.files)
+            .with("dropped", exporter.dropped);
+        fs::write(dir.join(MANIFEST_NAME), format!("{}\n", manifest))
+            .map_err(Error::WriteManifest)?;
+        Ok(Bundle { dir, manifest })
+    }
+
+    /// Read the manifest of the bundle in `dir`.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Bundle> {
+        let dir = dir.as_ref().to_path_buf();
+        let path = dir.join(MANIFEST_NAME);
+        let text = fs::read_to_string(&path)
+            .map_err(|e| Error::ReadManifest(path, e))?;
+        let manifest = JsonValue::parse(&text)
+            .map_err(Error::InvalidManifest)?;
+        match manifest.get("format").and_then(|v| v.as_i64()) {
+            Some(BUNDLE_FORMAT) => {},
+            Some(format) => return Err(Error::UnsupportedFormat(format)),
+            None => return Err(Error::InvalidManifest("no format version".to_string())),
+        }
+        if manifest.get("args").and_then(|v| v.as_array()).is_none() {
+            return Err(Error::InvalidManifest("no args".to_string()));
+        }
+        Ok(Bundle { dir, manifest })
+    }
+
+    /// The command line options of the VM with the paths of files in the
+    /// bundle resolved against the bundle directory. Fails if the manifest
+    /// holds options which refer to the host, or names files which are not
+    /// in the bundle directory.
+    pub fn args(&self) -> Result<Vec<String>> {
+        let args: Vec<&str> = self.manifest.get("args")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|v| v.as_str())
+            .collect();
+        let mut resolved = Vec::new();
+        let mut iter = args.into_iter();
+        while let Some(arg) = iter.next() {
+            if HOST_OPTIONS.contains(&arg) {
+                return Err(Error::HostOption(arg.to_string()));
+            }
+            if REALM_OPTIONS.contains(&arg) {
+                return Err(Error::RealmOption(arg.to_string()));
+            }
+            resolved.push(arg.to_string());
+            if FILE_OPTIONS.contains(&arg) {
+                let value = iter.next()
+                    .ok_or_else(|| Error::MissingValue(arg.to_string()))?;
+                resolved.push(self.resolve_option_file(arg, value)?);
+            }
+        }
+        Ok(resolved)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Resolve the path in the value of `opt` against the bundle directory,
+    // refusing absolute paths and paths which lead out of the directory
+    fn resolve_option_file(&self, opt: &str, value: &str) -> Result<String> {
+        let (prefix, path, suffix) = split_option_file(opt, value);
+        if Path::new(path).is_absolute() {
+            return Err(Error::FilePath(path.to_string()));
+        }
+        let dir = self.dir.canonicalize()
+            .map_err(|e| Error::ResolveFile(self.dir.clone(), e))?;
+        let joined = self.dir.join(path);
+        let resolved = joined.canonicalize()
+            .map_err(|e| Error::ResolveFile(joined, e))?;
+        if !resolved.starts_with(&dir) || resolved == dir {
+            return Err(Error::FilePath(path.to_string()));
+        }
+        Ok(format!("{}{}{}", prefix, resolved.display(), suffix))
+    }
+}
+
+struct Exporter {
+    dir: PathBuf,
+    files: Vec<JsonValue>,
+    dropped: Vec<JsonValue>,
+}
+
+impl Exporter {
+    // Copy the file named in the value of `opt` and return the value with
+    // the path of the copy in the bundle
+    fn copy_option_file(&mut self, opt: &str, value: &str) -> Result<String> {
+        let (prefix, path, suffix) = split_option_file(opt, value);
+        let source = Path::new(path);
+        let file_name = source.file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "file".to_string());
+        let name = format!("{}/{}-{}", FILES_DIR, self.files.len(), file_name);
+        let bytes = fs::copy(source, self.dir.join(&name))
+            .map_err(|e| Error::CopyFile(source.to_path_buf(), e))?;
+        self.files.push(JsonValue::object()
+            .with("name", name.as_str())
+            .with("option", opt)
+            .with("source", source.display().to_string())
+            .with("bytes", bytes));
+        Ok(format!("{}{}{}", prefix, name, suffix))
+    }
+}
+
+// Split the value of a file option into the text before the path, the path
+// and the text after it
+fn split_option_file<'a>(opt: &str, value: &'a str) -> (&'a str, &'a str, &'a str) {
+    match opt {
+        "--disk" | "--add-disk" => {
+            let end = value.find(',').unwrap_or(value.len());
+            ("", &value[..end], &value[end..])
+        }
+        "--boot-file" => match value.find('=') {
+            Some(idx) => (&value[..idx + 1], &value[idx + 1..], ""),
+            None => ("", value, ""),
+        },
+        _ => ("", value, ""),
+    }
+}
@@ -0,0 +1,121 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::util::JsonValue;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CGROUP_PARENT: &str = "/sys/fs/cgroup/ph";
+
+// cpu.max quota is given in microseconds per period
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+///
+/// A cgroup v2 group which holds the whole pH process so that every vcpu and
+/// device thread of the VM is limited together.
+///
+/// Each VM gets a group `/sys/fs/cgroup/ph/vm-<pid>` with `cpu.max` and
+/// `memory.max` set from the configured limits. Guest RAM is allocated by
+/// the pH process and so is counted against `memory.max`.
+///
+/// The process cannot leave the group after privileges are dropped, so the
+/// empty group is left behind when pH exits and groups of instances which
+/// have exited are removed the next time a group is created.
+///
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create the group for this process, apply `cpu_percent` (of one CPU)
+    /// and `memory_bytes` limits and move the process into it.
+    pub fn create(cpu_percent: Option<u32>, memory_bytes: Option<u64>) -> io::Result<Cgroup> {
+        let root = Path::new(CGROUP_ROOT);
+        if !root.join("cgroup.controllers").exists() {
+            return Err(io::Error::new(ErrorKind::NotFound, "cgroup v2 is not mounted on /sys/fs/cgroup"));
+        }
+        let parent = Path::new(CGROUP_PARENT);
+        if !parent.exists() {
+            fs::create_dir(parent)?;
+        }
+        // Controllers must be enabled at each level above the group of the VM
+        Self::enable_controllers(root)?;
+        Self::enable_controllers(parent)?;
+        Self::remove_stale_groups(parent);
+
+        let path = parent.join(format!("vm-{}", process::id()));
+        if !path.exists() {
+            fs::create_dir(&path)?;
+        }
+        let cgroup = Cgroup { path };
+        if let Some(percent) = cpu_percent {
+            let quota = u64::from(percent.max(1)) * CPU_PERIOD_USEC / 100;
+            cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD_USEC))?;
+        }
+        if let Some(bytes) = memory_bytes {
+            cgroup.write("memory.max", &bytes.to_string())?;
+        }
+        cgroup.write("cgroup.procs", &process::id().to_string())?;
+        Ok(cgroup)
+    }
+
+    fn enable_controllers(dir: &Path) -> io::Result<()> {
+        fs::write(dir.join("cgroup.subtree_control"), "+cpu +memory")
+    }
+
+    // A group can only be removed once it has no processes, so this only
+    // removes groups of instances which have exited.
+    fn remove_stale_groups(parent: &Path) {
+        let entries = match fs::read_dir(parent) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+            let is_vm = name.to_str().map(|s| s.starts_with("vm-")).unwrap_or(false);
+            if is_vm && entry.path().is_dir() {
+                let _ = fs::remove_dir(entry.path());
+            }
+        }
+    }
+
+    fn write(&self, name: &str, value: &str) -> io::Result<()> {
+        fs::write(self.path.join(name), value)
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to write {} to {}: {}", value, name, e)))
+    }
+
+    fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.path.join(name))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Value of `key` in cpu.stat
+    fn cpu_stat(&self, key: &str) -> u64 {
+        self.read("cpu.stat").lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                match (parts.next(), parts.next()) {
+                    (Some(k), Some(v)) if k == key => v.parse::<u64>().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(0)
+    }
+
+    pub fn describe(&self) -> JsonValue {
+        JsonValue::object()
+            .with("path", self.path.display().to_string())
+            .with("cpu.max", self.read("cpu.max"))
+            .with("memory.max", self.read("memory.max"))
+            .with("memory.current", self.read("memory.current"))
+            .with("cpu-usage-usec", self.cpu_stat("usage_usec"))
+            .with("cpu-throttled-usec", self.cpu_stat("throttled_usec"))
+    }
+}
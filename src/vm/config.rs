@@ -1,11 +1,29 @@
 use std::path::{PathBuf, Path};
-use crate::vm::{VmSetup, arch};
-use std::{env, process};
-use crate::devices::SyntheticFS;
-use crate::disk::{RawDiskImage, RealmFSImage, OpenType};
+use std::net::SocketAddr;
+use crate::vm::{self, VmSetup, MinimalRoot, BootTables, PlatformProfile, arch};
+use std::{env, io, process};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::time::Duration;
+use std::sync::mpsc::Sender;
+use crate::system::ListenFds;
+use crate::vm::RamPolicy;
+use crate::vm::console::ConsoleMode;
+use crate::vm::exits::{self, ExitStatus, FailureKind, RebootAction, VmEvent};
+use crate::vm::suspend::SuspendPolicy;
+use crate::devices::{SyntheticFS, CacheHint, MacAddress, SoundBackend, VhostUserSpec, MIN_MTU};
+use crate::devices::serial::{UartBackend, MAX_UARTS};
+use crate::disk::{RawDiskImage, RealmFSImage, OpenType, BlockTopology, OverlayLimit};
+use crate::virtio::{PciIdentity, IrqPolicy, device_type_by_name};
+use crate::system::SeccompMode;
+use crate::memory::HugePages;
+use crate::util::trace::{TraceSubsystem, DEFAULT_TRACE_BUFFER, MAX_TRACE_BUFFER};
 use libcitadel::Realms;
 use libcitadel::terminal::{TerminalPalette, AnsiTerminal, Base16Scheme};
-use crate::vm::arch::X86ArchSetup;
+use crate::vm::arch::PlatformSetup;
+
+// Longest interrupt batching window accepted by --irq-batch, in microseconds
+const MAX_INTERRUPT_WINDOW_US: u64 = 10_000;
 
 pub struct VmConfig {
     ram_size: usize,
@@ -15,22 +33,101 @@ pub struct VmConfig {
     wayland: bool,
     dmabuf: bool,
     network: bool,
+    vhost_net: bool,
+    vhost_user: Vec<VhostUserSpec>,
+    virtio_fs: bool,
+    balloon: bool,
+    balloon_weight: Option<u32>,
+    virtio_input: bool,
+    sound: Option<SoundBackend>,
+    notifications: bool,
+    notify_command: Option<String>,
+    reboot_action: RebootAction,
+    vm_events: Option<Sender<VmEvent>>,
+    scrub_memory: bool,
+    ram_file: Option<PathBuf>,
+    huge_pages: HugePages,
+    tiny: bool,
+    selftest: bool,
+    hyperv: bool,
+    steal_time: bool,
+    pv_eoi: bool,
+    console_socket: bool,
+    idle_timeout: Option<u64>,
+    suspend_after: Option<u64>,
+    suspend_ram: RamPolicy,
+    p9_timeout: u64,
+    p9_checksum_every: Option<u64>,
+    p9_io_uring: bool,
+    p9_process: bool,
+    p9_cache_hints: Vec<(String, CacheHint)>,
+    overlay_limit_megs: Option<u64>,
+    io_share_megs: Option<u64>,
+    block_io_threads: usize,
+    cpu_limit_percent: Option<u32>,
+    memory_limit_megs: Option<u64>,
+    overlay_spill_dir: Option<PathBuf>,
+    store_share: Option<PathBuf>,
     home: String,
     colorscheme: String,
     bridge_name: String,
+    wayland_tag: Option<String>,
+    lockdown: Option<String>,
+    module_sig_enforce: bool,
+    module_key: Option<PathBuf>,
+    boot_files: Vec<(PathBuf, PathBuf)>,
+    mac_address: Option<MacAddress>,
+    mtu: Option<u16>,
+    deterministic_seed: Option<u64>,
     kernel_path: Option<PathBuf>,
+    initrd_path: Option<PathBuf>,
+    pvh: bool,
     init_path: Option<PathBuf>,
     init_cmd: Option<String>,
+    record_path: Option<PathBuf>,
+    trace: Vec<TraceSubsystem>,
+    trace_path: Option<PathBuf>,
+    trace_buffer: usize,
+    replay_path: Option<PathBuf>,
+    control_path: Option<PathBuf>,
+    metrics_address: Option<SocketAddr>,
+    gdb_address: Option<SocketAddr>,
+    gdb_wait: bool,
+    vsock_path: Option<PathBuf>,
+    vsock_cid: u64,
+    serial_ports: Vec<(String, PathBuf)>,
+    uarts: Vec<(u8, UartBackend)>,
+    console_log: Option<PathBuf>,
+    console_mode: ConsoleMode,
     raw_disks: Vec<RawDiskImage>,
+    extra_disks: Vec<RawDiskImage>,
+    pci_identities: Vec<(u16, PciIdentity)>,
+    interrupt_windows: Vec<(u16, Duration)>,
+    irq_policy: IrqPolicy,
+    seccomp_mode: SeccompMode,
+    virtio_audit: bool,
+    msix: bool,
+    pci_hotplug: bool,
+    virtio_mmio: bool,
+    boot_tables: BootTables,
+    platform_profile: PlatformProfile,
 
     realmfs_images: Vec<RealmFSImage>,
     realm_name: Option<String>,
     synthetic: Option<SyntheticFS>,
+    run_path: Option<PathBuf>,
+    listen_fds: ListenFds,
 }
 
 #[allow(dead_code)]
 impl VmConfig {
     pub fn new() -> VmConfig {
+        Self::with_args(env::args().skip(1).collect())
+    }
+
+    /// Create a configuration from the command line options in `args`
+    /// rather than those of the process.
+    pub fn with_args(args: Vec<String>) -> VmConfig {
         let mut config = VmConfig {
             ram_size: 256 * 1024 * 1024,
             ncpus: 1,
@@ -39,131 +136,1160 @@ impl VmConfig {
             wayland: true,
             dmabuf: false,
             network: true,
+            vhost_net: false,
+            vhost_user: Vec::new(),
+            virtio_fs: false,
+            balloon: false,
+            balloon_weight: None,
+            virtio_input: false,
+            sound: None,
+            notifications: false,
+            notify_command: None,
+            reboot_action: RebootAction::Exit,
+            vm_events: None,
+            scrub_memory: false,
+            ram_file: None,
+            huge_pages: HugePages::default(),
+            tiny: false,
+            selftest: false,
+            hyperv: false,
+            steal_time: true,
+            pv_eoi: true,
+            console_socket: false,
+            idle_timeout: None,
+            suspend_after: None,
+            suspend_ram: RamPolicy::Keep,
+            p9_timeout: 30,
+            p9_checksum_every: None,
+            p9_io_uring: false,
+            p9_process: false,
+            p9_cache_hints: Vec::new(),
+            overlay_limit_megs: None,
+            io_share_megs: None,
+            block_io_threads: 4,
+            cpu_limit_percent: None,
+            memory_limit_megs: None,
+            overlay_spill_dir: None,
+            store_share: None,
             bridge_name: "vz-clear".to_string(),
+            mac_address: None,
+            mtu: None,
+            deterministic_seed: None,
             home: Self::default_homedir(),
             colorscheme: "dracula".to_string(),
             kernel_path: None,
+            initrd_path: None,
+            pvh: true,
             init_path: None,
             init_cmd: None,
+            record_path: None,
+            trace: Vec::new(),
+            trace_path: None,
+            trace_buffer: DEFAULT_TRACE_BUFFER,
+            replay_path: None,
+            control_path: None,
+            metrics_address: None,
+            gdb_address: None,
+            gdb_wait: false,
+            vsock_path: None,
+            vsock_cid: 3,
+            serial_ports: Vec::new(),
+            uarts: Vec::new(),
+            console_log: None,
+            console_mode: ConsoleMode::Stdio,
             realm_name: None,
+            wayland_tag: None,
+            lockdown: None,
+            module_sig_enforce: false,
+            module_key: None,
+            boot_files: Vec::new(),
             raw_disks: Vec::new(),
+            extra_disks: Vec::new(),
+            pci_identities: Vec::new(),
+            interrupt_windows: Vec::new(),
+            irq_policy: IrqPolicy::default(),
+            seccomp_mode: SeccompMode::default(),
+            virtio_audit: false,
+            msix: false,
+            pci_hotplug: false,
+            virtio_mmio: false,
+            boot_tables: BootTables::default(),
+            platform_profile: PlatformProfile::default(),
             realmfs_images: Vec::new(),
             synthetic: None,
+            run_path: None,
+            listen_fds: ListenFds::from_env(),
         };
-        config.parse_args();
+        config.parse_args(ProgramArgs::new(args));
         config
     }
 
-    fn default_homedir() -> String {
-        if let Ok(home) = env::var("HOME") {
-            if Path::new(&home).exists() {
-                return home;
-            }
-        }
-        String::from("/home/user")
+    fn default_homedir() -> String {
+        if let Ok(home) = env::var("HOME") {
+            if Path::new(&home).exists() {
+                return home;
+            }
+        }
+        String::from("/home/user")
+    }
+
+    pub fn ram_size_megs(mut self, megs: usize) -> Self {
+        self.ram_size = megs * 1024 * 1024;
+        self
+    }
+
+    pub fn raw_disk_image<P: Into<PathBuf>>(self, path: P, open_type: OpenType) -> Self {
+        self.raw_disk_image_with_offset(path, open_type, 0)
+    }
+
+    pub fn raw_disk_image_with_offset<P: Into<PathBuf>>(mut self, path: P, open_type: OpenType, offset: usize) -> Self {
+        match RawDiskImage::new_with_offset(path, open_type, offset) {
+            Ok(disk) => self.raw_disks.push(disk),
+            Err(e) => warn!("Could not add disk: {}", e),
+        };
+        self
+    }
+
+    /// Add partition number `partition` (counting from 1) of a disk image
+    /// with an MBR or GPT partition table.
+    pub fn raw_disk_image_partition<P: Into<PathBuf>>(mut self, path: P, open_type: OpenType, partition: usize) -> Self {
+        match RawDiskImage::new_with_partition(path, open_type, partition) {
+            Ok(disk) => self.raw_disks.push(disk),
+            Err(e) => warn!("Could not add disk: {}", e),
+        };
+        self
+    }
+
+    pub fn raw_disk_image_with_topology<P: Into<PathBuf>>(mut self, path: P, open_type: OpenType, offset: usize, topology: BlockTopology) -> Self {
+        if !topology.is_valid() {
+            warn!("Could not add disk: invalid block topology {:?}", topology);
+            return self;
+        }
+        match RawDiskImage::new_with_offset(path, open_type, offset) {
+            Ok(mut disk) => {
+                disk.set_topology(topology);
+                self.raw_disks.push(disk);
+            }
+            Err(e) => warn!("Could not add disk: {}", e),
+        };
+        self
+    }
+
+    /// Attach a disk image as an additional block device. Additional disks
+    /// follow the root disk (`/dev/vdb`, `/dev/vdc`, ...) in the order they
+    /// are added and are never mounted as the root filesystem.
+    pub fn add_disk<P: Into<PathBuf>>(mut self, path: P, open_type: OpenType) -> Self {
+        match RawDiskImage::new(path, open_type) {
+            Ok(disk) => self.extra_disks.push(disk),
+            Err(e) => warn!("Could not add disk: {}", e),
+        };
+        self
+    }
+
+    pub fn realmfs_image<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        match RealmFSImage::new(path, OpenType::MemoryOverlay) {
+            Ok(disk) => self.realmfs_images.push(disk),
+            Err(e) => warn!("Could not add disk: {}", e),
+        };
+        self
+    }
+
+    /// Add a RealmFS image with `topology` in place of the 4096 byte
+    /// minimum I/O size which RealmFS images are given by default.
+    pub fn realmfs_image_with_topology<P: Into<PathBuf>>(mut self, path: P, topology: BlockTopology) -> Self {
+        if !topology.is_valid() {
+            warn!("Could not add disk: invalid block topology {:?}", topology);
+            return self;
+        }
+        match RealmFSImage::new(path, OpenType::MemoryOverlay) {
+            Ok(mut disk) => {
+                disk.set_topology(topology);
+                self.realmfs_images.push(disk);
+            }
+            Err(e) => warn!("Could not add disk: {}", e),
+        };
+        self
+    }
+
+    pub fn num_cpus(mut self, ncpus: usize) -> Self {
+        self.ncpus = ncpus;
+        self
+    }
+
+    pub fn init_cmdline(mut self, val: &str) -> Self {
+        self.init_cmd = Some(val.to_owned());
+        self
+    }
+
+    /// Boot the kernel at `path`, either an uncompressed ELF `vmlinux` or a
+    /// `bzImage` with a 64-bit entry point, instead of the kernel built into pH.
+    pub fn kernel_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.kernel_path = Some(path.into());
+        self
+    }
+
+    /// Load the file at `path` into guest memory as the initial ramdisk.
+    pub fn initrd_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.initrd_path = Some(path.into());
+        self
+    }
+
+    /// Start an ELF kernel which has a PVH entry point at that entry point,
+    /// in 32-bit protected mode, rather than at its 64-bit entry point.
+    pub fn pvh(mut self, pvh: bool) -> Self {
+        self.pvh = pvh;
+        self
+    }
+
+    pub fn init_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.init_path = Some(path.into());
+        self
+    }
+
+    pub fn scrub_memory(mut self, scrub: bool) -> Self {
+        self.scrub_memory = scrub;
+        self
+    }
+
+    /// Back guest RAM with the file at `path`, which may be on tmpfs or on
+    /// disk, and keep it after the VM exits with the final contents of guest
+    /// memory written back. An existing file is mapped without being read.
+    pub fn ram_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.ram_file = Some(path.into());
+        self
+    }
+
+    /// Back guest RAM with huge pages from the hugetlb pool of the host or
+    /// with transparent huge pages. If the host cannot provide them guest RAM
+    /// falls back to transparent huge pages and then to ordinary pages.
+    pub fn huge_pages(mut self, pages: HugePages) -> Self {
+        self.huge_pages = pages;
+        self
+    }
+
+    /// Boot a minimal VM for running short lived commands. Only the console,
+    /// boot filesystem and root filesystem devices are created, and if a
+    /// `SyntheticFS` has been provided with `synthetic_fs()` it is used as the
+    /// root filesystem.
+    pub fn tiny_vm(mut self, tiny: bool) -> Self {
+        self.tiny = tiny;
+        self
+    }
+
+    /// Have ph-init exercise each virtio device and report the results as
+    /// `selftest` events instead of running a shell, then power off. This is
+    /// used by the integration tests, which prepare a scratch disk, files in
+    /// the home directory and a `ph.selftest` serial port for the checks.
+    pub fn selftest(mut self, selftest: bool) -> Self {
+        self.selftest = selftest;
+        self
+    }
+
+    /// Share `home` with the guest as the home directory.
+    pub fn home(mut self, home: &str) -> Self {
+        self.home = home.to_string();
+        self
+    }
+
+    pub fn use_network(mut self, network: bool) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn wayland(mut self, wayland: bool) -> Self {
+        self.wayland = wayland;
+        self
+    }
+
+    /// Expose Hyper-V enlightenments (relaxed timing, synthetic MSRs,
+    /// SynIC, and IPI hypercalls as supported by KVM) to the guest. This is
+    /// experimental and only useful for Windows guests.
+    pub fn hyperv(mut self, hyperv: bool) -> Self {
+        self.hyperv = hyperv;
+        self
+    }
+
+    /// Advertise KVM steal time accounting to the guest if KVM supports it.
+    /// Enabled by default.
+    pub fn steal_time(mut self, enabled: bool) -> Self {
+        self.steal_time = enabled;
+        self
+    }
+
+    /// Advertise paravirtual end of interrupt to the guest if KVM supports
+    /// it. Enabled by default.
+    pub fn pv_eoi(mut self, enabled: bool) -> Self {
+        self.pv_eoi = enabled;
+        self
+    }
+
+    /// Override the PCI identity of the virtio device named `device`
+    /// (`net`, `block`, `console`, `rng`, `9p`, or `wl`).
+    pub fn pci_identity(mut self, device: &str, identity: PciIdentity) -> Self {
+        self.add_pci_identity(device, identity);
+        self
+    }
+
+    /// Hold back the queue interrupts of the virtio device named `device`
+    /// for up to `window` so that a burst of completions raises fewer
+    /// interrupts, at the cost of that much added latency.
+    pub fn interrupt_batching(mut self, device: &str, window: Duration) -> Self {
+        match device_type_by_name(device) {
+            Some(device_type) => self.interrupt_windows.push((device_type, window)),
+            None => exit_config_error(&format!("Unknown virtio device name '{}'", device)),
+        }
+        self
+    }
+
+    /// Hardware address of the guest network interface. If this is not set
+    /// a realm is given a locally administered address derived from its
+    /// name, which stays the same each time it is started, and any other VM
+    /// a random one.
+    pub fn mac_address(mut self, mac: MacAddress) -> Self {
+        self.mac_address = Some(mac);
+        self
+    }
+
+    /// MTU of the guest network interface and of the TAP interface it is
+    /// connected to, for bridges which carry jumbo frames. Values below 68
+    /// are ignored.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        if mtu >= MIN_MTU {
+            self.mtu = Some(mtu);
+        }
+        self
+    }
+
+    /// Identify the windows of this VM to the host compositor with `tag`,
+    /// which prefixes their titles and application IDs. A realm is tagged
+    /// with its name unless another tag is set.
+    pub fn wayland_tag(mut self, tag: &str) -> Self {
+        if Self::is_valid_wayland_tag(tag) {
+            self.wayland_tag = Some(tag.to_string());
+        } else {
+            warn!("Invalid wayland tag: {}", tag);
+        }
+        self
+    }
+
+    // The tag is passed to the guest on the kernel command line
+    fn is_valid_wayland_tag(tag: &str) -> bool {
+        !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    }
+
+    /// Boot the guest kernel locked down in `mode`, which is `integrity` or
+    /// `confidentiality`. Needs a guest kernel of Linux 5.4 or later built
+    /// with the lockdown LSM.
+    pub fn lockdown(mut self, mode: &str) -> Self {
+        if Self::is_valid_lockdown_mode(mode) {
+            self.lockdown = Some(mode.to_string());
+        } else {
+            warn!("Invalid lockdown mode: {}", mode);
+        }
+        self
+    }
+
+    fn is_valid_lockdown_mode(mode: &str) -> bool {
+        mode == "integrity" || mode == "confidentiality"
+    }
+
+    /// Only allow the guest kernel to load modules with a valid signature.
+    pub fn module_sig_enforce(mut self, enforce: bool) -> Self {
+        self.module_sig_enforce = enforce;
+        self
+    }
+
+    /// Give ph-init the X.509 certificate in DER form at `path` to add to
+    /// the secondary trusted keyring of the guest kernel, so that modules
+    /// signed with the matching key can be loaded. A realm uses
+    /// `module-key.der` in the realm directory if it exists.
+    pub fn module_signing_key<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.module_key = Some(path.into());
+        self
+    }
+
+    /// Add a copy of the host file at `host_path` to the boot filesystem at
+    /// `guest_path`. The file is read once when the VM is created, and the
+    /// guest sees it under `/opt/ph` once the root filesystem is mounted.
+    pub fn boot_file<P: Into<PathBuf>, Q: Into<PathBuf>>(mut self, guest_path: P, host_path: Q) -> Self {
+        self.boot_files.push((guest_path.into(), host_path.into()));
+        self
+    }
+
+    /// Process packets of the guest network interface in the host kernel with
+    /// `/dev/vhost-net` instead of in pH.
+    pub fn vhost_net(mut self, vhost_net: bool) -> Self {
+        self.vhost_net = vhost_net;
+        self
+    }
+
+    /// Add a device whose queues are processed by the vhost-user backend
+    /// listening on the socket of `spec`. Guest RAM is then backed by a memfd
+    /// which is passed to the backend.
+    pub fn vhost_user(mut self, spec: VhostUserSpec) -> Self {
+        self.vhost_user.push(spec);
+        self
+    }
+
+    /// Export the root and home directories to the guest with virtio-fs
+    /// instead of 9p.
+    pub fn virtio_fs(mut self, virtio_fs: bool) -> Self {
+        self.virtio_fs = virtio_fs;
+        self
+    }
+
+    /// Add a virtio-balloon device with which guest memory can be reclaimed
+    /// while the VM runs.
+    pub fn balloon(mut self, balloon: bool) -> Self {
+        self.balloon = balloon;
+        self
+    }
+
+    /// Inflate and deflate the balloon as host memory pressure changes, taking
+    /// memory from each VM started with a weight in inverse proportion to
+    /// `weight`. See `PressurePolicy`. The balloon device is added as if
+    /// `balloon()` had been enabled.
+    pub fn balloon_weight(mut self, weight: u32) -> Self {
+        self.balloon_weight = Some(weight);
+        self
+    }
+
+    /// Add a virtio-input keyboard and mouse which receive events from the
+    /// host through `Vm::keyboard()` and `Vm::mouse()`.
+    pub fn virtio_input(mut self, virtio_input: bool) -> Self {
+        self.virtio_input = virtio_input;
+        self
+    }
+
+    /// Add a virtio-snd device whose playback and capture streams are played
+    /// and recorded through `backend` on the host.
+    pub fn sound(mut self, backend: SoundBackend) -> Self {
+        self.sound = Some(backend);
+        self
+    }
+
+    /// Show desktop notifications raised in the guest with `ph-init notify`
+    /// on the host, labeled with the realm name and rate limited.
+    pub fn guest_notifications(mut self, enabled: bool) -> Self {
+        self.notifications = enabled;
+        self
+    }
+
+    /// Show guest notifications by running `command` with the arguments of
+    /// `notify-send` instead of `notify-send`.
+    pub fn notify_command(mut self, command: &str) -> Self {
+        self.notify_command = Some(command.to_string());
+        self
+    }
+
+    /// Make the guest reproducible for testing. The entropy device and the
+    /// generated MAC address come from a generator seeded with `seed`, the
+    /// RTC starts from a fixed date, the guest does not read the host wall
+    /// clock through kvmclock, and RDRAND and RDSEED are hidden from the guest.
+    pub fn deterministic(mut self, seed: u64) -> Self {
+        self.deterministic_seed = Some(seed);
+        self
+    }
+
+    /// Choose how interrupt lines are assigned to virtio devices.
+    pub fn irq_policy(mut self, policy: IrqPolicy) -> Self {
+        self.irq_policy = policy;
+        self
+    }
+
+    /// Choose how the threads of the block, 9p, wayland and network devices
+    /// are confined to the system calls they need. By default a thread which
+    /// makes any other call kills the VM.
+    pub fn seccomp(mut self, mode: SeccompMode) -> Self {
+        self.seccomp_mode = mode;
+        self
+    }
+
+    /// Record every configuration space access of each virtio device so that
+    /// feature negotiation with the guest drivers can be inspected.
+    pub fn virtio_audit(mut self, audit: bool) -> Self {
+        self.virtio_audit = audit;
+        self
+    }
+
+    /// Give each virtio PCI device an MSI-X capability so the driver can
+    /// assign an interrupt vector to each queue instead of sharing a line.
+    pub fn msix(mut self, enabled: bool) -> Self {
+        self.msix = enabled;
+        self
+    }
+
+    /// Add a PCI hotplug controller so that virtio devices can be added to
+    /// and removed from the running VM. The controller is described to the
+    /// guest in the ACPI tables, so creating the VM fails without ACPI boot
+    /// tables or with the virtio-mmio transport.
+    pub fn pci_hotplug(mut self, enabled: bool) -> Self {
+        self.pci_hotplug = enabled;
+        self
+    }
+
+    /// Register virtio devices with the virtio-mmio transport at fixed
+    /// addresses which are passed to the guest kernel on its command line,
+    /// for kernels built without PCI. PCI hotplug and MSI-X are not
+    /// available to these devices.
+    pub fn virtio_mmio(mut self, enabled: bool) -> Self {
+        self.virtio_mmio = enabled;
+        self
+    }
+
+    /// Choose whether the CPUs and interrupt routing are described to the
+    /// guest with an MP table, which is the default, ACPI tables, or both.
+    pub fn boot_tables(mut self, tables: BootTables) -> Self {
+        self.boot_tables = tables;
+        self
+    }
+
+    /// Choose which legacy PC devices are emulated. With
+    /// `PlatformProfile::MinimalParavirt` the guest has no PIT, serial ports
+    /// or placeholder ports, and no RTC unless kvmclock is disabled.
+    pub fn platform_profile(mut self, profile: PlatformProfile) -> Self {
+        self.platform_profile = profile;
+        self
+    }
+
+    /// Record all device input to the guest into the log file at `path`.
+    pub fn record_events<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.record_path = Some(path.into());
+        self
+    }
+
+    /// Record trace events of `subsystems` from the start. Which subsystems
+    /// are traced can be changed later with the `trace` control method.
+    pub fn trace(mut self, subsystems: &[TraceSubsystem]) -> Self {
+        self.trace = subsystems.to_vec();
+        self
+    }
+
+    /// Append every trace event to the file at `path` as it is recorded.
+    pub fn trace_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.trace_path = Some(path.into());
+        self
+    }
+
+    /// Keep the most recent `records` trace events in memory, 4096 by
+    /// default.
+    pub fn trace_buffer(mut self, records: usize) -> Self {
+        self.trace_buffer = records;
+        self
+    }
+
+    /// Replace device input to the guest with the events recorded in the
+    /// log file at `path` by a previous run with `record_events()`.
+    pub fn replay_events<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.replay_path = Some(path.into());
+        self
+    }
+
+    /// Listen for control commands on a unix socket at `path` while the VM is running.
+    pub fn control_socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.control_path = Some(path.into());
+        self
+    }
+
+    /// Serve the counters of `metrics` in the Prometheus text format over
+    /// HTTP at `/metrics` on `address`.
+    pub fn metrics_listen(mut self, address: SocketAddr) -> Self {
+        self.metrics_address = Some(address);
+        self
+    }
+
+    /// Accept a connection from gdb on `address` to debug the guest kernel
+    /// with the GDB remote protocol.
+    pub fn gdb_listen(mut self, address: SocketAddr) -> Self {
+        self.gdb_address = Some(address);
+        self
+    }
+
+    /// Hold the vcpus before the first instruction of the guest until gdb
+    /// connects and continues them.
+    pub fn gdb_wait(mut self, wait: bool) -> Self {
+        self.gdb_wait = wait;
+        self
+    }
+
+    /// Add a virtio-vsock device. Host processes connect to guest services
+    /// through the unix socket at `path`, and guest connections to port P of
+    /// the host go to the socket at `path` followed by `_P`.
+    pub fn vsock_socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.vsock_path = Some(path.into());
+        self
+    }
+
+    /// The context ID of the guest on the vsock device, 3 by default.
+    pub fn vsock_cid(mut self, cid: u64) -> Self {
+        if Self::is_valid_vsock_cid(cid) {
+            self.vsock_cid = cid;
+        } else {
+            warn!("Invalid vsock CID: {}", cid);
+        }
+        self
+    }
+
+    // CIDs 0 to 2 belong to the hypervisor and host, and -1 means any CID
+    fn is_valid_vsock_cid(cid: u64) -> bool {
+        cid > 2 && cid < u64::from(u32::max_value())
+    }
+
+    /// Add a console port named `name` which is bound to a unix socket at
+    /// `path`. A program which connects to the socket talks to the guest
+    /// process which opens `/dev/virtio-ports/NAME`.
+    pub fn serial_port<P: Into<PathBuf>>(mut self, name: &str, path: P) -> Self {
+        if Self::is_valid_port_name(name) {
+            self.serial_ports.push((name.to_string(), path.into()));
+        } else {
+            warn!("Invalid console port name: {}", name);
+        }
+        self
+    }
+
+    /// Append everything the guest writes to the console to the file at
+    /// `path`, with a timestamp on each line, whether or not a terminal or
+    /// client is attached to the console.
+    pub fn console_log<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.console_log = Some(path.into());
+        self
+    }
+
+    /// Attach the console to a new PTY or a unix socket instead of the
+    /// terminal which started pH, so that the VM can run without a terminal.
+    pub fn console(mut self, mode: ConsoleMode) -> Self {
+        self.console_mode = mode;
+        self
+    }
+
+    /// Add the 16550 UART `port`, from 0 for COM1 to 3 for COM4, with its
+    /// output going to `backend`. With verbose output COM1 is added with the
+    /// `Stdio` backend unless it is given here.
+    pub fn uart(mut self, port: u8, backend: UartBackend) -> Self {
+        if port < MAX_UARTS {
+            self.uarts.retain(|&(p, _)| p != port);
+            self.uarts.push((port, backend));
+        } else {
+            warn!("Invalid serial port number: {}", port);
+        }
+        self
+    }
+
+    // The name is used for a file in /dev/virtio-ports in the guest
+    fn is_valid_port_name(name: &str) -> bool {
+        !name.is_empty() && name != "ph.agent" && !name.chars().any(|c| c == '/' || c.is_whitespace())
+    }
+
+    /// Shut down the VM after it has been idle for `secs` seconds. The VM is idle
+    /// when no client is connected to the control socket or to the console.
+    pub fn exit_on_idle(mut self, secs: u64) -> Self {
+        self.idle_timeout = Some(secs);
+        self
+    }
+
+    /// Pause the vcpus after the guest has been idle for `secs` seconds and
+    /// handle guest RAM according to `ram` while suspended. The VM resumes
+    /// when a client uses the console or the control socket.
+    pub fn suspend_on_idle(mut self, secs: u64, ram: RamPolicy) -> Self {
+        self.suspend_after = Some(secs);
+        self.suspend_ram = ram;
+        self
+    }
+
+    /// Fail operations on exported host directories with `EIO` if they take longer
+    /// than `secs` seconds. The default is 30 seconds and 0 disables the timeout.
+    pub fn p9_operation_timeout(mut self, secs: u64) -> Self {
+        self.p9_timeout = secs;
+        self
+    }
+
+    /// Log a checksum of the data of one in every `every` reads and writes of
+    /// exported host directories, compared with the file on the host, to help
+    /// find where corrupted file contents come from.
+    pub fn p9_checksum_sampling(mut self, every: u64) -> Self {
+        self.p9_checksum_every = Some(every);
+        self
+    }
+
+    /// Read and write files of exported host directories through io_uring.
+    /// These reads and writes are not subject to the operation timeout.
+    pub fn p9_io_uring(mut self, val: bool) -> Self {
+        self.p9_io_uring = val;
+        self
+    }
+
+    /// Advise the host kernel how to cache the files of the 9p share with
+    /// mount tag `tag`, such as `home`, so that reading large files through
+    /// it does not push everything else out of the host page cache.
+    pub fn p9_cache_hint(mut self, tag: &str, hint: CacheHint) -> Self {
+        self.p9_cache_hints.push((tag.to_string(), hint));
+        self
+    }
+
+    /// Serve exported host directories from child processes which cannot
+    /// reach guest memory or the other files of the VM process.
+    pub fn p9_separate_process(mut self, val: bool) -> Self {
+        self.p9_process = val;
+        self
+    }
+
+    /// Limit the memory used to hold writes to disk images opened with
+    /// `OpenType::MemoryOverlay` to `megs` megabytes per image.
+    pub fn overlay_memory_limit(mut self, megs: u64) -> Self {
+        self.overlay_limit_megs = Some(megs);
+        self
+    }
+
+    /// Move memory overlays which exceed the limit set with `overlay_memory_limit()`
+    /// to temporary files in `dir` instead of failing writes.
+    pub fn overlay_spill_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.overlay_spill_dir = Some(dir.into());
+        self
+    }
+
+    /// Export the read-only host tree `dir`, such as `/nix` or `/usr`, to the
+    /// guest, which mounts it at the same path.
+    pub fn store_share<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.store_share = Some(dir.into());
+        self
+    }
+
+    pub fn synthetic_fs(mut self, sfs: SyntheticFS) -> Self {
+        self.synthetic = Some(sfs);
+        self
+    }
+
+    /// Boot from a `MinimalRoot` instead of a disk image. The root is used
+    /// as a `SyntheticFS` root filesystem and ph-init runs its program in
+    /// place of the console shell.
+    pub fn minimal_root(mut self, root: MinimalRoot) -> Self {
+        self.run_path = Some(root.run_path());
+        self.synthetic = Some(root.into_filesystem());
+        self
+    }
+
+    /// Choose whether a reboot of the guest starts the VM again or exits
+    /// pH, which is the default.
+    pub fn on_reboot(mut self, action: RebootAction) -> Self {
+        self.reboot_action = action;
+        self
+    }
+
+    /// Send the `VmEvent`s of the VM to `sender` while it runs.
+    pub fn vm_events(mut self, sender: Sender<VmEvent>) -> Self {
+        self.vm_events = Some(sender);
+        self
+    }
+
+    /// Create and run the VM and return how it stopped. A caller which
+    /// wants to restart the guest when it reboots creates a new `VmConfig`
+    /// and boots it again when this returns `ExitStatus::Reboot` and
+    /// `get_reboot_action()` is `RebootAction::Restart`.
+    ///
+    /// If the VM fails or the guest crashes a one line summary with the
+    /// kind of failure is printed to stderr.
+    pub fn boot(mut self) -> ExitStatus {
+
+        if let Err(err) = self.attach_console_socket() {
+            let message = format!("Failed to accept console connection: {}", err);
+            warn!("{}", message);
+            let status = ExitStatus::Failed(FailureKind::Setup);
+            exits::print_exit_summary(status, &message);
+            return status;
+        }
+
+        let on_terminal = !self.console_socket && self.console_mode == ConsoleMode::Stdio;
+        let _terminal_restore = if on_terminal {
+            Some(TerminalRestore::save())
+        } else {
+            None
+        };
+
+        if let Some(scheme) = Base16Scheme::by_name(&self.colorscheme) {
+            if on_terminal {
+                let mut term = AnsiTerminal::new().unwrap();
+                if let Err(err) = term.apply_base16(scheme) {
+                    warn!("Failed to set terminal color scheme: {}", err);
+                }
+            }
+        }
+        let mut setup = self.setup();
+        let vm = match setup.create_vm() {
+            Ok(vm) => vm,
+            Err(err) => return Self::failed("Failed to create VM", err),
+        };
+
+        match vm.start() {
+            Ok(ExitStatus::Crash) => {
+                exits::print_exit_summary(ExitStatus::Crash, "the guest crashed");
+                ExitStatus::Crash
+            }
+            Ok(status) => status,
+            Err(err) => Self::failed("Failed to start VM", err),
+        }
+    }
+
+    fn failed(context: &str, err: vm::Error) -> ExitStatus {
+        let message = format!("{}: {}", context, err);
+        warn!("{}", message);
+        let status = ExitStatus::Failed(err.failure_kind());
+        exits::print_exit_summary(status, &message);
+        status
+    }
+
+    // If systemd passed a socket named `console`, wait for a client to connect
+    // and use the connection as stdin and stdout for the VM console.
+    fn attach_console_socket(&mut self) -> io::Result<()> {
+        let fd = match self.listen_fds.take("console") {
+            Some(fd) => fd,
+            None => return Ok(()),
+        };
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+        let (stream, _) = listener.accept()?;
+        for &target in &[0, 1] {
+            if unsafe { libc::dup2(stream.as_raw_fd(), target) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.console_socket = true;
+        Ok(())
+    }
+
+    pub fn setup(self) -> VmSetup<PlatformSetup> {
+        let arch_setup = arch::create_setup(&self);
+        VmSetup::new(self, arch_setup)
+    }
+
+    pub fn ram_size(&self) -> usize {
+        self.ram_size
+    }
+
+    pub fn ncpus(&self) -> usize {
+        self.ncpus
+    }
+
+    pub fn verbose(&self) -> bool {
+        self.verbose
+    }
+
+    pub fn rootshell(&self) -> bool {
+        self.rootshell
+    }
+
+    pub fn network(&self) -> bool {
+        if self.tiny {
+            false
+        } else if unsafe { libc::geteuid() } != 0 {
+            false
+        } else {
+            self.network
+        }
+    }
+
+    pub fn is_vhost_net_enabled(&self) -> bool {
+        self.vhost_net
+    }
+
+    pub fn vhost_user_devices(&self) -> &[VhostUserSpec] {
+        &self.vhost_user
+    }
+
+    pub fn is_virtio_fs_enabled(&self) -> bool {
+        self.virtio_fs
+    }
+
+    pub fn is_balloon_enabled(&self) -> bool {
+        self.balloon || self.balloon_weight.is_some()
+    }
+
+    pub fn get_balloon_weight(&self) -> Option<u32> {
+        self.balloon_weight
+    }
+
+    pub fn is_virtio_input_enabled(&self) -> bool {
+        self.virtio_input
+    }
+
+    pub fn is_notifications_enabled(&self) -> bool {
+        self.notifications
+    }
+
+    pub fn get_notify_command(&self) -> Option<&str> {
+        self.notify_command.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn get_sound_backend(&self) -> Option<SoundBackend> {
+        self.sound
+    }
+
+    pub fn get_reboot_action(&self) -> RebootAction {
+        self.reboot_action
+    }
+
+    pub fn take_vm_events(&mut self) -> Option<Sender<VmEvent>> {
+        self.vm_events.take()
+    }
+
+    pub fn is_scrub_memory_enabled(&self) -> bool {
+        self.scrub_memory
+    }
+
+    pub fn is_selftest(&self) -> bool {
+        self.selftest
+    }
+
+    pub fn is_tiny(&self) -> bool {
+        self.tiny
+    }
+
+    pub fn get_kernel_path(&self) -> Option<&Path> {
+        self.kernel_path.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn get_initrd_path(&self) -> Option<&Path> {
+        self.initrd_path.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn is_pvh_enabled(&self) -> bool {
+        self.pvh
+    }
+
+    pub fn is_hyperv_enabled(&self) -> bool {
+        self.hyperv
+    }
+
+    pub fn is_steal_time_enabled(&self) -> bool {
+        self.steal_time
+    }
+
+    pub fn is_pv_eoi_enabled(&self) -> bool {
+        self.pv_eoi
+    }
+
+    pub fn pci_identities(&self) -> &[(u16, PciIdentity)] {
+        &self.pci_identities
+    }
+
+    pub fn interrupt_windows(&self) -> &[(u16, Duration)] {
+        &self.interrupt_windows
+    }
+
+    pub fn get_irq_policy(&self) -> IrqPolicy {
+        self.irq_policy
+    }
+
+    pub fn get_seccomp_mode(&self) -> SeccompMode {
+        self.seccomp_mode
+    }
+
+    pub fn is_virtio_audit_enabled(&self) -> bool {
+        self.virtio_audit
+    }
+
+    pub fn is_msix_enabled(&self) -> bool {
+        self.msix
+    }
+
+    pub fn is_pci_hotplug_enabled(&self) -> bool {
+        self.pci_hotplug
+    }
+
+    /// There is no PCI host bridge on arm64, so virtio devices always use
+    /// the mmio transport there.
+    pub fn is_virtio_mmio_enabled(&self) -> bool {
+        self.virtio_mmio || cfg!(target_arch = "aarch64")
+    }
+
+    pub fn get_boot_tables(&self) -> BootTables {
+        self.boot_tables
+    }
+
+    /// There are no PC devices on arm64.
+    pub fn get_platform_profile(&self) -> PlatformProfile {
+        if cfg!(target_arch = "aarch64") {
+            PlatformProfile::MinimalParavirt
+        } else {
+            self.platform_profile
+        }
+    }
+
+    pub fn is_console_socket(&self) -> bool {
+        self.console_socket
+    }
+
+    /// Limit host disk and 9p file IO to `megs` megabytes per second, shared
+    /// equally between every pH instance with a limit which is doing IO.
+    pub fn shared_io_limit(mut self, megs: u64) -> Self {
+        self.io_share_megs = Some(megs);
+        self
+    }
+
+    /// Number of threads which process the requests of each virtio-blk
+    /// device, and so how many requests of a device can be in progress on
+    /// the host at once. The default is 4.
+    pub fn block_io_threads(mut self, nthreads: usize) -> Self {
+        self.block_io_threads = nthreads.max(1);
+        self
+    }
+
+    /// Limit the CPU time used by all threads of the VM on the host to
+    /// `percent` of one CPU, so 200 allows two CPUs to be fully used. The
+    /// limit is applied with a cgroup v2 group created for the VM.
+    pub fn cpu_limit(mut self, percent: u32) -> Self {
+        self.cpu_limit_percent = Some(percent);
+        self
+    }
+
+    /// Limit the memory used by the VM on the host, including guest RAM, to
+    /// `megs` megabytes with a cgroup v2 group created for the VM.
+    pub fn memory_limit_megs(mut self, megs: u64) -> Self {
+        self.memory_limit_megs = Some(megs);
+        self
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(Duration::from_secs)
+    }
+
+    pub fn p9_timeout(&self) -> Option<Duration> {
+        match self.p9_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn p9_checksum_every(&self) -> Option<u64> {
+        self.p9_checksum_every
     }
 
-    pub fn ram_size_megs(mut self, megs: usize) -> Self {
-        self.ram_size = megs * 1024 * 1024;
-        self
+    pub fn is_p9_io_uring_enabled(&self) -> bool {
+        self.p9_io_uring
     }
 
-    pub fn raw_disk_image<P: Into<PathBuf>>(self, path: P, open_type: OpenType) -> Self {
-        self.raw_disk_image_with_offset(path, open_type, 0)
+    pub fn is_p9_process_enabled(&self) -> bool {
+        self.p9_process
     }
 
-    pub fn raw_disk_image_with_offset<P: Into<PathBuf>>(mut self, path: P, open_type: OpenType, offset: usize) -> Self {
-        match RawDiskImage::new_with_offset(path, open_type, offset) {
-            Ok(disk) => self.raw_disks.push(disk),
-            Err(e) => warn!("Could not add disk: {}", e),
-        };
-        self
+    pub fn get_p9_cache_hint(&self, tag: &str) -> Option<CacheHint> {
+        self.p9_cache_hints.iter().rev()
+            .find(|(t, _)| t == tag)
+            .map(|&(_, hint)| hint)
     }
 
-    pub fn realmfs_image<P: Into<PathBuf>>(mut self, path: P) -> Self {
-        match RealmFSImage::new(path, OpenType::MemoryOverlay) {
-            Ok(disk) => self.realmfs_images.push(disk),
-            Err(e) => warn!("Could not add disk: {}", e),
-        };
-        self
+    pub fn overlay_limit(&self) -> OverlayLimit {
+        let max_bytes = self.overlay_limit_megs.map(|megs| megs * 1024 * 1024);
+        OverlayLimit::new(max_bytes, self.overlay_spill_dir.clone())
     }
 
-    pub fn num_cpus(mut self, ncpus: usize) -> Self {
-        self.ncpus = ncpus;
-        self
+    /// Host IO limit in bytes per second set with `shared_io_limit()`
+    pub fn io_share_limit(&self) -> Option<u64> {
+        self.io_share_megs.map(|megs| megs * 1024 * 1024)
     }
 
-    pub fn init_cmdline(mut self, val: &str) -> Self {
-        self.init_cmd = Some(val.to_owned());
-        self
+    pub fn get_block_io_threads(&self) -> usize {
+        self.block_io_threads
     }
 
-    pub fn kernel_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
-        self.kernel_path = Some(path.into());
-        self
+    pub fn cpu_limit_percent(&self) -> Option<u32> {
+        self.cpu_limit_percent
     }
 
-    pub fn init_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
-        self.init_path = Some(path.into());
-        self
+    pub fn memory_limit(&self) -> Option<u64> {
+        self.memory_limit_megs.map(|megs| megs * 1024 * 1024)
     }
 
-    pub fn synthetic_fs(mut self, sfs: SyntheticFS) -> Self {
-        self.synthetic = Some(sfs);
-        self
+    pub fn has_cgroup_limits(&self) -> bool {
+        self.cpu_limit_percent.is_some() || self.memory_limit_megs.is_some()
     }
 
-    pub fn boot(self) {
+    pub fn suspend_policy(&self) -> Option<SuspendPolicy> {
+        self.suspend_after.map(|secs| SuspendPolicy::new(Duration::from_secs(secs), self.suspend_ram.clone()))
+    }
 
-        let _terminal_restore = TerminalRestore::save();
+    /// Path of the file which backs guest RAM, either the file set with
+    /// `ram_file()` or the file used when suspending with `RamPolicy::Snapshot`
+    pub fn ram_backing_file(&self) -> Option<&Path> {
+        self.get_ram_file()
+            .or_else(|| self.suspend_after.and(self.suspend_ram.snapshot_path()))
+    }
 
-        if let Some(scheme) = Base16Scheme::by_name(&self.colorscheme) {
-            let mut term = AnsiTerminal::new().unwrap();
-            if let Err(err) = term.apply_base16(scheme) {
-                warn!("Failed to set terminal color scheme: {}", err);
-            }
-        }
-        let mut setup = self.setup();
-        let vm = match setup.create_vm() {
-            Ok(vm) => vm,
-            Err(err) => {
-                warn!("Failed to create VM: {}", err);
-                return;
-            }
-        };
+    pub fn get_ram_file(&self) -> Option<&Path> {
+        self.ram_file.as_ref().map(|p| p.as_path())
+    }
 
-        if let Err(err) = vm.start() {
-            warn!("Failed to start VM: {}", err);
-        }
+    pub fn get_huge_pages(&self) -> HugePages {
+        self.huge_pages
     }
 
-    pub fn setup(self) -> VmSetup<X86ArchSetup> {
-        let arch_setup = arch::create_setup(&self);
-        VmSetup::new(self, arch_setup)
+    /// Remove and return the socket passed by systemd socket activation with `name`.
+    pub fn take_listen_fd(&mut self, name: &str) -> Option<RawFd> {
+        self.listen_fds.take(name)
     }
 
-    pub fn ram_size(&self) -> usize {
-        self.ram_size
+    pub fn record_path(&self) -> Option<&Path> {
+        self.record_path.as_ref().map(|p| p.as_path())
     }
 
-    pub fn ncpus(&self) -> usize {
-        self.ncpus
+    pub fn replay_path(&self) -> Option<&Path> {
+        self.replay_path.as_ref().map(|p| p.as_path())
     }
 
-    pub fn verbose(&self) -> bool {
-        self.verbose
+    pub fn trace_subsystems(&self) -> &[TraceSubsystem] {
+        &self.trace
     }
 
-    pub fn rootshell(&self) -> bool {
-        self.rootshell
+    pub fn trace_path(&self) -> Option<&Path> {
+        self.trace_path.as_ref().map(|p| p.as_path())
     }
 
-    pub fn network(&self) -> bool {
-        if unsafe { libc::geteuid() } != 0 {
-            false
-        } else {
-            self.network
+    pub fn trace_buffer_size(&self) -> usize {
+        self.trace_buffer
+    }
+
+    pub fn control_path(&self) -> Option<&Path> {
+        self.control_path.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+
+    pub fn gdb_address(&self) -> Option<SocketAddr> {
+        self.gdb_address
+    }
+
+    pub fn is_gdb_wait(&self) -> bool {
+        self.gdb_wait
+    }
+
+    pub fn vsock_path(&self) -> Option<&Path> {
+        self.vsock_path.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn get_vsock_cid(&self) -> u64 {
+        self.vsock_cid
+    }
+
+    pub fn serial_ports(&self) -> &[(String, PathBuf)] {
+        &self.serial_ports
+    }
+
+    /// The UARTs to add, including COM1 for kernel messages with verbose
+    /// output.
+    pub fn uarts(&self) -> Vec<(u8, UartBackend)> {
+        let mut uarts = self.uarts.clone();
+        if self.verbose && !uarts.iter().any(|&(port, _)| port == 0) {
+            uarts.insert(0, (0, UartBackend::Stdio));
         }
+        uarts
+    }
+
+    pub fn get_console_log(&self) -> Option<&Path> {
+        self.console_log.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn get_console_mode(&self) -> &ConsoleMode {
+        &self.console_mode
     }
 
     pub fn homedir(&self) -> &str {
@@ -174,6 +1300,10 @@ impl VmConfig {
         !(self.realmfs_images.is_empty() && self.raw_disks.is_empty())
     }
 
+    pub fn get_disk_image_count(&self) -> usize {
+        self.realmfs_images.len() + self.raw_disks.len() + self.extra_disks.len()
+    }
+
     pub fn get_realmfs_images(&mut self) -> Vec<RealmFSImage> {
         self.realmfs_images.drain(..).collect()
     }
@@ -182,10 +1312,22 @@ impl VmConfig {
         self.raw_disks.drain(..).collect()
     }
 
+    pub fn get_extra_disk_images(&mut self) -> Vec<RawDiskImage> {
+        self.extra_disks.drain(..).collect()
+    }
+
     pub fn get_synthetic_fs(&self) -> Option<SyntheticFS> {
         self.synthetic.clone()
     }
 
+    pub fn get_store_share(&self) -> Option<&Path> {
+        self.store_share.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn get_run_path(&self) -> Option<&Path> {
+        self.run_path.as_ref().map(|p| p.as_path())
+    }
+
     pub fn get_init_cmdline(&self) -> Option<&str> {
         self.init_cmd.as_ref().map(|s| s.as_str())
     }
@@ -199,7 +1341,7 @@ impl VmConfig {
     }
 
     pub fn is_wayland_enabled(&self) -> bool {
-        if !self.wayland {
+        if !self.wayland || self.tiny {
             return false;
         }
         let display = env::var("WAYLAND_DISPLAY").unwrap_or("wayland-0".to_string());
@@ -209,6 +1351,28 @@ impl VmConfig {
         socket.exists()
     }
 
+    pub fn get_wayland_tag(&self) -> Option<&str> {
+        self.wayland_tag.as_ref()
+            .map(|s| s.as_str())
+            .or_else(|| self.realm_name())
+    }
+
+    pub fn get_lockdown(&self) -> Option<&str> {
+        self.lockdown.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn is_module_sig_enforced(&self) -> bool {
+        self.module_sig_enforce
+    }
+
+    pub fn get_module_signing_key(&self) -> Option<&Path> {
+        self.module_key.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn get_boot_files(&self) -> &[(PathBuf, PathBuf)] {
+        &self.boot_files
+    }
+
     pub fn is_dmabuf_enabled(&self) -> bool {
         self.dmabuf
     }
@@ -217,18 +1381,28 @@ impl VmConfig {
         &self.bridge_name
     }
 
+    pub fn get_mac_address(&self) -> Option<MacAddress> {
+        self.mac_address
+    }
+
+    pub fn get_mtu(&self) -> Option<u16> {
+        self.mtu
+    }
+
+    pub fn deterministic_seed(&self) -> Option<u64> {
+        self.deterministic_seed
+    }
+
     fn add_realmfs_by_name(&mut self, realmfs: &str) {
         let path = Path::new("/realms/realmfs-images")
             .join(format!("{}-realmfs.img", realmfs));
         if !path.exists() {
-            eprintln!("Realmfs image does not exist at {}", path.display());
-            process::exit(1);
+            exit_config_error(&format!("Realmfs image does not exist at {}", path.display()));
         }
         match RealmFSImage::new(path, OpenType::MemoryOverlay) {
             Ok(disk) => self.realmfs_images.push(disk),
             Err(e) => {
-                warn!("Could not add disk: {}", e);
-                process::exit(1);
+                exit_config_error(&format!("Could not add disk: {}", e));
             },
         };
     }
@@ -241,6 +1415,10 @@ impl VmConfig {
             self.add_realmfs_by_name(realmfs);
             self.home = realm.base_path().join("home").display().to_string();
             self.realm_name = Some(realm.name().to_string());
+            let module_key = realm.base_path().join("module-key.der");
+            if self.module_key.is_none() && module_key.exists() {
+                self.module_key = Some(module_key);
+            }
             self.bridge_name = format!("vz-{}", config.network_zone());
             if let Some(scheme) = config.terminal_scheme() {
                 self.colorscheme = scheme.to_string();
@@ -248,8 +1426,80 @@ impl VmConfig {
         }
     }
 
-    fn parse_args(&mut self) {
-        let args = ProgramArgs::new();
+    fn add_pci_identity(&mut self, device: &str, identity: PciIdentity) {
+        match device_type_by_name(device) {
+            Some(device_type) => self.pci_identities.push((device_type, identity)),
+            None => {
+                exit_config_error(&format!("Unknown virtio device name '{}'", device));
+            }
+        }
+    }
+
+    // --pci-identity net=transitional,block=legacy,rng=1af4:0041
+    fn parse_pci_identities(&mut self, arg: &str) {
+        for item in arg.split(',') {
+            let mut parts = item.splitn(2, '=');
+            let device = parts.next().unwrap_or("");
+            match parts.next().and_then(PciIdentity::parse) {
+                Some(identity) => self.add_pci_identity(device, identity),
+                None => {
+                    exit_config_error(&format!("Invalid PCI identity '{}', expected <device>=transitional, <device>=legacy, or <device>=<subsystem vendor>:<subsystem id>", item));
+                }
+            }
+        }
+    }
+
+    // --irq-batch block=50,net=20 (microseconds)
+    fn parse_interrupt_windows(&mut self, arg: &str) {
+        for item in arg.split(',') {
+            let mut parts = item.splitn(2, '=');
+            let device = parts.next().unwrap_or("");
+            let micros = parts.next()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|&us| us > 0 && us <= MAX_INTERRUPT_WINDOW_US);
+            match (device_type_by_name(device), micros) {
+                (Some(device_type), Some(us)) => self.interrupt_windows.push((device_type, Duration::from_micros(us))),
+                (None, _) => exit_config_error(&format!("Unknown virtio device name '{}'", device)),
+                (_, None) => {
+                    exit_config_error(&format!("Invalid interrupt batching window '{}', expected <device>=<microseconds> from 1 to {}", item, MAX_INTERRUPT_WINDOW_US));
+                }
+            }
+        }
+    }
+
+    // --disk /path/to/image or --disk /path/to/image,partition=2,ro
+    fn parse_disk(arg: &str) -> Option<RawDiskImage> {
+        let mut parts = arg.split(',');
+        let path = parts.next().unwrap_or("");
+        let mut partition = None;
+        let mut open_type = OpenType::ReadWrite;
+        for option in parts {
+            if option == "ro" {
+                open_type = OpenType::ReadOnly;
+                continue;
+            }
+            let mut kv = option.splitn(2, '=');
+            match (kv.next(), kv.next().and_then(|n| n.parse::<usize>().ok())) {
+                (Some("partition"), Some(n)) => partition = Some(n),
+                _ => {
+                    exit_config_error(&format!("Invalid disk option '{}', expected partition=<number> or ro", option));
+                }
+            }
+        }
+        let disk = match partition {
+            Some(n) => RawDiskImage::new_with_partition(path, open_type, n),
+            None => RawDiskImage::new(path, open_type),
+        };
+        match disk {
+            Ok(disk) => Some(disk),
+            Err(e) => {
+                warn!("Could not add disk: {}", e);
+                None
+            }
+        }
+    }
+
+    fn parse_args(&mut self, args: ProgramArgs) {
         if args.has_arg("-v") {
             self.verbose = true;
         }
@@ -266,9 +1516,338 @@ impl VmConfig {
         if args.has_arg("--no-network") {
             self.network = false;
         }
+        if args.has_arg("--vhost-net") {
+            self.vhost_net = true;
+        }
+        if args.has_arg("--virtio-fs") {
+            self.virtio_fs = true;
+        }
+        if args.has_arg("--balloon") {
+            self.balloon = true;
+        }
+        if args.has_arg("--virtio-input") {
+            self.virtio_input = true;
+        }
+        if args.has_arg("--notifications") {
+            self.notifications = true;
+        }
+        if let Some(command) = args.arg_with_value("--notify-command") {
+            self.notify_command = Some(command.to_string());
+        }
+        if let Some(backend) = args.arg_with_value("--sound") {
+            match SoundBackend::parse(backend) {
+                Some(backend) => self.sound = Some(backend),
+                None => warn!("Invalid value for --sound: {}", backend),
+            }
+        }
+        if let Some(action) = args.arg_with_value("--on-reboot") {
+            match RebootAction::parse(action) {
+                Some(action) => self.reboot_action = action,
+                None => warn!("Invalid value for --on-reboot: {}", action),
+            }
+        }
+        if let Some(weight) = args.arg_with_value("--balloon-weight") {
+            match weight.parse::<u32>() {
+                Ok(weight) if weight > 0 => self.balloon_weight = Some(weight),
+                _ => warn!("Invalid value for --balloon-weight: {}", weight),
+            }
+        }
+        if args.has_arg("--scrub-memory") {
+            self.scrub_memory = true;
+        }
+        if let Some(path) = args.arg_with_value("--ram-file") {
+            self.ram_file = Some(PathBuf::from(path));
+        }
+        if let Some(pages) = args.arg_with_value("--hugepages") {
+            match HugePages::parse(pages) {
+                Some(pages) => self.huge_pages = pages,
+                None => warn!("Invalid value for --hugepages: {}", pages),
+            }
+        }
+        if let Some(path) = args.arg_with_value("--kernel") {
+            self.kernel_path = Some(PathBuf::from(path));
+        }
+        if let Some(path) = args.arg_with_value("--initrd") {
+            self.initrd_path = Some(PathBuf::from(path));
+        }
+        if args.has_arg("--no-pvh") {
+            self.pvh = false;
+        }
+        if args.has_arg("--tiny") {
+            self.tiny = true;
+        }
+        if args.has_arg("--hyperv") {
+            self.hyperv = true;
+        }
+        if args.has_arg("--no-steal-time") {
+            self.steal_time = false;
+        }
+        if args.has_arg("--no-pv-eoi") {
+            self.pv_eoi = false;
+        }
+        if let Some(identities) = args.arg_with_value("--pci-identity") {
+            self.parse_pci_identities(identities);
+        }
+        if let Some(windows) = args.arg_with_value("--irq-batch") {
+            self.parse_interrupt_windows(windows);
+        }
+        if let Some(seed) = args.arg_with_value("--deterministic") {
+            match seed.parse::<u64>() {
+                Ok(seed) => self.deterministic_seed = Some(seed),
+                Err(_) => warn!("Invalid value for --deterministic: {}", seed),
+            }
+        }
+        if let Some(mac) = args.arg_with_value("--mac") {
+            match MacAddress::parse(mac) {
+                Some(mac) => self.mac_address = Some(mac),
+                None => warn!("Invalid value for --mac: {}", mac),
+            }
+        }
+        if let Some(mtu) = args.arg_with_value("--mtu") {
+            match mtu.parse::<u16>() {
+                Ok(n) if n >= MIN_MTU => self.mtu = Some(n),
+                _ => warn!("Invalid value for --mtu: {}", mtu),
+            }
+        }
+        if let Some(policy) = args.arg_with_value("--irq-policy") {
+            match IrqPolicy::parse(policy) {
+                Some(policy) => self.irq_policy = policy,
+                None => warn!("Invalid value for --irq-policy: {}", policy),
+            }
+        }
+        if let Some(mode) = args.arg_with_value("--seccomp") {
+            match SeccompMode::parse(mode) {
+                Some(mode) => self.seccomp_mode = mode,
+                None => warn!("Invalid value for --seccomp: {}", mode),
+            }
+        }
+        if args.has_arg("--virtio-audit") {
+            self.virtio_audit = true;
+        }
+        if args.has_arg("--msix") {
+            self.msix = true;
+        }
+        if let Some(tables) = args.arg_with_value("--boot-tables") {
+            match BootTables::parse(tables) {
+                Some(tables) => self.boot_tables = tables,
+                None => warn!("Invalid value for --boot-tables: {}", tables),
+            }
+        }
+        if let Some(profile) = args.arg_with_value("--platform") {
+            match PlatformProfile::parse(profile) {
+                Some(profile) => self.platform_profile = profile,
+                None => warn!("Invalid value for --platform: {}", profile),
+            }
+        }
+        if args.has_arg("--pci-hotplug") {
+            self.pci_hotplug = true;
+        }
+        if args.has_arg("--virtio-mmio") {
+            self.virtio_mmio = true;
+        }
+        if let Some(tag) = args.arg_with_value("--wayland-tag") {
+            if Self::is_valid_wayland_tag(tag) {
+                self.wayland_tag = Some(tag.to_string());
+            } else {
+                warn!("Invalid value for --wayland-tag: {}", tag);
+            }
+        }
+        if let Some(mode) = args.arg_with_value("--lockdown") {
+            if Self::is_valid_lockdown_mode(mode) {
+                self.lockdown = Some(mode.to_string());
+            } else {
+                warn!("Invalid value for --lockdown: {}", mode);
+            }
+        }
+        if args.has_arg("--module-sig-enforce") {
+            self.module_sig_enforce = true;
+        }
+        if let Some(path) = args.arg_with_value("--module-key") {
+            self.module_key = Some(PathBuf::from(path));
+        }
+        for file in args.all_args_with_value("--boot-file") {
+            let mut parts = file.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(guest), Some(host)) if guest.starts_with('/') && !host.is_empty() =>
+                    self.boot_files.push((PathBuf::from(guest), PathBuf::from(host))),
+                _ => warn!("Invalid value for --boot-file: {}, expected GUEST_PATH=HOST_PATH", file),
+            }
+        }
+        if let Some(path) = args.arg_with_value("--record-events") {
+            self.record_path = Some(PathBuf::from(path));
+        }
+        if let Some(path) = args.arg_with_value("--replay-events") {
+            self.replay_path = Some(PathBuf::from(path));
+        }
+        if let Some(subsystems) = args.arg_with_value("--trace") {
+            match TraceSubsystem::parse_list(subsystems) {
+                Some(subsystems) => self.trace = subsystems,
+                None => exit_config_error(&format!("Invalid value for --trace: {}, expected a list of exit, pio, mmio and virtqueue, or all", subsystems)),
+            }
+        }
+        if let Some(path) = args.arg_with_value("--trace-file") {
+            self.trace_path = Some(PathBuf::from(path));
+        }
+        if let Some(records) = args.arg_with_value("--trace-buffer") {
+            match records.parse::<usize>() {
+                Ok(n) if n > 0 && n <= MAX_TRACE_BUFFER => self.trace_buffer = n,
+                _ => warn!("Invalid value for --trace-buffer: {}, expected 1 to {} events", records, MAX_TRACE_BUFFER),
+            }
+        }
+        if let Some(path) = args.arg_with_value("--control-socket") {
+            self.control_path = Some(PathBuf::from(path));
+        }
+        if let Some(address) = args.arg_with_value("--metrics-listen") {
+            match address.parse() {
+                Ok(address) => self.metrics_address = Some(address),
+                Err(_) => warn!("Invalid value for --metrics-listen: {}, expected ADDRESS:PORT", address),
+            }
+        }
+        if let Some(address) = args.arg_with_value("--gdb") {
+            match address.parse() {
+                Ok(address) => self.gdb_address = Some(address),
+                Err(_) => warn!("Invalid value for --gdb: {}, expected ADDRESS:PORT", address),
+            }
+        }
+        if args.has_arg("--gdb-wait") {
+            self.gdb_wait = true;
+        }
+        if let Some(path) = args.arg_with_value("--vsock") {
+            self.vsock_path = Some(PathBuf::from(path));
+        }
+        for spec in args.all_args_with_value("--vhost-user") {
+            match VhostUserSpec::parse(spec) {
+                Some(spec) => self.vhost_user.push(spec),
+                None => warn!("Invalid value for --vhost-user: {}, expected fs:SOCKET,tag=TAG or net:SOCKET[,mac=ADDRESS]", spec),
+            }
+        }
+        for port in args.all_args_with_value("--serial-port") {
+            let mut parts = port.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(path)) if Self::is_valid_port_name(name) && !path.is_empty() =>
+                    self.serial_ports.push((name.to_string(), PathBuf::from(path))),
+                _ => warn!("Invalid value for --serial-port: {}, expected NAME=PATH", port),
+            }
+        }
+        for uart in args.all_args_with_value("--uart") {
+            let mut parts = uart.splitn(2, '=');
+            let port = parts.next().and_then(|p| p.parse::<u8>().ok());
+            let backend = parts.next().and_then(UartBackend::parse);
+            match (port, backend) {
+                (Some(port), Some(backend)) if port < MAX_UARTS => {
+                    self.uarts.retain(|&(p, _)| p != port);
+                    self.uarts.push((port, backend));
+                }
+                _ => warn!("Invalid value for --uart: {}, expected N=null|stdio|file:PATH|unix:PATH", uart),
+            }
+        }
+        if let Some(path) = args.arg_with_value("--console-log") {
+            self.console_log = Some(PathBuf::from(path));
+        }
+        if let Some(mode) = args.arg_with_value("--console") {
+            match ConsoleMode::parse(mode) {
+                Some(mode) => self.console_mode = mode,
+                None => warn!("Invalid value for --console: {}", mode),
+            }
+        }
+        if let Some(cid) = args.arg_with_value("--vsock-cid") {
+            match cid.parse::<u64>() {
+                Ok(cid) if Self::is_valid_vsock_cid(cid) => self.vsock_cid = cid,
+                _ => warn!("Invalid value for --vsock-cid: {}", cid),
+            }
+        }
+        if let Some(secs) = args.arg_with_value("--idle-timeout") {
+            match secs.parse::<u64>() {
+                Ok(secs) => self.idle_timeout = Some(secs),
+                Err(_) => warn!("Invalid value for --idle-timeout: {}", secs),
+            }
+        }
+        if let Some(secs) = args.arg_with_value("--suspend-after") {
+            match secs.parse::<u64>() {
+                Ok(secs) => self.suspend_after = Some(secs),
+                Err(_) => warn!("Invalid value for --suspend-after: {}", secs),
+            }
+        }
+        if let Some(ram) = args.arg_with_value("--suspend-ram") {
+            match RamPolicy::parse(ram) {
+                Some(ram) => self.suspend_ram = ram,
+                None => warn!("Invalid value for --suspend-ram: {}", ram),
+            }
+        }
+        if let Some(secs) = args.arg_with_value("--9p-timeout") {
+            match secs.parse::<u64>() {
+                Ok(secs) => self.p9_timeout = secs,
+                Err(_) => warn!("Invalid value for --9p-timeout: {}", secs),
+            }
+        }
+        if args.has_arg("--9p-io-uring") {
+            self.p9_io_uring = true;
+        }
+        if args.has_arg("--9p-process") {
+            self.p9_process = true;
+        }
+        for hint in args.all_args_with_value("--9p-cache-hint") {
+            let mut parts = hint.splitn(2, '=');
+            match (parts.next(), parts.next().and_then(CacheHint::parse)) {
+                (Some(tag), Some(h)) if !tag.is_empty() => self.p9_cache_hints.push((tag.to_string(), h)),
+                _ => warn!("Invalid value for --9p-cache-hint: {}, expected TAG=normal|sequential|noreuse|dontneed", hint),
+            }
+        }
+        if let Some(every) = args.arg_with_value("--9p-checksum") {
+            match every.parse::<u64>() {
+                Ok(every) if every > 0 => self.p9_checksum_every = Some(every),
+                _ => warn!("Invalid value for --9p-checksum: {}", every),
+            }
+        }
+        if let Some(megs) = args.arg_with_value("--overlay-limit") {
+            match megs.parse::<u64>() {
+                Ok(megs) => self.overlay_limit_megs = Some(megs),
+                Err(_) => warn!("Invalid value for --overlay-limit: {}", megs),
+            }
+        }
+        if let Some(megs) = args.arg_with_value("--shared-io-limit") {
+            match megs.parse::<u64>() {
+                Ok(megs) => self.io_share_megs = Some(megs),
+                Err(_) => warn!("Invalid value for --shared-io-limit: {}", megs),
+            }
+        }
+        if let Some(n) = args.arg_with_value("--block-io-threads") {
+            match n.parse::<usize>() {
+                Ok(n) if n > 0 => self.block_io_threads = n,
+                _ => warn!("Invalid value for --block-io-threads: {}", n),
+            }
+        }
+        if let Some(percent) = args.arg_with_value("--cpu-limit") {
+            match percent.parse::<u32>() {
+                Ok(percent) => self.cpu_limit_percent = Some(percent),
+                Err(_) => warn!("Invalid value for --cpu-limit: {}", percent),
+            }
+        }
+        if let Some(megs) = args.arg_with_value("--memory-limit") {
+            match megs.parse::<u64>() {
+                Ok(megs) => self.memory_limit_megs = Some(megs),
+                Err(_) => warn!("Invalid value for --memory-limit: {}", megs),
+            }
+        }
+        if let Some(dir) = args.arg_with_value("--overlay-spill-dir") {
+            self.overlay_spill_dir = Some(PathBuf::from(dir));
+        }
+        if let Some(dir) = args.arg_with_value("--store") {
+            if Path::new(dir).is_dir() {
+                self.store_share = Some(PathBuf::from(dir));
+            } else {
+                warn!("Invalid value for --store: {} is not a directory", dir);
+            }
+        }
         if let Some(home) = args.arg_with_value("--home") {
             self.home = home.to_string();
         }
+        if let Some(disk) = args.arg_with_value("--disk") {
+            self.raw_disks.extend(Self::parse_disk(disk));
+        }
+        for disk in args.all_args_with_value("--add-disk") {
+            self.extra_disks.extend(Self::parse_disk(disk));
+        }
         if let Some(realmfs) = args.arg_with_value("--realmfs") {
             self.add_realmfs_by_name(realmfs);
         }
@@ -283,10 +1862,8 @@ struct ProgramArgs {
 }
 
 impl ProgramArgs {
-    fn new() -> Self {
-        ProgramArgs {
-            args: env::args().skip(1).collect(),
-        }
+    fn new(args: Vec<String>) -> Self {
+        ProgramArgs { args }
     }
 
     fn has_arg(&self, name: &str) -> bool {
@@ -300,14 +1877,38 @@ impl ProgramArgs {
                 match iter.next() {
                     Some(val) => return Some(val.as_str()),
                     None => {
-                        eprintln!("Expected value for {} argument", name);
-                        process::exit(1);
+                        exit_config_error(&format!("Expected value for {} argument", name));
                     }
                 }
             }
         }
         None
     }
+
+    fn all_args_with_value(&self, name: &str) -> Vec<&str> {
+        let mut values = Vec::new();
+        let mut iter = self.args.iter();
+        while let Some(arg) = iter.next() {
+            if arg.as_str() == name {
+                match iter.next() {
+                    Some(val) => values.push(val.as_str()),
+                    None => {
+                        exit_config_error(&format!("Expected value for {} argument", name));
+                    }
+                }
+            }
+        }
+        values
+    }
+}
+
+// Exit with the code for an invalid configuration after printing `message`
+// and the exit summary.
+fn exit_config_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    let status = ExitStatus::Failed(FailureKind::Config);
+    exits::print_exit_summary(status, message);
+    process::exit(status.exit_code())
 }
 
 pub struct TerminalRestore {
@@ -0,0 +1,500 @@
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use termios::*;
+
+use crate::util::remove_stale_socket;
+use crate::virtio::VirtQueue;
+use crate::vm::control::StreamHandoff;
+
+// Recent console output kept for replay to clients which attach
+const HISTORY_SIZE: usize = 64 * 1024;
+
+// A client which does not read its output for this long is dropped
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Ctrl-] followed by 'd' detaches from the console, and Ctrl-] typed twice
+// sends a single Ctrl-] to the guest.
+const ESCAPE_CHAR: u8 = 0x1d;
+const DETACH_CHAR: u8 = b'd';
+
+const DETACH_HELP: &str = "Ctrl-] d";
+
+// How long to wait before reading a PTY again when no program has it open
+const PTY_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+lazy_static! {
+    static ref OUTPUT: Mutex<ConsoleOutput> = Mutex::new(ConsoleOutput::new());
+    static ref INPUT: Mutex<Option<VirtQueue>> = Mutex::new(None);
+}
+
+// Cleared when the console is attached to a PTY or a socket instead
+static USE_STDIO: AtomicBool = AtomicBool::new(true);
+
+///
+/// Where the console of the guest is attached on the host.
+///
+#[derive(Clone,Debug,PartialEq)]
+pub enum ConsoleMode {
+    /// The terminal on stdin and stdout of pH
+    Stdio,
+    /// A new pseudo-terminal, whose path is shown when the VM starts
+    Pty,
+    /// Clients which connect to a unix socket at the path
+    Unix(PathBuf),
+}
+
+impl ConsoleMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "stdio" => Some(ConsoleMode::Stdio),
+            "pty" => Some(ConsoleMode::Pty),
+            _ if s.starts_with("unix:") && s.len() > 5 => Some(ConsoleMode::Unix(PathBuf::from(&s[5..]))),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Where output written by the guest to the console goes.
+///
+/// Output is copied to stdout while the terminal which started pH is
+/// attached, or to the PTY of the console, to the client which most
+/// recently attached through the control socket or the console socket, and
+/// to the console log file if there is one. The last
+/// `HISTORY_SIZE` bytes are kept so that a client which attaches can be
+/// shown what it missed.
+///
+struct ConsoleOutput {
+    history: VecDeque<u8>,
+    local: bool,
+    pty: Option<File>,
+    client: Option<UnixStream>,
+    log: Option<ConsoleLog>,
+}
+
+impl ConsoleOutput {
+    fn new() -> Self {
+        ConsoleOutput {
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            local: true,
+            pty: None,
+            client: None,
+            log: None,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        let keep = buf.len().min(HISTORY_SIZE);
+        let excess = (self.history.len() + keep).saturating_sub(HISTORY_SIZE);
+        self.history.drain(..excess);
+        self.history.extend(&buf[buf.len() - keep..]);
+
+        if self.local {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(buf).and_then(|_| stdout.flush());
+        }
+        if let Some(ref mut pty) = self.pty {
+            // Output is dropped when nothing reads the PTY and its buffer is full
+            let _ = pty.write_all(buf);
+        }
+        let failed = match self.client {
+            Some(ref mut client) => client.write_all(buf).is_err(),
+            None => false,
+        };
+        if failed {
+            self.client = None;
+        }
+        self.write_log(buf);
+    }
+
+    fn write_log(&mut self, buf: &[u8]) {
+        let failed = match self.log {
+            Some(ref mut log) => log.write(buf).is_err(),
+            None => false,
+        };
+        if failed {
+            warn!("Failed to write to console log, no more output will be logged");
+            self.log = None;
+        }
+    }
+
+    fn replay(&self, bytes: usize) -> Vec<u8> {
+        let skip = self.history.len().saturating_sub(bytes);
+        self.history.iter().skip(skip).cloned().collect()
+    }
+}
+
+/// Write output from the guest to every attached console.
+pub fn write_output(buf: &[u8]) {
+    OUTPUT.lock().unwrap().write(buf);
+}
+
+/// Write output from the guest on the serial port. It is written to
+/// stdout and logged when the console uses stdio, and otherwise goes
+/// wherever console output goes.
+pub fn write_serial(buf: &[u8]) {
+    if is_stdio() {
+        let _ = io::stdout().write_all(buf);
+        OUTPUT.lock().unwrap().write_log(buf);
+    } else {
+        write_output(buf);
+    }
+}
+
+/// Returns `true` if the console is attached to the terminal of pH rather
+/// than a PTY or a socket.
+pub fn is_stdio() -> bool {
+    USE_STDIO.load(Ordering::SeqCst)
+}
+
+/// Returns `true` if a client is attached through the control socket or
+/// the console socket.
+pub fn is_client_attached() -> bool {
+    OUTPUT.lock().unwrap().client.is_some()
+}
+
+/// Attach the console to the host as `mode` asks. With a PTY or a socket
+/// the terminal of pH is left alone, and the VM keeps running when it is
+/// closed.
+pub fn start_host_console(mode: &ConsoleMode) -> io::Result<()> {
+    match mode {
+        ConsoleMode::Stdio => return Ok(()),
+        ConsoleMode::Pty => {
+            let (master, path) = open_pty()?;
+            let reader = master.try_clone()?;
+            thread::spawn(move || pty_input_loop(reader));
+            OUTPUT.lock().unwrap().pty = Some(master);
+            notify!("Console is attached to {}", path.display());
+        }
+        ConsoleMode::Unix(path) => {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)?;
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => { thread::spawn(move || {
+                            if let Err(e) = attach_client(stream, HISTORY_SIZE) {
+                                verbose!("Console client disconnected: {}", e);
+                            }
+                        }); },
+                        Err(e) => warn!("Error accepting console connection: {}", e),
+                    }
+                }
+            });
+            notify!("Console is attached to {}", path.display());
+        }
+    }
+    USE_STDIO.store(false, Ordering::SeqCst);
+    OUTPUT.lock().unwrap().local = false;
+    unsafe { libc::signal(libc::SIGHUP, libc::SIG_IGN); }
+    Ok(())
+}
+
+// Open a new PTY and return the master side, on which writes do not block,
+// and the path of the terminal. The terminal is put in raw mode so that
+// programs which open it see the console as the guest sends it.
+fn open_pty() -> io::Result<(File, PathBuf)> {
+    let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let master = unsafe { File::from_raw_fd(fd) };
+    let mut name = [0 as libc::c_char; 64];
+    unsafe {
+        if libc::grantpt(fd) < 0 || libc::unlockpt(fd) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let err = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+    }
+    let path = PathBuf::from(unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned());
+
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_CLOEXEC)
+        .open(&path)?;
+    let mut termios = Termios::from_fd(slave.as_raw_fd())?;
+    cfmakeraw(&mut termios);
+    tcsetattr(slave.as_raw_fd(), TCSANOW, &termios)?;
+
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((master, path))
+}
+
+// Send input typed on the PTY to the guest. The master side reports a hang
+// up while no program has the terminal open, and is polled again after a
+// pause.
+fn pty_input_loop(mut master: File) {
+    let mut buf = [0u8; 32];
+    loop {
+        let mut pollfd = libc::pollfd {
+            fd: master.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 || pollfd.revents & libc::POLLIN == 0 {
+            thread::sleep(PTY_RETRY_INTERVAL);
+            continue;
+        }
+        match master.read(&mut buf) {
+            Ok(n) if n > 0 => if let Err(e) = send_input(&buf[..n]) {
+                warn!("Error sending console input: {}", e);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Append all console output from now on to the file at `path`, with
+/// the time at the start of each line.
+pub fn set_log_file(path: &Path) -> io::Result<()> {
+    let log = ConsoleLog::open(path)?;
+    OUTPUT.lock().unwrap().log = Some(log);
+    Ok(())
+}
+
+///
+/// A host file which receives a copy of the console output. Each line
+/// starts with the UTC time at which its first byte was written.
+///
+struct ConsoleLog {
+    file: File,
+    line_start: bool,
+}
+
+impl ConsoleLog {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(path)?;
+        let mut log = ConsoleLog { file, line_start: true };
+        log.write(b"[console log opened]\n")?;
+        Ok(log)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let stamp = timestamp();
+        let mut out = Vec::with_capacity(buf.len() + stamp.len());
+        for &b in buf {
+            if self.line_start {
+                out.extend_from_slice(stamp.as_bytes());
+            }
+            out.push(b);
+            self.line_start = b == b'\n';
+        }
+        self.file.write_all(&out)
+    }
+}
+
+// The current UTC time as `[2020-01-31 23:59:59.999] `
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!("[{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}] ",
+            year, month, day, time / 3600, (time / 60) % 60, time % 60, now.subsec_millis())
+}
+
+// Date in the proleptic Gregorian calendar of a number of days since
+// 1970-01-01, from http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Set the queue which carries console input to the guest.
+pub fn set_input_queue(vq: VirtQueue) {
+    *INPUT.lock().unwrap() = Some(vq);
+}
+
+/// Send input from an attached console to the guest.
+pub fn send_input(buf: &[u8]) -> io::Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    crate::vm::suspend::wake();
+    let input = INPUT.lock().unwrap();
+    let vq = match *input {
+        Some(ref vq) => vq,
+        None => return Ok(()),
+    };
+    let mut chain = vq.wait_next_chain()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    chain.write_all(buf)?;
+    chain.flush_chain();
+    Ok(())
+}
+
+/// Stop copying console output to stdout. Closing the terminal afterwards
+/// does not stop the VM.
+pub fn detach_local() {
+    let mut output = OUTPUT.lock().unwrap();
+    if output.local {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\r\n[detached from console, attach again with: pH attach]\r\n");
+        let _ = stdout.flush();
+        output.local = false;
+        unsafe { libc::signal(libc::SIGHUP, libc::SIG_IGN); }
+    }
+}
+
+/// Control socket command which turns the connection into a console.
+/// The optional argument is the number of KiB of recent output to replay.
+pub fn attach_command(args: &[&str]) -> Result<StreamHandoff, String> {
+    let replay = match args.first() {
+        Some(kib) => kib.parse::<usize>()
+            .map(|kib| kib * 1024)
+            .map_err(|_| format!("invalid replay size: {}", kib))?,
+        None => HISTORY_SIZE,
+    };
+    Ok(Box::new(move |stream| {
+        if let Err(e) = attach_client(stream, replay) {
+            verbose!("Console client disconnected: {}", e);
+        }
+    }))
+}
+
+fn attach_client(mut stream: UnixStream, replay: usize) -> io::Result<()> {
+    stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT))?;
+    {
+        let mut output = OUTPUT.lock().unwrap();
+        stream.write_all(&output.replay(replay))?;
+        if let Some(old) = output.client.replace(stream.try_clone()?) {
+            let _ = old.shutdown(Shutdown::Both);
+        }
+    }
+    let mut buf = [0u8; 32];
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        send_input(&buf[..n])?;
+    }
+}
+
+///
+/// Recognizes the escape sequence which detaches from the console in a
+/// stream of input bytes.
+///
+#[derive(Default)]
+pub struct EscapeDetector {
+    escaped: bool,
+}
+
+impl EscapeDetector {
+    /// Return the bytes of `input` which should be sent to the guest, and
+    /// `true` if the detach sequence was typed.
+    pub fn filter(&mut self, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut out = Vec::with_capacity(input.len());
+        for &b in input {
+            if self.escaped {
+                self.escaped = false;
+                match b {
+                    DETACH_CHAR => return (out, true),
+                    ESCAPE_CHAR => out.push(ESCAPE_CHAR),
+                    _ => out.extend_from_slice(&[ESCAPE_CHAR, b]),
+                }
+            } else if b == ESCAPE_CHAR {
+                self.escaped = true;
+            } else {
+                out.push(b);
+            }
+        }
+        (out, false)
+    }
+}
+
+/// Connect the terminal on stdin and stdout to a console `stream` returned
+/// by the `console-attach` control command until the detach sequence is
+/// typed or the VM exits.
+pub fn run_attached(stream: UnixStream) -> io::Result<()> {
+    let saved = Termios::from_fd(0).ok();
+    if let Some(mut raw) = saved {
+        cfmakeraw(&mut raw);
+        tcsetattr(0, TCSANOW, &raw)?;
+    }
+    eprint!("[attached to console, detach with {}]\r\n", DETACH_HELP);
+
+    let reader = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = forward_output(reader);
+        // The VM exited or another client attached
+        restore_terminal(saved);
+        eprintln!("\n[console closed]");
+        ::std::process::exit(0);
+    });
+
+    let result = forward_input(stream);
+    restore_terminal(saved);
+    eprintln!("\n[detached from console]");
+    result
+}
+
+fn forward_output(mut stream: UnixStream) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        stdout.write_all(&buf[..n])?;
+        stdout.flush()?;
+    }
+}
+
+fn forward_input(mut stream: UnixStream) -> io::Result<()> {
+    let mut escape = EscapeDetector::default();
+    let mut buf = [0u8; 32];
+    loop {
+        let n = io::stdin().read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        let (input, detach) = escape.filter(&buf[..n]);
+        stream.write_all(&input)?;
+        if detach {
+            return Ok(());
+        }
+    }
+}
+
+fn restore_terminal(saved: Option<Termios>) {
+    if let Some(termios) = saved {
+        let _ = tcsetattr(0, TCSANOW, &termios);
+    }
+}
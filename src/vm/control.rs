@@ -0,0 +1,399 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::{fs, result, thread};
+
+use crate::util::{remove_stale_socket, JsonValue};
+use crate::vm::{console, suspend};
+
+/// Version of the control protocol. The major version changes when an
+/// existing method is removed or changes incompatibly, the minor version
+/// changes when methods are added.
+pub const PROTOCOL_VERSION_MAJOR: u32 = 1;
+pub const PROTOCOL_VERSION_MINOR: u32 = 0;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const COMMAND_FAILED: i64 = -32000;
+
+pub type CommandResult = result::Result<JsonValue, String>;
+
+type CommandHandler = Box<dyn Fn(&[&str]) -> CommandResult + Send + Sync>;
+
+/// Takes over a connection after a stream command has succeeded
+pub type StreamHandoff = Box<dyn FnOnce(UnixStream) + Send>;
+
+type StreamHandler = Box<dyn Fn(&[&str]) -> result::Result<StreamHandoff, String> + Send + Sync>;
+
+enum Handler {
+    Command(CommandHandler),
+    Stream(StreamHandler),
+}
+
+struct Command {
+    description: String,
+    handler: Handler,
+}
+
+// Tracks client connections so that an idle VM can be detected
+struct Activity {
+    connections: usize,
+    last_request: Instant,
+}
+
+///
+/// A unix socket which accepts commands for inspecting and controlling a
+/// running VM.
+///
+/// Clients which program against the socket send JSON-RPC 2.0 requests, one
+/// per line:
+///
+///     {"jsonrpc":"2.0","id":1,"method":"wl-status","params":[]}
+///
+/// and receive one response per line containing either a `result` or an
+/// `error` object with a `code` and `message`. Parameters are passed as an
+/// array of positional arguments. The built in `version` method returns the
+/// protocol version and `capabilities` describes every available method.
+///
+/// For interactive use a line which is not a JSON object is treated as a
+/// command name followed by whitespace separated arguments and the response
+/// is a JSON object with either a `result` member or an `error` member.
+///
+/// Devices and other components add commands with `register()`. A command
+/// added with `register_stream()` takes over the connection once it has
+/// replied, so that the client can exchange raw data such as console I/O.
+///
+#[derive(Clone)]
+pub struct ControlServer {
+    path: Option<PathBuf>,
+    listener: Arc<Mutex<Option<UnixListener>>>,
+    commands: Arc<RwLock<BTreeMap<String, Command>>>,
+    activity: Arc<Mutex<Activity>>,
+}
+
+impl ControlServer {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self::create(Some(path.into()), None)
+    }
+
+    /// Create a server which accepts connections on an already bound and
+    /// listening socket, such as one passed by systemd socket activation.
+    pub fn from_listener_fd(fd: RawFd) -> Self {
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+        Self::create(None, Some(listener))
+    }
+
+    fn create(path: Option<PathBuf>, listener: Option<UnixListener>) -> Self {
+        let activity = Activity { connections: 0, last_request: Instant::now() };
+        ControlServer {
+            path,
+            listener: Arc::new(Mutex::new(listener)),
+            commands: Arc::new(RwLock::new(BTreeMap::new())),
+            activity: Arc::new(Mutex::new(activity)),
+        }
+    }
+
+    /// Returns `true` if no client is connected and no request has been
+    /// received for at least `timeout`.
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        let activity = self.activity.lock().unwrap();
+        activity.connections == 0 && activity.last_request.elapsed() >= timeout
+    }
+
+    fn update_activity(&self, connections: isize) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.connections = (activity.connections as isize + connections) as usize;
+        activity.last_request = Instant::now();
+    }
+
+    pub fn register<F>(&self, name: &str, description: &str, handler: F)
+        where F: Fn(&[&str]) -> CommandResult + Send + Sync + 'static
+    {
+        self.add_command(name, description, Handler::Command(Box::new(handler)));
+    }
+
+    /// Register a command which returns a function that is passed the
+    /// connection after the reply has been sent. The client must not send
+    /// anything after the request until it has read the reply.
+    pub fn register_stream<F>(&self, name: &str, description: &str, handler: F)
+        where F: Fn(&[&str]) -> result::Result<StreamHandoff, String> + Send + Sync + 'static
+    {
+        self.add_command(name, description, Handler::Stream(Box::new(handler)));
+    }
+
+    fn add_command(&self, name: &str, description: &str, handler: Handler) {
+        let command = Command {
+            description: description.to_string(),
+            handler,
+        };
+        self.commands.write().unwrap()
+            .insert(name.to_string(), command);
+    }
+
+    fn version() -> JsonValue {
+        JsonValue::object()
+            .with("protocol", format!("{}.{}", PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR))
+            .with("major", PROTOCOL_VERSION_MAJOR)
+            .with("minor", PROTOCOL_VERSION_MINOR)
+            .with("ph", env!("CARGO_PKG_VERSION"))
+    }
+
+    fn capabilities(&self) -> JsonValue {
+        let builtin = |name: &str, description: &str| {
+            JsonValue::object()
+                .with("name", name)
+                .with("description", description)
+        };
+        let mut methods = vec![
+            builtin("version", "Protocol and program version"),
+            builtin("capabilities", "Describe the available methods"),
+        ];
+        for (name, command) in self.commands.read().unwrap().iter() {
+            methods.push(builtin(name, &command.description));
+        }
+        JsonValue::object()
+            .with("version", Self::version())
+            .with("methods", methods)
+    }
+
+    fn command_names(&self) -> JsonValue {
+        let mut names: Vec<JsonValue> = self.commands.read().unwrap()
+            .keys()
+            .map(|k| k.as_str().into())
+            .collect();
+        names.push("version".into());
+        names.push("capabilities".into());
+        names.push("help".into());
+        JsonValue::Array(names)
+    }
+
+    /// Bind the socket if necessary and start a thread to accept connections.
+    pub fn start(&self) -> io::Result<()> {
+        let listener = match (self.listener.lock().unwrap().take(), &self.path) {
+            (Some(listener), _) => listener,
+            (None, Some(path)) => {
+                remove_stale_socket(path)?;
+                UnixListener::bind(path)?
+            },
+            (None, None) => return Err(io::Error::new(io::ErrorKind::Other, "no control socket listener")),
+        };
+        let server = self.clone();
+        thread::spawn(move || server.accept_loop(listener));
+        Ok(())
+    }
+
+    /// Remove the socket file if it was created by this server.
+    pub fn shutdown(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Failed to remove control socket {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn accept_loop(&self, listener: UnixListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = self.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = server.handle_connection(stream) {
+                            verbose!("Control connection closed: {}", e);
+                        }
+                    });
+                },
+                Err(e) => {
+                    warn!("Error accepting control socket connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> io::Result<()> {
+        self.update_activity(1);
+        let result = self.process_requests(stream);
+        self.update_activity(-1);
+        result
+    }
+
+    fn process_requests(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut handoff = None;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            self.update_activity(0);
+            suspend::wake();
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let response = if line.starts_with('{') {
+                self.handle_rpc_request(line, &mut handoff)
+            } else {
+                self.handle_request(line, &mut handoff)
+            };
+            writeln!(writer, "{}", response)?;
+            if let Some(handoff) = handoff.take() {
+                handoff(writer);
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    // A successful stream command stores the function which takes over the
+    // connection in `handoff`.
+    fn call(&self, method: &str, args: &[&str], handoff: &mut Option<StreamHandoff>) -> Option<CommandResult> {
+        match method {
+            "help" => Some(Ok(self.command_names())),
+            "version" => Some(Ok(Self::version())),
+            "capabilities" => Some(Ok(self.capabilities())),
+            _ => self.commands.read().unwrap()
+                .get(method)
+                .map(|command| match command.handler {
+                    Handler::Command(ref handler) => handler(args),
+                    Handler::Stream(ref handler) => handler(args).map(|f| {
+                        *handoff = Some(f);
+                        JsonValue::Null
+                    }),
+                }),
+        }
+    }
+
+    fn handle_request(&self, line: &str, handoff: &mut Option<StreamHandoff>) -> JsonValue {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let result = self.call(args[0], &args[1..], handoff)
+            .unwrap_or_else(|| Err(format!("unknown command: {}", args[0])));
+        match result {
+            Ok(val) => JsonValue::object().with("result", val),
+            Err(msg) => JsonValue::object().with("error", msg),
+        }
+    }
+
+    fn rpc_error(id: JsonValue, code: i64, message: &str) -> JsonValue {
+        JsonValue::object()
+            .with("jsonrpc", "2.0")
+            .with("id", id)
+            .with("error", JsonValue::object()
+                .with("code", code)
+                .with("message", message))
+    }
+
+    fn handle_rpc_request(&self, line: &str, handoff: &mut Option<StreamHandoff>) -> JsonValue {
+        let request = match JsonValue::parse(line) {
+            Ok(request) => request,
+            Err(e) => return Self::rpc_error(JsonValue::Null, PARSE_ERROR, &e),
+        };
+        let id = request.get("id").cloned().unwrap_or(JsonValue::Null);
+
+        if request.get("jsonrpc").and_then(|v| v.as_str()) != Some("2.0") {
+            return Self::rpc_error(id, INVALID_REQUEST, "jsonrpc member must be \"2.0\"");
+        }
+        let method = match request.get("method").and_then(|v| v.as_str()) {
+            Some(method) => method,
+            None => return Self::rpc_error(id, INVALID_REQUEST, "method member missing"),
+        };
+        let params = match Self::rpc_params(request.get("params")) {
+            Some(params) => params,
+            None => return Self::rpc_error(id, INVALID_PARAMS, "params must be an array of strings, numbers, or booleans"),
+        };
+        let args: Vec<&str> = params.iter().map(|s| s.as_str()).collect();
+
+        match self.call(method, &args, handoff) {
+            Some(Ok(result)) => JsonValue::object()
+                .with("jsonrpc", "2.0")
+                .with("id", id)
+                .with("result", result),
+            Some(Err(msg)) => Self::rpc_error(id, COMMAND_FAILED, &msg),
+            None => Self::rpc_error(id, METHOD_NOT_FOUND, &format!("unknown method: {}", method)),
+        }
+    }
+
+    // Convert positional parameters to the string arguments passed to command handlers
+    fn rpc_params(params: Option<&JsonValue>) -> Option<Vec<String>> {
+        let params = match params {
+            None | Some(JsonValue::Null) => return Some(Vec::new()),
+            Some(params) => params.as_array()?,
+        };
+        params.iter().map(|p| match p {
+            JsonValue::String(s) => Some(s.clone()),
+            JsonValue::Number(_) | JsonValue::Bool(_) => Some(p.to_string()),
+            _ => None,
+        }).collect()
+    }
+}
+
+///
+/// Client for the JSON-RPC protocol of a `ControlServer`.
+///
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: i64,
+}
+
+impl ControlClient {
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(ControlClient { reader, writer, next_id: 1 })
+    }
+
+    /// Call `method` and return the result, or the error message from the server.
+    pub fn call(&mut self, method: &str, params: &[&str]) -> io::Result<CommandResult> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let params: Vec<JsonValue> = params.iter().map(|&p| p.into()).collect();
+        let request = JsonValue::object()
+            .with("jsonrpc", "2.0")
+            .with("id", id)
+            .with("method", method)
+            .with("params", params);
+        writeln!(self.writer, "{}", request)?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "control socket closed"));
+        }
+        let response = JsonValue::parse(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        if let Some(error) = response.get("error") {
+            let message = error.get("message")
+                .and_then(|m| m.as_str())
+                .unwrap_or("unknown error");
+            return Ok(Err(message.to_string()));
+        }
+        Ok(Ok(response.get("result").cloned().unwrap_or(JsonValue::Null)))
+    }
+
+    /// Attach the terminal to the console of the VM, first showing up to
+    /// `replay_kib` KiB of recent output, until the detach sequence is typed.
+    pub fn attach_console(mut self, replay_kib: Option<usize>) -> io::Result<()> {
+        let replay = replay_kib.map(|kib| kib.to_string());
+        let params: Vec<&str> = replay.iter().map(|s| s.as_str()).collect();
+        self.call("console-attach", &params)?
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        console::run_attached(self.writer)
+    }
+
+    /// Verify that the server speaks a compatible major version of the protocol.
+    pub fn check_version(&mut self) -> io::Result<()> {
+        let version = self.call("version", &[])?
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let major = version.get("major").and_then(|v| v.as_i64());
+        if major != Some(PROTOCOL_VERSION_MAJOR as i64) {
+            let msg = format!("incompatible control protocol version {}", version.get("protocol").unwrap_or(&JsonValue::Null));
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        Ok(())
+    }
+}
@@ -2,7 +2,8 @@ use std::{result, io};
 use std::fmt;
 use crate::{system, kvm, virtio};
 use crate::system::netlink;
-use crate::vm::arch;
+use crate::vm::{arch, preflight};
+use crate::vm::exits::FailureKind;
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -16,8 +17,31 @@ pub enum Error {
     NetworkSetup(netlink::Error),
     SetupBootFs(io::Error),
     SetupVirtio(virtio::Error),
+    ReplayLog(io::Error),
+    TraceFile(io::Error),
+    ControlSocket(io::Error),
+    MetricsListener(io::Error),
+    GdbListener(io::Error),
+    HostConsole(io::Error),
+    Cgroup(io::Error),
+    FileLimit(u64, u64),
+    PciHotplug(&'static str),
 }
 
+impl Error {
+    /// The kind of failure reported in the exit code of pH.
+    pub fn failure_kind(&self) -> FailureKind {
+        match self {
+            Error::ArchError(e) => e.failure_kind(),
+            Error::MappingFailed(_) | Error::FileLimit(..) => FailureKind::HostResources,
+            Error::ReplayLog(_) | Error::TraceFile(_) | Error::PciHotplug(_) => FailureKind::Config,
+            Error::NetworkSetup(_) | Error::SetupBootFs(_) | Error::SetupVirtio(_) |
+            Error::ControlSocket(_) | Error::MetricsListener(_) | Error::GdbListener(_) | Error::HostConsole(_) |
+            Error::Cgroup(_) => FailureKind::Setup,
+            Error::CreateVmFailed(_) | Error::TerminalTermios(_) | Error::IoError(_) => FailureKind::Other,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -29,6 +53,16 @@ impl fmt::Display for Error {
             Error::MappingFailed(e) => write!(f, "memory mapping failed: {}", e),
             Error::SetupBootFs(e) => write!(f, "setting up boot fs failed: {}", e),
             Error::SetupVirtio(e) => write!(f, "setting up virtio devices failed: {}", e),
+            Error::ReplayLog(e) => write!(f, "failed to open event replay log: {}", e),
+            Error::TraceFile(e) => write!(f, "failed to open trace file: {}", e),
+            Error::ControlSocket(e) => write!(f, "failed to create control socket: {}", e),
+            Error::MetricsListener(e) => write!(f, "failed to listen for metrics requests: {}", e),
+            Error::GdbListener(e) => write!(f, "failed to listen for gdb connections: {}", e),
+            Error::HostConsole(e) => write!(f, "failed to attach console: {}", e),
+            Error::Cgroup(e) => write!(f, "failed to set up cgroup: {}", e),
+            Error::FileLimit(needed, limit) => write!(f, "this VM needs at least {} file descriptors but the limit is {}. {}",
+                                                     needed, limit, preflight::remedy(*needed)),
+            Error::PciHotplug(reason) => write!(f, "cannot add a PCI hotplug controller because {}", reason),
             Error::ArchError(e) => e.fmt(f),
         }
     }
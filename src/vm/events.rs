@@ -0,0 +1,163 @@
+//! Events reported by the guest agent in ph-init.
+//!
+//! The agent writes one event per line to the `ph.agent` console port, an
+//! event name followed by `key=value` fields. Events are kept in a bounded
+//! log which control socket clients read with the `events` method, passing
+//! the `next` sequence number from the previous response to receive only
+//! events which arrived since then.
+//!
+//! A `power-off` event is sent by ph-init just before it resets the guest
+//! and is consumed by the exit handler for the reset, and `notify` events
+//! are passed to the `notify` module to be shown on the host.
+//!
+//! Events of the VM itself, such as the VM stopping or a kernel panic
+//! reported by the guest, are added to the same log by the host and are
+//! marked with `"source": "host"`.
+//!
+//! The host sends commands to the agent in the other direction on the same
+//! port, also one per line, such as `restart-wayland` when the wayland
+//! compositor of the host has restarted.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::util::JsonValue;
+use crate::virtio::VirtQueue;
+use crate::vm::control::CommandResult;
+use crate::vm::notify;
+
+// Oldest events are discarded once the log holds this many
+const MAX_EVENTS: usize = 256;
+
+// Upper bound on how long an `events` request waits for a new event
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref EVENTS: (Mutex<EventLog>, Condvar) = (Mutex::new(EventLog::default()), Condvar::new());
+    // Receive queue of the agent port
+    static ref COMMANDS: Mutex<Option<VirtQueue>> = Mutex::new(None);
+}
+
+static POWER_OFF_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Default)]
+struct EventLog {
+    next_seq: u64,
+    events: VecDeque<(u64, JsonValue)>,
+}
+
+impl EventLog {
+    fn since(&self, seq: u64) -> Vec<JsonValue> {
+        self.events.iter()
+            .filter(|(s,_)| *s >= seq)
+            .map(|(_,event)| event.clone())
+            .collect()
+    }
+}
+
+/// Parse a line sent by the guest agent and add it to the event log.
+pub fn guest_event(line: &str) {
+    let mut parts = line.split_whitespace();
+    let name = match parts.next() {
+        Some(name) => name,
+        None => return,
+    };
+    let mut event = JsonValue::object()
+        .with("event", name);
+    for field in parts {
+        let mut kv = field.splitn(2, '=');
+        let key = kv.next().unwrap_or_default();
+        let val = kv.next().unwrap_or_default();
+        // The text of a notification is kept as it is even if it is a number
+        match val.parse::<i64>() {
+            Ok(n) if name != "notify" => event.set(key, n),
+            _ => event.set(key, val),
+        }
+    }
+    if name == "power-off" {
+        POWER_OFF_REQUESTED.store(true, Ordering::SeqCst);
+    }
+    if name == "notify" {
+        notify::guest_notification(&mut event);
+    }
+    match name {
+        "memory-pressure" | "oom-kill" => warn!("Guest {}", line),
+        _ => verbose!("Guest {}", line),
+    }
+    post(event);
+}
+
+pub fn set_command_queue(vq: VirtQueue) {
+    *COMMANDS.lock().unwrap() = Some(vq);
+}
+
+/// Send `command` to the guest agent. Returns `false` if the guest has no
+/// agent port or no buffer is available for the command.
+pub fn send_command(command: &str) -> bool {
+    let commands = COMMANDS.lock().unwrap();
+    let mut chain = match commands.as_ref().and_then(|vq| vq.next_chain()) {
+        Some(chain) => chain,
+        None => return false,
+    };
+    let sent = writeln!(chain, "{}", command).is_ok();
+    chain.flush_chain();
+    sent
+}
+
+/// Add an event raised by the host rather than the guest agent to the log.
+pub fn host_event(mut event: JsonValue) {
+    event.set("source", "host");
+    post(event);
+}
+
+/// Returns `true` if the guest has announced a power off since the last
+/// call, and clears the request.
+pub fn take_power_off_request() -> bool {
+    POWER_OFF_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+fn post(mut event: JsonValue) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (lock, cvar) = &*EVENTS;
+    let mut log = lock.lock().unwrap();
+    let seq = log.next_seq;
+    log.next_seq += 1;
+    event.set("seq", seq);
+    event.set("time", time);
+    log.events.push_back((seq, event));
+    if log.events.len() > MAX_EVENTS {
+        log.events.pop_front();
+    }
+    cvar.notify_all();
+}
+
+/// Handler for the `events [SEQ [WAIT]]` control method. Returns events with
+/// a sequence number of at least `SEQ`, waiting up to `WAIT` seconds for one
+/// to arrive if there are none yet.
+pub fn events_command(args: &[&str]) -> CommandResult {
+    let parse = |idx: usize| match args.get(idx) {
+        Some(arg) => arg.parse::<u64>().map_err(|_| format!("invalid argument: {}", arg)),
+        None => Ok(0),
+    };
+    let seq = parse(0)?;
+    let wait = Duration::from_secs(parse(1)?).min(MAX_WAIT);
+
+    let (lock, cvar) = &*EVENTS;
+    let mut log = lock.lock().unwrap();
+    let deadline = Instant::now() + wait;
+    while log.next_seq <= seq {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        log = cvar.wait_timeout(log, deadline - now).unwrap().0;
+    }
+    Ok(JsonValue::object()
+        .with("next", log.next_seq)
+        .with("events", log.since(seq)))
+}
@@ -0,0 +1,600 @@
+//! Dispatch of vcpu exits from `KVM_RUN`.
+//!
+//! Every exit is passed to the tracepoints and then to the handlers which
+//! are registered for its exit reason. Handlers for I/O port and MMIO exits
+//! which forward to the `IoDispatcher`, and handlers for shutdown and error
+//! exits, are registered when an `ExitHandlers` is created. Devices and arch
+//! code register additional handlers for other exit reasons or to intercept
+//! particular port or address ranges before they reach the `IoDispatcher`.
+//!
+//! The guest has no ACPI power management, so it leaves the VM by resetting
+//! the CPU through the keyboard controller (`reboot=k`). ph-init sends a
+//! `power-off` event on the agent port before it resets when the console
+//! shell exits, which distinguishes a power off from a reboot. A triple
+//! fault is reported as a crash, as is a kernel panic if the guest kernel
+//! has the pvpanic driver, which reports the panic on I/O port 0x505. When
+//! a crash kernel is loaded the driver reports that instead and the crash
+//! kernel runs, and the reset which follows it is reported as a crash too.
+//! Without pvpanic a kernel panic also resets through the keyboard controller
+//! and is reported as a reboot.
+//!
+//! Whether a reboot starts the VM again is up to the caller of
+//! `VmConfig::boot()`, which can be told by `RebootAction`. A program which
+//! embeds pH can follow the VM from another thread through the `VmEvent`
+//! channel given to `VmConfig::vm_events()`, and each `VmEvent` is also
+//! added to the event log which control socket clients read with `events`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+
+use crate::kvm::KvmVcpu;
+use crate::memory::Mapping;
+use crate::util::JsonValue;
+use crate::util::trace::{self, TraceSubsystem};
+use crate::vm::arch::VcpuState;
+use crate::vm::events;
+use crate::vm::io::IoDispatcher;
+use crate::vm::suspend;
+
+pub const KVM_EXIT_UNKNOWN: u32 = 0;
+pub const KVM_EXIT_IO: u32 = 2;
+pub const KVM_EXIT_DEBUG: u32 = 4;
+pub const KVM_EXIT_MMIO: u32 = 6;
+pub const KVM_EXIT_SHUTDOWN: u32 = 8;
+pub const KVM_EXIT_INTR: u32 = 10;
+pub const KVM_EXIT_INTERNAL_ERROR: u32 = 17;
+pub const KVM_EXIT_SYSTEM_EVENT: u32 = 24;
+
+// Names of the exit reasons which are expected from KVM_RUN
+const EXIT_REASONS: &[(u32, &str)] = &[
+    (KVM_EXIT_UNKNOWN, "unknown"),
+    (KVM_EXIT_IO, "io"),
+    (KVM_EXIT_DEBUG, "debug"),
+    (KVM_EXIT_MMIO, "mmio"),
+    (KVM_EXIT_SHUTDOWN, "shutdown"),
+    (KVM_EXIT_INTR, "intr"),
+    (KVM_EXIT_INTERNAL_ERROR, "internal_error"),
+    (KVM_EXIT_SYSTEM_EVENT, "system_event"),
+];
+
+const KVM_SYSTEM_EVENT_SHUTDOWN: u32 = 1;
+const KVM_SYSTEM_EVENT_RESET: u32 = 2;
+const KVM_SYSTEM_EVENT_CRASH: u32 = 3;
+
+const I8042_COMMAND_PORT: u16 = 0x64;
+const I8042_CMD_RESET: u8 = 0xfe;
+
+/// I/O port of the pvpanic device, which is described to the guest in the
+/// ACPI tables.
+pub const PVPANIC_PORT: u16 = 0x505;
+const PVPANIC_PANICKED: u8 = 1;
+const PVPANIC_CRASH_LOADED: u8 = 2;
+
+// Set when the guest reports a panic through pvpanic and cleared by the
+// reset which ends the VM or when the next VM is created.
+static GUEST_PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// How a VM stopped running.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ExitStatus {
+    /// The guest powered off.
+    PowerOff,
+    /// The guest asked to be restarted.
+    Reboot,
+    /// The guest triple faulted or reported a crash.
+    Crash,
+    /// The VM was stopped by the host, for example by an idle timeout.
+    Stopped,
+    /// The VM could not be created or started.
+    Failed(FailureKind),
+}
+
+impl ExitStatus {
+    /// Process exit code for a VMM which exits with this status. These codes
+    /// are stable and are listed in the README.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ExitStatus::PowerOff | ExitStatus::Reboot | ExitStatus::Stopped => 0,
+            ExitStatus::Crash => 2,
+            ExitStatus::Failed(kind) => kind.exit_code(),
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitStatus::PowerOff => write!(f, "power off"),
+            ExitStatus::Reboot => write!(f, "reboot"),
+            ExitStatus::Crash => write!(f, "crash"),
+            ExitStatus::Stopped => write!(f, "stopped"),
+            ExitStatus::Failed(kind) => write!(f, "failed ({})", kind),
+        }
+    }
+}
+
+/// What to do when the guest reboots.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum RebootAction {
+    /// Boot the VM again with the same configuration.
+    Restart,
+    /// Exit as if the guest had powered off.
+    Exit,
+}
+
+impl RebootAction {
+    /// Parse `restart` or `exit`.
+    pub fn parse(s: &str) -> Option<RebootAction> {
+        match s {
+            "restart" => Some(RebootAction::Restart),
+            "exit" => Some(RebootAction::Exit),
+            _ => None,
+        }
+    }
+}
+
+/// Changes in the run state of a VM, sent to the channel given to
+/// `VmConfig::vm_events()`.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum VmEvent {
+    /// The vcpus have started running.
+    Started,
+    /// An exit of vcpu `vcpu` is stopping the VM with this status. Sent from
+    /// the vcpu thread before the other vcpus have stopped.
+    Stopping { vcpu: usize, status: ExitStatus },
+    /// Every vcpu has stopped and the VM exits with this status.
+    Stopped(ExitStatus),
+}
+
+impl VmEvent {
+    fn describe(&self) -> JsonValue {
+        match *self {
+            VmEvent::Started => JsonValue::object()
+                .with("event", "vm-started"),
+            VmEvent::Stopping { vcpu, status } => JsonValue::object()
+                .with("event", "vm-stopping")
+                .with("status", status.to_string())
+                .with("vcpu", vcpu),
+            VmEvent::Stopped(status) => JsonValue::object()
+                .with("event", "vm-stopped")
+                .with("status", status.to_string()),
+        }
+    }
+}
+
+/// Why a VM could not be created or started.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum FailureKind {
+    /// Any failure which does not fit one of the other kinds.
+    Other,
+    /// The command line or configuration is invalid, or a file it names
+    /// such as a disk image or realm does not exist.
+    Config,
+    /// `/dev/kvm` could not be opened, or KVM lacks a required capability.
+    KvmUnavailable,
+    /// The guest kernel could not be found or loaded into guest memory.
+    Kernel,
+    /// A host resource was exhausted or a limit is too low, such as the
+    /// file descriptor limit or the memory for guest RAM.
+    HostResources,
+    /// A device, the network, the cgroup, the control socket, the metrics
+    /// endpoint or the gdb stub could not be set up.
+    Setup,
+}
+
+impl FailureKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureKind::Other => 1,
+            FailureKind::Config => 3,
+            FailureKind::KvmUnavailable => 4,
+            FailureKind::Kernel => 5,
+            FailureKind::HostResources => 6,
+            FailureKind::Setup => 7,
+        }
+    }
+
+    /// The name of the kind in the exit summary.
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureKind::Other => "other",
+            FailureKind::Config => "config",
+            FailureKind::KvmUnavailable => "kvm-unavailable",
+            FailureKind::Kernel => "kernel",
+            FailureKind::HostResources => "host-resources",
+            FailureKind::Setup => "setup",
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Print the line which ends the output of a VM which failed or crashed, for
+/// scripts which run pH:
+///
+///     pH-exit: code=4 kind=kvm-unavailable message="could not open /dev/kvm: ..."
+///
+/// The message is quoted, and any `"` or `\` in it is escaped by a backslash.
+///
+pub fn print_exit_summary(status: ExitStatus, message: &str) {
+    let kind = match status {
+        ExitStatus::Failed(kind) => kind.name(),
+        ExitStatus::Crash => "guest-crash",
+        _ => return,
+    };
+    let mut quoted = String::with_capacity(message.len());
+    for c in message.chars() {
+        match c {
+            '"' | '\\' => { quoted.push('\\'); quoted.push(c); }
+            '\n' => quoted.push(' '),
+            c => quoted.push(c),
+        }
+    }
+    eprintln!("pH-exit: code={} kind={} message=\"{}\"", status.exit_code(), kind, quoted);
+}
+
+/// Result of passing an exit to a handler.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ExitAction {
+    /// The exit was handled and the vcpu continues running.
+    Handled,
+    /// The exit was handled and the VM stops with the given status.
+    Stop(ExitStatus),
+    /// The handler is not interested in this exit and it is passed to the next handler.
+    NotHandled,
+}
+
+pub type ExitHandler = dyn Fn(&VcpuExit) -> ExitAction + Send + Sync;
+pub type Tracepoint = dyn Fn(&VcpuExit) + Send + Sync;
+
+#[derive(Default)]
+struct HandlerTable {
+    handlers: BTreeMap<u32, Vec<Arc<ExitHandler>>>,
+    tracepoints: Vec<Arc<Tracepoint>>,
+}
+
+///
+/// The table of exit handlers shared by all vcpu threads of a VM.
+///
+/// Handlers and tracepoints are called on the vcpu thread while the table
+/// is locked for reading, so they must not register further handlers.
+///
+#[derive(Clone)]
+pub struct ExitHandlers {
+    table: Arc<RwLock<HandlerTable>>,
+    status: Arc<Mutex<Option<ExitStatus>>>,
+    events: Arc<Mutex<Option<Sender<VmEvent>>>>,
+}
+
+impl ExitHandlers {
+    pub fn new(io: Arc<IoDispatcher>) -> Self {
+        let handlers = ExitHandlers {
+            table: Arc::new(RwLock::new(HandlerTable::default())),
+            status: Arc::new(Mutex::new(None)),
+            events: Arc::new(Mutex::new(None)),
+        };
+        GUEST_PANICKED.store(false, Ordering::SeqCst);
+        handlers.register_defaults(io);
+        handlers
+    }
+
+    fn register_defaults(&self, io: Arc<IoDispatcher>) {
+        let io_port = io.clone();
+        self.register(KVM_EXIT_IO, move |exit| handle_io(&io_port, exit));
+        self.register(KVM_EXIT_IO, handle_i8042_reset);
+        self.register(KVM_EXIT_IO, handle_pvpanic);
+        self.register(KVM_EXIT_MMIO, move |exit| handle_mmio(&io, exit));
+        self.register(KVM_EXIT_SHUTDOWN, |_| ExitAction::Stop(ExitStatus::Crash));
+        self.register(KVM_EXIT_INTERNAL_ERROR, handle_internal_error);
+        self.register(KVM_EXIT_UNKNOWN, |_| { println!("unknown"); ExitAction::Handled });
+        self.register(KVM_EXIT_INTR, |_| { println!("intr"); ExitAction::Handled });
+        self.register(KVM_EXIT_SYSTEM_EVENT, handle_system_event);
+        self.add_tracepoint(trace_exit);
+    }
+
+    /// Add a handler for exits with reason `reason`. Handlers registered later
+    /// are tried first, so a handler can take over some of the exits which
+    /// would otherwise reach a built in handler by returning
+    /// `ExitAction::NotHandled` for the others.
+    pub fn register<F>(&self, reason: u32, handler: F)
+        where F: Fn(&VcpuExit) -> ExitAction + Send + Sync + 'static
+    {
+        self.table.write().unwrap()
+            .handlers
+            .entry(reason)
+            .or_insert_with(Vec::new)
+            .insert(0, Arc::new(handler));
+    }
+
+    /// Add a function which is called for every exit before it is handled.
+    pub fn add_tracepoint<F>(&self, tracepoint: F)
+        where F: Fn(&VcpuExit) + Send + Sync + 'static
+    {
+        self.table.write().unwrap()
+            .tracepoints
+            .push(Arc::new(tracepoint));
+    }
+
+    /// Status of the first exit which stopped the VM, or `None` if no
+    /// handler has returned `ExitAction::Stop`.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        *self.status.lock().unwrap()
+    }
+
+    /// Record `status` as the status the VM exits with. Returns `false` if
+    /// the VM is already stopping with another status.
+    pub fn set_exit_status(&self, status: ExitStatus) -> bool {
+        let mut current = self.status.lock().unwrap();
+        let first = current.is_none();
+        current.get_or_insert(status);
+        first
+    }
+
+    /// Send `VmEvent`s for this VM to `sender`.
+    pub fn set_event_sender(&self, sender: Sender<VmEvent>) {
+        *self.events.lock().unwrap() = Some(sender);
+    }
+
+    /// Add `event` to the event log and send it if there is an event
+    /// channel. A receiver which has gone away is ignored.
+    pub fn send_event(&self, event: VmEvent) {
+        events::host_event(event.describe());
+        if let Some(ref sender) = *self.events.lock().unwrap() {
+            let _ = sender.send(event);
+        }
+    }
+
+    pub fn dispatch(&self, exit: &VcpuExit) -> ExitAction {
+        let table = self.table.read().unwrap();
+        for tracepoint in &table.tracepoints {
+            tracepoint(exit);
+        }
+        let reason = exit.reason();
+        if let Some(handlers) = table.handlers.get(&reason) {
+            for handler in handlers {
+                match handler(exit) {
+                    ExitAction::NotHandled => continue,
+                    ExitAction::Stop(status) => {
+                        if self.set_exit_status(status) {
+                            self.send_event(VmEvent::Stopping { vcpu: exit.vcpu().id(), status });
+                        }
+                        return ExitAction::Stop(status);
+                    }
+                    action => return action,
+                }
+            }
+        }
+        println!("unhandled exit: {}", reason);
+        ExitAction::NotHandled
+    }
+}
+
+#[derive(Copy,Clone,Debug)]
+pub struct IoExitData {
+    pub dir_out: bool,
+    pub size: usize,
+    pub port: u16,
+    pub count: usize,
+    pub offset: usize,
+}
+
+#[derive(Copy,Clone,Debug)]
+pub struct MmioExitData {
+    pub phys: u64,
+    pub size: usize,
+    pub write: bool,
+}
+
+///
+/// A vcpu which has returned from `KVM_RUN`, with access to the exit
+/// information in its `kvm_run` structure.
+///
+pub struct VcpuExit<'a> {
+    vcpu: &'a KvmVcpu,
+    mapping: &'a Mapping,
+}
+
+impl <'a> VcpuExit<'a> {
+    pub fn new(vcpu: &'a KvmVcpu, mapping: &'a Mapping) -> Self {
+        VcpuExit { vcpu, mapping }
+    }
+
+    pub fn vcpu(&self) -> &KvmVcpu {
+        self.vcpu
+    }
+
+    pub fn r8(&self, offset: usize) -> u8 { self.mapping.read_int(offset).unwrap() }
+    pub fn r16(&self, offset: usize) -> u16 { self.mapping.read_int(offset).unwrap() }
+    pub fn r32(&self, offset: usize) -> u32 { self.mapping.read_int(offset).unwrap() }
+    pub fn r64(&self, offset: usize) -> u64 { self.mapping.read_int(offset).unwrap() }
+    pub fn w8(&self, offset: usize, val: u8) { self.mapping.write_int(offset, val).unwrap() }
+    pub fn w16(&self, offset: usize, val: u16) { self.mapping.write_int(offset, val).unwrap() }
+    pub fn w32(&self, offset: usize, val: u32) { self.mapping.write_int(offset, val).unwrap() }
+    pub fn w64(&self, offset: usize, val: u64) { self.mapping.write_int(offset, val).unwrap() }
+
+    pub fn reason(&self) -> u32 {
+        self.r32(8)
+    }
+
+    pub fn suberror(&self) -> u32 {
+        self.r32(32)
+    }
+
+    pub fn io(&self) -> IoExitData {
+        IoExitData {
+            dir_out: self.r8(32) != 0,
+            size: self.r8(33) as usize,
+            port: self.r16(34),
+            count: self.r32(36) as usize,
+            offset: self.r64(40) as usize,
+        }
+    }
+
+    pub fn mmio(&self) -> MmioExitData {
+        let size = self.r32(48) as usize;
+        assert!(size <= 8);
+        MmioExitData {
+            phys: self.r64(32),
+            size,
+            write: self.r8(52) != 0,
+        }
+    }
+
+    /// Value written by the guest for an MMIO write exit
+    pub fn mmio_data(&self, size: usize) -> Option<u64> {
+        match size {
+            1 => Some(self.r8(40) as u64),
+            2 => Some(self.r16(40) as u64),
+            4 => Some(self.r32(40) as u64),
+            8 => Some(self.r64(40)),
+            _ => None,
+        }
+    }
+
+    /// Set the value returned to the guest for an MMIO read exit
+    pub fn set_mmio_data(&self, size: usize, val: u64) {
+        match size {
+            1 => self.w8(40, val as u8),
+            2 => self.w16(40, val as u16),
+            4 => self.w32(40, val as u32),
+            8 => self.w64(40, val),
+            _ => (),
+        }
+    }
+}
+
+/// The name of exit reason `reason`, or `reason_N` for a reason which pH
+/// does not expect.
+pub fn exit_reason_name(reason: u32) -> String {
+    EXIT_REASONS.iter()
+        .find(|&&(r, _)| r == reason)
+        .map(|&(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("reason_{}", reason))
+}
+
+fn trace_exit(exit: &VcpuExit) {
+    trace::event(TraceSubsystem::Exit, || JsonValue::object()
+        .with("vcpu", exit.vcpu().id())
+        .with("reason", exit_reason_name(exit.reason())));
+}
+
+fn handle_io(io: &IoDispatcher, exit: &VcpuExit) -> ExitAction {
+    suspend::record_activity();
+    let data = exit.io();
+    for i in 0..data.count {
+        if data.dir_out {
+            let v = match data.size {
+                1 => exit.r8(data.offset + i) as u32,
+                2 => exit.r16(data.offset + i * 2) as u32,
+                4 => exit.r32(data.offset + i * 4),
+                _ => 0,
+            };
+            io.emulate_io_out(data.port, data.size, v);
+        } else {
+            let v = io.emulate_io_in(data.port, data.size);
+            match data.size {
+                1 => exit.w8(data.offset + i, v as u8),
+                2 => exit.w16(data.offset + i * 2, v as u16),
+                4 => exit.w32(data.offset + i * 4, v as u32),
+                _ => {},
+            }
+        }
+    }
+    ExitAction::Handled
+}
+
+// The guest kernel resets the CPU by pulsing the reset line of the keyboard
+// controller, both to reboot and, without ACPI, to power off.
+fn handle_i8042_reset(exit: &VcpuExit) -> ExitAction {
+    let data = exit.io();
+    if !data.dir_out || data.port != I8042_COMMAND_PORT || data.size != 1 || exit.r8(data.offset) != I8042_CMD_RESET {
+        return ExitAction::NotHandled;
+    }
+    if events::take_power_off_request() {
+        ExitAction::Stop(ExitStatus::PowerOff)
+    } else {
+        ExitAction::Stop(reset_status())
+    }
+}
+
+// A reset after the guest has panicked, such as the one at the end of a
+// crash kernel, is a crash and not a reboot.
+fn reset_status() -> ExitStatus {
+    if GUEST_PANICKED.swap(false, Ordering::SeqCst) {
+        ExitStatus::Crash
+    } else {
+        ExitStatus::Reboot
+    }
+}
+
+// A guest kernel with the pvpanic driver reads the events the device
+// supports and writes PVPANIC_PANICKED when it panics, or
+// PVPANIC_CRASH_LOADED if a crash kernel is loaded to run after the panic.
+fn handle_pvpanic(exit: &VcpuExit) -> ExitAction {
+    let data = exit.io();
+    if data.port != PVPANIC_PORT || data.size != 1 {
+        return ExitAction::NotHandled;
+    }
+    if !data.dir_out {
+        exit.w8(data.offset, PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+        return ExitAction::Handled;
+    }
+    let val = exit.r8(data.offset);
+    if val & PVPANIC_PANICKED != 0 {
+        warn!("Guest kernel panic reported by vcpu {}", exit.vcpu().id());
+        events::host_event(JsonValue::object()
+            .with("event", "guest-panic")
+            .with("vcpu", exit.vcpu().id()));
+        ExitAction::Stop(ExitStatus::Crash)
+    } else {
+        if val & PVPANIC_CRASH_LOADED != 0 {
+            warn!("Guest kernel panic reported by vcpu {}, running crash kernel", exit.vcpu().id());
+            GUEST_PANICKED.store(true, Ordering::SeqCst);
+            events::host_event(JsonValue::object().with("event", "guest-crash-loaded"));
+        }
+        ExitAction::Handled
+    }
+}
+
+fn handle_system_event(exit: &VcpuExit) -> ExitAction {
+    match exit.r32(32) {
+        KVM_SYSTEM_EVENT_SHUTDOWN => ExitAction::Stop(ExitStatus::PowerOff),
+        KVM_SYSTEM_EVENT_RESET => ExitAction::Stop(reset_status()),
+        KVM_SYSTEM_EVENT_CRASH => ExitAction::Stop(ExitStatus::Crash),
+        event => {
+            println!("unknown system event: {}", event);
+            ExitAction::Handled
+        }
+    }
+}
+
+fn handle_mmio(io: &IoDispatcher, exit: &VcpuExit) -> ExitAction {
+    suspend::record_activity();
+    let data = exit.mmio();
+    if data.write {
+        if let Some(val) = exit.mmio_data(data.size) {
+            io.emulate_mmio_write(data.phys, data.size, val)
+        }
+    } else if data.size == 1 || data.size == 2 || data.size == 4 || data.size == 8 {
+        let val = io.emulate_mmio_read(data.phys, data.size);
+        exit.set_mmio_data(data.size, val);
+    }
+    ExitAction::Handled
+}
+
+fn handle_internal_error(exit: &VcpuExit) -> ExitAction {
+    println!("internal error: {}", exit.suberror());
+    match VcpuState::capture(exit.vcpu()) {
+        Ok(state) => println!("{:?}", state),
+        Err(e) => println!("failed to capture vcpu state: {}", e),
+    }
+    trace::dump("the KVM internal error");
+    ExitAction::Handled
+}
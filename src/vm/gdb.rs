@@ -0,0 +1,864 @@
+//! A stub for the GDB remote serial protocol, for debugging the guest
+//! kernel with gdb.
+//!
+//! With `--gdb 127.0.0.1:1234` the stub listens for a connection from gdb:
+//!
+//!     $ gdb vmlinux
+//!     (gdb) target remote 127.0.0.1:1234
+//!
+//! Each vcpu appears to gdb as a thread. When gdb connects every vcpu is
+//! stopped, and the vcpus stay stopped until gdb continues or steps them.
+//! Registers are read and written with `KVM_GET_REGS` and `KVM_SET_REGS`,
+//! and memory is read and written through `GuestRam` after translating the
+//! virtual address with `KVM_TRANSLATE` in the address space of the
+//! selected vcpu. Breakpoints and watchpoints use the four debug registers
+//! of x86, which KVM loads into each vcpu with `KVM_SET_GUEST_DEBUG`, so at
+//! most four can be set at once.
+//!
+//! A stopped vcpu waits in `park_vcpu()` on its own thread, and the debug
+//! registers are loaded when it leaves, so that vcpu ioctls which change how
+//! the vcpu runs are only made on the vcpu thread.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::kvm::{KvmGuestDebug, KvmVcpu, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP};
+use crate::memory::GuestRam;
+use crate::vm::arch::{KvmRegs, VcpuState};
+use crate::vm::exits::{ExitAction, ExitHandlers, VcpuExit, KVM_EXIT_DEBUG};
+use crate::vm::run::VcpuKicker;
+use crate::vm::suspend;
+
+// How often a parked vcpu thread checks the shutdown flag
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+
+// How often the stub checks for a stop while the vcpus run
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const PACKET_SIZE: usize = 0x4000;
+const PAGE_SIZE: u64 = 4096;
+
+// Number of x86 debug registers which hold an address
+const MAX_BREAKPOINTS: usize = 4;
+
+// Bits of DR6 which tell which debug register matched and that a single
+// step completed
+const DR6_MATCH_MASK: u64 = 0xf;
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// General purpose registers, rip, eflags and the six segment registers in
+// the order of the `g` packet for i386:x86-64
+const GDB_REGS_64: usize = 17;
+const GDB_REGS: usize = GDB_REGS_64 + 7;
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+    <target><architecture>i386:x86-64</architecture></target>";
+
+lazy_static! {
+    static ref STATE: (Mutex<DebugState>, Condvar) = (Mutex::new(DebugState::default()), Condvar::new());
+    static ref LISTENING: Mutex<Option<SocketAddr>> = Mutex::new(None);
+}
+
+// Avoids taking the lock on every vcpu exit when no debugger has stopped the VM
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum BreakKind {
+    Execute,
+    Write,
+    Access,
+}
+
+impl BreakKind {
+    // The R/W field of DR7 for this kind
+    fn dr7_rw(self) -> u64 {
+        match self {
+            BreakKind::Execute => 0b00,
+            BreakKind::Write => 0b01,
+            BreakKind::Access => 0b11,
+        }
+    }
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+struct Breakpoint {
+    kind: BreakKind,
+    address: u64,
+    len: u64,
+}
+
+impl Breakpoint {
+    // The enable, R/W and LEN fields of DR7 for debug register `slot`
+    fn dr7_bits(&self, slot: usize) -> u64 {
+        let len = match self.len {
+            2 => 0b01,
+            8 => 0b10,
+            4 => 0b11,
+            _ => 0b00,
+        };
+        let global_enable = 1 << (slot * 2 + 1);
+        global_enable | (self.kind.dr7_rw() << (16 + slot * 4)) | (len << (18 + slot * 4))
+    }
+}
+
+#[derive(Copy,Clone,Debug)]
+enum StopReason {
+    Step,
+    Breakpoint(Breakpoint),
+    Interrupted,
+}
+
+#[derive(Copy,Clone,Debug)]
+struct StopEvent {
+    vcpu: usize,
+    reason: StopReason,
+}
+
+#[derive(Clone)]
+struct Target {
+    vcpus: Vec<KvmVcpu>,
+    ram: GuestRam,
+    kicker: VcpuKicker,
+    shutdown: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct DebugState {
+    // The vcpus are held in `park_vcpu()`
+    stopped: bool,
+    // A vcpu which runs one instruction while the others stay stopped
+    stepping: Option<usize>,
+    parked: usize,
+    // A debugger is connected and the debug registers are loaded
+    attached: bool,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    event: Option<StopEvent>,
+    target: Option<Target>,
+}
+
+impl DebugState {
+    fn set_stopped(&mut self, stopped: bool) {
+        self.stopped = stopped;
+        STOPPED.store(stopped, Ordering::SeqCst);
+    }
+
+    // The flags and debug registers for `KVM_SET_GUEST_DEBUG` on vcpu `id`
+    fn guest_debug(&self, id: usize) -> KvmGuestDebug {
+        let mut debug = KvmGuestDebug::default();
+        if !self.attached {
+            return debug;
+        }
+        debug.control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_HW_BP;
+        if self.stepping == Some(id) {
+            debug.control |= KVM_GUESTDBG_SINGLESTEP;
+        }
+        for (slot, bp) in self.breakpoints.iter().enumerate() {
+            if let Some(bp) = bp {
+                debug.debugreg[slot] = bp.address;
+                debug.debugreg[7] |= bp.dr7_bits(slot);
+            }
+        }
+        debug
+    }
+}
+
+/// Returns `true` if a debugger has stopped the vcpus, in which case
+/// `park_vcpu()` is called before the vcpu runs again.
+pub fn is_stopped() -> bool {
+    STOPPED.load(Ordering::Relaxed)
+}
+
+/// Called on a vcpu thread when `is_stopped()` returns `true`. Blocks until
+/// the debugger resumes or steps this vcpu, or `shutdown` is set, and then
+/// loads the debug registers into the vcpu.
+pub fn park_vcpu(vcpu: &KvmVcpu, shutdown: &AtomicBool) {
+    let id = vcpu.id();
+    let (lock, cvar) = &*STATE;
+    let mut state = lock.lock().unwrap();
+    state.parked += 1;
+    cvar.notify_all();
+    while state.stopped && state.stepping != Some(id) && !shutdown.load(Ordering::Relaxed) {
+        state = cvar.wait_timeout(state, PARK_TIMEOUT).unwrap().0;
+    }
+    state.parked -= 1;
+    let debug = state.guest_debug(id);
+    drop(state);
+    if let Err(e) = vcpu.set_guest_debug(&debug) {
+        warn!("Failed to set debug registers of vcpu {}: {}", id, e);
+    }
+}
+
+/// Handle the exits of vcpus which hit a breakpoint or completed a single
+/// step by stopping the VM and reporting the stop to the debugger.
+pub fn register_exit_handler(handlers: &ExitHandlers) {
+    handlers.register(KVM_EXIT_DEBUG, handle_debug_exit);
+}
+
+fn handle_debug_exit(exit: &VcpuExit) -> ExitAction {
+    // struct kvm_debug_exit_arch: exception, pad, pc, dr6, dr7
+    let dr6 = exit.r64(48);
+    let (lock, cvar) = &*STATE;
+    let mut state = lock.lock().unwrap();
+    if !state.attached {
+        // Left over from a debugger which has gone away
+        return ExitAction::Handled;
+    }
+    let hit = (0..MAX_BREAKPOINTS)
+        .find(|&slot| dr6 & DR6_MATCH_MASK & (1 << slot) != 0)
+        .and_then(|slot| state.breakpoints[slot]);
+    let reason = match hit {
+        Some(bp) => StopReason::Breakpoint(bp),
+        None if dr6 & DR6_SINGLE_STEP != 0 => StopReason::Step,
+        None => StopReason::Interrupted,
+    };
+    state.event = Some(StopEvent { vcpu: exit.vcpu().id(), reason });
+    state.stepping = None;
+    state.set_stopped(true);
+    cvar.notify_all();
+    ExitAction::Handled
+}
+
+/// The vcpus and memory of the VM which is running, which the debugger
+/// operates on. Replaced each time the VM boots.
+pub fn set_target(vcpus: Vec<KvmVcpu>, ram: GuestRam, kicker: VcpuKicker, shutdown: Arc<AtomicBool>) {
+    let (lock, _) = &*STATE;
+    lock.lock().unwrap().target = Some(Target { vcpus, ram, kicker, shutdown });
+}
+
+/// Called when the VM stops running.
+pub fn clear_target() {
+    let (lock, _) = &*STATE;
+    lock.lock().unwrap().target = None;
+}
+
+/// Accept connections from gdb on `address` on a new thread. If `wait` is
+/// set the vcpus are stopped before they run the first instruction and
+/// the guest only starts once gdb continues it. Does nothing if the stub is
+/// already listening on `address`, as it is when the VM is restarted after
+/// a reboot.
+pub fn listen(address: SocketAddr, wait: bool) -> io::Result<()> {
+    let mut listening = LISTENING.lock().unwrap();
+    if *listening == Some(address) {
+        return Ok(());
+    }
+    let listener = TcpListener::bind(address)?;
+    *listening = Some(address);
+    if wait {
+        let (lock, _) = &*STATE;
+        lock.lock().unwrap().set_stopped(true);
+        notify!("Waiting for gdb to connect to {}", address);
+    }
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => serve_debugger(stream),
+                Err(e) => warn!("Error accepting gdb connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve_debugger(stream: TcpStream) {
+    let target = {
+        let (lock, _) = &*STATE;
+        lock.lock().unwrap().target.clone()
+    };
+    let target = match target {
+        Some(target) => target,
+        None => {
+            warn!("Rejected gdb connection, the VM is not running");
+            return;
+        }
+    };
+    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| "?".to_string());
+    notify!("gdb connected from {}", peer);
+    let mut session = Session::new(stream, target);
+    if let Err(e) = session.run() {
+        verbose!("gdb connection closed: {}", e);
+    }
+    session.detach();
+    notify!("gdb disconnected");
+}
+
+// Stop every vcpu and wait until all of them are parked. Returns false if
+// the VM is shut down first.
+fn stop_all(target: &Target) -> bool {
+    // Vcpus parked by an idle suspend do not respond to a kick
+    suspend::wake();
+    let (lock, cvar) = &*STATE;
+    let mut state = lock.lock().unwrap();
+    state.stepping = None;
+    state.set_stopped(true);
+    loop {
+        if target.shutdown.load(Ordering::Relaxed) {
+            return false;
+        }
+        // A signal which arrives while a vcpu thread is outside of KVM_RUN
+        // is lost, so kick again until every thread is parked.
+        let running = target.kicker.kick_all();
+        if state.parked >= running {
+            return true;
+        }
+        state = cvar.wait_timeout(state, Duration::from_millis(10)).unwrap().0;
+    }
+}
+
+// Let the vcpus run, or only vcpu `step` for one instruction
+fn resume(step: Option<usize>) {
+    let (lock, cvar) = &*STATE;
+    let mut state = lock.lock().unwrap();
+    state.event = None;
+    match step {
+        Some(id) => state.stepping = Some(id),
+        None => state.set_stopped(false),
+    }
+    cvar.notify_all();
+}
+
+fn take_stop_event() -> Option<StopEvent> {
+    let (lock, _) = &*STATE;
+    lock.lock().unwrap().event.take()
+}
+
+enum Packet {
+    Command(String),
+    // Ctrl-C from gdb, which asks for the running vcpus to be stopped
+    Interrupt,
+}
+
+enum Reply {
+    Send(String),
+    Resume(Option<usize>),
+    Detach,
+}
+
+struct Session {
+    stream: TcpStream,
+    input: Vec<u8>,
+    target: Target,
+    // Index of the vcpu selected with `Hg`, whose registers and address
+    // space are used
+    vcpu: usize,
+    // Index of the vcpu selected with `Hc`, which is stepped
+    step_vcpu: usize,
+}
+
+impl Session {
+    fn new(stream: TcpStream, target: Target) -> Self {
+        Session { stream, input: Vec::new(), target, vcpu: 0, step_vcpu: 0 }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        self.stream.set_nodelay(true)?;
+        if !stop_all(&self.target) {
+            return Ok(());
+        }
+        {
+            let (lock, _) = &*STATE;
+            let mut state = lock.lock().unwrap();
+            state.attached = true;
+            state.event = None;
+        }
+        loop {
+            let packet = match self.read_packet()? {
+                Some(Packet::Command(packet)) => packet,
+                Some(Packet::Interrupt) => continue,
+                None if self.target.shutdown.load(Ordering::Relaxed) => {
+                    return self.send_packet("W00");
+                }
+                None => continue,
+            };
+            match self.handle(&packet) {
+                Reply::Send(reply) => self.send_packet(&reply)?,
+                Reply::Resume(step) => {
+                    resume(step);
+                    let reply = self.wait_for_stop()?;
+                    self.send_packet(&reply)?;
+                }
+                Reply::Detach => {
+                    self.send_packet("OK")?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Remove the breakpoints and let the vcpus run on without a debugger
+    fn detach(&mut self) {
+        let (lock, cvar) = &*STATE;
+        let mut state = lock.lock().unwrap();
+        state.attached = false;
+        state.breakpoints = [None; MAX_BREAKPOINTS];
+        state.event = None;
+        state.stepping = None;
+        state.set_stopped(false);
+        cvar.notify_all();
+    }
+
+    // Wait until a vcpu stops or gdb interrupts the VM, and return the stop reply
+    fn wait_for_stop(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(event) = take_stop_event() {
+                if !stop_all(&self.target) {
+                    return Ok("W00".to_string());
+                }
+                self.vcpu = event.vcpu;
+                self.step_vcpu = event.vcpu;
+                return Ok(Self::stop_reply(event));
+            }
+            if self.target.shutdown.load(Ordering::Relaxed) {
+                return Ok("W00".to_string());
+            }
+            if let Some(Packet::Interrupt) = self.read_packet()? {
+                if !stop_all(&self.target) {
+                    return Ok("W00".to_string());
+                }
+                return Ok(Self::stop_reply(StopEvent { vcpu: self.vcpu, reason: StopReason::Interrupted }));
+            }
+        }
+    }
+
+    fn stop_reply(event: StopEvent) -> String {
+        let thread = event.vcpu + 1;
+        match event.reason {
+            StopReason::Interrupted => format!("T{:02x}thread:{:x};", SIGINT, thread),
+            StopReason::Step => format!("T{:02x}thread:{:x};", SIGTRAP, thread),
+            StopReason::Breakpoint(bp) => {
+                let what = match bp.kind {
+                    BreakKind::Execute => "hwbreak:".to_string(),
+                    BreakKind::Write => format!("watch:{:x}", bp.address),
+                    BreakKind::Access => format!("awatch:{:x}", bp.address),
+                };
+                format!("T{:02x}thread:{:x};{};", SIGTRAP, thread, what)
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str) -> Reply {
+        let (cmd, args) = packet.split_at(packet.chars().next().map(|c| c.len_utf8()).unwrap_or(0));
+        let reply = match cmd {
+            // The vcpus were stopped when gdb connected, which is reported as a trap
+            "?" => Self::stop_reply(StopEvent { vcpu: self.vcpu, reason: StopReason::Step }),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "H" => self.select_thread(args),
+            "T" => match Self::parse_thread(args) {
+                Some(Some(id)) if id < self.target.vcpus.len() => "OK".to_string(),
+                _ => "E01".to_string(),
+            },
+            "Z" => self.set_breakpoint(args, true),
+            "z" => self.set_breakpoint(args, false),
+            "c" => return Reply::Resume(None),
+            "s" => return Reply::Resume(Some(self.step_vcpu)),
+            "v" => return self.handle_v(args),
+            "q" => self.handle_query(args),
+            "D" => return Reply::Detach,
+            "k" => return Reply::Detach,
+            _ => String::new(),
+        };
+        Reply::Send(reply)
+    }
+
+    fn handle_v(&mut self, args: &str) -> Reply {
+        if args == "Cont?" {
+            return Reply::Send("vCont;c;C;s;S".to_string());
+        }
+        if !args.starts_with("Cont;") {
+            return Reply::Send(String::new());
+        }
+        // Signals are not delivered to the guest, so `C` and `S` act as `c` and `s`
+        for action in args["Cont;".len()..].split(';') {
+            let mut parts = action.splitn(2, ':');
+            let op = parts.next().unwrap_or("");
+            let thread = parts.next().and_then(Self::parse_thread).and_then(|t| t);
+            if op.starts_with('s') || op.starts_with('S') {
+                return Reply::Resume(Some(thread.unwrap_or(self.step_vcpu)));
+            }
+        }
+        Reply::Resume(None)
+    }
+
+    fn handle_query(&mut self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+;hwbreak+", PACKET_SIZE);
+        }
+        if args.starts_with("Xfer:features:read:target.xml:") {
+            return Self::read_target_xml(&args["Xfer:features:read:target.xml:".len()..]);
+        }
+        match args {
+            "Attached" => "1".to_string(),
+            "C" => format!("QC{:x}", self.vcpu + 1),
+            "fThreadInfo" => {
+                let threads: Vec<String> = (1..=self.target.vcpus.len()).map(|t| format!("{:x}", t)).collect();
+                format!("m{}", threads.join(","))
+            }
+            "sThreadInfo" => "l".to_string(),
+            "Symbol::" => "OK".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // `OFFSET,LENGTH` of the target description
+    fn read_target_xml(args: &str) -> String {
+        let (offset, len) = match Self::parse_pair(args) {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+        let xml = TARGET_XML.as_bytes();
+        let start = (offset as usize).min(xml.len());
+        let end = start.saturating_add(len as usize).min(xml.len());
+        let prefix = if end == xml.len() { "l" } else { "m" };
+        format!("{}{}", prefix, String::from_utf8_lossy(&xml[start..end]))
+    }
+
+    // `g0`, `c-1` and the like. Thread 0 is any thread and -1 is all threads.
+    fn select_thread(&mut self, args: &str) -> String {
+        if args.is_empty() {
+            return "E01".to_string();
+        }
+        let (op, thread) = args.split_at(1);
+        let id = match Self::parse_thread(thread) {
+            Some(Some(id)) if id < self.target.vcpus.len() => id,
+            Some(None) => 0,
+            _ => return "E01".to_string(),
+        };
+        match op {
+            "g" => self.vcpu = id,
+            "c" => self.step_vcpu = id,
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    // The vcpu index of a thread id, or `Some(None)` for any or all threads
+    fn parse_thread(s: &str) -> Option<Option<usize>> {
+        match s {
+            "0" | "-1" => Some(None),
+            s => match usize::from_str_radix(s, 16) {
+                Ok(t) if t > 0 => Some(Some(t - 1)),
+                _ => None,
+            },
+        }
+    }
+
+    fn parse_pair(s: &str) -> Option<(u64, u64)> {
+        let mut parts = s.splitn(2, ',');
+        let a = u64::from_str_radix(parts.next()?, 16).ok()?;
+        let b = u64::from_str_radix(parts.next()?, 16).ok()?;
+        Some((a, b))
+    }
+
+    fn selected_vcpu(&self) -> &KvmVcpu {
+        &self.target.vcpus[self.vcpu]
+    }
+
+    // Register `n` in gdb order, other than the segment registers
+    fn register_slot(regs: &mut KvmRegs, n: usize) -> Option<&mut u64> {
+        Some(match n {
+            0 => &mut regs.rax,
+            1 => &mut regs.rbx,
+            2 => &mut regs.rcx,
+            3 => &mut regs.rdx,
+            4 => &mut regs.rsi,
+            5 => &mut regs.rdi,
+            6 => &mut regs.rbp,
+            7 => &mut regs.rsp,
+            8 => &mut regs.r8,
+            9 => &mut regs.r9,
+            10 => &mut regs.r10,
+            11 => &mut regs.r11,
+            12 => &mut regs.r12,
+            13 => &mut regs.r13,
+            14 => &mut regs.r14,
+            15 => &mut regs.r15,
+            16 => &mut regs.rip,
+            17 => &mut regs.rflags,
+            _ => return None,
+        })
+    }
+
+    // The value of each register of the `g` packet
+    fn register_values(&self) -> Option<Vec<u64>> {
+        let state = VcpuState::capture(self.selected_vcpu()).ok()?;
+        let mut regs = *state.regs();
+        let mut values: Vec<u64> = (0..=17).map(|n| *Self::register_slot(&mut regs, n).unwrap()).collect();
+        let sregs = state.sregs();
+        for seg in &[&sregs.cs, &sregs.ss, &sregs.ds, &sregs.es, &sregs.fs, &sregs.gs] {
+            values.push(seg.selector() as u64);
+        }
+        Some(values)
+    }
+
+    fn register_hex(n: usize, value: u64) -> String {
+        let bytes = if n < GDB_REGS_64 { 8 } else { 4 };
+        hex_encode(&value.to_le_bytes()[..bytes])
+    }
+
+    fn read_registers(&self) -> String {
+        match self.register_values() {
+            Some(values) => values.iter().enumerate().map(|(n, &v)| Self::register_hex(n, v)).collect(),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let n = match usize::from_str_radix(args, 16) {
+            Ok(n) if n < GDB_REGS => n,
+            _ => return "E01".to_string(),
+        };
+        match self.register_values() {
+            Some(values) => Self::register_hex(n, values[n]),
+            None => "E01".to_string(),
+        }
+    }
+
+    // Changes to the segment registers are ignored
+    fn write_registers(&self, args: &str) -> String {
+        let bytes = match hex_decode(args) {
+            Some(bytes) => bytes,
+            None => return "E01".to_string(),
+        };
+        let vcpu = self.selected_vcpu();
+        let mut regs = match vcpu.get_regs() {
+            Ok(regs) => regs,
+            Err(_) => return "E01".to_string(),
+        };
+        for (n, chunk) in bytes.chunks(8).take(GDB_REGS_64).enumerate() {
+            if chunk.len() == 8 {
+                *Self::register_slot(&mut regs, n).unwrap() = le_u64(chunk);
+            }
+        }
+        // eflags is 4 bytes wide and follows rip
+        let eflags = GDB_REGS_64 * 8;
+        if let Some(chunk) = bytes.get(eflags..eflags + 4) {
+            regs.rflags = le_u64(chunk);
+        }
+        match vcpu.set_regs(&regs) {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    // `N=VALUE`
+    fn write_register(&self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let n = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+        let value = parts.next().and_then(hex_decode);
+        let (n, value) = match (n, value) {
+            (Some(n), Some(value)) if n < GDB_REGS && !value.is_empty() => (n, le_u64(&value)),
+            _ => return "E01".to_string(),
+        };
+        if n > GDB_REGS_64 {
+            // Segment registers are left as they are
+            return "OK".to_string();
+        }
+        let vcpu = self.selected_vcpu();
+        let result = vcpu.get_regs().and_then(|mut regs| {
+            *Self::register_slot(&mut regs, n).unwrap() = value;
+            vcpu.set_regs(&regs)
+        });
+        match result {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    // Call `f` with the guest physical address and length of each piece of
+    // the virtual address range which lies within one page
+    fn for_each_page<F>(&self, address: u64, len: u64, mut f: F) -> bool
+        where F: FnMut(u64, usize, usize) -> bool
+    {
+        let mut done = 0;
+        while done < len {
+            let va = address.wrapping_add(done);
+            let chunk = (PAGE_SIZE - (va % PAGE_SIZE)).min(len - done);
+            let pa = match self.selected_vcpu().translate(va) {
+                Ok(Some(pa)) => pa,
+                _ => return false,
+            };
+            if !f(pa, done as usize, chunk as usize) {
+                return false;
+            }
+            done += chunk;
+        }
+        true
+    }
+
+    // `ADDRESS,LENGTH`
+    fn read_memory(&self, args: &str) -> String {
+        let (address, len) = match Self::parse_pair(args) {
+            Some((address, len)) => (address, len.min(PACKET_SIZE as u64 / 2)),
+            None => return "E01".to_string(),
+        };
+        let mut buf = vec![0u8; len as usize];
+        let ram = &self.target.ram;
+        let ok = self.for_each_page(address, len, |pa, offset, n| {
+            ram.read_bytes(pa, &mut buf[offset..offset + n]).is_ok()
+        });
+        if !ok {
+            return "E14".to_string();
+        }
+        hex_encode(&buf)
+    }
+
+    // `ADDRESS,LENGTH:DATA`
+    fn write_memory(&self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let range = parts.next().and_then(Self::parse_pair);
+        let data = parts.next().and_then(hex_decode);
+        let (address, data) = match (range, data) {
+            (Some((address, len)), Some(data)) if data.len() as u64 == len => (address, data),
+            _ => return "E01".to_string(),
+        };
+        let ram = &self.target.ram;
+        let ok = self.for_each_page(address, data.len() as u64, |pa, offset, n| {
+            ram.write_bytes(pa, &data[offset..offset + n]).is_ok()
+        });
+        if !ok {
+            return "E14".to_string();
+        }
+        "OK".to_string()
+    }
+
+    // `TYPE,ADDRESS,KIND`. Software breakpoints also use a debug register,
+    // and read watchpoints are not supported by x86.
+    fn set_breakpoint(&self, args: &str, insert: bool) -> String {
+        let mut parts = args.splitn(3, ',');
+        let kind = match parts.next() {
+            Some("0") | Some("1") => BreakKind::Execute,
+            Some("2") => BreakKind::Write,
+            Some("4") => BreakKind::Access,
+            _ => return String::new(),
+        };
+        let address = parts.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+        let len = parts.next().and_then(|l| u64::from_str_radix(l, 16).ok());
+        let (address, len) = match (address, len) {
+            (Some(address), Some(len)) => (address, len),
+            _ => return "E01".to_string(),
+        };
+        let len = if kind == BreakKind::Execute { 1 } else { len };
+        if kind != BreakKind::Execute && (!(len == 1 || len == 2 || len == 4 || len == 8) || address % len != 0) {
+            return "E22".to_string();
+        }
+        let bp = Breakpoint { kind, address, len };
+        let (lock, _) = &*STATE;
+        let mut state = lock.lock().unwrap();
+        let slots = &mut state.breakpoints;
+        if insert {
+            if slots.contains(&Some(bp)) {
+                return "OK".to_string();
+            }
+            match slots.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => *slot = Some(bp),
+                // Out of debug registers
+                None => return "E28".to_string(),
+            }
+        } else if let Some(slot) = slots.iter_mut().find(|slot| **slot == Some(bp)) {
+            *slot = None;
+        }
+        "OK".to_string()
+    }
+
+    // Read the next packet, acknowledging it, or `None` if nothing arrives
+    // within `POLL_INTERVAL`
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            if let Some(packet) = self.parse_input()? {
+                return Ok(Some(packet));
+            }
+            let mut buf = [0u8; 4096];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "gdb closed the connection")),
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn parse_input(&mut self) -> io::Result<Option<Packet>> {
+        while let Some(&b) = self.input.first() {
+            match b {
+                0x03 => {
+                    self.input.remove(0);
+                    return Ok(Some(Packet::Interrupt));
+                }
+                b'$' => break,
+                // Acknowledgements of our packets and stray bytes
+                _ => { self.input.remove(0); }
+            }
+        }
+        let end = match self.input.iter().position(|&b| b == b'#') {
+            Some(end) if self.input.len() >= end + 3 => end,
+            _ => return Ok(None),
+        };
+        let body = self.input[1..end].to_vec();
+        let checksum = std::str::from_utf8(&self.input[end + 1..end + 3]).ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        self.input.drain(..end + 3);
+        if checksum != Some(checksum_of(&body)) {
+            self.stream.write_all(b"-")?;
+            return Ok(None);
+        }
+        self.stream.write_all(b"+")?;
+        Ok(Some(Packet::Command(String::from_utf8_lossy(&unescape(&body)).into_owned())))
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+// `}` escapes the following byte, which is XORed with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(&b) = iter.next() {
+        if b == b'}' {
+            if let Some(&next) = iter.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Little endian value of up to 8 bytes
+fn le_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().take(8).rev().fold(0, |v, &b| (v << 8) | b as u64)
+}
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::vm::{console, ControlServer};
+use crate::vm::run::VcpuKicker;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const POLLRDHUP: libc::c_short = 0x2000;
+
+///
+/// Shuts down the VM once nobody has used it for a while.
+///
+/// The VM is idle when no client is connected to the control socket and
+/// no client is attached to the console. A console on an interactive
+/// terminal is always considered attached, and a console socket passed by
+/// systemd is attached until the client hangs up.
+///
+pub struct IdleMonitor {
+    timeout: Duration,
+    control: Option<ControlServer>,
+    console_socket: bool,
+    console_active: Instant,
+}
+
+impl IdleMonitor {
+    pub fn new(timeout: Duration, control: Option<ControlServer>, console_socket: bool) -> Self {
+        IdleMonitor {
+            timeout,
+            control,
+            console_socket,
+            console_active: Instant::now(),
+        }
+    }
+
+    pub fn start(mut self, shutdown: Arc<AtomicBool>, kicker: VcpuKicker) {
+        thread::spawn(move || self.run(&shutdown, &kicker));
+    }
+
+    fn run(&mut self, shutdown: &AtomicBool, kicker: &VcpuKicker) {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            if shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+            if self.is_idle() {
+                notify!("VM idle for {} seconds, shutting down", self.timeout.as_secs());
+                shutdown.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+        // A signal which arrives while a vcpu thread is outside of KVM_RUN
+        // is lost, so keep kicking until every vcpu thread has exited.
+        while kicker.kick_all() > 0 {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn is_idle(&mut self) -> bool {
+        if self.console_attached() {
+            self.console_active = Instant::now();
+            return false;
+        }
+        let control_idle = self.control.as_ref()
+            .map(|c| c.is_idle(self.timeout))
+            .unwrap_or(true);
+        control_idle && self.console_active.elapsed() >= self.timeout
+    }
+
+    fn console_attached(&self) -> bool {
+        if !console::is_stdio() {
+            return console::is_client_attached();
+        }
+        if !self.console_socket {
+            return unsafe { libc::isatty(0) } == 1;
+        }
+        let mut pollfd = libc::pollfd {
+            fd: 0,
+            events: POLLRDHUP,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pollfd, 1, 0) } < 0 {
+            return true;
+        }
+        pollfd.revents & (POLLRDHUP | libc::POLLHUP | libc::POLLERR) == 0
+    }
+}
@@ -1,5 +1,8 @@
 use std::sync::{Arc,RwLock,RwLockWriteGuard};
 use crate::memory::AddressRange;
+use crate::vm::replay;
+use crate::util::JsonValue;
+use crate::util::trace::{self, TraceSubsystem};
 
 pub trait IoPortOps: Send+Sync {
     fn io_in(&mut self, port: u16, size: usize) -> u32 {
@@ -32,7 +35,9 @@ impl IoPortOps for IoPortPS2Control {
     fn io_in(&mut self, _port: u16, _size: usize) -> u32 { 0x02 }
 }
 
-struct IoPortFakeI8042(bool);
+// Writes of the reset command to port 0x64 are handled as vcpu exits
+// before they reach the dispatcher.
+struct IoPortFakeI8042;
 
 impl IoPortOps for IoPortFakeI8042 {
     fn io_in(&mut self, port: u16, _size: usize) -> u32 {
@@ -44,12 +49,6 @@ impl IoPortOps for IoPortFakeI8042 {
             0
         }
     }
-    fn io_out(&mut self, port: u16, _size: usize, val: u32) {
-        if port == 0x64 && val == 0xfe && !self.0 {
-            self.0 = true;
-            println!("Reset signal!");
-        }
-    }
 }
 
 struct IoPortEntry {
@@ -125,23 +124,55 @@ impl IoDispatcher {
         self.state_mut().register_mmio(range, device);
     }
 
-    pub fn emulate_io_in(&self, port: u16, size: usize) -> u32 {
-        self.state_mut().emulate_io_in(port, size)
+    /// Ignore accesses to the ports of the DMA controllers, the PICs, the
+    /// PIT, the parallel ports and the display controller which a PC kernel
+    /// probes. The PICs and the PIT themselves are emulated by KVM.
+    pub fn register_legacy_ports(&self) {
+        self.state_mut().setup_legacy_ioports()
+    }
+
+    /// Remove the device registered for the ports starting at `port`.
+    pub fn unregister_ioports(&self, port: u16) {
+        self.state_mut().ioport_entries.retain(|e| e.port != port);
+    }
 
+    /// Remove the device registered for `range`.
+    pub fn unregister_mmio(&self, range: AddressRange) {
+        self.state_mut().mmio_entries.retain(|e| e.range != range);
+    }
+
+    pub fn emulate_io_in(&self, port: u16, size: usize) -> u32 {
+        let val = self.state_mut().emulate_io_in(port, size);
+        let val = replay::io_in(port, val);
+        trace_access(TraceSubsystem::Pio, port as u64, size, false, val as u64);
+        val
     }
     pub fn emulate_io_out(&self, port: u16, size: usize, val: u32) {
+        trace_access(TraceSubsystem::Pio, port as u64, size, true, val as u64);
         self.state_mut().emulate_io_out(port, size, val)
     }
 
     pub fn emulate_mmio_read(&self, address: u64, size: usize) -> u64 {
-        self.state_mut().emulate_mmio_read(address, size)
+        let val = self.state_mut().emulate_mmio_read(address, size);
+        let val = replay::mmio_read(address, val);
+        trace_access(TraceSubsystem::Mmio, address, size, false, val);
+        val
     }
 
     pub fn emulate_mmio_write(&self, address: u64, size: usize, val: u64) {
+        trace_access(TraceSubsystem::Mmio, address, size, true, val);
         self.state_mut().emulate_mmio_write(address, size, val)
     }
 }
 
+fn trace_access(subsystem: TraceSubsystem, address: u64, size: usize, write: bool, val: u64) {
+    trace::event(subsystem, || JsonValue::object()
+        .with("address", format!("{:#x}", address))
+        .with("size", size)
+        .with("write", write)
+        .with("value", format!("{:#x}", val)));
+}
+
 struct IoDispatcherState {
     last_unhandled_port: u16,
     ioport_entries: Vec<IoPortEntry>,
@@ -227,17 +258,21 @@ impl IoDispatcherState {
         self.register_ioports(port, count, Arc::new(RwLock::new(IoPortDummy)));
     }
 
+    // The ports through which the guest resets, which every platform has
     fn setup_ioports(&mut self) {
+        /* 0060 - 0068 - i8042 */
+        self.register_ioports(0x0060, 8, Arc::new(RwLock::new(IoPortFakeI8042)));
+        /* 0092 - PS/2 system control port A */
+        self.register_ioports(0x0092, 1, Arc::new(RwLock::new(IoPortPS2Control)));
+    }
+
+    fn setup_legacy_ioports(&mut self) {
         /* 0000 - 001F - DMA1 controller */
         self.register_dummy(0x0000, 32);
         /* 0020 - 003F - 8259A PIC 1 */
         self.register_dummy(0x0020, 2);
-        /* 0060 - 0068 - i8042 */
-        self.register_ioports(0x0060, 8, Arc::new(RwLock::new(IoPortFakeI8042(false))));
         /* 0040 - 005F - PIT (8253,8254) */
         self.register_dummy(0x0040, 4);
-        /* 0092 - PS/2 system control port A */
-        self.register_ioports(0x0092, 1, Arc::new(RwLock::new(IoPortPS2Control)));
         /* 00A0 - 00AF - 8259A PIC 1 */
         self.register_dummy(0x00A0, 2);
         /* 00C0 - 00CF - DMA1 controller */
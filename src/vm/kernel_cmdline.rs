@@ -1,12 +1,12 @@
 use std::ffi::OsString;
 use std::os::unix::ffi::OsStrExt;
 
+use crate::vm::phinit_vars;
+
 
 
 fn add_defaults(cmdline: &mut KernelCmdLine) {
     cmdline
-        .push("noapic")
-        .push("noacpi")
         // keyboard reboot
         .push("reboot=k")
         .push_set_true("panic")
@@ -62,6 +62,20 @@ impl KernelCmdLine {
         (&self.buffer).as_bytes().len() + 1
     }
 
+    /// Warn about each `phinit.*` variable which ph-init does not know or
+    /// which is missing the value it needs, since ph-init ignores them.
+    pub fn check_phinit_vars(&self) {
+        for word in self.buffer.to_string_lossy().split_whitespace() {
+            let (name, has_value) = match word.find('=') {
+                Some(eq) => (&word[..eq], true),
+                None => (word, false),
+            };
+            if let Some(problem) = phinit_vars::check(name, has_value) {
+                warn!("Kernel command line: {}", problem);
+            }
+        }
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         self.buffer.as_bytes()
     }
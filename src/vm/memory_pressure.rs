@@ -0,0 +1,376 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{io, process, thread};
+
+use crate::devices::BalloonHandle;
+use crate::util::JsonValue;
+
+const PSI_MEMORY: &str = "/proc/pressure/memory";
+
+const GROUP_DIR: &str = "/run/ph";
+const GROUP_FILE: &str = "/run/ph/balloon-policy";
+
+// The header holds the reclaim level and when it was last changed
+const HEADER_SIZE: usize = 16;
+// Number of pH instances which can take part
+const GROUP_SLOTS: usize = 64;
+// Each slot holds a pid, a weight and the guest RAM size in megabytes
+const SLOT_SIZE: usize = 16;
+
+// How often each instance looks at the pressure and its balloon target
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How often the shared reclaim level may change, whichever instance does it
+const ADJUST_INTERVAL_MS: u64 = 2000;
+
+// Reclaim more while some task stalled on memory for this percentage of the
+// last 10 seconds, and give memory back once stalls are below the low mark.
+const PRESSURE_HIGH: f64 = 10.0;
+const PRESSURE_LOW: f64 = 1.0;
+
+// The reclaim level is in thousandths of the guest RAM of all instances
+const LEVEL_STEP_UP: u32 = 25;
+const LEVEL_STEP_DOWN: u32 = 10;
+const LEVEL_MAX: u32 = 500;
+
+// No guest is asked to give up more than this many thousandths of its RAM
+const MAX_RECLAIM: u64 = 750;
+
+///
+/// Inflates and deflates the balloon of a VM as memory pressure on the host
+/// rises and falls, so that the desktop stays responsive when the running
+/// VMs together have more RAM than the host.
+///
+/// Every pH instance started with a balloon weight takes a slot in the table
+/// at `/run/ph/balloon-policy` holding its weight and guest RAM size. Only
+/// instances running as the user who owns the table can open it. The
+/// table also holds a shared reclaim level, the part of the guest RAM of all
+/// instances which should be in balloons. Each second the instances check
+/// `some avg10` in `/proc/pressure/memory`, and at most every two seconds
+/// one of them raises the level if it is above 10% or lowers it if it is
+/// below 1%. The RAM to reclaim is divided between the instances in
+/// proportion to their RAM divided by their weight, so a VM with twice the
+/// weight gives up half as much of its memory.
+///
+#[derive(Clone)]
+pub struct PressurePolicy {
+    state: Arc<Mutex<PolicyState>>,
+    table: Arc<Mutex<Option<PolicyTable>>>,
+}
+
+struct PolicyState {
+    weight: u32,
+    ram_size: u64,
+    pressure: f64,
+    level: u32,
+    members: usize,
+    target: u64,
+}
+
+impl PressurePolicy {
+    /// Start adjusting the target of `balloon` for a VM with `ram_size`
+    /// bytes of RAM. Returns `None` if the host does not report memory
+    /// pressure or the table cannot be opened.
+    pub fn start(balloon: BalloonHandle, weight: u32, ram_size: usize) -> Option<PressurePolicy> {
+        if !Path::new(PSI_MEMORY).exists() {
+            warn!("Cannot adjust the balloon to memory pressure because {} does not exist", PSI_MEMORY);
+            return None;
+        }
+        let weight = weight.max(1);
+        let ram_size = ram_size as u64;
+        let table = match PolicyTable::open(weight, ram_size) {
+            Ok(table) => table,
+            Err(e) => {
+                warn!("Cannot open {}, the balloon will not be adjusted to memory pressure: {}", GROUP_FILE, e);
+                return None;
+            }
+        };
+        let state = Arc::new(Mutex::new(PolicyState {
+            weight, ram_size, pressure: 0.0, level: 0, members: 1, target: 0,
+        }));
+        let table = Arc::new(Mutex::new(Some(table)));
+        let policy = PressurePolicy { state, table };
+        let p = policy.clone();
+        thread::spawn(move || p.run(balloon));
+        Some(policy)
+    }
+
+    /// Give up the slot of this instance in the table when the VM exits,
+    /// and remove the table if no other instance is using it.
+    pub fn leave(&self) {
+        if let Some(table) = self.table.lock().unwrap().take() {
+            if let Err(e) = table.leave() {
+                warn!("Failed to leave {}: {}", GROUP_FILE, e);
+            }
+        }
+    }
+
+    fn run(&self, balloon: BalloonHandle) {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let pressure = match read_pressure() {
+                Ok(pressure) => pressure,
+                Err(e) => {
+                    warn!("Failed to read {}: {}", PSI_MEMORY, e);
+                    continue;
+                }
+            };
+            let update = match self.table.lock().unwrap().as_ref() {
+                Some(table) => table.update(pressure),
+                None => return,
+            };
+            let (level, target, members) = match update {
+                Ok(update) => update,
+                Err(e) => {
+                    warn!("Failed to update {}: {}", GROUP_FILE, e);
+                    continue;
+                }
+            };
+            let mut state = self.state.lock().unwrap();
+            state.pressure = pressure;
+            state.level = level;
+            state.members = members;
+            if target != state.target {
+                state.target = target;
+                balloon.set_target(target);
+            }
+        }
+    }
+
+    pub fn describe(&self) -> JsonValue {
+        let state = self.state.lock().unwrap();
+        JsonValue::object()
+            .with("weight", state.weight as u64)
+            .with("ram-bytes", state.ram_size)
+            .with("pressure-avg10", format!("{:.2}", state.pressure))
+            .with("reclaim-per-mille", state.level)
+            .with("instances", state.members)
+            .with("target-bytes", state.target)
+    }
+}
+
+// The `avg10` value of the `some` line, which looks like:
+//
+//     some avg10=0.00 avg60=0.00 avg300=0.00 total=0
+//
+fn read_pressure() -> io::Result<f64> {
+    let psi = fs::read_to_string(PSI_MEMORY)?;
+    psi.lines()
+        .filter(|line| line.starts_with("some "))
+        .flat_map(|line| line.split_whitespace())
+        .find(|field| field.starts_with("avg10="))
+        .and_then(|field| field["avg10=".len()..].parse::<f64>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no avg10 value"))
+}
+
+struct PolicyTable {
+    file: File,
+    slot: usize,
+}
+
+impl PolicyTable {
+    fn open(weight: u32, ram_size: u64) -> io::Result<PolicyTable> {
+        if !Path::new(GROUP_DIR).exists() {
+            fs::create_dir_all(GROUP_DIR)?;
+        }
+        loop {
+            let file = Self::open_file()?;
+            // Another instance removed the table before the slot was claimed
+            if let Some(slot) = Self::claim_slot(&file, weight, ram_size)? {
+                return Ok(PolicyTable { file, slot });
+            }
+        }
+    }
+
+    // Open the table, which must belong to the user running this instance
+    // since whoever can write it sets the balloon target of every instance.
+    fn open_file() -> io::Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o600)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NOFOLLOW)
+            .open(GROUP_FILE)?;
+        let meta = file.metadata()?;
+        let uid = unsafe { libc::getuid() };
+        if !meta.is_file() || meta.uid() != uid {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("owned by uid {} rather than {}", meta.uid(), uid)));
+        }
+        if meta.mode() & 0o077 != 0 {
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(file)
+    }
+
+    // True if `file` is still the table at GROUP_FILE and was not removed
+    // by an instance leaving it.
+    fn is_linked(file: &File) -> io::Result<bool> {
+        let meta = file.metadata()?;
+        match fs::symlink_metadata(GROUP_FILE) {
+            Ok(path) => Ok(path.dev() == meta.dev() && path.ino() == meta.ino()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn table_size() -> u64 {
+        (HEADER_SIZE + GROUP_SLOTS * SLOT_SIZE) as u64
+    }
+
+    fn claim_slot(file: &File, weight: u32, ram_size: u64) -> io::Result<Option<usize>> {
+        let _lock = FileLock::lock(file)?;
+        if !Self::is_linked(file)? {
+            return Ok(None);
+        }
+        if file.metadata()?.len() < Self::table_size() {
+            file.set_len(Self::table_size())?;
+        }
+        for slot in 0..GROUP_SLOTS {
+            let (pid, _, _) = Self::read_slot(file, slot)?;
+            if pid == 0 || !Self::is_running(pid) {
+                Self::write_slot(file, slot, process::id(), weight, ram_size >> 20)?;
+                return Ok(Some(slot));
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::Other, "every slot is in use"))
+    }
+
+    fn is_running(pid: u32) -> bool {
+        if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+            return true;
+        }
+        io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    fn now_ms() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    fn read_header(&self) -> io::Result<(u32, u64)> {
+        let mut buf = [0u8; HEADER_SIZE];
+        self.file.read_exact_at(&mut buf, 0)?;
+        let mut level = [0u8; 4];
+        let mut changed = [0u8; 8];
+        level.copy_from_slice(&buf[..4]);
+        changed.copy_from_slice(&buf[8..]);
+        Ok((u32::from_le_bytes(level), u64::from_le_bytes(changed)))
+    }
+
+    fn write_header(&self, level: u32, changed: u64) -> io::Result<()> {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[..4].copy_from_slice(&level.to_le_bytes());
+        buf[8..].copy_from_slice(&changed.to_le_bytes());
+        self.file.write_all_at(&buf, 0)
+    }
+
+    fn read_slot(file: &File, slot: usize) -> io::Result<(u32, u32, u64)> {
+        let mut buf = [0u8; SLOT_SIZE];
+        file.read_exact_at(&mut buf, (HEADER_SIZE + slot * SLOT_SIZE) as u64)?;
+        let mut pid = [0u8; 4];
+        let mut weight = [0u8; 4];
+        let mut megs = [0u8; 8];
+        pid.copy_from_slice(&buf[..4]);
+        weight.copy_from_slice(&buf[4..8]);
+        megs.copy_from_slice(&buf[8..]);
+        Ok((u32::from_le_bytes(pid), u32::from_le_bytes(weight), u64::from_le_bytes(megs)))
+    }
+
+    fn write_slot(file: &File, slot: usize, pid: u32, weight: u32, ram_megs: u64) -> io::Result<()> {
+        let mut buf = [0u8; SLOT_SIZE];
+        buf[..4].copy_from_slice(&pid.to_le_bytes());
+        buf[4..8].copy_from_slice(&weight.to_le_bytes());
+        buf[8..].copy_from_slice(&ram_megs.to_le_bytes());
+        file.write_all_at(&buf, (HEADER_SIZE + slot * SLOT_SIZE) as u64)
+    }
+
+    // Adjust the shared reclaim level to `pressure` if it has not been
+    // changed recently and return the level, the balloon target of this
+    // instance and the number of instances.
+    fn update(&self, pressure: f64) -> io::Result<(u32, u64, usize)> {
+        let _lock = FileLock::lock(&self.file)?;
+        let now = Self::now_ms();
+        let (mut level, changed) = self.read_header()?;
+        if now.saturating_sub(changed) >= ADJUST_INTERVAL_MS {
+            let new_level = if pressure > PRESSURE_HIGH {
+                (level + LEVEL_STEP_UP).min(LEVEL_MAX)
+            } else if pressure < PRESSURE_LOW {
+                level.saturating_sub(LEVEL_STEP_DOWN)
+            } else {
+                level
+            };
+            if new_level != level {
+                level = new_level;
+                self.write_header(level, now)?;
+            }
+        }
+
+        let mut total_megs = 0u64;
+        let mut total_share = 0f64;
+        let mut own = (1, 0);
+        let mut members = 0;
+        for slot in 0..GROUP_SLOTS {
+            let (pid, weight, megs) = Self::read_slot(&self.file, slot)?;
+            if pid == 0 || !Self::is_running(pid) {
+                continue;
+            }
+            if slot == self.slot {
+                own = (weight.max(1), megs);
+            }
+            members += 1;
+            total_megs += megs;
+            total_share += megs as f64 / weight.max(1) as f64;
+        }
+        if level == 0 || total_share == 0.0 {
+            return Ok((level, 0, members.max(1)));
+        }
+        let (weight, megs) = own;
+        let reclaim_megs = total_megs as f64 * level as f64 / 1000.0;
+        let share = (megs as f64 / weight as f64) / total_share;
+        let target_megs = (reclaim_megs * share) as u64;
+        let target_megs = target_megs.min(megs * MAX_RECLAIM / 1000);
+        Ok((level, target_megs << 20, members.max(1)))
+    }
+
+    // Clear the slot of this instance and remove the table if it was the
+    // last one running. Instances which open the table later create a new one.
+    fn leave(self) -> io::Result<()> {
+        let _lock = FileLock::lock(&self.file)?;
+        Self::write_slot(&self.file, self.slot, 0, 0, 0)?;
+        for slot in 0..GROUP_SLOTS {
+            let (pid, _, _) = Self::read_slot(&self.file, slot)?;
+            if pid != 0 && Self::is_running(pid) {
+                return Ok(());
+            }
+        }
+        if Self::is_linked(&self.file)? {
+            fs::remove_file(GROUP_FILE)?;
+        }
+        Ok(())
+    }
+}
+
+struct FileLock<'a> {
+    file: &'a File,
+}
+
+impl <'a> FileLock<'a> {
+    fn lock(file: &'a File) -> io::Result<Self> {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FileLock { file })
+    }
+}
+
+impl <'a> Drop for FileLock<'a> {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN); }
+    }
+}
@@ -0,0 +1,264 @@
+//! Counters for diagnosing the performance of a long running VM.
+//!
+//! Devices and vcpu threads register counters in a registry shared by the
+//! whole process and update them with atomic adds, so counting does not
+//! take a lock. The counters are read with the `metrics` control method, or
+//! in the Prometheus text format from an HTTP endpoint started with
+//! `--metrics-listen`:
+//!
+//!     ph_virtqueue_notifications_total{device="block",slot="03",queue="0"} 1042
+//!     ph_block_read_bytes_total{device="block",slot="03"} 88317952
+//!     ph_vcpu_exits_total{vcpu="0",reason="mmio"} 23411
+//!
+//! A counter is identified by its name and labels. Registering the same
+//! counter again returns the existing one, so counts carry on across a
+//! reset of a device or a reboot of the VM.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::util::JsonValue;
+use crate::vm::exits::{self, ExitHandlers};
+
+// A scrape request which takes longer than this to arrive is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: u64 = 8192;
+
+lazy_static! {
+    static ref REGISTRY: Mutex<BTreeMap<String, Family>> = Mutex::new(BTreeMap::new());
+    static ref LISTENING: Mutex<Option<SocketAddr>> = Mutex::new(None);
+}
+
+///
+/// A count which only goes up, such as a number of events or of bytes.
+///
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicUsize,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: usize) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+///
+/// Names and values which tell apart the counters of a family, such as the
+/// device and queue a notification count belongs to.
+///
+#[derive(Clone,Debug,Default,PartialEq,Eq,PartialOrd,Ord)]
+pub struct Labels(Vec<(String, String)>);
+
+impl Labels {
+    pub fn new() -> Self {
+        Labels(Vec::new())
+    }
+
+    pub fn with<V: ToString>(mut self, name: &str, value: V) -> Self {
+        self.0.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn describe(&self) -> JsonValue {
+        self.0.iter().fold(JsonValue::object(), |obj, (name, value)| obj.with(name.as_str(), value.as_str()))
+    }
+
+    // `{name="value",...}`, or nothing if there are no labels
+    fn write_text(&self, out: &mut String) {
+        if self.0.is_empty() {
+            return;
+        }
+        out.push('{');
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}=\"", name);
+            for c in value.chars() {
+                match c {
+                    '\\' | '"' => { out.push('\\'); out.push(c); }
+                    '\n' => out.push_str("\\n"),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        out.push('}');
+    }
+}
+
+struct Family {
+    help: String,
+    counters: BTreeMap<Labels, Arc<Counter>>,
+}
+
+/// The counter `name` with `labels`, which is created if it has not been
+/// registered yet. `help` describes every counter with this name.
+pub fn counter(name: &str, help: &str, labels: Labels) -> Arc<Counter> {
+    let mut registry = REGISTRY.lock().unwrap();
+    let family = registry.entry(name.to_string())
+        .or_insert_with(|| Family { help: help.to_string(), counters: BTreeMap::new() });
+    family.counters.entry(labels)
+        .or_insert_with(|| Arc::new(Counter::default()))
+        .clone()
+}
+
+///
+/// Counts of the bytes a device has read from and written to the host on
+/// behalf of the guest.
+///
+#[derive(Clone)]
+pub struct IoBytes {
+    read: Arc<Counter>,
+    written: Arc<Counter>,
+}
+
+impl IoBytes {
+    /// Register `ph_PREFIX_read_bytes_total` and `ph_PREFIX_written_bytes_total`
+    /// with `labels`.
+    pub fn new(prefix: &str, what: &str, labels: Labels) -> Self {
+        IoBytes {
+            read: counter(&format!("ph_{}_read_bytes_total", prefix),
+                          &format!("Bytes read from {} for the guest", what), labels.clone()),
+            written: counter(&format!("ph_{}_written_bytes_total", prefix),
+                             &format!("Bytes written to {} by the guest", what), labels),
+        }
+    }
+
+    pub fn record_read(&self, n: usize) {
+        self.read.add(n);
+    }
+
+    pub fn record_written(&self, n: usize) {
+        self.written.add(n);
+    }
+}
+
+/// Count the exits of each vcpu by exit reason with a tracepoint.
+pub fn count_exits(handlers: &ExitHandlers) {
+    let counters: RwLock<BTreeMap<(usize, u32), Arc<Counter>>> = RwLock::new(BTreeMap::new());
+    handlers.add_tracepoint(move |exit| {
+        let key = (exit.vcpu().id(), exit.reason());
+        if let Some(counter) = counters.read().unwrap().get(&key) {
+            counter.inc();
+            return;
+        }
+        let counter = exit_counter(key.0, key.1);
+        counter.inc();
+        counters.write().unwrap().insert(key, counter);
+    });
+}
+
+fn exit_counter(vcpu: usize, reason: u32) -> Arc<Counter> {
+    let reason = exits::exit_reason_name(reason);
+    counter("ph_vcpu_exits_total", "Exits from KVM_RUN by vcpu and exit reason",
+            Labels::new().with("vcpu", vcpu).with("reason", reason))
+}
+
+/// Every counter, for the `metrics` control method.
+pub fn describe() -> JsonValue {
+    let registry = REGISTRY.lock().unwrap();
+    let metrics: Vec<JsonValue> = registry.iter().map(|(name, family)| {
+        let values: Vec<JsonValue> = family.counters.iter().map(|(labels, counter)| {
+            JsonValue::object()
+                .with("labels", labels.describe())
+                .with("value", counter.get())
+        }).collect();
+        JsonValue::object()
+            .with("name", name.as_str())
+            .with("help", family.help.as_str())
+            .with("values", values)
+    }).collect();
+    JsonValue::object()
+        .with("metrics", metrics)
+}
+
+/// Every counter in the Prometheus text exposition format.
+pub fn render_text() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, family) in registry.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, family.help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (labels, counter) in &family.counters {
+            out.push_str(name);
+            labels.write_text(&mut out);
+            let _ = writeln!(out, " {}", counter.get());
+        }
+    }
+    out
+}
+
+/// Serve `GET /metrics` over HTTP on `address` from a new thread. Does
+/// nothing if the endpoint is already listening on `address`, as it is
+/// when the VM is restarted after a reboot.
+pub fn listen(address: SocketAddr) -> io::Result<()> {
+    let mut listening = LISTENING.lock().unwrap();
+    if *listening == Some(address) {
+        return Ok(());
+    }
+    let listener = TcpListener::bind(address)?;
+    *listening = Some(address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => if let Err(e) = serve_request(stream) {
+                    verbose!("Error serving metrics request: {}", e);
+                },
+                Err(e) => warn!("Error accepting metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve_request(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let request = read_request_head(&mut stream)?;
+    let mut words = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render_text()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "Metrics are served at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
+
+// Read up to the blank line which ends the request headers
+fn read_request_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut limited = stream.take(MAX_REQUEST);
+    let mut buf = [0u8; 512];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = limited.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
@@ -0,0 +1,155 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::devices::SyntheticFS;
+
+// Directories which ph-init expects to find in the root filesystem. The guest
+// mounts devtmpfs on /dev, so device nodes do not need to be created.
+const ROOT_DIRS: &[&str] = &[
+    "/bin", "/sbin", "/usr/bin", "/usr/sbin", "/etc", "/dev", "/proc",
+    "/sys", "/run", "/tmp", "/root", "/home/user", "/opt/ph",
+];
+
+// Applets linked into /bin if `busybox --list` cannot be run on the host
+const DEFAULT_APPLETS: &[&str] = &[
+    "sh", "ash", "cat", "cp", "dmesg", "echo", "env", "grep", "ip", "kill",
+    "ls", "mkdir", "mount", "mv", "ps", "rm", "sleep", "touch", "umount", "vi",
+];
+
+const INIT_PATH: &str = "/init";
+const DEFAULT_RUN: &str = "/bin/sh";
+
+///
+/// Builds a minimal root filesystem which is exported to the guest as a
+/// `SyntheticFS`, for running a workload without preparing a disk image.
+///
+/// The root holds a busybox binary with a link for each of its applets, any
+/// other binaries which are added and an optional `/init` script. Host files
+/// are exported read only and writes go to a tmpfs overlay in the guest.
+///
+/// Pass the root to `VmConfig::minimal_root()`. Instead of a login shell
+/// ph-init runs the program set with `run()`, or `/init` if a script was
+/// added, or `/bin/sh`, and the VM powers off when it exits.
+///
+pub struct MinimalRoot {
+    fs: SyntheticFS,
+    run: Option<PathBuf>,
+    has_init: bool,
+}
+
+impl MinimalRoot {
+    pub fn new() -> Self {
+        let mut fs = SyntheticFS::new();
+        fs.mkdirs(ROOT_DIRS);
+        MinimalRoot { fs, run: None, has_init: false }
+    }
+
+    /// Add the busybox binary at `path` on the host as `/bin/busybox` and
+    /// link each applet which it lists into `/bin`.
+    pub fn busybox<P: AsRef<Path>>(mut self, path: P) -> Self {
+        let path = path.as_ref();
+        if !path.exists() {
+            warn!("Cannot add busybox to root, {} does not exist", path.display());
+            return self;
+        }
+        self.add_binary(Path::new("/bin"), "busybox", path);
+        for applet in Self::busybox_applets(path) {
+            if applet != "busybox" {
+                self.fs.add_file("/bin", applet.as_str(), 0o755, path);
+            }
+        }
+        self
+    }
+
+    fn busybox_applets(path: &Path) -> Vec<String> {
+        let output = Command::new(path)
+            .arg("--list")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .output();
+        match output {
+            Ok(ref out) if out.status.success() => {
+                String::from_utf8_lossy(&out.stdout)
+                    .lines()
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty() && !s.contains('/'))
+                    .collect()
+            }
+            _ => {
+                warn!("Could not list busybox applets, adding a default set");
+                DEFAULT_APPLETS.iter().map(|s| s.to_string()).collect()
+            }
+        }
+    }
+
+    /// Add the host binary at `path` to directory `dir` of the root, along
+    /// with the shared libraries it links to if it is not static.
+    pub fn binary<P: AsRef<Path>, Q: AsRef<Path>>(mut self, dir: P, path: Q) -> Self {
+        let path = path.as_ref();
+        let name = match path.file_name().and_then(|s| s.to_str()) {
+            Some(name) => name.to_string(),
+            None => {
+                warn!("Cannot add binary with invalid path {} to root", path.display());
+                return self;
+            }
+        };
+        self.add_binary(dir.as_ref(), &name, path);
+        self
+    }
+
+    // A static binary has no libraries and `ldd` may not be installed, so
+    // failing to list libraries is not an error.
+    fn add_binary(&mut self, dir: &Path, name: &str, path: &Path) {
+        if let Err(e) = self.fs.add_library_dependencies(path) {
+            warn!("Could not add libraries needed by {}: {}", path.display(), e);
+        }
+        self.fs.add_file(dir, name, 0o755, path);
+    }
+
+    /// Add a file with the contents `bytes` to directory `dir` of the root.
+    pub fn file<P: AsRef<Path>>(mut self, dir: P, name: &str, mode: u32, bytes: Vec<u8>) -> Self {
+        let dir = dir.as_ref();
+        if let Err(e) = self.fs.add_memory_file(dir, name, mode, bytes) {
+            warn!("Failed to add file {} to {}: {}", name, dir.display(), e);
+        }
+        self
+    }
+
+    /// Add `/init`, a shell script with the commands in `script`, and run it
+    /// in place of a shell. It needs a shell in `/bin/sh`, such as the one
+    /// provided by `busybox()`.
+    pub fn init_script(mut self, script: &str) -> Self {
+        let mut content = String::from("#!/bin/sh\n");
+        content.push_str(script);
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        self.has_init = true;
+        self.file("/", "init", 0o755, content.into_bytes())
+    }
+
+    /// Run the program at `path` in the guest instead of `/bin/sh`.
+    pub fn run<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.run = Some(path.into());
+        self
+    }
+
+    /// Path in the guest of the program which ph-init runs.
+    pub fn run_path(&self) -> PathBuf {
+        match self.run {
+            Some(ref path) => path.clone(),
+            None if self.has_init => PathBuf::from(INIT_PATH),
+            None => PathBuf::from(DEFAULT_RUN),
+        }
+    }
+
+    pub fn into_filesystem(self) -> SyntheticFS {
+        self.fs
+    }
+}
+
+impl Default for MinimalRoot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -4,16 +4,42 @@ static SOMMELIER: &[u8] = include_bytes!("../../sommelier/sommelier");
 
 pub mod arch;
 mod run;
+pub mod exits;
 pub mod io;
+pub mod replay;
+mod control;
+mod idle;
+pub mod events;
+pub mod metrics;
+pub mod console;
+pub mod suspend;
+#[cfg(target_arch = "x86_64")]
+mod gdb;
 mod setup;
 mod error;
 mod kernel_cmdline;
+#[path = "../../ph-init/src/vars.rs"]
+mod phinit_vars;
 mod config;
+mod minimal_root;
+mod cgroup;
+mod preflight;
+mod memory_pressure;
+mod bundle;
+mod notify;
+mod platform;
 
 pub use config::VmConfig;
-pub use setup::VmSetup;
+pub use minimal_root::MinimalRoot;
+pub use bundle::Bundle;
+pub use setup::{VmSetup, warm_boot_cache};
+pub use control::{ControlServer, ControlClient};
+pub use suspend::RamPolicy;
+pub use console::ConsoleMode;
+pub use platform::PlatformProfile;
+pub use exits::{ExitStatus, FailureKind, RebootAction, VmEvent};
 
 pub use self::error::{Result,Error};
-pub use arch::{ArchSetup,create_setup};
+pub use arch::{ArchSetup,BootTables,create_setup};
 
 
@@ -0,0 +1,172 @@
+//! Desktop notifications raised by programs in the guest.
+//!
+//! ph-init sends a `notify` event on the agent port for each request made
+//! with `ph-init notify` in the guest, with the title and body escaped. When
+//! notifications are enabled the event is shown on the host by running
+//! `notify-send`, or the command set in the configuration with the same
+//! arguments, with the title prefixed by the name of the realm so that a
+//! guest cannot pass its notifications off as those of the host.
+
+use std::collections::VecDeque;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::util::JsonValue;
+
+const DEFAULT_COMMAND: &str = "notify-send";
+
+// At most this many notifications are shown in each `RATE_WINDOW`, and
+// the rest are dropped
+const MAX_NOTIFICATIONS: usize = 5;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+const MAX_TITLE_CHARS: usize = 100;
+const MAX_BODY_CHARS: usize = 500;
+
+lazy_static! {
+    static ref NOTIFIER: Mutex<Option<Notifier>> = Mutex::new(None);
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "low" => Some(Urgency::Low),
+            "normal" => Some(Urgency::Normal),
+            "critical" => Some(Urgency::Critical),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::Critical => "critical",
+        }
+    }
+}
+
+struct Notifier {
+    label: String,
+    command: String,
+    shown: VecDeque<Instant>,
+    dropped: u64,
+}
+
+impl Notifier {
+    // Returns `false` if too many notifications were shown recently
+    fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        while self.shown.front().map(|t| now.duration_since(*t) >= RATE_WINDOW).unwrap_or(false) {
+            self.shown.pop_front();
+        }
+        if self.shown.len() >= MAX_NOTIFICATIONS {
+            self.dropped += 1;
+            return false;
+        }
+        self.shown.push_back(now);
+        true
+    }
+
+    fn show(&self, urgency: Urgency, title: &str, body: &str) {
+        let mut command = Command::new(&self.command);
+        command.arg("--urgency").arg(urgency.as_str())
+            .arg("--app-name").arg("pH")
+            // The guest chooses the title and body, which must not be taken as options
+            .arg("--")
+            .arg(format!("[{}] {}", self.label, title))
+            .arg(body)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        let name = self.command.clone();
+        // Waited for on a thread of its own so that the agent port is not held up
+        thread::spawn(move || match command.status() {
+            Ok(status) if !status.success() => warn!("{} exited with {}", name, status),
+            Ok(_) => {},
+            Err(e) => warn!("Failed to run {} to show guest notification: {}", name, e),
+        });
+    }
+}
+
+/// Show notifications from the guest on the host, labeled with `label`,
+/// by running `command` or `notify-send`.
+pub fn enable(label: &str, command: Option<&str>) {
+    *NOTIFIER.lock().unwrap() = Some(Notifier {
+        label: label.to_string(),
+        command: command.unwrap_or(DEFAULT_COMMAND).to_string(),
+        shown: VecDeque::new(),
+        dropped: 0,
+    });
+}
+
+/// Decode the title and body of a `notify` event from the guest agent in
+/// place and show it if notifications are enabled.
+pub fn guest_notification(event: &mut JsonValue) {
+    let field = |name: &str| unescape(event.get(name).and_then(|v| v.as_str()).unwrap_or(""));
+    let title = sanitize(&field("title"), MAX_TITLE_CHARS, false);
+    let body = sanitize(&field("body"), MAX_BODY_CHARS, true);
+    let urgency = event.get("urgency")
+        .and_then(|v| v.as_str())
+        .and_then(Urgency::parse)
+        .unwrap_or(Urgency::Normal);
+    event.set("title", title.as_str());
+    event.set("body", body.as_str());
+    event.set("urgency", urgency.as_str());
+
+    let mut notifier = NOTIFIER.lock().unwrap();
+    let notifier = match notifier.as_mut() {
+        Some(notifier) => notifier,
+        None => return,
+    };
+    if title.is_empty() {
+        return;
+    }
+    if notifier.allow() {
+        notifier.show(urgency, &title, &body);
+        event.set("shown", true);
+    } else {
+        if notifier.dropped == 1 || notifier.dropped % 100 == 0 {
+            warn!("Dropped {} notifications from the guest, at most {} are shown each minute", notifier.dropped, MAX_NOTIFICATIONS);
+        }
+        event.set("shown", false);
+    }
+}
+
+// Replace each `%XX` escape with the byte it encodes
+fn unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = s.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Control characters, other than newlines if `keep_newlines` is set, are
+// dropped and the text is cut to `max` characters
+fn sanitize(s: &str, max: usize, keep_newlines: bool) -> String {
+    s.chars()
+        .filter(|&c| !c.is_control() || (keep_newlines && c == '\n'))
+        .take(max)
+        .collect()
+}
@@ -0,0 +1,44 @@
+///
+/// Which of the legacy PC devices are emulated for the guest.
+///
+/// Probing these devices takes time at boot, and a guest kernel which
+/// takes its clocks from kvmclock and prints to the virtio console needs
+/// none of them.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum PlatformProfile {
+    /// The CMOS RTC, the PIT, the serial ports given with `--uart` and
+    /// placeholders for the other ports a PC kernel probes.
+    LegacyFull,
+    /// Only the keyboard controller ports through which the guest resets.
+    /// The RTC is kept when the guest has no kvmclock.
+    MinimalParavirt,
+}
+
+impl PlatformProfile {
+    /// Parse `legacy-full` or `minimal-paravirt`.
+    pub fn parse(s: &str) -> Option<PlatformProfile> {
+        match s {
+            "legacy-full" => Some(PlatformProfile::LegacyFull),
+            "minimal-paravirt" => Some(PlatformProfile::MinimalParavirt),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PlatformProfile::LegacyFull => "legacy-full",
+            PlatformProfile::MinimalParavirt => "minimal-paravirt",
+        }
+    }
+
+    pub fn has_legacy_devices(self) -> bool {
+        self == PlatformProfile::LegacyFull
+    }
+}
+
+impl Default for PlatformProfile {
+    fn default() -> Self {
+        PlatformProfile::LegacyFull
+    }
+}
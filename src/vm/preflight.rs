@@ -0,0 +1,171 @@
+use std::io;
+
+use crate::vm::{VmConfig, Result, Error};
+
+// Descriptors held regardless of the configured devices: KVM, the VM, the
+// control socket and its clients, epoll instances and eventfds of the vcpu
+// threads.
+const BASE_FDS: u64 = 32;
+
+// Every virtqueue has an ioeventfd and an irqfd
+const FDS_PER_QUEUE: u64 = 2;
+
+// The console has a control queue pair and a queue pair for each port
+const SERIAL_QUEUES: u64 = 6;
+
+// Host files which the guest may hold open on a shared directory
+const SHARE_OPEN_FILES: u64 = 512;
+
+// Host sockets of vsock connections
+const VSOCK_CONNECTIONS: u64 = 64;
+
+// Connections to the host compositor and buffers shared with the guest
+const WAYLAND_FDS: u64 = 256;
+
+///
+/// Number of file descriptors which a VM with a given configuration uses.
+///
+/// `required` are opened during setup, and setup fails part way through if
+/// they are not available. `reserve` are opened while the VM runs, when the
+/// guest opens files on a shared directory, windows on the host compositor
+/// or vsock connections.
+///
+/// Guest RAM is not locked into memory, so there is no `RLIMIT_MEMLOCK` to
+/// estimate alongside the descriptors. 9p io_uring falls back to unregistered
+/// buffers if guest RAM cannot be pinned.
+///
+struct FdEstimate {
+    required: u64,
+    reserve: u64,
+}
+
+impl FdEstimate {
+    fn new(config: &VmConfig) -> Self {
+        let mut est = FdEstimate { required: BASE_FDS, reserve: 0 };
+        est.required += config.ncpus() as u64;
+
+        // Console and the synthetic boot filesystem
+        est.add_queues(SERIAL_QUEUES + 1);
+
+        // A queue pair, the listening socket and a client for each socket port
+        let serial_ports = config.serial_ports().len() as u64;
+        est.add_queues(2 * serial_ports);
+        est.required += 2 * serial_ports;
+
+        let share_queues = if config.is_virtio_fs_enabled() { 2 } else { 1 };
+        if !config.is_tiny() {
+            // Entropy device and home directory
+            est.add_queues(1 + share_queues);
+            est.reserve += SHARE_OPEN_FILES;
+            if config.is_wayland_enabled() {
+                est.add_queues(2);
+                est.reserve += WAYLAND_FDS;
+            }
+            if config.is_balloon_enabled() {
+                // Inflate, deflate and stats queues
+                est.add_queues(3);
+                if config.get_balloon_weight().is_some() {
+                    // The balloon policy table
+                    est.required += 1;
+                }
+            }
+            if config.is_virtio_input_enabled() {
+                // An event and status queue for the keyboard and the mouse
+                est.add_queues(4);
+            }
+            if config.get_sound_backend().is_some() {
+                // Control, event, transmit and receive queues, and a pipe to
+                // the sound process of each running stream
+                est.add_queues(4);
+                est.required += 2;
+            }
+        }
+
+        // Each disk has the image and a file for writes spilled from the overlay
+        let disks = config.get_disk_image_count() as u64;
+        est.add_queues(disks);
+        est.required += 2 * disks;
+        if !config.has_block_image() {
+            // The root directory is shared instead
+            est.add_queues(share_queues);
+            est.reserve += SHARE_OPEN_FILES;
+        }
+
+        if config.is_p9_io_uring_enabled() && !config.is_virtio_fs_enabled() {
+            // An io_uring for the root and home directory shares
+            est.required += 2;
+        }
+
+        if config.vsock_path().is_some() {
+            // The rx, tx and event queues and the listening socket
+            est.add_queues(3);
+            est.required += 1;
+            est.reserve += VSOCK_CONNECTIONS;
+        }
+
+        if config.network() {
+            // A receive and transmit queue and the TAP device
+            est.add_queues(2);
+            est.required += 1;
+            if config.is_vhost_net_enabled() {
+                // /dev/vhost-net and an eventfd for each queue
+                est.required += 3;
+            }
+        }
+        est
+    }
+
+    fn add_queues(&mut self, n: u64) {
+        self.required += n * FDS_PER_QUEUE;
+    }
+
+    fn total(&self) -> u64 {
+        self.required + self.reserve
+    }
+}
+
+/// Check that the descriptors which the VM needs are available before any
+/// device is set up, and raise the soft `RLIMIT_NOFILE` limit up to the hard
+/// limit if they are not.
+pub fn check_resource_limits(config: &VmConfig) -> Result<()> {
+    let est = FdEstimate::new(config);
+    let (soft, hard) = get_nofile_limit()?;
+    if soft >= est.total() {
+        return Ok(());
+    }
+    if hard > soft {
+        set_nofile_limit(hard, hard)?;
+        verbose!("Raised file descriptor limit from {} to {}", soft, hard);
+    }
+    if hard < est.required {
+        return Err(Error::FileLimit(est.required, hard));
+    }
+    if hard < est.total() {
+        warn!("The file descriptor limit of {} may be too low for this VM, which can use up to {}", hard, est.total());
+        warn!("{}", remedy(est.total()));
+    }
+    Ok(())
+}
+
+/// How to raise the hard limit on file descriptors to `n`.
+pub fn remedy(n: u64) -> String {
+    format!("Raise the hard limit with 'ulimit -Hn {}' in a root shell before starting pH, \
+             'LimitNOFILE={}' in the systemd unit which starts it, or a 'nofile' entry \
+             in /etc/security/limits.conf", n, n)
+}
+
+fn get_nofile_limit() -> io::Result<(u64, u64)> {
+    let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((rlim.rlim_cur, rlim.rlim_max))
+}
+
+fn set_nofile_limit(soft: u64, hard: u64) -> io::Result<()> {
+    let rlim = libc::rlimit { rlim_cur: soft, rlim_max: hard };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,252 @@
+//! Recording and replay of the inputs which devices deliver to the guest.
+//!
+//! Every value the guest observes from an emulated device passes through one
+//! of the interposition hooks in this module:
+//!
+//!   * `io_in()` and `mmio_read()` from `IoDispatcher` (including RTC clock reads)
+//!   * `chain_data()` when a virtqueue chain is returned to the guest
+//!   * `interrupt()` when a device raises an interrupt line
+//!
+//! While recording, each value is appended to a log file. While replaying, the
+//! values from the log are substituted for the live device values. Events are
+//! matched per stream (per I/O port, MMIO address, virtqueue, or irq line) rather
+//! than in one global order so that scheduling differences between the vcpu and
+//! device threads do not immediately cause the replay to diverge. When a replayed
+//! stream no longer matches the log a warning is printed and the live value is used.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const TAG_IO_IN: u8 = 1;
+const TAG_MMIO_READ: u8 = 2;
+const TAG_CHAIN_DATA: u8 = 3;
+const TAG_INTERRUPT: u8 = 4;
+
+const LOG_MAGIC: &[u8; 8] = b"phreplay";
+
+lazy_static! {
+    static ref HOOK: Mutex<Option<Box<dyn EventHook>>> = Mutex::new(None);
+}
+
+// Avoids taking the lock on every I/O exit when no hook is installed
+static HOOK_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum ReplayEvent {
+    IoIn { port: u16, val: u32 },
+    MmioRead { address: u64, val: u64 },
+    ChainData { queue: u64, data: Vec<u8> },
+    Interrupt { irq: u8, isr: u8 },
+}
+
+impl ReplayEvent {
+
+    // Identifies the stream an event belongs to
+    fn stream(&self) -> (u8, u64) {
+        match *self {
+            ReplayEvent::IoIn { port, .. } => (TAG_IO_IN, port as u64),
+            ReplayEvent::MmioRead { address, .. } => (TAG_MMIO_READ, address),
+            ReplayEvent::ChainData { queue, .. } => (TAG_CHAIN_DATA, queue),
+            ReplayEvent::Interrupt { irq, .. } => (TAG_INTERRUPT, irq as u64),
+        }
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let (tag, key) = self.stream();
+        w.write_all(&[tag])?;
+        w.write_all(&key.to_le_bytes())?;
+        match self {
+            ReplayEvent::IoIn { val, .. } => w.write_all(&val.to_le_bytes()),
+            ReplayEvent::MmioRead { val, .. } => w.write_all(&val.to_le_bytes()),
+            ReplayEvent::ChainData { data, .. } => {
+                w.write_all(&(data.len() as u32).to_le_bytes())?;
+                w.write_all(data)
+            }
+            ReplayEvent::Interrupt { isr, .. } => w.write_all(&[*isr]),
+        }
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Option<ReplayEvent>> {
+        let mut tag = [0u8; 1];
+        if r.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let key = read_u64(r)?;
+        let event = match tag[0] {
+            TAG_IO_IN => ReplayEvent::IoIn { port: key as u16, val: read_u32(r)? },
+            TAG_MMIO_READ => ReplayEvent::MmioRead { address: key, val: read_u64(r)? },
+            TAG_CHAIN_DATA => {
+                let len = read_u32(r)? as usize;
+                let mut data = vec![0u8; len];
+                r.read_exact(&mut data)?;
+                ReplayEvent::ChainData { queue: key, data }
+            },
+            TAG_INTERRUPT => {
+                let mut isr = [0u8; 1];
+                r.read_exact(&mut isr)?;
+                ReplayEvent::Interrupt { irq: key as u8, isr: isr[0] }
+            },
+            tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad event tag {}", tag))),
+        };
+        Ok(Some(event))
+    }
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+///
+/// Interposition point for device inputs. `event` describes the live value
+/// produced by the device and the returned event is what is delivered to the guest.
+///
+pub trait EventHook: Send {
+    fn on_event(&mut self, event: ReplayEvent) -> ReplayEvent;
+}
+
+pub struct EventRecorder {
+    out: BufWriter<File>,
+}
+
+impl EventRecorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(LOG_MAGIC)?;
+        Ok(EventRecorder { out })
+    }
+}
+
+impl EventHook for EventRecorder {
+    fn on_event(&mut self, event: ReplayEvent) -> ReplayEvent {
+        if let Err(e) = event.write_to(&mut self.out).and_then(|_| self.out.flush()) {
+            warn!("Failed to write event to replay log: {}", e);
+        }
+        event
+    }
+}
+
+pub struct EventReplayer {
+    streams: HashMap<(u8, u64), VecDeque<ReplayEvent>>,
+    diverged: bool,
+}
+
+impl EventReplayer {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != LOG_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a replay log"));
+        }
+        let mut streams = HashMap::new();
+        let mut count = 0;
+        while let Some(event) = ReplayEvent::read_from(&mut r)? {
+            streams.entry(event.stream())
+                .or_insert_with(VecDeque::new)
+                .push_back(event);
+            count += 1;
+        }
+        notify!("Loaded {} events from replay log", count);
+        Ok(EventReplayer { streams, diverged: false })
+    }
+
+    fn diverge(&mut self, event: &ReplayEvent) {
+        if !self.diverged {
+            warn!("Replay diverged from log at {:?}", event.stream());
+            self.diverged = true;
+        }
+    }
+}
+
+impl EventHook for EventReplayer {
+    fn on_event(&mut self, event: ReplayEvent) -> ReplayEvent {
+        let next = self.streams.get_mut(&event.stream())
+            .and_then(|q| q.pop_front());
+
+        match (next, &event) {
+            (Some(ReplayEvent::ChainData { queue, data: recorded }), ReplayEvent::ChainData { data, .. }) => {
+                if recorded.len() != data.len() {
+                    self.diverge(&event);
+                    return event;
+                }
+                ReplayEvent::ChainData { queue, data: recorded }
+            },
+            (Some(ReplayEvent::Interrupt { isr, .. }), ReplayEvent::Interrupt { isr: live, .. }) => {
+                if isr != *live {
+                    self.diverge(&event);
+                }
+                event
+            },
+            (Some(recorded), _) => recorded,
+            (None, _) => {
+                self.diverge(&event);
+                event
+            }
+        }
+    }
+}
+
+/// Install `hook` as the interposition hook for all devices in this process.
+pub fn set_hook(hook: Box<dyn EventHook>) {
+    *HOOK.lock().unwrap() = Some(hook);
+    HOOK_ACTIVE.store(true, Ordering::SeqCst);
+}
+
+pub fn is_active() -> bool {
+    HOOK_ACTIVE.load(Ordering::Relaxed)
+}
+
+fn dispatch(event: ReplayEvent) -> ReplayEvent {
+    match HOOK.lock().unwrap().as_mut() {
+        Some(hook) => hook.on_event(event),
+        None => event,
+    }
+}
+
+pub fn io_in(port: u16, val: u32) -> u32 {
+    if !is_active() {
+        return val;
+    }
+    match dispatch(ReplayEvent::IoIn { port, val }) {
+        ReplayEvent::IoIn { val, .. } => val,
+        _ => val,
+    }
+}
+
+pub fn mmio_read(address: u64, val: u64) -> u64 {
+    if !is_active() {
+        return val;
+    }
+    match dispatch(ReplayEvent::MmioRead { address, val }) {
+        ReplayEvent::MmioRead { val, .. } => val,
+        _ => val,
+    }
+}
+
+/// `data` is the content written into the guest buffers of a chain on the
+/// virtqueue with descriptor table at `queue`. When replaying it is replaced
+/// with the recorded content.
+pub fn chain_data(queue: u64, data: &mut Vec<u8>) {
+    let event = ReplayEvent::ChainData { queue, data: data.clone() };
+    if let ReplayEvent::ChainData { data: replayed, .. } = dispatch(event) {
+        *data = replayed;
+    }
+}
+
+pub fn interrupt(irq: u8, isr: u8) {
+    if is_active() {
+        dispatch(ReplayEvent::Interrupt { irq, isr });
+    }
+}
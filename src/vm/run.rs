@@ -1,204 +1,179 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fs, io, mem, ptr, thread};
 
 use crate::kvm::KvmVcpu;
 use crate::memory::Mapping;
 use super::Result;
-use super::io::IoDispatcher;
 use std::sync::atomic::{AtomicBool, Ordering};
 use crate::vm::Error;
-
-const KVM_EXIT_UNKNOWN:u32 = 0;
-const KVM_EXIT_IO:u32 = 2;
-const KVM_EXIT_MMIO:u32 = 6;
-const KVM_EXIT_INTR:u32 = 10;
-const KVM_EXIT_SHUTDOWN:u32 = 8;
-const KVM_EXIT_INTERNAL_ERROR: u32 = 17;
-const KVM_EXIT_SYSTEM_EVENT:u32 = 24;
+use crate::vm::exits::{ExitAction, ExitHandlers, ExitStatus, VcpuExit};
+#[cfg(target_arch = "x86_64")]
+use crate::vm::gdb;
+use crate::vm::suspend;
 
 pub struct KvmRunArea {
     vcpu: KvmVcpu,
-    io: Arc<IoDispatcher>,
+    handlers: ExitHandlers,
     mapping: Mapping,
     shutdown: Arc<AtomicBool>,
 }
 
-pub struct IoExitData {
-    dir_out: bool,
-    size: usize,
-    port: u16,
-    count: usize,
-    offset: usize,
+///
+/// Interrupts vcpu threads blocked in `KVM_RUN` with a signal so that they
+/// notice when another thread has set the shutdown flag.
+///
+#[derive(Clone)]
+pub struct VcpuKicker {
+    threads: Arc<Mutex<Vec<VcpuThread>>>,
 }
 
-pub struct MmioExitData {
-    phys: u64,
-    size: usize,
-    write: bool,
+struct VcpuThread {
+    handle: libc::pthread_t,
+    tid: libc::pid_t,
 }
 
-impl KvmRunArea {
-    pub fn new(vcpu: KvmVcpu, shutdown: Arc<AtomicBool>, io_dispatcher: Arc<IoDispatcher>) -> Result<KvmRunArea> {
-        let size = vcpu.get_vcpu_mmap_size().map_err(Error::CreateVmFailed)?;
-        let mapping = Mapping::new_from_fd(vcpu.raw_fd(), size).map_err(Error::MappingFailed)?;
-        Ok(KvmRunArea{
-            vcpu,
-            io: io_dispatcher,
-            mapping,
-            shutdown,
-        })
-    }
-
-    fn r8(&self, offset: usize) -> u8 { self.mapping.read_int(offset).unwrap() }
-    fn r16(&self, offset: usize) -> u16 { self.mapping.read_int(offset).unwrap() }
-    fn r32(&self, offset: usize) -> u32 { self.mapping.read_int(offset).unwrap() }
-    fn r64(&self, offset: usize) -> u64 { self.mapping.read_int(offset).unwrap() }
-    fn w8(&self, offset: usize, val: u8) { self.mapping.write_int(offset, val).unwrap() }
-    fn w16(&self, offset: usize, val: u16) { self.mapping.write_int(offset, val).unwrap() }
-    fn w32(&self, offset: usize, val: u32) { self.mapping.write_int(offset, val).unwrap() }
-    fn w64(&self, offset: usize, val: u64) { self.mapping.write_int(offset, val).unwrap() }
-
-    fn exit_reason(&self) -> u32 {
-        self.r32(8)
-    }
-
-    fn suberror(&self) -> u32 {
-        self.r32(32)
-    }
+extern "C" fn handle_kick_signal(_: libc::c_int) {}
 
-    fn get_io_exit(&self) -> IoExitData {
-        let d = self.r8(32) != 0;
-        let size = self.r8(33) as usize;
-        let port = self.r16(34);
-        let count = self.r32(36) as usize;
-        let offset = self.r64(40) as usize;
-
-        IoExitData{
-            dir_out: d,
-            size,
-            port,
-            count,
-            offset,
+impl VcpuKicker {
+    pub fn new() -> Result<Self> {
+        // Installed without SA_RESTART so that KVM_RUN returns EINTR
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_kick_signal as extern "C" fn(libc::c_int) as usize;
+            if libc::sigaction(Self::signal(), &action, ptr::null_mut()) < 0 {
+                return Err(Error::IoError(io::Error::last_os_error()));
+            }
         }
+        Ok(VcpuKicker { threads: Arc::new(Mutex::new(Vec::new())) })
     }
 
-    fn get_mmio_exit(&self) -> MmioExitData {
-        let phys = self.r64(32);
-        let size = self.r32(48) as usize;
-        assert!(size <= 8);
-        let write = self.r8(52) != 0;
-        MmioExitData {
-            phys, size, write
-        }
+    fn signal() -> libc::c_int {
+        libc::SIGUSR1
     }
 
-    pub fn run(&mut self) {
-        loop {
-            if let Err(err) = self.vcpu.run() {
-                if !err.is_interrupted() {
-                    println!("KVM_RUN returned error, bailing: {:?}", err);
-                    return;
-                }
-            } else {
-               self.handle_exit();
-            }
-            if self.shutdown.load(Ordering::Relaxed) {
-                return;
+    /// Called on a vcpu thread before it starts running the vcpu.
+    pub fn register_current(&self) {
+        let current = unsafe {
+            VcpuThread {
+                handle: libc::pthread_self(),
+                tid: libc::syscall(libc::SYS_gettid) as libc::pid_t,
             }
-        }
+        };
+        self.threads.lock().unwrap().push(current);
     }
 
-    fn handle_exit(&mut self) {
-        match self.exit_reason() {
-            KVM_EXIT_UNKNOWN => {println!("unknown")},
-            KVM_EXIT_IO => { self.handle_exit_io() },
-            KVM_EXIT_MMIO => { self.handle_exit_mmio() },
-            KVM_EXIT_INTR => { println!("intr")},
-            KVM_EXIT_SHUTDOWN => {
-                self.handle_shutdown();
-            },
-            KVM_EXIT_SYSTEM_EVENT => { println!("event")},
-            KVM_EXIT_INTERNAL_ERROR => {
-                let sub = self.suberror();
-                println!("internal error: {}", sub);
-                println!("{:?}", self.vcpu.get_regs().unwrap());
-            }
-            n => { println!("unhandled exit: {}", n);},
-        }
+    /// Called on a vcpu thread before it exits.
+    pub fn unregister_current(&self) {
+        let current = unsafe { libc::pthread_self() };
+        self.threads.lock().unwrap().retain(|t| t.handle != current);
     }
 
-    fn handle_shutdown(&mut self) {
-        self.shutdown.store(true, Ordering::Relaxed);
+    /// Signal every registered vcpu thread and return how many are still running.
+    pub fn kick_all(&self) -> usize {
+        let threads = self.threads.lock().unwrap();
+        for t in threads.iter() {
+            unsafe { libc::pthread_kill(t.handle, Self::signal()); }
+        }
+        threads.len()
     }
 
-    fn handle_exit_io(&mut self) {
-        let exit = self.get_io_exit();
-        if exit.dir_out {
-            self.handle_exit_io_out(&exit);
-        } else {
-            self.handle_exit_io_in(&exit);
+    /// Total time the registered vcpu threads have spent running, or `None`
+    /// if it cannot be read from /proc.
+    pub fn cpu_time(&self) -> Option<Duration> {
+        let threads = self.threads.lock().unwrap();
+        let mut total = 0;
+        for t in threads.iter() {
+            let schedstat = fs::read_to_string(format!("/proc/self/task/{}/schedstat", t.tid)).ok()?;
+            total += schedstat.split_whitespace().next()?.parse::<u64>().ok()?;
         }
+        Some(Duration::from_nanos(total))
     }
+}
 
-    fn handle_exit_io_in(&mut self, exit: &IoExitData) {
-        for i in 0..exit.count {
-            let v = self.io.emulate_io_in(exit.port, exit.size);
-            match exit.size {
-                1 => self.w8(exit.offset + i, v as u8),
-                2 => self.w16(exit.offset + i * 2, v as u16),
-                4 => self.w32(exit.offset + i * 4, v as u32),
-                _ => {},
-            }
-        }
+///
+/// Stops a running VM from outside of its vcpu threads, such as when a
+/// control socket client asks for it.
+///
+#[derive(Clone)]
+pub struct VmStopper {
+    shutdown: Arc<AtomicBool>,
+    kicker: VcpuKicker,
+    handlers: ExitHandlers,
+}
+
+impl VmStopper {
+    pub fn new(shutdown: Arc<AtomicBool>, kicker: VcpuKicker, handlers: ExitHandlers) -> Self {
+        VmStopper { shutdown, kicker, handlers }
     }
 
-    fn handle_exit_io_out(&self, exit: &IoExitData) {
-        for i in 0..exit.count {
-            let v = match exit.size {
-                1 => self.r8(exit.offset + i) as u32,
-                2 => self.r16(exit.offset + i * 2) as u32,
-                4 => self.r32(exit.offset + i * 4) as u32,
-                _ => 0,
-            };
-            self.io.emulate_io_out(exit.port, exit.size, v);
+    /// Stop the vcpus and exit the VM with `status`. Returns `false` if the
+    /// VM is already stopping.
+    pub fn stop(&self, status: ExitStatus) -> bool {
+        if !self.handlers.set_exit_status(status) {
+            return false;
         }
+        self.shutdown.store(true, Ordering::Relaxed);
+        // A signal which arrives while a vcpu thread is outside of KVM_RUN
+        // is lost, so keep kicking until every vcpu thread has exited.
+        let kicker = self.kicker.clone();
+        thread::spawn(move || {
+            while kicker.kick_all() > 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        true
     }
 
-    fn handle_exit_mmio(&mut self) {
-        let exit = self.get_mmio_exit();
-        if exit.write {
-            self.handle_mmio_write(exit.phys, exit.size)
-        } else {
-            self.handle_mmio_read(exit.phys, exit.size)
-        }
+    pub fn is_stopping(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed) || self.handlers.exit_status().is_some()
     }
+}
 
-    fn handle_mmio_write(&self, address: u64, size: usize) {
-        if let Some(val) = self.data_to_val64(size) {
-            self.io.emulate_mmio_write(address, size, val)
-        }
+impl KvmRunArea {
+    pub fn new(vcpu: KvmVcpu, shutdown: Arc<AtomicBool>, handlers: ExitHandlers) -> Result<KvmRunArea> {
+        let size = vcpu.get_vcpu_mmap_size().map_err(Error::CreateVmFailed)?;
+        let mapping = Mapping::new_from_fd(vcpu.raw_fd(), size).map_err(Error::MappingFailed)?;
+        Ok(KvmRunArea{
+            vcpu,
+            handlers,
+            mapping,
+            shutdown,
+        })
     }
 
-    fn handle_mmio_read(&self, address: u64, size: usize)  {
-        if size == 1 || size == 2 || size == 4 || size == 8 {
-            let val = self.io.emulate_mmio_read(address, size);
-            match size {
-                1 => self.w8(40, val as u8),
-                2 => self.w16(40, val as u16),
-                4 => self.w32(40, val as u32),
-                8 => self.w64(40, val),
-                _ => (),
+    pub fn run(&mut self) {
+        loop {
+            // Checked before entering the guest so that a vcpu which starts
+            // stopped waits for the debugger before its first instruction
+            #[cfg(target_arch = "x86_64")]
+            if gdb::is_stopped() {
+                gdb::park_vcpu(&self.vcpu, &self.shutdown);
+                if self.shutdown.load(Ordering::Relaxed) {
+                    return;
+                }
+            }
+            if let Err(err) = self.vcpu.run() {
+                if !err.is_interrupted() {
+                    println!("KVM_RUN returned error, bailing: {:?}", err);
+                    return;
+                }
+            } else {
+                self.handle_exit();
+            }
+            if self.shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+            if suspend::is_suspended() {
+                suspend::park_vcpu(&self.shutdown);
             }
         }
     }
 
-    fn data_to_val64(&self, size: usize) -> Option<u64> {
-        match size {
-            1 => { Some(self.r8(40) as u64)}
-            2 => { Some(self.r16(40) as u64)}
-            4 => { Some(self.r32(40) as u64)}
-            8 => { Some(self.r64(40))}
-            _ => { None }
+    fn handle_exit(&mut self) {
+        let exit = VcpuExit::new(&self.vcpu, &self.mapping);
+        if let ExitAction::Stop(_) = self.handlers.dispatch(&exit) {
+            self.shutdown.store(true, Ordering::Relaxed);
         }
     }
 }
-
@@ -1,4 +1,4 @@
-use crate::vm::{VmConfig, Result, Error, PHINIT, SOMMELIER};
+use crate::vm::{VmConfig, Result, Error, KERNEL, PHINIT, SOMMELIER};
 use crate::vm::arch::ArchSetup;
 use crate::vm::kernel_cmdline::KernelCmdLine;
 use crate::vm::io::IoDispatcher;
@@ -6,22 +6,120 @@ use crate::devices;
 use termios::Termios;
 use crate::virtio::VirtioBus;
 use crate::virtio;
-use crate::devices::SyntheticFS;
+use crate::devices::{SyntheticFS, P9Share, VirtioFsShare, MacAddress, VhostNet, VhostUserBackend, BalloonHandle, InputHandle};
 use std::{fs, thread};
-use crate::system::{Tap, NetlinkSocket};
-use crate::disk::DiskImage;
+use crate::system::{Tap, NetlinkSocket, MemoryFd, seccomp};
+use crate::disk::{DiskImage, OverlayStats, IoShare, RawDiskImage, OpenType};
 use crate::kvm::{KvmVcpu, Kvm};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use crate::memory::MemoryManager;
-use std::sync::atomic::AtomicBool;
-use crate::vm::run::KvmRunArea;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::path::Path;
+use std::os::unix::fs::PermissionsExt;
+use crate::vm::run::{KvmRunArea, VcpuKicker, VmStopper};
+use crate::vm::idle::IdleMonitor;
+use crate::vm::suspend::{self, SuspendMonitor, SuspendPolicy};
+use crate::vm::replay::{self, EventRecorder, EventReplayer};
+use crate::vm::{ControlServer, console, events, metrics, notify};
+#[cfg(target_arch = "x86_64")]
+use crate::vm::gdb;
+use crate::vm::cgroup::Cgroup;
+use crate::vm::memory_pressure::PressurePolicy;
+use crate::vm::preflight;
+use crate::vm::exits::{ExitHandlers, ExitStatus, VmEvent};
+use crate::util::{JsonValue, Logger, LogLevel};
+use crate::util::debug_flags::{self, DebugFlag};
+use crate::util::trace::{self, TraceSubsystem};
+
+lazy_static! {
+    // The boot filesystem only depends on the embedded binaries and the host
+    // libraries they link against, so build it once and share it between VMs.
+    static ref BOOTFS: Mutex<Option<SyntheticFS>> = Mutex::new(None);
+}
+
+///
+/// Build the boot filesystem and ask the host to read the embedded kernel
+/// image ahead of time so that later calls to `VmSetup::create_vm()` do not
+/// pay for either. Guest RAM is loaded afresh for each VM, as there is no
+/// snapshot of a booted guest to restore.
+///
+pub fn warm_boot_cache() -> ::std::io::Result<()> {
+    bootfs()?;
+    // The image is part of this executable, so it is read ahead from the
+    // mapping of the executable which holds it
+    let page_mask = 4096 - 1;
+    let start = KERNEL.as_ptr() as usize & !page_mask;
+    let len = KERNEL.as_ptr() as usize + KERNEL.len() - start;
+    unsafe {
+        if libc::madvise(start as *mut libc::c_void, len, libc::MADV_WILLNEED) != 0 {
+            return Err(::std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn bootfs() -> ::std::io::Result<SyntheticFS> {
+    let mut cached = BOOTFS.lock().unwrap();
+    if let Some(ref fs) = *cached {
+        return Ok(fs.clone());
+    }
+    let fs = create_bootfs()?;
+    *cached = Some(fs.clone());
+    Ok(fs)
+}
+
+fn create_bootfs() -> ::std::io::Result<SyntheticFS> {
+    let mut s = SyntheticFS::new();
+    s.mkdirs(&["/tmp", "/proc", "/sys", "/dev", "/home/user", "/bin", "/etc"]);
+
+    // ldd needs a path to the binary, which a sealed memfd provides without
+    // writing it to the host filesystem
+    let phinit = MemoryFd::new_sealed_with_contents("ph-init", PHINIT)?;
+    s.add_library_dependencies(phinit.proc_path())?;
+
+    s.add_memory_file("/usr/bin", "ph-init", 0o755, PHINIT)?;
+    s.add_memory_file("/usr/bin", "sommelier", 0o755, SOMMELIER)?;
+
+    s.add_file("/etc", "ld.so.cache", 0o644, "/etc/ld.so.cache");
+    Ok(s)
+}
+
+// The file is copied into a sealed memfd so that the guest reads the contents
+// it had when the VM was created, whatever later happens to the host file.
+fn add_boot_file(bootfs: &mut SyntheticFS, guest_path: &Path, host_path: &Path) -> ::std::io::Result<()> {
+    let (dir, name) = match (guest_path.parent(), guest_path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Err(::std::io::Error::from_raw_os_error(libc::EINVAL)),
+    };
+    let bytes = fs::read(host_path)?;
+    let mode = fs::metadata(host_path)?.permissions().mode() & 0o777;
+    let memfd = MemoryFd::new_sealed_with_contents(&name.to_string_lossy(), &bytes)?;
+    if mode & 0o111 != 0 {
+        bootfs.add_library_dependencies(memfd.proc_path())?;
+    }
+    bootfs.add_sealed_file(dir, name, mode, memfd)
+}
 
 pub struct Vm {
     kvm: Kvm,
     vcpus: Vec<KvmVcpu>,
     memory: MemoryManager,
     io_dispatch: Arc<IoDispatcher>,
+    exit_handlers: ExitHandlers,
+    shutdown: Arc<AtomicBool>,
+    kicker: VcpuKicker,
     termios: Option<Termios>,
+    scrub_memory: bool,
+    // Guest RAM is kept in a file after the VM exits
+    sync_ram: bool,
+    control: Option<ControlServer>,
+    idle_timeout: Option<Duration>,
+    suspend_policy: Option<SuspendPolicy>,
+    console_socket: bool,
+    balloon: Option<BalloonHandle>,
+    pressure_policy: Option<PressurePolicy>,
+    input: Option<(InputHandle, InputHandle)>,
 }
 
 impl Vm {
@@ -30,33 +128,138 @@ impl Vm {
             .map_err(Error::ArchError)?;
         let memory = arch.create_memory(&kvm)
             .map_err(Error::ArchError)?;
+        let io_dispatch = IoDispatcher::new();
         Ok(Vm {
             kvm,
             memory,
             vcpus: Vec::new(),
-            io_dispatch: IoDispatcher::new(),
+            exit_handlers: ExitHandlers::new(io_dispatch.clone()),
+            io_dispatch,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            kicker: VcpuKicker::new()?,
             termios: None,
+            scrub_memory: false,
+            sync_ram: false,
+            control: None,
+            idle_timeout: None,
+            suspend_policy: None,
+            console_socket: false,
+            balloon: None,
+            pressure_policy: None,
+            input: None,
         })
     }
 
-    pub fn start(&self) -> Result<()> {
-        let shutdown = Arc::new(AtomicBool::new(false));
+    /// Handlers for vcpu exits, to which handlers and tracepoints can be
+    /// added before the VM is started.
+    pub fn exit_handlers(&self) -> &ExitHandlers {
+        &self.exit_handlers
+    }
+
+    /// Ask the guest to give up `bytes` of its RAM to the balloon device so
+    /// that the host can reclaim it. A smaller value than before returns
+    /// memory to the guest. Returns `false` if the VM has no balloon device.
+    pub fn balloon_target(&self, bytes: u64) -> bool {
+        match self.balloon {
+            Some(ref balloon) => {
+                balloon.set_target(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The virtio-input keyboard, to which key events can be sent while the
+    /// VM runs, if the VM was configured with one.
+    pub fn keyboard(&self) -> Option<&InputHandle> {
+        self.input.as_ref().map(|&(ref keyboard, _)| keyboard)
+    }
+
+    /// The virtio-input mouse, to which button, movement and wheel events can
+    /// be sent while the VM runs, if the VM was configured with one.
+    pub fn mouse(&self) -> Option<&InputHandle> {
+        self.input.as_ref().map(|&(_, ref mouse)| mouse)
+    }
+
+    /// Run the VM until the guest stops it or it is stopped by the host,
+    /// and return how it stopped.
+    pub fn start(&self) -> Result<ExitStatus> {
+        if let Some(control) = self.control.as_ref() {
+            control.start().map_err(Error::ControlSocket)?;
+        }
+        // Discard a power off announced by the guest before a previous reset
+        events::take_power_off_request();
+        let shutdown = self.shutdown.clone();
+        let kicker = self.kicker.clone();
+        #[cfg(target_arch = "x86_64")]
+        gdb::set_target(self.vcpus.clone(), self.memory.guest_ram().clone(), kicker.clone(), shutdown.clone());
         let mut handles = Vec::new();
         for vcpu in self.vcpus.clone() {
-            let mut run_area = KvmRunArea::new(vcpu, shutdown.clone(), self.io_dispatch.clone())?;
-            let h = thread::spawn(move || run_area.run());
+            let mut run_area = KvmRunArea::new(vcpu, shutdown.clone(), self.exit_handlers.clone())?;
+            let kicker = kicker.clone();
+            let shutdown = shutdown.clone();
+            let h = thread::spawn(move || {
+                kicker.register_current();
+                run_area.run();
+                kicker.unregister_current();
+                // If this vcpu stopped the VM, interrupt the others until they notice
+                while shutdown.load(Ordering::Relaxed) && kicker.kick_all() > 0 {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            });
             handles.push(h);
         }
 
+        if let Some(timeout) = self.idle_timeout {
+            IdleMonitor::new(timeout, self.control.clone(), self.console_socket)
+                .start(shutdown.clone(), kicker.clone());
+        }
+        if let Some(policy) = self.suspend_policy.clone() {
+            SuspendMonitor::new(policy, self.memory.guest_ram().clone())
+                .start(shutdown.clone(), kicker);
+        }
+        self.exit_handlers.send_event(VmEvent::Started);
+
         for h in handles {
             h.join().expect("...");
         }
+        shutdown.store(true, Ordering::Relaxed);
+        #[cfg(target_arch = "x86_64")]
+        gdb::clear_target();
+        if let Some(control) = self.control.as_ref() {
+            control.shutdown();
+        }
+        if let Some(policy) = self.pressure_policy.as_ref() {
+            policy.leave();
+        }
+        if self.sync_ram {
+            if let Err(e) = self.memory.guest_ram().sync() {
+                warn!("Failed to write guest RAM back to its file: {}", e);
+            }
+        }
         if let Some(termios) = self.termios {
             let _ = termios::tcsetattr(0, termios::TCSANOW, &termios)
                 .map_err(Error::TerminalTermios)?;
         }
-        Ok(())
+        let status = self.exit_handlers.exit_status().unwrap_or(ExitStatus::Stopped);
+        if status == ExitStatus::Crash {
+            trace::dump("the guest crashed");
+        } else {
+            trace::flush();
+        }
+        self.exit_handlers.send_event(VmEvent::Stopped(status));
+        Ok(status)
+    }
+}
 
+impl Drop for Vm {
+    // Guest RAM is scrubbed when the VM is dropped rather than at the end of
+    // start(), so that it is also scrubbed if creating or running the VM
+    // fails with an error or a panic.
+    fn drop(&mut self) {
+        if self.scrub_memory {
+            self.memory.scrub();
+        }
     }
 }
 
@@ -64,27 +267,66 @@ pub struct VmSetup <T: ArchSetup> {
     config: VmConfig,
     cmdline: KernelCmdLine,
     arch: T,
+    control: Option<ControlServer>,
+    shares: Vec<P9Share>,
+    fs_shares: Vec<VirtioFsShare>,
+    balloon: Option<BalloonHandle>,
+    pressure_policy: Option<PressurePolicy>,
+    input: Option<(InputHandle, InputHandle)>,
+    // Set once pH has switched to an unprivileged user after creating the network
+    privileges_dropped: bool,
 }
 
 impl <T: ArchSetup> VmSetup <T> {
 
-    pub fn new(config: VmConfig, arch: T) -> Self {
+    pub fn new(mut config: VmConfig, arch: T) -> Self {
+        let control = match config.take_listen_fd("control") {
+            Some(fd) => Some(ControlServer::from_listener_fd(fd)),
+            None => config.control_path().map(ControlServer::new),
+        };
         VmSetup {
             config,
             cmdline: KernelCmdLine::new_default(),
             arch,
+            control,
+            shares: Vec::new(),
+            fs_shares: Vec::new(),
+            balloon: None,
+            pressure_policy: None,
+            input: None,
+            privileges_dropped: false,
         }
     }
 
     pub fn create_vm(&mut self) -> Result<Vm> {
+        self.check_pci_hotplug()?;
+        // Before guest RAM is allocated and any threads are started
+        preflight::check_resource_limits(&self.config)?;
+        self.setup_cgroup()?;
         let mut vm = Vm::create(&mut self.arch)?;
+        // Set as soon as guest RAM exists, to scrub it if the rest of the setup fails
+        vm.scrub_memory = self.config.is_scrub_memory_enabled();
+        if self.config.get_ram_file().is_some() {
+            if vm.scrub_memory {
+                warn!("--scrub-memory is ignored when guest RAM is kept in a file");
+                vm.scrub_memory = false;
+            }
+            vm.sync_ram = true;
+        }
 
-        devices::rtc::Rtc::register(vm.io_dispatch.clone());
+        if let Some(path) = self.config.get_console_log() {
+            if let Err(err) = console::set_log_file(path) {
+                warn!("Failed to open console log {}: {}", path.display(), err);
+            }
+        }
+        console::start_host_console(self.config.get_console_mode())
+            .map_err(Error::HostConsole)?;
 
-        if self.config.verbose() {
-            self.cmdline.push("earlyprintk=serial");
-            devices::serial::SerialDevice::register(vm.kvm.clone(),vm.io_dispatch.clone(), 0);
-        } else {
+        let deterministic = self.config.deterministic_seed().is_some();
+        self.setup_legacy_devices(&vm, deterministic);
+        #[cfg(target_arch = "x86_64")]
+        self.setup_x86_platform(deterministic);
+        if !self.config.verbose() {
             self.cmdline.push("quiet");
         }
         if self.config.rootshell() {
@@ -97,60 +339,320 @@ impl <T: ArchSetup> VmSetup <T> {
         if let Some(realm) = self.config.realm_name() {
             self.cmdline.push_set_val("phinit.realm", realm);
         }
+        if self.config.is_wayland_enabled() {
+            if let Some(tag) = self.config.get_wayland_tag() {
+                self.cmdline.push_set_val("phinit.wayland_tag", tag);
+            }
+        }
+
+        if let Some(mode) = self.config.get_lockdown() {
+            self.cmdline.push_set_val("lockdown", mode);
+        }
+        if self.config.is_module_sig_enforced() {
+            self.cmdline.push_set_true("module.sig_enforce");
+        }
+
+        if self.config.is_selftest() {
+            self.cmdline.push("phinit.selftest");
+        }
 
-        let saved= Termios::from_fd(0)
-            .map_err(Error::TerminalTermios)?;
-        vm.termios = Some(saved);
+        if self.config.is_tiny() {
+            self.cmdline
+                .push("phinit.no_home")
+                .push_set_val("rcupdate.rcu_expedited", "1");
+            #[cfg(target_arch = "x86_64")]
+            self.cmdline
+                .push_set_val("tsc", "reliable")
+                .push_set_val("pci", "lastbus=0");
+        }
+
+        if let Some(sender) = self.config.take_vm_events() {
+            vm.exit_handlers.set_event_sender(sender);
+        }
+
+        self.setup_replay()?;
+        self.setup_tracing()?;
+        self.register_debug_commands();
+
+        metrics::count_exits(&vm.exit_handlers);
+        if let Some(address) = self.config.metrics_address() {
+            metrics::listen(address).map_err(Error::MetricsListener)?;
+        }
+        self.setup_gdb(&vm)?;
+
+        vm.idle_timeout = self.config.idle_timeout();
+        vm.suspend_policy = self.config.suspend_policy();
+        vm.console_socket = self.config.is_console_socket();
+        // The integration tests run without a terminal
+        if !vm.console_socket && console::is_stdio() && unsafe { libc::isatty(0) } == 1 {
+            let saved= Termios::from_fd(0)
+                .map_err(Error::TerminalTermios)?;
+            vm.termios = Some(saved);
+        }
 
         let mut virtio = VirtioBus::new(vm.memory.clone(), vm.io_dispatch.clone(), vm.kvm.clone());
+        for &(device_type, identity) in self.config.pci_identities() {
+            virtio.set_pci_identity(device_type, identity);
+        }
+        for &(device_type, window) in self.config.interrupt_windows() {
+            virtio.set_interrupt_window(device_type, window);
+        }
+        virtio.set_irq_policy(self.config.get_irq_policy());
+        virtio.set_config_audit(self.config.is_virtio_audit_enabled());
+        virtio.set_msix(self.config.is_msix_enabled());
+        virtio.set_mmio_transport(self.config.is_virtio_mmio_enabled());
+        if self.config.is_pci_hotplug_enabled() {
+            virtio.enable_hotplug()
+                .map_err(Error::SetupVirtio)?;
+        }
         self.setup_synthetic_bootfs(&mut virtio)?;
         self.setup_virtio(&mut virtio)
             .map_err(Error::SetupVirtio)?;
+        // Device threads are started once the guest drivers are ready
+        seccomp::set_mode(self.config.get_seccomp_mode());
+        self.register_share_commands();
+        if self.config.is_notifications_enabled() {
+            let label = self.config.realm_name()
+                .or_else(|| self.config.get_wayland_tag())
+                .unwrap_or("guest");
+            notify::enable(label, self.config.get_notify_command());
+        }
+        vm.balloon = self.balloon.take();
+        vm.pressure_policy = self.pressure_policy.take();
+        vm.input = self.input.take();
+        if let Some(control) = self.control.as_ref() {
+            let irqs = virtio.irq_stats();
+            control.register("irqs", "Interrupt line assigned to each virtio device and interrupt counts per line", move |_| Ok(irqs.describe()));
+            let audit = virtio.config_audit();
+            control.register("virtio-audit", "Configuration space accesses and driver quirks of each virtio device", move |_| Ok(audit.describe()));
+            let audit = virtio.config_audit();
+            control.register("virtio-features", "Features offered by each virtio device and accepted by its driver, and the device status", move |_| Ok(audit.describe_features()));
+            let memory = vm.memory.clone();
+            control.register("device-memory", "Device memory and shared memory regions mapped into the guest", move |_| Ok(memory.describe_device_memory()));
+            let memory = vm.memory.clone();
+            control.register("hugepages", "Huge pages requested for guest RAM, the pages which back it and the bytes currently mapped with huge pages", move |_| Ok(memory.describe_ram_backing()));
+            if let Some(path) = self.config.get_ram_file() {
+                let ram = vm.memory.guest_ram().clone();
+                let path = path.display().to_string();
+                control.register("ram-sync", "Write guest RAM back to the file which backs it", move |_| {
+                    ram.sync().map_err(|e| format!("failed to write guest RAM to {}: {}", path, e))?;
+                    // Only an image taken while the vcpus are parked is consistent
+                    Ok(JsonValue::object()
+                        .with("file", path.as_str())
+                        .with("suspended", suspend::is_suspended()))
+                });
+            }
+            control.register("metrics", "Virtqueue notifications, device IO bytes and vcpu exits counted since the VM started", |_| Ok(metrics::describe()));
+            control.register("events", "SEQ WAIT: events from the guest agent starting at SEQ, waiting up to WAIT seconds", events::events_command);
+            control.register_stream("console-attach", "[KIB]: attach to the console after replaying up to KIB of recent output", console::attach_command);
+            self.register_run_state_commands(&vm);
+        }
 
         if let Some(init_cmd) = self.config.get_init_cmdline() {
             self.cmdline.push_set_val("init", init_cmd);
         }
 
-        self.arch.setup_memory(&self.cmdline, &virtio.pci_irqs())
+        // The device tree describes the virtio-mmio devices on arm64
+        #[cfg(target_arch = "x86_64")]
+        for &(area, irq) in virtio.mmio_devices() {
+            let device = format!("{}K@0x{:x}:{}", area.size() >> 10, area.base(), irq);
+            self.cmdline.push_set_val("virtio_mmio.device", &device);
+        }
+
+        self.cmdline.check_phinit_vars();
+        self.arch.setup_memory(&self.cmdline, &virtio.pci_irqs(), virtio.hotplug_slots().as_ref(), virtio.mmio_devices())
             .map_err(Error::ArchError)?;
+        if self.config.is_pci_hotplug_enabled() {
+            virtio.start_hotplug();
+            self.register_hotplug_commands(virtio);
+        }
 
         for id in 0..self.config.ncpus() {
             let vcpu = vm.kvm.new_vcpu(id).map_err(Error::CreateVmFailed)?;
             self.arch.setup_vcpu(&vcpu).map_err(Error::ArchError)?;
             vm.vcpus.push(vcpu);
         }
+        vm.control = self.control.take();
         Ok(vm)
     }
 
+    // The devices of the platform profile on the IO ports of a PC
+    fn setup_legacy_devices(&mut self, vm: &Vm, deterministic: bool) {
+        let profile = self.config.get_platform_profile();
+        // A deterministic guest has no kvmclock and reads the wall clock
+        // from the RTC whatever the profile
+        if profile.has_legacy_devices() || deterministic {
+            let rtc_start = if deterministic { Some(devices::rtc::FIXED_EPOCH) } else { None };
+            devices::rtc::Rtc::register(vm.io_dispatch.clone(), rtc_start);
+        }
+        if profile.has_legacy_devices() {
+            vm.io_dispatch.register_legacy_ports();
+            devices::serial::SerialDevice::register_ports(&vm.kvm, &vm.io_dispatch, &self.config.uarts());
+        } else if !self.config.uarts().is_empty() {
+            warn!("--uart is ignored with --platform {}", profile.name());
+        }
+    }
+
+    // The clock, interrupt delivery and early console of a PC
+    #[cfg(target_arch = "x86_64")]
+    fn setup_x86_platform(&mut self, deterministic: bool) {
+        let profile = self.config.get_platform_profile();
+        if deterministic {
+            // Take the wall clock from the RTC rather than the host through kvmclock
+            self.cmdline.push("no-kvmclock");
+        }
+        if self.config.is_tiny() || !profile.has_legacy_devices() {
+            // Skip the check that the timer interrupt arrives, which there
+            // is no PIT to raise with the minimal profile
+            self.cmdline.push("no_timer_check");
+        }
+
+        if !self.config.get_boot_tables().has_acpi() {
+            // Deliver interrupts through the 8259 PICs described by the MP table
+            self.cmdline.push("noapic").push("noacpi");
+        }
+
+        if profile.has_legacy_devices() && self.config.uarts().iter().any(|&(port, _)| port == 0) {
+            // The bundled kernel has no 8250 driver and only prints to COM1
+            self.cmdline.push("earlyprintk=serial");
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn setup_gdb(&self, vm: &Vm) -> Result<()> {
+        if let Some(address) = self.config.gdb_address() {
+            gdb::register_exit_handler(&vm.exit_handlers);
+            gdb::listen(address, self.config.is_gdb_wait()).map_err(Error::GdbListener)?;
+        }
+        Ok(())
+    }
+
+    // The gdb stub only knows the x86 registers
+    #[cfg(target_arch = "aarch64")]
+    fn setup_gdb(&self, _vm: &Vm) -> Result<()> {
+        if self.config.gdb_address().is_some() {
+            warn!("--gdb is not supported on arm64 hosts");
+        }
+        Ok(())
+    }
+
+    fn setup_replay(&self) -> Result<()> {
+        if let Some(path) = self.config.replay_path() {
+            let replayer = EventReplayer::open(path)
+                .map_err(Error::ReplayLog)?;
+            notify!("Replaying device events from {}", path.display());
+            replay::set_hook(Box::new(replayer));
+        } else if let Some(path) = self.config.record_path() {
+            let recorder = EventRecorder::create(path)
+                .map_err(Error::ReplayLog)?;
+            notify!("Recording device events to {}", path.display());
+            replay::set_hook(Box::new(recorder));
+        }
+        Ok(())
+    }
+
+    fn setup_tracing(&self) -> Result<()> {
+        trace::set_capacity(self.config.trace_buffer_size());
+        if let Some(path) = self.config.trace_path() {
+            trace::stream_to(path).map_err(Error::TraceFile)?;
+        }
+        trace::set_enabled(self.config.trace_subsystems());
+        if let Some(control) = self.control.as_ref() {
+            control.register("trace", "[SUBSYSTEMS|all|none]: show or set the subsystems traced, from exit, pio, mmio and virtqueue", |args| {
+                if let Some(list) = args.first() {
+                    let subsystems = TraceSubsystem::parse_list(list)
+                        .ok_or_else(|| format!("unknown trace subsystem in '{}'", list))?;
+                    trace::set_enabled(&subsystems);
+                }
+                Ok(trace::describe(0))
+            });
+            control.register("trace-dump", "[COUNT]: the most recent COUNT trace events, 100 by default", |args| {
+                let count = match args.first() {
+                    Some(n) => n.parse().map_err(|_| format!("invalid count '{}'", n))?,
+                    None => 100,
+                };
+                Ok(trace::describe(count))
+            });
+        }
+        Ok(())
+    }
+
+    fn register_debug_commands(&self) {
+        let control = match self.control.as_ref() {
+            Some(control) => control,
+            None => return,
+        };
+        control.register("log-level", "[warn|notice|info|verbose|debug]: show or set the level of messages logged", |args| {
+            if let Some(name) = args.first() {
+                let level = LogLevel::parse(name)
+                    .ok_or_else(|| format!("unknown log level '{}'", name))?;
+                Logger::set_log_level(level);
+            }
+            Ok(Logger::log_level().name().into())
+        });
+        control.register("debug", "[FLAG on|off]: show or switch debug output of 9p, virtiofs, wl or vring", |args| {
+            match args {
+                [] => {},
+                [name, state] => {
+                    let flag = DebugFlag::parse(name)
+                        .ok_or_else(|| format!("unknown debug flag '{}'", name))?;
+                    let enabled = match *state {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(format!("expected on or off, not '{}'", state)),
+                    };
+                    debug_flags::set_enabled(flag, enabled);
+                }
+                _ => return Err("usage: debug [FLAG on|off]".to_string()),
+            }
+            Ok(debug_flags::describe())
+        });
+    }
+
     fn setup_virtio(&mut self, virtio: &mut VirtioBus) -> virtio::Result<()> {
-        devices::VirtioSerial::create(virtio)?;
-        devices::VirtioRandom::create(virtio)?;
+        devices::VirtioSerial::create(virtio, self.config.serial_ports())?;
 
-        if self.config.is_wayland_enabled() {
-            devices::VirtioWayland::create(virtio)?;
+        if let Some(path) = self.config.vsock_path() {
+            devices::VirtioSock::create(virtio, self.config.get_vsock_cid(), path)?;
         }
 
-        let homedir = self.config.homedir();
-        devices::VirtioP9::create(virtio, "home", homedir, false, false)?;
-        if homedir != "/home/user" && !self.config.is_realm() {
-            self.cmdline.push_set_val("phinit.home", homedir);
+        // The sharing table is opened before privileges are dropped
+        let io_share = self.config.io_share_limit().map(IoShare::new);
+
+        if !self.config.is_tiny() {
+            self.setup_optional_devices(virtio)?;
         }
 
         let mut block_root = None;
+        let io_threads = self.config.get_block_io_threads();
 
-        for disk in self.config.get_realmfs_images() {
+        let overlay_limit = self.config.overlay_limit();
+        let mut overlays = Vec::new();
+
+        for mut disk in self.config.get_realmfs_images() {
             if block_root == None {
                 block_root = Some(disk.read_only());
             }
-            devices::VirtioBlock::create(virtio, disk)?;
+            disk.set_overlay_limit(overlay_limit.clone());
+            overlays.extend(disk.overlay_stats());
+            devices::VirtioBlock::create(virtio, disk, io_share.clone(), io_threads)?;
         }
 
-        for disk in self.config.get_raw_disk_images() {
+        for mut disk in self.config.get_raw_disk_images() {
             if block_root == None {
                 block_root = Some(disk.read_only());
             }
-            devices::VirtioBlock::create(virtio, disk)?;
+            disk.set_overlay_limit(overlay_limit.clone());
+            overlays.extend(disk.overlay_stats());
+            devices::VirtioBlock::create(virtio, disk, io_share.clone(), io_threads)?;
+        }
+
+        for mut disk in self.config.get_extra_disk_images() {
+            disk.set_overlay_limit(overlay_limit.clone());
+            overlays.extend(disk.overlay_stats());
+            devices::VirtioBlock::create(virtio, disk, io_share.clone(), io_threads)?;
         }
+        self.register_overlay_command(overlays);
 
         if let Some(read_only) = block_root {
             if !read_only {
@@ -158,13 +660,90 @@ impl <T: ArchSetup> VmSetup <T> {
             }
             self.cmdline.push("phinit.root=/dev/vda");
             self.cmdline.push("phinit.rootfstype=ext4");
+        } else if let Some(rootfs) = self.config.get_synthetic_fs() {
+            if self.config.is_virtio_fs_enabled() {
+                devices::VirtioFs::create_with_filesystem(rootfs, virtio, "synthroot", "/", false)?;
+                self.cmdline.push_set_val("phinit.rootfstype", "virtiofs");
+            } else {
+                devices::VirtioP9::create_with_filesystem(rootfs, virtio, "synthroot", "/", false)?;
+                self.cmdline.push_set_val("phinit.rootfstype", "9p");
+                self.cmdline.push_set_val("phinit.rootflags", "trans=virtio");
+            }
+            self.cmdline.push_set_val("phinit.root", "synthroot");
+            if let Some(run) = self.config.get_run_path() {
+                self.cmdline.push_set_val("phinit.run", &run.display().to_string());
+            }
+        } else if self.config.is_virtio_fs_enabled() {
+            let share = devices::VirtioFs::create(virtio, "fsroot", "/", true, self.config.p9_timeout(), false)?;
+            self.fs_shares.push(share);
+            self.cmdline.push_set_val("phinit.root", "fsroot");
+            self.cmdline.push_set_val("phinit.rootfstype", "virtiofs");
         } else {
-            devices::VirtioP9::create(virtio, "9proot", "/", true, false)?;
+            let share = devices::VirtioP9::create(virtio, "9proot", "/", true, self.config.p9_timeout(), false)?;
+            self.shares.push(share);
             self.cmdline.push_set_val("phinit.root", "9proot");
             self.cmdline.push_set_val("phinit.rootfstype", "9p");
             self.cmdline.push_set_val("phinit.rootflags", "trans=virtio");
         }
 
+        if let Some(store) = self.config.get_store_share() {
+            let store = store.display().to_string();
+            let share = devices::VirtioP9::create_store(virtio, "store", &store, self.config.p9_timeout(), false)?;
+            self.shares.push(share);
+            self.cmdline.push_set_val("phinit.store", &store);
+        }
+
+        if self.config.is_p9_io_uring_enabled() {
+            // Pages released by the balloon would stay pinned by the ring and
+            // no longer be the pages the guest sees
+            let register_buffers = !self.config.is_balloon_enabled();
+            for share in &self.shares {
+                share.use_io_uring(register_buffers);
+            }
+        }
+
+        if let Some(every) = self.config.p9_checksum_every() {
+            for share in &self.shares {
+                share.sample_checksums(every);
+            }
+        }
+
+        for share in &self.shares {
+            if let Some(hint) = self.config.get_p9_cache_hint(share.tag()) {
+                share.set_cache_hint(hint);
+            }
+        }
+
+        if self.config.is_p9_process_enabled() {
+            for share in &self.shares {
+                share.use_separate_process();
+            }
+        }
+
+        if let Some(io_share) = io_share {
+            if self.config.is_p9_process_enabled() {
+                // The sharing table of a child process would not be shared with this one
+                warn!("IO sharing does not apply to 9p shares served by separate processes");
+            } else {
+                for share in &self.shares {
+                    share.set_io_share(io_share.clone());
+                }
+            }
+            for share in &self.fs_shares {
+                share.set_io_share(io_share.clone());
+            }
+            if let Some(control) = self.control.as_ref() {
+                control.register("io-share", "Host IO bandwidth shared with other instances", move |_| Ok(io_share.describe()));
+            }
+        }
+
+        for spec in self.config.vhost_user_devices() {
+            match VhostUserBackend::connect(spec.socket()) {
+                Ok(backend) => devices::VhostUser::create(virtio, spec, backend)?,
+                Err(e) => warn!("Not adding vhost-user device: {}", e),
+            }
+        }
+
         if self.config.network() {
             self.setup_network(virtio)?;
             self.drop_privs();
@@ -173,20 +752,337 @@ impl <T: ArchSetup> VmSetup <T> {
         Ok(())
     }
 
-    fn drop_privs(&self) {
+    fn setup_cgroup(&self) -> Result<()> {
+        if !self.config.has_cgroup_limits() {
+            return Ok(());
+        }
+        if let Some(limit) = self.config.memory_limit() {
+            if limit <= self.config.ram_size() as u64 {
+                warn!("Memory limit of {} bytes is not larger than guest RAM of {} bytes", limit, self.config.ram_size());
+            }
+        }
+        let cgroup = Cgroup::create(self.config.cpu_limit_percent(), self.config.memory_limit())
+            .map_err(Error::Cgroup)?;
+        notify!("Running in cgroup {}", cgroup.path().display());
+        if let Some(control) = self.control.as_ref() {
+            control.register("cgroup", "CPU and memory limits and usage of the cgroup of the VM", move |_| Ok(cgroup.describe()));
+        }
+        Ok(())
+    }
+
+    // The hotplug controller is described to the guest in the ACPI tables
+    // and only takes devices on the PCI bus.
+    fn check_pci_hotplug(&self) -> Result<()> {
+        if !self.config.is_pci_hotplug_enabled() {
+            return Ok(());
+        }
+        if !self.config.get_boot_tables().has_acpi() {
+            return Err(Error::PciHotplug("it requires --boot-tables acpi or both"));
+        }
+        if self.config.is_virtio_mmio_enabled() {
+            return Err(Error::PciHotplug("it is not supported with the virtio-mmio transport"));
+        }
+        Ok(())
+    }
+
+    fn setup_optional_devices(&mut self, virtio: &mut VirtioBus) -> virtio::Result<()> {
+        devices::VirtioRandom::create(virtio, self.config.deterministic_seed())?;
+
+        if self.config.is_wayland_enabled() {
+            let wl = devices::VirtioWayland::create(virtio)?;
+            if let Some(control) = self.control.as_ref() {
+                control.register("wl-status", "Wayland VFD table and queue state", move |_| wl.status());
+            }
+        }
+
+        if self.config.is_balloon_enabled() {
+            let balloon = devices::VirtioBalloon::create(virtio, self.config.ram_size())?;
+            self.register_balloon_command(balloon.clone());
+            if let Some(weight) = self.config.get_balloon_weight() {
+                let policy = PressurePolicy::start(balloon.clone(), weight, self.config.ram_size());
+                self.register_pressure_command(policy.clone());
+                self.pressure_policy = policy;
+            }
+            self.balloon = Some(balloon);
+        }
+
+        if let Some(backend) = self.config.get_sound_backend() {
+            devices::VirtioSound::create(virtio, backend)?;
+        }
+
+        if self.config.is_virtio_input_enabled() {
+            let (keyboard, mouse) = devices::VirtioInput::create(virtio)?;
+            self.register_input_command(keyboard.clone(), mouse.clone());
+            self.input = Some((keyboard, mouse));
+        }
+
+        let homedir = self.config.homedir();
+        if self.config.is_virtio_fs_enabled() {
+            let share = devices::VirtioFs::create(virtio, "home", homedir, false, self.config.p9_timeout(), false)?;
+            self.fs_shares.push(share);
+            self.cmdline.push_set_val("phinit.home_fs", "virtiofs");
+        } else {
+            let share = devices::VirtioP9::create(virtio, "home", homedir, false, self.config.p9_timeout(), false)?;
+            self.shares.push(share);
+        }
+        if homedir != "/home/user" && !self.config.is_realm() {
+            self.cmdline.push_set_val("phinit.home", homedir);
+        }
+        Ok(())
+    }
+
+    fn register_overlay_command(&self, overlays: Vec<OverlayStats>) {
+        let control = match self.control.as_ref() {
+            Some(control) => control,
+            None => return,
+        };
+        control.register("disk-overlays", "Memory used by writes to disk images with a memory overlay", move |_| {
+            let list: Vec<JsonValue> = overlays.iter()
+                .map(|o| JsonValue::object()
+                    .with("disk", o.name())
+                    .with("used_bytes", o.used_bytes())
+                    .with("limit_bytes", o.limit_bytes())
+                    .with("spilled", o.is_spilled()))
+                .collect();
+            Ok(list.into())
+        });
+    }
+
+    // Commands which inspect and change whether the VM is running
+    fn register_run_state_commands(&self, vm: &Vm) {
+        let control = match self.control.as_ref() {
+            Some(control) => control,
+            None => return,
+        };
+        let stopper = VmStopper::new(vm.shutdown.clone(), vm.kicker.clone(), vm.exit_handlers.clone());
+        let handlers = vm.exit_handlers.clone();
+        let ncpus = self.config.ncpus();
+        let s = stopper.clone();
+        control.register("query-status", "Whether the VM is running, suspended or stopping", move |_| {
+            let status = if s.is_stopping() {
+                "stopping"
+            } else if suspend::is_suspended() {
+                "suspended"
+            } else {
+                "running"
+            };
+            Ok(JsonValue::object()
+                .with("status", status)
+                .with("exit-status", handlers.exit_status().map(|s| s.to_string()))
+                .with("vcpus", ncpus))
+        });
+        let s = stopper.clone();
+        control.register("shutdown", "[force]: ask the guest to power off, or stop the VM at once with force", move |args| {
+            match args {
+                [] => if events::send_command("power-off") {
+                    Ok(JsonValue::from("power off requested"))
+                } else {
+                    Err("the guest agent is not running, use 'shutdown force' to stop the VM".to_string())
+                },
+                ["force"] => if s.stop(ExitStatus::Stopped) {
+                    Ok(JsonValue::from("stopping"))
+                } else {
+                    Err("the VM is already stopping".to_string())
+                },
+                _ => Err("expected no arguments or 'force'".to_string()),
+            }
+        });
+        let s = stopper.clone();
+        control.register("reset", "Stop the VM as if the guest had rebooted", move |_| {
+            if s.stop(ExitStatus::Reboot) {
+                Ok(JsonValue::from("resetting"))
+            } else {
+                Err("the VM is already stopping".to_string())
+            }
+        });
+        if let Some(path) = self.config.get_ram_file() {
+            let ram = vm.memory.guest_ram().clone();
+            let path = path.display().to_string();
+            let (kicker, shutdown) = (vm.kicker.clone(), vm.shutdown.clone());
+            control.register("snapshot", "Pause the vcpus and write guest RAM back to the file which backs it", move |_| {
+                let start = Instant::now();
+                suspend::while_paused(&kicker, &shutdown, || ram.sync())
+                    .ok_or_else(|| "the VM was resumed or stopped before the vcpus paused".to_string())?
+                    .map_err(|e| format!("failed to write guest RAM to {}: {}", path, e))?;
+                Ok(JsonValue::object()
+                    .with("file", path.as_str())
+                    .with("paused-ms", start.elapsed().as_millis() as u64))
+            });
+        }
+    }
+
+    fn register_hotplug_commands(&self, virtio: VirtioBus) {
+        let control = match self.control.as_ref() {
+            Some(control) => control,
+            None => return,
+        };
+        let virtio = Arc::new(Mutex::new(virtio));
+        let io_threads = self.config.get_block_io_threads();
+        let bus = virtio.clone();
+        control.register("disk-attach", "PATH [ro]: add a disk image as a virtio block device to the running VM", move |args| {
+            let path = args.get(0).ok_or_else(|| "disk-attach requires a path".to_string())?;
+            let open_type = match args.get(1) {
+                Some(&"ro") => OpenType::ReadOnly,
+                Some(arg) => return Err(format!("invalid disk-attach option: {}", arg)),
+                None => OpenType::ReadWrite,
+            };
+            let disk = RawDiskImage::new(*path, open_type)
+                .map_err(|e| format!("could not add disk: {}", e))?;
+            let mut bus = bus.lock().unwrap();
+            bus.attach_device_live(|vbus| devices::VirtioBlock::create(vbus, disk, None, io_threads))
+                .map_err(|e| e.to_string())?;
+            Ok(bus.describe_hotplug())
+        });
+        let bus = virtio.clone();
+        let bridge = self.config.bridge().to_string();
+        let mtu = self.config.get_mtu();
+        let privileges_dropped = self.privileges_dropped;
+        control.register("net-attach", "[MAC]: add a virtio network device on the bridge of the VM to the running VM", move |args| {
+            let mac = match args.get(0) {
+                Some(mac) => MacAddress::parse(mac)
+                    .ok_or_else(|| format!("invalid MAC address: {}", mac))?,
+                None => MacAddress::random(),
+            };
+            check_can_create_tap(privileges_dropped)?;
+            let tap = create_tap(&bridge, mtu)
+                .map_err(|e| format!("could not create tap device: {}", e))?;
+            let mut bus = bus.lock().unwrap();
+            bus.attach_device_live(|vbus| devices::VirtioNet::create(vbus, tap, mac, mtu, None))
+                .map_err(|e| e.to_string())?;
+            Ok(bus.describe_hotplug())
+        });
+        let bus = virtio.clone();
+        control.register("device-detach", "SLOT: ask the guest to release the virtio device in PCI slot SLOT and remove it", move |args| {
+            let slot = args.get(0)
+                .and_then(|s| s.parse::<u8>().ok())
+                .ok_or_else(|| "device-detach requires a PCI slot number".to_string())?;
+            let mut bus = bus.lock().unwrap();
+            bus.detach_device_live(slot)
+                .map_err(|e| e.to_string())?;
+            Ok(bus.describe_hotplug())
+        });
+        control.register("hotplug", "Devices which can be removed from the running VM and their PCI slots", move |_| {
+            Ok(virtio.lock().unwrap().describe_hotplug())
+        });
+    }
+
+    fn register_balloon_command(&self, balloon: BalloonHandle) {
+        let control = match self.control.as_ref() {
+            Some(control) => control,
+            None => return,
+        };
+        control.register("balloon", "[MEGS]: set how much guest RAM the balloon takes back, and show its size and guest memory statistics", move |args| {
+            if let Some(megs) = args.get(0) {
+                let megs = megs.parse::<u64>()
+                    .map_err(|_| format!("invalid balloon size: {}", megs))?;
+                balloon.set_target(megs * 1024 * 1024);
+            }
+            Ok(balloon.describe())
+        });
+    }
+
+    fn register_input_command(&self, keyboard: InputHandle, mouse: InputHandle) {
+        let control = match self.control.as_ref() {
+            Some(control) => control,
+            None => return,
+        };
+        control.register("input", "[key|button CODE [down|up] | move DX DY | wheel CLICKS]: send input events to the guest, and show the state of the input devices", move |args| {
+            fn number<N: ::std::str::FromStr>(arg: Option<&&str>) -> ::std::result::Result<N, String> {
+                let arg = arg.ok_or_else(|| "missing argument".to_string())?;
+                arg.parse().map_err(|_| format!("invalid number: {}", arg))
+            }
+            match args.get(0).cloned() {
+                Some(cmd @ "key") | Some(cmd @ "button") => {
+                    let device = if cmd == "key" { &keyboard } else { &mouse };
+                    let code = number::<u16>(args.get(1))?;
+                    match args.get(2).cloned() {
+                        Some("down") => device.key(code, true),
+                        Some("up") => device.key(code, false),
+                        None => {
+                            device.key(code, true);
+                            device.key(code, false);
+                        }
+                        Some(other) => return Err(format!("expected down or up: {}", other)),
+                    }
+                }
+                Some("move") => mouse.move_pointer(number(args.get(1))?, number(args.get(2))?),
+                Some("wheel") => mouse.scroll(number(args.get(1))?),
+                Some(other) => return Err(format!("unknown input event: {}", other)),
+                None => {},
+            }
+            Ok(JsonValue::object()
+                .with("keyboard", keyboard.describe())
+                .with("mouse", mouse.describe()))
+        });
+    }
+
+    fn register_pressure_command(&self, policy: Option<PressurePolicy>) {
+        let (control, policy) = match (self.control.as_ref(), policy) {
+            (Some(control), Some(policy)) => (control, policy),
+            _ => return,
+        };
+        control.register("memory-pressure", "Host memory pressure and the balloon target it sets", move |_| Ok(policy.describe()));
+    }
+
+    fn register_share_commands(&self) {
+        let control = match self.control.as_ref() {
+            Some(control) => control,
+            None => return,
+        };
+        let shares = self.shares.clone();
+        control.register("9p-shares", "List exported 9p shares and their host directories", move |_| {
+            let list: Vec<JsonValue> = shares.iter()
+                .map(|s| JsonValue::object()
+                    .with("tag", s.tag())
+                    .with("root", s.root().display().to_string()))
+                .collect();
+            Ok(list.into())
+        });
+        let shares = self.shares.clone();
+        control.register("9p-remount", "TAG PATH: export a different host directory for a 9p share", move |args| {
+            if args.len() != 2 {
+                return Err("usage: 9p-remount TAG PATH".to_string());
+            }
+            let share = shares.iter()
+                .find(|s| s.tag() == args[0])
+                .ok_or_else(|| format!("no 9p share with tag {}", args[0]))?;
+            share.remount(args[1])
+                .map_err(|e| format!("failed to remount {}: {}", args[0], e))?;
+            Ok(JsonValue::Null)
+        });
+    }
+
+    fn drop_privs(&mut self) {
         unsafe {
             libc::setgid(1000);
             libc::setuid(1000);
             libc::setegid(1000);
             libc::seteuid(1000);
         }
+        self.privileges_dropped = true;
 
     }
 
     fn setup_synthetic_bootfs(&mut self, virtio: &mut VirtioBus) -> Result<()> {
-        let bootfs = self.create_bootfs()
+        let mut bootfs = bootfs()
             .map_err(Error::SetupBootFs)?;
 
+        if let Some(key) = self.config.get_module_signing_key() {
+            if key.exists() {
+                // ph-init reads the key before the root filesystem replaces the boot filesystem
+                bootfs.add_file("/etc/ph", "module-key.der", 0o444, key);
+                self.cmdline.push("phinit.module_key");
+            } else {
+                warn!("Module signing key {} does not exist", key.display());
+            }
+        }
+
+        for (guest_path, host_path) in self.config.get_boot_files() {
+            if let Err(e) = add_boot_file(&mut bootfs, guest_path, host_path) {
+                warn!("Could not add {} to boot filesystem: {}", host_path.display(), e);
+            }
+        }
+
         devices::VirtioP9::create_with_filesystem(bootfs, virtio, "/dev/root", "/", false)
             .map_err(Error::SetupVirtio)?;
 
@@ -198,21 +1094,6 @@ impl <T: ArchSetup> VmSetup <T> {
         Ok(())
     }
 
-    fn create_bootfs(&self) -> ::std::io::Result<SyntheticFS> {
-        let mut s = SyntheticFS::new();
-        s.mkdirs(&["/tmp", "/proc", "/sys", "/dev", "/home/user", "/bin", "/etc"]);
-
-        fs::write("/tmp/ph-init", PHINIT)?;
-        s.add_library_dependencies("/tmp/ph-init")?;
-        fs::remove_file("/tmp/ph-init")?;
-
-        s.add_memory_file("/usr/bin", "ph-init", 0o755, PHINIT)?;
-        s.add_memory_file("/usr/bin", "sommelier", 0o755, SOMMELIER)?;
-
-        s.add_file("/etc", "ld.so.cache", 0o644, "/etc/ld.so.cache");
-        Ok(s)
-    }
-
     fn setup_network(&mut self, virtio: &mut VirtioBus) -> virtio::Result<()> {
         let tap = match self.setup_tap() {
             Ok(tap) => tap,
@@ -221,22 +1102,78 @@ impl <T: ArchSetup> VmSetup <T> {
                 return Ok(());
             }
         };
-        devices::VirtioNet::create(virtio, tap)?;
+        let mac = match (self.config.get_mac_address(), self.config.deterministic_seed(), self.config.realm_name()) {
+            (Some(mac), _, _) => mac,
+            (None, Some(seed), _) => MacAddress::from_seed(seed),
+            (None, None, Some(realm)) => MacAddress::from_name(realm),
+            (None, None, None) => MacAddress::random(),
+        };
+        verbose!("Guest network interface has address {}", mac);
+        // /dev/vhost-net is opened now because privileges are dropped
+        // before the device is started
+        let vhost = if self.config.is_vhost_net_enabled() {
+            match VhostNet::open() {
+                Ok(vhost) => Some(vhost),
+                Err(e) => {
+                    warn!("Not using vhost-net: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        devices::VirtioNet::create(virtio, tap, mac, self.config.get_mtu(), vhost)?;
         self.cmdline.push("phinit.ip=172.17.0.22");
         Ok(())
     }
 
     fn setup_tap(&self) -> Result<Tap> {
-        let bridge_name = self.config.bridge();
-        let tap = Tap::new_default()?;
-        let nl = NetlinkSocket::open()?;
+        create_tap(self.config.bridge(), self.config.get_mtu())
+    }
+}
 
-        if !nl.interface_exists(bridge_name) {
-            nl.create_bridge(bridge_name)?;
-            nl.set_interface_up(bridge_name)?;
-        }
-        nl.add_interface_to_bridge(tap.name(), bridge_name)?;
-        nl.set_interface_up(tap.name())?;
-        Ok(tap)
+// A TAP interface can only be created and added to the bridge before pH
+// switches to an unprivileged user, which it does once the network of the VM
+// has been created.
+fn check_can_create_tap(privileges_dropped: bool) -> ::std::result::Result<(), String> {
+    if privileges_dropped {
+        return Err("net-attach cannot create a tap device because pH has dropped its privileges after setting up the network".to_string());
+    }
+    Ok(())
+}
+
+// Create a TAP interface and add it to the bridge `bridge_name`, which is
+// created if it does not exist yet.
+fn create_tap(bridge_name: &str, mtu: Option<u16>) -> Result<Tap> {
+    let tap = Tap::new_default()?;
+    let nl = NetlinkSocket::open()?;
+
+    if !nl.interface_exists(bridge_name) {
+        nl.create_bridge(bridge_name)?;
+        nl.set_interface_up(bridge_name)?;
+    }
+    // Set before the TAP interface joins the bridge, which then allows
+    // frames up to the smallest MTU of its ports
+    if let Some(mtu) = mtu {
+        nl.set_interface_mtu(tap.name(), u32::from(mtu))?;
     }
-}
\ No newline at end of file
+    nl.add_interface_to_bridge(tap.name(), bridge_name)?;
+    nl.set_interface_up(tap.name())?;
+    Ok(tap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_can_create_tap;
+
+    #[test]
+    fn net_attach_is_refused_after_privileges_are_dropped() {
+        let err = check_can_create_tap(true).unwrap_err();
+        assert!(err.contains("dropped its privileges"), "{}", err);
+    }
+
+    #[test]
+    fn net_attach_is_allowed_before_privileges_are_dropped() {
+        assert_eq!(check_can_create_tap(false), Ok(()));
+    }
+}
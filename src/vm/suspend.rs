@@ -0,0 +1,229 @@
+//! Automatic suspension of idle VMs.
+//!
+//! A `SuspendMonitor` samples the cpu time consumed by the vcpu threads and
+//! the number of I/O exits once per second. When the vcpus have been almost
+//! completely idle and no I/O has occurred for the configured time, the vcpu
+//! threads are parked and guest RAM is optionally handed back to the host
+//! kernel according to the `RamPolicy`.
+//!
+//! The VM is resumed by calling `wake()`, which the console and the control
+//! socket do whenever a client is active.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::memory::GuestRam;
+use crate::vm::run::VcpuKicker;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Vcpus which run for less than this in a POLL_INTERVAL are considered idle
+const IDLE_CPU_TIME: Duration = Duration::from_millis(10);
+
+// How often a parked vcpu thread checks the shutdown flag
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    static ref STATE: (Mutex<PauseState>, Condvar) = (Mutex::new(PauseState::default()), Condvar::new());
+}
+
+// Avoids taking the lock on every vcpu exit when the VM is not suspended
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+static ACTIVITY: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct PauseState {
+    suspended: bool,
+    parked: usize,
+}
+
+/// How guest RAM is handled while the VM is suspended.
+#[derive(Clone,Debug,PartialEq)]
+pub enum RamPolicy {
+    /// Guest RAM stays resident.
+    Keep,
+    /// Guest RAM is moved to swap, which is compressed if the host swaps to zram.
+    PageOut,
+    /// Guest RAM is backed by a snapshot file at this path and written back
+    /// to the file and released when the VM is suspended.
+    Snapshot(PathBuf),
+}
+
+impl RamPolicy {
+    /// Parse `keep`, `pageout`, or the path of a snapshot file.
+    pub fn parse(s: &str) -> Option<RamPolicy> {
+        match s {
+            "" => None,
+            "keep" => Some(RamPolicy::Keep),
+            "pageout" => Some(RamPolicy::PageOut),
+            path => Some(RamPolicy::Snapshot(PathBuf::from(path))),
+        }
+    }
+
+    pub fn snapshot_path(&self) -> Option<&Path> {
+        match self {
+            RamPolicy::Snapshot(path) => Some(path.as_path()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct SuspendPolicy {
+    idle_time: Duration,
+    ram: RamPolicy,
+}
+
+impl SuspendPolicy {
+    pub fn new(idle_time: Duration, ram: RamPolicy) -> Self {
+        SuspendPolicy { idle_time, ram }
+    }
+}
+
+/// Note guest I/O so that the VM is not considered idle.
+pub fn record_activity() {
+    ACTIVITY.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Resume the VM if it is suspended and restart the idle timer.
+pub fn wake() {
+    record_activity();
+    if is_suspended() && resume() {
+        notify!("Resuming suspended VM");
+    }
+}
+
+// Returns false if the VM was not suspended
+fn resume() -> bool {
+    let (lock, cvar) = &*STATE;
+    let mut state = lock.lock().unwrap();
+    if !state.suspended {
+        return false;
+    }
+    state.suspended = false;
+    SUSPENDED.store(false, Ordering::SeqCst);
+    cvar.notify_all();
+    true
+}
+
+/// Run `f` with every vcpu parked so that guest memory does not change while
+/// it runs, then resume the vcpus unless the VM was already suspended.
+/// Returns `None` if the VM was woken or shut down before the vcpus stopped.
+pub fn while_paused<T, F: FnOnce() -> T>(kicker: &VcpuKicker, shutdown: &AtomicBool, f: F) -> Option<T> {
+    let suspended = is_suspended();
+    if !suspended && !pause(kicker, shutdown) {
+        return None;
+    }
+    let result = f();
+    if !suspended {
+        resume();
+    }
+    Some(result)
+}
+
+/// Called on a vcpu thread when `is_suspended()` returns `true`. Blocks until
+/// the VM is resumed or `shutdown` is set.
+pub fn park_vcpu(shutdown: &AtomicBool) {
+    let (lock, cvar) = &*STATE;
+    let mut state = lock.lock().unwrap();
+    state.parked += 1;
+    cvar.notify_all();
+    while state.suspended && !shutdown.load(Ordering::Relaxed) {
+        state = cvar.wait_timeout(state, PARK_TIMEOUT).unwrap().0;
+    }
+    state.parked -= 1;
+}
+
+// Park every vcpu thread. Returns false if the VM was woken up or shut down
+// before all of the vcpus stopped.
+fn pause(kicker: &VcpuKicker, shutdown: &AtomicBool) -> bool {
+    let (lock, cvar) = &*STATE;
+    let mut state = lock.lock().unwrap();
+    state.suspended = true;
+    SUSPENDED.store(true, Ordering::SeqCst);
+    loop {
+        if !state.suspended || shutdown.load(Ordering::Relaxed) {
+            return false;
+        }
+        // A signal which arrives while a vcpu thread is outside of KVM_RUN
+        // is lost, so kick again until every thread is parked.
+        let running = kicker.kick_all();
+        if state.parked >= running {
+            return true;
+        }
+        state = cvar.wait_timeout(state, Duration::from_millis(10)).unwrap().0;
+    }
+}
+
+///
+/// Suspends the VM when the guest has been idle for the time set in the
+/// `SuspendPolicy`.
+///
+pub struct SuspendMonitor {
+    policy: SuspendPolicy,
+    ram: GuestRam,
+}
+
+impl SuspendMonitor {
+    pub fn new(policy: SuspendPolicy, ram: GuestRam) -> Self {
+        SuspendMonitor { policy, ram }
+    }
+
+    pub fn start(self, shutdown: Arc<AtomicBool>, kicker: VcpuKicker) {
+        thread::spawn(move || self.run(&shutdown, &kicker));
+    }
+
+    fn run(&self, shutdown: &AtomicBool, kicker: &VcpuKicker) {
+        let mut idle_since = Instant::now();
+        let mut last_cpu = kicker.cpu_time();
+        let mut last_activity = ACTIVITY.load(Ordering::Relaxed);
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            if shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+            let cpu = kicker.cpu_time();
+            let activity = ACTIVITY.load(Ordering::Relaxed);
+            let busy = activity != last_activity || Self::cpu_busy(cpu, last_cpu);
+            last_cpu = cpu;
+            last_activity = activity;
+
+            if busy || is_suspended() {
+                idle_since = Instant::now();
+            } else if idle_since.elapsed() >= self.policy.idle_time {
+                self.suspend(shutdown, kicker);
+                idle_since = Instant::now();
+            }
+        }
+    }
+
+    // If cpu time cannot be read the vcpus are assumed to be busy
+    fn cpu_busy(cpu: Option<Duration>, last_cpu: Option<Duration>) -> bool {
+        match (cpu, last_cpu) {
+            (Some(cpu), Some(last)) => cpu.checked_sub(last)
+                .map(|used| used >= IDLE_CPU_TIME)
+                .unwrap_or(true),
+            _ => true,
+        }
+    }
+
+    fn suspend(&self, shutdown: &AtomicBool, kicker: &VcpuKicker) {
+        if !pause(kicker, shutdown) {
+            return;
+        }
+        notify!("VM idle for {} seconds, suspending", self.policy.idle_time.as_secs());
+        if self.policy.ram != RamPolicy::Keep {
+            if let Err(e) = self.ram.page_out() {
+                warn!("Failed to release guest RAM of suspended VM: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! End-to-end test of the virtio devices with the bundled kernel and ph-init.
+//!
+//! Needs `/dev/kvm` and a built kernel, so it only runs with
+//! `cargo test --features integration-tests`.
+//!
+//! The VM is booted with `VmConfig::selftest()`, so ph-init exercises each
+//! device in place of a shell and reports a `selftest` event for each check
+//! through the guest agent. The test reads the events from the control
+//! socket, plays the host end of the serial echo, and once the guest has
+//! powered off checks what it wrote to the scratch disk and the home share.
+//! The layout of the disk and files must match `ph-init/src/selftest.rs`.
+#![cfg(feature = "integration-tests")]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ph::{ControlClient, ExitStatus, OpenType, VmConfig};
+use ph::util::JsonValue;
+
+const DISK_MAGIC: &[u8] = b"pH-selftest-disk";
+const DISK_BLOCK_SIZE: usize = 4096;
+const DISK_SIZE: u64 = 1024 * 1024;
+
+const SHARE_DIR: &str = "ph-selftest";
+const HOST_FILE_CONTENTS: &str = "written by the host\n";
+const GUEST_FILE_CONTENTS: &str = "written by the guest\n";
+const LARGE_HOST_FILE: &str = "large-host.bin";
+const LARGE_GUEST_FILE: &str = "large-guest.bin";
+const LARGE_FILE_SIZE: usize = 4 * 1024 * 1024;
+
+const ECHO_PORT: &str = "ph.selftest";
+const ECHO_LINE: &str = "echo from the host\n";
+
+const DEVICES: &[&str] = &["rng", "blk", "9p", "serial"];
+
+// Boot and every check should finish well within this
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new() -> TestDir {
+        let path = std::env::temp_dir().join(format!("ph-selftest-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("home").join(SHARE_DIR)).unwrap();
+        TestDir(path)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn create_disk(path: &Path) {
+    let mut block: Vec<u8> = (0..DISK_BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    block[..DISK_MAGIC.len()].copy_from_slice(DISK_MAGIC);
+    let mut disk = fs::File::create(path).unwrap();
+    disk.write_all(&block).unwrap();
+    disk.set_len(DISK_SIZE).unwrap();
+}
+
+// Retry until the VM has created the socket
+fn connect<T, F: Fn() -> std::io::Result<T>>(connect: F) -> T {
+    let start = Instant::now();
+    loop {
+        match connect() {
+            Ok(t) => return t,
+            Err(e) if start.elapsed() > TIMEOUT => panic!("failed to connect to VM: {}", e),
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+fn serial_echo(path: PathBuf) -> String {
+    let mut stream = connect(|| UnixStream::connect(&path));
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(ECHO_LINE.as_bytes()).unwrap();
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).unwrap();
+    line
+}
+
+// The `selftest` events sent by the guest up to `selftest-done`
+fn selftest_events(path: &Path) -> Vec<JsonValue> {
+    let mut client = connect(|| ControlClient::connect(path));
+    let start = Instant::now();
+    let mut next = 0;
+    let mut events = Vec::new();
+    while start.elapsed() < TIMEOUT {
+        let result = client.call("events", &[next.to_string().as_str(), "10"])
+            .expect("control socket failed")
+            .expect("events method failed");
+        next = result.get("next").and_then(|n| n.as_i64()).unwrap_or(next);
+        for event in result.get("events").and_then(|e| e.as_array()).unwrap_or(&[]) {
+            match event.get("event").and_then(|e| e.as_str()) {
+                Some("selftest") => events.push(event.clone()),
+                Some("selftest-done") => return events,
+                _ => {},
+            }
+        }
+    }
+    panic!("guest did not finish the selftest in {:?}", TIMEOUT);
+}
+
+fn result_of<'a>(events: &'a [JsonValue], device: &str) -> Option<&'a str> {
+    events.iter()
+        .find(|e| e.get("device").and_then(|d| d.as_str()) == Some(device))
+        .and_then(|e| e.get("result"))
+        .and_then(|r| r.as_str())
+}
+
+#[test]
+fn virtio_devices() {
+    let dir = TestDir::new();
+    let disk = dir.path("disk.img");
+    let home = dir.path("home");
+    let control = dir.path("control.sock");
+    let serial = dir.path("serial.sock");
+    create_disk(&disk);
+    fs::write(home.join(SHARE_DIR).join("host.txt"), HOST_FILE_CONTENTS).unwrap();
+    let large: Vec<u8> = (0..LARGE_FILE_SIZE).map(|i| (i % 239) as u8).collect();
+    fs::write(home.join(SHARE_DIR).join(LARGE_HOST_FILE), &large).unwrap();
+
+    let config = VmConfig::new()
+        .selftest(true)
+        .use_network(false)
+        .wayland(false)
+        .home(home.to_str().unwrap())
+        .add_disk(&disk, OpenType::ReadWrite)
+        .serial_port(ECHO_PORT, &serial)
+        .control_socket(&control);
+    let vm = thread::spawn(move || config.boot());
+    let echo = thread::spawn(move || serial_echo(serial));
+
+    let events = selftest_events(&control);
+    assert_eq!(vm.join().unwrap(), ExitStatus::PowerOff);
+    assert_eq!(echo.join().unwrap(), ECHO_LINE);
+
+    for device in DEVICES {
+        assert_eq!(result_of(&events, device), Some("pass"), "{} check", device);
+    }
+
+    let mut block = vec![0u8; DISK_BLOCK_SIZE];
+    let mut image = fs::File::open(&disk).unwrap();
+    image.seek(SeekFrom::Start(DISK_BLOCK_SIZE as u64)).unwrap();
+    image.read_exact(&mut block).unwrap();
+    assert!(block.iter().enumerate().all(|(i, &b)| b == (i % 241) as u8), "block written by the guest");
+
+    let written = fs::read_to_string(home.join(SHARE_DIR).join("guest.txt")).unwrap();
+    assert_eq!(written, GUEST_FILE_CONTENTS);
+
+    let large = fs::read(home.join(SHARE_DIR).join(LARGE_GUEST_FILE)).unwrap();
+    assert_eq!(large.len(), LARGE_FILE_SIZE, "size of large file written by the guest");
+    assert!(large.iter().enumerate().all(|(i, &b)| b == (i % 233) as u8), "large file written by the guest");
+}